@@ -0,0 +1,7 @@
+#![no_main]
+use libfuzzer_sys::fuzz_target;
+
+// Arbitrary input must give an error rather than panicking
+fuzz_target!(|data: &[u8]| {
+    let _ = lizards::decode::decode_bytes(data);
+});
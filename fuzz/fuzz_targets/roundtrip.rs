@@ -0,0 +1,10 @@
+#![no_main]
+use libfuzzer_sys::fuzz_target;
+use lizards::EncodeOptions;
+
+fuzz_target!(|data: &[u8]| {
+    let mut encoded = Vec::new();
+    lizards::encode::encode_from_reader(data, &mut encoded, &EncodeOptions::default()).unwrap();
+    let decoded = lizards::decode::decode_bytes(&encoded).unwrap();
+    assert_eq!(data, &decoded[..]);
+});
@@ -0,0 +1,1142 @@
+//! Whole directories, packed as a tar stream and encoded with a [StreamEncoder] as they're
+//! packed. There's no container format of lizards' own: a `.tar.lizard` file decodes to a
+//! plain tar stream. Each frame has its own window, so any of them can be decoded without
+//! those before it. After the last frame is an index saying which frame each entry starts in,
+//! so [decode_matching_to_dir] can decode just the frames of the entries it extracts. It's laid
+//! out as `[INDEX_MAGIC][body len: u32][body][body len: u32]`, the body being the entries
+//! serialised with rmp_serde, so it can be found from the end of the file and skipped over when
+//! decoding the whole stream
+
+use log::{debug, info};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fs::OpenOptions;
+use std::io::{BufReader, BufWriter, Read, Seek, SeekFrom, Write};
+use std::path::{Component, Path, PathBuf};
+
+use crate::decode::{self, decode_with_header, DecodeOptions, DecodeSummary};
+use crate::encode::{EncodeOptions, EncodeSummary, StreamEncoder};
+use crate::error::{Error, Result};
+use crate::header::{INDEX_MAGIC, SIGNATURE_TRAILER_LEN};
+use crate::sniff::already_compressed;
+use crate::{file_io, header, helpers, DEFAULT_MAX_HEADER_SIZE};
+
+/// Extension of encoded tar streams, e.g. `dir.tar.lizard`
+pub const TAR_EXTENSION: &str = "tar.lizard";
+
+/// Pack [dir] into a tar stream encoded to [writer]. Entries are named under [dir]'s own name,
+/// as `tar -cf dir.tar dir` would. Symlinks are kept as links, which extracting recreates,
+/// unless [EncodeOptions::follow_symlinks] says to store what they point to
+pub fn encode_dir<W: Write>(
+    dir: impl AsRef<Path>,
+    writer: W,
+    options: &EncodeOptions,
+) -> Result<EncodeSummary> {
+    let dir = dir.as_ref();
+    let name = dir.canonicalize()?;
+    let name = name
+        .file_name()
+        .ok_or_else(|| invalid_input(format!("{:?} has no name to put its entries under", dir)))?;
+    let mut builder = tar::Builder::new(StreamEncoder::new(writer, options));
+    builder.follow_symlinks(options.follow_symlinks);
+    let mut index = Vec::new();
+    append_all(
+        &mut builder,
+        Path::new(name),
+        &walk_root(dir)?,
+        options,
+        &mut index,
+    )?;
+    let (mut writer, summary) = builder.into_inner()?.finish()?;
+    let index_len = write_index(&mut writer, &index)?;
+    writer.flush()?;
+    info!("Packed {:?} into {} bytes of tar", dir, summary.input_bytes);
+    Ok(EncodeSummary {
+        output_bytes: summary.output_bytes + index_len,
+        ..summary
+    })
+}
+
+/// Pack [dir] as [encode_dir] would, only counting the bytes of output rather than writing them,
+/// to find how big the archive would be
+pub fn dry_run_dir(dir: impl AsRef<Path>, options: &EncodeOptions) -> Result<EncodeSummary> {
+    let mut writer = helpers::CountingWriter::new(std::io::sink());
+    let summary = encode_dir(dir, &mut writer, options)?;
+    Ok(EncodeSummary {
+        output_bytes: writer.count(),
+        ..summary
+    })
+}
+
+/// Add [paths] (files or directories) to the end of [archive], a file [encode_dir] wrote, as
+/// a tar stream of their own encoded into frames after its existing ones. Nothing already in
+/// the archive is recompressed. The archive's index is replaced by one covering the new entries
+/// too, so they can be extracted on their own like the rest. Entries are named by the paths as
+/// given, less any leading `/`. If appending fails the archive is put back as it was. Signed
+/// archives can't be appended to
+pub fn append_to(
+    archive: impl AsRef<Path>,
+    paths: &[impl AsRef<Path>],
+    options: &EncodeOptions,
+) -> Result<EncodeSummary> {
+    let archive = archive.as_ref();
+    let names = paths
+        .iter()
+        .map(|path| entry_name(path.as_ref()))
+        .collect::<Result<Vec<_>>>()?;
+    let mut file = OpenOptions::new().read(true).write(true).open(archive)?;
+    if header::has_signature(&file)? {
+        return Err(invalid_input(String::from(
+            "Can't append to a signed archive, the signature wouldn't cover what's added",
+        )));
+    }
+    let original_len = file.metadata()?.len();
+    // The new frames go over the index, which is kept to put back should appending fail. An
+    // archive without one is left without, as an index of only the new entries would hide the
+    // others from [decode_matching_to_dir]
+    let (start, mut index) = match read_index(&mut file, DEFAULT_MAX_HEADER_SIZE)? {
+        Some((start, index)) => (start, Some(index)),
+        None => (original_len, None),
+    };
+    let mut replaced = Vec::new();
+    file.seek(SeekFrom::Start(start))?;
+    (&file).read_to_end(&mut replaced)?;
+    file.seek(SeekFrom::Start(start))?;
+    let appended = (|| {
+        // Frames don't share their window, so the new entries can be decoded on their own
+        let writer = StreamEncoder::new(BufWriter::new(&file), options);
+        let mut builder = tar::Builder::new(writer);
+        builder.follow_symlinks(options.follow_symlinks);
+        let mut added = Vec::new();
+        for (path, name) in paths.iter().zip(names) {
+            append_all(
+                &mut builder,
+                &name,
+                &walk_root(path.as_ref())?,
+                options,
+                &mut added,
+            )?;
+        }
+        let (mut writer, mut summary) = builder.into_inner()?.finish()?;
+        if let Some(index) = &mut index {
+            index.extend(added.into_iter().map(|entry| IndexEntry {
+                frame: start + entry.frame,
+                ..entry
+            }));
+            summary.output_bytes += write_index(&mut writer, index)?;
+        }
+        writer.flush()?;
+        Ok(summary)
+    })();
+    if appended.is_err() {
+        file.set_len(start)?;
+        file.seek(SeekFrom::Start(start))?;
+        file.write_all(&replaced)?;
+    }
+    appended
+}
+
+/// What [update] found had changed in the directory since the archive was written
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+pub struct UpdateSummary {
+    /// Entries whose contents differ from the directory's, or which are now something else
+    pub changed: Vec<String>,
+    /// In the directory but not the archive
+    pub added: Vec<String>,
+    /// In the archive but no longer in the directory
+    pub removed: Vec<String>,
+    /// Of the archive, copied into the new one as it was rather than packed again
+    pub copied_bytes: u64,
+    /// Of re-packing the directory, None when nothing had changed and the archive was left as
+    /// it was
+    #[serde(skip)]
+    pub encoded: Option<EncodeSummary>,
+}
+
+impl UpdateSummary {
+    pub fn unchanged(&self) -> bool {
+        self.changed.is_empty() && self.added.is_empty() && self.removed.is_empty()
+    }
+}
+
+/// Bring [archive], a file [encode_dir] wrote from [dir], up to date with [dir]. Its entries
+/// are read back and compared with what's in [dir] now, and if any have changed, been added or
+/// been removed, [dir] is packed again into a file next to [archive] which then replaces it.
+/// That also drops entries [append_to] added which have since been replaced. Given the
+/// archive's index, the frames holding only unchanged entries are copied over as they are, and
+/// just the rest of the entries are packed again, after them, with an index of what's left.
+/// Without one the archive is rewritten whole, but with [EncodeOptions::cache_dir] and
+/// [EncodeOptions::content_defined_blocks] only the blocks of changed entries are compressed
+/// again, the rest being replayed from the cache. Signed archives can't be updated
+pub fn update(
+    archive: impl AsRef<Path>,
+    dir: impl AsRef<Path>,
+    options: &EncodeOptions,
+) -> Result<UpdateSummary> {
+    let archive = archive.as_ref();
+    let dir = dir.as_ref();
+    let mut file = std::fs::File::open(archive)?;
+    if header::has_signature(&file)? {
+        return Err(invalid_input(String::from(
+            "Can't update a signed archive, the signature wouldn't cover it",
+        )));
+    }
+    file.rewind()?;
+    let name = dir.canonicalize()?;
+    let name = name
+        .file_name()
+        .ok_or_else(|| invalid_input(format!("{:?} has no name to put its entries under", dir)))?;
+    let mut on_disk = BTreeMap::new();
+    walk(
+        &walk_root(dir)?,
+        PathBuf::from(name),
+        options.follow_symlinks,
+        &mut on_disk,
+    )?;
+
+    // Later entries, which append_to added, replace earlier ones with the same name
+    let mut in_archive = BTreeMap::new();
+    read_tar_stream(
+        BufReader::new(&mut file),
+        &DecodeOptions::default(),
+        |tar| {
+            for entry in tar.entries()? {
+                let mut entry = entry?;
+                let name = entry.path()?.into_owned();
+                let same = match on_disk.get(&name) {
+                    Some(path) => same_entry(&mut entry, path, options.follow_symlinks)?,
+                    None => false,
+                };
+                in_archive.insert(name, same);
+            }
+            Ok(())
+        },
+    )?;
+    let mut summary = UpdateSummary::default();
+    for (name, same) in &in_archive {
+        match on_disk.contains_key(name) {
+            true if !same => summary.changed.push(name.display().to_string()),
+            true => (),
+            false => summary.removed.push(name.display().to_string()),
+        }
+    }
+    summary.added = on_disk
+        .keys()
+        .filter(|name| !in_archive.contains_key(*name))
+        .map(|name| name.display().to_string())
+        .collect();
+    if summary.unchanged() {
+        info!("{:?} is up to date with {:?}", archive, dir);
+        return Ok(summary);
+    }
+
+    let mut temp_name = archive.as_os_str().to_owned();
+    temp_name.push(".update");
+    let temp = PathBuf::from(temp_name);
+    let index = read_index(&mut file, DEFAULT_MAX_HEADER_SIZE)?;
+    let encoded = (|| -> Result<EncodeSummary> {
+        let mut writer = BufWriter::new(std::fs::File::create(&temp)?);
+        let encoded = match index {
+            Some((end, index)) => {
+                let unchanged = in_archive
+                    .iter()
+                    .filter(|(_, same)| **same)
+                    .map(|(name, _)| index_path(name))
+                    .collect();
+                let reader = BufReader::new(file);
+                let (copied, encoded) = rewrite_indexed(
+                    reader,
+                    end,
+                    &index,
+                    &unchanged,
+                    &on_disk,
+                    &mut writer,
+                    options,
+                )?;
+                summary.copied_bytes = copied;
+                encoded
+            }
+            None => encode_dir(dir, &mut writer, options)?,
+        };
+        writer
+            .into_inner()
+            .map_err(|e| e.into_error())?
+            .sync_all()?;
+        std::fs::rename(&temp, archive)?;
+        Ok(encoded)
+    })();
+    if encoded.is_err() {
+        let _ = std::fs::remove_file(&temp);
+    }
+    summary.encoded = Some(encoded?);
+    Ok(summary)
+}
+
+/// Write the archive [reader] reads, whose [index] starts at [end], to [writer] with what's in
+/// [on_disk] in place of what it had. Runs of entries from one starting a frame to the next
+/// which does are copied as they are when all of them are [unchanged] and none are replaced by
+/// later ones. The rest of [on_disk] is packed after them, then the index of it all. Gives back
+/// how many bytes were copied, and the summary of packing the rest
+fn rewrite_indexed<R: Read + Seek, W: Write>(
+    mut reader: R,
+    end: u64,
+    index: &[IndexEntry],
+    unchanged: &HashSet<String>,
+    on_disk: &BTreeMap<PathBuf, PathBuf>,
+    writer: &mut W,
+    options: &EncodeOptions,
+) -> Result<(u64, EncodeSummary)> {
+    let latest: HashMap<&str, usize> = index
+        .iter()
+        .enumerate()
+        .map(|(i, entry)| (entry.path.as_str(), i))
+        .collect();
+    let mut copied = 0;
+    let mut new_index = Vec::new();
+    let mut run_start = 0;
+    while run_start < index.len() {
+        let run_len = index[run_start + 1..]
+            .iter()
+            .position(|entry| entry.skip == 0)
+            .map_or(index.len() - run_start, |len| len + 1);
+        let first = run_start;
+        let run = &index[first..first + run_len];
+        run_start += run_len;
+        let keep = run.iter().enumerate().all(|(i, entry)| {
+            unchanged.contains(&entry.path) && latest[entry.path.as_str()] == first + i
+        });
+        if !keep {
+            continue;
+        }
+        let frame_end = index.get(run_start).map_or(end, |next| next.frame);
+        let len = frame_end
+            .checked_sub(run[0].frame)
+            .ok_or_else(|| Error::CorruptHeader(String::from("Archive index out of order")))?;
+        reader.seek(SeekFrom::Start(run[0].frame))?;
+        if std::io::copy(&mut (&mut reader).take(len), writer)? != len {
+            return Err(Error::TruncatedStream("archive frames"));
+        }
+        new_index.extend(run.iter().map(|entry| IndexEntry {
+            frame: copied + entry.frame - run[0].frame,
+            ..entry.clone()
+        }));
+        copied += len;
+    }
+
+    let kept: HashSet<_> = new_index.iter().map(|entry| entry.path.clone()).collect();
+    let repack: Vec<_> = on_disk
+        .iter()
+        .filter(|(name, _)| !kept.contains(&index_path(name)))
+        .collect();
+    let repacked = repack.len();
+    let mut encoded = EncodeSummary::default();
+    if !repack.is_empty() {
+        let mut builder = tar::Builder::new(StreamEncoder::new(&mut *writer, options));
+        builder.follow_symlinks(options.follow_symlinks);
+        let mut added = Vec::new();
+        for (name, path) in repack {
+            let metadata = match options.follow_symlinks {
+                true => std::fs::metadata(path)?,
+                false => std::fs::symlink_metadata(path)?,
+            };
+            append_entry(&mut builder, name, path, &metadata, options, &mut added)?;
+        }
+        (_, encoded) = builder.into_inner()?.finish()?;
+        new_index.extend(added.into_iter().map(|entry| IndexEntry {
+            frame: copied + entry.frame,
+            ..entry
+        }));
+    }
+    encoded.output_bytes += write_index(writer, &new_index)?;
+    info!(
+        "Copied {} bytes of unchanged entries, packed {} entries again",
+        copied, repacked
+    );
+    Ok((copied, encoded))
+}
+
+/// Add [path] to [builder] as [name], and everything in it if it's a directory, as
+/// [tar::Builder::append_dir_all] would, adding where each entry went to [index]. Files which
+/// look already compressed are written as stored frames rather than compressed again, unless
+/// [EncodeOptions::force_compress]
+fn append_all<W: Write>(
+    builder: &mut tar::Builder<StreamEncoder<W>>,
+    name: &Path,
+    path: &Path,
+    options: &EncodeOptions,
+    index: &mut Vec<IndexEntry>,
+) -> Result<()> {
+    let mut stack = vec![(path.to_path_buf(), name.to_path_buf())];
+    // Given a link, what it points to is packed either way
+    let mut root = true;
+    while let Some((path, name)) = stack.pop() {
+        let metadata = match options.follow_symlinks || std::mem::take(&mut root) {
+            true => std::fs::metadata(&path)?,
+            false => std::fs::symlink_metadata(&path)?,
+        };
+        if metadata.is_dir() {
+            for entry in std::fs::read_dir(&path)? {
+                let entry = entry?;
+                stack.push((entry.path(), name.join(entry.file_name())));
+            }
+        }
+        append_entry(builder, &name, &path, &metadata, options, index)?;
+    }
+    Ok(())
+}
+
+/// Add just [path] to [builder] as [name], not what's in it if it's a directory, adding where
+/// it went to [index]. See [append_all]
+fn append_entry<W: Write>(
+    builder: &mut tar::Builder<StreamEncoder<W>>,
+    name: &Path,
+    path: &Path,
+    metadata: &std::fs::Metadata,
+    options: &EncodeOptions,
+    index: &mut Vec<IndexEntry>,
+) -> Result<()> {
+    let store = metadata.is_file() && !options.force_compress && already_compressed(path);
+    if store {
+        debug!("Storing {:?}, which looks already compressed", path);
+        builder.get_mut().store(true)?;
+    }
+    let (frame, skip) = builder.get_ref().position();
+    let start = builder.get_ref().written();
+    match metadata.is_dir() {
+        true => builder.append_dir(name, path)?,
+        false => builder.append_path_with_name(path, name)?,
+    }
+    if store {
+        builder.get_mut().store(false)?;
+    }
+    index.push(IndexEntry {
+        path: index_path(name),
+        frame,
+        skip,
+        len: builder.get_ref().written() - start,
+    });
+    Ok(())
+}
+
+/// [name] as it's put in an archive's index
+fn index_path(name: &Path) -> String {
+    name.components()
+        .map(|component| component.as_os_str().to_string_lossy())
+        .collect::<Vec<_>>()
+        .join("/")
+}
+
+/// Where an entry of an archive is, so it can be extracted without decoding the frames of those
+/// before it
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+struct IndexEntry {
+    /// As it was packed, with `/` between its components
+    path: String,
+    /// Offset into the archive of the frame the entry's tar header is in
+    frame: u64,
+    /// How many bytes of that frame's output come before the entry
+    skip: u64,
+    /// How many bytes of tar the entry takes, its headers and padding included
+    len: u64,
+}
+
+/// Write [index] after an archive's last frame, returning how many bytes that took
+fn write_index<W: Write>(writer: &mut W, index: &[IndexEntry]) -> Result<u64> {
+    let body = rmp_serde::to_vec(index).unwrap();
+    let body_len = u32::try_from(body.len())
+        .map_err(|_| invalid_input(format!("Too many entries to index ({})", index.len())))?;
+    writer.write_all(INDEX_MAGIC)?;
+    writer.write_all(&body_len.to_be_bytes())?;
+    writer.write_all(&body)?;
+    writer.write_all(&body_len.to_be_bytes())?;
+    Ok((INDEX_MAGIC.len() + 8 + body.len()) as u64)
+}
+
+/// Skip the rest of an archive's index in [reader], once its magic has been read
+pub(crate) fn skip_index<R: Read>(reader: &mut R) -> Result<()> {
+    let mut body_len = [0; 4];
+    reader
+        .read_exact(&mut body_len)
+        .map_err(|_| Error::TruncatedStream("archive index"))?;
+    let len = u64::from(u32::from_be_bytes(body_len)) + 4;
+    match std::io::copy(&mut reader.take(len), &mut std::io::sink())? == len {
+        true => Ok(()),
+        false => Err(Error::TruncatedStream("archive index")),
+    }
+}
+
+/// The index at the end of the archive [reader] reads, before any signature, and the offset it
+/// starts at. None if there isn't one, as with archives written before there was. Fails on
+/// indexes longer than [max_len] bytes
+fn read_index<R: Read + Seek>(
+    reader: &mut R,
+    max_len: usize,
+) -> Result<Option<(u64, Vec<IndexEntry>)>> {
+    let mut end = reader.seek(SeekFrom::End(0))?;
+    if header::has_signature(&mut *reader)? {
+        end -= SIGNATURE_TRAILER_LEN as u64;
+    }
+    let mut len_bytes = [0; 4];
+    let Some(len_start) = end.checked_sub(len_bytes.len() as u64) else {
+        return Ok(None);
+    };
+    reader.seek(SeekFrom::Start(len_start))?;
+    reader.read_exact(&mut len_bytes)?;
+    let body_len = u32::from_be_bytes(len_bytes);
+    let prefix_len = (INDEX_MAGIC.len() + len_bytes.len()) as u64;
+    let Some(start) = len_start.checked_sub(u64::from(body_len) + prefix_len) else {
+        return Ok(None);
+    };
+    reader.seek(SeekFrom::Start(start))?;
+    let mut prefix = [0; 8];
+    reader.read_exact(&mut prefix)?;
+    if !header::starts_index(&prefix[..4]) || prefix[4..] != len_bytes {
+        return Ok(None);
+    }
+    if body_len as usize > max_len {
+        return Err(Error::LimitExceeded {
+            limit: "header size",
+            max: max_len as u64,
+        });
+    }
+    let mut body = vec![0; body_len as usize];
+    reader.read_exact(&mut body)?;
+    let index = rmp_serde::from_slice(&body)
+        .map_err(|e| Error::CorruptHeader(format!("Archive index: {}", e)))?;
+    Ok(Some((start, index)))
+}
+
+/// Extract the entries of [index] whose paths [matches] from the archive [reader] reads into
+/// [dest], decoding only the frames they're in. Gives back what was decoded of the tar stream,
+/// and how many entries were extracted
+fn extract_indexed<R: Read + Seek>(
+    reader: R,
+    index: &[IndexEntry],
+    dest: &Path,
+    matches: impl Fn(&Path) -> bool,
+    options: &DecodeOptions,
+) -> Result<(DecodeSummary, usize)> {
+    let mut reader = BufReader::new(reader);
+    let mut hasher = crc32fast::Hasher::new();
+    let mut decoded = 0;
+    let mut extracted = 0;
+    // The output of the frames from [start] on, as entries next to each other share frames
+    let mut start = None;
+    let mut next = 0;
+    let mut output = Vec::new();
+    for entry in index.iter().filter(|entry| matches(Path::new(&entry.path))) {
+        if start != Some(entry.frame) {
+            start = Some(entry.frame);
+            next = entry.frame;
+            output.clear();
+        }
+        let end = entry.skip.saturating_add(entry.len);
+        while (output.len() as u64) < end {
+            reader.seek(SeekFrom::Start(next))?;
+            let mut options = options.clone();
+            options.max_output_size = options.max_output_size.saturating_sub(decoded);
+            let frame_start = output.len();
+            decode::decode_frame(&mut reader, &mut output, &options, &[], None)?;
+            next = reader.stream_position()?;
+            hasher.update(&output[frame_start..]);
+            decoded += (output.len() - frame_start) as u64;
+        }
+        let tar = &output[entry.skip as usize..end as usize];
+        let mut archive = tar::Archive::new(tar);
+        archive.set_preserve_mtime(options.preserve);
+        archive.set_preserve_permissions(options.preserve);
+        let mut tar_entry = archive.entries()?.next().ok_or_else(|| {
+            Error::CorruptHeader(format!("Archive index: no entry for {}", entry.path))
+        })??;
+        if matches(&tar_entry.path()?) && tar_entry.unpack_in(dest)? {
+            extracted += 1;
+        }
+    }
+    let summary = DecodeSummary {
+        input_bytes: 0,
+        output_bytes: decoded,
+        checksum: hasher.finalize(),
+    };
+    Ok((summary, extracted))
+}
+
+/// Add what [encode_dir] would pack of [path] to [entries], by entry name (starting with
+/// [name]) to where it's read from
+fn walk(
+    path: &Path,
+    name: PathBuf,
+    follow_symlinks: bool,
+    entries: &mut BTreeMap<PathBuf, PathBuf>,
+) -> Result<()> {
+    let metadata = match follow_symlinks {
+        true => std::fs::metadata(path)?,
+        false => std::fs::symlink_metadata(path)?,
+    };
+    if metadata.is_dir() {
+        for entry in std::fs::read_dir(path)? {
+            let entry = entry?;
+            walk(
+                &entry.path(),
+                name.join(entry.file_name()),
+                follow_symlinks,
+                entries,
+            )?;
+        }
+    }
+    entries.insert(name, path.to_path_buf());
+    Ok(())
+}
+
+/// Whether [entry] holds what packing [path] would
+fn same_entry<R: Read>(
+    entry: &mut tar::Entry<R>,
+    path: &Path,
+    follow_symlinks: bool,
+) -> std::io::Result<bool> {
+    let metadata = match follow_symlinks {
+        true => std::fs::metadata(path)?,
+        false => std::fs::symlink_metadata(path)?,
+    };
+    let entry_type = entry.header().entry_type();
+    if metadata.is_dir() || metadata.is_symlink() {
+        let link = match metadata.is_symlink() {
+            true => Some(std::fs::read_link(path)?),
+            false => None,
+        };
+        let same_kind = match link {
+            Some(_) => entry_type.is_symlink(),
+            None => entry_type.is_dir(),
+        };
+        return Ok(same_kind && entry.link_name()?.map(|name| name.into_owned()) == link);
+    }
+    if !entry_type.is_file() || entry.size() != metadata.len() {
+        return Ok(false);
+    }
+    let mut file = std::fs::File::open(path)?;
+    let mut left = entry.size();
+    let mut packed = vec![0; 64 * 1024];
+    let mut current = vec![0; 64 * 1024];
+    while left > 0 {
+        let len = left.min(packed.len() as u64) as usize;
+        entry.read_exact(&mut packed[..len])?;
+        match file.read_exact(&mut current[..len]) {
+            // It shrank since its size was read
+            Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => return Ok(false),
+            read => read?,
+        }
+        if packed[..len] != current[..len] {
+            return Ok(false);
+        }
+        left -= len as u64;
+    }
+    Ok(true)
+}
+
+/// Where to read [path] from when packing it: absolute, and on Windows verbatim, so files deep
+/// enough to be past the 260 character limit can still be read. Entry names don't come from it
+fn walk_root(path: &Path) -> Result<PathBuf> {
+    Ok(file_io::verbatim(&std::path::absolute(path)?))
+}
+
+/// [path] as the name of an archive entry, which has to be relative and stay within wherever
+/// it's extracted
+fn entry_name(path: &Path) -> Result<PathBuf> {
+    let mut name = PathBuf::new();
+    for component in path.components() {
+        match component {
+            Component::Normal(part) => name.push(part),
+            Component::CurDir | Component::RootDir | Component::Prefix(_) => (),
+            Component::ParentDir => {
+                return Err(invalid_input(format!(
+                    "{:?} can't be added, as it goes up a directory",
+                    path
+                )))
+            }
+        }
+    }
+    match name.as_os_str().is_empty() {
+        true => Err(invalid_input(format!(
+            "{:?} has no name to add it as",
+            path
+        ))),
+        false => Ok(name),
+    }
+}
+
+fn invalid_input(message: String) -> Error {
+    Error::Io(std::io::Error::new(
+        std::io::ErrorKind::InvalidInput,
+        message,
+    ))
+}
+
+/// Decode a tar stream such as [encode_dir] makes from [reader], extracting it under [dest],
+/// along with any tar streams [append_to] put after it. Entries that would land outside [dest]
+/// are skipped. Modification times and permissions are restored with
+/// [DecodeOptions::preserve]
+pub fn decode_to_dir<R: Read + Send>(
+    reader: R,
+    dest: &Path,
+    options: &DecodeOptions,
+) -> Result<DecodeSummary> {
+    extract_matching(reader, dest, &[], options)
+}
+
+/// [decode_to_dir], extracting only entries whose paths in the archive match one of
+/// [patterns], or all of them if there are none. `*` doesn't match across a `/` but `**` does.
+/// With the archive's index only the frames of matching entries are decoded, the rest being
+/// skipped. Without one (archives written before there was, or appended to since) the whole
+/// stream is decoded, and the entries which don't match just aren't written. When the index is
+/// used, the summary is of the frames decoded rather than the whole stream
+pub fn decode_matching_to_dir<R: Read + Seek + Send>(
+    mut reader: R,
+    dest: &Path,
+    patterns: &[glob::Pattern],
+    options: &DecodeOptions,
+) -> Result<DecodeSummary> {
+    if !patterns.is_empty() {
+        if let Some((_, index)) = read_index(&mut reader, options.max_header_size)? {
+            std::fs::create_dir_all(dest)?;
+            let matches = |path: &Path| matches_any(patterns, path);
+            let (summary, extracted) = extract_indexed(reader, &index, dest, matches, options)?;
+            info!(
+                "Extracted {} matching entries into {:?}, decoding {} bytes of tar",
+                extracted, dest, summary.output_bytes
+            );
+            return Ok(summary);
+        }
+        reader.rewind()?;
+    }
+    extract_matching(reader, dest, patterns, options)
+}
+
+/// Whether [path] matches one of [patterns], as [decode_matching_to_dir] matches them
+fn matches_any(patterns: &[glob::Pattern], path: &Path) -> bool {
+    let match_options = glob::MatchOptions {
+        require_literal_separator: true,
+        ..Default::default()
+    };
+    patterns
+        .iter()
+        .any(|pattern| pattern.matches_path_with(path, match_options))
+}
+
+/// [decode_matching_to_dir] decoding the whole stream
+fn extract_matching<R: Read + Send>(
+    reader: R,
+    dest: &Path,
+    patterns: &[glob::Pattern],
+    options: &DecodeOptions,
+) -> Result<DecodeSummary> {
+    let (summary, extracted) = read_tar_stream(reader, options, |archive| {
+        if patterns.is_empty() {
+            archive.unpack(dest)?;
+            return Ok(None);
+        }
+        std::fs::create_dir_all(dest)?;
+        let mut extracted = 0;
+        for entry in archive.entries()? {
+            let mut entry = entry?;
+            let path = entry.path()?.into_owned();
+            if matches_any(patterns, &path) && entry.unpack_in(dest)? {
+                extracted += 1;
+            }
+        }
+        Ok(Some(extracted))
+    })?;
+    match extracted {
+        Some(extracted) => info!("Extracted {} matching entries into {:?}", extracted, dest),
+        None => info!(
+            "Extracted {} bytes of tar into {:?}",
+            summary.output_bytes, dest
+        ),
+    }
+    Ok(summary)
+}
+
+/// An entry found by [check_entries]
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct CheckedEntry {
+    pub path: String,
+    pub size: u64,
+    /// Why the entry couldn't be read, if it couldn't
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+/// Decode [reader] as [decode_to_dir] would, but read each entry through rather than
+/// extracting it. Gives back the entries found, up to and including any that couldn't be read,
+/// along with the result of decoding the whole stream. Checksums cover frames of the stream
+/// rather than entries, so when decoding fails the entries just before the one that failed may
+/// be damaged too
+pub fn check_entries<R: Read + Send>(
+    reader: R,
+    options: &DecodeOptions,
+) -> (Vec<CheckedEntry>, Result<DecodeSummary>) {
+    let mut checked = Vec::new();
+    let result = read_tar_stream(reader, options, |archive| {
+        for entry in archive.entries()? {
+            let mut entry = entry?;
+            let path = entry.path()?.display().to_string();
+            let size = entry.size();
+            let read = std::io::copy(&mut entry, &mut std::io::sink());
+            checked.push(CheckedEntry {
+                path,
+                size,
+                error: read.as_ref().err().map(ToString::to_string),
+            });
+            read?;
+        }
+        Ok(())
+    });
+    (checked, result.map(|(summary, ())| summary))
+}
+
+/// Decode [reader] on another thread, handing the tar stream to [read_tar] as it's decoded
+pub(crate) fn read_tar_stream<R: Read + Send, T>(
+    reader: R,
+    options: &DecodeOptions,
+    read_tar: impl FnOnce(&mut tar::Archive<std::io::PipeReader>) -> std::io::Result<T>,
+) -> Result<(DecodeSummary, T)> {
+    let (pipe_reader, pipe_writer) = std::io::pipe()?;
+    let (decoded, read) = std::thread::scope(|scope| {
+        let decoding = scope.spawn(move || decode_with_header(reader, pipe_writer, options));
+        let mut archive = tar::Archive::new(pipe_reader);
+        archive.set_preserve_mtime(options.preserve);
+        archive.set_preserve_permissions(options.preserve);
+        // Appended entries follow the end of archive marker of those before them
+        archive.set_ignore_zeros(true);
+        // Padding can follow the end of the archive, which the decoder still has to write
+        let read = read_tar(&mut archive).and_then(|read| {
+            std::io::copy(&mut archive.into_inner(), &mut std::io::sink()).map(|_| read)
+        });
+        (decoding.join().unwrap(), read)
+    });
+    match (decoded, read) {
+        // The decoder failing stops the tar stream short, so its error says more
+        (Err(Error::Io(e)), Err(read_error)) if e.kind() == std::io::ErrorKind::BrokenPipe => {
+            Err(Error::Io(read_error))
+        }
+        (Err(e), _) => Err(e),
+        (Ok(_), Err(e)) => Err(Error::Io(e)),
+        (Ok((summary, _)), Ok(read)) => Ok((summary, read)),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::Cursor;
+    use std::path::Path;
+
+    use crate::archive::{
+        append_to, check_entries, decode_matching_to_dir, decode_to_dir, encode_dir, read_index,
+        update, IndexEntry,
+    };
+    use crate::header;
+    use crate::test_util::temp_path;
+    use crate::{DecodeOptions, EncodeOptions, Error};
+
+    fn assert_same_tree(expected: &Path, actual: &Path) {
+        for entry in std::fs::read_dir(expected).unwrap() {
+            let entry = entry.unwrap();
+            let other = actual.join(entry.file_name());
+            let file_type = entry.file_type().unwrap();
+            if file_type.is_dir() {
+                assert_same_tree(&entry.path(), &other);
+            } else if file_type.is_symlink() {
+                let link = std::fs::read_link(entry.path()).unwrap();
+                assert_eq!(link, std::fs::read_link(&other).unwrap());
+            } else {
+                assert_eq!(
+                    std::fs::read(entry.path()).unwrap(),
+                    std::fs::read(other).unwrap()
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn round_trip() {
+        let base = temp_path("tar");
+        let dir = base.join("packed");
+        std::fs::create_dir_all(dir.join("nested/deeper")).unwrap();
+        std::fs::create_dir_all(dir.join("empty")).unwrap();
+        std::fs::write(dir.join("top.txt"), b"at the top".repeat(1000)).unwrap();
+        std::fs::write(dir.join("nested/deeper/binary"), [0u8, 1, 2, 255]).unwrap();
+        std::fs::write(dir.join("nested/nothing"), b"").unwrap();
+        #[cfg(unix)]
+        std::os::unix::fs::symlink("../top.txt", dir.join("nested/link")).unwrap();
+
+        let mut encoded = Vec::new();
+        let summary = encode_dir(&dir, &mut encoded, &EncodeOptions::default()).unwrap();
+        assert!(summary.output_bytes < summary.input_bytes / 4);
+
+        let dest = base.join("extracted");
+        std::fs::create_dir_all(&dest).unwrap();
+        decode_to_dir(&encoded[..], &dest, &DecodeOptions::default()).unwrap();
+        assert_same_tree(&dir, &dest.join("packed"));
+        assert!(dest.join("packed/empty").is_dir());
+
+        // Following symlinks stores a copy of what they point to
+        #[cfg(unix)]
+        {
+            let mut followed = Vec::new();
+            let options = EncodeOptions::default().follow_symlinks(true);
+            encode_dir(&dir, &mut followed, &options).unwrap();
+            let dest = base.join("followed");
+            decode_to_dir(&followed[..], &dest, &DecodeOptions::default()).unwrap();
+            let link = dest.join("packed/nested/link");
+            assert!(!link.is_symlink());
+            assert_eq!(std::fs::read(link).unwrap(), b"at the top".repeat(1000));
+        }
+
+        // A corrupt stream fails with the decoder's error rather than the tar stream ending
+        let encoded_whole = encoded.clone();
+        encoded.truncate(encoded.len() - 1);
+        let result = decode_to_dir(
+            &encoded[..],
+            &base.join("truncated"),
+            &DecodeOptions::default(),
+        );
+        assert!(
+            matches!(result, Err(Error::TruncatedStream(_))),
+            "{:?}",
+            result
+        );
+        let (checked, result) = check_entries(&encoded[..], &DecodeOptions::default());
+        assert!(matches!(result, Err(Error::TruncatedStream(_))));
+        let (all, result) = check_entries(&encoded_whole[..], &DecodeOptions::default());
+        assert!(result.is_ok());
+        assert!(all.iter().all(|entry| entry.error.is_none()));
+        assert!(all
+            .iter()
+            .any(|entry| entry.path == "packed/top.txt" && entry.size == 10_000));
+        assert!(checked.len() <= all.len());
+        std::fs::remove_dir_all(&base).unwrap();
+    }
+
+    #[test]
+    fn stores_compressed_files() {
+        let base = temp_path("tar_stored");
+        let dir = base.join("photos");
+        std::fs::create_dir_all(&dir).unwrap();
+        let photo = b"not really a photo ".repeat(200);
+        std::fs::write(dir.join("photo.jpg"), &photo).unwrap();
+        std::fs::write(dir.join("notes.txt"), b"some notes ".repeat(200)).unwrap();
+
+        // The photo goes in as it is, between the frames the rest is compressed into
+        let mut encoded = Vec::new();
+        encode_dir(&dir, &mut encoded, &EncodeOptions::default()).unwrap();
+        let contains_photo = |encoded: &[u8]| encoded.windows(photo.len()).any(|w| w == photo);
+        assert!(contains_photo(&encoded));
+        let dest = base.join("extracted");
+        decode_to_dir(&encoded[..], &dest, &DecodeOptions::default()).unwrap();
+        assert_same_tree(&dir, &dest.join("photos"));
+
+        let mut forced = Vec::new();
+        let options = EncodeOptions::default().force_compress(true);
+        encode_dir(&dir, &mut forced, &options).unwrap();
+        assert!(!contains_photo(&forced));
+        assert!(forced.len() < encoded.len());
+        std::fs::remove_dir_all(&base).unwrap();
+    }
+
+    #[test]
+    fn only_decodes_matching_frames() {
+        let base = temp_path("only");
+        let dir = base.join("picked");
+        std::fs::create_dir_all(&dir).unwrap();
+        // Stored files start and end frames, so each of these is in its own
+        std::fs::write(dir.join("a.zip"), b"not really a zip".repeat(100)).unwrap();
+        std::fs::write(dir.join("b.txt"), b"compressed between them".repeat(100)).unwrap();
+        std::fs::write(dir.join("c.zip"), b"not a zip either".repeat(100)).unwrap();
+        let mut encoded = Vec::new();
+        let summary = encode_dir(&dir, &mut encoded, &EncodeOptions::default()).unwrap();
+        assert_eq!(summary.output_bytes, encoded.len() as u64);
+        let (_, index) = read_index(&mut Cursor::new(&encoded), usize::MAX)
+            .unwrap()
+            .unwrap();
+        let paths: Vec<_> = index.iter().map(|entry| entry.path.as_str()).collect();
+        assert!(paths.contains(&"picked/b.txt"));
+        assert_eq!(paths.len(), 4);
+
+        // Breaking the frames of the others leaves the matching entry to be extracted
+        let skipped = index.iter().filter(|entry| entry.path.ends_with(".zip"));
+        for entry in skipped {
+            encoded[entry.frame as usize] ^= 0xff;
+        }
+        let dest = base.join("extracted");
+        let patterns = [glob::Pattern::new("*/b.txt").unwrap()];
+        let options = DecodeOptions::default();
+        let decoded =
+            decode_matching_to_dir(Cursor::new(&encoded), &dest, &patterns, &options).unwrap();
+        assert!(decoded.output_bytes < summary.input_bytes);
+        assert_eq!(
+            std::fs::read(dest.join("picked/b.txt")).unwrap(),
+            std::fs::read(dir.join("b.txt")).unwrap()
+        );
+        assert_eq!(std::fs::read_dir(dest.join("picked")).unwrap().count(), 1);
+
+        // Without the index, the whole stream is read, and stops at the first frame broken
+        let end = encoded.len() - header::INDEX_MAGIC.len() - 8 - rmp_len(&index);
+        assert!(header::starts_index(&encoded[end..][..4]));
+        let dest = base.join("unindexed");
+        let unindexed = Cursor::new(&encoded[..end]);
+        let _ = decode_matching_to_dir(unindexed, &dest, &patterns, &options);
+        assert!(!dest.join("picked/b.txt").exists());
+
+        std::fs::remove_dir_all(&base).unwrap();
+    }
+
+    fn rmp_len(index: &[IndexEntry]) -> usize {
+        rmp_serde::to_vec(index).unwrap().len()
+    }
+
+    #[test]
+    fn append() {
+        let base = temp_path("append");
+        let dir = base.join("first");
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("one.txt"), b"one ".repeat(100)).unwrap();
+        let later = base.join("later");
+        std::fs::create_dir_all(later.join("inner")).unwrap();
+        std::fs::write(later.join("two.txt"), b"two ".repeat(100)).unwrap();
+        std::fs::write(later.join("inner/three.txt"), b"three").unwrap();
+
+        let archive = base.join("first.tar.lizard");
+        let file = std::fs::File::create(&archive).unwrap();
+        encode_dir(&dir, file, &EncodeOptions::default()).unwrap();
+        let before = std::fs::read(&archive).unwrap();
+
+        // Nothing is added when any of the paths can't be
+        let bad = [later.join("two.txt"), base.join("first/../first/one.txt")];
+        assert!(append_to(&archive, &bad, &EncodeOptions::default()).is_err());
+        assert_eq!(std::fs::read(&archive).unwrap(), before);
+        // Including when some of them were, over the index
+        let missing = [later.join("two.txt"), later.join("missing")];
+        assert!(append_to(&archive, &missing, &EncodeOptions::default()).is_err());
+        assert_eq!(std::fs::read(&archive).unwrap(), before);
+        let (index_start, index) = read_index(&mut Cursor::new(&before), usize::MAX)
+            .unwrap()
+            .unwrap();
+
+        // Absolute paths are added relative to the root
+        let paths = [later.join("two.txt"), later.join("./inner")];
+        append_to(&archive, &paths, &EncodeOptions::default()).unwrap();
+        let encoded = std::fs::read(&archive).unwrap();
+        let index_start = index_start as usize;
+        assert_eq!(&encoded[..index_start], &before[..index_start]);
+        let (_, appended) = read_index(&mut Cursor::new(&encoded), usize::MAX)
+            .unwrap()
+            .unwrap();
+        assert_eq!(appended[..index.len()], index);
+        assert_eq!(appended.len(), index.len() + 3);
+
+        let dest = base.join("extracted");
+        decode_to_dir(&encoded[..], &dest, &DecodeOptions::default()).unwrap();
+        assert_same_tree(&dir, &dest.join("first"));
+        assert_same_tree(&later, &dest.join(later.strip_prefix("/").unwrap()));
+
+        // Picking out entries, from either tar stream
+        let dest = base.join("only");
+        let patterns = ["first/*.txt", "**/inner/*"].map(|p| glob::Pattern::new(p).unwrap());
+        let encoded = Cursor::new(encoded);
+        decode_matching_to_dir(encoded.clone(), &dest, &patterns, &DecodeOptions::default())
+            .unwrap();
+        let encoded = encoded.into_inner();
+        let mut extracted = Vec::new();
+        let mut dirs = vec![dest.clone()];
+        while let Some(dir) = dirs.pop() {
+            for entry in std::fs::read_dir(dir).unwrap() {
+                let path = entry.unwrap().path();
+                match path.is_dir() {
+                    true => dirs.push(path),
+                    false => extracted.push(path.strip_prefix(&dest).unwrap().to_path_buf()),
+                }
+            }
+        }
+        extracted.sort();
+        let inner = later.join("inner/three.txt");
+        let inner = inner.strip_prefix("/").unwrap().to_path_buf();
+        assert_eq!(extracted, [Path::new("first/one.txt").to_path_buf(), inner]);
+
+        // Nothing can be added after a signature, as it wouldn't cover it
+        let mut signed = encoded.clone();
+        signed.extend(header::SIGNATURE_MAGIC);
+        signed.resize(encoded.len() + header::SIGNATURE_TRAILER_LEN, 0);
+        std::fs::write(&archive, &signed).unwrap();
+        assert!(append_to(&archive, &paths, &EncodeOptions::default()).is_err());
+        assert_eq!(std::fs::read(&archive).unwrap(), signed);
+        std::fs::remove_dir_all(&base).unwrap();
+    }
+
+    #[test]
+    fn update_archive() {
+        let base = temp_path("update");
+        let dir = base.join("tree");
+        std::fs::create_dir_all(dir.join("nested")).unwrap();
+        std::fs::write(dir.join("same.txt"), b"stays the same ".repeat(500)).unwrap();
+        std::fs::write(dir.join("nested/edited.txt"), b"before the edit").unwrap();
+        std::fs::write(dir.join("gone.txt"), b"about to go").unwrap();
+        // Stored, so in frames of its own which can be copied over
+        std::fs::write(dir.join("photo.jpg"), b"not really a jpeg ".repeat(50)).unwrap();
+        let archive = base.join("tree.tar.lizard");
+        let options = EncodeOptions::default();
+        encode_dir(&dir, std::fs::File::create(&archive).unwrap(), &options).unwrap();
+        let before = std::fs::read(&archive).unwrap();
+
+        // Nothing to do
+        let summary = update(&archive, &dir, &options).unwrap();
+        assert!(summary.unchanged());
+        assert_eq!(None, summary.encoded);
+        assert_eq!(std::fs::read(&archive).unwrap(), before);
+
+        // The same length, so it's the contents that have to be compared
+        std::fs::write(dir.join("nested/edited.txt"), b"after  the edit").unwrap();
+        std::fs::remove_file(dir.join("gone.txt")).unwrap();
+        std::fs::write(dir.join("new.txt"), b"new").unwrap();
+        let summary = update(&archive, &dir, &options).unwrap();
+        assert_eq!(vec!["tree/nested/edited.txt"], summary.changed);
+        assert_eq!(vec!["tree/new.txt"], summary.added);
+        assert_eq!(vec!["tree/gone.txt"], summary.removed);
+        assert!(summary.encoded.is_some());
+        let dest = base.join("extracted");
+        let encoded = std::fs::read(&archive).unwrap();
+        decode_to_dir(&encoded[..], &dest, &DecodeOptions::default()).unwrap();
+        assert_same_tree(&dir, &dest.join("tree"));
+        assert!(!dest.join("tree/gone.txt").exists());
+        // The stored file's frames were copied as they were, and the index has what's left
+        let (_, index) = read_index(&mut Cursor::new(&before), usize::MAX)
+            .unwrap()
+            .unwrap();
+        let photo = index.iter().find(|entry| entry.path == "tree/photo.jpg");
+        let photo = photo.unwrap().frame as usize;
+        assert!(summary.copied_bytes > 0);
+        let copied = &before[photo..][..summary.copied_bytes as usize];
+        assert!(encoded.windows(copied.len()).any(|window| window == copied));
+        let (_, index) = read_index(&mut Cursor::new(&encoded), usize::MAX)
+            .unwrap()
+            .unwrap();
+        assert_eq!(index.len(), 6);
+        assert!(index.iter().all(|entry| entry.path != "tree/gone.txt"));
+        let dest = base.join("only");
+        let patterns = [glob::Pattern::new("*/*.jpg").unwrap()];
+        let decode_options = DecodeOptions::default();
+        decode_matching_to_dir(Cursor::new(&encoded), &dest, &patterns, &decode_options).unwrap();
+        assert_eq!(
+            std::fs::read(dest.join("tree/photo.jpg")).unwrap(),
+            std::fs::read(dir.join("photo.jpg")).unwrap()
+        );
+        assert!(update(&archive, &dir, &options).unwrap().unchanged());
+        assert!(!base.join("tree.tar.lizard.update").exists());
+
+        // Entries appended since are compared by their latest version, and dropped by updating
+        std::fs::write(base.join("extra.txt"), b"extra").unwrap();
+        append_to(&archive, &[base.join("extra.txt")], &options).unwrap();
+        let summary = update(&archive, &dir, &options).unwrap();
+        assert_eq!(1, summary.removed.len());
+        assert!(summary.removed[0].ends_with("extra.txt"));
+        assert!(update(&archive, &dir, &options).unwrap().unchanged());
+        std::fs::remove_dir_all(&base).unwrap();
+    }
+}
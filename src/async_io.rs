@@ -0,0 +1,568 @@
+//! tokio [AsyncWrite]/[AsyncRead] wrappers around the encoder and decoder, for the async
+//! feature. Neither does any IO of its own, and both only take more once what they've made has
+//! been passed on, so a slow reader or writer on the other side holds things up rather than
+//! memory piling up. The encoder hands bytes to the synchronous core a segment at a time between
+//! polls, so encoding a frame takes up the task for that long. The decoder runs on a thread of
+//! its own, see [AsyncLizardDecoder]
+
+use std::collections::VecDeque;
+use std::io::{BufReader, ErrorKind, Read, Write};
+use std::panic::AssertUnwindSafe;
+use std::pin::Pin;
+use std::sync::{Arc, Condvar, Mutex, MutexGuard};
+use std::task::{ready, Context, Poll, Waker};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+
+use crate::decode::{self, DecodeOptions};
+use crate::encode::{EncodeOptions, StreamEncoder};
+use crate::error::{Error, Result};
+use crate::format::Format;
+use crate::header;
+
+/// How much is asked of the inner reader at once
+const READ_CHUNK_LEN: usize = 1 << 13;
+
+/// Encodes everything written to it to [inner], as a [StreamEncoder] would. Nothing is
+/// complete until [AsyncWriteExt::shutdown](tokio::io::AsyncWriteExt::shutdown), which
+/// encodes the last frame
+pub struct AsyncLizardEncoder<W> {
+    inner: W,
+    /// Writes its frames to the Vec, from which they're passed on to [inner]
+    encoder: StreamEncoder<Vec<u8>>,
+    /// How much of the encoder's output [inner] has taken
+    written: usize,
+    ended: bool,
+}
+
+impl<W: AsyncWrite + Unpin> AsyncLizardEncoder<W> {
+    pub fn new(inner: W, options: &EncodeOptions) -> Self {
+        Self {
+            inner,
+            encoder: StreamEncoder::new(Vec::new(), options),
+            written: 0,
+            ended: false,
+        }
+    }
+
+    /// See [StreamEncoder::segment_len]
+    pub fn segment_len(mut self, segment_len: usize) -> Self {
+        self.encoder = self.encoder.segment_len(segment_len);
+        self
+    }
+
+    /// See [StreamEncoder::shared_window]
+    pub fn shared_window(mut self, shared_window: bool) -> Self {
+        self.encoder = self.encoder.shared_window(shared_window);
+        self
+    }
+
+    pub fn get_ref(&self) -> &W {
+        &self.inner
+    }
+
+    pub fn get_mut(&mut self) -> &mut W {
+        &mut self.inner
+    }
+
+    pub fn into_inner(self) -> W {
+        self.inner
+    }
+
+    /// Pass everything encoded so far on to [inner]
+    fn poll_write_encoded(&mut self, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        let encoded = self.encoder.get_mut();
+        while self.written < encoded.len() {
+            let written =
+                ready!(Pin::new(&mut self.inner).poll_write(cx, &encoded[self.written..]))?;
+            if written == 0 {
+                return Poll::Ready(Err(std::io::ErrorKind::WriteZero.into()));
+            }
+            self.written += written;
+        }
+        encoded.clear();
+        self.written = 0;
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<W: AsyncWrite + Unpin> AsyncWrite for AsyncLizardEncoder<W> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<std::io::Result<usize>> {
+        let this = self.get_mut();
+        ready!(this.poll_write_encoded(cx))?;
+        if this.ended {
+            return Poll::Ready(Err(std::io::Error::other("Written to after shutdown")));
+        }
+        Poll::Ready(this.encoder.write(buf))
+    }
+
+    /// Doesn't end the frame being written, see [StreamEncoder]'s flush
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        let this = self.get_mut();
+        ready!(this.poll_write_encoded(cx))?;
+        Pin::new(&mut this.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        let this = self.get_mut();
+        if !this.ended {
+            this.encoder.end()?;
+            this.ended = true;
+        }
+        ready!(this.poll_write_encoded(cx))?;
+        Pin::new(&mut this.inner).poll_shutdown(cx)
+    }
+}
+
+/// Decodes the lizards stream read from [inner]. The decoding happens on a thread of its own,
+/// which takes what's been read as it needs it and gives back what it's decoded, so the task
+/// polling this is only held up by reads of [inner]. At most [PIPE_LEN] bytes are held either
+/// side of the decoder: it waits once that much hasn't been read from here, and [inner] isn't
+/// read from while that much is waiting to be decoded. Version 1 and 2 streams are refused with
+/// [Error::UnsupportedVersion], as they don't mark where they end
+pub struct AsyncLizardDecoder<R> {
+    inner: R,
+    decoding: DecodeThread,
+}
+
+/// How much decoded output is held for the reader, or input for the decoder, before the side
+/// giving it has to wait for the other to take some
+const PIPE_LEN: usize = 1 << 16;
+
+/// What's passed between an [AsyncLizardDecoder] and its [DecodeThread]
+#[derive(Default)]
+struct Pipe {
+    /// Read from the inner reader, for the decoder to take
+    input: VecDeque<u8>,
+    /// The inner reader has nothing more to give
+    input_ended: bool,
+    /// Decoded, for [AsyncLizardDecoder::poll_read] to give out
+    output: VecDeque<u8>,
+    /// How the decoding went, once it's over
+    result: Option<Result<()>>,
+    /// The [AsyncLizardDecoder] has gone, so the decoder should give up
+    closed: bool,
+    /// Of the task to wake when the decoder takes input, gives output or finishes
+    waker: Option<Waker>,
+}
+
+impl Pipe {
+    fn wake(&mut self) {
+        if let Some(waker) = self.waker.take() {
+            waker.wake();
+        }
+    }
+}
+
+#[derive(Default)]
+struct Shared {
+    pipe: Mutex<Pipe>,
+    /// Notified whenever either side changes [pipe], for the decoder to wait on
+    changed: Condvar,
+}
+
+impl Shared {
+    fn lock(&self) -> MutexGuard<'_, Pipe> {
+        self.pipe
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    /// Wait for [changed], e.g. for there to be input, as the decoder
+    fn wait<'a>(&self, pipe: MutexGuard<'a, Pipe>) -> MutexGuard<'a, Pipe> {
+        self.changed
+            .wait(pipe)
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+}
+
+/// The thread an [AsyncLizardDecoder] decodes on, told to give up when this is dropped
+struct DecodeThread(Arc<Shared>);
+
+impl DecodeThread {
+    fn spawn(options: DecodeOptions) -> Self {
+        let shared = Arc::new(Shared::default());
+        let decoder = Arc::clone(&shared);
+        std::thread::spawn(move || {
+            // Otherwise the reader would wait forever for a result
+            let result =
+                std::panic::catch_unwind(AssertUnwindSafe(|| decode_piped(&decoder, &options)))
+                    .unwrap_or_else(|_| {
+                        Err(Error::Io(std::io::Error::other("The decoder panicked")))
+                    });
+            let mut pipe = decoder.lock();
+            pipe.result = Some(result);
+            pipe.wake();
+        });
+        Self(shared)
+    }
+}
+
+impl Drop for DecodeThread {
+    fn drop(&mut self) {
+        self.0.lock().closed = true;
+        self.0.changed.notify_all();
+    }
+}
+
+/// Decode the stream given to [shared]'s pipe, giving back the output through it
+fn decode_piped(shared: &Arc<Shared>, options: &DecodeOptions) -> Result<()> {
+    // The decoder reads a byte at a time, which shouldn't each take the lock
+    let mut reader = BufReader::new(PipeReader(Arc::clone(shared)));
+    let mut prefix = Vec::with_capacity(header::VERSIONED_PREFIX_LEN);
+    (&mut reader)
+        .take(header::VERSIONED_PREFIX_LEN as u64)
+        .read_to_end(&mut prefix)?;
+    // Without a trailer the end of the input is the end of the stream, and a stream cut short
+    // can't be told from a whole one
+    if let Some(version) = header::frame_version(&prefix) {
+        if !Format::for_version(version)?.trailer {
+            return Err(Error::UnsupportedVersion(version));
+        }
+    }
+    let writer = PipeWriter(Arc::clone(shared));
+    decode::decode_to_writer_with_options(prefix.chain(reader), writer, options)?;
+    Ok(())
+}
+
+fn broken_pipe() -> std::io::Error {
+    std::io::Error::new(ErrorKind::BrokenPipe, "The async decoder was dropped")
+}
+
+/// Gives the decoder the input read into the pipe, waiting for it to be read
+struct PipeReader(Arc<Shared>);
+
+impl Read for PipeReader {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let mut pipe = self.0.lock();
+        while pipe.input.is_empty() && !pipe.input_ended && !pipe.closed {
+            pipe = self.0.wait(pipe);
+        }
+        if pipe.closed {
+            return Err(broken_pipe());
+        }
+        let len = buf.len().min(pipe.input.len());
+        buf.iter_mut()
+            .zip(pipe.input.drain(..len))
+            .for_each(|(to, from)| *to = from);
+        pipe.wake();
+        Ok(len)
+    }
+}
+
+/// Puts the decoder's output in the pipe, waiting while it's full
+struct PipeWriter(Arc<Shared>);
+
+impl Write for PipeWriter {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let mut pipe = self.0.lock();
+        while pipe.output.len() >= PIPE_LEN && !pipe.closed {
+            pipe = self.0.wait(pipe);
+        }
+        if pipe.closed {
+            return Err(broken_pipe());
+        }
+        let len = buf.len().min(PIPE_LEN - pipe.output.len());
+        pipe.output.extend(&buf[..len]);
+        pipe.wake();
+        Ok(len)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+impl<R: AsyncRead + Unpin> AsyncLizardDecoder<R> {
+    pub fn new(inner: R) -> Self {
+        Self::with_options(inner, &DecodeOptions::default())
+    }
+
+    /// Decode within [options]' limits. The stream is always decoded on a single thread
+    pub fn with_options(inner: R, options: &DecodeOptions) -> Self {
+        Self {
+            inner,
+            decoding: DecodeThread::spawn(options.clone().threads(1)),
+        }
+    }
+
+    pub fn get_ref(&self) -> &R {
+        &self.inner
+    }
+
+    pub fn get_mut(&mut self) -> &mut R {
+        &mut self.inner
+    }
+
+    pub fn into_inner(self) -> R {
+        self.inner
+    }
+}
+
+impl<R: AsyncRead + Unpin> AsyncRead for AsyncLizardDecoder<R> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        let this = self.get_mut();
+        let shared = &this.decoding.0;
+        loop {
+            let mut pipe = shared.lock();
+            if !pipe.output.is_empty() {
+                let len = buf.remaining().min(pipe.output.len());
+                let (front, back) = pipe.output.as_slices();
+                let from_front = len.min(front.len());
+                buf.put_slice(&front[..from_front]);
+                buf.put_slice(&back[..len - from_front]);
+                pipe.output.drain(..len);
+                shared.changed.notify_all();
+                return Poll::Ready(Ok(()));
+            }
+            // Anything after an error reads as the end
+            if let Some(result) = &mut pipe.result {
+                let result = std::mem::replace(result, Ok(()));
+                return Poll::Ready(result.map_err(Into::into));
+            }
+            // Anything the decoder does from here on wakes the task
+            pipe.waker = Some(cx.waker().clone());
+            if pipe.input_ended || pipe.input.len() >= PIPE_LEN {
+                return Poll::Pending;
+            }
+            drop(pipe);
+
+            let mut chunk = [0u8; READ_CHUNK_LEN];
+            let mut chunk = ReadBuf::new(&mut chunk);
+            ready!(Pin::new(&mut this.inner).poll_read(cx, &mut chunk))?;
+            let mut pipe = shared.lock();
+            match chunk.filled() {
+                [] => pipe.input_ended = true,
+                filled => pipe.input.extend(filled),
+            }
+            shared.changed.notify_all();
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::pin::Pin;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+    use std::task::{Context, Poll};
+    use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, ReadBuf};
+
+    use crate::async_io::{AsyncLizardDecoder, AsyncLizardEncoder, PIPE_LEN};
+    use crate::decode::{decode_bytes, DecodeOptions};
+    use crate::encode::{encode_from_reader, EncodeOptions};
+    use crate::Error;
+
+    /// Takes or gives at most a few bytes at a time, and is only ready every other poll, like
+    /// a slow socket
+    struct Trickle<T> {
+        inner: T,
+        ready: bool,
+    }
+
+    impl<T> Trickle<T> {
+        fn new(inner: T) -> Self {
+            Self {
+                inner,
+                ready: false,
+            }
+        }
+
+        fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<()> {
+            self.ready = !self.ready;
+            match self.ready {
+                true => Poll::Ready(()),
+                false => {
+                    cx.waker().wake_by_ref();
+                    Poll::Pending
+                }
+            }
+        }
+    }
+
+    impl AsyncRead for Trickle<&[u8]> {
+        fn poll_read(
+            mut self: Pin<&mut Self>,
+            cx: &mut Context<'_>,
+            buf: &mut ReadBuf<'_>,
+        ) -> Poll<std::io::Result<()>> {
+            std::task::ready!(self.poll_ready(cx));
+            let len = buf.remaining().min(self.inner.len()).min(7);
+            buf.put_slice(&self.inner[..len]);
+            self.inner = &self.inner[len..];
+            Poll::Ready(Ok(()))
+        }
+    }
+
+    impl AsyncWrite for Trickle<Vec<u8>> {
+        fn poll_write(
+            mut self: Pin<&mut Self>,
+            cx: &mut Context<'_>,
+            buf: &[u8],
+        ) -> Poll<std::io::Result<usize>> {
+            std::task::ready!(self.poll_ready(cx));
+            let len = buf.len().min(7);
+            self.inner.extend_from_slice(&buf[..len]);
+            Poll::Ready(Ok(len))
+        }
+
+        fn poll_flush(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+            Poll::Ready(Ok(()))
+        }
+
+        fn poll_shutdown(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+            Poll::Ready(Ok(()))
+        }
+    }
+
+    fn text() -> Vec<u8> {
+        (0..2000)
+            .flat_map(|i| format!("line {} of some text, ", i % 37).into_bytes())
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn round_trip() {
+        let input = text();
+        for shared_window in [false, true] {
+            let mut encoder =
+                AsyncLizardEncoder::new(Trickle::new(Vec::new()), &EncodeOptions::default())
+                    .segment_len(10_000)
+                    .shared_window(shared_window);
+            for piece in input.chunks(999) {
+                encoder.write_all(piece).await.unwrap();
+            }
+            encoder.shutdown().await.unwrap();
+            let encoded = encoder.into_inner().inner;
+            assert!(encoded.len() < input.len() / 4);
+            assert_eq!(decode_bytes(&encoded).unwrap(), input);
+
+            let mut decoder = AsyncLizardDecoder::new(Trickle::new(&encoded[..]));
+            let mut decoded = Vec::new();
+            decoder.read_to_end(&mut decoded).await.unwrap();
+            assert_eq!(decoded, input);
+        }
+    }
+
+    #[tokio::test]
+    async fn whole_streams() {
+        // Nothing written still makes a stream, of nothing
+        let mut encoder = AsyncLizardEncoder::new(Vec::new(), &EncodeOptions::default());
+        encoder.shutdown().await.unwrap();
+        let encoded = encoder.into_inner();
+        assert_eq!(decode_bytes(&encoded).unwrap(), b"");
+        let mut decoded = Vec::new();
+        AsyncLizardDecoder::new(&encoded[..])
+            .read_to_end(&mut decoded)
+            .await
+            .unwrap();
+        assert!(decoded.is_empty());
+
+        // A stream encoded all at once, with junk after it
+        let input = text();
+        let mut encoded = Vec::new();
+        encode_from_reader(&input[..], &mut encoded, &EncodeOptions::default()).unwrap();
+        let whole_len = encoded.len();
+        encoded.extend_from_slice(b"garbage");
+        let mut decoded = Vec::new();
+        AsyncLizardDecoder::new(Trickle::new(&encoded[..]))
+            .read_to_end(&mut decoded)
+            .await
+            .unwrap();
+        assert_eq!(decoded, input);
+
+        // Cut short, or not a stream at all
+        for bad in [&encoded[..whole_len - 1], b"", b"garbage"] {
+            let mut decoded = Vec::new();
+            let result = AsyncLizardDecoder::new(bad).read_to_end(&mut decoded).await;
+            assert!(result.is_err());
+        }
+
+        // Limits hold across frames
+        let mut encoder =
+            AsyncLizardEncoder::new(Vec::new(), &EncodeOptions::default()).segment_len(1000);
+        encoder.write_all(&input).await.unwrap();
+        encoder.shutdown().await.unwrap();
+        let encoded = encoder.into_inner();
+        let options = DecodeOptions::default().max_output_size(input.len() as u64 - 1);
+        let mut decoded = Vec::new();
+        let error = AsyncLizardDecoder::with_options(&encoded[..], &options)
+            .read_to_end(&mut decoded)
+            .await
+            .unwrap_err();
+        let error = error.into_inner().unwrap().downcast::<Error>().unwrap();
+        assert!(matches!(*error, Error::LimitExceeded { .. }), "{:?}", error);
+    }
+
+    #[tokio::test]
+    async fn trailerless_streams_are_refused() {
+        // Version 1 streams just stop, so one cut short would look whole
+        let v1_file = include_bytes!("../testfile.lizard");
+        assert!(decode_bytes(v1_file).is_ok());
+        let mut decoded = Vec::new();
+        let error = AsyncLizardDecoder::new(Trickle::new(&v1_file[..]))
+            .read_to_end(&mut decoded)
+            .await
+            .unwrap_err();
+        let error = error.into_inner().unwrap().downcast::<Error>().unwrap();
+        assert!(
+            matches!(*error, Error::UnsupportedVersion(1)),
+            "{:?}",
+            error
+        );
+        assert!(decoded.is_empty());
+    }
+
+    #[tokio::test]
+    async fn backpressure() {
+        /// Counts how much has been read through it
+        struct Counting<'a> {
+            inner: &'a [u8],
+            read: Arc<AtomicUsize>,
+        }
+
+        impl AsyncRead for Counting<'_> {
+            fn poll_read(
+                mut self: Pin<&mut Self>,
+                _: &mut Context<'_>,
+                buf: &mut ReadBuf<'_>,
+            ) -> Poll<std::io::Result<()>> {
+                let len = buf.remaining().min(self.inner.len());
+                buf.put_slice(&self.inner[..len]);
+                self.inner = &self.inner[len..];
+                self.read.fetch_add(len, Ordering::Relaxed);
+                Poll::Ready(Ok(()))
+            }
+        }
+
+        // Stored, so it's quick to encode a stream much bigger than the pipes
+        let input = text().repeat(40);
+        let options = EncodeOptions::default().algorithm(crate::Algorithm::Stored);
+        let mut encoded = Vec::new();
+        encode_from_reader(&input[..], &mut encoded, &options).unwrap();
+
+        // Nothing more is read than it takes to fill the pipes, however long that's left
+        let read = Arc::new(AtomicUsize::new(0));
+        let mut decoder = AsyncLizardDecoder::new(Counting {
+            inner: &encoded,
+            read: Arc::clone(&read),
+        });
+        let mut start = [0; 100];
+        decoder.read_exact(&mut start).await.unwrap();
+        assert_eq!(start, input[..100]);
+        std::thread::sleep(std::time::Duration::from_millis(100));
+        assert!(read.load(Ordering::Relaxed) < 4 * PIPE_LEN);
+
+        let mut decoded = start.to_vec();
+        decoder.read_to_end(&mut decoded).await.unwrap();
+        assert_eq!(decoded, input);
+        assert_eq!(read.load(Ordering::Relaxed), encoded.len());
+    }
+}
@@ -0,0 +1,211 @@
+//! Reading and writing streams of bits, most significant bit of each byte first
+
+use std::io::{ErrorKind, Read, Write};
+
+use crate::huffman::Bits;
+
+/// Packs bits into bytes, writing each byte to [output] as soon as it's filled, so only the
+/// last partial byte is ever held
+pub struct BitWriter<W: Write> {
+    output: W,
+    /// The low [pending_len] bits are waiting for the rest of their byte
+    pending: u64,
+    pending_len: u32,
+}
+
+impl<W: Write> BitWriter<W> {
+    pub fn new(output: W) -> Self {
+        Self {
+            output,
+            pending: 0,
+            pending_len: 0,
+        }
+    }
+
+    pub fn write_bit(&mut self, bit: bool) -> std::io::Result<()> {
+        self.write_bits(&Bits::from_iter([bit]))
+    }
+
+    pub fn write_bits(&mut self, bits: &Bits) -> std::io::Result<()> {
+        // At most 128 bits plus the pending ones
+        let mut bytes = [0u8; 17];
+        let mut filled = 0;
+        let mut len = bits.len() as u32;
+        // 56 bits at a time, so they always fit in [pending] alongside a partial byte
+        while len > 0 {
+            let take = len.min(56);
+            len -= take;
+            let chunk = (bits.value() >> len) as u64 & ((1 << take) - 1);
+            self.pending = (self.pending << take) | chunk;
+            self.pending_len += take;
+            while self.pending_len >= 8 {
+                self.pending_len -= 8;
+                bytes[filled] = (self.pending >> self.pending_len) as u8;
+                filled += 1;
+            }
+            self.pending &= (1 << self.pending_len) - 1;
+        }
+        self.output.write_all(&bytes[..filled])
+    }
+
+    /// How many bits have been written to the current, unfinished, byte
+    pub fn pending_bits(&self) -> usize {
+        self.pending_len as usize
+    }
+
+    pub fn get_ref(&self) -> &W {
+        &self.output
+    }
+
+    /// Bytes can be taken out of the writer's output, since any written there are finished
+    pub fn get_mut(&mut self) -> &mut W {
+        &mut self.output
+    }
+
+    /// Fill the rest of the current byte with the start of [fill], then zeros if that runs
+    /// out, and write it. Nothing is written if the bits so far make whole bytes
+    pub fn finish_with(mut self, fill: &Bits) -> std::io::Result<W> {
+        if self.pending_len > 0 {
+            let free = 8 - self.pending_len;
+            let fill_len = fill.len() as u32;
+            let fill_bits = match fill_len >= free {
+                true => (fill.value() >> (fill_len - free)) as u64,
+                false => (fill.value() as u64) << (free - fill_len),
+            };
+            let byte = (self.pending << free) | fill_bits;
+            self.output.write_all(&[byte as u8])?;
+        }
+        Ok(self.output)
+    }
+
+    /// Pad the current byte with zeros, and give back the output
+    pub fn into_inner(self) -> std::io::Result<W> {
+        self.finish_with(&Bits::new())
+    }
+}
+
+/// Reads bits back out of bytes from [input], a byte at a time, so an unbuffered [input] wants
+/// wrapping in a [std::io::BufReader]. Iterating gives each bit, ending where [input] does
+pub struct BitReader<R: Read> {
+    input: R,
+    current_byte: u8,
+    /// How many bits of [current_byte] are still to be read, from the most significant
+    bits_left: u32,
+}
+
+impl<R: Read> BitReader<R> {
+    pub fn new(input: R) -> Self {
+        Self {
+            input,
+            current_byte: 0,
+            bits_left: 0,
+        }
+    }
+
+    /// The next bit, or None if [input] has ended
+    pub fn read_bit(&mut self) -> std::io::Result<Option<bool>> {
+        if self.bits_left == 0 {
+            let mut byte = [0u8];
+            loop {
+                match self.input.read(&mut byte) {
+                    Ok(0) => return Ok(None),
+                    Ok(_) => break,
+                    Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+                    Err(e) => return Err(e),
+                }
+            }
+            self.current_byte = byte[0];
+            self.bits_left = 8;
+        }
+        self.bits_left -= 1;
+        Ok(Some((self.current_byte >> self.bits_left) & 1 == 1))
+    }
+
+    /// The next [count] bits as a number, the first read being the most significant. [count]
+    /// can be up to 64. Fails with [ErrorKind::UnexpectedEof] if [input] ends first
+    pub fn read_bits(&mut self, count: u32) -> std::io::Result<u64> {
+        assert!(count <= u64::BITS, "Can read at most 64 bits at once");
+        let mut value = 0u64;
+        for _ in 0..count {
+            let Some(bit) = self.read_bit()? else {
+                return Err(std::io::Error::new(
+                    ErrorKind::UnexpectedEof,
+                    "Input ended part way through reading bits",
+                ));
+            };
+            value = (value << 1) | bit as u64;
+        }
+        Ok(value)
+    }
+
+    /// Bits left over from the byte being read are dropped
+    pub fn into_inner(self) -> R {
+        self.input
+    }
+}
+
+impl<R: Read> Iterator for BitReader<R> {
+    type Item = std::io::Result<bool>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.read_bit().transpose()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::{ErrorKind, Read};
+
+    use crate::bit_io::{BitReader, BitWriter};
+    use crate::huffman::Bits;
+
+    #[test]
+    fn bit_writer() {
+        let mut writer = BitWriter::new(Vec::new());
+        writer.write_bits(&Bits::from((0b101, 3))).unwrap();
+        writer.write_bit(true).unwrap();
+        assert_eq!(4, writer.pending_bits());
+        assert!(writer.get_ref().is_empty());
+        // Long enough to go through [pending] in two parts
+        let long: Bits = (0..99).map(|i| i % 2 == 0).collect();
+        writer.write_bits(&long).unwrap();
+        assert_eq!(12, writer.get_ref().len());
+        assert_eq!(7, writer.pending_bits());
+        assert_eq!(&[0b10111010, 0b10101010], &writer.get_ref()[..2]);
+        assert_eq!(0b10101010, writer.get_ref()[11]);
+        // Only room for the first bit of the fill
+        let output = writer.finish_with(&Bits::from((0b110, 3))).unwrap();
+        assert_eq!(0b10101011, output[12]);
+
+        // Already whole bytes, so there's nothing to fill
+        let mut writer = BitWriter::new(Vec::new());
+        writer.write_bits(&Bits::from((0xff, 8))).unwrap();
+        assert_eq!(vec![0xff], writer.finish_with(&Bits::from((1, 1))).unwrap());
+    }
+
+    #[test]
+    fn bit_reader() {
+        let mut reader = BitReader::new(&[0b10110000, 0b00001111][..]);
+        assert_eq!(Some(true), reader.read_bit().unwrap());
+        assert_eq!(0b011, reader.read_bits(3).unwrap());
+        assert_eq!(0b0000000011, reader.read_bits(10).unwrap());
+        assert_eq!(
+            vec![true, true],
+            reader.by_ref().map(|bit| bit.unwrap()).collect::<Vec<_>>()
+        );
+        assert_eq!(None, reader.read_bit().unwrap());
+        assert_eq!(
+            ErrorKind::UnexpectedEof,
+            reader.read_bits(1).unwrap_err().kind()
+        );
+
+        // Errors from the input come through rather than looking like it ended
+        struct Broken;
+        impl Read for Broken {
+            fn read(&mut self, _: &mut [u8]) -> std::io::Result<usize> {
+                Err(std::io::Error::other("broken"))
+            }
+        }
+        assert!(BitReader::new(Broken).next().unwrap().is_err());
+    }
+}
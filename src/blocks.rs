@@ -0,0 +1,363 @@
+//! Streams written with [crate::EncodeOptions::block_size] are made of blocks which decode on
+//! their own, each ended by a [ControlRecord::BlockCheck]. They can be split out of the stream
+//! without decoding anything, then decoded on several threads at once
+
+use log::debug;
+use std::collections::BTreeMap;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::sync::mpsc::{channel, sync_channel};
+use std::sync::{Arc, Mutex};
+
+use crate::control::{ControlRecord, Trailer, VarintReader};
+use crate::decode::{decode_following_frames, decode_stream, decode_stream_to, DecodeOptions};
+use crate::encode::Algorithm;
+use crate::error::{Error, Result};
+use crate::filter::{Filter, Unfilter};
+use crate::format::stored;
+use crate::header::Header;
+use crate::helpers::TailWriter;
+use crate::offset_len::OffsetLen;
+use crate::token::ChunkMarker;
+use crate::MAX_PRIMED_LEN;
+
+/// A block's tokens, along with what's needed to decode them on their own
+pub(crate) struct Block {
+    /// The stream's header, with whichever huffman tree is in use at the start of the block
+    pub header: Arc<Vec<u8>>,
+    pub tokens: Vec<u8>,
+    pub trailer: Trailer,
+}
+
+impl Block {
+    pub fn decode(&self, options: &DecodeOptions) -> Result<Vec<u8>> {
+        decode_block(&self.header, &self.tokens, self.trailer, options)
+    }
+}
+
+/// Whether the stream [header] starts can be read as blocks by [BlockReader]. A bitstream can't
+/// be split without decoding it, and before relative offsets blocks weren't written. A primed
+/// window needs a frame before this one
+pub(crate) fn splits_into_blocks(header: &Header) -> bool {
+    let format = header.format();
+    format.control_records
+        && format.relative_offsets
+        && header.algorithm() != Algorithm::Huffman
+        && header.primed_len() == 0
+}
+
+/// Reads the blocks of a stream one after another, without decoding them
+pub(crate) struct BlockReader<R: BufRead> {
+    reader: R,
+    /// The stream's header without its filter, as blocks are unfiltered as they're written,
+    /// the filter running across them. Its huffman tree is the one in use at the next block
+    header: Header,
+    header_bytes: Arc<Vec<u8>>,
+    index: usize,
+    /// The whole stream's, once its end has been read
+    pub trailer: Option<Trailer>,
+}
+
+impl<R: BufRead> BlockReader<R> {
+    /// Read the blocks following [header] in [reader]
+    pub fn new(reader: R, header: &[u8]) -> Result<Self> {
+        let header = Header::from_bytes(header)?.with_filter(Filter::None);
+        Ok(Self {
+            reader,
+            header_bytes: Arc::new(header.to_bytes()),
+            header,
+            index: 0,
+            trailer: None,
+        })
+    }
+
+    /// The next block, or None once the stream has ended. A stream written without a block
+    /// size is a single block, checked against the stream's trailer
+    pub fn next_block(&mut self) -> Result<Option<Block>> {
+        if self.trailer.is_some() {
+            return Ok(None);
+        }
+        let (tokens, record, new_tree) = read_block(&mut self.reader, self.header.format())?;
+        let trailer = match record {
+            ControlRecord::BlockCheck {
+                checksum,
+                decoded_len,
+                ..
+            } => Trailer {
+                len: decoded_len,
+                checksum,
+            },
+            ControlRecord::EndOfStream(trailer) if self.index == 0 => {
+                self.trailer = Some(trailer);
+                trailer
+            }
+            ControlRecord::EndOfStream(trailer) if tokens.is_empty() => {
+                self.trailer = Some(trailer);
+                return Ok(None);
+            }
+            _ => {
+                return Err(Error::InvalidControlRecord(String::from(
+                    "Tokens after the last block",
+                )))
+            }
+        };
+        let block = Block {
+            header: Arc::clone(&self.header_bytes),
+            tokens,
+            trailer,
+        };
+        self.index += 1;
+        // Later blocks start with the tree this one switched to
+        if let Some(tree) = new_tree {
+            self.header.huffman_tree = Some(tree);
+            self.header_bytes = Arc::new(self.header.to_bytes());
+        }
+        Ok(Some(block))
+    }
+}
+
+/// Decode [reader] with up to [DecodeOptions::threads] blocks decoded at once, giving the same
+/// result as [decode_stream_to]. Streams which can't be split up are decoded on this thread.
+/// Blocks are written to [writer] in order as they're decoded, so only those being decoded
+/// or waiting on an earlier one are held
+pub(crate) fn decode_stream_threaded<R: Read, W: Write>(
+    reader: &mut R,
+    writer: &mut W,
+    options: &DecodeOptions,
+) -> Result<Header> {
+    let threads = options.threads;
+    let mut reader = BufReader::new(reader);
+    let header_bytes = Header::read_bytes_from(&mut reader, options.max_header_size)?;
+    if stored::is_stored(&header_bytes) {
+        return decode_stream_to(&mut header_bytes.chain(reader), writer, options);
+    }
+    let header = Header::from_bytes(&header_bytes)?;
+    // A primed window fails in the sequential decode, as it needs a frame before this one
+    if !splits_into_blocks(&header) {
+        return decode_stream_to(&mut header_bytes.chain(reader), writer, options);
+    }
+    let mut writer = TailWriter::new(writer, MAX_PRIMED_LEN);
+    let mut output = BlockWriter {
+        writer: &mut writer,
+        unfilter: Unfilter::new(header.filter()),
+        waiting: BTreeMap::new(),
+        next: 0,
+        len: 0,
+        hasher: crc32fast::Hasher::new(),
+        options,
+    };
+
+    let (block_sender, block_receiver) = sync_channel::<(usize, Block)>(threads);
+    let block_receiver = Arc::new(Mutex::new(block_receiver));
+    // Unbounded, as this thread only takes from it between sending blocks. Sending is what
+    // holds back how many are in flight
+    let (decoded_sender, decoded_receiver) = channel::<(usize, Result<Vec<u8>>)>();
+    let (read_result, drained) = std::thread::scope(|scope| {
+        for _ in 0..threads {
+            let block_receiver = Arc::clone(&block_receiver);
+            let decoded_sender = decoded_sender.clone();
+            scope.spawn(move || loop {
+                let next = block_receiver.lock().unwrap().recv();
+                let Ok((index, block)) = next else { break };
+                let decoded = block.decode(options);
+                if decoded_sender.send((index, decoded)).is_err() {
+                    break;
+                }
+            });
+        }
+        drop(block_receiver);
+        drop(decoded_sender);
+
+        let read_result = (|| -> Result<Trailer> {
+            let mut blocks = BlockReader::new(&mut reader, &header_bytes)?;
+            for index in 0.. {
+                let Some(block) = blocks.next_block()? else {
+                    break;
+                };
+                let trailer = block.trailer;
+                if block_sender.send((index, block)).is_err() {
+                    return Ok(trailer);
+                }
+                output.write_decoded(decoded_receiver.try_iter())?;
+            }
+            Ok(blocks.trailer.unwrap())
+        })();
+        drop(block_sender);
+        (read_result, output.write_decoded(decoded_receiver.iter()))
+    });
+    // A block which failed to decode came before anything wrong found reading later ones
+    drained?;
+    let trailer = read_result?;
+    let checksum = output.hasher.clone().finalize();
+    if !output.waiting.is_empty() || output.len != trailer.len || checksum != trailer.checksum {
+        debug!("Decoded {} bytes, trailer says {}", output.len, trailer.len);
+        return Err(Error::VerificationFailed {
+            expected: trailer.checksum,
+            actual: checksum,
+        });
+    }
+    decode_following_frames(&mut reader, output.writer, options, output.len, None)?;
+    Ok(header)
+}
+
+/// Puts decoded blocks back in order, writing each as soon as those before it have been
+struct BlockWriter<'a, W: Write> {
+    writer: &'a mut W,
+    unfilter: Unfilter,
+    /// Decoded blocks which came back before an earlier one
+    waiting: BTreeMap<usize, Vec<u8>>,
+    /// Index of the block to write next
+    next: usize,
+    /// Of the blocks written so far, before unfiltering
+    len: u64,
+    hasher: crc32fast::Hasher,
+    options: &'a DecodeOptions,
+}
+
+impl<W: Write> BlockWriter<'_, W> {
+    fn write_decoded(
+        &mut self,
+        decoded: impl Iterator<Item = (usize, Result<Vec<u8>>)>,
+    ) -> Result<()> {
+        for (index, block) in decoded {
+            self.waiting.insert(index, block?);
+            while let Some(mut block) = self.waiting.remove(&self.next) {
+                self.len += block.len() as u64;
+                self.options.check_output_len(self.len)?;
+                self.hasher.update(&block);
+                self.unfilter.apply(&mut block);
+                self.writer.write_all(&block)?;
+                self.next += 1;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Decode one block's [tokens] as a stream of their own, checked against [trailer]
+pub(crate) fn decode_block(
+    block_header: &[u8],
+    tokens: &[u8],
+    trailer: Trailer,
+    options: &DecodeOptions,
+) -> Result<Vec<u8>> {
+    let mut stream = block_header.to_vec();
+    stream.extend_from_slice(tokens);
+    ControlRecord::EndOfStream(trailer).write_to(&mut stream)?;
+    decode_stream(&mut &stream[..], options).map(|(output, _)| output)
+}
+
+/// Read the next block's tokens, up to the block check or end of stream record ending it,
+/// without decoding them. Also gives back the last huffman tree switched to in the block
+fn read_block<R: BufRead>(
+    reader: &mut R,
+    format: crate::format::Format,
+) -> Result<(Vec<u8>, ControlRecord, Option<crate::huffman::HuffmanTree>)> {
+    let mut tokens = Vec::new();
+    let mut new_tree = None;
+    loop {
+        let start = tokens.len();
+        read_into(reader, &mut tokens, 1, "end of stream marker")?;
+        let v = tokens[start];
+        match v >> 6 {
+            0b11 => {
+                let len = ChunkMarker::from_encoded_u8(v).len as usize;
+                read_into(reader, &mut tokens, len, "raw byte chunk")?;
+            }
+            0b10 => {
+                let len = match format.binned_matches {
+                    true => {
+                        read_into(reader, &mut tokens, 1, "offset len")?;
+                        OffsetLen::binned_len(&tokens[start..]).unwrap()
+                    }
+                    false => OffsetLen::whole_bytes_len(v),
+                };
+                let remaining = len - (tokens.len() - start);
+                read_into(reader, &mut tokens, remaining, "offset len")?;
+            }
+            0b00 => {
+                let mut varint_reader = VarintReader::default();
+                let len = loop {
+                    read_into(reader, &mut tokens, 1, "control record")?;
+                    if let Some(len) = varint_reader.push(*tokens.last().unwrap())? {
+                        break len;
+                    }
+                };
+                let value_start = tokens.len();
+                read_into(reader, &mut tokens, len as usize, "control record")?;
+                match ControlRecord::from_parts(v, &tokens[value_start..])? {
+                    Some(record @ ControlRecord::BlockCheck { .. })
+                    | Some(record @ ControlRecord::EndOfStream(_)) => {
+                        tokens.truncate(start);
+                        return Ok((tokens, record, new_tree));
+                    }
+                    // Left in the block to switch to, but later blocks start with it too
+                    Some(ControlRecord::NewHuffmanTree(tree)) => new_tree = Some(tree),
+                    _ => (),
+                }
+            }
+            _ => return Err(Error::InvalidToken(v)),
+        }
+    }
+}
+
+/// Append exactly [len] more bytes from [reader] onto [buffer], or fail saying what was being
+/// read when the input ran out
+fn read_into<R: BufRead>(
+    reader: &mut R,
+    buffer: &mut Vec<u8>,
+    len: usize,
+    reading: &'static str,
+) -> Result<()> {
+    let read = reader.take(len as u64).read_to_end(buffer)?;
+    match read == len {
+        true => Ok(()),
+        false => Err(Error::TruncatedStream(reading)),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::blocks::decode_stream_threaded;
+    use crate::decode::{decode_bytes, DecodeOptions};
+    use crate::{encode, EncodeOptions, Filter};
+
+    #[test]
+    fn threaded_matches_sequential() {
+        let input = "The cat sat on the mat. The dog sat on the log. ".repeat(300);
+        for options in [
+            EncodeOptions::default().block_size(Some(1000)),
+            EncodeOptions::default()
+                .block_size(Some(777))
+                .filter(Filter::Delta8),
+            // A single block
+            EncodeOptions::default(),
+        ] {
+            let mut encoded = Vec::new();
+            encode::encode_from_reader(input.as_bytes(), &mut encoded, &options).unwrap();
+            assert_eq!(input.as_bytes(), decode_bytes(&encoded).unwrap());
+            for threads in [2, 4] {
+                let mut decoded = Vec::new();
+                let options = DecodeOptions::default().threads(threads);
+                decode_stream_threaded(&mut &encoded[..], &mut decoded, &options).unwrap();
+                assert_eq!(input.as_bytes(), decoded);
+            }
+        }
+    }
+
+    #[test]
+    fn errors_like_sequential() {
+        let input = "abcdefgh".repeat(1000);
+        let options = EncodeOptions::default().block_size(Some(500));
+        let mut encoded = Vec::new();
+        encode::encode_from_reader(input.as_bytes(), &mut encoded, &options).unwrap();
+        let options = DecodeOptions::default().threads(3);
+        let decode =
+            |encoded: &[u8]| decode_stream_threaded(&mut &encoded[..], &mut Vec::new(), &options);
+        for cut in [10, encoded.len() / 2, encoded.len() - 1] {
+            assert!(decode(&encoded[..cut]).is_err());
+        }
+        let middle = encoded.len() / 2;
+        encoded[middle] ^= 0xff;
+        assert!(decode(&encoded).is_err());
+    }
+}
@@ -0,0 +1,213 @@
+//! An experimental alternative to LZSS: each block of input is put through the Burrows-Wheeler
+//! transform, which groups bytes by the context following them, then move-to-front, which turns
+//! those groups into runs of small numbers, then run length encoding. The result is huffman
+//! coded as usual. Much slower than LZSS, but gives markedly better ratios on text
+use std::io::Read;
+
+use crate::error::{Error, Result};
+
+/// After this many of the same byte in a row, the next byte is how many more follow
+const RUN_THRESHOLD: usize = 4;
+
+/// A transformed block, ready to be huffman coded
+#[derive(Debug, PartialEq)]
+pub(crate) struct Block {
+    /// Where the input's first rotation ended up after sorting, needed to invert the transform
+    pub primary_index: u64,
+    pub data: Vec<u8>,
+}
+
+/// Read all of [reader], transforming it [block_size] bytes at a time
+pub(crate) fn transform_blocks<R: Read>(mut reader: R, block_size: usize) -> Result<Vec<Block>> {
+    let mut blocks = Vec::new();
+    loop {
+        let mut input = Vec::with_capacity(block_size);
+        (&mut reader)
+            .take(block_size as u64)
+            .read_to_end(&mut input)?;
+        if input.is_empty() {
+            return Ok(blocks);
+        }
+        let (last_column, primary_index) = bwt(&input);
+        blocks.push(Block {
+            primary_index: primary_index as u64,
+            data: run_length_encode(&move_to_front(&last_column)),
+        });
+    }
+}
+
+/// Undo everything [transform_blocks] did to one block
+pub(crate) fn invert_block(block: &Block) -> Result<Vec<u8>> {
+    let last_column = move_to_front_inverse(&run_length_decode(&block.data)?);
+    match usize::try_from(block.primary_index) {
+        Ok(primary_index) if primary_index < last_column.len() => {
+            Ok(bwt_inverse(&last_column, primary_index))
+        }
+        _ => Err(Error::InvalidControlRecord(format!(
+            "BWT primary index {} outside a {} byte block",
+            block.primary_index,
+            last_column.len()
+        ))),
+    }
+}
+
+/// Sort all rotations of [input], returning the last byte of each and where the unrotated
+/// input ended up. Rotations are sorted by prefix doubling: each pass sorts by the ranks of the
+/// first k bytes and the k after them, so the prefix compared doubles every pass
+fn bwt(input: &[u8]) -> (Vec<u8>, usize) {
+    let n = input.len();
+    let mut rotations: Vec<usize> = (0..n).collect();
+    let mut rank: Vec<usize> = input.iter().map(|b| *b as usize).collect();
+    let mut next_rank = vec![0; n];
+    let mut k = 1;
+    loop {
+        let key = |i: usize| (rank[i], rank[(i + k) % n]);
+        rotations.sort_unstable_by_key(|i| key(*i));
+        next_rank[rotations[0]] = 0;
+        for pair in rotations.windows(2) {
+            let step = (key(pair[0]) != key(pair[1])) as usize;
+            next_rank[pair[1]] = next_rank[pair[0]] + step;
+        }
+        std::mem::swap(&mut rank, &mut next_rank);
+        // Done once every rotation is distinct, or every byte has been compared (i.e. the
+        // input is periodic and some rotations are identical)
+        if rank[rotations[n - 1]] == n - 1 || k >= n {
+            break;
+        }
+        k *= 2;
+    }
+    let last_column = rotations.iter().map(|i| input[(i + n - 1) % n]).collect();
+    let primary_index = rotations.iter().position(|i| *i == 0).unwrap();
+    (last_column, primary_index)
+}
+
+fn bwt_inverse(last_column: &[u8], primary_index: usize) -> Vec<u8> {
+    // Where each byte value starts in the sorted first column
+    let mut starts = [0; 256];
+    for b in last_column {
+        starts[*b as usize] += 1;
+    }
+    let mut total = 0;
+    for start in starts.iter_mut() {
+        (*start, total) = (total, total + *start);
+    }
+    // Maps each row of the first column to the row holding the same byte in the last column
+    let mut next = vec![0; last_column.len()];
+    for (i, b) in last_column.iter().enumerate() {
+        next[starts[*b as usize]] = i;
+        starts[*b as usize] += 1;
+    }
+    let mut output = Vec::with_capacity(last_column.len());
+    let mut row = next[primary_index];
+    for _ in 0..last_column.len() {
+        output.push(last_column[row]);
+        row = next[row];
+    }
+    output
+}
+
+fn move_to_front(input: &[u8]) -> Vec<u8> {
+    let mut order: Vec<u8> = (0..=255).collect();
+    input
+        .iter()
+        .map(|b| {
+            let index = order.iter().position(|v| v == b).unwrap();
+            order.remove(index);
+            order.insert(0, *b);
+            index as u8
+        })
+        .collect()
+}
+
+fn move_to_front_inverse(input: &[u8]) -> Vec<u8> {
+    let mut order: Vec<u8> = (0..=255).collect();
+    input
+        .iter()
+        .map(|index| {
+            let b = order.remove(*index as usize);
+            order.insert(0, b);
+            b
+        })
+        .collect()
+}
+
+/// Runs of [RUN_THRESHOLD] or more of a byte are written as [RUN_THRESHOLD] of it followed by a
+/// count of how many more there were (up to 255)
+fn run_length_encode(input: &[u8]) -> Vec<u8> {
+    let mut output = Vec::with_capacity(input.len());
+    let mut i = 0;
+    while i < input.len() {
+        let b = input[i];
+        let run = input[i..]
+            .iter()
+            .take(RUN_THRESHOLD + u8::MAX as usize)
+            .take_while(|v| **v == b)
+            .count();
+        if run >= RUN_THRESHOLD {
+            output.extend([b; RUN_THRESHOLD]);
+            output.push((run - RUN_THRESHOLD) as u8);
+        } else {
+            output.extend(std::iter::repeat_n(b, run));
+        }
+        i += run;
+    }
+    output
+}
+
+fn run_length_decode(input: &[u8]) -> Result<Vec<u8>> {
+    let mut output = Vec::with_capacity(input.len());
+    let mut iter = input.iter();
+    let mut run = 0;
+    let mut previous = None;
+    while let Some(b) = iter.next() {
+        run = if previous == Some(*b) { run + 1 } else { 1 };
+        previous = Some(*b);
+        output.push(*b);
+        if run == RUN_THRESHOLD {
+            let Some(more) = iter.next() else {
+                return Err(Error::InvalidControlRecord(String::from(
+                    "BWT block ends without a run length",
+                )));
+            };
+            output.extend(std::iter::repeat_n(*b, *more as usize));
+            run = 0;
+            previous = None;
+        }
+    }
+    Ok(output)
+}
+
+#[cfg(test)]
+mod test {
+    use crate::bwt::{
+        bwt, invert_block, move_to_front, move_to_front_inverse, run_length_decode,
+        run_length_encode, transform_blocks,
+    };
+
+    #[test]
+    fn banana() {
+        let (last_column, primary_index) = bwt(b"banana");
+        assert_eq!(b"nnbaaa".to_vec(), last_column);
+        assert_eq!(3, primary_index);
+    }
+
+    #[test]
+    fn stages_round_trip() {
+        let text = include_bytes!("../testfile.txt");
+        let inputs: [&[u8]; 6] = [b"a", b"abababab", &[0; 1000], b"mississippi", text, &[]];
+        for input in inputs {
+            let mtf = move_to_front(input);
+            assert_eq!(input, move_to_front_inverse(&mtf));
+            let rle = run_length_encode(input);
+            assert_eq!(input, run_length_decode(&rle).unwrap());
+        }
+        for block_size in [1, 7, 1000] {
+            let blocks = transform_blocks(&text[..], block_size).unwrap();
+            let decoded: Vec<u8> = blocks
+                .iter()
+                .flat_map(|block| invert_block(block).unwrap())
+                .collect();
+            assert_eq!(text.to_vec(), decoded);
+        }
+    }
+}
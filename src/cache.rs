@@ -0,0 +1,256 @@
+//! An on-disk cache of the tokens blocks ([crate::EncodeOptions::block_size]) were coded as,
+//! keyed by a BLAKE3 hash of their bytes and the options that decide the tokens. Finding the
+//! matches is most of the work of encoding, so re-encoding a file which has mostly stayed the
+//! same, e.g. for a nightly backup, replays the tokens of the blocks it's seen before rather than
+//! looking for their matches again. Best with [crate::EncodeOptions::content_defined_blocks], as
+//! otherwise an insertion moves every block after it.
+//!
+//! The cache is only a shortcut: tokens read from it are checked to decode to the block before
+//! they're used, and a cache that can't be read or written is encoded without
+
+use log::{debug, warn};
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
+
+use crate::encode::{tokenise_block, EncodeOptions};
+use crate::error::Result;
+use crate::offset_len::OffsetLen;
+use crate::optimal_parse::Prices;
+use crate::token::Token;
+use crate::MAX_LEVEL;
+
+/// Changed whenever the tokens for the same block and options could change, so entries written
+/// by older versions are missed rather than used
+const CACHE_VERSION: &str = "lizards block cache 1";
+
+/// A directory of cached block tokens, see [crate::EncodeOptions::cache_dir]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BlockCache {
+    dir: PathBuf,
+}
+
+impl BlockCache {
+    pub fn new(dir: impl Into<PathBuf>) -> Self {
+        Self { dir: dir.into() }
+    }
+
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    /// Where the tokens for [block] encoded with [options] are kept
+    fn path(&self, block: &[u8], options: &EncodeOptions, min_match: usize) -> PathBuf {
+        let mut hasher = blake3::Hasher::new();
+        hasher.update(CACHE_VERSION.as_bytes());
+        // Everything the tokenisers are given. Optimal parsing also prices tokens by the huffman
+        // codes of the whole input, but tokens parsed with other prices still decode the same
+        let settings = format!(
+            "{:?} {} {:?} {} {} {:?} {:?} {}",
+            options.algorithm,
+            options.window_size,
+            options.long_range,
+            min_match,
+            options.max_match_len,
+            options.rle_min_run,
+            options.match_strategy,
+            options.level == MAX_LEVEL,
+        );
+        hasher.update(&(settings.len() as u64).to_le_bytes());
+        hasher.update(settings.as_bytes());
+        hasher.update(block);
+        let hash = hasher.finalize().to_hex();
+        // Spread over subdirectories, as some filesystems slow down with many files in one
+        self.dir.join(&hash[..2]).join(&hash[2..])
+    }
+
+    /// Tokenise the block read from [block_reader], passing each token to [emit], with the
+    /// tokens it was coded as before if they're in the cache. Gives whether they were
+    pub(crate) fn tokenise<R: Read>(
+        &self,
+        mut block_reader: R,
+        min_match: usize,
+        prices: Option<&Prices>,
+        options: &EncodeOptions,
+        mut emit: impl FnMut(&Token) -> Result<()>,
+    ) -> Result<bool> {
+        let mut block = Vec::new();
+        block_reader.read_to_end(&mut block)?;
+        // At the end of the input
+        if block.is_empty() {
+            return Ok(false);
+        }
+        if let Some(tokens) = self.load(&block, options, min_match) {
+            tokens.iter().try_for_each(emit)?;
+            return Ok(true);
+        }
+        let mut tokens = Vec::new();
+        // Blocks decode on their own, so there's never a primer
+        tokenise_block(&block[..], &[], min_match, prices, options, |token| {
+            tokens.push(token.clone());
+            emit(token)
+        })?;
+        self.store(&block, options, min_match, &tokens);
+        Ok(false)
+    }
+
+    /// The tokens [block] was coded as with the same options, if they're in the cache and
+    /// decode to it
+    fn load(&self, block: &[u8], options: &EncodeOptions, min_match: usize) -> Option<Vec<Token>> {
+        let path = self.path(block, options, min_match);
+        let bytes = std::fs::read(&path).ok()?;
+        let tokens = rmp_serde::from_slice::<Vec<Token>>(&bytes)
+            .map_err(|e| debug!("Ignoring unreadable cache entry {:?}: {}", path, e))
+            .ok()?;
+        let history_len = options.long_range.unwrap_or(0).max(options.window_size);
+        let tokens = checked_tokens(tokens, block, history_len);
+        if tokens.is_none() {
+            debug!(
+                "Ignoring cache entry {:?} which doesn't match its block",
+                path
+            );
+        }
+        tokens
+    }
+
+    /// Keep the [tokens] [block] was coded as. Failing to is logged rather than failing the
+    /// encode
+    fn store(&self, block: &[u8], options: &EncodeOptions, min_match: usize, tokens: &[Token]) {
+        let path = self.path(block, options, min_match);
+        if let Err(e) = write_entry(&path, tokens) {
+            warn!("Couldn't write cache entry {:?}: {}", path, e);
+        }
+    }
+}
+
+/// Write [tokens] to [path] by way of a temporary file, so other encodes sharing the cache never
+/// see half an entry
+fn write_entry(path: &Path, tokens: &[Token]) -> Result<()> {
+    // Matched bytes are only for tracing, and are put back from the block when loading
+    let tokens: Vec<Token> = tokens
+        .iter()
+        .map(|token| match token {
+            Token::Match(offset_len) => {
+                Token::Match(OffsetLen::new(offset_len.offset(), offset_len.len))
+            }
+            token => token.clone(),
+        })
+        .collect();
+    let bytes = rmp_serde::to_vec(&tokens).expect("Tokens always serialise");
+    let dir = path.parent().expect("Entries are in a subdirectory");
+    std::fs::create_dir_all(dir)?;
+    let temp_path = path.with_extension(format!("tmp{}", std::process::id()));
+    let mut temp = std::fs::File::create(&temp_path)?;
+    temp.write_all(&bytes)?;
+    drop(temp);
+    std::fs::rename(&temp_path, path).inspect_err(|_| {
+        let _ = std::fs::remove_file(&temp_path);
+    })?;
+    Ok(())
+}
+
+/// [tokens] if they decode to [block] on their own, with matches fitting in a window of
+/// [window_size], with each match's matched bytes filled in from [block]. Otherwise None
+fn checked_tokens(tokens: Vec<Token>, block: &[u8], window_size: usize) -> Option<Vec<Token>> {
+    let mut position = 0;
+    let mut checked = Vec::with_capacity(tokens.len());
+    for token in tokens {
+        let token = match token {
+            Token::Literal(value) => {
+                (block.get(position) == Some(&value)).then_some(())?;
+                position += 1;
+                Token::Literal(value)
+            }
+            Token::Run(value, len) => {
+                let end = position.checked_add(usize::try_from(len).ok()?)?;
+                block
+                    .get(position..end)?
+                    .iter()
+                    .all(|b| *b == value)
+                    .then_some(())?;
+                position = end;
+                Token::Run(value, len)
+            }
+            Token::Match(offset_len) => {
+                let distance = offset_len.offset().get();
+                let len = offset_len.len.get();
+                let end = position.checked_add(len)?;
+                if distance == 0 || distance > position || distance > window_size {
+                    return None;
+                }
+                let matched = block.get(position..end)?;
+                // Matches can run on into the bytes they're copying
+                let copies = (position..end).all(|i| block[i] == block[i - distance]);
+                copies.then_some(())?;
+                position = end;
+                Token::Match(OffsetLen::new_with_match(
+                    offset_len.offset(),
+                    offset_len.len,
+                    Some(matched.to_vec()),
+                ))
+            }
+            // The tokenisers don't make anything else
+            Token::EndOfStream(_) | Token::Control(_) => return None,
+        };
+        checked.push(token);
+    }
+    (position == block.len()).then_some(checked)
+}
+
+#[cfg(test)]
+mod test {
+    use crate::cache::{checked_tokens, BlockCache};
+    use crate::offset_len::OffsetLen;
+    use crate::test_util::temp_path;
+    use crate::{EncodeOptions, Token};
+
+    #[test]
+    fn checks_tokens() {
+        let block = b"abcabcabcx";
+        let tokens = vec![
+            Token::Literal(b'a'),
+            Token::Literal(b'b'),
+            Token::Literal(b'c'),
+            Token::Match(OffsetLen::of(3, 6)),
+            Token::Literal(b'x'),
+        ];
+        assert!(checked_tokens(tokens.clone(), block, 100).is_some());
+        // Doesn't fit the window, points before the block, decodes to something else, or stops
+        // short of the end
+        assert!(checked_tokens(tokens.clone(), block, 2).is_none());
+        let mut far = tokens.clone();
+        far[3] = Token::Match(OffsetLen::of(4, 6));
+        assert!(checked_tokens(far, block, 100).is_none());
+        assert!(checked_tokens(tokens.clone(), b"abcabcabcy", 100).is_none());
+        assert!(checked_tokens(tokens[..4].to_vec(), block, 100).is_none());
+        assert!(checked_tokens(vec![Token::Run(0, 3)], &[0; 3], 100).is_some());
+        assert!(checked_tokens(vec![Token::Run(0, 3)], &[0; 2], 100).is_none());
+    }
+
+    #[test]
+    fn stores_and_loads() {
+        let dir = temp_path("cache");
+        let cache = BlockCache::new(&dir);
+        let options = EncodeOptions::default();
+        let block = b"abcabcabc";
+        let tokens = vec![
+            Token::Literal(b'a'),
+            Token::Literal(b'b'),
+            Token::Literal(b'c'),
+            Token::Match(OffsetLen::of(3, 6)),
+        ];
+        assert_eq!(None, cache.load(block, &options, 4));
+        cache.store(block, &options, 4, &tokens);
+        let loaded = cache.load(block, &options, 4).unwrap();
+        assert_eq!(tokens.len(), loaded.len());
+        match &loaded[3] {
+            Token::Match(offset_len) => {
+                assert_eq!(Some(&b"abcabc"[..]), offset_len.matched_bytes())
+            }
+            token => panic!("Expected a match, got {:?}", token),
+        }
+        // Other options, or another block, miss
+        assert_eq!(None, cache.load(block, &options, 5));
+        assert_eq!(None, cache.load(b"abcabcabd", &options, 4));
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
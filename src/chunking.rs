@@ -0,0 +1,198 @@
+//! Where the encoder ends its blocks ([crate::EncodeOptions::block_size]). Fixed size blocks all
+//! move along when bytes are inserted near the start of the input, so every block after the
+//! insertion encodes differently. Content-defined boundaries
+//! ([crate::EncodeOptions::content_defined_blocks]) are instead put wherever a gear hash of the
+//! bytes just before them has its top bits clear, so they move with the content around them.
+//! After an edit the boundaries soon fall back into the same places, and the blocks from there
+//! on hold the same bytes as before, which deduplicating backups can make use of
+
+use std::io::{BufRead, Read};
+
+/// Content-defined blocks are at least this fraction of the average size...
+const MIN_FRACTION: usize = 4;
+/// ...and at most this multiple of it
+const MAX_MULTIPLE: usize = 4;
+
+/// One random looking value per byte, mixed into the hash as each byte goes by
+pub(crate) static GEAR: [u64; 256] = gear_table();
+
+const fn gear_table() -> [u64; 256] {
+    // splitmix64, so the table is the same on every build
+    let mut table = [0; 256];
+    let mut state: u64 = 0x6c69_7a61_7264_7321;
+    let mut i = 0;
+    while i < 256 {
+        state = state.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut z = state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        table[i] = z ^ (z >> 31);
+        i += 1;
+    }
+    table
+}
+
+/// Where blocks end
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Boundaries {
+    /// The input is one block
+    None,
+    /// Every this many bytes
+    Fixed(u64),
+    /// Where the gear hash picks, this many bytes apart on average
+    ContentDefined(usize),
+}
+
+/// Reads [inner] a block at a time, giving the end of input at the end of each block until
+/// [BlockSplitter::next_block] moves on to the next
+pub(crate) struct BlockSplitter<R: BufRead> {
+    inner: R,
+    block: BlockState,
+}
+
+struct BlockState {
+    boundaries: Boundaries,
+    /// Into the block
+    len: u64,
+    hash: u64,
+    ended: bool,
+}
+
+impl<R: BufRead> BlockSplitter<R> {
+    pub fn new(inner: R, boundaries: Boundaries) -> Self {
+        Self {
+            inner,
+            block: BlockState {
+                boundaries,
+                len: 0,
+                hash: 0,
+                ended: false,
+            },
+        }
+    }
+
+    pub fn next_block(&mut self) {
+        self.block.len = 0;
+        self.block.hash = 0;
+        self.block.ended = false;
+    }
+}
+
+impl BlockState {
+    /// How many of [bytes], which come next in the block, to read before it ends. All of them
+    /// when the block doesn't end within them
+    fn block_len_within(&mut self, bytes: &[u8]) -> usize {
+        match self.boundaries {
+            Boundaries::None => bytes.len(),
+            Boundaries::Fixed(size) => {
+                let left = size - self.len;
+                if left <= bytes.len() as u64 {
+                    self.ended = true;
+                }
+                bytes.len().min(left as usize)
+            }
+            Boundaries::ContentDefined(average) => {
+                let min = (average / MIN_FRACTION) as u64;
+                let max = (average * MAX_MULTIPLE).max(1) as u64;
+                // The top bits, which depend on the last 64 bytes rather than only the last few
+                let mask_bits = average.max(1).ilog2();
+                let mask = match mask_bits {
+                    0 => 0,
+                    bits => u64::MAX << (64 - bits),
+                };
+                for (index, byte) in bytes.iter().enumerate() {
+                    self.hash = (self.hash << 1).wrapping_add(GEAR[*byte as usize]);
+                    let len = self.len + index as u64 + 1;
+                    if len >= max || (len >= min && self.hash & mask == 0) {
+                        self.ended = true;
+                        return index + 1;
+                    }
+                }
+                bytes.len()
+            }
+        }
+    }
+}
+
+impl<R: BufRead> Read for BlockSplitter<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        if self.block.ended || buf.is_empty() {
+            return Ok(0);
+        }
+        let available = self.inner.fill_buf()?;
+        let available = &available[..available.len().min(buf.len())];
+        let len = self.block.block_len_within(available);
+        buf[..len].copy_from_slice(&available[..len]);
+        self.inner.consume(len);
+        self.block.len += len as u64;
+        Ok(len)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::Read;
+
+    use crate::chunking::{BlockSplitter, Boundaries};
+
+    /// The lengths of the blocks [input] is split into
+    fn block_lens(input: &[u8], boundaries: Boundaries) -> Vec<usize> {
+        let mut splitter = BlockSplitter::new(input, boundaries);
+        let mut lens = Vec::new();
+        loop {
+            let mut block = Vec::new();
+            splitter.read_to_end(&mut block).unwrap();
+            if block.is_empty() {
+                return lens;
+            }
+            lens.push(block.len());
+            splitter.next_block();
+        }
+    }
+
+    fn random(len: usize, seed: u32) -> Vec<u8> {
+        (0..len)
+            .scan(seed, |x, _| {
+                *x = x.wrapping_mul(1_103_515_245).wrapping_add(12345);
+                Some((*x >> 16) as u8)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn fixed() {
+        assert_eq!(vec![1000], block_lens(&[0; 1000], Boundaries::None));
+        assert_eq!(
+            vec![300, 300, 300, 100],
+            block_lens(&[0; 1000], Boundaries::Fixed(300))
+        );
+        assert_eq!(Vec::<usize>::new(), block_lens(&[], Boundaries::Fixed(300)));
+    }
+
+    #[test]
+    fn content_defined() {
+        let input = random(200_000, 1);
+        let lens = block_lens(&input, Boundaries::ContentDefined(4096));
+        assert_eq!(input.len(), lens.iter().sum::<usize>());
+        assert!(lens.iter().all(|len| (1024..=16384).contains(len)));
+        let average = input.len() / lens.len();
+        assert!((2048..8192).contains(&average), "{}", average);
+
+        // The same after an insertion near the start, apart from the first block or two
+        let mut inserted = random(100, 2);
+        inserted.extend(&input);
+        let inserted_lens = block_lens(&inserted, Boundaries::ContentDefined(4096));
+        let same = lens.len() - 2;
+        assert_eq!(
+            lens[lens.len() - same..],
+            inserted_lens[inserted_lens.len() - same..]
+        );
+
+        // A run of one byte settles on one hash, which for 7 isn't a boundary, so it's cut at
+        // the most a block can hold
+        assert_eq!(
+            vec![16384, 3616],
+            block_lens(&[7; 20_000], Boundaries::ContentDefined(4096))
+        );
+    }
+}
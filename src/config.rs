@@ -0,0 +1,240 @@
+//! Defaults for the command line's options, read from `lizards.toml` in the user's config
+//! directory (`$XDG_CONFIG_HOME`, or `~/.config`) when it starts, and then from environment
+//! variables ([LEVEL_VAR] and so on) so wrapper scripts and CI can tune it. Anything given on the
+//! command line wins over both, and the environment over the config file. For example
+//!
+//! ```toml
+//! level = 9
+//! threads = 4
+//! window-size = 32768
+//! on-conflict = "rename"
+//! exclude = ["*.tmp", "**/target/**"]
+//! ```
+
+use serde::de::{Deserializer, Error as _};
+use serde::Deserialize;
+use std::fmt::Display;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+use crate::error::{Error, Result};
+use crate::MAX_LEVEL;
+
+/// Name of the config file within the config directory
+pub const CONFIG_FILE_NAME: &str = "lizards.toml";
+
+/// Environment variables overriding the config file, one per field of [Config] but
+/// [Config::exclude]
+pub const LEVEL_VAR: &str = "LIZARDS_LEVEL";
+pub const THREADS_VAR: &str = "LIZARDS_THREADS";
+pub const WINDOW_VAR: &str = "LIZARDS_WINDOW";
+pub const ON_CONFLICT_VAR: &str = "LIZARDS_ON_CONFLICT";
+
+/// Defaults for options not given on the command line, each None (or empty) if it isn't set
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+pub struct Config {
+    /// As `compress --level`
+    pub level: Option<Level>,
+    /// As `--threads`
+    pub threads: Option<usize>,
+    /// As `compress --buffer-size`, i.e. [crate::EncodeOptions::window_size]
+    pub window_size: Option<usize>,
+    /// As `--on-conflict`
+    pub on_conflict: Option<OnConflict>,
+    /// As `--exclude`, added to any given on the command line
+    pub exclude: Vec<String>,
+}
+
+/// What to do when the output exists
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum OnConflict {
+    Fail,
+    Overwrite,
+    Rename,
+    Prompt,
+}
+
+/// How hard to compress, see [crate::EncodeOptions::level] and
+/// [crate::EncodeOptions::auto_level]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Level {
+    Auto,
+    Fixed(u8),
+}
+
+impl<'de> Deserialize<'de> for Level {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> std::result::Result<Self, D::Error> {
+        /// `level = 9` or `level = "auto"`
+        #[derive(Deserialize)]
+        #[serde(untagged)]
+        enum Raw {
+            Fixed(u64),
+            Named(String),
+        }
+        match Raw::deserialize(deserializer)? {
+            Raw::Fixed(level) => Level::from_str(&level.to_string()),
+            Raw::Named(name) => Level::from_str(&name),
+        }
+        .map_err(D::Error::custom)
+    }
+}
+
+impl FromStr for Level {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        if s == "auto" {
+            return Ok(Level::Auto);
+        }
+        s.parse()
+            .ok()
+            .filter(|level| (1..=MAX_LEVEL).contains(level))
+            .map(Level::Fixed)
+            .ok_or_else(|| format!("expected a level from 1 to {} or \"auto\"", MAX_LEVEL))
+    }
+}
+
+impl FromStr for OnConflict {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s {
+            "fail" => Ok(OnConflict::Fail),
+            "overwrite" => Ok(OnConflict::Overwrite),
+            "rename" => Ok(OnConflict::Rename),
+            "prompt" => Ok(OnConflict::Prompt),
+            _ => Err(String::from("expected fail, overwrite, rename or prompt")),
+        }
+    }
+}
+
+impl Config {
+    /// Where the config file is looked for, if there's a config directory
+    pub fn default_path() -> Option<PathBuf> {
+        let dir = match std::env::var_os("XDG_CONFIG_HOME").filter(|dir| !dir.is_empty()) {
+            Some(dir) => PathBuf::from(dir),
+            None => PathBuf::from(std::env::var_os("HOME")?).join(".config"),
+        };
+        Some(dir.join(CONFIG_FILE_NAME))
+    }
+
+    /// The config file at [Config::default_path], or an empty config if there isn't one
+    pub fn load_default() -> Result<Self> {
+        match Self::default_path() {
+            Some(path) if path.exists() => Self::load(&path),
+            _ => Ok(Self::default()),
+        }
+    }
+
+    /// The config file at [path]
+    pub fn load(path: &Path) -> Result<Self> {
+        let contents = std::fs::read_to_string(path)?;
+        Self::parse(&contents).map_err(|reason| Error::Config {
+            origin: format!("config file {:?}", path),
+            reason,
+        })
+    }
+
+    /// [self] with whatever the environment variables ([LEVEL_VAR] and so on) set instead
+    pub fn with_env(self) -> Result<Self> {
+        self.with_vars(|name| std::env::var(name).ok())
+    }
+
+    /// [self] with the values [var] gives for the environment variables instead
+    fn with_vars(mut self, var: impl Fn(&str) -> Option<String>) -> Result<Self> {
+        fn parsed<T: FromStr>(
+            var: &impl Fn(&str) -> Option<String>,
+            name: &str,
+        ) -> Result<Option<T>>
+        where
+            T::Err: Display,
+        {
+            var(name)
+                .map(|value| {
+                    value.trim().parse().map_err(|e: T::Err| Error::Config {
+                        origin: name.to_string(),
+                        reason: e.to_string(),
+                    })
+                })
+                .transpose()
+        }
+        self.level = parsed(&var, LEVEL_VAR)?.or(self.level);
+        self.threads = parsed(&var, THREADS_VAR)?.or(self.threads);
+        self.window_size = parsed(&var, WINDOW_VAR)?.or(self.window_size);
+        self.on_conflict = parsed(&var, ON_CONFLICT_VAR)?.or(self.on_conflict);
+        Ok(self)
+    }
+
+    fn parse(contents: &str) -> std::result::Result<Self, String> {
+        toml::from_str(contents).map_err(|e| e.message().to_string())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::config::{Config, Level, OnConflict, LEVEL_VAR, THREADS_VAR, WINDOW_VAR};
+
+    #[test]
+    fn parses() {
+        let config = Config::parse(
+            r#"
+            level = 9
+            threads = 4
+            window-size = 32768
+            on-conflict = "rename"
+            exclude = ["*.tmp"]
+            "#,
+        )
+        .unwrap();
+        assert_eq!(
+            Config {
+                level: Some(Level::Fixed(9)),
+                threads: Some(4),
+                window_size: Some(32768),
+                on_conflict: Some(OnConflict::Rename),
+                exclude: vec![String::from("*.tmp")],
+            },
+            config
+        );
+        assert_eq!(Config::default(), Config::parse("").unwrap());
+        assert_eq!(
+            Some(Level::Auto),
+            Config::parse("level = \"auto\"").unwrap().level
+        );
+
+        // Typos are caught rather than ignored
+        assert!(Config::parse("levle = 9").is_err());
+        assert!(Config::parse("level = 99").is_err());
+        assert!(Config::parse("level = \"fast\"").is_err());
+        assert!(Config::parse("threads = \"four\"").is_err());
+        assert!(Config::parse("on-conflict = \"ask\"").is_err());
+    }
+
+    #[test]
+    fn environment_wins() {
+        let config = Config::parse("level = 9\nthreads = 4").unwrap();
+        let vars = |values: &'static [(&'static str, &'static str)]| {
+            move |name: &str| {
+                values
+                    .iter()
+                    .find(|(var, _)| *var == name)
+                    .map(|(_, value)| value.to_string())
+            }
+        };
+        let config = config
+            .with_vars(vars(&[(LEVEL_VAR, "auto"), (WINDOW_VAR, "1024")]))
+            .unwrap();
+        assert_eq!(Some(Level::Auto), config.level);
+        // Left as it was
+        assert_eq!(Some(4), config.threads);
+        assert_eq!(Some(1024), config.window_size);
+
+        let invalid = config.with_vars(vars(&[(THREADS_VAR, "lots")]));
+        assert_eq!(
+            "Invalid LIZARDS_THREADS: invalid digit found in string",
+            invalid.unwrap_err().to_string()
+        );
+    }
+}
@@ -0,0 +1,416 @@
+//! Control records are tokens with the 0b00 prefix, for anything other than literals and
+//! matches. They are laid out as `[00tttttt] [len: LEB128 varint] [value: len bytes]`, with the
+//! record type in the low 6 bits. Types with [OPTIONAL_BIT] set can be skipped by a decoder that
+//! doesn't know them, so adding one doesn't break older decoders. Any other unknown type changes
+//! how the rest of the stream decodes, so is an error
+
+use serde::{Deserialize, Serialize};
+use std::fmt::{Display, Formatter};
+use std::io::Write;
+
+use crate::error::{Error, Result};
+use crate::huffman::HuffmanTree;
+
+const TYPE_MASK: u8 = 0b00111111;
+const OPTIONAL_BIT: u8 = 0b00100000;
+
+const END_OF_STREAM: u8 = 0;
+const NEW_HUFFMAN_TREE: u8 = 1;
+const BLOCK_BOUNDARY: u8 = 2;
+const RUN: u8 = 3;
+const BWT_BLOCK: u8 = 4;
+const PADDING: u8 = OPTIONAL_BIT;
+const BLOCK_CHECK: u8 = OPTIONAL_BIT | 1;
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum ControlRecord {
+    /// The last token of the stream
+    EndOfStream(Trailer),
+    /// Literals after this are coded with this tree rather than the header's
+    #[allow(dead_code)] // Decoded, but not written by the encoder yet
+    NewHuffmanTree(HuffmanTree),
+    /// A run of literals doesn't continue over this
+    BlockBoundary,
+    /// This many bytes of filler
+    #[allow(dead_code)]
+    Padding(usize),
+    /// [value] repeated [len] times. Value is laid out as `[value][len: LEB128 varint]`
+    Run { value: u8, len: u64 },
+    /// A block of Burrows-Wheeler transformed input, huffman coded with the current tree. Laid
+    /// out as `[primary index: LEB128 varint][packed bytes]`
+    #[allow(dead_code)] // Only written with the bwt feature
+    BwtBlock { primary_index: u64, packed: Vec<u8> },
+    /// Ends a block which decodes on its own, so a damaged one can be skipped. Laid out as
+    /// `[checksum: u32][decoded len: LEB128 varint][encoded len: LEB128 varint]`, where the
+    /// encoded len covers the block's tokens before this record
+    BlockCheck {
+        checksum: u32,
+        decoded_len: u64,
+        encoded_len: u64,
+    },
+}
+
+/// Held in [ControlRecord::EndOfStream] so the decoder can tell it got everything, and got it
+/// right
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct Trailer {
+    /// Number of bytes the stream decodes to
+    pub len: u64,
+    /// CRC32 of those bytes
+    pub checksum: u32,
+}
+
+impl ControlRecord {
+    fn record_type(&self) -> u8 {
+        match self {
+            Self::EndOfStream(_) => END_OF_STREAM,
+            Self::NewHuffmanTree(_) => NEW_HUFFMAN_TREE,
+            Self::BlockBoundary => BLOCK_BOUNDARY,
+            Self::Padding(_) => PADDING,
+            Self::Run { .. } => RUN,
+            Self::BlockCheck { .. } => BLOCK_CHECK,
+            Self::BwtBlock { .. } => BWT_BLOCK,
+        }
+    }
+
+    fn value(&self) -> Vec<u8> {
+        match self {
+            Self::EndOfStream(trailer) => {
+                let mut bytes = trailer.len.to_be_bytes().to_vec();
+                bytes.extend_from_slice(&trailer.checksum.to_be_bytes());
+                bytes
+            }
+            Self::NewHuffmanTree(tree) => rmp_serde::to_vec(tree).unwrap(),
+            Self::BlockBoundary => Vec::new(),
+            Self::Padding(len) => vec![0; *len],
+            Self::Run { value, len } => {
+                let mut bytes = vec![*value];
+                bytes.extend(encode_varint(*len));
+                bytes
+            }
+            Self::BwtBlock {
+                primary_index,
+                packed,
+            } => {
+                let mut bytes = encode_varint(*primary_index);
+                bytes.extend(packed);
+                bytes
+            }
+            Self::BlockCheck {
+                checksum,
+                decoded_len,
+                encoded_len,
+            } => {
+                let mut bytes = checksum.to_be_bytes().to_vec();
+                bytes.extend(encode_varint(*decoded_len));
+                bytes.extend(encode_varint(*encoded_len));
+                bytes
+            }
+        }
+    }
+
+    /// Write the record to [writer], returning how many bytes that took
+    pub fn write_to<W: Write>(&self, writer: &mut W) -> std::io::Result<usize> {
+        let value = self.value();
+        writer.write_all(&[self.record_type()])?;
+        let len_bytes = write_varint(writer, value.len() as u64)?;
+        writer.write_all(&value)?;
+        Ok(1 + len_bytes + value.len())
+    }
+
+    #[cfg(test)]
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        self.write_to(&mut bytes).unwrap();
+        bytes
+    }
+
+    /// Whether the record started by [control_byte] is an optional one this version doesn't
+    /// know, so its value can be skipped unread. [ControlRecord::from_parts] gives None for these
+    pub fn is_skippable(control_byte: u8) -> bool {
+        let record_type = control_byte & TYPE_MASK;
+        record_type & OPTIONAL_BIT != 0 && !matches!(record_type, PADDING | BLOCK_CHECK)
+    }
+
+    /// Parse the record started by [control_byte] from its [value]. None if it's an optional
+    /// record this version doesn't know, which should be skipped
+    pub fn from_parts(control_byte: u8, value: &[u8]) -> Result<Option<Self>> {
+        let invalid = |reason: &str| {
+            Err(Error::InvalidControlRecord(format!(
+                "{} (type {:#04x}, {} bytes)",
+                reason,
+                control_byte & TYPE_MASK,
+                value.len()
+            )))
+        };
+        match control_byte & TYPE_MASK {
+            END_OF_STREAM => match value.try_into() {
+                Ok(bytes) => {
+                    let bytes: [u8; 12] = bytes;
+                    Ok(Some(Self::EndOfStream(Trailer {
+                        len: u64::from_be_bytes(bytes[..8].try_into().unwrap()),
+                        checksum: u32::from_be_bytes(bytes[8..].try_into().unwrap()),
+                    })))
+                }
+                Err(_) => invalid("Wrong length for end of stream"),
+            },
+            NEW_HUFFMAN_TREE => match rmp_serde::from_slice::<HuffmanTree>(value) {
+                Ok(tree) => {
+                    tree.validate_bytes()?;
+                    Ok(Some(Self::NewHuffmanTree(tree)))
+                }
+                Err(e) => invalid(&e.to_string()),
+            },
+            BLOCK_BOUNDARY => Ok(Some(Self::BlockBoundary)),
+            PADDING => Ok(Some(Self::Padding(value.len()))),
+            RUN => {
+                let mut varint_reader = VarintReader::default();
+                let len = match value.split_first() {
+                    Some((_, [len_bytes @ .., last])) => {
+                        for byte in len_bytes {
+                            if varint_reader.push(*byte)?.is_some() {
+                                return invalid("Bytes after the run length");
+                            }
+                        }
+                        varint_reader.push(*last)?
+                    }
+                    _ => None,
+                };
+                match len {
+                    Some(len) => Ok(Some(Self::Run {
+                        value: value[0],
+                        len,
+                    })),
+                    None => invalid("Run length missing"),
+                }
+            }
+            BWT_BLOCK => {
+                let mut varint_reader = VarintReader::default();
+                for (i, byte) in value.iter().enumerate() {
+                    if let Some(primary_index) = varint_reader.push(*byte)? {
+                        return Ok(Some(Self::BwtBlock {
+                            primary_index,
+                            packed: value[(i + 1)..].to_vec(),
+                        }));
+                    }
+                }
+                invalid("BWT primary index missing")
+            }
+            BLOCK_CHECK => {
+                let lens = value
+                    .get(4..)
+                    .and_then(split_varint)
+                    .and_then(|(decoded_len, rest)| match split_varint(rest) {
+                        Some((encoded_len, [])) => Some((decoded_len, encoded_len)),
+                        _ => None,
+                    });
+                match lens {
+                    Some((decoded_len, encoded_len)) => Ok(Some(Self::BlockCheck {
+                        checksum: u32::from_be_bytes(value[..4].try_into().unwrap()),
+                        decoded_len,
+                        encoded_len,
+                    })),
+                    None => invalid("Block check lengths missing"),
+                }
+            }
+            other if other & OPTIONAL_BIT != 0 => Ok(None),
+            _ => invalid("Unknown record type"),
+        }
+    }
+
+    /// Parse a whole record from the start of [bytes], returning it and how many bytes it took.
+    /// None if they don't start with a valid record of a known type
+    pub fn from_bytes(bytes: &[u8]) -> Option<(Self, usize)> {
+        let (&control_byte, rest) = bytes.split_first()?;
+        if control_byte >> 6 != 0 {
+            return None;
+        }
+        let (len, rest) = split_varint(rest)?;
+        let value = rest.get(..usize::try_from(len).ok()?)?;
+        let record = Self::from_parts(control_byte, value).ok()??;
+        Some((record, bytes.len() - rest.len() + value.len()))
+    }
+}
+
+impl Display for ControlRecord {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::EndOfStream(trailer) => {
+                write!(
+                    f,
+                    "<End: {} bytes, crc {:08x}>",
+                    trailer.len, trailer.checksum
+                )
+            }
+            Self::NewHuffmanTree(tree) => write!(f, "<New tree: size {}>", tree.size()),
+            Self::BlockBoundary => write!(f, "<Block boundary>"),
+            Self::Padding(len) => write!(f, "<Padding: {}>", len),
+            Self::Run { value, len } => write!(f, "<Run: {:#04x} x {}>", value, len),
+            Self::BwtBlock {
+                primary_index,
+                packed,
+            } => write!(
+                f,
+                "<BWT block: index {}, {} bytes>",
+                primary_index,
+                packed.len()
+            ),
+            Self::BlockCheck {
+                checksum,
+                decoded_len,
+                encoded_len,
+            } => write!(
+                f,
+                "<Block check: {} -> {} bytes, crc {:08x}>",
+                decoded_len, encoded_len, checksum
+            ),
+        }
+    }
+}
+
+/// Read the varint at the start of [bytes], returning it and the bytes after it
+pub(crate) fn split_varint(bytes: &[u8]) -> Option<(u64, &[u8])> {
+    let mut varint_reader = VarintReader::default();
+    for (i, byte) in bytes.iter().enumerate() {
+        if let Some(v) = varint_reader.push(*byte).ok()? {
+            return Some((v, &bytes[(i + 1)..]));
+        }
+    }
+    None
+}
+
+fn encode_varint(v: u64) -> Vec<u8> {
+    let mut bytes = Vec::new();
+    write_varint(&mut bytes, v).unwrap();
+    bytes
+}
+
+/// LEB128: 7 bits at a time, least significant first, with the top bit set on all but the last.
+/// Returns how many bytes were written
+pub(crate) fn write_varint<W: Write>(writer: &mut W, mut v: u64) -> std::io::Result<usize> {
+    let mut written = 0;
+    loop {
+        let byte = (v & 0b01111111) as u8;
+        v >>= 7;
+        written += 1;
+        if v == 0 {
+            writer.write_all(&[byte])?;
+            return Ok(written);
+        }
+        writer.write_all(&[byte | 0b10000000])?;
+    }
+}
+
+/// Reads a LEB128 varint a byte at a time, as the decoder gets them
+#[derive(Debug, Default)]
+pub(crate) struct VarintReader {
+    value: u64,
+    shift: u32,
+}
+
+impl VarintReader {
+    /// Add the next byte, returning the value once it's complete
+    pub fn push(&mut self, byte: u8) -> Result<Option<u64>> {
+        let bits = (byte & 0b01111111) as u64;
+        if self.shift >= 64 || (bits << self.shift) >> self.shift != bits {
+            return Err(Error::InvalidControlRecord(String::from(
+                "Length doesn't fit in a u64",
+            )));
+        }
+        self.value |= bits << self.shift;
+        self.shift += 7;
+        match byte & 0b10000000 {
+            0 => Ok(Some(self.value)),
+            _ => Ok(None),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::control::{encode_varint, ControlRecord, Trailer, VarintReader};
+    use crate::huffman::{build_tree, ByteStats};
+
+    fn round_trip(record: ControlRecord) {
+        let bytes = record.to_bytes();
+        let mut varint_reader = VarintReader::default();
+        let mut i = 1;
+        let len = loop {
+            if let Some(len) = varint_reader.push(bytes[i]).unwrap() {
+                break len as usize;
+            }
+            i += 1;
+        };
+        let value = &bytes[(i + 1)..];
+        assert_eq!(len, value.len());
+        assert_eq!(
+            Some(record),
+            ControlRecord::from_parts(bytes[0], value).unwrap()
+        );
+    }
+
+    #[test]
+    fn records_round_trip() {
+        round_trip(ControlRecord::EndOfStream(Trailer {
+            len: 123456789,
+            checksum: 0xdeadbeef,
+        }));
+        let stats: ByteStats = [(b'a'.into(), 3), (b'b'.into(), 1)].into_iter().collect();
+        round_trip(ControlRecord::NewHuffmanTree(build_tree(stats)));
+        round_trip(ControlRecord::BlockBoundary);
+        round_trip(ControlRecord::Padding(300));
+        round_trip(ControlRecord::Run {
+            value: 0,
+            len: 1 << 40,
+        });
+        round_trip(ControlRecord::BwtBlock {
+            primary_index: 1000,
+            packed: vec![1, 2, 3],
+        });
+        let block_check = ControlRecord::BlockCheck {
+            checksum: 0xdeadbeef,
+            decoded_len: 1 << 20,
+            encoded_len: 300,
+        };
+        let mut bytes = block_check.to_bytes();
+        let len = bytes.len();
+        bytes.extend([1, 2, 3]);
+        assert_eq!(Some((block_check, len)), ControlRecord::from_bytes(&bytes));
+        round_trip(ControlRecord::BlockCheck {
+            checksum: 1,
+            decoded_len: 0,
+            encoded_len: 0,
+        });
+    }
+
+    #[test]
+    fn unknown_records() {
+        // Optional, so skipped
+        assert_eq!(None, ControlRecord::from_parts(0b00111111, b"?").unwrap());
+        assert!(ControlRecord::from_parts(0b00011111, b"?").is_err());
+        assert!(ControlRecord::is_skippable(0b00111111));
+        assert!(!ControlRecord::is_skippable(0b00011111));
+        assert!(!ControlRecord::is_skippable(super::PADDING));
+        assert!(!ControlRecord::is_skippable(super::BLOCK_CHECK));
+        // Runs need exactly one length
+        assert!(ControlRecord::from_parts(super::RUN, &[0]).is_err());
+        assert!(ControlRecord::from_parts(super::RUN, &[0, 1, 1]).is_err());
+    }
+
+    #[test]
+    fn varint() {
+        for v in [0, 1, 127, 128, 300, u32::MAX as u64, u64::MAX] {
+            let mut reader = VarintReader::default();
+            let bytes = encode_varint(v);
+            for byte in &bytes[..bytes.len() - 1] {
+                assert_eq!(None, reader.push(*byte).unwrap());
+            }
+            assert_eq!(Some(v), reader.push(*bytes.last().unwrap()).unwrap());
+        }
+        let mut reader = VarintReader::default();
+        for _ in 0..9 {
+            reader.push(0xff).unwrap();
+        }
+        assert!(reader.push(0x7f).is_err());
+    }
+}
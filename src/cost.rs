@@ -0,0 +1,68 @@
+//! What tokens cost in the output, for tooling and the optimal parser alike. Costs are of the
+//! token alone: the chunk markers and padding around runs of literals depend on the tokens
+//! either side, so aren't counted
+
+use crate::huffman::{CodeMap, Symbol};
+use crate::Token;
+
+/// Bits [value] takes in the output, with literals coded with [code_map]. A literal without a
+/// code in [code_map] can't be coded with it, so costs [u32::MAX]
+pub fn cost_in_bits(value: &Token, code_map: &CodeMap) -> u32 {
+    token_bits(value, Some(code_map))
+}
+
+/// [cost_in_bits], with literals stored as they are without a [code_map]
+pub(crate) fn token_bits(value: &Token, code_map: Option<&CodeMap>) -> u32 {
+    match (value, code_map) {
+        (Token::Literal(b), Some(code_map)) => code_map
+            .code_for(Symbol::from(*b))
+            .map_or(u32::MAX, |code| code.len() as u32),
+        (Token::Literal(_), None) => 8,
+        // Everything else is whole bytes
+        (value, _) => value.write_to(&mut std::io::sink()).unwrap() as u32 * 8,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::HashMap;
+
+    use crate::cost::{cost_in_bits, token_bits};
+    use crate::encode::encode_from_reader;
+    use crate::huffman::{Bits, CodeMap, Symbol};
+    use crate::offset_len::OffsetLen;
+    use crate::{Algorithm, EncodeOptions, Token};
+
+    #[test]
+    fn token_costs() {
+        let mut codes = HashMap::new();
+        codes.insert(Symbol::from(b'a'), Bits::from((0b0, 1)));
+        codes.insert(Symbol::from(b'b'), Bits::from((0b10, 2)));
+        let code_map = CodeMap::new(codes, Bits::from((0b11, 2)));
+        assert_eq!(1, cost_in_bits(&Token::Literal(b'a'), &code_map));
+        assert_eq!(2, cost_in_bits(&Token::Literal(b'b'), &code_map));
+        assert_eq!(u32::MAX, cost_in_bits(&Token::Literal(b'c'), &code_map));
+        assert_eq!(8, token_bits(&Token::Literal(b'c'), None));
+        let offset_len = OffsetLen::of(1000, 40);
+        let bits = offset_len.encoded_len() as u32 * 8;
+        assert_eq!(bits, cost_in_bits(&Token::Match(offset_len), &code_map));
+        assert_eq!(32, cost_in_bits(&Token::Run(0, 100), &code_map));
+    }
+
+    #[test]
+    fn summary_costs() {
+        let input = "The cat sat on the mat. The cat sat on the hat. ".repeat(20);
+        for algorithm in [Algorithm::Lz, Algorithm::LzHuffman] {
+            let options = EncodeOptions::default().algorithm(algorithm);
+            let summary = encode_from_reader(input.as_bytes(), std::io::sink(), &options).unwrap();
+            assert!(summary.literal_bits > 0);
+            assert!(summary.match_bits > 0);
+            // The rest of the output is the header, chunk markers and padding
+            let token_bits = summary.literal_bits + summary.match_bits + summary.run_bits;
+            assert!(token_bits < summary.output_bytes * 8);
+            if algorithm == Algorithm::Lz {
+                assert_eq!(summary.literals * 8, summary.literal_bits);
+            }
+        }
+    }
+}
@@ -0,0 +1,549 @@
+//! Passphrase encryption of a whole encoded file. An encrypted file is an envelope:
+//! [header::ENCRYPTED_MAGIC], a version byte and a varint length prefixed [Envelope] saying
+//! how the key was derived, all in the clear, followed by the encoded stream encrypted with
+//! AES-256-GCM. The stream is encrypted in chunks (the STREAM construction), so neither side
+//! holds more than a chunk, and dropping, reordering or cutting off chunks is caught. Everything
+//! before the chunks is authenticated along with each of them
+
+use aes_gcm::aead::rand_core::RngCore;
+use aes_gcm::aead::stream::{DecryptorBE32, EncryptorBE32};
+use aes_gcm::aead::{OsRng, Payload};
+use aes_gcm::{Aes256Gcm, Key};
+use serde::{Deserialize, Serialize};
+use std::fmt::{Debug, Formatter};
+use std::io::{Read, Write};
+use subtle::ConstantTimeEq;
+use zeroize::Zeroizing;
+
+use crate::control::{split_varint, write_varint};
+use crate::error::{Error, Result};
+use crate::header::{self, ENCRYPTED_MAGIC};
+
+/// Version of the envelope, which changes separately from the stream inside it
+const ENVELOPE_VERSION: u8 = 1;
+/// Bytes of plaintext in each encrypted chunk
+const CHUNK_LEN: u32 = 1 << 16;
+/// Chunks longer than this are rejected without reading them
+const MAX_CHUNK_LEN: u32 = 1 << 24;
+/// Envelopes longer than this are rejected, real ones are a few dozen bytes
+const MAX_ENVELOPE_LEN: u64 = 1 << 12;
+const TAG_LEN: usize = 16;
+/// The STREAM construction takes 5 bytes of the 12 byte GCM nonce for its counter
+const NONCE_LEN: usize = 7;
+const SALT_LEN: usize = 16;
+/// Of an AES-256 key
+pub const KEY_LEN: usize = 32;
+/// Key derivation asking for more memory than this (in KiB) is rejected, so a crafted file
+/// can't take all of it
+const MAX_KDF_MEMORY: u32 = 1 << 21;
+const MAX_KDF_PASSES: u32 = 64;
+
+/// Whether [reader] starts with an encrypted file, reading no more than its magic
+pub fn is_encrypted<R: Read>(reader: R) -> std::io::Result<bool> {
+    let mut prefix = Vec::new();
+    reader
+        .take(header::FRAME_PREFIX_LEN as u64)
+        .read_to_end(&mut prefix)?;
+    Ok(header::is_encrypted(&prefix))
+}
+
+/// A passphrase to derive keys from, wiped from memory when dropped. Debug output leaves it
+/// out, and comparisons take the same time wherever they differ
+#[derive(Clone)]
+pub struct Passphrase(Zeroizing<String>);
+
+impl Passphrase {
+    pub fn new(passphrase: impl Into<String>) -> Self {
+        Self(Zeroizing::new(passphrase.into()))
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+}
+
+impl PartialEq for Passphrase {
+    fn eq(&self, other: &Self) -> bool {
+        self.0.as_bytes().ct_eq(other.0.as_bytes()).into()
+    }
+}
+
+impl Eq for Passphrase {}
+
+impl Debug for Passphrase {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Passphrase(..)")
+    }
+}
+
+/// A key to encrypt with as it is, for services which manage their own keys rather than
+/// prompting for passphrases. Wiped from memory when dropped, and left out of Debug output
+#[derive(Clone)]
+pub struct EncryptionKey(Zeroizing<[u8; KEY_LEN]>);
+
+impl EncryptionKey {
+    pub fn new(key: [u8; KEY_LEN]) -> Self {
+        Self(Zeroizing::new(key))
+    }
+}
+
+impl PartialEq for EncryptionKey {
+    fn eq(&self, other: &Self) -> bool {
+        self.0.ct_eq(&*other.0).into()
+    }
+}
+
+impl Eq for EncryptionKey {}
+
+impl Debug for EncryptionKey {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "EncryptionKey(..)")
+    }
+}
+
+/// What a file is encrypted with
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Secret {
+    Passphrase(Passphrase),
+    Key(EncryptionKey),
+}
+
+impl From<Passphrase> for Secret {
+    fn from(passphrase: Passphrase) -> Self {
+        Self::Passphrase(passphrase)
+    }
+}
+
+impl From<EncryptionKey> for Secret {
+    fn from(key: EncryptionKey) -> Self {
+        Self::Key(key)
+    }
+}
+
+/// How the key for the chunks following it is derived, stored in the clear
+#[derive(Debug, Serialize, Deserialize)]
+struct Envelope {
+    kdf: Kdf,
+    /// Combined with each chunk's counter to give its nonce
+    nonce: Vec<u8>,
+    chunk_len: u32,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+enum Kdf {
+    /// Argon2id with memory in KiB, passes and lanes as the argon2 crate takes them
+    Argon2id {
+        memory: u32,
+        passes: u32,
+        lanes: u32,
+        salt: Vec<u8>,
+    },
+    /// The key was given as it is, see [EncryptionKey]
+    None,
+}
+
+impl Kdf {
+    fn new(secret: &Secret) -> Self {
+        if let Secret::Key(_) = secret {
+            return Self::None;
+        }
+        let params = argon2::Params::default();
+        let mut salt = vec![0; SALT_LEN];
+        OsRng.fill_bytes(&mut salt);
+        Self::Argon2id {
+            memory: params.m_cost(),
+            passes: params.t_cost(),
+            lanes: params.p_cost(),
+            salt,
+        }
+    }
+
+    fn derive_key(&self, secret: &Secret) -> Result<Zeroizing<[u8; KEY_LEN]>> {
+        let (memory, passes, lanes, salt, passphrase) = match (self, secret) {
+            (
+                Self::Argon2id {
+                    memory,
+                    passes,
+                    lanes,
+                    salt,
+                },
+                Secret::Passphrase(passphrase),
+            ) => (memory, passes, lanes, salt, passphrase),
+            (Self::None, Secret::Key(key)) => return Ok(key.0.clone()),
+            (Self::None, Secret::Passphrase(_)) => {
+                return Err(Error::Decryption(String::from(
+                    "the input was encrypted with a key rather than a passphrase",
+                )))
+            }
+            (Self::Argon2id { .. }, Secret::Key(_)) => {
+                return Err(Error::Decryption(String::from(
+                    "the input was encrypted with a passphrase rather than a key",
+                )))
+            }
+        };
+        if *memory > MAX_KDF_MEMORY || *passes > MAX_KDF_PASSES {
+            return Err(Error::CorruptHeader(format!(
+                "Key derivation asks for {} KiB over {} passes",
+                memory, passes
+            )));
+        }
+        let params = argon2::Params::new(*memory, *passes, *lanes, Some(KEY_LEN))
+            .map_err(|e| Error::CorruptHeader(e.to_string()))?;
+        let argon2 = argon2::Argon2::new(argon2::Algorithm::Argon2id, Default::default(), params);
+        let mut key = Zeroizing::new([0; KEY_LEN]);
+        argon2
+            .hash_password_into(passphrase.0.as_bytes(), salt, &mut key[..])
+            .map_err(|e| Error::CorruptHeader(e.to_string()))?;
+        Ok(key)
+    }
+}
+
+/// Encrypts everything written to it into an envelope written to [inner]. Nothing is written
+/// until the first write, and [EncryptWriter::finish] has to be called to write the last chunk
+pub struct EncryptWriter<W: Write> {
+    inner: W,
+    key: Zeroizing<[u8; KEY_LEN]>,
+    kdf: Kdf,
+    /// Everything before the chunks, authenticated with each of them
+    prefix: Vec<u8>,
+    encryptor: Option<EncryptorBE32<Aes256Gcm>>,
+    /// Plaintext not yet encrypted. A full chunk is only encrypted once more is written, as
+    /// the last chunk is encrypted differently
+    chunk: Vec<u8>,
+    started: bool,
+    written: u64,
+}
+
+impl<W: Write> EncryptWriter<W> {
+    /// Encrypt with [secret]. A key is derived from a passphrase with a new random salt, which
+    /// takes a moment on purpose
+    pub fn new(inner: W, secret: &Secret) -> Result<Self> {
+        let kdf = Kdf::new(secret);
+        let key = kdf.derive_key(secret)?;
+        let mut writer = Self {
+            inner,
+            key,
+            kdf,
+            prefix: Vec::new(),
+            encryptor: None,
+            chunk: Vec::with_capacity(CHUNK_LEN as usize),
+            started: false,
+            written: 0,
+        };
+        writer.start_over();
+        Ok(writer)
+    }
+
+    pub fn get_ref(&self) -> &W {
+        &self.inner
+    }
+
+    pub fn get_mut(&mut self) -> &mut W {
+        &mut self.inner
+    }
+
+    /// Forget everything written and start again with a new nonce, for when [inner] has been
+    /// emptied. The key is kept, as deriving it is the slow part
+    pub(crate) fn start_over(&mut self) {
+        let mut nonce = vec![0; NONCE_LEN];
+        OsRng.fill_bytes(&mut nonce);
+        self.encryptor = Some(EncryptorBE32::new(
+            Key::<Aes256Gcm>::from_slice(&self.key[..]),
+            nonce.as_slice().into(),
+        ));
+        let envelope = Envelope {
+            kdf: self.kdf.clone(),
+            nonce,
+            chunk_len: CHUNK_LEN,
+        };
+        let body = rmp_serde::to_vec(&envelope).unwrap();
+        self.prefix = Vec::from(&ENCRYPTED_MAGIC[..]);
+        self.prefix.push(ENVELOPE_VERSION);
+        write_varint(&mut self.prefix, body.len() as u64).unwrap();
+        self.prefix.extend(body);
+        self.chunk.clear();
+        self.started = false;
+        self.written = 0;
+    }
+
+    fn write_encrypted(&mut self, last: bool) -> std::io::Result<()> {
+        if !self.started {
+            self.inner.write_all(&self.prefix)?;
+            self.written += self.prefix.len() as u64;
+            self.started = true;
+        }
+        let payload = Payload {
+            msg: &self.chunk,
+            aad: &self.prefix,
+        };
+        let encrypted = match last {
+            true => self.encryptor.take().unwrap().encrypt_last(payload),
+            false => self.encryptor.as_mut().unwrap().encrypt_next(payload),
+        }
+        .map_err(|_| Error::Decryption(String::from("too many chunks to encrypt")))?;
+        self.inner.write_all(&encrypted)?;
+        self.written += encrypted.len() as u64;
+        self.chunk.clear();
+        Ok(())
+    }
+
+    /// Encrypt what's left as the last chunk, giving back [inner] and the number of bytes
+    /// written to it
+    pub fn finish(mut self) -> Result<(W, u64)> {
+        self.write_encrypted(true)?;
+        self.inner.flush()?;
+        Ok((self.inner, self.written))
+    }
+}
+
+impl<W: Write> Write for EncryptWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        if self.chunk.len() == CHUNK_LEN as usize {
+            self.write_encrypted(false)?;
+        }
+        let taken = buf.len().min(CHUNK_LEN as usize - self.chunk.len());
+        self.chunk.extend_from_slice(&buf[..taken]);
+        Ok(taken)
+    }
+
+    /// Flushes [inner], without encrypting a partial chunk
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// Decrypts an envelope written by [EncryptWriter] from [inner], reading as the decrypted
+/// stream. Chunks only come out once they've been authenticated
+pub struct DecryptReader<R: Read> {
+    inner: R,
+    prefix: Vec<u8>,
+    /// None once the last chunk has been decrypted
+    decryptor: Option<DecryptorBE32<Aes256Gcm>>,
+    chunk_len: usize,
+    /// Read ahead of the chunk being decrypted, to tell whether it's the last
+    encrypted: Vec<u8>,
+    decrypted: Vec<u8>,
+    read: usize,
+}
+
+impl<R: Read> DecryptReader<R> {
+    /// Read the envelope from the start of [inner], and decrypt with [secret]
+    pub fn new(mut inner: R, secret: &Secret) -> Result<Self> {
+        let mut prefix = Vec::new();
+        let mut read_more = |prefix: &mut Vec<u8>, len: u64| -> Result<()> {
+            match (&mut inner).take(len).read_to_end(prefix)? as u64 == len {
+                true => Ok(()),
+                false => Err(Error::TruncatedStream("encryption header")),
+            }
+        };
+        read_more(&mut prefix, header::FRAME_PREFIX_LEN as u64 + 1)?;
+        if !header::is_encrypted(&prefix[..header::FRAME_PREFIX_LEN]) {
+            return Err(Error::Decryption(String::from("the input isn't encrypted")));
+        }
+        let version = prefix[header::FRAME_PREFIX_LEN];
+        if version != ENVELOPE_VERSION {
+            return Err(Error::UnsupportedVersion(version));
+        }
+        let body_len = loop {
+            match split_varint(&prefix[header::FRAME_PREFIX_LEN + 1..]) {
+                Some((body_len, _)) => break body_len,
+                None => read_more(&mut prefix, 1)?,
+            }
+        };
+        if body_len > MAX_ENVELOPE_LEN {
+            return Err(Error::LimitExceeded {
+                limit: "encryption header size",
+                max: MAX_ENVELOPE_LEN,
+            });
+        }
+        let body_start = prefix.len();
+        read_more(&mut prefix, body_len)?;
+        let envelope = rmp_serde::from_slice::<Envelope>(&prefix[body_start..])
+            .map_err(|e| Error::CorruptHeader(e.to_string()))?;
+        if envelope.nonce.len() != NONCE_LEN
+            || envelope.chunk_len == 0
+            || envelope.chunk_len > MAX_CHUNK_LEN
+        {
+            return Err(Error::CorruptHeader(format!(
+                "Unusable encryption parameters: {:?}",
+                envelope
+            )));
+        }
+        let key = envelope.kdf.derive_key(secret)?;
+        let key = Key::<Aes256Gcm>::from_slice(&key[..]);
+        Ok(Self {
+            inner,
+            prefix,
+            decryptor: Some(DecryptorBE32::new(key, envelope.nonce.as_slice().into())),
+            chunk_len: envelope.chunk_len as usize,
+            encrypted: Vec::new(),
+            decrypted: Vec::new(),
+            read: 0,
+        })
+    }
+
+    /// Decrypt the next chunk into [decrypted]
+    fn decrypt_chunk(&mut self) -> Result<()> {
+        let full_len = self.chunk_len + TAG_LEN;
+        // One more byte than a whole chunk, to tell whether anything follows it
+        let wanted = (full_len + 1 - self.encrypted.len()) as u64;
+        (&mut self.inner)
+            .take(wanted)
+            .read_to_end(&mut self.encrypted)?;
+        let is_last = self.encrypted.len() <= full_len;
+        let rest = match is_last {
+            true => Vec::new(),
+            false => self.encrypted.split_off(full_len),
+        };
+        let payload = Payload {
+            msg: &self.encrypted,
+            aad: &self.prefix,
+        };
+        let decrypted = match is_last {
+            true => self.decryptor.take().unwrap().decrypt_last(payload),
+            false => self.decryptor.as_mut().unwrap().decrypt_next(payload),
+        };
+        self.decrypted = decrypted.map_err(|_| {
+            Error::Decryption(String::from(
+                "wrong passphrase or key, or the input is damaged or has been tampered with",
+            ))
+        })?;
+        self.read = 0;
+        self.encrypted = rest;
+        Ok(())
+    }
+}
+
+impl<R: Read> Read for DecryptReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        while self.read == self.decrypted.len() {
+            if self.decryptor.is_none() || buf.is_empty() {
+                return Ok(0);
+            }
+            self.decrypt_chunk()?;
+        }
+        let len = buf.len().min(self.decrypted.len() - self.read);
+        buf[..len].copy_from_slice(&self.decrypted[self.read..self.read + len]);
+        self.read += len;
+        Ok(len)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::{Read, Write};
+
+    use crate::crypto::{
+        is_encrypted, DecryptReader, EncryptWriter, EncryptionKey, Passphrase, Secret, CHUNK_LEN,
+        KEY_LEN,
+    };
+    use crate::decode::{decode_to_writer, decode_to_writer_with_options, DecodeOptions};
+    use crate::encode::{encode_from_reader, EncodeOptions};
+    use crate::Error;
+
+    fn encrypt(input: &[u8], secret: &Secret) -> Vec<u8> {
+        let mut writer = EncryptWriter::new(Vec::new(), secret).unwrap();
+        for piece in input.chunks(10_000) {
+            writer.write_all(piece).unwrap();
+        }
+        let (encrypted, written) = writer.finish().unwrap();
+        assert_eq!(written, encrypted.len() as u64);
+        encrypted
+    }
+
+    fn decrypt(encrypted: &[u8], secret: &Secret) -> crate::Result<Vec<u8>> {
+        let mut decrypted = Vec::new();
+        DecryptReader::new(encrypted, secret)?.read_to_end(&mut decrypted)?;
+        Ok(decrypted)
+    }
+
+    #[test]
+    fn round_trip() {
+        let passphrase = Secret::from(Passphrase::new("correct horse battery staple"));
+        let chunk_len = CHUNK_LEN as usize;
+        for len in [0, 1, chunk_len, chunk_len + 1, chunk_len * 3] {
+            let input: Vec<u8> = (0..len).map(|i| (i % 251) as u8).collect();
+            let encrypted = encrypt(&input, &passphrase);
+            assert!(is_encrypted(&encrypted[..]).unwrap());
+            assert_eq!(decrypt(&encrypted, &passphrase).unwrap(), input);
+        }
+
+        let input = vec![7; chunk_len * 2];
+        let encrypted = encrypt(&input, &passphrase);
+        // The same input encrypts differently every time
+        assert_ne!(encrypt(&input, &passphrase), encrypted);
+        let wrong = Passphrase::new("incorrect horse");
+        assert_eq!(format!("{:?}", wrong), "Passphrase(..)");
+        assert!(matches!(
+            decrypt(&encrypted, &wrong.into()),
+            Err(Error::Decryption(_))
+        ));
+        // Anything changed, or cut off at a chunk boundary, fails rather than decrypting
+        let mut flipped = encrypted.clone();
+        *flipped.last_mut().unwrap() ^= 1;
+        let cut = &encrypted[..encrypted.len() - chunk_len - 16];
+        let extended = [&encrypted[..], b"more"].concat();
+        for bad in [&flipped[..], cut, &extended] {
+            assert!(matches!(
+                decrypt(bad, &passphrase),
+                Err(Error::Decryption(_))
+            ));
+        }
+    }
+
+    #[test]
+    fn encoded() {
+        let passphrase = Passphrase::new("lizard");
+        let options = EncodeOptions::default().encrypt(passphrase.clone());
+        let text: Vec<u8> = (0..5000)
+            .flat_map(|i| format!("line {}\n", i % 37).into_bytes())
+            .collect();
+        // Short input ends up a stored frame, which is encrypted the same way
+        for input in [&b"ab"[..], &text] {
+            let mut encoded = Vec::new();
+            let summary = encode_from_reader(input, &mut encoded, &options).unwrap();
+            assert_eq!(summary.output_bytes, encoded.len() as u64);
+            assert!(is_encrypted(&encoded[..]).unwrap());
+
+            let decode_options = DecodeOptions::default().passphrase(passphrase.clone());
+            let mut decoded = Vec::new();
+            decode_to_writer_with_options(&encoded[..], &mut decoded, &decode_options).unwrap();
+            assert_eq!(decoded, input);
+            assert!(matches!(
+                decode_to_writer(&encoded[..], std::io::sink()),
+                Err(Error::Decryption(_))
+            ));
+        }
+    }
+
+    #[test]
+    fn keys() {
+        let key = EncryptionKey::new([3; KEY_LEN]);
+        assert_eq!(format!("{:?}", key), "EncryptionKey(..)");
+        assert_eq!(key, EncryptionKey::new([3; KEY_LEN]));
+        assert_ne!(key, EncryptionKey::new([4; KEY_LEN]));
+        assert_ne!(Passphrase::new("lizard"), Passphrase::new("lizards"));
+        assert!(Passphrase::new("").is_empty());
+
+        let input = b"given a key rather than a passphrase".repeat(100);
+        let mut encoded = Vec::new();
+        let options = EncodeOptions::default().with_key(key.clone());
+        encode_from_reader(&input[..], &mut encoded, &options).unwrap();
+        let mut decoded = Vec::new();
+        let decode_options = DecodeOptions::default().with_key(key);
+        decode_to_writer_with_options(&encoded[..], &mut decoded, &decode_options).unwrap();
+        assert_eq!(decoded, input);
+        for wrong in [
+            DecodeOptions::default().with_key(EncryptionKey::new([4; KEY_LEN])),
+            DecodeOptions::default().passphrase(Passphrase::new("lizard")),
+        ] {
+            assert!(matches!(
+                decode_to_writer_with_options(&encoded[..], std::io::sink(), &wrong),
+                Err(Error::Decryption(_))
+            ));
+        }
+    }
+}
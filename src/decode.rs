@@ -1,189 +1,1455 @@
-use log::debug;
+//! Decoding streams back to the bytes they were made from. Decoded bytes are written out as
+//! soon as they've left the window matches can reach back into, so memory use is bounded by
+//! the window (plus up to [crate::MAX_LITERAL_RUN_LEN] literals being unpacked) rather than the
+//! size of the output. Threaded decoding also holds the blocks being decoded and any waiting on
+//! an earlier one. [decode_bytes] is the exception, as it returns the whole output
+
+use log::{debug, info, warn};
 use std::collections::VecDeque;
 use std::fs::File;
-use std::io::{BufReader, BufWriter, Read, Write};
+use std::io::{BufReader, Read, Write};
 
+use crate::control::{ControlRecord, Trailer, VarintReader};
+#[cfg(feature = "crypto")]
+use crate::crypto::{self, EncryptionKey, Passphrase, Secret};
+use crate::encode::Algorithm;
+use crate::entropy::EntropyCoder;
+use crate::error::{Error, Result};
 use crate::file_io::FileInputOutput;
-use crate::header::Header;
+use crate::filter::{Filter, Unfilter};
+use crate::format::stored;
+use crate::header::{self, Header};
+use crate::huffman::BitstreamDecoder;
 use crate::offset_len::OffsetLen;
-use crate::{helpers, ChunkMarker, MAX_LOOKBACK_BUFFER_LEN};
+use crate::output::output_writer;
+use crate::throttle::{Throttle, Throttled};
+use crate::token::{ChunkMarker, Token};
+use crate::volumes::VolumeReader;
+use crate::{
+    helpers, DEFAULT_MAX_HEADER_SIZE, DEFAULT_MAX_OUTPUT_SIZE, MAX_PRIMED_LEN,
+    MIN_THREADED_DECODE_LEN,
+};
+
+/// What happened during a decode, mirroring [crate::EncodeSummary]
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct DecodeSummary {
+    pub input_bytes: u64,
+    pub output_bytes: u64,
+    /// CRC32 of the decoded output
+    pub checksum: u32,
+}
+
+/// Options for decoding, built up from the defaults like [crate::EncodeOptions] e.g.
+/// `DecodeOptions::default().threads(4)`
+#[derive(Debug, Clone, PartialEq)]
+pub struct DecodeOptions {
+    /// Restore the original file's recorded attributes (modification time, permissions) onto
+    /// the output
+    pub(crate) preserve: bool,
+    /// Blocks written with [crate::EncodeOptions::block_size] are decoded on up to this many
+    /// threads at once. 1 decodes on the calling thread, as do inputs under
+    /// [crate::MIN_THREADED_DECODE_LEN]
+    pub(crate) threads: usize,
+    /// Decoding fails rather than give more than this many bytes, so a crafted input can't
+    /// expand enormously
+    pub(crate) max_output_size: u64,
+    /// Headers longer than this many bytes are rejected without reading them, as are control
+    /// records (huffman tables, BWT blocks) with longer values
+    pub(crate) max_header_size: usize,
+    /// Bytes per second the input file is read and the output written at, together
+    pub(crate) rate_limit: Option<u64>,
+    /// Write the output file on a thread of its own, see [DecodeOptions::write_behind]
+    pub(crate) write_behind: Option<usize>,
+    /// To decrypt encrypted input with
+    #[cfg(feature = "crypto")]
+    pub(crate) secret: Option<Secret>,
+}
+
+impl Default for DecodeOptions {
+    fn default() -> Self {
+        Self {
+            preserve: true,
+            threads: 1,
+            max_output_size: DEFAULT_MAX_OUTPUT_SIZE,
+            max_header_size: DEFAULT_MAX_HEADER_SIZE,
+            rate_limit: None,
+            write_behind: None,
+            #[cfg(feature = "crypto")]
+            secret: None,
+        }
+    }
+}
+
+impl DecodeOptions {
+    pub fn preserve(mut self, preserve: bool) -> Self {
+        self.preserve = preserve;
+        self
+    }
+
+    pub fn threads(mut self, threads: usize) -> Self {
+        self.threads = threads.max(1);
+        self
+    }
+
+    pub fn max_output_size(mut self, max_output_size: u64) -> Self {
+        self.max_output_size = max_output_size;
+        self
+    }
+
+    pub fn max_header_size(mut self, max_header_size: usize) -> Self {
+        self.max_header_size = max_header_size;
+        self
+    }
+
+    /// Read and write files ([decode], [crate::resume::resume_stream]) at no more than [rate_limit]
+    /// bytes per second between them, see [crate::throttle]
+    pub fn rate_limit(mut self, rate_limit: Option<u64>) -> Self {
+        self.rate_limit = rate_limit;
+        self
+    }
+
+    /// Write the output file ([decode]) on a thread of its own, holding up to [write_behind]
+    /// bytes which haven't been written yet, so decoding and writing the disk overlap, see
+    /// [crate::output]. Written in blocks of 256KiB, so anything less is one block
+    pub fn write_behind(mut self, write_behind: Option<usize>) -> Self {
+        self.write_behind = write_behind;
+        self
+    }
+
+    /// A new [Throttle] for one file's reads and writes, if there's a rate limit
+    pub fn throttle(&self) -> Option<Throttle> {
+        self.rate_limit.map(Throttle::new)
+    }
+
+    /// Decrypt input encrypted with [crate::EncodeOptions::encrypt] using [passphrase]
+    #[cfg(feature = "crypto")]
+    pub fn passphrase(mut self, passphrase: Passphrase) -> Self {
+        self.secret = Some(Secret::Passphrase(passphrase));
+        self
+    }
+
+    /// Decrypt input encrypted with [crate::EncodeOptions::with_key] using [key]
+    #[cfg(feature = "crypto")]
+    pub fn with_key(mut self, key: EncryptionKey) -> Self {
+        self.secret = Some(Secret::Key(key));
+        self
+    }
+
+    pub(crate) fn output_limit(&self) -> Error {
+        Error::LimitExceeded {
+            limit: "output size",
+            max: self.max_output_size,
+        }
+    }
+
+    /// Fail if a control record's value of [len] bytes is longer than
+    /// [DecodeOptions::max_header_size], before it's buffered
+    pub(crate) fn check_control_len(&self, len: u64) -> Result<()> {
+        match len <= self.max_header_size as u64 {
+            true => Ok(()),
+            false => Err(Error::LimitExceeded {
+                limit: "header size",
+                max: self.max_header_size as u64,
+            }),
+        }
+    }
+
+    /// Fail if having decoded [len] bytes goes past [DecodeOptions::max_output_size]
+    pub(crate) fn check_output_len(&self, len: u64) -> Result<()> {
+        match len <= self.max_output_size {
+            true => Ok(()),
+            false => Err(self.output_limit()),
+        }
+    }
+}
+
+/// Decode the file, or the series of volumes it names (see [crate::volumes])
+pub fn decode(file_io: &FileInputOutput, options: &DecodeOptions) -> Result<DecodeSummary> {
+    let input = VolumeReader::open(file_io.encoded_filename.as_path())?;
+    let input_bytes = input.len();
+    let throttle = options.throttle();
+    let reader = BufReader::new(Throttled::new(input, throttle.clone()));
+    let outf = File::create(file_io.unencoded_filename.as_path())?;
+    let writer = output_writer(Throttled::new(outf, throttle), options.write_behind)?;
+
+    let mut options = options.clone();
+    if input_bytes < MIN_THREADED_DECODE_LEN {
+        options.threads = 1;
+    }
+    // Output is written as it's decoded, so don't leave half of it behind on failure
+    let (mut summary, header) = match decode_with_header(reader, writer, &options) {
+        Ok(decoded) => decoded,
+        Err(e) => {
+            let _ = std::fs::remove_file(file_io.unencoded_filename.as_path());
+            return Err(e);
+        }
+    };
+    summary.input_bytes = input_bytes;
+    if options.preserve {
+        if let Some(file_metadata) = header.file_metadata() {
+            file_metadata.apply(file_io.unencoded_filename.as_path())?;
+        }
+    }
+    info!(
+        "Decoded {:?} -> {:?} ({} -> {} bytes)",
+        file_io.encoded_filename,
+        file_io.unencoded_filename,
+        summary.input_bytes,
+        summary.output_bytes
+    );
+    Ok(summary)
+}
+
+/// Decode a lizards stream from [reader], writing the decoded bytes to [writer]. Returns the
+/// number of bytes written
+pub fn decode_to_writer<R: Read, W: Write>(reader: R, writer: W) -> Result<u64> {
+    decode_to_writer_with_options(reader, writer, &DecodeOptions::default())
+}
+
+/// [decode_to_writer] with [options]' threads and limits, e.g. to decode untrusted input
+/// within a tighter [DecodeOptions::max_output_size]
+pub fn decode_to_writer_with_options<R: Read, W: Write>(
+    reader: R,
+    writer: W,
+    options: &DecodeOptions,
+) -> Result<u64> {
+    decode_with_header(reader, writer, options).map(|(summary, _)| summary.output_bytes)
+}
+
+/// Decode a whole lizards stream held in memory
+pub fn decode_bytes(bytes: &[u8]) -> Result<Vec<u8>> {
+    decode_stream(&mut &bytes[..], &DecodeOptions::default()).map(|(output, _)| output)
+}
+
+/// Decode a lizards stream from [reader] without keeping the output, checking it has the CRC32
+/// [expected_checksum] (e.g. [crate::EncodeSummary::checksum] from encoding it)
+pub fn verify<R: Read>(reader: R, expected_checksum: u32) -> Result<()> {
+    let (summary, _) = decode_with_header(reader, std::io::sink(), &DecodeOptions::default())?;
+    if summary.checksum == expected_checksum {
+        Ok(())
+    } else {
+        Err(Error::VerificationFailed {
+            expected: expected_checksum,
+            actual: summary.checksum,
+        })
+    }
+}
+
+/// Decode a lizards stream from [reader] without keeping the output, as `gzip -t` does. Errors
+/// if the stream is malformed or any of its checksums don't match
+pub fn check<R: Read>(reader: R, options: &DecodeOptions) -> Result<DecodeSummary> {
+    decode_with_header(reader, std::io::sink(), options).map(|(summary, _)| summary)
+}
+
+/// Decode the stream from [reader], decrypting it first if it's encrypted. Returns the first
+/// frame's header
+pub(crate) fn decode_with_header<R: Read, W: Write>(
+    mut reader: R,
+    writer: W,
+    options: &DecodeOptions,
+) -> Result<(DecodeSummary, Header)> {
+    let mut prefix = Vec::with_capacity(header::FRAME_PREFIX_LEN);
+    (&mut reader)
+        .take(header::FRAME_PREFIX_LEN as u64)
+        .read_to_end(&mut prefix)?;
+    let reader = prefix.as_slice().chain(reader);
+    match header::is_encrypted(&prefix) {
+        true => decode_encrypted(reader, writer, options),
+        false => decode_plain(reader, writer, options),
+    }
+}
+
+/// Read the first frame's header from [reader], decrypting it first if need be, e.g. to see the
+/// original file's name before deciding where to decode to
+pub fn read_header<R: Read>(mut reader: R, options: &DecodeOptions) -> Result<Header> {
+    let mut prefix = Vec::with_capacity(header::FRAME_PREFIX_LEN);
+    (&mut reader)
+        .take(header::FRAME_PREFIX_LEN as u64)
+        .read_to_end(&mut prefix)?;
+    let reader = prefix.as_slice().chain(reader);
+    match header::is_encrypted(&prefix) {
+        true => decrypt_then(reader, options, |reader| {
+            Header::read_from_limited(reader, options.max_header_size)
+        }),
+        false => Header::read_from_limited(reader, options.max_header_size),
+    }
+}
+
+fn decode_encrypted<R: Read, W: Write>(
+    reader: R,
+    writer: W,
+    options: &DecodeOptions,
+) -> Result<(DecodeSummary, Header)> {
+    decrypt_then(reader, options, |reader| {
+        decode_plain(reader, writer, options)
+    })
+}
 
-pub fn decode(file_io: &FileInputOutput) {
+/// Call [then] with [reader] decrypted using [options]' passphrase or key
+#[cfg(feature = "crypto")]
+fn decrypt_then<R: Read, T>(
+    reader: R,
+    options: &DecodeOptions,
+    then: impl FnOnce(&mut dyn Read) -> Result<T>,
+) -> Result<T> {
+    let secret = options.secret.as_ref().ok_or_else(|| {
+        Error::Decryption(String::from(
+            "the input is encrypted, but no passphrase or key was given",
+        ))
+    })?;
+    then(&mut crypto::DecryptReader::new(reader, secret)?)
+}
+
+#[cfg(not(feature = "crypto"))]
+fn decrypt_then<R: Read, T>(
+    _: R,
+    _: &DecodeOptions,
+    _: impl FnOnce(&mut dyn Read) -> Result<T>,
+) -> Result<T> {
+    Err(Error::FeatureDisabled("crypto"))
+}
+
+fn decode_plain<R: Read, W: Write>(
+    mut reader: R,
+    writer: W,
+    options: &DecodeOptions,
+) -> Result<(DecodeSummary, Header)> {
+    let mut writer = helpers::CountingWriter::new(helpers::RetryWriter::new(writer));
+    let header = match options.threads {
+        1 => decode_stream_to(&mut reader, &mut writer, options)?,
+        _ => crate::blocks::decode_stream_threaded(&mut reader, &mut writer, options)?,
+    };
+    debug!(
+        "Decoded format version {} {:?} stream",
+        header.version(),
+        header.algorithm()
+    );
+    writer.flush()?;
+    let summary = DecodeSummary {
+        input_bytes: 0,
+        output_bytes: writer.count(),
+        checksum: writer.checksum(),
+    };
+    Ok((summary, header))
+}
+
+/// Decode the whole stream from [reader] into memory
+pub(crate) fn decode_stream<R: Read>(
+    reader: &mut R,
+    options: &DecodeOptions,
+) -> Result<(Vec<u8>, Header)> {
+    let mut output = Vec::new();
+    let header = decode_stream_to(reader, &mut output, options)?;
+    Ok((output, header))
+}
+
+/// Decode the stream from [reader], writing the decoded bytes to [writer] as soon as they've
+/// left the window, so nothing more than that is held whatever the size of the output.
+/// Returns the first frame's header
+pub(crate) fn decode_stream_to<R: Read, W: Write>(
+    reader: &mut R,
+    writer: &mut W,
+    options: &DecodeOptions,
+) -> Result<Header> {
+    decode_frames(reader, writer, options, None)
+}
+
+/// Decode a lizards stream from [reader] without keeping the output, calling [on_token] with
+/// each token as it's decoded, e.g. to compare how two files were coded. Literals are given one
+/// by one, as [crate::trace] has them, and frames written with [crate::Algorithm::Stored] are
+/// all literals. Encrypted input isn't supported
+pub fn for_each_token<R: Read>(
+    mut reader: R,
+    options: &DecodeOptions,
+    mut on_token: impl FnMut(Token),
+) -> Result<DecodeSummary> {
+    let mut writer = helpers::CountingWriter::new(std::io::sink());
+    decode_frames(&mut reader, &mut writer, options, Some(&mut on_token))?;
+    Ok(DecodeSummary {
+        input_bytes: 0,
+        output_bytes: writer.count(),
+        checksum: writer.checksum(),
+    })
+}
+
+/// Called with each token as it's decoded
+pub(crate) type TokenSink<'a> = Option<&'a mut dyn FnMut(Token)>;
+
+/// [tokens] for passing on while keeping hold of it
+fn reborrow<'a>(tokens: &'a mut TokenSink) -> TokenSink<'a> {
+    match tokens {
+        Some(tokens) => Some(&mut **tokens),
+        None => None,
+    }
+}
+
+fn decode_frames<R: Read, W: Write>(
+    reader: &mut R,
+    writer: &mut W,
+    options: &DecodeOptions,
+    mut tokens: TokenSink,
+) -> Result<Header> {
+    let mut writer = helpers::TailWriter::new(helpers::CountingWriter::new(writer), MAX_PRIMED_LEN);
+    let header = decode_frame(reader, &mut writer, options, &[], reborrow(&mut tokens))?;
+    let decoded = writer.get_ref().count();
+    decode_following_frames(reader, &mut writer, options, decoded, tokens)?;
+    Ok(header)
+}
+
+/// Decode the frames following the first in [reader], e.g. from a
+/// [crate::encode::StreamEncoder], once [decoded] bytes have come out of those before. [writer]
+/// holds the end of the previous frame's output, for frames whose window starts with it.
+/// An archive's index between frames is skipped, and anything else other than another frame
+/// after the end of one is ignored
+pub(crate) fn decode_following_frames<R: Read, W: Write>(
+    reader: &mut R,
+    writer: &mut helpers::TailWriter<W>,
+    options: &DecodeOptions,
+    mut decoded: u64,
+    mut tokens: TokenSink,
+) -> Result<()> {
+    loop {
+        let mut prefix = Vec::with_capacity(header::FRAME_PREFIX_LEN);
+        (&mut *reader)
+            .take(header::FRAME_PREFIX_LEN as u64)
+            .read_to_end(&mut prefix)?;
+        if prefix.is_empty() || header::starts_signature(&prefix) {
+            return Ok(());
+        }
+        // Entries appended to an archive follow its index
+        if header::starts_index(&prefix) {
+            crate::archive::skip_index(reader)?;
+            continue;
+        }
+        if !header::starts_frame(&prefix) {
+            warn!("Ignoring trailing bytes after the end of the stream");
+            return Ok(());
+        }
+        let mut options = options.clone();
+        options.max_output_size = options.max_output_size.saturating_sub(decoded);
+        let history = writer.take_tail();
+        let mut writer = helpers::CountingWriter::new(&mut *writer);
+        decode_frame(
+            &mut prefix.chain(&mut *reader),
+            &mut writer,
+            &options,
+            &history,
+            reborrow(&mut tokens),
+        )?;
+        decoded += writer.count();
+    }
+}
+
+/// Decode one frame from [reader], reading no further than its end. [history] is the end of
+/// what the previous frame decoded to, for a frame whose window starts with it
+pub(crate) fn decode_frame<R: Read, W: Write>(
+    reader: &mut R,
+    writer: &mut W,
+    options: &DecodeOptions,
+    history: &[u8],
+    tokens: TokenSink,
+) -> Result<Header> {
+    let header = Header::read_from_limited(&mut *reader, options.max_header_size)?;
+    if header.algorithm() == Algorithm::Stored {
+        match tokens {
+            Some(tokens) => stored::read(
+                reader,
+                &mut LiteralWriter { writer, tokens },
+                options.max_output_size,
+            )?,
+            None => stored::read(reader, writer, options.max_output_size)?,
+        };
+        return Ok(header);
+    }
+    let primer = match history.len().checked_sub(header.primed_len()) {
+        Some(start) => &history[start..],
+        None => {
+            return Err(Error::CorruptHeader(format!(
+                "Window primed with {} bytes, but only {} came before the frame",
+                header.primed_len(),
+                history.len()
+            )))
+        }
+    };
+    decode_tokens(reader, header, writer, options, primer, tokens)
+}
+
+/// Passes on what's written to [writer], giving [tokens] each byte as a literal, as that's
+/// what a stored frame's bytes are
+struct LiteralWriter<'a, W: Write> {
+    writer: &'a mut W,
+    tokens: &'a mut dyn FnMut(Token),
+}
+
+impl<W: Write> Write for LiteralWriter<'_, W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let written = self.writer.write(buf)?;
+        buf[..written]
+            .iter()
+            .for_each(|v| (self.tokens)(Token::Literal(*v)));
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.writer.flush()
+    }
+}
+
+/// Decode the tokens following [header] in [reader], with [primer] already in the window
+fn decode_tokens<R: Read, W: Write>(
+    reader: &mut R,
+    mut header: Header,
+    writer: &mut W,
+    options: &DecodeOptions,
+    primer: &[u8],
+    mut tokens: TokenSink,
+) -> Result<Header> {
     let mut input_buffer: [u8; 1] = [0b0; 1];
-    let mut output_buffer = Vec::<u8>::new();
-    let mut read_buffer = VecDeque::<u8>::new();
+    // Borrowed for as long as the writer is
+    let tokens = reborrow(&mut tokens);
+    let mut output = DecodedOutput::new(writer, header.filter(), primer.len(), tokens);
+    let mut read_buffer = VecDeque::<u8>::from(primer.to_vec());
     let mut raw_byte_buffer = Vec::<u8>::new();
     let mut offset_len_read_buffer = Vec::<u8>::new();
-    let mut header_buffer = Vec::<u8>::new();
-
-    let f = File::open(file_io.encoded_filename.as_path()).unwrap();
-    let mut reader = BufReader::new(f);
+    let mut control_value_buffer = Vec::<u8>::new();
 
-    let mut decode_state = DecodeParseState::Start;
-    let mut header = None;
+    let mut decode_state = state_after_header(&header);
 
     loop {
         let result = reader.read(&mut input_buffer);
 
         debug!("State: {:?}", decode_state);
         match result {
-            Err(e) => panic!("Error reading file: {}", e),
+            Err(e) => return Err(Error::Io(e)),
             Ok(0) => break,
             Ok(1) => {
                 let v = input_buffer[0];
                 debug!("{:#010b} : {:?}", v, String::from_utf8(vec![v]));
                 match decode_state {
-                    DecodeParseState::Start => {
-                        header_buffer = vec![v];
-                        decode_state = DecodeParseState::ReadingHeaderLen(v);
-                    }
-                    DecodeParseState::ReadingHeaderLen(first_byte) => {
-                        header_buffer.push(v);
-                        let header_len = u16::from_be_bytes([first_byte, v]) as usize;
-                        decode_state = DecodeParseState::ReadingHeader(header_len - 2);
-                    }
-                    DecodeParseState::ReadingHeader(remaining) => {
-                        header_buffer.push(v);
-                        match remaining - 1 {
-                            0 => {
-                                header = Some(Header::from_bytes(&header_buffer));
-                                decode_state = DecodeParseState::ExpectingMatchOrRawChunk;
-                            }
-                            decr => {
-                                decode_state = DecodeParseState::ReadingHeader(decr);
-                            }
-                        }
-                    }
                     DecodeParseState::ExpectingMatchOrRawChunk => {
                         match v >> 6 {
                             0b10 => {
-                                let (num_offset_bytes, num_len_bytes) =
-                                    OffsetLen::read_header_byte(v);
+                                unpack_raw_bytes(
+                                    &mut raw_byte_buffer,
+                                    &header,
+                                    &mut read_buffer,
+                                    &mut output,
+                                    options,
+                                )?;
                                 offset_len_read_buffer.clear();
                                 offset_len_read_buffer.push(v);
-                                decode_state = DecodeParseState::OffsetLenRead(
-                                    num_offset_bytes + num_len_bytes,
-                                );
+                                decode_state = DecodeParseState::OffsetLenRead;
                             }
                             0b11 => {
                                 let marker = ChunkMarker::from_encoded_u8(v);
-                                decode_state = DecodeParseState::RawByteChunk(
-                                    marker.len,
-                                    RawByteReadOnFinish::Nothing,
-                                )
+                                // The encoder never writes an empty chunk
+                                if marker.len == 0 {
+                                    return Err(Error::InvalidToken(v));
+                                }
+                                decode_state = DecodeParseState::RawByteChunk(marker.len)
                             }
-                            other => {
-                                panic!("Did not get leading bits expected: {}  ({:#010b}", other, v)
+                            // Versions without control records fall through to be invalid
+                            0b00 if header.format().control_records => {
+                                control_value_buffer.clear();
+                                decode_state =
+                                    DecodeParseState::ControlLen(v, VarintReader::default());
                             }
+                            _ => return Err(Error::InvalidToken(v)),
                         }
                         //Accept either control byte or chunk marker
                     }
-                    DecodeParseState::RawByteChunk(remaining, on_finish) => {
+                    DecodeParseState::RawByteChunk(remaining) => {
                         //Read u8 as is.
                         // decr [remaining]
                         // if zero, state -> DecodeParseState::None
                         raw_byte_buffer.push(v);
+                        if raw_byte_buffer.len() > MAX_PACKED_RUN_LEN
+                            && header.format().capped_literal_runs
+                        {
+                            return Err(Error::LimitExceeded {
+                                limit: "literal run",
+                                max: MAX_PACKED_RUN_LEN as u64,
+                            });
+                        }
                         match remaining - 1 {
-                            0 => {
-                                if let Some(header) = &header {
-                                    let unpacked_bytes = crate::huffman::unpack_bytes(
-                                        &raw_byte_buffer,
-                                        &header.huffman_tree,
-                                    );
-                                    read_buffer.extend(unpacked_bytes);
-                                    raw_byte_buffer.clear();
-                                }
-                                match on_finish {
-                                    RawByteReadOnFinish::Nothing => (),
-                                    RawByteReadOnFinish::FinaliseMatch(offset_len) => {
-                                        finalise_match(&mut read_buffer, &offset_len);
-                                    }
-                                }
-                                decode_state = DecodeParseState::ExpectingMatchOrRawChunk
-                            }
-                            decr => decode_state = DecodeParseState::RawByteChunk(decr, on_finish),
+                            // Don't unpack yet, as the next token may be another chunk
+                            // continuing this one
+                            0 => decode_state = DecodeParseState::ExpectingMatchOrRawChunk,
+                            decr => decode_state = DecodeParseState::RawByteChunk(decr),
                         }
                     }
-                    DecodeParseState::OffsetLenRead(remaining_bytes) => {
-                        offset_len_read_buffer.push(v);
-                        match remaining_bytes - 1 {
-                            0 => {
-                                let offset_len = OffsetLen::of_bytes_new(&offset_len_read_buffer);
-                                finalise_match(&mut read_buffer, &offset_len);
-                                decode_state = DecodeParseState::ExpectingMatchOrRawChunk
+                    DecodeParseState::HuffmanBitstream(mut decoder) => {
+                        let decoded = read_buffer.len();
+                        let finished = decoder.push_byte(v, &mut read_buffer);
+                        output.add_literals(&read_buffer, decoded);
+                        decode_state = match finished {
+                            // The rest is control records, i.e. the end of stream
+                            true => DecodeParseState::ExpectingMatchOrRawChunk,
+                            false => DecodeParseState::HuffmanBitstream(decoder),
+                        };
+                    }
+                    DecodeParseState::ControlLen(control_byte, mut varint_reader) => {
+                        decode_state = match varint_reader.push(v)? {
+                            None => DecodeParseState::ControlLen(control_byte, varint_reader),
+                            Some(0) => apply_control_record(
+                                control_byte,
+                                &[],
+                                &mut header,
+                                &mut raw_byte_buffer,
+                                &mut output,
+                                &mut read_buffer,
+                                options,
+                            )?,
+                            // Nothing of an unknown record is needed, so it isn't kept
+                            Some(len) if ControlRecord::is_skippable(control_byte) => {
+                                DecodeParseState::ControlSkip(len)
                             }
-                            decr => decode_state = DecodeParseState::OffsetLenRead(decr),
+                            Some(len) => {
+                                options.check_control_len(len)?;
+                                DecodeParseState::ControlValue(control_byte, len)
+                            }
+                        };
+                    }
+                    DecodeParseState::ControlValue(control_byte, remaining) => {
+                        control_value_buffer.push(v);
+                        decode_state = match remaining - 1 {
+                            0 => apply_control_record(
+                                control_byte,
+                                &control_value_buffer,
+                                &mut header,
+                                &mut raw_byte_buffer,
+                                &mut output,
+                                &mut read_buffer,
+                                options,
+                            )?,
+                            decr => DecodeParseState::ControlValue(control_byte, decr),
+                        };
+                    }
+                    DecodeParseState::ControlSkip(remaining) => {
+                        decode_state = match remaining - 1 {
+                            0 => DecodeParseState::ExpectingMatchOrRawChunk,
+                            decr => DecodeParseState::ControlSkip(decr),
+                        };
+                    }
+                    DecodeParseState::Finished => unreachable!("Stopped at the end of stream"),
+                    DecodeParseState::OffsetLenRead => {
+                        offset_len_read_buffer.push(v);
+                        let binned = header.format().binned_matches;
+                        let expected_len = match binned {
+                            true => OffsetLen::binned_len(&offset_len_read_buffer),
+                            false => Some(OffsetLen::whole_bytes_len(offset_len_read_buffer[0])),
+                        };
+                        if expected_len == Some(offset_len_read_buffer.len()) {
+                            let (offset, len) = match binned {
+                                true => OffsetLen::read_binned(&offset_len_read_buffer),
+                                false => OffsetLen::read_whole_bytes(&offset_len_read_buffer),
+                            };
+                            // Before version 6 offsets were an index into the window
+                            let distance = match header.format().relative_offsets {
+                                true => Some(offset),
+                                false => (read_buffer.len() as u64).checked_sub(offset),
+                            };
+                            let offset_len =
+                                finalise_match(&mut read_buffer, distance, (offset, len))?;
+                            output.add_token(Token::Match(offset_len));
+                            decode_state = DecodeParseState::ExpectingMatchOrRawChunk
                         }
                     }
                 }
-                // We use max "Lookback" buffer len here because the offsets generated by
-                // matching when encoding are from the lookback buffer
-                while read_buffer.len() > MAX_LOOKBACK_BUFFER_LEN {
-                    output_buffer.push(read_buffer.pop_front().unwrap());
+                output.take_from_window(&mut read_buffer, header.history_len())?;
+                options.check_output_len(output.len_with(&read_buffer))?;
+                // Whatever follows isn't part of this frame, so leave it unread
+                if let DecodeParseState::Finished = decode_state {
+                    break;
                 }
             }
             Ok(n) => panic!("Read more than expected bytes: {}", n),
         }
     }
 
-    //Handle final decode state
+    // Only finishing after the trailer (or between tokens, for streams without one) is valid,
+    // anywhere else means the input was cut short
     match decode_state {
-        DecodeParseState::Start | DecodeParseState::ExpectingMatchOrRawChunk => (),
-        DecodeParseState::ReadingHeaderLen(_) | DecodeParseState::ReadingHeader(_) => {
-            panic!("Ended parsing file while still reading header");
+        DecodeParseState::Finished => (),
+        DecodeParseState::ExpectingMatchOrRawChunk => {
+            if header.format().trailer {
+                return Err(Error::TruncatedStream("end of stream marker"));
+            }
         }
-        DecodeParseState::RawByteChunk(_, RawByteReadOnFinish::Nothing) => {
-            panic!("Ended parsing file but still just expecting to read raw bytes");
+        DecodeParseState::RawByteChunk(_) => {
+            return Err(Error::TruncatedStream("raw byte chunk"));
         }
-        DecodeParseState::RawByteChunk(
-            num_bytes_left,
-            RawByteReadOnFinish::FinaliseMatch(offset_len),
-        ) => {
-            //If we finish the file with a partial match, we can infer there was some repetition?
+        DecodeParseState::OffsetLenRead => {
+            return Err(Error::TruncatedStream("offset len"));
         }
-        DecodeParseState::OffsetLenRead(_) => {
-            panic!("Ended parsing file but still not finished reading command bytes")
+        DecodeParseState::HuffmanBitstream(_) => {
+            return Err(Error::TruncatedStream("huffman bitstream"));
+        }
+        DecodeParseState::ControlLen(..)
+        | DecodeParseState::ControlValue(..)
+        | DecodeParseState::ControlSkip(_) => {
+            return Err(Error::TruncatedStream("control record"));
         }
     }
 
-    // TODO, stream output
-    println!("Writing to output file");
-    let outf = File::create(file_io.unencoded_filename.as_path()).unwrap();
-    let mut writer = BufWriter::new(outf);
-    read_buffer.make_contiguous();
-    output_buffer.extend_from_slice(read_buffer.as_slices().0);
-    writer.write_all(&output_buffer);
-    println!("Done");
+    unpack_raw_bytes(
+        &mut raw_byte_buffer,
+        &header,
+        &mut read_buffer,
+        &mut output,
+        options,
+    )?;
+    output.take_from_window(&mut read_buffer, 0)?;
+    output.finish()?;
+    Ok(header)
 }
 
-#[derive(Debug)]
-enum RawByteReadOnFinish {
-    Nothing,
-    FinaliseMatch(OffsetLen),
+/// How many decoded bytes are gathered up before being unfiltered and written out
+const OUTPUT_CHUNK_LEN: usize = 1 << 16;
+
+/// Where decoded bytes go once they've left the window. They're checksummed for the trailer,
+/// which describes them as they were before unfiltering, then unfiltered and written out
+struct DecodedOutput<'w, W: Write> {
+    writer: &'w mut W,
+    unfilter: Unfilter,
+    /// Waiting to be written, so [writer] gets a few big writes rather than one per byte
+    pending: Vec<u8>,
+    /// Of all the bytes given so far, including [pending]
+    len: u64,
+    /// Of the bytes already written, before unfiltering
+    hasher: crc32fast::Hasher,
+    /// How many bytes at the front of the window came from the previous frame, and so are
+    /// dropped rather than written when they leave it
+    primed: usize,
+    /// Given each token as it's decoded, see [for_each_token]
+    tokens: TokenSink<'w>,
+}
+
+impl<'w, W: Write> DecodedOutput<'w, W> {
+    fn new(writer: &'w mut W, filter: Filter, primed: usize, tokens: TokenSink<'w>) -> Self {
+        Self {
+            writer,
+            unfilter: Unfilter::new(filter),
+            pending: Vec::with_capacity(OUTPUT_CHUNK_LEN),
+            len: 0,
+            hasher: crc32fast::Hasher::new(),
+            primed,
+            tokens,
+        }
+    }
+
+    fn add_token(&mut self, token: Token) {
+        if let Some(tokens) = &mut self.tokens {
+            tokens(token);
+        }
+    }
+
+    /// Give [DecodedOutput::tokens] the bytes of [read_buffer] from [start] on, which were just
+    /// decoded as literals
+    fn add_literals(&mut self, read_buffer: &VecDeque<u8>, start: usize) {
+        if let Some(tokens) = &mut self.tokens {
+            read_buffer
+                .range(start..)
+                .for_each(|v| tokens(Token::Literal(*v)));
+        }
+    }
+
+    fn len(&self) -> u64 {
+        self.len
+    }
+
+    /// Take bytes from the front of [read_buffer] until it's down to [window] bytes. Before
+    /// version 6 offsets index into the encoder's lookback buffer, so it has to be kept at
+    /// exactly its len. Since then it only has to hold at least the window
+    fn take_from_window(&mut self, read_buffer: &mut VecDeque<u8>, window: usize) -> Result<()> {
+        let dropped = self.primed.min(read_buffer.len().saturating_sub(window));
+        read_buffer.drain(..dropped);
+        self.primed -= dropped;
+        while read_buffer.len() > window {
+            let take = (read_buffer.len() - window).min(OUTPUT_CHUNK_LEN - self.pending.len());
+            self.pending.extend(read_buffer.drain(..take));
+            self.len += take as u64;
+            if self.pending.len() == OUTPUT_CHUNK_LEN {
+                self.write_pending()?;
+            }
+        }
+        Ok(())
+    }
+
+    fn write_pending(&mut self) -> Result<()> {
+        self.hasher.update(&self.pending);
+        self.unfilter.apply(&mut self.pending);
+        self.writer.write_all(&self.pending)?;
+        self.pending.clear();
+        Ok(())
+    }
+
+    /// How many bytes this frame has decoded to, given those left in [rest]
+    fn len_with(&self, rest: &VecDeque<u8>) -> u64 {
+        self.len + (rest.len() - self.primed) as u64
+    }
+
+    /// CRC32 of everything given so far followed by [rest], before unfiltering
+    fn checksum_with(&self, rest: &VecDeque<u8>) -> u32 {
+        let mut hasher = self.hasher.clone();
+        hasher.update(&self.pending);
+        let (front, back) = rest.as_slices();
+        let primed_front = self.primed.min(front.len());
+        hasher.update(&front[primed_front..]);
+        hasher.update(&back[self.primed - primed_front..]);
+        hasher.finalize()
+    }
+
+    fn finish(mut self) -> Result<()> {
+        self.write_pending()
+    }
 }
 
 #[derive(Debug)]
 enum DecodeParseState {
-    Start,
-    ReadingHeaderLen(u8),
-    ReadingHeader(usize),
-    RawByteChunk(u8, RawByteReadOnFinish),
+    RawByteChunk(u8),
     ExpectingMatchOrRawChunk,
-    OffsetLenRead(usize),
-}
-
-fn finalise_match(read_buffer: &mut VecDeque<u8>, offset_len: &OffsetLen) {
-    let values_from_buf: Vec<u8> = {
-        let range = offset_len.to_range();
-        if range.end > read_buffer.len() {
-            panic!(
-                "Range loaded from file exceeds read_buffer ({:?}):\n{} ({})",
-                offset_len,
-                helpers::read_buffer_to_string(&read_buffer),
-                read_buffer.len()
+    /// Reading a match, whose bytes so far are in the offset len read buffer
+    OffsetLenRead,
+    /// Decoding a stream which is a single huffman bitstream, up to its END code
+    HuffmanBitstream(BitstreamDecoder),
+    /// Reading the length of the control record started by the u8
+    ControlLen(u8, VarintReader),
+    /// Reading the value of the control record started by the u8, with this many bytes to go
+    ControlValue(u8, u64),
+    /// Skipping the value of an optional control record of a type this version doesn't know,
+    /// with this many bytes to go
+    ControlSkip(u64),
+    Finished,
+}
+
+/// Where the tokens start once [header] has been read
+fn state_after_header(header: &Header) -> DecodeParseState {
+    match header.algorithm() {
+        Algorithm::Huffman => {
+            // Checked to be there when the header was read
+            let tree = header.huffman_tree.as_ref().unwrap();
+            DecodeParseState::HuffmanBitstream(BitstreamDecoder::new(tree))
+        }
+        // BWT blocks are control records
+        Algorithm::LzHuffman | Algorithm::Lz | Algorithm::Bwt => {
+            DecodeParseState::ExpectingMatchOrRawChunk
+        }
+        // Rejected when the header was read, as stored frames don't have one
+        Algorithm::Stored => unreachable!(),
+    }
+}
+
+/// Act on the control record started by [control_byte], returning the state to carry on in
+fn apply_control_record<W: Write>(
+    control_byte: u8,
+    value: &[u8],
+    header: &mut Header,
+    raw_byte_buffer: &mut Vec<u8>,
+    output: &mut DecodedOutput<W>,
+    read_buffer: &mut VecDeque<u8>,
+    options: &DecodeOptions,
+) -> Result<DecodeParseState> {
+    let record = match ControlRecord::from_parts(control_byte, value)? {
+        Some(record) => record,
+        None => {
+            debug!(
+                "Skipping unknown optional control record {:#010b}",
+                control_byte
             );
+            return Ok(DecodeParseState::ExpectingMatchOrRawChunk);
+        }
+    };
+    debug!("Control record: {:?}", record);
+    // Literals never run over a control record, so unpack them with the current tree
+    unpack_raw_bytes(raw_byte_buffer, header, read_buffer, output, options)?;
+    if output.tokens.is_some() {
+        output.add_token(Token::from(record.clone()));
+    }
+    match record {
+        ControlRecord::EndOfStream(trailer) => {
+            check_trailer(&trailer, output, read_buffer)?;
+            return Ok(DecodeParseState::Finished);
+        }
+        ControlRecord::NewHuffmanTree(tree) => header.huffman_tree = Some(tree),
+        ControlRecord::Run { value, len } => {
+            // A few bytes of run can ask for any length, so it's checked before expanding, and
+            // expanded a piece at a time to keep to the window
+            let decoded = output.len_with(read_buffer);
+            options.check_output_len(decoded.saturating_add(len))?;
+            let mut remaining = len;
+            while remaining > 0 {
+                let piece = remaining.min(OUTPUT_CHUNK_LEN as u64);
+                read_buffer.extend(std::iter::repeat_n(value, piece as usize));
+                output.take_from_window(read_buffer, header.history_len())?;
+                remaining -= piece;
+            }
+        }
+        ControlRecord::BwtBlock {
+            primary_index,
+            packed,
+        } => read_buffer.extend(invert_bwt_block(header, primary_index, &packed)?),
+        // Only used to skip damaged blocks when recovering, as the trailer checks everything
+        ControlRecord::BlockBoundary
+        | ControlRecord::Padding(_)
+        | ControlRecord::BlockCheck { .. } => (),
+    }
+    Ok(DecodeParseState::ExpectingMatchOrRawChunk)
+}
+
+#[cfg(feature = "bwt")]
+fn invert_bwt_block(header: &Header, primary_index: u64, packed: &[u8]) -> Result<Vec<u8>> {
+    let tree = header.huffman_tree.as_ref().ok_or_else(|| {
+        Error::InvalidControlRecord(String::from("BWT block without a huffman tree"))
+    })?;
+    let data = crate::huffman::unpack_bytes(packed, tree);
+    crate::bwt::invert_block(&crate::bwt::Block {
+        primary_index,
+        data,
+    })
+}
+
+#[cfg(not(feature = "bwt"))]
+fn invert_bwt_block(_: &Header, _: u64, _: &[u8]) -> Result<Vec<u8>> {
+    Err(Error::FeatureDisabled("bwt"))
+}
+
+/// Check everything decoded, i.e. [output] followed by [read_buffer], matches [trailer]
+fn check_trailer<W: Write>(
+    trailer: &Trailer,
+    output: &DecodedOutput<W>,
+    read_buffer: &VecDeque<u8>,
+) -> Result<()> {
+    let checksum = output.checksum_with(read_buffer);
+    let len = output.len_with(read_buffer);
+    if len != trailer.len || checksum != trailer.checksum {
+        debug!("Decoded {} bytes, trailer says {}", len, trailer.len);
+        return Err(Error::VerificationFailed {
+            expected: trailer.checksum,
+            actual: checksum,
+        });
+    }
+    Ok(())
+}
+
+/// The most bytes a run of [crate::MAX_LITERAL_RUN_LEN] literals packs to, which is as huffman
+/// codes of up to [crate::huffman::MAX_HUFFMAN_CODE_LEN] bits plus END. rANS' 12 bits a literal,
+/// run length and final state take less
+const MAX_PACKED_RUN_LEN: usize =
+    ((crate::MAX_LITERAL_RUN_LEN + 1) * crate::huffman::MAX_HUFFMAN_CODE_LEN).div_ceil(8);
+
+/// The encoder splits a single huffman packed run of raw bytes over as many chunks as it
+/// needs, so codes can straddle chunk markers. Chunks are therefore accumulated in
+/// [raw_byte_buffer] and only unpacked together once something other than a chunk follows.
+/// Formats with [crate::format::Format::capped_literal_runs] keep this to [MAX_PACKED_RUN_LEN],
+/// while older ones have it grow with the run.
+/// What's already left [read_buffer] for [output] counts towards
+/// [DecodeOptions::max_output_size]
+fn unpack_raw_bytes<W: Write>(
+    raw_byte_buffer: &mut Vec<u8>,
+    header: &Header,
+    read_buffer: &mut VecDeque<u8>,
+    output: &mut DecodedOutput<W>,
+    options: &DecodeOptions,
+) -> Result<()> {
+    if raw_byte_buffer.is_empty() {
+        return Ok(());
+    }
+    let unpacked = read_buffer.len();
+    debug!(
+        "Unpacking chunk: {}",
+        helpers::u8_iter_str(raw_byte_buffer.iter())
+    );
+    match (
+        header.algorithm(),
+        header.rans_table(),
+        &header.huffman_tree,
+    ) {
+        (Algorithm::Lz, ..) | (_, None, None) => read_buffer.extend(raw_byte_buffer.iter()),
+        (_, Some(rans_table), _) => {
+            let room = options
+                .max_output_size
+                .saturating_sub(output.len() + read_buffer.len() as u64);
+            // Running out of room is reported against the limit on the whole output
+            let decoded = rans_table
+                .decode(raw_byte_buffer, room)
+                .map_err(|e| match e {
+                    Error::LimitExceeded { .. } => options.output_limit(),
+                    e => e,
+                })?;
+            read_buffer.extend(decoded)
         }
-        read_buffer.range(range).copied().collect()
+        (_, None, Some(tree)) => {
+            read_buffer.extend(crate::huffman::unpack_bytes(raw_byte_buffer, tree))
+        }
+    }
+    raw_byte_buffer.clear();
+    output.add_literals(read_buffer, unpacked);
+    Ok(())
+}
+
+/// How much of the read buffer to log when a match doesn't fit it
+const MAX_CORRUPT_DUMP_LEN: usize = 256;
+
+/// Copy the match [distance] bytes back from the end of [read_buffer] onto its end, [read] being
+/// its offset and length as read from the stream, returning the match. Errors if it doesn't fit
+/// in [read_buffer]
+fn finalise_match(
+    read_buffer: &mut VecDeque<u8>,
+    distance: Option<u64>,
+    read: (u64, u64),
+) -> Result<OffsetLen> {
+    let window = read_buffer.len();
+    let (offset, len) = read;
+    let Some(offset_len) = distance.and_then(|d| OffsetLen::checked(d, len, window)) else {
+        info!(
+            "Range loaded from file exceeds read_buffer ({:?}): {}",
+            read,
+            helpers::hex_dump_tail(read_buffer, MAX_CORRUPT_DUMP_LEN)
+        );
+        return Err(Error::CorruptStream {
+            offset,
+            len,
+            window,
+        });
+    };
+    // Checked to be within the window, so starts in it
+    let start = window - offset_len.offset().get();
+    // Copy a byte at a time, as the match may run on into the bytes it is producing
+    for i in start..start + offset_len.len.get() {
+        let v = read_buffer[i];
+        read_buffer.push_back(v);
+    }
+    Ok(offset_len)
+}
+
+#[cfg(test)]
+mod test {
+    use std::cell::Cell;
+    use std::io::{BufWriter, Read, Write};
+    use std::rc::Rc;
+
+    use crate::control::{ControlRecord, Trailer};
+    use crate::decode::{
+        check, decode_bytes, decode_stream, decode_to_writer, decode_to_writer_with_options,
+        verify, DecodeOptions, MAX_PACKED_RUN_LEN,
     };
-    read_buffer.extend(values_from_buf.iter());
+    use crate::error::Error;
+    use crate::header::Header;
+    use crate::huffman::ByteStats;
+    use crate::offset_len::OffsetLen;
+    use crate::output_stream::OutputStream;
+    use crate::token::ChunkMarker;
+    use crate::{Token, MAX_LOOKBACK_BUFFER_LEN};
+
+    /// Encode [tokens] by hand, with a trailer describing [decoded]
+    fn encode_tokens(tokens: &[Token], decoded: &[u8]) -> Vec<u8> {
+        let mut stats = ByteStats::new();
+        for byte in decoded.iter() {
+            let count = stats.entry(u16::from(*byte)).or_insert(0);
+            *count += 1;
+        }
+        let tree = crate::huffman::build_tree(stats);
+        let code_map = crate::huffman::tree_to_code_map(&tree);
+        let mut output_buf = Vec::new();
+        {
+            let mut output_stream =
+                OutputStream::new(Some(code_map), BufWriter::new(&mut output_buf), None);
+            output_stream
+                .write_header(&Header::new(Some(tree), MAX_LOOKBACK_BUFFER_LEN as u64))
+                .unwrap();
+            for token in tokens.iter() {
+                output_stream.add(token).unwrap();
+            }
+            output_stream
+                .finalise(Trailer {
+                    len: decoded.len() as u64,
+                    checksum: crc32fast::hash(decoded),
+                })
+                .unwrap();
+        }
+        output_buf
+    }
+
+    #[test]
+    fn decode_literals_and_match() {
+        let values = [
+            Token::Literal(b'a'),
+            Token::Literal(b'b'),
+            Token::Literal(b'c'),
+            Token::Match(OffsetLen::of(3, 3)),
+            Token::Literal(b'd'),
+        ];
+        let encoded = encode_tokens(&values, b"abcabcd");
+        let (decoded, _) = decode_stream(&mut &encoded[..], &DecodeOptions::default()).unwrap();
+        assert_eq!(b"abcabcd".to_vec(), decoded);
+    }
+
+    #[test]
+    fn decode_into_writer() {
+        let values = [Token::Literal(b'z'), Token::Match(OffsetLen::of(1, 4))];
+        let encoded = encode_tokens(&values, b"zzzzz");
+        let mut output = Vec::new();
+        let written = decode_to_writer(&encoded[..], &mut output).unwrap();
+        assert_eq!(5, written);
+        assert_eq!(b"zzzzz".to_vec(), output);
+
+        let checksum = crc32fast::hash(b"zzzzz");
+        verify(&encoded[..], checksum).unwrap();
+        match verify(&encoded[..], checksum + 1) {
+            Err(Error::VerificationFailed { actual, .. }) => assert_eq!(checksum, actual),
+            other => panic!("Expected verification to fail, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn decode_overlapping_match() {
+        let values = [
+            Token::Literal(b'x'),
+            Token::Literal(b'a'),
+            Token::Literal(b'b'),
+            Token::Match(OffsetLen::of(2, 7)),
+        ];
+        let encoded = encode_tokens(&values, b"xababababa");
+        let (decoded, _) = decode_stream(&mut &encoded[..], &DecodeOptions::default()).unwrap();
+        assert_eq!(b"xababababa".to_vec(), decoded);
+    }
+
+    #[test]
+    fn decode_literals_split_over_chunks() {
+        // Enough distinct literals that the packed bytes need more than one chunk marker
+        let input: String = (0..200u8).map(|i| (b'0' + i % 75) as char).collect();
+        let values: Vec<Token> = input.bytes().map(Token::Literal).collect();
+        let encoded = encode_tokens(&values, input.as_bytes());
+        let (decoded, _) = decode_stream(&mut &encoded[..], &DecodeOptions::default()).unwrap();
+        assert_eq!(input.into_bytes(), decoded);
+    }
+
+    #[test]
+    fn match_outside_window_is_an_error() {
+        let values = [
+            Token::Literal(b'a'),
+            Token::Literal(b'b'),
+            Token::Match(OffsetLen::of(3, 3)),
+        ];
+        let encoded = encode_tokens(&values, b"ab");
+        match decode_stream(&mut &encoded[..], &DecodeOptions::default()) {
+            Err(Error::CorruptStream {
+                offset: 3,
+                len: 3,
+                window: 2,
+            }) => (),
+            other => panic!(
+                "Expected a corrupt stream error, got {:?}",
+                other.map(|_| ())
+            ),
+        }
+    }
+
+    #[test]
+    fn invalid_token_is_an_error() {
+        let mut encoded = encode_tokens(&[Token::Literal(b'a')], b"a");
+        // Just before the end of stream record (type, length and 12 byte trailer)
+        let end_of_stream = encoded.len() - 14;
+        encoded.insert(end_of_stream, 0b01000000);
+        match decode_bytes(&encoded) {
+            Err(Error::InvalidToken(0b01000000)) => (),
+            other => panic!("Expected an invalid token error, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn empty_chunk_is_an_error() {
+        let mut encoded = encode_tokens(&[Token::Literal(b'a')], b"a");
+        let end_of_stream = encoded.len() - 14;
+        encoded.insert(end_of_stream, 0b11000000);
+        match decode_bytes(&encoded) {
+            Err(Error::InvalidToken(0b11000000)) => (),
+            other => panic!("Expected an invalid token error, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn truncated_stream_is_an_error() {
+        let values = [
+            Token::Literal(b'a'),
+            Token::Literal(b'b'),
+            Token::Match(OffsetLen::of(2, 2)),
+        ];
+        let encoded = encode_tokens(&values, b"abab");
+        // With the end of stream marker and trailer, stopping anywhere short is an error
+        for cut in 0..encoded.len() {
+            match decode_stream(&mut &encoded[..cut], &DecodeOptions::default()) {
+                Ok((decoded, _)) => panic!(
+                    "Decoding {} of {} bytes unexpectedly succeeded: {:?}",
+                    cut,
+                    encoded.len(),
+                    decoded
+                ),
+                Err(Error::TruncatedStream(_)) => (),
+                Err(e) => panic!("Unexpected error: {}", e),
+            }
+        }
+    }
+
+    #[test]
+    fn control_records() {
+        let other_tree = {
+            let stats: ByteStats = (b'0'..=b'9').map(|b| (b.into(), 1)).collect();
+            crate::huffman::build_tree(stats)
+        };
+        let tokens = [
+            Token::Literal(b'a'),
+            Token::Control(ControlRecord::Padding(3)),
+            Token::Literal(b'b'),
+            Token::Control(ControlRecord::BlockBoundary),
+            Token::Control(ControlRecord::NewHuffmanTree(other_tree)),
+            Token::Literal(b'1'),
+            Token::Literal(b'2'),
+            Token::Run(b'3', 4),
+        ];
+        let mut encoded = encode_tokens(&tokens, b"ab123333");
+        assert_eq!(b"ab123333".to_vec(), decode_bytes(&encoded).unwrap());
+
+        // An unknown optional record is skipped, an unknown required one is an error
+        let header_len = Header::expected_len(&encoded).unwrap();
+        encoded.splice(header_len..header_len, [0b00111111, 1, 0]);
+        assert_eq!(b"ab123333".to_vec(), decode_bytes(&encoded).unwrap());
+        encoded[header_len] = 0b00011111;
+        match decode_bytes(&encoded) {
+            Err(Error::InvalidControlRecord(_)) => (),
+            other => panic!("Expected an invalid control record error, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn constant_memory() {
+        /// Shares how far into [bytes] it's read
+        struct Tracked<'a> {
+            bytes: &'a [u8],
+            read: Rc<Cell<usize>>,
+        }
+        impl Read for Tracked<'_> {
+            fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+                let read = (&self.bytes[self.read.get()..]).read(buf)?;
+                self.read.set(self.read.get() + read);
+                Ok(read)
+            }
+        }
+        /// Notes how far the decoder had read when output first came out, and the biggest write
+        #[derive(Default)]
+        struct Watcher {
+            read: Rc<Cell<usize>>,
+            read_at_first_write: Option<usize>,
+            largest_write: usize,
+        }
+        impl Write for Watcher {
+            fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+                self.read_at_first_write.get_or_insert(self.read.get());
+                self.largest_write = self.largest_write.max(buf.len());
+                Ok(buf.len())
+            }
+            fn flush(&mut self) -> std::io::Result<()> {
+                Ok(())
+            }
+        }
+        let decode = |encoded: &[u8], threads| {
+            let mut watcher = Watcher::default();
+            let reader = Tracked {
+                bytes: encoded,
+                read: Rc::clone(&watcher.read),
+            };
+            let options = DecodeOptions::default().threads(threads);
+            let len = decode_to_writer_with_options(reader, &mut watcher, &options).unwrap();
+            (len, watcher)
+        };
+
+        // Far more run than the window, which would all be held if nothing was written until
+        // the end
+        let run_len = 1 << 22;
+        let mut hasher = crc32fast::Hasher::new();
+        hasher.update(b"a");
+        for _ in 0..run_len / super::OUTPUT_CHUNK_LEN {
+            hasher.update(&[b'x'; super::OUTPUT_CHUNK_LEN]);
+        }
+        let mut encoded = Vec::new();
+        {
+            let mut output_stream = OutputStream::new(None, BufWriter::new(&mut encoded), None);
+            let header = Header::new(None, MAX_LOOKBACK_BUFFER_LEN as u64);
+            output_stream
+                .write_header(&header.with_algorithm(crate::Algorithm::Lz))
+                .unwrap();
+            output_stream.add(&Token::Literal(b'a')).unwrap();
+            output_stream
+                .add(&Token::Run(b'x', run_len as u64))
+                .unwrap();
+            output_stream
+                .finalise(Trailer {
+                    len: run_len as u64 + 1,
+                    checksum: hasher.finalize(),
+                })
+                .unwrap();
+        }
+        let (len, watcher) = decode(&encoded, 1);
+        assert_eq!(run_len as u64 + 1, len);
+        assert!(watcher.largest_write <= super::OUTPUT_CHUNK_LEN);
+
+        // Output comes out as the input goes in, one thread or several
+        let mut state = 3u64;
+        let words = ["the ", "cat ", "sat ", "on ", "a ", "mat ", "dog ", "log "];
+        let input: Vec<u8> = (0..60_000)
+            .flat_map(|_| {
+                state = state.wrapping_mul(6364136223846793005).wrapping_add(1);
+                words[(state >> 61) as usize].bytes()
+            })
+            .collect();
+        let options = crate::EncodeOptions::default().block_size(Some(10_000));
+        let mut encoded = Vec::new();
+        crate::encode::encode_from_reader(&input[..], &mut encoded, &options).unwrap();
+        for threads in [1, 3] {
+            let (len, watcher) = decode(&encoded, threads);
+            assert_eq!(input.len() as u64, len);
+            assert!(watcher.read_at_first_write.unwrap() < encoded.len() / 2);
+        }
+    }
+
+    #[test]
+    fn limits() {
+        let limited = |max_output_size| DecodeOptions::default().max_output_size(max_output_size);
+        fn is_limit<T: std::fmt::Debug>(result: crate::Result<T>, expected: &str) {
+            match result {
+                Err(Error::LimitExceeded { limit, .. }) => assert_eq!(expected, limit),
+                other => panic!("Expected the {} limit, got {:?}", expected, other),
+            }
+        }
+        // A run claiming a petabyte is refused before anything is expanded
+        let bomb = encode_tokens(&[Token::Run(b'x', 1 << 50)], b"");
+        is_limit(decode_bytes(&bomb), "output size");
+
+        let input = "The cat sat on the mat. The dog sat on the log. ".repeat(100);
+        let len = input.len() as u64;
+        for options in [
+            crate::EncodeOptions::default(),
+            crate::EncodeOptions::default().entropy(crate::Entropy::Rans),
+            crate::EncodeOptions::default().block_size(Some(500)),
+            crate::EncodeOptions::default().algorithm(crate::Algorithm::Stored),
+        ] {
+            let mut encoded = Vec::new();
+            crate::encode::encode_from_reader(input.as_bytes(), &mut encoded, &options).unwrap();
+            for threads in [1, 3] {
+                let decode = |options: DecodeOptions| {
+                    let options = options.threads(threads);
+                    decode_to_writer_with_options(&encoded[..], std::io::sink(), &options)
+                };
+                assert_eq!(len, decode(limited(len)).unwrap());
+                is_limit(decode(limited(len - 1)), "output size");
+                // Stored frames don't have a header to limit
+                if !crate::format::stored::is_stored(&encoded[..4]) {
+                    let header_len = Header::expected_len(&encoded).unwrap();
+                    let options = DecodeOptions::default().max_header_size(header_len - 1);
+                    is_limit(decode(options), "header size");
+                }
+            }
+        }
+
+        // A control record can't have the decoder buffer more than a header's worth
+        let mut huge_record = encode_tokens(&[Token::Literal(b'a')], b"a");
+        let end_of_stream = huge_record.len() - 14;
+        let mut record = vec![0b00000001];
+        crate::control::write_varint(&mut record, 1 << 40).unwrap();
+        huge_record.splice(end_of_stream..end_of_stream, record);
+        is_limit(decode_bytes(&huge_record), "header size");
+
+        // Unless it's an unknown optional one, which is skipped without being kept
+        let mut unknown_record = encode_tokens(&[Token::Literal(b'a')], b"a");
+        let header_len = Header::expected_len(&unknown_record).unwrap();
+        let end_of_stream = unknown_record.len() - 14;
+        let mut record = vec![0b00111111];
+        crate::control::write_varint(&mut record, header_len as u64 * 2).unwrap();
+        record.resize(record.len() + header_len * 2, 0);
+        unknown_record.splice(end_of_stream..end_of_stream, record);
+        let options = DecodeOptions::default().max_header_size(header_len);
+        let (decoded, _) = decode_stream(&mut &unknown_record[..], &options).unwrap();
+        assert_eq!(b"a".to_vec(), decoded);
+
+        // Nor can back to back chunks have it hold more than a capped run of literals packs to
+        let mut long_run = encode_tokens(&[Token::Literal(b'a')], b"a");
+        let end_of_stream = long_run.len() - 14;
+        let mut chunk = [0u8; ChunkMarker::MAX_VALUE + 1];
+        chunk[0] = ChunkMarker {
+            len: ChunkMarker::MAX_VALUE as u8,
+        }
+        .to_u8();
+        let chunks = chunk.repeat(MAX_PACKED_RUN_LEN / ChunkMarker::MAX_VALUE + 1);
+        long_run.splice(end_of_stream..end_of_stream, chunks);
+        is_limit(decode_bytes(&long_run), "literal run");
+    }
+
+    #[test]
+    fn older_versions() {
+        // Version 1 header, without a trailer
+        let v1_file = include_bytes!("../testfile.lizard");
+        let decoded = decode_bytes(v1_file).unwrap();
+        assert!(decoded.starts_with(b"72222222\r\nI am Sam"));
+
+        // Rewrite a current stream as version 2, which has no control records and gives the
+        // header's length as a u16
+        let values = [Token::Literal(b'a'), Token::Literal(b'b')];
+        let current = encode_tokens(&values, b"ab");
+        let header_len = Header::expected_len(&current).unwrap();
+        let body = rmp_serde::to_vec(&Header::from_bytes(&current[..header_len]).unwrap()).unwrap();
+        let mut encoded = b"LZRD\x02".to_vec();
+        encoded.extend_from_slice(&(body.len() as u16 + 7).to_be_bytes());
+        encoded.extend_from_slice(&body);
+        encoded.extend_from_slice(&current[header_len..]);
+        let end_of_stream = encoded.len() - 14;
+        match decode_bytes(&encoded) {
+            Err(Error::InvalidToken(0)) => (),
+            other => panic!("Expected an invalid token error, got {:?}", other),
+        }
+        encoded.truncate(end_of_stream);
+        assert_eq!(b"ab".to_vec(), decode_bytes(&encoded).unwrap());
+    }
+
+    #[test]
+    fn trailer_is_checked() {
+        let values = [Token::Literal(b'a'), Token::Literal(b'b')];
+        let mut encoded = encode_tokens(&values, b"ab");
+        // Trailing bytes after the trailer are ignored, unless they're another frame
+        let frame = encoded.clone();
+        encoded.extend(b"garbage");
+        assert_eq!(b"ab".to_vec(), decode_bytes(&encoded).unwrap());
+        let mut frames = [&frame[..], &frame, b"garbage"].concat();
+        assert_eq!(b"abab".to_vec(), decode_bytes(&frames).unwrap());
+        frames.truncate(frame.len() * 2 - 1);
+        assert!(matches!(
+            decode_bytes(&frames),
+            Err(Error::TruncatedStream(_))
+        ));
+
+        let encoded = encode_tokens(&values, b"abc");
+        match decode_bytes(&encoded) {
+            Err(Error::VerificationFailed { .. }) => (),
+            other => panic!("Expected verification to fail, got {:?}", other),
+        }
+        assert!(matches!(
+            check(&encoded[..], &DecodeOptions::default()),
+            Err(Error::VerificationFailed { .. })
+        ));
+        let summary = check(&frame[..], &DecodeOptions::default()).unwrap();
+        assert_eq!(summary.output_bytes, 2);
+    }
+
+    #[test]
+    fn short_writes() {
+        let input = "abcabcabc, all the way to the end of the input. ".repeat(200);
+        for algorithm in [crate::Algorithm::LzHuffman, crate::Algorithm::Stored] {
+            let mut encoded = Vec::new();
+            let options = crate::EncodeOptions::default().algorithm(algorithm);
+            crate::encode::encode_from_reader(input.as_bytes(), &mut encoded, &options).unwrap();
+            let mut writer = crate::helpers::ShortWriter::new(5);
+            decode_to_writer(&encoded[..], &mut writer).unwrap();
+            assert_eq!(input.as_bytes(), writer.inner);
+        }
+    }
 }
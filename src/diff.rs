@@ -0,0 +1,191 @@
+//! Comparing how two streams were coded, e.g. the same input compressed with two sets of
+//! options or two versions of the encoder. Both are decoded side by side into their tokens
+//! (see [crate::decode::for_each_token]), which are counted up and compared as they come, so
+//! neither is held in memory
+
+use serde::Serialize;
+use std::io::Read;
+use std::sync::mpsc::sync_channel;
+
+use crate::decode::{for_each_token, DecodeOptions};
+use crate::error::Result;
+use crate::token::Token;
+
+/// How many tokens the right hand stream can decode ahead of the left
+const TOKENS_IN_FLIGHT: usize = 1 << 12;
+
+/// What a stream's tokens are made up of
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+pub struct TokenStats {
+    pub literals: u64,
+    pub matches: u64,
+    /// Bytes the matches copied
+    pub matched_bytes: u64,
+    pub runs: u64,
+    /// Bytes the runs expanded to
+    pub run_bytes: u64,
+    /// Other control records, including the end of stream
+    pub control_records: u64,
+}
+
+impl TokenStats {
+    fn add(&mut self, token: &Token) {
+        match token {
+            Token::Literal(_) => self.literals += 1,
+            Token::Match(offset_len) => {
+                self.matches += 1;
+                self.matched_bytes += offset_len.len.get() as u64;
+            }
+            Token::Run(_, len) => {
+                self.runs += 1;
+                self.run_bytes += len;
+            }
+            Token::EndOfStream(_) | Token::Control(_) => self.control_records += 1,
+        }
+    }
+}
+
+/// The first place two streams' tokens differ. One side is [None] if its stream ended there
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Divergence {
+    /// How many tokens the streams have in common before it
+    pub index: u64,
+    pub left: Option<Token>,
+    pub right: Option<Token>,
+}
+
+/// How two streams' tokens compare
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+pub struct TokenDiff {
+    /// [None] if the streams were coded the same
+    pub first_difference: Option<Divergence>,
+    pub left: TokenStats,
+    pub right: TokenStats,
+}
+
+impl TokenDiff {
+    /// Count [left] and [right], the next token of each stream, noting them if they're the
+    /// first to differ
+    fn add(&mut self, index: u64, left: Option<Token>, right: Option<Token>) {
+        left.iter().for_each(|token| self.left.add(token));
+        right.iter().for_each(|token| self.right.add(token));
+        if self.first_difference.is_none() && left != right {
+            self.first_difference = Some(Divergence { index, left, right });
+        }
+    }
+}
+
+/// Decode [left] and [right], comparing their tokens. [right] is decoded on another thread.
+/// Either failing to decode is an error, [left]'s if both do
+pub fn diff_tokens<L: Read, R: Read + Send>(
+    left: L,
+    right: R,
+    options: &DecodeOptions,
+) -> Result<TokenDiff> {
+    let (sender, receiver) = sync_channel::<Token>(TOKENS_IN_FLIGHT);
+    std::thread::scope(|scope| {
+        let right = scope.spawn(move || {
+            // The left stream failing stops it listening, so just carry on to the end
+            for_each_token(right, options, |token| {
+                let _ = sender.send(token);
+            })
+        });
+        let mut diff = TokenDiff::default();
+        let mut index = 0;
+        let left_result = for_each_token(left, options, |token| {
+            diff.add(index, Some(token), receiver.recv().ok());
+            index += 1;
+        });
+        if left_result.is_ok() {
+            for token in receiver.iter() {
+                diff.add(index, None, Some(token));
+                index += 1;
+            }
+        }
+        drop(receiver);
+        let right_result = right.join().expect("Right hand stream's decode panicked");
+        left_result?;
+        right_result?;
+        Ok(diff)
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use crate::decode::DecodeOptions;
+    use crate::diff::{diff_tokens, Divergence, TokenStats};
+    use crate::test_util::encode;
+    use crate::{Algorithm, EncodeOptions, Error, Token};
+
+    #[test]
+    fn same_stream() {
+        let input = "abcabcabcabc xyz ".repeat(10);
+        let encoded = encode(input.as_bytes(), &EncodeOptions::default());
+        let diff = diff_tokens(&encoded[..], &encoded[..], &DecodeOptions::default()).unwrap();
+        assert_eq!(None, diff.first_difference);
+        assert_eq!(diff.left, diff.right);
+        let stats = &diff.left;
+        assert!(stats.literals > 0 && stats.matches > 0);
+        assert_eq!(input.len() as u64, stats.literals + stats.matched_bytes);
+        assert_eq!(1, stats.control_records);
+    }
+
+    #[test]
+    fn first_difference() {
+        let input: String = (0..40).map(|i| format!("abcde{}", i)).collect();
+        let input = input.as_bytes();
+        let matched = encode(input, &EncodeOptions::default().min_match(3));
+        let literal = encode(input, &EncodeOptions::default().min_match(8));
+        let diff = diff_tokens(&matched[..], &literal[..], &DecodeOptions::default()).unwrap();
+        let Some(Divergence { index, left, right }) = diff.first_difference else {
+            panic!("Expected the streams to differ");
+        };
+        assert!(matches!(left, Some(Token::Match(_))));
+        assert_eq!(Some(Token::Literal(input[index as usize])), right);
+        assert!(diff.left.matches > diff.right.matches);
+        assert_eq!(
+            diff.left.literals + diff.left.matched_bytes,
+            diff.right.literals + diff.right.matched_bytes
+        );
+
+        // One stream running out first differs where it ends
+        let empty = encode(b"", &EncodeOptions::default());
+        let diff = diff_tokens(&empty[..], &matched[..], &DecodeOptions::default()).unwrap();
+        let divergence = diff.first_difference.unwrap();
+        assert_eq!(0, divergence.index);
+        assert!(matches!(divergence.left, Some(Token::EndOfStream(_))));
+        assert_eq!(Some(Token::Literal(b'a')), divergence.right);
+        assert_eq!(
+            TokenStats {
+                control_records: 1,
+                ..Default::default()
+            },
+            diff.left
+        );
+    }
+
+    #[test]
+    fn stored_as_literals() {
+        let stored = encode(
+            b"abc",
+            &EncodeOptions::default().algorithm(Algorithm::Stored),
+        );
+        let coded = encode(b"abd", &EncodeOptions::default().algorithm(Algorithm::Lz));
+        let diff = diff_tokens(&stored[..], &coded[..], &DecodeOptions::default()).unwrap();
+        let divergence = diff.first_difference.unwrap();
+        assert_eq!(2, divergence.index);
+        assert_eq!(Some(Token::Literal(b'c')), divergence.left);
+        assert_eq!(Some(Token::Literal(b'd')), divergence.right);
+        assert_eq!(3, diff.left.literals);
+    }
+
+    #[test]
+    fn corrupt_stream() {
+        let encoded = encode(b"hello hello hello", &EncodeOptions::default());
+        let truncated = &encoded[..encoded.len() - 3];
+        for (left, right) in [(&encoded[..], truncated), (truncated, &encoded[..])] {
+            let result = diff_tokens(left, right, &DecodeOptions::default());
+            assert!(matches!(result, Err(Error::TruncatedStream(_))));
+        }
+    }
+}
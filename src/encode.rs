@@ -1,22 +1,391 @@
-use std::collections::VecDeque;
+use log::{debug, info};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, VecDeque};
 use std::fs::File;
-use std::io::{BufReader, BufWriter, Seek};
+use std::io::{BufRead, BufReader, BufWriter, Cursor, Read, Seek, SeekFrom, Write};
 
-use crate::file_io::FileInputOutput;
+#[cfg(feature = "cache")]
+use crate::cache::BlockCache;
+use crate::chunking::{BlockSplitter, Boundaries};
+use crate::control::Trailer;
+use crate::cost::token_bits;
+#[cfg(feature = "crypto")]
+use crate::crypto::{EncryptWriter, EncryptionKey, Passphrase, Secret};
+use crate::entropy::{Entropy, RansTable};
+use crate::error::Result;
+use crate::file_io::{FileInputOutput, FileMetadata};
+use crate::filter::{Filter, FilterReader};
+use crate::format::stored;
 use crate::header::Header;
-use crate::huffman::ByteStats;
-use crate::offset_len::OffsetLen;
-use crate::output_stream::OutputStream;
-use crate::{helpers, EncodedValue, MAX_LOOKBACK_BUFFER_LEN, MAX_READ_BUFFER_LEN, MIN_MATCH_SIZE};
+use crate::huffman::{build_tree, tree_to_code_map, ByteStats, CodeMap, Symbol};
+use crate::input::input_reader;
+use crate::long_range;
+use crate::match_finder::{MatchFinder, MatchStrategy, Window};
+use crate::offset_len::{MatchLen, OffsetLen, WindowPos};
+use crate::optimal_parse::{OptimalParser, Prices};
+use crate::output::{output_writer, WriteBehind};
+use crate::output_stream::{OutputStream, StreamStats};
+use crate::throttle::{Throttle, Throttled};
+use crate::volumes::VolumeWriter;
+use crate::{
+    helpers, Token, AUTO_LEVEL_SAMPLE_LEN, BWT_BLOCK_SIZE, DEFAULT_LEVEL, MAX_AUTO_MIN_MATCH_SIZE,
+    MAX_LEVEL, MAX_LITERAL_RUN_LEN, MAX_LOOKBACK_BUFFER_LEN, MAX_MATCH_LEN, MAX_PRIMED_LEN,
+    MAX_READ_BUFFER_LEN, MIN_MATCH_SAMPLE_LEN, MIN_MATCH_SIZE, STREAM_SEGMENT_LEN,
+};
 
-fn populate_byte_stats(byte_stats: &mut ByteStats, reader: &mut impl std::io::Read) {
+/// How the input is compressed. Recorded in the header so the decoder knows what to expect
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Algorithm {
+    /// Huffman code every byte as one continuous bitstream, without looking for matches. For
+    /// data that is already deduplicated, or to see what the huffman coding alone achieves
+    Huffman,
+    /// LZSS matches, with the literals between them huffman coded
+    #[default]
+    LzHuffman,
+    /// LZSS matches, with the literals stored as they are. Much faster to encode and decode, and
+    /// loses little on nearly random data where huffman coding gains next to nothing
+    Lz,
+    /// Experimental, needs the bwt feature. Blocks of input go through the Burrows-Wheeler
+    /// transform, move-to-front and run length encoding before huffman coding. Slow, but much
+    /// better on text
+    Bwt,
+    /// The input as it is, in a stored frame without the usual header. Picked automatically
+    /// whenever the other algorithms would make the input bigger
+    Stored,
+}
+
+/// Tuning knobs for encoding, built up from the defaults e.g.
+/// `EncodeOptions::default().window_size(4096).min_match(6)`
+#[derive(Debug, Clone, PartialEq)]
+pub struct EncodeOptions {
+    /// Size of the lookback buffer matches are made against. This is recorded in the header as
+    /// the decoder has to keep the same amount of output around
+    pub(crate) window_size: usize,
+    /// Shortest match worth encoding, or None to pick one based on the input
+    pub(crate) min_match: Option<usize>,
+    /// Longest a single match may be
+    pub(crate) max_match_len: usize,
+    /// Record the input file's modification time and permissions in the header. Without them
+    /// the output depends only on the input's contents and the options
+    pub(crate) store_metadata: bool,
+    pub(crate) algorithm: Algorithm,
+    /// Runs of a single byte at least this long are written as one run record, found before
+    /// looking for matches. None to leave runs to matching
+    pub(crate) rle_min_run: Option<usize>,
+    /// Applied to the input before anything else, and undone by the decoder. Not supported by
+    /// gzip output
+    pub(crate) filter: Filter,
+    /// Input bytes per block with [Algorithm::Bwt]
+    pub(crate) bwt_block_size: usize,
+    /// Split the input into blocks of this many bytes, each encoded on its own and followed by
+    /// its length and checksum, so [crate::recover] can skip damaged ones. Costs some ratio, as
+    /// matches can't reach back into earlier blocks. Only with [Algorithm::Lz] and
+    /// [Algorithm::LzHuffman], ignored otherwise
+    pub(crate) block_size: Option<usize>,
+    /// End blocks where the content picks rather than every [block_size] bytes, see
+    /// [EncodeOptions::content_defined_blocks]
+    pub(crate) content_defined_blocks: bool,
+    /// Blocks of [block_size] are tokenised on up to this many threads at once. 1 encodes on
+    /// the calling thread, as does everything without a [block_size]
+    pub(crate) threads: usize,
+    /// Key-value pairs recorded in the header, e.g. where the input came from. Not kept by
+    /// [Algorithm::Stored] or gzip output
+    pub(crate) metadata: BTreeMap<String, String>,
+    /// How literals are entropy coded. Only with [Algorithm::LzHuffman], which is otherwise
+    /// always huffman coded
+    pub(crate) entropy: Entropy,
+    /// When packing a directory ([crate::archive]), store what symlinks point to rather than
+    /// the links themselves
+    pub(crate) follow_symlinks: bool,
+    /// When packing a directory, compress files which look already compressed rather than
+    /// storing them, see [EncodeOptions::force_compress]
+    pub(crate) force_compress: bool,
+    /// How matches are looked for
+    pub(crate) match_strategy: MatchStrategy,
+    /// How hard to try, from 1 to [MAX_LEVEL]
+    pub(crate) level: u8,
+    /// Pick the level and more for each frame from a sample of it, see
+    /// [EncodeOptions::auto_level]
+    pub(crate) auto_level: bool,
+    /// Runs of literals are ended once they're this long, see
+    /// [EncodeOptions::max_literal_run]
+    pub(crate) max_literal_run: usize,
+    /// Bytes per second the input file is read and the output written at, together
+    pub(crate) rate_limit: Option<u64>,
+    /// Read the input file up to this many bytes ahead on a thread of its own, see
+    /// [EncodeOptions::read_ahead]
+    pub(crate) read_ahead: Option<usize>,
+    /// Write output files on a thread of their own, see [EncodeOptions::write_behind]
+    pub(crate) write_behind: Option<usize>,
+    /// Encrypt the output with this
+    #[cfg(feature = "crypto")]
+    pub(crate) secret: Option<Secret>,
+    /// Also match this far back, beyond the window, see [EncodeOptions::long_range]
+    pub(crate) long_range: Option<usize>,
+    /// Where blocks' tokens are kept to be reused, see [EncodeOptions::cache_dir]
+    #[cfg(feature = "cache")]
+    pub(crate) cache: Option<BlockCache>,
+}
+
+impl Default for EncodeOptions {
+    fn default() -> Self {
+        Self {
+            window_size: MAX_LOOKBACK_BUFFER_LEN,
+            min_match: None,
+            max_match_len: MAX_MATCH_LEN,
+            store_metadata: true,
+            algorithm: Algorithm::default(),
+            rle_min_run: None,
+            filter: Filter::default(),
+            bwt_block_size: BWT_BLOCK_SIZE,
+            block_size: None,
+            content_defined_blocks: false,
+            threads: 1,
+            metadata: BTreeMap::new(),
+            entropy: Entropy::default(),
+            follow_symlinks: false,
+            force_compress: false,
+            match_strategy: MatchStrategy::default(),
+            level: DEFAULT_LEVEL,
+            auto_level: false,
+            max_literal_run: MAX_LITERAL_RUN_LEN,
+            rate_limit: None,
+            read_ahead: None,
+            write_behind: None,
+            #[cfg(feature = "crypto")]
+            secret: None,
+            long_range: None,
+            #[cfg(feature = "cache")]
+            cache: None,
+        }
+    }
+}
+
+impl EncodeOptions {
+    pub fn window_size(mut self, window_size: usize) -> Self {
+        // Matches need at least one byte to point at, and can't point back further than a
+        // WindowPos holds
+        self.window_size = window_size.clamp(1, WindowPos::MAX_WINDOW_LEN);
+        self
+    }
+
+    pub fn min_match(mut self, min_match: usize) -> Self {
+        // A zero length match would never move the encoder forward
+        self.min_match = Some(min_match.max(1));
+        self
+    }
+
+    pub fn auto_min_match(mut self) -> Self {
+        self.min_match = None;
+        self
+    }
+
+    pub fn max_match_len(mut self, max_match_len: usize) -> Self {
+        self.max_match_len = max_match_len.clamp(1, MatchLen::MAX);
+        self
+    }
+
+    /// Record the input file's modification time, permissions and name in the header, for
+    /// decoding to restore. On by default
+    pub fn store_metadata(mut self, store_metadata: bool) -> Self {
+        self.store_metadata = store_metadata;
+        self
+    }
+
+    pub fn follow_symlinks(mut self, follow_symlinks: bool) -> Self {
+        self.follow_symlinks = follow_symlinks;
+        self
+    }
+
+    /// When packing a directory ([crate::archive]), files which look already compressed (JPEGs,
+    /// zips, videos and so on, by their extension or first bytes) are written as stored frames
+    /// by default, as compressing them again costs time for next to nothing. This compresses
+    /// them like anything else
+    pub fn force_compress(mut self, force_compress: bool) -> Self {
+        self.force_compress = force_compress;
+        self
+    }
+
+    pub fn match_strategy(mut self, match_strategy: MatchStrategy) -> Self {
+        self.match_strategy = match_strategy;
+        self
+    }
+
+    /// How hard to try, from 1 to [MAX_LEVEL]. Only [MAX_LEVEL] does anything different for
+    /// now: it picks tokens by what they cost over the whole input rather than taking the
+    /// longest match each time, which is smaller but much slower. Its matches are found with
+    /// hash chains, whatever [match_strategy] says. Only for [Algorithm::Lz] and
+    /// [Algorithm::LzHuffman], and not for gzip output
+    pub fn level(mut self, level: u8) -> Self {
+        self.level = level.clamp(1, MAX_LEVEL);
+        self.auto_level = false;
+        self
+    }
+
+    /// Rather than a fixed [level], pick the level, min match and whether literals are huffman
+    /// coded for each frame from the first 64KiB of its input, see [AutoSettings]. What was
+    /// picked is recorded in the header. A min match given with [min_match] is kept, and
+    /// literals are only ever stored rather than huffman coded, not the other way round. Only
+    /// for [Algorithm::Lz] and [Algorithm::LzHuffman]
+    pub fn auto_level(mut self) -> Self {
+        self.auto_level = true;
+        self
+    }
+
+    /// End runs of literals with a block boundary after this many, rather than
+    /// [MAX_LITERAL_RUN_LEN], so input with few matches goes out in pieces as it's coded. For
+    /// streaming, as with [Algorithm::LzHuffman] and [Entropy::Rans] a whole run is held until
+    /// it ends. Each run ended costs 2 bytes
+    pub fn max_literal_run(mut self, max_literal_run: usize) -> Self {
+        self.max_literal_run = max_literal_run.clamp(1, MAX_LITERAL_RUN_LEN);
+        self
+    }
+
+    /// Read and write files ([encode], [crate::gzip::encode]) at no more than [rate_limit]
+    /// bytes per second between them, see [crate::throttle]. Encoding from a reader isn't held
+    /// to it, as the caller has the reader and writer to wrap
+    pub fn rate_limit(mut self, rate_limit: Option<u64>) -> Self {
+        self.rate_limit = rate_limit;
+        self
+    }
+
+    /// Read input files ([encode], [crate::gzip::encode]) on a thread of their own, holding up
+    /// to [read_ahead] bytes that the encoder hasn't got to yet, so reading the disk and encoding
+    /// overlap, see [crate::input]. Read in blocks of 256KiB, so anything less is one block
+    pub fn read_ahead(mut self, read_ahead: Option<usize>) -> Self {
+        self.read_ahead = read_ahead;
+        self
+    }
+
+    /// Write output files ([encode], [encode_to_volumes], [crate::gzip::encode]) and the
+    /// debug output on a thread of their own, holding up to [write_behind] bytes which haven't
+    /// been written yet, so packing the output and writing the disk overlap, see
+    /// [crate::output]. Written in blocks of 256KiB, so anything less is one block
+    pub fn write_behind(mut self, write_behind: Option<usize>) -> Self {
+        self.write_behind = write_behind;
+        self
+    }
+
+    /// A new [Throttle] for one file's reads and writes, if there's a rate limit
+    pub fn throttle(&self) -> Option<Throttle> {
+        self.rate_limit.map(Throttle::new)
+    }
+
+    pub fn algorithm(mut self, algorithm: Algorithm) -> Self {
+        self.algorithm = algorithm;
+        self
+    }
+
+    pub fn rle_min_run(mut self, rle_min_run: Option<usize>) -> Self {
+        // A run record is at least 3 bytes, so shorter runs would never be worth it
+        self.rle_min_run = rle_min_run.map(|min_run| min_run.max(4));
+        self
+    }
+
+    pub fn filter(mut self, filter: Filter) -> Self {
+        self.filter = filter;
+        self
+    }
+
+    /// Record [key] as [value] in the header, replacing any earlier value for [key]
+    pub fn metadata(mut self, key: impl Into<String>, value: impl Into<String>) -> Self {
+        self.metadata.insert(key.into(), value.into());
+        self
+    }
+
+    pub fn bwt_block_size(mut self, bwt_block_size: usize) -> Self {
+        self.bwt_block_size = bwt_block_size.max(1);
+        self
+    }
+
+    pub fn block_size(mut self, block_size: Option<usize>) -> Self {
+        self.block_size = block_size.map(|block_size| block_size.max(1));
+        self
+    }
+
+    /// Tokenise up to [threads] blocks at once, each on a thread of its own, holding that many
+    /// blocks of input and their tokens in memory. Only with [EncodeOptions::block_size], as
+    /// otherwise there's one block, and without [EncodeOptions::cache_dir]. The output is the
+    /// same however many threads there are
+    pub fn threads(mut self, threads: usize) -> Self {
+        self.threads = threads.max(1);
+        self
+    }
+
+    /// End blocks at boundaries picked by the content rather than every [block_size] bytes,
+    /// which is then their average size (they're from a quarter to four times that). Inserting
+    /// or removing bytes then only changes the blocks around the edit, rather than every block
+    /// after it, for deduplicating backups of the output
+    pub fn content_defined_blocks(mut self, content_defined_blocks: bool) -> Self {
+        self.content_defined_blocks = content_defined_blocks;
+        self
+    }
+
+    /// Keep the tokens each block is coded as in [cache_dir], keyed by a hash of the block, and
+    /// reuse them when the same block comes up again rather than looking for its matches. Only
+    /// with a [block_size], and best with [content_defined_blocks]. See [crate::cache]
+    #[cfg(feature = "cache")]
+    pub fn cache_dir(mut self, cache_dir: Option<impl Into<std::path::PathBuf>>) -> Self {
+        self.cache = cache_dir.map(BlockCache::new);
+        self
+    }
+
+    /// Also look for repeats of [long_range::FINGERPRINT_LEN] bytes or more from up to
+    /// [long_range] bytes back, rather than only within the window, as zstd's long distance
+    /// matching does. Decoding then holds that much output, so it's recorded in the header. Only
+    /// with [Algorithm::Lz] and [Algorithm::LzHuffman], and only within a block
+    pub fn long_range(mut self, long_range: Option<usize>) -> Self {
+        self.long_range = long_range.map(|long_range| long_range.min(WindowPos::MAX_WINDOW_LEN));
+        self
+    }
+
+    fn boundaries(&self) -> Boundaries {
+        match (self.block_size, self.content_defined_blocks) {
+            (None, _) => Boundaries::None,
+            (Some(block_size), false) => Boundaries::Fixed(block_size as u64),
+            (Some(block_size), true) => Boundaries::ContentDefined(block_size),
+        }
+    }
+
+    pub fn entropy(mut self, entropy: Entropy) -> Self {
+        self.entropy = entropy;
+        self
+    }
+
+    /// Encrypt the output with a key derived from [passphrase], see [crate::crypto]. Applies to
+    /// [encode], [encode_to_volumes] and [encode_from_reader], but not [StreamEncoder]
+    #[cfg(feature = "crypto")]
+    pub fn encrypt(mut self, passphrase: Passphrase) -> Self {
+        self.secret = Some(Secret::Passphrase(passphrase));
+        self
+    }
+
+    /// Encrypt the output with [key] as it is, for callers managing their own keys rather than
+    /// passphrases. Decoding needs [crate::DecodeOptions::with_key] with the same key
+    #[cfg(feature = "crypto")]
+    pub fn with_key(mut self, key: EncryptionKey) -> Self {
+        self.secret = Some(Secret::Key(key));
+        self
+    }
+}
+
+/// Count occurrences of each byte in [reader], returning the total number of bytes read and
+/// their CRC32
+fn populate_byte_stats(
+    byte_stats: &mut ByteStats,
+    reader: &mut impl std::io::Read,
+) -> std::io::Result<(u64, u32)> {
     let mut buffer = [0; 10];
+    let mut total = 0;
+    let mut hasher = crc32fast::Hasher::new();
     loop {
         match reader.read(&mut buffer) {
-            Ok(0) => return,
+            Ok(0) => return Ok((total, hasher.finalize())),
             Ok(n) if n <= 10 => {
-                for b in buffer {
-                    let mut count = byte_stats.entry(b).or_insert(0);
+                total += n as u64;
+                hasher.update(&buffer[..n]);
+                for b in &buffer[..n] {
+                    let count = byte_stats.entry(Symbol::from(*b)).or_insert(0);
                     *count += 1;
                 }
             }
@@ -26,97 +395,1109 @@ fn populate_byte_stats(byte_stats: &mut ByteStats, reader: &mut impl std::io::Re
                     too_many_bytes
                 );
             }
-            Err(e) => {
-                panic!("Error when reading file: {}", e);
-            }
+            Err(e) if e.kind() == std::io::ErrorKind::Interrupted => (),
+            Err(e) => return Err(e),
         }
     }
 }
 
-pub fn encode(file_io: &FileInputOutput) {
-    let mut input_buffer: [u8; 1] = [0b0; 1];
-    let mut read_buffer = VecDeque::<u8>::new();
-    let mut lookback_buffer = VecDeque::<u8>::new();
+/// Pick the shortest match worth encoding given a sample of the input. A match costs its
+/// encoded bytes plus a chunk marker to restart literals afterwards, and is only worth it if
+/// the literals it replaces would cost more than that. The better the literals compress with
+/// huffman coding (i.e. the lower their entropy), the longer a match needs to be to win
+fn auto_min_match(sample: &[u8], window_size: usize) -> usize {
+    if sample.is_empty() {
+        return MIN_MATCH_SIZE;
+    }
+    min_match_for_literal_cost(entropy_bits(sample), window_size)
+}
 
+/// Bits per byte [sample] would take with a code built for its byte frequencies
+fn entropy_bits(sample: &[u8]) -> f64 {
     let mut byte_stats = ByteStats::new();
+    for b in sample {
+        let count = byte_stats.entry(Symbol::from(*b)).or_insert(0);
+        *count += 1;
+    }
+    byte_stats
+        .values()
+        .map(|count| {
+            let p = *count as f64 / sample.len() as f64;
+            -p * p.log2()
+        })
+        .sum()
+}
+
+/// The shortest match which costs fewer bits than the literals it replaces, when each literal
+/// costs [literal_bits]
+fn min_match_for_literal_cost(literal_bits: f64, window_size: usize) -> usize {
+    let match_cost_bits = {
+        let offset = WindowPos::new(window_size - 1, window_size).unwrap_or(WindowPos::NEAREST);
+        let typical_match = OffsetLen::new(offset, MatchLen::new(MIN_MATCH_SIZE).unwrap());
+        (typical_match.encoded_len() + 1) * 8
+    };
+    let break_even = (match_cost_bits as f64 / literal_bits).ceil();
+    if break_even.is_finite() {
+        (break_even as usize).clamp(MIN_MATCH_SIZE, MAX_AUTO_MIN_MATCH_SIZE)
+    } else {
+        MAX_AUTO_MIN_MATCH_SIZE
+    }
+}
+
+/// Samples with at least this much of them matched (or in runs) are match heavy
+const MATCH_HEAVY_FRACTION: f64 = 0.5;
+/// Literals costing more bits than this huffman coded aren't worth the tree
+const RANDOM_LITERAL_BITS: f64 = 7.5;
+
+/// What [EncodeOptions::auto_level] picked for a frame, recorded in its header so the encode
+/// can be repeated by giving the same settings
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct AutoSettings {
+    pub level: u8,
+    pub min_match: usize,
+    /// [Algorithm::Lz] where the literals looked too random to be worth huffman coding
+    pub algorithm: Algorithm,
+}
+
+impl AutoSettings {
+    /// Pick settings for input starting with [sample]. Match heavy input (greedy matching
+    /// covers at least half of it) is parsed optimally at [MAX_LEVEL], as choosing between
+    /// overlapping matches is where it gains. Otherwise there's little to gain for the time,
+    /// so it's [DEFAULT_LEVEL]
+    fn pick(sample: &[u8], options: &EncodeOptions) -> Result<Self> {
+        let algorithm = match options.algorithm {
+            Algorithm::LzHuffman if entropy_bits(sample) > RANDOM_LITERAL_BITS => Algorithm::Lz,
+            algorithm => algorithm,
+        };
+        let min_match = match (options.min_match, algorithm) {
+            (Some(min_match), _) => min_match,
+            (None, Algorithm::Lz) => min_match_for_literal_cost(8.0, options.window_size),
+            (None, _) => auto_min_match(sample, options.window_size),
+        };
+        let mut matched = 0;
+        tokenise(
+            sample,
+            &[],
+            options.window_size,
+            MatchStrategy::HashChain
+                .finder(min_match, options.window_size)
+                .as_mut(),
+            options.max_match_len,
+            options.rle_min_run,
+            |token| {
+                match token {
+                    Token::Match(offset_len) => matched += offset_len.len.get() as u64,
+                    Token::Run(_, len) => matched += len,
+                    _ => (),
+                }
+                Ok(())
+            },
+        )?;
+        let level = match matched as f64 >= sample.len() as f64 * MATCH_HEAVY_FRACTION {
+            true if !sample.is_empty() => MAX_LEVEL,
+            _ => DEFAULT_LEVEL,
+        };
+        Ok(Self {
+            level,
+            min_match,
+            algorithm,
+        })
+    }
+
+    fn apply(self, options: &EncodeOptions) -> EncodeOptions {
+        options
+            .clone()
+            .level(self.level)
+            .min_match(self.min_match)
+            .algorithm(self.algorithm)
+    }
+}
+
+/// What happened during an encode, so callers can log ratios etc
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct EncodeSummary {
+    pub input_bytes: u64,
+    pub output_bytes: u64,
+    /// Number of OffsetLen matches emitted
+    pub matches: u64,
+    /// Number of bytes emitted as literals rather than covered by a match
+    pub literals: u64,
+    /// Number of run records emitted
+    pub runs: u64,
+    /// Bits taken by the literals, by [crate::cost::cost_in_bits]. Estimated from their
+    /// huffman codes with [Entropy::Rans]. The token costs are only counted for
+    /// [Algorithm::Lz] and [Algorithm::LzHuffman], and not for gzip output
+    pub literal_bits: u64,
+    /// Bits taken by the matches
+    pub match_bits: u64,
+    /// Bits taken by the run records
+    pub run_bits: u64,
+    /// Blocks whose tokens were found in [EncodeOptions::cache_dir] rather than looked for.
+    /// Always zero without the `cache` feature
+    pub cached_blocks: u64,
+    /// CRC32 of the input
+    pub checksum: u32,
+    /// What the output stream wrote, left at zero for stored frames and gzip output
+    pub stream: StreamStats,
+}
+
+impl EncodeSummary {
+    /// Output size as a fraction of input size, lower is better
+    pub fn ratio(&self) -> f64 {
+        self.output_bytes as f64 / self.input_bytes as f64
+    }
+}
+
+/// Encode the file. Input which doesn't compress is stored as it is instead, so the output is
+/// never bigger than [max_encoded_len] of the input, unless there's metadata to keep (see
+/// [EncodeOptions::store_metadata])
+pub fn encode(file_io: &FileInputOutput, options: &EncodeOptions) -> Result<EncodeSummary> {
+    let mut outf = File::create(file_io.encoded_filename.as_path())?;
+    match options.write_behind {
+        Some(write_behind) => {
+            let mut writer = WriteBehind::new(outf, write_behind)?;
+            let summary = encode_file_to(file_io, &mut writer, options)?;
+            writer.finish()?;
+            Ok(summary)
+        }
+        None => encode_file_to(file_io, &mut outf, options),
+    }
+}
+
+/// [encode], splitting the output into volumes of [volume_size] bytes named after
+/// [FileInputOutput::encoded_filename], see [VolumeWriter]
+pub fn encode_to_volumes(
+    file_io: &FileInputOutput,
+    options: &EncodeOptions,
+    volume_size: u64,
+) -> Result<EncodeSummary> {
+    let mut writer = VolumeWriter::create(&file_io.encoded_filename, volume_size);
+    let (summary, volumes) = match options.write_behind {
+        Some(write_behind) => {
+            let mut writer = WriteBehind::new(writer, write_behind)?;
+            let summary = encode_file_to(file_io, &mut writer, options)?;
+            (summary, writer.finish()?.finish()?)
+        }
+        None => {
+            let summary = encode_file_to(file_io, &mut writer, options)?;
+            (summary, writer.finish()?)
+        }
+    };
+    info!("Split the output into {} volumes", volumes);
+    Ok(summary)
+}
+
+/// Encode the file as [encode] would, only counting the bytes of output rather than writing them,
+/// to find how big the output would be. Nothing is written to [FileInputOutput::encoded_filename]
+/// (or any trace), which needn't be writable
+pub fn dry_run(file_io: &FileInputOutput, options: &EncodeOptions) -> Result<EncodeSummary> {
+    let mut file_io = file_io.clone();
+    file_io.debug_encoded_filename = None;
+    let mut writer = helpers::CountingWriter::new(std::io::sink());
+    let summary = encode_file_to(&file_io, &mut writer, options)?;
+    Ok(EncodeSummary {
+        output_bytes: writer.count(),
+        ..summary
+    })
+}
+
+fn encode_file_to<W: Restartable>(
+    file_io: &FileInputOutput,
+    writer: &mut W,
+    options: &EncodeOptions,
+) -> Result<EncodeSummary> {
+    let throttle = options.throttle();
+    let writer = &mut Throttled::new(writer, throttle.clone());
+    #[cfg(feature = "crypto")]
+    if let Some(secret) = &options.secret {
+        let mut writer = EncryptWriter::new(writer, secret)?;
+        let summary = encode_plain_file_to(file_io, &mut writer, throttle, options)?;
+        let (_, output_bytes) = writer.finish()?;
+        return Ok(EncodeSummary {
+            output_bytes,
+            ..summary
+        });
+    }
+    encode_plain_file_to(file_io, writer, throttle, options)
+}
 
-    let outf = File::create(file_io.encoded_filename.as_path()).unwrap();
-    let mut writer = BufWriter::new(outf);
-    let mut debug_writer = match file_io.debug_encoded_filename.as_deref() {
+/// [encode_file_to] once any encryption is set up. Reading the input shares [throttle] with
+/// the writer
+fn encode_plain_file_to<W: Restartable>(
+    file_io: &FileInputOutput,
+    writer: &mut W,
+    throttle: Option<Throttle>,
+    options: &EncodeOptions,
+) -> Result<EncodeSummary> {
+    let trace_writer: Option<Box<dyn Write>> = match file_io.debug_encoded_filename.as_deref() {
         Some(debug_file_path) => {
-            let df = File::create(debug_file_path).unwrap();
-            Some(BufWriter::new(df))
+            let df = File::create(debug_file_path)?;
+            Some(output_writer(df, options.write_behind)?)
         }
         None => None,
     };
 
-    let input_file = File::open(file_io.unencoded_filename.as_path()).unwrap();
-    let mut input_file_reader = BufReader::new(input_file);
+    let input_file = File::open(file_io.unencoded_filename.as_path())?;
+    let file_metadata = match options.store_metadata {
+        true => Some(
+            FileMetadata::from_fs(&input_file.metadata()?).with_name(&file_io.unencoded_filename),
+        ),
+        false => None,
+    };
+    let input_file_reader = Throttled::new(input_reader(input_file, options.read_ahead)?, throttle);
+
+    let summary = encode_stream(
+        input_file_reader,
+        writer,
+        trace_writer,
+        file_metadata,
+        &[],
+        options,
+    )?;
+    info!(
+        "Encoded {:?} -> {:?} ({} -> {} bytes)",
+        file_io.unencoded_filename,
+        file_io.encoded_filename,
+        summary.input_bytes,
+        summary.output_bytes
+    );
+    Ok(summary)
+}
+
+/// Encode everything from [reader], writing the lizards stream to [writer]. Encoding needs two
+/// passes over the input (the huffman tree is built from the whole input first), so the input
+/// is read into memory, as is the output to check it came out smaller. Use [encode] to encode a
+/// file without doing so. Like [encode] the output is never bigger than [max_encoded_len]
+pub fn encode_from_reader<R: Read, W: Write>(
+    mut reader: R,
+    mut writer: W,
+    options: &EncodeOptions,
+) -> Result<EncodeSummary> {
+    let mut input = Vec::new();
+    reader.read_to_end(&mut input)?;
+    let mut encoded = Vec::new();
+    let summary = encode_stream(Cursor::new(input), &mut encoded, None, None, &[], options)?;
+    #[cfg(feature = "crypto")]
+    if let Some(secret) = &options.secret {
+        let mut writer = EncryptWriter::new(writer, secret)?;
+        writer.write_all(&encoded)?;
+        let (_, output_bytes) = writer.finish()?;
+        return Ok(EncodeSummary {
+            output_bytes,
+            ..summary
+        });
+    }
+    writer.write_all(&encoded)?;
+    writer.flush()?;
+    Ok(summary)
+}
+
+/// Encodes everything written to it as a series of frames, each holding up to
+/// [StreamEncoder::segment_len] bytes of input, so only one segment is held however much is
+/// written. Decoding reads the frames back as one stream. Each frame starts over with its own
+/// huffman tree, and unless [StreamEncoder::shared_window] its own window, so this compresses
+/// a little worse than [encode_from_reader]
+pub struct StreamEncoder<W: Write> {
+    writer: W,
+    options: EncodeOptions,
+    segment: Vec<u8>,
+    segment_len: usize,
+    /// The end of the previous segment, for the next frame's window to start with. None
+    /// without [StreamEncoder::shared_window]
+    primer: Option<Vec<u8>>,
+    /// Of the frames written so far
+    summary: EncodeSummary,
+    hasher: crc32fast::Hasher,
+    frames: u64,
+    /// Write segments as stored frames, see [StreamEncoder::store]
+    store: bool,
+}
+
+impl<W: Write> StreamEncoder<W> {
+    pub fn new(writer: W, options: &EncodeOptions) -> Self {
+        Self {
+            writer,
+            options: options.clone(),
+            segment: Vec::new(),
+            segment_len: STREAM_SEGMENT_LEN,
+            primer: None,
+            summary: EncodeSummary::default(),
+            hasher: crc32fast::Hasher::new(),
+            frames: 0,
+            store: false,
+        }
+    }
+
+    /// Encode every [segment_len] bytes written as a frame of their own. Longer segments
+    /// compress better, but are held in memory until they're full
+    pub fn segment_len(mut self, segment_len: usize) -> Self {
+        self.segment_len = segment_len.max(1);
+        self
+    }
+
+    /// Start each frame's window with the end of the segment before, so matches can reach
+    /// back across frames, e.g. into an earlier file of an archive. Only unfiltered LZ
+    /// encoding without [EncodeOptions::block_size] can, and then only up to
+    /// [crate::MAX_PRIMED_LEN] bytes. The frames can then only be decoded in order
+    pub fn shared_window(mut self, shared_window: bool) -> Self {
+        self.primer = shared_window.then(Vec::new);
+        self
+    }
+
+    pub fn get_ref(&self) -> &W {
+        &self.writer
+    }
+
+    pub fn get_mut(&mut self) -> &mut W {
+        &mut self.writer
+    }
+
+    /// Where what's written next will be encoded: the offset into the output of the frame it
+    /// goes in, and how many bytes of that frame's input come before it
+    pub(crate) fn position(&self) -> (u64, u64) {
+        (self.summary.output_bytes, self.segment.len() as u64)
+    }
+
+    /// How many bytes have been written so far, whether they've been encoded yet or not
+    pub(crate) fn written(&self) -> u64 {
+        self.summary.input_bytes + self.segment.len() as u64
+    }
+
+    /// Encode whatever is left as the last frame, giving back [writer] and what happened
+    /// across all the frames
+    pub fn finish(mut self) -> Result<(W, EncodeSummary)> {
+        self.end()?;
+        self.summary.checksum = self.hasher.clone().finalize();
+        Ok((self.writer, self.summary))
+    }
+
+    /// Encode whatever is left of the current segment, or an empty frame if nothing has been
+    /// written, so the output so far is a whole stream
+    pub(crate) fn end(&mut self) -> Result<()> {
+        if !self.segment.is_empty() || self.frames == 0 {
+            self.encode_segment()?;
+        }
+        self.writer.flush()?;
+        Ok(())
+    }
+
+    /// Write what's written from now on as stored frames, or go back to encoding it. What was
+    /// written before is encoded first, so it isn't stored with it. For parts of the stream
+    /// that won't compress, e.g. already compressed files in an archive
+    pub(crate) fn store(&mut self, store: bool) -> Result<()> {
+        if store != self.store && !self.segment.is_empty() {
+            self.encode_segment()?;
+        }
+        self.store = store;
+        Ok(())
+    }
+
+    fn encode_segment(&mut self) -> Result<()> {
+        let segment = std::mem::take(&mut self.segment);
+        self.hasher.update(&segment);
+        let mut encoded = Vec::new();
+        let mut cursor = Cursor::new(segment);
+        let primer = self.primer.as_deref().unwrap_or_default();
+        let stored_options;
+        let options = match self.store {
+            true => {
+                stored_options = self.options.clone().algorithm(Algorithm::Stored);
+                &stored_options
+            }
+            false => &self.options,
+        };
+        let frame = encode_stream(&mut cursor, &mut encoded, None, None, primer, options)?;
+        self.writer.write_all(&encoded)?;
+        self.summary.input_bytes += frame.input_bytes;
+        self.summary.output_bytes += frame.output_bytes;
+        self.summary.matches += frame.matches;
+        self.summary.literals += frame.literals;
+        self.summary.runs += frame.runs;
+        self.summary.cached_blocks += frame.cached_blocks;
+        self.summary.literal_bits += frame.literal_bits;
+        self.summary.match_bits += frame.match_bits;
+        self.summary.run_bits += frame.run_bits;
+        self.summary.stream += frame.stream;
+        self.frames += 1;
+        // Keep the allocation for the next segment
+        self.segment = cursor.into_inner();
+        if let Some(primer) = &mut self.primer {
+            primer.clear();
+            let start = self.segment.len().saturating_sub(MAX_PRIMED_LEN);
+            primer.extend_from_slice(&self.segment[start..]);
+        }
+        self.segment.clear();
+        Ok(())
+    }
+}
+
+impl<W: Write> Write for StreamEncoder<W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let taken = buf.len().min(self.segment_len - self.segment.len());
+        self.segment.extend_from_slice(&buf[..taken]);
+        if self.segment.len() == self.segment_len {
+            self.encode_segment()?;
+        }
+        Ok(taken)
+    }
+
+    /// Flushes [writer], but doesn't end the segment, as that would make a frame for every
+    /// flush. Use [StreamEncoder::finish] to encode the rest
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.writer.flush()
+    }
+}
+
+/// The most [encode] can write for [input_len] bytes of input, which is the size of a stored
+/// frame holding them. That's at most 14 bytes more than the input. Doesn't hold with
+/// [EncodeOptions::metadata], or for files with [EncodeOptions::store_metadata] (the default),
+/// as stored frames have no header to keep it in
+pub fn max_encoded_len(input_len: u64) -> u64 {
+    stored::frame_len(input_len)
+}
+
+/// Predict the ratio ([EncodeSummary::ratio]) encoding [sample] with the default options would
+/// get, cheaply enough to decide whether to compress at all, e.g. to skip already compressed
+/// media. Only the first [AUTO_LEVEL_SAMPLE_LEN] bytes are looked at, with the quicker hash chain
+/// matcher: matches and runs are priced as they'd be written, and the literals between them at
+/// their entropy, as huffman coding gets close to that. Headers and the tree aren't counted, so
+/// this is low for tiny samples. Never more than storing the sample would take
+pub fn estimate_ratio(sample: &[u8]) -> f64 {
+    let sample = &sample[..sample.len().min(AUTO_LEVEL_SAMPLE_LEN)];
+    if sample.is_empty() {
+        return 1.0;
+    }
+    let options = EncodeOptions::default();
+    let min_match = auto_min_match(sample, options.window_size);
+    let mut literals = Vec::new();
+    let mut token_bits = 0;
+    tokenise(
+        sample,
+        &[],
+        options.window_size,
+        MatchStrategy::HashChain
+            .finder(min_match, options.window_size)
+            .as_mut(),
+        options.max_match_len,
+        options.rle_min_run,
+        |token| {
+            match token {
+                Token::Literal(b) => literals.push(*b),
+                // Along with the chunk marker restarting the literals after it
+                token => token_bits += crate::cost::token_bits(token, None) as u64 + 8,
+            }
+            Ok(())
+        },
+    )
+    .expect("Tokenising a slice can't fail");
+    let literal_bits = match literals.is_empty() {
+        true => 0.0,
+        false => entropy_bits(&literals) * literals.len() as f64,
+    };
+    let estimated_bytes = (literal_bits + token_bits as f64) / 8.0;
+    let stored_bytes = max_encoded_len(sample.len() as u64) as f64;
+    estimated_bytes.min(stored_bytes) / sample.len() as f64
+}
+
+/// Output which can be thrown away and started again, to replace an encoding which came out
+/// bigger than the input
+pub(crate) trait Restartable: Write {
+    fn restart(&mut self) -> std::io::Result<()>;
+}
+
+impl Restartable for File {
+    fn restart(&mut self) -> std::io::Result<()> {
+        self.set_len(0)?;
+        self.rewind()
+    }
+}
+
+impl Restartable for VolumeWriter {
+    fn restart(&mut self) -> std::io::Result<()> {
+        self.remove_all()
+    }
+}
+
+impl<W: Restartable + ?Sized> Restartable for &mut W {
+    fn restart(&mut self) -> std::io::Result<()> {
+        (**self).restart()
+    }
+}
+
+#[cfg(feature = "crypto")]
+impl<W: Restartable> Restartable for EncryptWriter<W> {
+    fn restart(&mut self) -> std::io::Result<()> {
+        self.get_mut().restart()?;
+        self.start_over();
+        Ok(())
+    }
+}
+
+impl<W: Restartable> Restartable for Throttled<W> {
+    fn restart(&mut self) -> std::io::Result<()> {
+        self.get_mut().restart()
+    }
+}
+
+impl Restartable for Vec<u8> {
+    fn restart(&mut self) -> std::io::Result<()> {
+        self.clear();
+        Ok(())
+    }
+}
+
+impl Restartable for std::io::Sink {
+    fn restart(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+impl<W: Restartable> Restartable for helpers::CountingWriter<W> {
+    fn restart(&mut self) -> std::io::Result<()> {
+        self.reset();
+        self.get_mut().restart()
+    }
+}
+
+/// Encode [input_reader] as a frame, or a stored frame if that comes out smaller. Matches can
+/// reach back into [primer], the end of the previous frame's input, where the frame allows
+pub(crate) fn encode_stream<R: Read + Seek, W: Restartable>(
+    mut input_reader: R,
+    writer: &mut W,
+    trace_writer: Option<Box<dyn Write>>,
+    file_metadata: Option<FileMetadata>,
+    primer: &[u8],
+    options: &EncodeOptions,
+) -> Result<EncodeSummary> {
+    let input_len = input_reader.seek(SeekFrom::End(0))?;
+    input_reader.rewind()?;
+    // Stored frames have nowhere to keep metadata, so only without any can they stand in for a
+    // frame with a header
+    let has_metadata = !options.metadata.is_empty() || file_metadata.is_some();
+    // With nothing to code, a stored frame with an empty payload is all there is to write
+    if options.algorithm == Algorithm::Stored || (input_len == 0 && !has_metadata) {
+        return encode_stored(input_reader, input_len, writer);
+    }
+    let summary = encode_frame(
+        &mut input_reader,
+        &mut *writer,
+        trace_writer,
+        file_metadata,
+        primer,
+        options,
+    )?;
+    // Nothing compresses everything, and the header and huffman tree alone can outweigh a tiny
+    // input. A stored frame costs a few bytes more than the input, so never loses by much
+    if summary.output_bytes <= max_encoded_len(input_len) || has_metadata {
+        return Ok(summary);
+    }
+    debug!(
+        "Encoded {} bytes to {}, storing them as they are instead",
+        input_len, summary.output_bytes
+    );
+    input_reader.rewind()?;
+    writer.restart()?;
+    encode_stored(input_reader, input_len, writer)
+}
+
+/// Write the [input_len] bytes from [input_reader] as a stored frame
+fn encode_stored<R: Read, W: Write>(
+    input_reader: R,
+    input_len: u64,
+    writer: &mut W,
+) -> Result<EncodeSummary> {
+    let mut input_reader = helpers::CountingReader::new(input_reader);
+    let output_bytes = stored::write(&mut input_reader, input_len, writer)?;
+    writer.flush()?;
+    Ok(EncodeSummary {
+        input_bytes: input_len,
+        output_bytes,
+        literals: input_len,
+        checksum: input_reader.checksum(),
+        ..Default::default()
+    })
+}
+
+/// Encode [input_reader] as a header followed by tokens
+fn encode_frame<R: Read + Seek, W: Write>(
+    input_reader: R,
+    writer: W,
+    trace_writer: Option<Box<dyn Write>>,
+    file_metadata: Option<FileMetadata>,
+    primer: &[u8],
+    options: &EncodeOptions,
+) -> Result<EncodeSummary> {
+    #[cfg(not(feature = "bwt"))]
+    if options.algorithm == Algorithm::Bwt {
+        return Err(crate::Error::FeatureDisabled("bwt"));
+    }
+    let mut summary = EncodeSummary::default();
+    let mut input_reader = FilterReader::new(input_reader, options.filter);
+
+    let auto_options;
+    let auto_settings = match options.algorithm {
+        Algorithm::Lz | Algorithm::LzHuffman if options.auto_level => {
+            let mut sample = Vec::new();
+            (&mut input_reader)
+                .take(AUTO_LEVEL_SAMPLE_LEN as u64)
+                .read_to_end(&mut sample)?;
+            input_reader.rewind()?;
+            let settings = AutoSettings::pick(&sample, options)?;
+            info!("Picked {:?}", settings);
+            Some(settings)
+        }
+        _ => None,
+    };
+    let options = match auto_settings {
+        Some(settings) => {
+            auto_options = settings.apply(options);
+            &auto_options
+        }
+        None => options,
+    };
+
+    let min_match = match options.min_match {
+        Some(min_match) => min_match,
+        // Not used without matching, so no point looking
+        None if matches!(options.algorithm, Algorithm::Huffman | Algorithm::Bwt) => MIN_MATCH_SIZE,
+        // Literals always cost 8 bits, whatever the input looks like
+        None if options.algorithm == Algorithm::Lz => {
+            min_match_for_literal_cost(8.0, options.window_size)
+        }
+        None => {
+            let mut sample = Vec::new();
+            (&mut input_reader)
+                .take(MIN_MATCH_SAMPLE_LEN as u64)
+                .read_to_end(&mut sample)?;
+            input_reader.rewind()?;
+            let min_match = auto_min_match(&sample, options.window_size);
+            info!("Picked min match size of {}", min_match);
+            min_match
+        }
+    };
 
-    populate_byte_stats(&mut byte_stats, &mut input_file_reader);
-    input_file_reader.rewind();
+    // The decoder primes its window with what it decoded, which only matches the input without
+    // a filter, and blocks have to decode on their own
+    let primer = match (options.algorithm, options.filter, options.block_size) {
+        (Algorithm::Lz | Algorithm::LzHuffman, Filter::None, None) => {
+            let primed_len = primer.len().min(options.window_size).min(MAX_PRIMED_LEN);
+            &primer[primer.len() - primed_len..]
+        }
+        _ => &[],
+    };
 
-    let mut output_stream = create_output_stream(byte_stats, writer, debug_writer);
+    let mut byte_stats = ByteStats::new();
+    // The trailer describes the filtered bytes the tokens decode to, whereas the summary
+    // describes the input
+    let (filtered_len, filtered_checksum) =
+        populate_byte_stats(&mut byte_stats, &mut input_reader)?;
+    summary.input_bytes = filtered_len;
+    summary.checksum = input_reader.checksum();
+    input_reader.rewind()?;
+
+    // The tree has to be built from the transformed blocks, so they're all transformed first
+    #[cfg(feature = "bwt")]
+    let bwt_blocks = match options.algorithm {
+        Algorithm::Bwt => {
+            let blocks = crate::bwt::transform_blocks(&mut input_reader, options.bwt_block_size)?;
+            byte_stats.clear();
+            for b in blocks.iter().flat_map(|block| &block.data) {
+                *byte_stats.entry(Symbol::from(*b)).or_insert(0) += 1;
+            }
+            blocks
+        }
+        _ => Vec::new(),
+    };
+
+    // How the output stream will code literals (or near enough, with rANS), for pricing tokens
+    // and counting what they cost
+    let literal_code_map = match options.algorithm {
+        Algorithm::LzHuffman => Some(tree_to_code_map(&build_tree(byte_stats.clone()))),
+        _ => None,
+    };
+    let prices = match (options.algorithm, &literal_code_map) {
+        _ if options.level < MAX_LEVEL => None,
+        (Algorithm::Lz, _) => Some(Prices::stored()),
+        (Algorithm::LzHuffman, Some(code_map)) => Some(Prices::from_code_map(code_map)),
+        _ => None,
+    };
+
+    let mut counting_writer = helpers::CountingWriter::new(helpers::RetryWriter::new(writer));
+    {
+        let mut output_stream = create_output_stream(
+            byte_stats,
+            BufWriter::new(&mut counting_writer),
+            trace_writer,
+            file_metadata,
+            primer.len(),
+            auto_settings,
+            options,
+        )?;
+
+        match options.algorithm {
+            Algorithm::Huffman => {
+                summary.literals = summary.input_bytes;
+                // Hold on to any read error, as the bitstream is packed from an iterator
+                let mut read_error = None;
+                let bytes = BufReader::new(&mut input_reader)
+                    .bytes()
+                    .map_while(|byte| byte.map_err(|e| read_error = Some(e)).ok());
+                let written = output_stream.add_bitstream(bytes);
+                if let Some(e) = read_error {
+                    return Err(e.into());
+                }
+                written?;
+            }
+            #[cfg(feature = "bwt")]
+            Algorithm::Bwt => {
+                for block in &bwt_blocks {
+                    output_stream.add_bwt_block(block)?;
+                }
+            }
+            // Checked at the start
+            #[cfg(not(feature = "bwt"))]
+            Algorithm::Bwt => unreachable!(),
+            // Written without a frame
+            Algorithm::Stored => unreachable!(),
+            // Without a block size everything is one block, which isn't marked
+            Algorithm::LzHuffman | Algorithm::Lz => {
+                let mut splitter =
+                    BlockSplitter::new(BufReader::new(&mut input_reader), options.boundaries());
+                let threads = match options.block_size {
+                    Some(_) => options.threads,
+                    None => 1,
+                };
+                #[cfg(feature = "cache")]
+                let threads = match options.cache {
+                    Some(_) => 1,
+                    None => threads,
+                };
+                #[cfg(feature = "cache")]
+                let mut cached_blocks = 0;
+                if threads > 1 {
+                    loop {
+                        let blocks = read_blocks(&mut splitter, threads)?;
+                        let tokenised = std::thread::scope(|scope| {
+                            let tokenising: Vec<_> = blocks
+                                .iter()
+                                .map(|block| {
+                                    scope.spawn(|| {
+                                        let mut tokens = Vec::new();
+                                        tokenise_block(
+                                            &block[..],
+                                            primer,
+                                            min_match,
+                                            prices.as_ref(),
+                                            options,
+                                            |value: &Token| {
+                                                tokens.push(value.clone());
+                                                Ok(())
+                                            },
+                                        )?;
+                                        Ok(tokens)
+                                    })
+                                })
+                                .collect();
+                            tokenising
+                                .into_iter()
+                                .map(|thread| thread.join().expect("Tokenising doesn't panic"))
+                                .collect::<Result<Vec<_>>>()
+                        })?;
+                        for (block, tokens) in blocks.iter().zip(tokenised) {
+                            for value in &tokens {
+                                count_token(&mut summary, value, literal_code_map.as_ref());
+                                output_stream.add(value)?;
+                            }
+                            output_stream.end_block(block.len() as u64, crc32fast::hash(block))?;
+                        }
+                        if blocks.len() < threads {
+                            break;
+                        }
+                    }
+                } else {
+                    loop {
+                        let mut block_reader = helpers::CountingReader::new(&mut splitter);
+                        let mut emit = |value: &Token| {
+                            count_token(&mut summary, value, literal_code_map.as_ref());
+                            output_stream.add(value)?;
+                            Ok(())
+                        };
+                        let prices = prices.as_ref();
+                        #[cfg(feature = "cache")]
+                        if let Some(cache) = options
+                            .cache
+                            .as_ref()
+                            .filter(|_| options.block_size.is_some())
+                        {
+                            if cache.tokenise(
+                                &mut block_reader,
+                                min_match,
+                                prices,
+                                options,
+                                &mut emit,
+                            )? {
+                                cached_blocks += 1;
+                            }
+                        } else {
+                            tokenise_block(
+                                &mut block_reader,
+                                primer,
+                                min_match,
+                                prices,
+                                options,
+                                &mut emit,
+                            )?;
+                        }
+                        #[cfg(not(feature = "cache"))]
+                        tokenise_block(
+                            &mut block_reader,
+                            primer,
+                            min_match,
+                            prices,
+                            options,
+                            &mut emit,
+                        )?;
+                        match options.block_size {
+                            Some(_) if block_reader.count() > 0 => output_stream
+                                .end_block(block_reader.count(), block_reader.checksum())?,
+                            _ => break,
+                        }
+                        splitter.next_block();
+                    }
+                }
+                #[cfg(feature = "cache")]
+                {
+                    summary.cached_blocks = cached_blocks;
+                }
+            }
+        }
+        summary.stream = output_stream.finalise(Trailer {
+            len: filtered_len,
+            checksum: filtered_checksum,
+        })?;
+    }
+    summary.output_bytes = counting_writer.count();
+    Ok(summary)
+}
+
+/// Add [value] to the counts of tokens and the bits they take in [summary]
+fn count_token(summary: &mut EncodeSummary, value: &Token, code_map: Option<&CodeMap>) {
+    let bits = token_bits(value, code_map) as u64;
+    match value {
+        Token::Literal(_) => {
+            summary.literals += 1;
+            summary.literal_bits += bits;
+        }
+        Token::Match(_) => {
+            summary.matches += 1;
+            summary.match_bits += bits;
+        }
+        Token::Run(..) => {
+            summary.runs += 1;
+            summary.run_bits += bits;
+        }
+        // Only written around the tokens, which is where they're counted
+        Token::EndOfStream(_) | Token::Control(_) => (),
+    }
+}
+
+/// Read up to [max] blocks from [splitter], fewer once it runs out. An empty block is the end
+fn read_blocks<R: BufRead>(splitter: &mut BlockSplitter<R>, max: usize) -> Result<Vec<Vec<u8>>> {
+    let mut blocks = Vec::new();
+    while blocks.len() < max {
+        let mut block = Vec::new();
+        splitter.read_to_end(&mut block)?;
+        splitter.next_block();
+        if block.is_empty() {
+            break;
+        }
+        blocks.push(block);
+    }
+    Ok(blocks)
+}
+
+/// Tokenise one block of [input_reader], with long range matches if
+/// [EncodeOptions::long_range] says to
+pub(crate) fn tokenise_block<R: Read>(
+    input_reader: R,
+    primer: &[u8],
+    min_match: usize,
+    prices: Option<&Prices>,
+    options: &EncodeOptions,
+    emit: impl FnMut(&Token) -> Result<()>,
+) -> Result<()> {
+    match options.long_range {
+        Some(long_range) => long_range::tokenise(
+            input_reader,
+            primer,
+            long_range,
+            min_match,
+            prices,
+            options,
+            emit,
+        ),
+        None => tokenise_window(input_reader, primer, min_match, prices, options, emit),
+    }
+}
+
+/// Tokenise [input_reader] with matches from within the window, parsing optimally by [prices]
+/// if there are any
+pub(crate) fn tokenise_window<R: Read>(
+    input_reader: R,
+    primer: &[u8],
+    min_match: usize,
+    prices: Option<&Prices>,
+    options: &EncodeOptions,
+    emit: impl FnMut(&Token) -> Result<()>,
+) -> Result<()> {
+    match prices {
+        Some(prices) => OptimalParser::new(
+            options.window_size,
+            min_match,
+            options.max_match_len,
+            options.rle_min_run,
+            prices.clone(),
+        )
+        .tokenise(input_reader, primer, emit),
+        None => tokenise(
+            input_reader,
+            primer,
+            options.window_size,
+            options
+                .match_strategy
+                .finder(min_match, options.window_size)
+                .as_mut(),
+            options.max_match_len,
+            options.rle_min_run,
+            emit,
+        ),
+    }
+}
+
+/// Run the LZSS matcher over [input_reader], passing each value to [emit]. With [rle_min_run],
+/// runs at least that long are picked out before matching, and otherwise [finder] picks the
+/// value. Matches can reach back into [primer], which is taken as coming just before the input
+pub(crate) fn tokenise<R: Read>(
+    mut input_reader: R,
+    primer: &[u8],
+    window_size: usize,
+    finder: &mut dyn MatchFinder,
+    max_match_len: usize,
+    rle_min_run: Option<usize>,
+    mut emit: impl FnMut(&Token) -> Result<()>,
+) -> Result<()> {
+    let mut input_buffer: [u8; 1] = [0b0; 1];
+    let mut read_buffer = VecDeque::<u8>::new();
+    let mut lookback_buffer = VecDeque::<u8>::from(primer.to_vec());
+    // Of the front of the read buffer, counting the primer
+    let mut position = primer.len() as u64;
 
     //Init read buffer
     for _i in 0..MAX_READ_BUFFER_LEN {
         helpers::step_buffers(
             1,
-            &mut input_file_reader,
+            &mut input_reader,
             &mut input_buffer,
             &mut read_buffer,
             &mut lookback_buffer,
+            window_size,
             false,
-        );
+        )?;
     }
 
-    // TODO: Expose this or just get rid of it
-    let no_matching = false;
-
     // Keep going until read_buffer is empty
-    while read_buffer.len() > 0 {
+    while !read_buffer.is_empty() {
         //Match
-        let next_value = find_match(&read_buffer, &lookback_buffer, no_matching);
+        let run = match rle_min_run {
+            Some(min_run) => find_run(
+                min_run,
+                max_match_len,
+                &mut input_reader,
+                &mut input_buffer,
+                &mut read_buffer,
+            )?,
+            None => None,
+        };
+        let mut next_value = run.unwrap_or_else(|| {
+            finder.next_token(&Window::new(&lookback_buffer, &read_buffer, position))
+        });
+        if let Token::Match(offset_len) = &mut next_value {
+            if offset_len.len.get() == read_buffer.len() {
+                extend_match(
+                    offset_len,
+                    max_match_len,
+                    &mut input_reader,
+                    &mut input_buffer,
+                    &mut read_buffer,
+                    &lookback_buffer,
+                )?;
+            }
+        }
         let step_size = match next_value {
-            EncodedValue::RawU8(_) => 1,
-            EncodedValue::OffsetLen(OffsetLen { len, .. }) => len as usize,
+            Token::Literal(_) => 1,
+            Token::Match(OffsetLen { len, .. }) => len.get(),
+            Token::Run(_, len) => len as usize,
+            Token::EndOfStream(_) | Token::Control(_) => {
+                unreachable!("Match finders only make literals and matches")
+            }
         };
-        output_stream.add(&next_value);
+        emit(&next_value)?;
+        position += step_size as u64;
 
         helpers::step_buffers(
             step_size,
-            &mut input_file_reader,
+            &mut input_reader,
             &mut input_buffer,
             &mut read_buffer,
             &mut lookback_buffer,
+            window_size,
             true,
-        );
-    }
-    output_stream.finalise();
-    {
-        let debug_filename = match &file_io.debug_encoded_filename {
-            Some(p) => format!(" (and {:?})", p),
-            None => String::from(""),
-        };
-        println!(
-            "Done: Encoded {:?} -> {:?}{}",
-            file_io.unencoded_filename, file_io.encoded_filename, debug_filename
-        );
+        )?;
     }
+    Ok(())
 }
 
-fn create_output_stream(
+fn create_output_stream<W: Write>(
     byte_stats: ByteStats,
-    writer: BufWriter<File>,
-    debug_writer: Option<BufWriter<File>>,
-) -> OutputStream<File> {
-    let tree = crate::huffman::build_tree(byte_stats);
-    let code_map = crate::huffman::tree_to_code_map(&tree);
-    let mut output_stream = OutputStream::new(code_map, writer, debug_writer);
-    let header = Header::new(tree, MAX_LOOKBACK_BUFFER_LEN as u64);
-    output_stream.write_header(&header);
+    writer: BufWriter<W>,
+    trace_writer: Option<Box<dyn Write>>,
+    file_metadata: Option<FileMetadata>,
+    primed_len: usize,
+    auto_settings: Option<AutoSettings>,
+    options: &EncodeOptions,
+) -> Result<OutputStream<'static, W>> {
+    let rans_table = match (options.algorithm, options.entropy) {
+        (Algorithm::LzHuffman, Entropy::Rans) => Some(RansTable::from_stats(&byte_stats)),
+        _ => None,
+    };
+    let tree = match options.algorithm {
+        Algorithm::Lz | Algorithm::Stored => None,
+        Algorithm::LzHuffman if rans_table.is_some() => None,
+        Algorithm::Huffman | Algorithm::LzHuffman | Algorithm::Bwt => {
+            Some(crate::huffman::build_tree(byte_stats))
+        }
+    };
+    let code_map = tree.as_ref().map(crate::huffman::tree_to_code_map);
+    let mut output_stream = OutputStream::new(code_map, writer, trace_writer)
+        .with_max_literal_run(options.max_literal_run);
+    if let Some(rans_table) = &rans_table {
+        output_stream = output_stream.with_run_coder(Box::new(rans_table.clone()));
+    }
+    let header = Header::new(tree, options.window_size as u64)
+        .with_rans_table(rans_table)
+        .with_file_metadata(file_metadata)
+        .with_metadata(options.metadata.clone())
+        .with_algorithm(options.algorithm)
+        .with_filter(options.filter)
+        .with_primed_len(primed_len as u64)
+        .with_auto_settings(auto_settings)
+        .with_long_range(match options.algorithm {
+            Algorithm::Lz | Algorithm::LzHuffman => options.long_range.map(|len| len as u64),
+            _ => None,
+        });
+    output_stream.write_header(&header)?;
 
-    output_stream
+    Ok(output_stream)
 
     /*
     for value in encoded_values.iter() {
@@ -126,63 +1507,811 @@ fn create_output_stream(
     */
 }
 
-fn find_match(
-    read_buffer: &VecDeque<u8>,
-    lookback_buffer: &VecDeque<u8>,
-    no_matching: bool,
-) -> EncodedValue {
-    // TODO support the max values in the OffsetLen
-    let total_len = read_buffer.len() + lookback_buffer.len();
-    // Current match: offset, matched bytes
-    // TODO: Type this up a bit?
-    let mut current_match = (0, Vec::new());
-    let mut best_match: Option<(usize, Vec<u8>)> = None;
-    if !no_matching {
-        for i in 0..total_len {
-            // TODO: Disabled looking ahead into read_buffer because repetitions into it are broken
-            if i >= lookback_buffer.len() {
-                break;
-            }
-            //Never start matching when looking at read buffer, or we'll always match read buffer on itself
-            if i >= lookback_buffer.len() && current_match.1.is_empty() {
-                break;
-            }
-            let looking_at = if i < lookback_buffer.len() {
-                lookback_buffer[i]
-            } else {
-                read_buffer[i - lookback_buffer.len()]
-            };
-            let expecting = read_buffer.get(current_match.1.len());
-            if let Some(expecting_v) = expecting {
-                if looking_at == *expecting_v {
-                    if current_match.1.is_empty() {
-                        current_match.0 = i;
-                    }
-                    current_match.1.push(looking_at);
-
-                    let is_best = match &best_match {
-                        None => true,
-                        Some((_, matched_values)) => current_match.1.len() > matched_values.len(),
-                    };
-                    if is_best {
-                        best_match = Some(current_match.clone())
+/// A run of the byte at the front of [read_buffer] if it's at least [min_run] long. Like
+/// [extend_match], a run covering the whole read buffer carries on reading input (up to
+/// [max_len])
+fn find_run<R: Read>(
+    min_run: usize,
+    max_len: usize,
+    reader: &mut R,
+    input_buffer: &mut [u8],
+    read_buffer: &mut VecDeque<u8>,
+) -> std::io::Result<Option<Token>> {
+    let Some(&value) = read_buffer.front() else {
+        return Ok(None);
+    };
+    let mut len = read_buffer
+        .iter()
+        .take(max_len)
+        .take_while(|v| **v == value)
+        .count();
+    if len == read_buffer.len() {
+        while len < max_len {
+            match helpers::read_byte(reader, input_buffer)? {
+                None => break,
+                Some(v) => {
+                    read_buffer.push_back(v);
+                    if v != value {
+                        break;
                     }
-                } else {
-                    current_match.0 = 0;
-                    current_match.1.clear();
+                    len += 1;
+                }
+            }
+        }
+    }
+    Ok((len >= min_run).then_some(Token::Run(value, len as u64)))
+}
+
+/// A match covering the whole read buffer may well carry on past it, so keep reading input
+/// onto the end of the read buffer for as long as it continues the match (up to
+/// [max_match_len]). This lets a single match cover repeats much longer than the read buffer
+fn extend_match<R: Read>(
+    offset_len: &mut OffsetLen,
+    max_match_len: usize,
+    reader: &mut R,
+    input_buffer: &mut [u8],
+    read_buffer: &mut VecDeque<u8>,
+    lookback_buffer: &VecDeque<u8>,
+) -> std::io::Result<()> {
+    let Some(start) = offset_len.offset().start(lookback_buffer.len()) else {
+        return Ok(());
+    };
+    while offset_len.len.get() < max_match_len {
+        let source_pos = start + offset_len.len.get();
+        let expecting = if source_pos < lookback_buffer.len() {
+            lookback_buffer[source_pos]
+        } else {
+            read_buffer[source_pos - lookback_buffer.len()]
+        };
+        match helpers::read_byte(reader, input_buffer)? {
+            None => break,
+            Some(v) => {
+                read_buffer.push_back(v);
+                if v != expecting {
+                    break;
+                }
+                offset_len.extend(v);
+            }
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::VecDeque;
+    use std::io::{Cursor, Write};
+
+    use crate::control::ControlRecord;
+    use crate::decode::{decode_to_writer_with_options, DecodeOptions};
+    use crate::encode::{
+        auto_min_match, dry_run, encode, encode_frame, encode_from_reader, estimate_ratio,
+        extend_match, AutoSettings, StreamEncoder,
+    };
+    use crate::file_io::{FileInputOutput, InputFile, OutputTarget};
+    use crate::helpers::{FailingReader, FailingWriter, ShortWriter};
+    use crate::layout::Part;
+    use crate::match_finder::{MatchFinder, MatchStrategy, Scan, Window};
+    use crate::offset_len::OffsetLen;
+    use crate::test_util::temp_path;
+    use crate::{
+        Algorithm, EncodeOptions, Error, Filter, Header, Token, DEFAULT_LEVEL, MAX_LEVEL,
+        MAX_LOOKBACK_BUFFER_LEN,
+    };
+
+    fn find(lookback: &str, read: &str) -> Token {
+        let lookback_buffer: VecDeque<u8> = lookback.bytes().collect();
+        let read_buffer: VecDeque<u8> = read.bytes().collect();
+        Scan::new(4).next_token(&Window::new(&lookback_buffer, &read_buffer, 0))
+    }
+
+    fn assert_match(expected: Option<(usize, usize)>, value: Token) {
+        match (expected, value) {
+            (None, Token::Literal(_)) => (),
+            (Some((offset, len)), Token::Match(offset_len)) => {
+                assert_eq!(
+                    (offset, len),
+                    (offset_len.offset().get(), offset_len.len.get())
+                )
+            }
+            (Some(expected), Token::Literal(v)) => {
+                panic!("Expected match {:?}, got raw {}", expected, v)
+            }
+            (None, Token::Match(offset_len)) => {
+                panic!("Expected raw, got match {:?}", offset_len)
+            }
+            (_, token) => panic!("Scan only makes literals and matches, not {:?}", token),
+        }
+    }
+
+    #[test]
+    fn finds_longest_match() {
+        assert_match(Some((7, 6)), find("abcd_abcdef_", "abcdefgh"));
+        assert_match(None, find("abcd_abcdef_", "xyz"));
+        // Too short to be worth it
+        assert_match(None, find("abc_", "abcx"));
+    }
+
+    #[test]
+    fn match_runs_into_read_buffer() {
+        assert_match(Some((1, 10)), find("xa", "aaaaaaaaaa"));
+        assert_match(Some((3, 9)), find("abc", "abcabcabc"));
+    }
+
+    #[test]
+    fn min_match_follows_entropy() {
+        let window_size = crate::MAX_LOOKBACK_BUFFER_LEN;
+        assert_eq!(crate::MIN_MATCH_SIZE, auto_min_match(&[], window_size));
+        // Every byte value equally likely: 8 bits per literal
+        let uniform: Vec<u8> = (0..=255).collect();
+        assert_eq!(4, auto_min_match(&uniform, window_size));
+        // A bigger window means dearer offsets
+        assert_eq!(5, auto_min_match(&uniform, 100_000));
+        // Literals are cheap, so matches need to be long to be worth it
+        let two_symbols = "ab".repeat(100);
+        assert_eq!(
+            crate::MAX_AUTO_MIN_MATCH_SIZE,
+            auto_min_match(two_symbols.as_bytes(), window_size)
+        );
+        let one_symbol = "a".repeat(100);
+        assert_eq!(
+            crate::MAX_AUTO_MIN_MATCH_SIZE,
+            auto_min_match(one_symbol.as_bytes(), window_size)
+        );
+    }
+
+    #[test]
+    fn match_extends_past_read_buffer() {
+        let lookback_buffer: VecDeque<u8> = "_ab".bytes().collect();
+        let mut read_buffer: VecDeque<u8> = "abab".bytes().collect();
+        let mut offset_len = OffsetLen::of(2, 4);
+        let mut reader = "abababaX_ab".as_bytes();
+        extend_match(
+            &mut offset_len,
+            crate::MAX_MATCH_LEN,
+            &mut reader,
+            &mut [0],
+            &mut read_buffer,
+            &lookback_buffer,
+        )
+        .unwrap();
+        assert_eq!((2, 11), (offset_len.offset().get(), offset_len.len.get()));
+        // Everything read is kept in the read buffer, including the byte which broke the match
+        assert_eq!(b"abababababaX".to_vec(), Vec::from(read_buffer));
+        assert_eq!("_ab".as_bytes(), reader);
+
+        let mut read_buffer: VecDeque<u8> = "abab".bytes().collect();
+        let mut offset_len = OffsetLen::of(2, 4);
+        let mut reader = "abababaX_ab".as_bytes();
+        extend_match(
+            &mut offset_len,
+            6,
+            &mut reader,
+            &mut [0],
+            &mut read_buffer,
+            &lookback_buffer,
+        )
+        .unwrap();
+        assert_eq!((2, 6), (offset_len.offset().get(), offset_len.len.get()));
+    }
+
+    #[test]
+    fn encode_from_reader_round_trip() {
+        let input = "The cat sat on the mat. The cat sat on the hat. ".repeat(20);
+        let mut encoded = Vec::new();
+        let summary =
+            encode_from_reader(input.as_bytes(), &mut encoded, &EncodeOptions::default()).unwrap();
+        assert_eq!(input.len() as u64, summary.input_bytes);
+        assert_eq!(encoded.len() as u64, summary.output_bytes);
+        assert!(summary.matches > 0);
+        assert!(summary.literals < input.len() as u64);
+        assert!(summary.ratio() < 1.0);
+
+        let mut decoded = Vec::new();
+        crate::decode::decode_to_writer(&encoded[..], &mut decoded).unwrap();
+        assert_eq!(input.into_bytes(), decoded);
+    }
+
+    #[test]
+    fn algorithms() {
+        let input = "The cat sat on the mat. The cat sat on the hat. ".repeat(20);
+        let encode = |algorithm| {
+            let options = EncodeOptions::default().algorithm(algorithm);
+            let mut encoded = Vec::new();
+            let summary = encode_from_reader(input.as_bytes(), &mut encoded, &options).unwrap();
+            assert_eq!(
+                input.as_bytes(),
+                crate::decode::decode_bytes(&encoded).unwrap()
+            );
+            summary
+        };
+        let huffman = encode(Algorithm::Huffman);
+        assert_eq!(0, huffman.matches);
+        assert_eq!(input.len() as u64, huffman.literals);
+        let lz = encode(Algorithm::Lz);
+        assert!(lz.matches > 0);
+        // Repeated sentences are all matches after the first, so matching wins
+        assert!(lz.output_bytes < huffman.output_bytes);
+        let stored = encode(Algorithm::Stored);
+        assert_eq!(input.len() as u64 + 6, stored.output_bytes);
+    }
+
+    #[test]
+    fn never_expands() {
+        let mut state = 7u64;
+        let input: Vec<u8> = (0..20_000)
+            .map(|_| {
+                state = state
+                    .wrapping_mul(6364136223846793005)
+                    .wrapping_add(1442695040888963407);
+                (state >> 56) as u8
+            })
+            .collect();
+        for algorithm in [Algorithm::LzHuffman, Algorithm::Huffman, Algorithm::Lz] {
+            let options = EncodeOptions::default().algorithm(algorithm);
+            let mut encoded = Vec::new();
+            let summary = encode_from_reader(&input[..], &mut encoded, &options).unwrap();
+            assert_eq!(
+                super::max_encoded_len(input.len() as u64),
+                summary.output_bytes
+            );
+            assert_eq!(encoded.len() as u64, summary.output_bytes);
+            assert_eq!(crc32fast::hash(&input), summary.checksum);
+            assert_eq!(input, crate::decode::decode_bytes(&encoded).unwrap());
+        }
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn incompressible_files_keep_metadata() {
+        use std::os::unix::fs::PermissionsExt;
+        let dir = temp_path("incompressible_metadata");
+        std::fs::create_dir_all(&dir).unwrap();
+        let mut state = 3u64;
+        let input: Vec<u8> = (0..300)
+            .map(|_| {
+                state = state
+                    .wrapping_mul(6364136223846793005)
+                    .wrapping_add(1442695040888963407);
+                (state >> 56) as u8
+            })
+            .collect();
+        let input_path = dir.join("random.bin");
+        std::fs::write(&input_path, &input).unwrap();
+        let modified = std::time::UNIX_EPOCH + std::time::Duration::from_secs(1_000_000_000);
+        let file = std::fs::File::options()
+            .write(true)
+            .open(&input_path)
+            .unwrap();
+        file.set_modified(modified).unwrap();
+        std::fs::set_permissions(&input_path, std::fs::Permissions::from_mode(0o640)).unwrap();
+
+        let file_io = FileInputOutput::builder(InputFile::Unencoded(input_path))
+            .build()
+            .unwrap();
+        encode(&file_io, &EncodeOptions::default()).unwrap();
+        let encoded = std::fs::read(&file_io.encoded_filename).unwrap();
+        assert!(!encoded.starts_with(crate::format::stored::MAGIC));
+        let output = dir.join("decoded");
+        let file_io = FileInputOutput::builder(InputFile::Encoded(file_io.encoded_filename))
+            .output(OutputTarget::Path(output.clone()))
+            .build()
+            .unwrap();
+        crate::decode::decode(&file_io, &DecodeOptions::default()).unwrap();
+        assert_eq!(std::fs::read(&output).unwrap(), input);
+        let metadata = std::fs::metadata(&output).unwrap();
+        assert_eq!(metadata.modified().unwrap(), modified);
+        assert_eq!(metadata.permissions().mode() & 0o777, 0o640);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn tiny_inputs_are_stored() {
+        for input in [&b"hello"[..], b"The cat sat on the mat"] {
+            let mut encoded = Vec::new();
+            let summary =
+                encode_from_reader(input, &mut encoded, &EncodeOptions::default()).unwrap();
+            assert_eq!(input.len() + 5, encoded.len());
+            assert_eq!(encoded.len() as u64, summary.output_bytes);
+            assert!(encoded.starts_with(crate::format::stored::MAGIC));
+            assert_eq!(input, crate::decode::decode_bytes(&encoded).unwrap());
+        }
+    }
+
+    #[test]
+    fn degenerate_inputs() {
+        let mut algorithms = vec![Algorithm::LzHuffman, Algorithm::Huffman, Algorithm::Lz];
+        if cfg!(feature = "bwt") {
+            algorithms.push(Algorithm::Bwt);
+        }
+        for input in [vec![], vec![b'a'], vec![b'a'; 10_000]] {
+            for algorithm in &algorithms {
+                for options in [
+                    EncodeOptions::default(),
+                    EncodeOptions::default().entropy(crate::Entropy::Rans),
+                    // Metadata rules out a stored frame, so empty input has to be coded
+                    EncodeOptions::default().metadata("name", "empty"),
+                    EncodeOptions::default().block_size(Some(100)),
+                    EncodeOptions::default()
+                        .entropy(crate::Entropy::Rans)
+                        .max_literal_run(3),
+                ] {
+                    let options = options.algorithm(*algorithm);
+                    let mut encoded = Vec::new();
+                    encode_from_reader(&input[..], &mut encoded, &options).unwrap();
+                    assert_eq!(input, crate::decode::decode_bytes(&encoded).unwrap());
                 }
             }
         }
+        // Empty input makes an empty stored frame
+        let mut encoded = Vec::new();
+        encode_from_reader(&[][..], &mut encoded, &EncodeOptions::default()).unwrap();
+        assert_eq!(&crate::format::stored::MAGIC[..], &encoded[..4]);
+    }
+
+    #[test]
+    fn stream_encoder() {
+        let input: Vec<u8> = (0..5000)
+            .flat_map(|i| format!("{} bottles, ", i % 99).into_bytes())
+            .collect();
+        for options in [
+            EncodeOptions::default(),
+            EncodeOptions::default().block_size(Some(2000)),
+            EncodeOptions::default().algorithm(Algorithm::Stored),
+        ] {
+            let mut encoder = StreamEncoder::new(Vec::new(), &options).segment_len(10_000);
+            for piece in input.chunks(333) {
+                encoder.write_all(piece).unwrap();
+            }
+            let (encoded, summary) = encoder.finish().unwrap();
+            assert_eq!(summary.input_bytes, input.len() as u64);
+            assert_eq!(summary.output_bytes, encoded.len() as u64);
+            assert_eq!(summary.checksum, crc32fast::hash(&input));
+            assert_eq!(input, crate::decode::decode_bytes(&encoded).unwrap());
+            let threaded = DecodeOptions::default().threads(4);
+            let mut decoded = Vec::new();
+            decode_to_writer_with_options(&encoded[..], &mut decoded, &threaded).unwrap();
+            assert_eq!(input, decoded);
+        }
+        // Finishing without writing anything gives an empty stream
+        let (encoded, _) = StreamEncoder::new(Vec::new(), &EncodeOptions::default())
+            .finish()
+            .unwrap();
+        assert_eq!(b"".to_vec(), crate::decode::decode_bytes(&encoded).unwrap());
     }
-    match best_match {
-        None => EncodedValue::RawU8(*read_buffer.front().unwrap()),
-        Some((_, matched_values)) if matched_values.len() < MIN_MATCH_SIZE => {
-            EncodedValue::RawU8(*read_buffer.front().unwrap())
+
+    #[test]
+    fn shared_window() {
+        // Each segment repeats the one before, but nothing within a segment repeats
+        let segment: Vec<u8> = (0..600u32)
+            .scan(1u32, |x, _| {
+                *x = x.wrapping_mul(1_103_515_245).wrapping_add(12345);
+                Some((*x >> 16) as u8)
+            })
+            .collect();
+        let input = segment.repeat(10);
+        let encode = |options: &EncodeOptions, shared_window| {
+            let mut encoder = StreamEncoder::new(Vec::new(), options)
+                .segment_len(segment.len())
+                .shared_window(shared_window);
+            encoder.write_all(&input[..segment.len()]).unwrap();
+            let first_frame_len = encoder.get_ref().len();
+            encoder.write_all(&input[segment.len()..]).unwrap();
+            let (encoded, _) = encoder.finish().unwrap();
+            assert_eq!(input, crate::decode::decode_bytes(&encoded).unwrap());
+            let threaded = DecodeOptions::default().threads(4);
+            let mut decoded = Vec::new();
+            decode_to_writer_with_options(&encoded[..], &mut decoded, &threaded).unwrap();
+            assert_eq!(input, decoded);
+            (encoded, first_frame_len)
+        };
+        // A huffman tree of random bytes would outweigh the matches saved
+        let lz = EncodeOptions::default().algorithm(Algorithm::Lz);
+        let (independent, _) = encode(&lz, false);
+        let (shared, first_frame_len) = encode(&lz, true);
+        assert!(shared.len() * 3 < independent.len());
+        // Later frames can't be decoded without the ones before
+        assert!(matches!(
+            crate::decode::decode_bytes(&shared[first_frame_len..]),
+            Err(Error::CorruptHeader(_))
+        ));
+        // Frames which can't be primed are still written, just without sharing
+        for options in [
+            EncodeOptions::default().filter(Filter::Delta8),
+            EncodeOptions::default().block_size(Some(200)),
+            EncodeOptions::default().algorithm(Algorithm::Huffman),
+        ] {
+            let (independent, _) = encode(&options, false);
+            assert_eq!(encode(&options, true).0, independent);
         }
-        Some((offset, matched_values)) => EncodedValue::OffsetLen(OffsetLen::new_with_match(
-            offset as u64,
-            matched_values.len() as u64,
-            Some(matched_values),
-        )),
+    }
+
+    #[cfg(feature = "bwt")]
+    #[test]
+    fn bwt() {
+        let input = include_bytes!("../README.md").to_vec();
+        let encode = |options: EncodeOptions| {
+            let mut encoded = Vec::new();
+            let summary = encode_from_reader(&input[..], &mut encoded, &options).unwrap();
+            assert_eq!(input, crate::decode::decode_bytes(&encoded).unwrap());
+            summary.output_bytes
+        };
+        let bwt = EncodeOptions::default().algorithm(Algorithm::Bwt);
+        // Several blocks, including a short one at the end
+        encode(bwt.clone().bwt_block_size(500));
+        assert!(encode(bwt) < encode(EncodeOptions::default()));
+    }
+
+    #[test]
+    fn filters() {
+        // A slowly rising 16 bit signal
+        let input: Vec<u8> = (0..4000u16).flat_map(|i| (i * 3).to_be_bytes()).collect();
+        let encode = |filter| {
+            let options = EncodeOptions::default().filter(filter);
+            let mut encoded = Vec::new();
+            let summary = encode_from_reader(&input[..], &mut encoded, &options).unwrap();
+            // The summary describes the input, not what it was filtered to
+            assert_eq!(crc32fast::hash(&input), summary.checksum);
+            assert_eq!(input, crate::decode::decode_bytes(&encoded).unwrap());
+            summary.output_bytes
+        };
+        assert!(encode(Filter::Delta16) < encode(Filter::None) / 10);
+    }
+
+    #[test]
+    fn auto_level() {
+        let options = EncodeOptions::default().auto_level();
+        let random: Vec<u8> = (0..20_000u32)
+            .scan(1u32, |x, _| {
+                *x = x.wrapping_mul(1_103_515_245).wrapping_add(12345);
+                Some((*x >> 16) as u8)
+            })
+            .collect();
+        let settings = AutoSettings::pick(&random, &options).unwrap();
+        assert_eq!(
+            (DEFAULT_LEVEL, Algorithm::Lz),
+            (settings.level, settings.algorithm)
+        );
+        // A min match that was given is kept
+        let settings = AutoSettings::pick(&random, &options.clone().min_match(9)).unwrap();
+        assert_eq!(9, settings.min_match);
+
+        let text: Vec<u8> = (0..3000)
+            .flat_map(|i| format!("{} bottles, ", i % 99).into_bytes())
+            .collect();
+        let encode = |options: &EncodeOptions| {
+            let mut encoded = Vec::new();
+            encode_from_reader(&text[..], &mut encoded, options).unwrap();
+            assert_eq!(text, crate::decode::decode_bytes(&encoded).unwrap());
+            Header::read_from(&encoded[..]).unwrap().auto_settings()
+        };
+        let expected = AutoSettings {
+            level: MAX_LEVEL,
+            min_match: auto_min_match(&text, MAX_LOOKBACK_BUFFER_LEN),
+            algorithm: Algorithm::LzHuffman,
+        };
+        assert_eq!(Some(expected), encode(&options));
+        // Giving a level turns it off again
+        assert_eq!(None, encode(&options.level(DEFAULT_LEVEL)));
+    }
+
+    #[test]
+    fn threaded_blocks() {
+        let text = include_bytes!("../README.md");
+        let encode = |input: &[u8], options: &EncodeOptions| {
+            let mut encoded = Vec::new();
+            let summary = encode_from_reader(input, &mut encoded, options).unwrap();
+            (encoded, summary)
+        };
+        // Fewer blocks than threads, a whole number of blocks, and a partial block at the end
+        for input in [&text[..10_000], &text[..8192], &text[..]] {
+            for options in [
+                EncodeOptions::default().block_size(Some(1024)),
+                EncodeOptions::default()
+                    .algorithm(Algorithm::Lz)
+                    .block_size(Some(4096))
+                    .content_defined_blocks(true),
+            ] {
+                let (expected, summary) = encode(input, &options);
+                for threads in [2, 16] {
+                    let threaded = encode(input, &options.clone().threads(threads));
+                    assert_eq!((&expected, &summary), (&threaded.0, &threaded.1));
+                }
+            }
+        }
+        let blocks = EncodeOptions::default().block_size(Some(1024));
+        assert_eq!(
+            encode(b"", &blocks.clone().threads(4)),
+            encode(b"", &blocks)
+        );
+    }
+
+    #[test]
+    fn content_defined_blocks() {
+        // Text rather than anything periodic, which might never hit a boundary and so be cut
+        // into the longest blocks wherever it starts
+        let input = include_bytes!("../README.md").repeat(2);
+        let options = EncodeOptions::default()
+            .block_size(Some(4096))
+            .content_defined_blocks(true);
+        let mut encoded = Vec::new();
+        encode_from_reader(&input[..], &mut encoded, &options).unwrap();
+        assert_eq!(input, crate::decode::decode_bytes(&encoded).unwrap());
+        let threaded = DecodeOptions::default().threads(4);
+        let mut decoded = Vec::new();
+        decode_to_writer_with_options(&encoded[..], &mut decoded, &threaded).unwrap();
+        assert_eq!(input, decoded);
+        let blocks = crate::layout::layout(&encoded)
+            .0
+            .iter()
+            .filter(|span| matches!(span.part, Part::Control(ControlRecord::BlockCheck { .. })))
+            .count();
+        assert!((input.len() / 16384..=input.len() / 1024).contains(&blocks));
+
+        // With stored literals, the blocks after an insertion near the start are encoded just as
+        // they were before it
+        let encoded_blocks = |input: &[u8]| {
+            let mut encoded = Vec::new();
+            let lz = options.clone().algorithm(Algorithm::Lz);
+            encode_from_reader(input, &mut encoded, &lz).unwrap();
+            let (spans, _) = crate::layout::layout(&encoded);
+            let ends: Vec<_> = spans
+                .iter()
+                .filter(|span| matches!(span.part, Part::Control(ControlRecord::BlockCheck { .. })))
+                .map(|span| span.start + span.len)
+                .collect();
+            ends.windows(2)
+                .map(|pair| encoded[pair[0] as usize..pair[1] as usize].to_vec())
+                .collect::<std::collections::HashSet<_>>()
+        };
+        let before = encoded_blocks(&input);
+        let mut inserted = b"inserted ".to_vec();
+        inserted.extend(&input);
+        let after = encoded_blocks(&inserted);
+        assert!(before.intersection(&after).count() >= before.len() - 3);
+    }
+
+    #[cfg(feature = "cache")]
+    #[test]
+    fn cache_dir() {
+        let dir = temp_path("cache_dir");
+        let input = include_bytes!("../README.md").repeat(2);
+        let options = EncodeOptions::default()
+            .block_size(Some(4096))
+            .content_defined_blocks(true)
+            .cache_dir(Some(&dir));
+        let encode = |input: &[u8]| {
+            let mut encoded = Vec::new();
+            let summary = encode_from_reader(input, &mut encoded, &options).unwrap();
+            assert_eq!(input, crate::decode::decode_bytes(&encoded).unwrap());
+            (encoded, summary.cached_blocks)
+        };
+        // Most of the second copy's blocks are cut just as the first copy's were, so they're
+        // found in the cache already
+        let (first, first_cached) = encode(&input);
+        // Coded just as before, every block from the cache
+        let (second, cached) = encode(&input);
+        assert_eq!(first, second);
+        let blocks = crate::layout::layout(&second)
+            .0
+            .iter()
+            .filter(|span| matches!(span.part, Part::Control(ControlRecord::BlockCheck { .. })))
+            .count();
+        assert_eq!(blocks as u64, cached);
+        assert!((1..blocks as u64 / 2 + 1).contains(&first_cached));
+        // All but the blocks around an insertion
+        let mut inserted = b"inserted ".to_vec();
+        inserted.extend(&input);
+        let (_, cached) = encode(&inserted);
+        assert!(cached >= blocks as u64 - 3);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn long_range() {
+        let random = |len: usize, seed: u32| -> Vec<u8> {
+            (0..len)
+                .scan(seed, |x, _| {
+                    *x = x.wrapping_mul(1_103_515_245).wrapping_add(12345);
+                    Some((*x >> 16) as u8)
+                })
+                .collect()
+        };
+        let mut input = random(20_000, 1);
+        input.extend(random(30_000, 2));
+        input.extend(input[..20_000].to_vec());
+        let encode = |options: &EncodeOptions| {
+            let options = options.clone().match_strategy(MatchStrategy::HashChain);
+            let mut encoded = Vec::new();
+            encode_from_reader(&input[..], &mut encoded, &options).unwrap();
+            assert_eq!(input, crate::decode::decode_bytes(&encoded).unwrap());
+            let threaded = DecodeOptions::default().threads(4);
+            let mut decoded = Vec::new();
+            decode_to_writer_with_options(&encoded[..], &mut decoded, &threaded).unwrap();
+            assert_eq!(input, decoded);
+            encoded
+        };
+        let without = encode(&EncodeOptions::default());
+        let options = EncodeOptions::default().long_range(Some(1 << 20));
+        let with = encode(&options);
+        assert!(with.len() < without.len() - 15_000);
+        assert_eq!(
+            Some(1 << 20),
+            Header::read_from(&with[..]).unwrap().long_range()
+        );
+        // Only within blocks, which decode on their own
+        let blocks = encode(&options.clone().block_size(Some(40_000)));
+        assert!(blocks.len() > without.len() - 1000);
+        assert!(encode(&options.block_size(Some(100_000))).len() < without.len() - 15_000);
+    }
+
+    #[test]
+    fn runs() {
+        let mut input = vec![1, 2, 3];
+        input.extend([0; 5000]);
+        input.extend(b"abcabc");
+        input.extend([b'x'; 9]);
+        let options = EncodeOptions::default().rle_min_run(Some(10));
+        let mut encoded = Vec::new();
+        let summary = encode_from_reader(&input[..], &mut encoded, &options).unwrap();
+        // The zeros, but not the x's
+        assert_eq!(1, summary.runs);
+        assert_eq!(input, crate::decode::decode_bytes(&encoded).unwrap());
+    }
+
+    #[test]
+    fn optimal_parse() {
+        let input = crate::selftest::inputs(20_000).remove(5).1;
+        for algorithm in [Algorithm::Lz, Algorithm::LzHuffman] {
+            for block_size in [None, Some(4096)] {
+                let encode = |level| {
+                    let options = EncodeOptions::default()
+                        .algorithm(algorithm)
+                        .block_size(block_size)
+                        .level(level);
+                    let mut encoded = Vec::new();
+                    let summary = encode_from_reader(&input[..], &mut encoded, &options).unwrap();
+                    assert_eq!(input, crate::decode::decode_bytes(&encoded).unwrap());
+                    summary.output_bytes
+                };
+                assert!(encode(MAX_LEVEL) < encode(DEFAULT_LEVEL));
+            }
+        }
+    }
+
+    #[test]
+    fn failing_writer() {
+        let input = crate::selftest::inputs(20_000).remove(5).1;
+        let options = [
+            EncodeOptions::default(),
+            EncodeOptions::default().algorithm(Algorithm::Lz),
+            EncodeOptions::default().algorithm(Algorithm::Huffman),
+            EncodeOptions::default().entropy(crate::Entropy::Rans),
+            EncodeOptions::default().block_size(Some(4096)),
+        ];
+        for options in &options {
+            let encode = |writer: &mut dyn Write, trace: Option<Box<dyn Write>>| {
+                encode_frame(Cursor::new(&input), writer, trace, None, &[], options)
+            };
+            let mut encoded = Vec::new();
+            encode(&mut encoded, None).unwrap();
+            // Failing straight away, part way through and on the very last byte
+            for accept in [0, encoded.len() / 2, encoded.len() - 1] {
+                let result = encode(&mut FailingWriter { accept }, None);
+                assert!(matches!(result, Err(Error::Io(_))), "{:?}", result);
+            }
+            let trace = Box::new(FailingWriter { accept: 100 });
+            let result = encode(&mut Vec::new(), Some(trace));
+            assert!(matches!(result, Err(Error::Io(_))), "{:?}", result);
+        }
+    }
+
+    #[test]
+    fn failing_reader() {
+        let input = crate::selftest::inputs(20_000).remove(5).1;
+        let options = [
+            EncodeOptions::default(),
+            EncodeOptions::default()
+                .algorithm(Algorithm::Lz)
+                .rle_min_run(Some(4)),
+            EncodeOptions::default().algorithm(Algorithm::Huffman),
+            EncodeOptions::default().level(MAX_LEVEL),
+            EncodeOptions::default().long_range(Some(1 << 20)),
+        ];
+        for options in &options {
+            let encode = |fail_at| {
+                let reader = FailingReader {
+                    inner: Cursor::new(&input[..]),
+                    fail_at,
+                    interrupted: false,
+                };
+                let mut encoded = Vec::new();
+                encode_frame(reader, &mut encoded, None, None, &[], options).map(|_| encoded)
+            };
+            // Interrupted reads are retried
+            let mut expected = Vec::new();
+            encode_frame(Cursor::new(&input), &mut expected, None, None, &[], options).unwrap();
+            assert_eq!(expected, encode(None).unwrap());
+            // Failing straight away, part way through and on the very last byte
+            for fail_at in [0, input.len() / 2, input.len() - 1] {
+                let result = encode(Some(fail_at as u64));
+                assert!(matches!(result, Err(Error::Io(_))), "{:?}", result);
+            }
+        }
+    }
+
+    #[test]
+    fn short_writes() {
+        let input = crate::selftest::inputs(20_000).remove(5).1;
+        for algorithm in [Algorithm::Lz, Algorithm::LzHuffman, Algorithm::Huffman] {
+            let options = EncodeOptions::default().algorithm(algorithm);
+            let mut encoded = Vec::new();
+            encode_frame(Cursor::new(&input), &mut encoded, None, None, &[], &options).unwrap();
+            let mut writer = ShortWriter::new(3);
+            encode_frame(Cursor::new(&input), &mut writer, None, None, &[], &options).unwrap();
+            assert_eq!(encoded, writer.inner);
+        }
+    }
+
+    #[test]
+    fn dry_run_counts_output() {
+        let dir = temp_path("dry_run");
+        std::fs::create_dir_all(&dir).unwrap();
+        let mut state = 1u32;
+        let random: Vec<u8> = (0..10_000)
+            .map(|_| {
+                state ^= state << 13;
+                state ^= state >> 17;
+                state ^= state << 5;
+                state as u8
+            })
+            .collect();
+        // Including input that doesn't compress, so is stored after all, there being no file
+        // metadata to keep
+        for input in [include_bytes!("../README.md").to_vec(), random] {
+            let input_path = dir.join("input");
+            std::fs::write(&input_path, &input).unwrap();
+            let file_io = FileInputOutput::builder(InputFile::Unencoded(input_path))
+                .build()
+                .unwrap();
+            let options = EncodeOptions::default().store_metadata(false);
+            let predicted = dry_run(&file_io, &options).unwrap();
+            assert!(!file_io.encoded_filename.exists());
+            let summary = encode(&file_io, &options).unwrap();
+            let written = std::fs::metadata(&file_io.encoded_filename).unwrap().len();
+            assert_eq!(written, predicted.output_bytes);
+            assert_eq!(summary.output_bytes, predicted.output_bytes);
+            assert_eq!(summary.checksum, predicted.checksum);
+            std::fs::remove_file(&file_io.encoded_filename).unwrap();
+        }
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn estimates_ratio() {
+        let text = include_bytes!("../README.md");
+        let summary =
+            encode_from_reader(&text[..], std::io::sink(), &EncodeOptions::default()).unwrap();
+        let estimate = estimate_ratio(text);
+        assert!(
+            (estimate - summary.ratio()).abs() < 0.1,
+            "Estimated {} for a ratio of {}",
+            estimate,
+            summary.ratio()
+        );
+
+        // Already compressed input barely shrinks, if at all
+        let mut state = 1u32;
+        let random: Vec<u8> = (0..100_000)
+            .map(|_| {
+                state ^= state << 13;
+                state ^= state >> 17;
+                state ^= state << 5;
+                state as u8
+            })
+            .collect();
+        assert!(estimate_ratio(&random) > 0.95);
+        assert!(estimate_ratio(&[0; 10_000]) < 0.01);
+        assert_eq!(1.0, estimate_ratio(&[]));
     }
 }
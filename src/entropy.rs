@@ -0,0 +1,304 @@
+//! The entropy coding stage literals go through after matching. Huffman coding is the default;
+//! rANS gets closer to the entropy of skewed literals, at the cost of coding each run of
+//! literals as a whole rather than as they're added
+
+use serde::{Deserialize, Serialize};
+
+use crate::control::{split_varint, write_varint};
+use crate::error::{Error, Result};
+use crate::huffman::{ByteStats, CodeMap, HuffmanTree, Symbol};
+
+/// Which entropy coder literals go through, recorded in the header
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Entropy {
+    #[default]
+    Huffman,
+    /// Range asymmetric numeral systems, with a static table of byte frequencies
+    Rans,
+}
+
+/// Turns a run of symbols into bytes and back. The decoder is given exactly the bytes the
+/// encoder produced, and fails with [Error::LimitExceeded] rather than give more than
+/// [max_len] symbols
+pub trait EntropyCoder {
+    fn encode(&self, symbols: &[u8]) -> Vec<u8>;
+    fn decode(&self, encoded: &[u8], max_len: u64) -> Result<Vec<u8>>;
+}
+
+fn output_limit(max_len: u64) -> Error {
+    Error::LimitExceeded {
+        limit: "output size",
+        max: max_len,
+    }
+}
+
+/// Huffman coding with a [HuffmanTree], ending each run with (as much as fits of) the END code
+pub struct HuffmanCoder {
+    tree: HuffmanTree,
+    code_map: CodeMap,
+}
+
+impl HuffmanCoder {
+    pub fn new(tree: HuffmanTree) -> Self {
+        let code_map = crate::huffman::tree_to_code_map(&tree);
+        Self { tree, code_map }
+    }
+}
+
+impl EntropyCoder for HuffmanCoder {
+    fn encode(&self, symbols: &[u8]) -> Vec<u8> {
+        crate::huffman::pack_to_u8(&self.code_map, symbols.iter().copied())
+    }
+
+    fn decode(&self, encoded: &[u8], max_len: u64) -> Result<Vec<u8>> {
+        let decoded = crate::huffman::unpack_bytes(encoded, &self.tree);
+        match decoded.len() as u64 <= max_len {
+            true => Ok(decoded),
+            false => Err(output_limit(max_len)),
+        }
+    }
+}
+
+/// Frequencies are scaled to add up to 1 << this
+const RANS_SCALE_BITS: u32 = 12;
+const RANS_TOTAL: u32 = 1 << RANS_SCALE_BITS;
+/// The state is kept between this and 256 times it, outside of which a byte is moved between
+/// it and the output. Small, so the final state written after each run takes only 3 bytes
+const RANS_LOWER_BOUND: u32 = 1 << 15;
+const RANS_STATE_LEN: usize = 3;
+
+/// How often each byte value turns up, scaled to add up to [RANS_TOTAL]. Only the frequencies
+/// are stored in the header, and the rest is worked out from them when it's read
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(try_from = "Vec<u16>", into = "Vec<u16>")]
+pub struct RansTable {
+    freqs: Vec<u16>,
+    /// Where each value's range starts
+    starts: Vec<u32>,
+    /// The value each of the [RANS_TOTAL] slots belongs to
+    slots: Vec<u8>,
+}
+
+impl RansTable {
+    /// Scale [stats] down to [RANS_TOTAL], keeping every value seen at a frequency of at least 1.
+    /// The table only covers bytes, so [stats] mustn't have wider symbols
+    pub fn from_stats(stats: &ByteStats) -> Self {
+        assert!(
+            stats.keys().all(|value| *value <= u8::MAX as Symbol),
+            "rANS tables only code bytes"
+        );
+        let total: u64 = stats.values().sum();
+        let mut freqs = vec![0u16; 256];
+        if total == 0 {
+            // Nothing to code, but the table still has to add up
+            freqs[0] = RANS_TOTAL as u16;
+            return Self::try_from(freqs).unwrap();
+        }
+        for (value, count) in stats {
+            let scaled = (*count * RANS_TOTAL as u64 / total) as u16;
+            freqs[*value as usize] = scaled.max(1);
+        }
+        // Rounding leaves the sum a little off, which is taken from or given to the commonest
+        // values, where it matters least. Ties are broken on value for a deterministic table
+        let mut by_freq: Vec<usize> = (0..256).filter(|value| freqs[*value] > 0).collect();
+        by_freq.sort_by_key(|value| (std::cmp::Reverse(freqs[*value]), *value));
+        let mut sum: u32 = freqs.iter().map(|freq| *freq as u32).sum();
+        for value in by_freq.iter().cycle() {
+            match sum.cmp(&RANS_TOTAL) {
+                std::cmp::Ordering::Equal => break,
+                std::cmp::Ordering::Less => {
+                    freqs[*value] += 1;
+                    sum += 1;
+                }
+                std::cmp::Ordering::Greater if freqs[*value] > 1 => {
+                    freqs[*value] -= 1;
+                    sum -= 1;
+                }
+                std::cmp::Ordering::Greater => (),
+            }
+        }
+        Self::try_from(freqs).unwrap()
+    }
+
+    fn freq(&self, value: u8) -> u32 {
+        self.freqs[value as usize] as u32
+    }
+}
+
+impl TryFrom<Vec<u16>> for RansTable {
+    type Error = String;
+
+    fn try_from(freqs: Vec<u16>) -> std::result::Result<Self, String> {
+        if freqs.len() != 256 {
+            return Err(format!(
+                "Expected 256 rANS frequencies, got {}",
+                freqs.len()
+            ));
+        }
+        let sum: u32 = freqs.iter().map(|freq| *freq as u32).sum();
+        if sum != RANS_TOTAL {
+            return Err(format!(
+                "rANS frequencies add up to {}, not {}",
+                sum, RANS_TOTAL
+            ));
+        }
+        let mut starts = Vec::with_capacity(256);
+        let mut slots = Vec::with_capacity(RANS_TOTAL as usize);
+        for (value, freq) in freqs.iter().enumerate() {
+            starts.push(slots.len() as u32);
+            slots.extend(std::iter::repeat_n(value as u8, *freq as usize));
+        }
+        Ok(Self {
+            freqs,
+            starts,
+            slots,
+        })
+    }
+}
+
+impl From<RansTable> for Vec<u16> {
+    fn from(table: RansTable) -> Self {
+        table.freqs
+    }
+}
+
+/// Runs are coded as a varint of how many symbols there are, then the final state and the
+/// bytes the encoder shifted out, in the order the decoder wants them
+impl EntropyCoder for RansTable {
+    fn encode(&self, symbols: &[u8]) -> Vec<u8> {
+        // rANS decodes in the reverse order to encoding, so encode backwards and then reverse
+        // the output to have the decoder go forwards
+        let mut reversed = Vec::new();
+        let mut state = RANS_LOWER_BOUND;
+        for value in symbols.iter().rev() {
+            let freq = self.freq(*value);
+            assert!(freq > 0, "No rANS frequency for {}", value);
+            let max_state = ((RANS_LOWER_BOUND >> RANS_SCALE_BITS) << 8) * freq;
+            while state >= max_state {
+                reversed.push(state as u8);
+                state >>= 8;
+            }
+            state =
+                ((state / freq) << RANS_SCALE_BITS) + state % freq + self.starts[*value as usize];
+        }
+        reversed.extend(&state.to_le_bytes()[..RANS_STATE_LEN]);
+        let mut output = Vec::new();
+        write_varint(&mut output, symbols.len() as u64).unwrap();
+        output.extend(reversed.iter().rev());
+        output
+    }
+
+    fn decode(&self, encoded: &[u8], max_len: u64) -> Result<Vec<u8>> {
+        let corrupt = || Error::InvalidControlRecord(String::from("Corrupt rANS coded literals"));
+        let (len, rest) = split_varint(encoded).ok_or_else(corrupt)?;
+        // Common values take a fraction of a bit each, so a short run can claim a huge length
+        if len > max_len {
+            return Err(output_limit(max_len));
+        }
+        let (state_bytes, mut rest) = rest
+            .split_first_chunk::<RANS_STATE_LEN>()
+            .ok_or_else(corrupt)?;
+        let mut state = state_bytes
+            .iter()
+            .fold(0, |state, byte| (state << 8) | *byte as u32);
+        // The encoder always leaves it in range, and outside it decoding could overflow
+        if !(RANS_LOWER_BOUND..RANS_LOWER_BOUND << 8).contains(&state) {
+            return Err(corrupt());
+        }
+        // The length comes from the input, so isn't trusted with the allocation
+        let mut output = Vec::with_capacity((len as usize).min(encoded.len() * 8));
+        for _ in 0..len {
+            let slot = state & (RANS_TOTAL - 1);
+            let value = self.slots[slot as usize];
+            output.push(value);
+            state =
+                self.freq(value) * (state >> RANS_SCALE_BITS) + slot - self.starts[value as usize];
+            while state < RANS_LOWER_BOUND {
+                let (byte, remaining) = rest.split_first().ok_or_else(corrupt)?;
+                state = (state << 8) | *byte as u32;
+                rest = remaining;
+            }
+        }
+        match rest.is_empty() && state == RANS_LOWER_BOUND {
+            true => Ok(output),
+            false => Err(corrupt()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::decode::decode_bytes;
+    use crate::entropy::{Entropy, EntropyCoder, HuffmanCoder, RansTable, RANS_TOTAL};
+    use crate::header::Header;
+    use crate::huffman::{build_tree, ByteStats};
+    use crate::{encode, EncodeOptions};
+
+    fn stats(input: &[u8]) -> ByteStats {
+        let mut stats = ByteStats::new();
+        for b in input {
+            *stats.entry(u16::from(*b)).or_insert(0) += 1;
+        }
+        stats
+    }
+
+    #[test]
+    fn round_trip() {
+        let input = "A_DEAD_DAD_CEDED_A_BAD_BABE_A_BEADED_ABACA_BED".repeat(20);
+        let stats = stats(input.as_bytes());
+        let coders: [Box<dyn EntropyCoder>; 2] = [
+            Box::new(HuffmanCoder::new(build_tree(stats.clone()))),
+            Box::new(RansTable::from_stats(&stats)),
+        ];
+        for coder in coders {
+            for len in [0, 1, 2, 100, input.len()] {
+                let encoded = coder.encode(&input.as_bytes()[..len]);
+                assert_eq!(
+                    &input.as_bytes()[..len],
+                    coder.decode(&encoded, len as u64).unwrap()
+                );
+            }
+        }
+        let rans = RansTable::from_stats(&stats);
+        let encoded = rans.encode(input.as_bytes());
+        assert!(rans
+            .decode(&encoded[..encoded.len() - 1], u64::MAX)
+            .is_err());
+        assert!(rans.decode(&encoded, input.len() as u64 - 1).is_err());
+    }
+
+    #[test]
+    fn table() {
+        // One value swamps the rest, which still need a frequency of at least 1
+        let mut input = vec![0u8; 100_000];
+        input.extend(1..=255);
+        let table = RansTable::from_stats(&stats(&input));
+        assert_eq!(
+            RANS_TOTAL,
+            table.freqs.iter().map(|f| *f as u32).sum::<u32>()
+        );
+        assert!(table.freqs.iter().all(|freq| *freq > 0));
+        let encoded = table.encode(&input);
+        assert_eq!(input, table.decode(&encoded, u64::MAX).unwrap());
+
+        assert!(RansTable::try_from(vec![1u16; 256]).is_err());
+        assert!(RansTable::try_from(vec![16u16; 255]).is_err());
+    }
+
+    #[test]
+    fn rans_stream() {
+        let input = "The cat sat on the mat. The dog sat on the log. ".repeat(100);
+        for options in [
+            EncodeOptions::default(),
+            EncodeOptions::default().block_size(Some(1000)),
+        ] {
+            let mut encoded = Vec::new();
+            let options = options.entropy(Entropy::Rans);
+            encode::encode_from_reader(input.as_bytes(), &mut encoded, &options).unwrap();
+            let header = Header::read_from(&encoded[..]).unwrap();
+            assert_eq!(Entropy::Rans, header.entropy());
+            assert!(header.huffman_tree.is_none());
+            assert_eq!(input.as_bytes(), decode_bytes(&encoded).unwrap());
+        }
+    }
+}
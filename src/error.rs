@@ -0,0 +1,152 @@
+use std::fmt::{Display, Formatter};
+use std::path::PathBuf;
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+#[derive(Debug)]
+pub enum Error {
+    Io(std::io::Error),
+    /// The input ended while the decoder was part way through reading something. The str
+    /// describes what it was expecting
+    TruncatedStream(&'static str),
+    CorruptHeader(String),
+    /// A match points outside of the bytes decoded so far. [window] is how many bytes were
+    /// available to match against
+    CorruptStream {
+        offset: u64,
+        len: u64,
+        window: usize,
+    },
+    /// A byte where a token should start doesn't have the prefix of any known token
+    InvalidToken(u8),
+    /// A control record is malformed, or is of a type this version can't skip
+    InvalidControlRecord(String),
+    /// The header is from a newer (or unknown) version of the format
+    UnsupportedVersion(u8),
+    /// Handling this needs a cargo feature this build doesn't have
+    FeatureDisabled(&'static str),
+    /// Decoding gave back different bytes to those that were encoded
+    VerificationFailed {
+        expected: u32,
+        actual: u32,
+    },
+    /// Decoding would go past one of [crate::DecodeOptions]'s limits, naming which, of [max]
+    /// bytes
+    LimitExceeded {
+        limit: &'static str,
+        max: u64,
+    },
+    /// The input is encrypted and couldn't be decrypted, saying why: no passphrase was given,
+    /// it was the wrong one, or the input was damaged or tampered with
+    Decryption(String),
+    /// A signature is missing, malformed, by another key, or doesn't match the file, saying which
+    Signature(String),
+    /// The file to encode or decode doesn't exist
+    InputMissing(PathBuf),
+    /// The file to encode or decode is a symlink, and following them wasn't allowed
+    InputIsSymlink(PathBuf),
+    /// The output file exists, and overwriting it wasn't asked for
+    OutputExists(PathBuf),
+    /// The output file is the input, perhaps under another name or through a link
+    OutputIsInput(PathBuf),
+    /// The file to decode doesn't end with the suffix encoded files are given, so there's no
+    /// telling what to name the output
+    UnknownSuffix {
+        path: PathBuf,
+        suffix: String,
+    },
+    /// The config file or an environment variable ([crate::config]) isn't valid, saying which
+    /// and why
+    Config {
+        origin: String,
+        reason: String,
+    },
+}
+
+impl Display for Error {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Io(e) => write!(f, "IO error: {}", e),
+            Self::TruncatedStream(expecting) => {
+                write!(f, "Input ended unexpectedly while reading {}", expecting)
+            }
+            Self::CorruptHeader(reason) => write!(f, "Corrupt header: {}", reason),
+            Self::CorruptStream {
+                offset,
+                len,
+                window,
+            } => write!(
+                f,
+                "Corrupt stream: match of {} bytes at offset {} is outside the {} byte window",
+                len, offset, window
+            ),
+            Self::InvalidToken(byte) => write!(f, "Invalid token: {:#010b}", byte),
+            Self::InvalidControlRecord(reason) => write!(f, "Invalid control record: {}", reason),
+            Self::UnsupportedVersion(version) => {
+                write!(f, "Unsupported format version: {}", version)
+            }
+            Self::FeatureDisabled(feature) => {
+                write!(
+                    f,
+                    "Needs the {} feature, which this build doesn't have",
+                    feature
+                )
+            }
+            Self::VerificationFailed { expected, actual } => write!(
+                f,
+                "Verification failed: expected checksum {:08x}, decoded {:08x}",
+                expected, actual
+            ),
+            Self::LimitExceeded { limit, max } => {
+                write!(f, "Decoding exceeds the {} limit of {} bytes", limit, max)
+            }
+            Self::Decryption(reason) => write!(f, "Decryption failed: {}", reason),
+            Self::Signature(reason) => write!(f, "Signature check failed: {}", reason),
+            Self::InputMissing(path) => write!(f, "Input file does not exist: {:?}", path),
+            Self::InputIsSymlink(path) => write!(
+                f,
+                "Input file is a symlink, which isn't being followed: {:?}",
+                path
+            ),
+            Self::OutputExists(path) => write!(
+                f,
+                "Output file exists: {:?}. Consider passing overwrite to ignore this",
+                path
+            ),
+            Self::OutputIsInput(path) => write!(
+                f,
+                "Output file is the input file (or a link to it): {:?}",
+                path
+            ),
+            Self::UnknownSuffix { path, suffix } => write!(
+                f,
+                "Input file doesn't end in .{}: {:?}. Consider passing force, or an output file",
+                suffix, path
+            ),
+            Self::Config { origin, reason } => write!(f, "Invalid {}: {}", origin, reason),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+impl From<std::io::Error> for Error {
+    fn from(e: std::io::Error) -> Self {
+        // One of ours passed through a reader or writer comes back out as it was
+        match e.get_ref().is_some_and(|inner| inner.is::<Error>()) {
+            true => *e.into_inner().unwrap().downcast::<Error>().unwrap(),
+            false => Self::Io(e),
+        }
+    }
+}
+
+/// For implementing [std::io::Write] and friends on top of the library. IO errors come back
+/// out as they went in
+impl From<Error> for std::io::Error {
+    fn from(e: Error) -> Self {
+        match e {
+            Error::Io(e) => e,
+            e => std::io::Error::new(std::io::ErrorKind::InvalidData, e),
+        }
+    }
+}
@@ -1,110 +1,997 @@
 use log::info;
-use std::path::PathBuf;
+use serde::{Deserialize, Serialize};
+use std::ffi::{OsStr, OsString};
+use std::fs::{File, Metadata};
+use std::path::{Component, Path, PathBuf, Prefix};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
+use crate::error::{Error, Result};
+use crate::volumes;
+
+/// Extension [FileInputOutputBuilder::suffix] gives encoded files and expects decoded ones to
+/// have, unless it's given another
+pub const DEFAULT_SUFFIX: &str = "lizard";
+
+/// The files one encode or decode reads and writes, from [FileInputOutput::builder]
+#[derive(Clone)]
 pub struct FileInputOutput {
     pub unencoded_filename: PathBuf,
     pub encoded_filename: PathBuf,
     pub debug_encoded_filename: Option<PathBuf>,
+    encoding: bool,
 }
 
-impl FileInputOutput {
-    pub fn new_from_unencoded(
-        unencoded_filename: &str,
-        encoded_filename: Option<&str>,
-        debug: bool,
-    ) -> Self {
-        let unencoded_filename = PathBuf::from(unencoded_filename);
-        let encoded_filename = match encoded_filename {
-            Some(provided) => PathBuf::from(provided),
-            None => unencoded_filename.with_extension("lizard"),
-        };
-        let debug_encoded_filename = match debug {
-            true => Some(unencoded_filename.with_extension("dblzd")),
-            false => None,
+/// The file to encode or decode, saying which
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum InputFile {
+    /// A file to encode
+    Unencoded(PathBuf),
+    /// A lizards file to decode, or the first of its volumes
+    Encoded(PathBuf),
+}
+
+impl InputFile {
+    pub fn path(&self) -> &Path {
+        match self {
+            Self::Unencoded(path) | Self::Encoded(path) => path,
+        }
+    }
+}
+
+/// Where to write the output
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub enum OutputTarget {
+    /// Next to the input, named after it
+    #[default]
+    Auto,
+    /// This file (or directory, when extracting an archive)
+    Path(PathBuf),
+    /// In this directory, named as with [OutputTarget::Auto]
+    Dir(PathBuf),
+}
+
+impl OutputTarget {
+    /// From an optional output argument: [OutputTarget::Dir] if it's an existing directory,
+    /// otherwise [OutputTarget::Path], or [OutputTarget::Auto] if there's none
+    pub fn from_arg<P: AsRef<Path>>(arg: Option<P>) -> Self {
+        match arg.map(|arg| arg.as_ref().to_path_buf()) {
+            Some(path) if path.is_dir() => Self::Dir(path),
+            Some(path) => Self::Path(path),
+            None => Self::Auto,
+        }
+    }
+}
+
+/// What to do when the output already exists
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum OverwritePolicy {
+    /// Fail with [Error::OutputExists]
+    #[default]
+    Never,
+    /// Replace it
+    Always,
+    /// Write to the output's name with `.1`, `.2` and so on appended instead, the first that's
+    /// free (along with its volumes)
+    Rename,
+}
+
+/// Works out where a [FileInputOutput]'s output goes, and checks it can be written
+#[derive(Debug, Clone)]
+pub struct FileInputOutputBuilder {
+    input: InputFile,
+    output: OutputTarget,
+    overwrite: OverwritePolicy,
+    debug: bool,
+    split: bool,
+    follow_symlinks: bool,
+    suffix: OsString,
+    force: bool,
+    original_name: Option<OsString>,
+}
+
+/// Attributes of the original file, recorded so they can be restored on decode
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct FileMetadata {
+    /// Modification time as (seconds, nanoseconds) since the unix epoch
+    pub modified: Option<(u64, u32)>,
+    /// Unix permission bits
+    pub mode: Option<u32>,
+    /// The original file's name, without its directory, so decoding can restore it
+    #[serde(default)]
+    pub name: Option<String>,
+    /// The original file's name when it isn't valid UTF-8, in place of [Self::name]
+    #[serde(default)]
+    pub raw_name: Option<RawName>,
+}
+
+/// A file name as the platform gave it, for names that aren't valid UTF-8
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RawName {
+    pub encoding: NameEncoding,
+    pub bytes: Vec<u8>,
+}
+
+/// How a [RawName]'s bytes encode it
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum NameEncoding {
+    /// Arbitrary bytes, as unix file names are
+    Unix,
+    /// UTF-16 code units, little-endian, not necessarily well formed, as Windows file names are
+    Windows,
+}
+
+impl RawName {
+    pub fn from_os_str(name: &OsStr) -> Option<Self> {
+        #[cfg(unix)]
+        {
+            use std::os::unix::ffi::OsStrExt;
+            Some(Self {
+                encoding: NameEncoding::Unix,
+                bytes: name.as_bytes().to_vec(),
+            })
+        }
+        #[cfg(windows)]
+        {
+            use std::os::windows::ffi::OsStrExt;
+            Some(Self {
+                encoding: NameEncoding::Windows,
+                bytes: name.encode_wide().flat_map(u16::to_le_bytes).collect(),
+            })
+        }
+        #[cfg(not(any(unix, windows)))]
+        {
+            let _ = name;
+            None
+        }
+    }
+
+    /// The name as this platform has it. A name from another platform is converted lossily,
+    /// with anything that doesn't map replaced by U+FFFD
+    pub fn to_os_string(&self) -> OsString {
+        match self.encoding {
+            #[cfg(unix)]
+            NameEncoding::Unix => {
+                use std::os::unix::ffi::OsStringExt;
+                OsString::from_vec(self.bytes.clone())
+            }
+            #[cfg(windows)]
+            NameEncoding::Windows => {
+                use std::os::windows::ffi::OsStringExt;
+                OsString::from_wide(&self.utf16())
+            }
+            #[allow(unreachable_patterns)]
+            NameEncoding::Unix => String::from_utf8_lossy(&self.bytes).into_owned().into(),
+            #[allow(unreachable_patterns)]
+            NameEncoding::Windows => String::from_utf16_lossy(&self.utf16()).into(),
+        }
+    }
+
+    fn utf16(&self) -> Vec<u16> {
+        self.bytes
+            .chunks(2)
+            .map(|pair| u16::from_le_bytes([pair[0], *pair.get(1).unwrap_or(&0)]))
+            .collect()
+    }
+}
+
+impl FileMetadata {
+    pub fn from_fs(metadata: &Metadata) -> Self {
+        let modified = metadata
+            .modified()
+            .ok()
+            .and_then(|time| time.duration_since(UNIX_EPOCH).ok())
+            .map(|since_epoch| (since_epoch.as_secs(), since_epoch.subsec_nanos()));
+        #[cfg(unix)]
+        let mode = {
+            use std::os::unix::fs::PermissionsExt;
+            Some(metadata.permissions().mode())
         };
-        FileInputOutput {
-            unencoded_filename,
-            encoded_filename,
-            debug_encoded_filename,
+        #[cfg(not(unix))]
+        let mode = None;
+        Self {
+            modified,
+            mode,
+            name: None,
+            raw_name: None,
         }
     }
-    pub fn new_from_encoded(encoded_filename: &str, unencoded_filename: Option<&str>) -> Self {
-        let encoded_filename = PathBuf::from(encoded_filename);
-        let unencoded_filename = match unencoded_filename {
-            Some(provided) => PathBuf::from(provided),
-            None => encoded_filename.with_extension("txt"),
+
+    /// Record the name of the file at [path], as a [RawName] if it isn't valid UTF-8
+    pub fn with_name(mut self, path: impl AsRef<Path>) -> Self {
+        let name = path.as_ref().file_name();
+        self.name = name.and_then(OsStr::to_str).map(String::from);
+        self.raw_name = match self.name {
+            Some(_) => None,
+            None => name.and_then(RawName::from_os_str),
         };
-        FileInputOutput {
-            unencoded_filename,
-            encoded_filename,
-            debug_encoded_filename: None,
+        self
+    }
+
+    /// The recorded name, if it's a plain file name. It comes from the input, so anything that
+    /// could point outside the output directory, e.g. "../x" or "/etc/x", is ignored
+    pub fn safe_name(&self) -> Option<OsString> {
+        let name = match (&self.name, &self.raw_name) {
+            (Some(name), _) => OsString::from(name),
+            (None, Some(raw_name)) => raw_name.to_os_string(),
+            (None, None) => return None,
+        };
+        let mut components = Path::new(&name).components();
+        let separator = name
+            .as_encoded_bytes()
+            .iter()
+            .any(|b| matches!(b, b'/' | b'\\'));
+        match (components.next(), components.next()) {
+            (Some(Component::Normal(_)), None) if !separator => Some(name),
+            _ => None,
+        }
+    }
+
+    /// Set the recorded attributes on the file at [path]. Anything not recorded (or not
+    /// supported on this platform) is left alone
+    pub fn apply(&self, path: &Path) -> std::io::Result<()> {
+        if let Some((secs, nanos)) = self.modified {
+            let file = File::options().write(true).open(path)?;
+            file.set_modified(UNIX_EPOCH + Duration::new(secs, nanos))?;
+        }
+        // Permissions last, as they might stop us writing to the file
+        #[cfg(unix)]
+        if let Some(mode) = self.mode {
+            use std::os::unix::fs::PermissionsExt;
+            std::fs::set_permissions(path, std::fs::Permissions::from_mode(mode))?;
         }
+        Ok(())
     }
 
-    pub fn input_is_valid(&self, is_encode: bool) -> Result<(), String> {
-        let input_file_path = match is_encode {
-            true => self.unencoded_filename.as_path(),
-            false => self.encoded_filename.as_path(),
+    pub fn modified_time(&self) -> Option<SystemTime> {
+        self.modified
+            .map(|(secs, nanos)| UNIX_EPOCH + Duration::new(secs, nanos))
+    }
+}
+
+/// [path] with the first of `.1`, `.2`... appended that neither exists nor has volumes
+fn renamed(path: &Path) -> PathBuf {
+    (1..)
+        .map(|n| {
+            let mut renamed = path.as_os_str().to_owned();
+            renamed.push(format!(".{}", n));
+            PathBuf::from(renamed)
+        })
+        .find(|renamed| !renamed.exists() && !volumes::volume_path(renamed, 1).exists())
+        .unwrap()
+}
+
+/// Whether [a] and [b] are the same existing file, however they're named: through symlinks, hard
+/// links, or different but equivalent paths, e.g. "./x" and "x"
+pub fn same_file(a: &Path, b: &Path) -> bool {
+    let (Ok(a_metadata), Ok(b_metadata)) = (std::fs::metadata(a), std::fs::metadata(b)) else {
+        return false;
+    };
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::MetadataExt;
+        a_metadata.dev() == b_metadata.dev() && a_metadata.ino() == b_metadata.ino()
+    }
+    #[cfg(not(unix))]
+    {
+        // Hard links can't be told apart from copies without platform specific calls here
+        let _ = (a_metadata, b_metadata);
+        matches!(
+            (std::fs::canonicalize(a), std::fs::canonicalize(b)),
+            (Ok(a), Ok(b)) if a == b
+        )
+    }
+}
+
+/// [path] as a Windows verbatim path (`\\?\C:\...`), which isn't limited to 260 characters.
+/// Anything other than an absolute Windows path with a drive or server (so anywhere but
+/// Windows) is left as it is, as are paths with `.` or `..` in, which verbatim paths don't
+/// resolve
+pub fn verbatim(path: &Path) -> PathBuf {
+    let mut components = path.components();
+    let (Some(Component::Prefix(prefix)), Some(Component::RootDir)) =
+        (components.next(), components.next())
+    else {
+        return path.to_path_buf();
+    };
+    if path
+        .components()
+        .any(|component| matches!(component, Component::CurDir | Component::ParentDir))
+    {
+        return path.to_path_buf();
+    }
+    let mut verbatim = match prefix.kind() {
+        Prefix::Disk(disk) => OsString::from(format!("\\\\?\\{}:\\", disk as char)),
+        Prefix::UNC(server, share) => {
+            let mut verbatim = OsString::from("\\\\?\\UNC\\");
+            verbatim.push(server);
+            verbatim.push("\\");
+            verbatim.push(share);
+            verbatim.push("\\");
+            verbatim
+        }
+        _ => return path.to_path_buf(),
+    };
+    for (i, component) in components.enumerate() {
+        if i > 0 {
+            verbatim.push("\\");
+        }
+        verbatim.push(component);
+    }
+    PathBuf::from(verbatim)
+}
+
+/// [pattern] without any Windows verbatim prefix, which glob doesn't understand, and whether
+/// it had one, in which case the matches should be made verbatim again with [verbatim]
+fn strip_verbatim(pattern: &str) -> (String, bool) {
+    if let Some(rest) = pattern.strip_prefix("\\\\?\\UNC\\") {
+        (format!(r"\\{}", rest), true)
+    } else if let Some(rest) = pattern.strip_prefix("\\\\?\\") {
+        (String::from(rest), true)
+    } else {
+        (String::from(pattern), false)
+    }
+}
+
+/// Expand each of [patterns] into the files matching it, leaving out any matching one of the
+/// [exclude] patterns. The result is sorted and has no duplicates. On Windows patterns can be
+/// verbatim paths (`\\?\C:\...`), as long ones need to be, giving verbatim matches
+pub fn expand_globs(
+    patterns: &[String],
+    exclude: &[String],
+) -> std::result::Result<Vec<PathBuf>, String> {
+    let exclude = exclude
+        .iter()
+        .map(|pattern| glob::Pattern::new(pattern))
+        .collect::<std::result::Result<Vec<_>, _>>()
+        .map_err(|e| format!("Invalid exclude pattern: {}", e))?;
+    let mut paths = Vec::new();
+    for pattern in patterns {
+        let (pattern, was_verbatim) = match cfg!(windows) {
+            true => strip_verbatim(pattern),
+            false => (pattern.clone(), false),
         };
-        match input_file_path.exists() {
-            true => Ok(()),
-            false => Err(format!("Input file does not exist: {:?}", input_file_path)),
+        let matches = glob::glob(&pattern).map_err(|e| format!("Invalid glob pattern: {}", e))?;
+        for path in matches {
+            let path = path.map_err(|e| format!("Error expanding glob: {}", e))?;
+            let excluded = exclude.iter().any(|pattern| pattern.matches_path(&path));
+            if path.is_file() && !excluded {
+                paths.push(match was_verbatim {
+                    true => verbatim(&path),
+                    false => path,
+                });
+            }
+        }
+    }
+    paths.sort();
+    paths.dedup();
+    Ok(paths)
+}
+
+/// [path] without [suffix] (e.g. "lizard" or "tar.lizard") on the end of its name, if it has it
+/// and there's more of the name than that
+fn strip_suffix(path: &Path, suffix: &OsStr) -> Option<PathBuf> {
+    let mut stripped = path.to_path_buf();
+    for part in suffix.to_string_lossy().rsplit('.') {
+        if stripped.extension()? != part {
+            return None;
+        }
+        stripped.set_extension("");
+    }
+    Some(stripped)
+}
+
+impl FileInputOutput {
+    pub fn builder(input: InputFile) -> FileInputOutputBuilder {
+        FileInputOutputBuilder {
+            input,
+            output: OutputTarget::Auto,
+            overwrite: OverwritePolicy::Never,
+            debug: false,
+            split: false,
+            follow_symlinks: true,
+            suffix: OsString::from(DEFAULT_SUFFIX),
+            force: false,
+            original_name: None,
+        }
+    }
+
+    pub fn input_path(&self) -> &Path {
+        match self.encoding {
+            true => &self.unencoded_filename,
+            false => &self.encoded_filename,
+        }
+    }
+
+    pub fn output_path(&self) -> &Path {
+        match self.encoding {
+            true => &self.encoded_filename,
+            false => &self.unencoded_filename,
+        }
+    }
+
+    /// Sync the output to disk, so it survives a crash or power cut once this returns. On unix
+    /// the directory it's in is synced too, as otherwise a new file's entry in it can still be
+    /// lost. The output may be split into volumes
+    pub fn sync_output(&self) -> std::io::Result<()> {
+        for output_path in volumes::input_paths(self.output_path()) {
+            File::open(output_path)?.sync_all()?;
+        }
+        #[cfg(unix)]
+        {
+            let dir = match self.output_path().parent() {
+                Some(dir) if !dir.as_os_str().is_empty() => dir,
+                _ => Path::new("."),
+            };
+            File::open(dir)?.sync_all()?;
+        }
+        Ok(())
+    }
+
+    /// Delete the input file, for use once it has been successfully encoded/decoded. The output
+    /// is synced to disk first (see [Self::sync_output]) so the data isn't lost if we crash
+    /// right after. Either may be split into volumes
+    pub fn remove_input(&self) -> std::io::Result<()> {
+        self.sync_output()?;
+        for input_path in volumes::input_paths(self.input_path()) {
+            info!("Removing input file {:?}", input_path);
+            std::fs::remove_file(input_path)?;
+        }
+        Ok(())
+    }
+}
+
+impl FileInputOutputBuilder {
+    pub fn output(mut self, output: OutputTarget) -> Self {
+        self.output = output;
+        self
+    }
+
+    pub fn overwrite(mut self, overwrite: OverwritePolicy) -> Self {
+        self.overwrite = overwrite;
+        self
+    }
+
+    /// When encoding, also write a [crate::trace] of the encoded tokens next to the input
+    /// (`file.dblzd`)
+    pub fn debug(mut self, debug: bool) -> Self {
+        self.debug = debug;
+        self
+    }
+
+    /// Whether the input may be a symlink, read as the file it points to (the default).
+    /// Otherwise [Self::build] fails with [Error::InputIsSymlink] if it is one
+    pub fn follow_symlinks(mut self, follow_symlinks: bool) -> Self {
+        self.follow_symlinks = follow_symlinks;
+        self
+    }
+
+    /// The output will be written in volumes (see [volumes]), so it's the first volume that
+    /// mustn't already exist
+    pub fn split(mut self, split: bool) -> Self {
+        self.split = split;
+        self
+    }
+
+    /// The extension encoding appends to the input's name to name the output, and decoding
+    /// expects the input's name to end with and strips from it, as gzip does: `file.txt` encodes
+    /// to `file.txt.lizard` by default, which decodes to `file.txt`. Without the leading dot, and
+    /// it can have more than one part, e.g. "tar.lizard"
+    pub fn suffix(mut self, suffix: impl AsRef<OsStr>) -> Self {
+        self.suffix = suffix.as_ref().to_owned();
+        self
+    }
+
+    /// When decoding, take an input whose name doesn't end with the [Self::suffix], naming the
+    /// output after it with `.out` appended, unless there's an [Self::original_name]. Otherwise
+    /// [Self::build] refuses one with [Error::UnknownSuffix], unless the output's path is given
+    pub fn force(mut self, force: bool) -> Self {
+        self.force = force;
+        self
+    }
+
+    /// When decoding, name the output [name] (next to the input, or in [OutputTarget::Dir])
+    /// rather than guessing from the input's name. [name] should be one from
+    /// [FileMetadata::safe_name]
+    pub fn original_name(mut self, name: impl AsRef<OsStr>) -> Self {
+        self.original_name = Some(name.as_ref().to_owned());
+        self
+    }
+
+    /// Where the output will go, though it's not checked until [Self::build]
+    pub fn output_path(&self) -> PathBuf {
+        let derived = self.derived_output();
+        match &self.output {
+            OutputTarget::Auto => derived,
+            OutputTarget::Path(path) => path.clone(),
+            OutputTarget::Dir(dir) => match derived.file_name() {
+                Some(name) => dir.join(name),
+                None => dir.clone(),
+            },
         }
     }
-    pub fn output_is_valid(&self, is_encode: bool, overwrite: bool) -> Result<(), &str> {
-        let output_file_path = match is_encode {
-            true => self.encoded_filename.as_path(),
-            false => self.unencoded_filename.as_path(),
+
+    fn derived_output(&self) -> PathBuf {
+        match (&self.input, &self.original_name) {
+            (InputFile::Unencoded(path), _) => {
+                let mut path = path.as_os_str().to_owned();
+                path.push(".");
+                path.push(&self.suffix);
+                PathBuf::from(path)
+            }
+            (InputFile::Encoded(path), Some(name)) => path.with_file_name(name),
+            (InputFile::Encoded(path), None) => {
+                let base = volumes::strip_first_volume(path).unwrap_or_else(|| path.clone());
+                // Anything else is only decoded with force, and named as bzip2 would
+                strip_suffix(&base, &self.suffix).unwrap_or_else(|| {
+                    let mut path = base.into_os_string();
+                    path.push(".out");
+                    PathBuf::from(path)
+                })
+            }
+        }
+    }
+
+    /// Checks the input exists and the output can be written without losing anything: it's
+    /// not the input however it's named, even with [OverwritePolicy::Always], as writing it
+    /// would truncate the input before it's read, and it doesn't exist unless overwriting. An
+    /// existing directory is left to be written into, e.g. when extracting an archive
+    pub fn build(&self) -> Result<FileInputOutput> {
+        let input_path = self.input.path();
+        let encoding = matches!(self.input, InputFile::Unencoded(_));
+        let volumes = !encoding && volumes::volumes_base(input_path).is_some();
+        if !input_path.exists() && !volumes {
+            return Err(Error::InputMissing(input_path.to_path_buf()));
+        }
+        if !self.follow_symlinks && input_path.is_symlink() {
+            return Err(Error::InputIsSymlink(input_path.to_path_buf()));
+        }
+        let base = volumes::strip_first_volume(input_path).unwrap_or_else(|| input_path.into());
+        let named = self.force || matches!(self.output, OutputTarget::Path(_));
+        if !encoding && !named && strip_suffix(&base, &self.suffix).is_none() {
+            return Err(Error::UnknownSuffix {
+                path: input_path.to_path_buf(),
+                suffix: self.suffix.to_string_lossy().into_owned(),
+            });
+        }
+
+        let output_path = self.output_path();
+        let debug_encoded_filename = match encoding && self.debug {
+            true => Some(input_path.with_extension("dblzd")),
+            false => None,
         };
-        match (output_file_path.exists(), overwrite) {
-            (true, true) => {
-                info!("Output file exists, but overwriting");
-                Ok(())
+        // Compressing with --split-size writes to the output's volumes
+        let mut output_paths = volumes::existing_volumes(&output_path);
+        output_paths.push(output_path.clone());
+        output_paths.extend(debug_encoded_filename.clone());
+        for input_path in volumes::input_paths(input_path) {
+            if output_paths
+                .iter()
+                .any(|output| same_file(&input_path, output))
+            {
+                return Err(Error::OutputIsInput(output_path));
             }
-            (false, _) => Ok(()),
-            (true, false) => Err("Output file exists. Consider passing overwrite to ignore this"),
         }
+        let written = match self.split {
+            true => volumes::volume_path(&output_path, 1),
+            false => output_path.clone(),
+        };
+        let output_path = match written.exists() && !written.is_dir() {
+            true => match self.overwrite {
+                OverwritePolicy::Never => return Err(Error::OutputExists(written)),
+                OverwritePolicy::Always => {
+                    info!("Output file exists, but overwriting");
+                    output_path
+                }
+                OverwritePolicy::Rename => renamed(&output_path),
+            },
+            false => output_path,
+        };
+
+        let (unencoded_filename, encoded_filename) = match encoding {
+            true => (input_path.to_path_buf(), output_path),
+            false => (output_path, input_path.to_path_buf()),
+        };
+        Ok(FileInputOutput {
+            unencoded_filename,
+            encoded_filename,
+            debug_encoded_filename,
+            encoding,
+        })
     }
 }
 
+#[cfg(test)]
 mod test {
-    use crate::file_io::FileInputOutput;
-    use std::path::PathBuf;
+    use crate::error::Error;
+    use crate::file_io::{
+        expand_globs, same_file, strip_verbatim, verbatim, FileInputOutput, FileMetadata,
+        InputFile, NameEncoding, OutputTarget, OverwritePolicy, RawName,
+    };
+    use crate::test_util::temp_path;
+    use std::path::{Path, PathBuf};
 
     #[test]
     fn test_encoding() {
-        let encoding_io = FileInputOutput::new_from_unencoded("file.txt", None, true);
-        assert_eq!(encoding_io.unencoded_filename, PathBuf::from("file.txt"));
+        let builder = FileInputOutput::builder(InputFile::Unencoded(PathBuf::from("file.txt")));
+        assert_eq!(builder.output_path(), PathBuf::from("file.txt.lizard"));
+        let builder = builder.output(OutputTarget::Path(PathBuf::from("custom_output.foo")));
+        assert_eq!(builder.output_path(), PathBuf::from("custom_output.foo"));
+
+        let builder = FileInputOutput::builder(InputFile::Unencoded(PathBuf::from("dir")))
+            .suffix("tar.lizard");
+        assert_eq!(builder.output_path(), PathBuf::from("dir.tar.lizard"));
+        let builder =
+            FileInputOutput::builder(InputFile::Unencoded(PathBuf::from("a.b.txt"))).suffix("gz");
+        assert_eq!(builder.output_path(), PathBuf::from("a.b.txt.gz"));
+    }
+
+    #[test]
+    fn test_decoding() {
+        let builder =
+            FileInputOutput::builder(InputFile::Encoded(PathBuf::from("file.txt.lizard")));
+        assert_eq!(builder.output_path(), PathBuf::from("file.txt"));
+        let builder = builder.output(OutputTarget::Path(PathBuf::from("my_unencoded_file.log")));
         assert_eq!(
-            encoding_io.debug_encoded_filename,
-            Some(PathBuf::from("file.dblzd"))
+            builder.output_path(),
+            PathBuf::from("my_unencoded_file.log")
         );
-        assert_eq!(encoding_io.encoded_filename, PathBuf::from("file.lizard"));
 
-        let encoding_io =
-            FileInputOutput::new_from_unencoded("file.txt", Some("custom_output.foo"), false);
-        assert_eq!(encoding_io.unencoded_filename, PathBuf::from("file.txt"));
-        assert_eq!(encoding_io.debug_encoded_filename, None);
+        let builder =
+            FileInputOutput::builder(InputFile::Encoded(PathBuf::from("backup.tar.lizard")));
+        assert_eq!(builder.output_path(), PathBuf::from("backup.tar"));
+        let builder = FileInputOutput::builder(InputFile::Encoded(PathBuf::from("out.lizard.001")));
+        assert_eq!(builder.output_path(), PathBuf::from("out"));
+
+        // Another suffix, which is all that's stripped
+        let builder = FileInputOutput::builder(InputFile::Encoded(PathBuf::from("a.b.lz")));
         assert_eq!(
-            encoding_io.encoded_filename,
-            PathBuf::from("custom_output.foo")
+            builder.clone().suffix("lz").output_path(),
+            PathBuf::from("a.b")
         );
+        assert_eq!(builder.suffix("b.lz").output_path(), PathBuf::from("a"));
+        // Without it, the output is only named if forced to
+        let builder = FileInputOutput::builder(InputFile::Encoded(PathBuf::from("file.bin")));
+        assert_eq!(builder.output_path(), PathBuf::from("file.bin.out"));
+        let builder = FileInputOutput::builder(InputFile::Encoded(PathBuf::from(".lizard")));
+        assert_eq!(builder.output_path(), PathBuf::from(".lizard.out"));
     }
 
     #[test]
-    fn test_decoding() {
-        let decoding_io = FileInputOutput::new_from_encoded("file.lizard", None);
-        assert_eq!(decoding_io.encoded_filename, PathBuf::from("file.lizard"));
-        assert_eq!(decoding_io.debug_encoded_filename, None);
-        assert_eq!(decoding_io.unencoded_filename, PathBuf::from("file.txt"));
-
-        let decoding_io =
-            FileInputOutput::new_from_encoded("file.lizard", Some("my_unencoded_file.log"));
-        assert_eq!(decoding_io.encoded_filename, PathBuf::from("file.lizard"));
-        assert_eq!(decoding_io.debug_encoded_filename, None);
+    fn unknown_suffix() {
+        let dir = temp_path("suffix");
+        std::fs::create_dir_all(&dir).unwrap();
+        let input = dir.join("file.bin");
+        std::fs::write(&input, b"input").unwrap();
+        let builder = FileInputOutput::builder(InputFile::Encoded(input.clone()));
+        assert!(matches!(
+            builder.build(),
+            Err(Error::UnknownSuffix { path, suffix }) if path == input && suffix == "lizard"
+        ));
+        let file_io = builder.clone().force(true).build().unwrap();
+        assert_eq!(file_io.output_path(), dir.join("file.bin.out"));
+        // A given output needs no name working out
+        let output = dir.join("file");
+        let file_io = builder
+            .output(OutputTarget::Path(output.clone()))
+            .build()
+            .unwrap();
+        assert_eq!(file_io.output_path(), output);
+        let builder = FileInputOutput::builder(InputFile::Encoded(input)).suffix("bin");
+        assert_eq!(builder.build().unwrap().output_path(), dir.join("file"));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn build() {
+        let dir = temp_path("build");
+        std::fs::create_dir_all(&dir).unwrap();
+        let input = dir.join("file.txt");
+        let missing = FileInputOutput::builder(InputFile::Unencoded(input.clone())).build();
+        assert!(matches!(missing, Err(Error::InputMissing(path)) if path == input));
+        std::fs::write(&input, b"input").unwrap();
+
+        let file_io = FileInputOutput::builder(InputFile::Unencoded(input.clone()))
+            .debug(true)
+            .build()
+            .unwrap();
+        assert_eq!(file_io.unencoded_filename, input);
+        assert_eq!(file_io.encoded_filename, dir.join("file.txt.lizard"));
+        assert_eq!(file_io.debug_encoded_filename, Some(dir.join("file.dblzd")));
+        assert_eq!(file_io.input_path(), input);
+        #[cfg(unix)]
+        {
+            let link = dir.join("link.txt");
+            std::os::unix::fs::symlink(&input, &link).unwrap();
+            let builder = FileInputOutput::builder(InputFile::Unencoded(link.clone()));
+            assert!(builder.build().is_ok());
+            let rejected = builder.follow_symlinks(false).build();
+            assert!(matches!(rejected, Err(Error::InputIsSymlink(path)) if path == link));
+        }
+
+        let output = dir.join("file.lizard");
+        std::fs::write(&output, b"output").unwrap();
+        let builder = FileInputOutput::builder(InputFile::Encoded(output.clone()))
+            .output(OutputTarget::Path(input.clone()));
+        assert!(matches!(builder.build(), Err(Error::OutputExists(path)) if path == input));
+        let file_io = builder
+            .clone()
+            .overwrite(OverwritePolicy::Always)
+            .build()
+            .unwrap();
+        assert_eq!(file_io.encoded_filename, output);
+        assert_eq!(file_io.output_path(), input);
+
+        let builder = builder.overwrite(OverwritePolicy::Rename);
         assert_eq!(
-            decoding_io.unencoded_filename,
-            PathBuf::from("my_unencoded_file.log")
+            builder.build().unwrap().output_path(),
+            dir.join("file.txt.1")
+        );
+        std::fs::write(dir.join("file.txt.1"), b"").unwrap();
+        std::fs::write(dir.join("file.txt.2.001"), b"").unwrap();
+        assert_eq!(
+            builder.build().unwrap().output_path(),
+            dir.join("file.txt.3")
+        );
+
+        // Split output conflicts with its first volume
+        let builder = builder.output(OutputTarget::Path(dir.join("file.txt.2")));
+        assert_eq!(
+            builder.build().unwrap().output_path(),
+            dir.join("file.txt.2")
+        );
+        let builder = builder.split(true);
+        assert_eq!(
+            builder.build().unwrap().output_path(),
+            dir.join("file.txt.2.1")
+        );
+        let split = builder.overwrite(OverwritePolicy::Never).build();
+        assert!(
+            matches!(split, Err(Error::OutputExists(path)) if path == dir.join("file.txt.2.001"))
+        );
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn decoding_restores_name() {
+        let metadata = FileMetadata::default().with_name(Path::new("/elsewhere/data.v1.json"));
+        let builder = FileInputOutput::builder(InputFile::Encoded(PathBuf::from("dir/x.lizard")))
+            .original_name(metadata.safe_name().unwrap());
+        assert_eq!(builder.output_path(), PathBuf::from("dir/data.v1.json"));
+        // A given output file wins
+        let builder = builder.output(OutputTarget::Path(PathBuf::from("out")));
+        assert_eq!(builder.output_path(), PathBuf::from("out"));
+
+        // Names from the header can't escape the output directory
+        for name in ["../x", "/etc/x", "a/b", "a\\b", "..", ".", ""] {
+            let metadata = FileMetadata {
+                name: Some(String::from(name)),
+                ..FileMetadata::default()
+            };
+            assert_eq!(None, metadata.safe_name(), "{}", name);
+            let metadata = FileMetadata {
+                raw_name: Some(RawName {
+                    encoding: NameEncoding::Unix,
+                    bytes: name.as_bytes().to_vec(),
+                }),
+                ..FileMetadata::default()
+            };
+            assert_eq!(None, metadata.safe_name(), "{}", name);
+        }
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn non_utf8_names() {
+        use std::ffi::OsStr;
+        use std::os::unix::ffi::OsStrExt;
+        let dir = temp_path("non_utf8");
+        std::fs::create_dir_all(&dir).unwrap();
+        let input = dir.join(OsStr::from_bytes(b"caf\xe9.txt"));
+        std::fs::write(&input, b"input").unwrap();
+        let file_io = FileInputOutput::builder(InputFile::Unencoded(input.clone()))
+            .build()
+            .unwrap();
+        assert_eq!(
+            file_io.encoded_filename,
+            dir.join(OsStr::from_bytes(b"caf\xe9.txt.lizard"))
         );
+
+        // The name survives the header as bytes, and decoding restores it
+        let metadata = FileMetadata::default().with_name(&input);
+        assert_eq!(metadata.name, None);
+        let metadata: FileMetadata =
+            rmp_serde::from_slice(&rmp_serde::to_vec(&metadata).unwrap()).unwrap();
+        let name = metadata.safe_name().unwrap();
+        assert_eq!(name, OsStr::from_bytes(b"caf\xe9.txt"));
+        let builder = FileInputOutput::builder(InputFile::Encoded(file_io.encoded_filename))
+            .original_name(name);
+        assert_eq!(builder.output_path(), input);
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        // A name from Windows is converted as best it can be, here with an unpaired surrogate
+        let windows = RawName {
+            encoding: NameEncoding::Windows,
+            bytes: [0x63, 0xe9, 0xd800]
+                .into_iter()
+                .flat_map(u16::to_le_bytes)
+                .collect(),
+        };
+        assert_eq!(windows.to_os_string(), "c\u{e9}\u{fffd}");
+    }
+
+    #[test]
+    fn verbatim_paths() {
+        assert_eq!(
+            strip_verbatim(r"\\?\C:\logs\**\*.log"),
+            (String::from(r"C:\logs\**\*.log"), true)
+        );
+        assert_eq!(
+            strip_verbatim(r"\\?\UNC\server\share\*.log"),
+            (String::from(r"\\server\share\*.log"), true)
+        );
+        assert_eq!(
+            strip_verbatim("logs/*.log"),
+            (String::from("logs/*.log"), false)
+        );
+        // Only absolute Windows paths have a verbatim form
+        for path in ["/tmp/x", "x/y", "../x"] {
+            assert_eq!(verbatim(Path::new(path)), PathBuf::from(path));
+        }
+        #[cfg(windows)]
+        {
+            assert_eq!(
+                verbatim(Path::new(r"C:\a\b.txt")),
+                PathBuf::from(r"\\?\C:\a\b.txt")
+            );
+            assert_eq!(
+                verbatim(Path::new(r"\\server\share\a")),
+                PathBuf::from(r"\\?\UNC\server\share\a")
+            );
+            assert_eq!(
+                verbatim(Path::new(r"C:\a\..\b")),
+                PathBuf::from(r"C:\a\..\b")
+            );
+        }
+    }
+
+    #[test]
+    fn metadata_round_trip() {
+        let path = temp_path("metadata");
+        std::fs::write(&path, b"some data").unwrap();
+        let metadata = FileMetadata {
+            modified: Some((1000000000, 500)),
+            mode: if cfg!(unix) { Some(0o100600) } else { None },
+            name: None,
+            raw_name: None,
+        };
+        metadata.apply(&path).unwrap();
+
+        let read_back = FileMetadata::from_fs(&std::fs::metadata(&path).unwrap());
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(metadata, read_back);
+    }
+
+    #[test]
+    fn remove_input() {
+        let input = temp_path("remove_input").with_extension("txt");
+        let output = input.with_extension("txt.lizard");
+        std::fs::write(&input, b"input").unwrap();
+        std::fs::write(&output, b"output").unwrap();
+
+        let encoding_io = FileInputOutput::builder(InputFile::Unencoded(input.clone()))
+            .overwrite(OverwritePolicy::Always)
+            .build()
+            .unwrap();
+        encoding_io.remove_input().unwrap();
+        assert!(!input.exists());
+        assert!(output.exists());
+        std::fs::remove_file(&output).unwrap();
+    }
+
+    #[test]
+    fn output_to_dir() {
+        let dir = temp_path("output_dir");
+        std::fs::create_dir_all(&dir).unwrap();
+        let output = OutputTarget::from_arg(Some(&dir));
+        assert_eq!(output, OutputTarget::Dir(dir.clone()));
+
+        let encoding = FileInputOutput::builder(InputFile::Unencoded(PathBuf::from("a/file.txt")))
+            .output(output.clone());
+        assert_eq!(encoding.output_path(), dir.join("file.txt.lizard"));
+        let decoding =
+            FileInputOutput::builder(InputFile::Encoded(PathBuf::from("a/file.tar.lizard")))
+                .output(output.clone());
+        assert_eq!(decoding.output_path(), dir.join("file.tar"));
+        let decoding = decoding.original_name("notes.md");
+        assert_eq!(decoding.output_path(), dir.join("notes.md"));
+
+        // Only an existing directory is treated as one
+        let missing = dir.join("missing");
+        let output = OutputTarget::from_arg(Some(&missing));
+        assert_eq!(output, OutputTarget::Path(missing));
+        assert_eq!(OutputTarget::from_arg(None::<&Path>), OutputTarget::Auto);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn sync_output() {
+        let dir = temp_path("sync");
+        std::fs::create_dir_all(&dir).unwrap();
+        let input = dir.join("input.txt");
+        std::fs::write(&input, b"input").unwrap();
+        let file_input_output = FileInputOutput::builder(InputFile::Unencoded(input))
+            .build()
+            .unwrap();
+        // Nothing's been written yet
+        assert!(file_input_output.sync_output().is_err());
+        std::fs::write(file_input_output.output_path(), b"output").unwrap();
+        file_input_output.sync_output().unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn output_is_input() {
+        let dir = temp_path("same_file");
+        std::fs::create_dir_all(&dir).unwrap();
+        let input = dir.join("input.txt");
+        std::fs::write(&input, b"input").unwrap();
+        let encode_to = |output: &Path| {
+            FileInputOutput::builder(InputFile::Unencoded(input.clone()))
+                .output(OutputTarget::Path(output.to_path_buf()))
+                .overwrite(OverwritePolicy::Always)
+                .build()
+        };
+
+        assert!(matches!(encode_to(&input), Err(Error::OutputIsInput(_))));
+        let dotted = dir.join(".").join("input.txt");
+        let decode = FileInputOutput::builder(InputFile::Encoded(input.clone()))
+            .output(OutputTarget::Path(dotted))
+            .overwrite(OverwritePolicy::Always)
+            .build();
+        assert!(matches!(decode, Err(Error::OutputIsInput(_))));
+
+        let linked = dir.join("linked.txt");
+        std::fs::hard_link(&input, &linked).unwrap();
+        assert!(matches!(encode_to(&linked), Err(Error::OutputIsInput(_))));
+        #[cfg(unix)]
+        {
+            let symlinked = dir.join("symlinked.txt");
+            std::os::unix::fs::symlink(&input, &symlinked).unwrap();
+            assert!(same_file(&symlinked, &input));
+        }
+
+        // Another file, or one that doesn't exist yet, is fine
+        let other = dir.join("other.txt");
+        std::fs::write(&other, b"input").unwrap();
+        assert!(!same_file(&input, &other));
+        assert!(encode_to(&other).is_ok());
+        assert!(encode_to(&dir.join("new.lizard")).is_ok());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn glob_with_exclude() {
+        let dir = temp_path("glob");
+        std::fs::create_dir_all(dir.join("logs/old")).unwrap();
+        for file in [
+            "logs/a.log",
+            "logs/b.txt",
+            "logs/old/c.log",
+            "logs/old/d.log",
+        ] {
+            std::fs::write(dir.join(file), b"").unwrap();
+        }
+        let pattern = |p: &str| format!("{}/{}", dir.to_str().unwrap(), p);
+
+        let paths = expand_globs(&[pattern("logs/**/*.log")], &[pattern("**/d.log")]).unwrap();
+        let expected = vec![dir.join("logs/a.log"), dir.join("logs/old/c.log")];
+        assert_eq!(expected, paths);
+
+        // Overlapping patterns don't give the same file twice
+        let paths = expand_globs(&[pattern("logs/*.log"), pattern("logs/a.*")], &[]).unwrap();
+        assert_eq!(vec![dir.join("logs/a.log")], paths);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+        assert!(expand_globs(&[String::from("[")], &[]).is_err());
     }
 }
@@ -0,0 +1,177 @@
+//! Reversible transforms applied to the input before matching, recorded in the header so the
+//! decoder can undo them. The token stream (and its trailer) describe the filtered bytes
+use serde::{Deserialize, Serialize};
+use std::io::{Read, Seek, SeekFrom};
+
+/// Furthest back any filter looks
+const MAX_DISTANCE: usize = 2;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Filter {
+    #[default]
+    None,
+    /// Each byte minus the one before it. Slowly changing 8 bit samples become runs of small
+    /// values, which match and huffman code far better
+    Delta8,
+    /// Each byte minus the one two before it, i.e. [Filter::Delta8] for 16 bit samples
+    Delta16,
+}
+
+impl Filter {
+    /// How many bytes back each byte is differenced against
+    fn distance(&self) -> Option<usize> {
+        match self {
+            Self::None => None,
+            Self::Delta8 => Some(1),
+            Self::Delta16 => Some(2),
+        }
+    }
+
+    /// Reverse the filter over the whole of [bytes] in place
+    pub(crate) fn unfilter(&self, bytes: &mut [u8]) {
+        Unfilter::new(*self).apply(bytes);
+    }
+}
+
+/// Reverses [Filter] on bytes given a piece at a time, so a decoder can write them out as it
+/// goes
+pub(crate) struct Unfilter {
+    filter: Filter,
+    /// The last unfiltered bytes, indexed by position modulo the filter's distance
+    history: [u8; MAX_DISTANCE],
+    position: usize,
+}
+
+impl Unfilter {
+    pub fn new(filter: Filter) -> Self {
+        Self {
+            filter,
+            history: [0; MAX_DISTANCE],
+            position: 0,
+        }
+    }
+
+    /// Reverse the filter over [bytes] in place, carrying on from those before
+    pub fn apply(&mut self, bytes: &mut [u8]) {
+        if let Some(distance) = self.filter.distance() {
+            for byte in bytes {
+                let slot = self.position % distance;
+                *byte = byte.wrapping_add(self.history[slot]);
+                self.history[slot] = *byte;
+                self.position += 1;
+            }
+        }
+    }
+}
+
+/// Applies [Filter] to everything read from [inner], keeping the length and CRC32 of the
+/// unfiltered bytes. Only supports seeking back to the start
+pub(crate) struct FilterReader<R: Read + Seek> {
+    inner: R,
+    filter: Filter,
+    history: [u8; MAX_DISTANCE],
+    position: usize,
+    hasher: crc32fast::Hasher,
+}
+
+impl<R: Read + Seek> FilterReader<R> {
+    pub fn new(inner: R, filter: Filter) -> Self {
+        Self {
+            inner,
+            filter,
+            history: [0; MAX_DISTANCE],
+            position: 0,
+            hasher: crc32fast::Hasher::new(),
+        }
+    }
+
+    /// CRC32 of the unfiltered bytes read since the start
+    pub fn checksum(&self) -> u32 {
+        self.hasher.clone().finalize()
+    }
+}
+
+impl<R: Read + Seek> Read for FilterReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let read = self.inner.read(buf)?;
+        self.hasher.update(&buf[..read]);
+        if let Some(distance) = self.filter.distance() {
+            for byte in &mut buf[..read] {
+                let slot = self.position % distance;
+                let previous = std::mem::replace(&mut self.history[slot], *byte);
+                *byte = byte.wrapping_sub(previous);
+                self.position += 1;
+            }
+        }
+        Ok(read)
+    }
+}
+
+impl<R: Read + Seek> Seek for FilterReader<R> {
+    fn seek(&mut self, pos: SeekFrom) -> std::io::Result<u64> {
+        match pos {
+            SeekFrom::Start(0) => {
+                self.history = [0; MAX_DISTANCE];
+                self.position = 0;
+                self.hasher = crc32fast::Hasher::new();
+                self.inner.seek(pos)
+            }
+            _ => Err(std::io::Error::new(
+                std::io::ErrorKind::Unsupported,
+                "Filtered input can only be rewound",
+            )),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::{Cursor, Read, Seek};
+
+    use crate::filter::{Filter, FilterReader, Unfilter};
+
+    #[test]
+    fn round_trip() {
+        let input: Vec<u8> = (0..1000u32).map(|i| (i * i / 7) as u8).collect();
+        for filter in [Filter::None, Filter::Delta8, Filter::Delta16] {
+            let mut reader = FilterReader::new(Cursor::new(&input), filter);
+            let mut filtered = Vec::new();
+            // Small reads, so the history carries between them
+            let mut buf = [0; 7];
+            loop {
+                match reader.read(&mut buf).unwrap() {
+                    0 => break,
+                    n => filtered.extend_from_slice(&buf[..n]),
+                }
+            }
+            assert_eq!(crc32fast::hash(&input), reader.checksum());
+            // Rewinding starts afresh
+            reader.rewind().unwrap();
+            let mut again = Vec::new();
+            reader.read_to_end(&mut again).unwrap();
+            assert_eq!(filtered, again);
+
+            // Unfiltering a piece at a time carries the history between pieces too
+            let mut unfilter = Unfilter::new(filter);
+            for piece in again.chunks_mut(7) {
+                unfilter.apply(piece);
+            }
+            assert_eq!(input, again);
+            filter.unfilter(&mut filtered);
+            assert_eq!(input, filtered);
+        }
+    }
+
+    #[test]
+    fn delta() {
+        let mut reader = FilterReader::new(Cursor::new([1, 2, 4, 7, 3]), Filter::Delta8);
+        let mut filtered = Vec::new();
+        reader.read_to_end(&mut filtered).unwrap();
+        assert_eq!(vec![1, 1, 2, 3, 252], filtered);
+
+        let mut reader = FilterReader::new(Cursor::new([1, 2, 4, 7, 3]), Filter::Delta16);
+        let mut filtered = Vec::new();
+        reader.read_to_end(&mut filtered).unwrap();
+        assert_eq!(vec![1, 2, 3, 5, 255], filtered);
+    }
+}
@@ -0,0 +1,88 @@
+//! What differs between versions of the format, so the decoder can keep reading old files as
+//! the format changes. The version byte in the header picks the [Format] used to decode the
+//! rest of the stream
+pub(crate) mod stored;
+pub(crate) mod v1;
+
+use crate::error::{Error, Result};
+
+/// Format version written by this build. Version 4 records the [crate::encode::Algorithm] in
+/// the header, which older decoders would misread the stream without. Version 5 packs matches
+/// into bins plus extra bits, and version 6 makes their offsets relative to where they're made.
+/// Version 7 writes the header's length as a varint, so it can be longer than 64KiB. Version 8
+/// adds fields to the end of the header (the rANS table, primed window, auto level settings and
+/// long range), which version 7 decoders reject the header for having
+pub const FORMAT_VERSION: u8 = 8;
+
+/// The parts of the token stream which vary by version
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct Format {
+    pub version: u8,
+    /// Whether a 0b00 prefix starts a control record, rather than being invalid
+    pub control_records: bool,
+    /// Whether the stream has to finish with an end of stream record
+    pub trailer: bool,
+    /// Whether matches are laid out as bins plus extra bits, rather than whole bytes
+    pub binned_matches: bool,
+    /// Whether match offsets are the distance back from where the match is made, rather than
+    /// an index into the lookback buffer
+    pub relative_offsets: bool,
+    /// Whether runs of literals are ended after [crate::MAX_LITERAL_RUN_LEN], so the packed bytes
+    /// of a longer one are invalid rather than just large
+    pub capped_literal_runs: bool,
+}
+
+impl Format {
+    pub fn for_version(version: u8) -> Result<Self> {
+        match version {
+            1 | 2 => Ok(Self {
+                version,
+                ..v1::FORMAT
+            }),
+            3 | 4 => Ok(Self {
+                version,
+                control_records: true,
+                trailer: true,
+                binned_matches: false,
+                relative_offsets: false,
+                capped_literal_runs: false,
+            }),
+            5..=8 => Ok(Self {
+                version,
+                control_records: true,
+                trailer: true,
+                binned_matches: true,
+                relative_offsets: version >= 6,
+                // Runs were capped partway through version 7, so only later versions can be
+                // held to it
+                capped_literal_runs: version >= 8,
+            }),
+            other => Err(Error::UnsupportedVersion(other)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::error::Error;
+    use crate::format::{Format, FORMAT_VERSION};
+
+    #[test]
+    fn for_version() {
+        assert!(!Format::for_version(1).unwrap().trailer);
+        assert!(!Format::for_version(2).unwrap().control_records);
+        assert!(Format::for_version(FORMAT_VERSION).unwrap().trailer);
+        assert!(!Format::for_version(4).unwrap().binned_matches);
+        assert!(!Format::for_version(5).unwrap().relative_offsets);
+        assert!(!Format::for_version(7).unwrap().capped_literal_runs);
+        assert!(
+            Format::for_version(FORMAT_VERSION)
+                .unwrap()
+                .capped_literal_runs
+        );
+        match Format::for_version(FORMAT_VERSION + 1) {
+            Err(Error::UnsupportedVersion(_)) => (),
+            other => panic!("Expected an unsupported version error, got {:?}", other),
+        }
+    }
+}
@@ -0,0 +1,83 @@
+//! Stored frames hold the input as it is, without the usual header. They're
+//! `[magic][LEB128 len][bytes]`, so only a handful of bytes bigger than the input, for inputs
+//! where the header and huffman tree alone would be bigger than the input compressed
+
+use std::io::{Read, Write};
+
+use crate::control::{write_varint, VarintReader};
+use crate::error::{Error, Result};
+
+/// Starts every stored frame. Can't be mistaken for a versioned header's magic, and as a
+/// version 1 header length it would be far longer than any real one
+pub(crate) const MAGIC: &[u8; 4] = b"LZRs";
+
+/// Whether [prefix], the first [MAGIC].len() bytes of a stream, starts a stored frame
+pub(crate) fn is_stored(prefix: &[u8]) -> bool {
+    prefix == MAGIC
+}
+
+/// How many bytes [write] takes to store [len] bytes
+pub(crate) fn frame_len(len: u64) -> u64 {
+    let varint_len = (u64::BITS - len.leading_zeros()).div_ceil(7).max(1);
+    MAGIC.len() as u64 + varint_len as u64 + len
+}
+
+/// Write the [len] bytes from [reader] as a stored frame, returning how many bytes that took
+pub(crate) fn write<R: Read, W: Write>(reader: R, len: u64, writer: &mut W) -> Result<u64> {
+    writer.write_all(MAGIC)?;
+    let varint_len = write_varint(writer, len)?;
+    if std::io::copy(&mut reader.take(len), writer)? != len {
+        return Err(Error::Io(std::io::Error::new(
+            std::io::ErrorKind::UnexpectedEof,
+            "Input ended before the length being stored",
+        )));
+    }
+    Ok(MAGIC.len() as u64 + varint_len as u64 + len)
+}
+
+/// Copy the rest of a stored frame, after its magic, to [writer], failing if it holds more
+/// than [max_len] bytes. Returns how many bytes it held
+pub(crate) fn read<R: Read, W: Write>(reader: &mut R, writer: &mut W, max_len: u64) -> Result<u64> {
+    let mut varint_reader = VarintReader::default();
+    let mut byte = [0u8];
+    let len = loop {
+        if reader.read(&mut byte)? == 0 {
+            return Err(Error::TruncatedStream("stored length"));
+        }
+        if let Some(len) = varint_reader.push(byte[0])? {
+            break len;
+        }
+    };
+    if len > max_len {
+        return Err(Error::LimitExceeded {
+            limit: "output size",
+            max: max_len,
+        });
+    }
+    if std::io::copy(&mut reader.take(len), writer)? != len {
+        return Err(Error::TruncatedStream("stored bytes"));
+    }
+    Ok(len)
+}
+
+#[cfg(test)]
+mod test {
+    use crate::format::stored;
+
+    #[test]
+    fn round_trip() {
+        for bytes in [&b""[..], b"hi", &[7u8; 300]] {
+            let mut frame = Vec::new();
+            let len = stored::write(bytes, bytes.len() as u64, &mut frame).unwrap();
+            assert_eq!(frame.len() as u64, len);
+            assert_eq!(stored::frame_len(bytes.len() as u64), len);
+            assert!(stored::is_stored(&frame[..stored::MAGIC.len()]));
+            let mut read = Vec::new();
+            let frame_body = &frame[stored::MAGIC.len()..];
+            stored::read(&mut &frame_body[..], &mut read, u64::MAX).unwrap();
+            assert_eq!(bytes, read);
+            let truncated = &frame_body[..frame_body.len() - 1];
+            assert!(stored::read(&mut &truncated[..], &mut Vec::new(), u64::MAX).is_err());
+        }
+    }
+}
@@ -0,0 +1,44 @@
+//! Version 1 is from before the header was versioned. The header is
+//! `[u16 header len][u64 lookback buffer len][huffman tree]`, and the token stream is only
+//! matches and chunks of literals, which just stops at the end of the input. Version 2 added the
+//! magic, version byte and optional fields to the header but kept this token stream
+
+use crate::error::{Error, Result};
+use crate::format::Format;
+use crate::huffman::HuffmanTree;
+
+pub(crate) const FORMAT: Format = Format {
+    version: 1,
+    control_records: false,
+    trailer: false,
+    binned_matches: false,
+    relative_offsets: false,
+    capped_literal_runs: false,
+};
+
+const LOOKBACK_BUFFER_LEN_OFFSET: usize = 2;
+const LOOKBACK_BUFFER_LEN_LEN: usize = 8;
+
+/// The header's length from its first bytes, or None if more are needed
+pub(crate) fn header_len(prefix: &[u8]) -> Option<usize> {
+    if prefix.len() < 2 {
+        None
+    } else {
+        Some(u16::from_be_bytes([prefix[0], prefix[1]]) as usize)
+    }
+}
+
+/// Read the lookback buffer length and huffman tree from a whole header
+pub(crate) fn parse_header(bytes: &[u8]) -> Result<(u64, HuffmanTree)> {
+    let tree_offset = LOOKBACK_BUFFER_LEN_OFFSET + LOOKBACK_BUFFER_LEN_LEN;
+    if bytes.len() < tree_offset {
+        return Err(Error::CorruptHeader(String::from("Header too short")));
+    }
+    let be_bytes: [u8; 8] = bytes[LOOKBACK_BUFFER_LEN_OFFSET..tree_offset]
+        .try_into()
+        .unwrap();
+    let lookback_buffer_len = u64::from_be_bytes(be_bytes);
+    let huffman_tree = rmp_serde::from_slice::<HuffmanTree>(&bytes[tree_offset..])
+        .map_err(|e| Error::CorruptHeader(e.to_string()))?;
+    Ok((lookback_buffer_len, huffman_tree))
+}
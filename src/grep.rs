@@ -0,0 +1,365 @@
+//! Searching inside compressed files without extracting them, i.e. `lizards grep`. The output is
+//! matched line by line as it's decoded, so nothing but the line being matched and the context
+//! before it is held, and decoding stops once [GrepOptions::max_count] lines have matched.
+//! Archives written by [crate::archive::encode_dir] are searched entry by entry, with each line
+//! found given the name of the entry it's in
+
+use regex::bytes::Regex;
+use std::collections::VecDeque;
+use std::io::{Read, Write};
+
+use crate::archive::read_tar_stream;
+use crate::decode::{decode_to_writer_with_options, DecodeOptions};
+use crate::error::Result;
+
+/// What to search for, built up like [crate::EncodeOptions] e.g.
+/// `GrepOptions::new(pattern).context(2, 2)`
+#[derive(Debug, Clone)]
+pub struct GrepOptions {
+    pattern: Regex,
+    /// Lines to give before each matching line, as with `grep -B`
+    before_context: usize,
+    /// Lines to give after each matching line, as with `grep -A`
+    after_context: usize,
+    /// Stop once this many lines have matched, as with `grep -m`
+    max_count: Option<u64>,
+}
+
+impl GrepOptions {
+    /// Lines matching [pattern] anywhere in them. Use [regex::escape] to look for a literal
+    pub fn new(pattern: Regex) -> Self {
+        Self {
+            pattern,
+            before_context: 0,
+            after_context: 0,
+            max_count: None,
+        }
+    }
+
+    /// Also give the [before] lines before each matching line, and the [after] lines after it
+    pub fn context(mut self, before: usize, after: usize) -> Self {
+        self.before_context = before;
+        self.after_context = after;
+        self
+    }
+
+    /// Stop decoding once [max_count] lines have matched, after giving the context after the
+    /// last of them. In an archive, that's lines of all its entries together
+    pub fn max_count(mut self, max_count: Option<u64>) -> Self {
+        self.max_count = max_count;
+        self
+    }
+}
+
+/// A line [grep] found, either matching or context around a matching line
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct GrepLine<'a> {
+    /// Path of the archive entry the line is in, for [grep_archive]
+    pub member: Option<&'a str>,
+    /// Counting from 1, within the entry for [grep_archive]
+    pub number: u64,
+    /// Without the newline ending it
+    pub line: &'a [u8],
+    /// Whether it matched, rather than being context
+    pub matched: bool,
+}
+
+/// What [grep] and [grep_archive] found
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct GrepSummary {
+    /// Lines that matched
+    pub matches: u64,
+    /// Whether decoding stopped at [GrepOptions::max_count], before the end of the input
+    pub stopped: bool,
+}
+
+/// Decode the lizards stream from [reader], passing each line that matches [options]' pattern
+/// to [emit], along with the context around it. An error from [emit], e.g. from writing the
+/// lines out, stops the search
+pub fn grep<R: Read>(
+    reader: R,
+    options: &GrepOptions,
+    decode_options: &DecodeOptions,
+    emit: impl FnMut(&GrepLine) -> std::io::Result<()>,
+) -> Result<GrepSummary> {
+    let mut lines = LineMatcher::new(options, emit);
+    let decoded = decode_to_writer_with_options(reader, &mut lines, decode_options);
+    lines.finish(decoded)
+}
+
+/// [grep] for an archive written by [crate::archive::encode_dir], looking through each file in
+/// it and giving the lines found the entry's path
+pub fn grep_archive<R: Read + Send>(
+    reader: R,
+    options: &GrepOptions,
+    decode_options: &DecodeOptions,
+    emit: impl FnMut(&GrepLine) -> std::io::Result<()>,
+) -> Result<GrepSummary> {
+    let mut lines = LineMatcher::new(options, emit);
+    let read = read_tar_stream(reader, decode_options, |archive| {
+        for entry in archive.entries()? {
+            let mut entry = entry?;
+            if entry.header().entry_type() != tar::EntryType::Regular {
+                continue;
+            }
+            lines.start_member(entry.path()?.display().to_string());
+            std::io::copy(&mut entry, &mut lines)?;
+            lines.end_member()?;
+        }
+        Ok(())
+    });
+    lines.finish(read)
+}
+
+/// Matches the lines written to it, keeping the context before the current line
+struct LineMatcher<'a, F> {
+    options: &'a GrepOptions,
+    emit: F,
+    member: Option<String>,
+    /// The end of a line that hasn't been written yet
+    partial: Vec<u8>,
+    /// Of the last whole line
+    number: u64,
+    before: VecDeque<(u64, Vec<u8>)>,
+    /// Lines still to give after the last match
+    after: usize,
+    matches: u64,
+    /// At [GrepOptions::max_count], with all the context after it given
+    stopped: bool,
+}
+
+impl<'a, F: FnMut(&GrepLine) -> std::io::Result<()>> LineMatcher<'a, F> {
+    fn new(options: &'a GrepOptions, emit: F) -> Self {
+        Self {
+            options,
+            emit,
+            member: None,
+            partial: Vec::new(),
+            number: 0,
+            before: VecDeque::new(),
+            after: 0,
+            matches: 0,
+            stopped: false,
+        }
+    }
+
+    /// Write to stop decoding, once [stopped]
+    fn stop_error() -> std::io::Error {
+        std::io::Error::other("Stopped at the max count")
+    }
+
+    fn start_member(&mut self, member: String) {
+        self.member = Some(member);
+        self.number = 0;
+        self.before.clear();
+        self.after = 0;
+    }
+
+    /// The last line, if it doesn't end with a newline
+    fn end_member(&mut self) -> std::io::Result<()> {
+        if !self.partial.is_empty() && !self.stopped {
+            let line = std::mem::take(&mut self.partial);
+            self.line(&line)?;
+        }
+        Ok(())
+    }
+
+    fn line(&mut self, line: &[u8]) -> std::io::Result<()> {
+        self.number += 1;
+        let member = self.member.as_deref();
+        // Lines after the last of the max count are only context
+        let at_max = self
+            .options
+            .max_count
+            .is_some_and(|max| self.matches >= max);
+        if !at_max && self.options.pattern.is_match(line) {
+            for (number, line) in self.before.drain(..) {
+                (self.emit)(&GrepLine {
+                    member,
+                    number,
+                    line: &line,
+                    matched: false,
+                })?;
+            }
+            (self.emit)(&GrepLine {
+                member,
+                number: self.number,
+                line,
+                matched: true,
+            })?;
+            self.matches += 1;
+            self.after = self.options.after_context;
+        } else if self.after > 0 {
+            (self.emit)(&GrepLine {
+                member,
+                number: self.number,
+                line,
+                matched: false,
+            })?;
+            self.after -= 1;
+        } else if self.options.before_context > 0 {
+            if self.before.len() == self.options.before_context {
+                self.before.pop_front();
+            }
+            self.before.push_back((self.number, line.to_vec()));
+        }
+        if self.after == 0
+            && self
+                .options
+                .max_count
+                .is_some_and(|max| self.matches >= max)
+        {
+            self.stopped = true;
+        }
+        Ok(())
+    }
+
+    /// What was found, given how decoding went
+    fn finish<T>(mut self, decoded: Result<T>) -> Result<GrepSummary> {
+        let stopped = match decoded {
+            // From writing once stopped, which is the point
+            Err(_) if self.stopped => true,
+            Err(e) => return Err(e),
+            Ok(_) => {
+                self.end_member()?;
+                false
+            }
+        };
+        Ok(GrepSummary {
+            matches: self.matches,
+            stopped,
+        })
+    }
+}
+
+impl<F: FnMut(&GrepLine) -> std::io::Result<()>> Write for LineMatcher<'_, F> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        if self.stopped {
+            return Err(Self::stop_error());
+        }
+        let mut rest = buf;
+        while let Some(end) = rest.iter().position(|b| *b == b'\n') {
+            let line = match self.partial.is_empty() {
+                true => self.line(&rest[..end]),
+                false => {
+                    let mut line = std::mem::take(&mut self.partial);
+                    line.extend_from_slice(&rest[..end]);
+                    self.line(&line)
+                }
+            };
+            line?;
+            rest = &rest[end + 1..];
+            if self.stopped {
+                return Ok(buf.len() - rest.len());
+            }
+        }
+        self.partial.extend_from_slice(rest);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use regex::bytes::Regex;
+
+    use crate::archive::encode_dir;
+    use crate::grep::{grep, grep_archive, GrepOptions, GrepSummary};
+    use crate::test_util::{encode, temp_path};
+    use crate::{DecodeOptions, EncodeOptions};
+
+    /// The lines found in [encoded], as `member:number:line` for matches and with `-`s for
+    /// context
+    fn found(encoded: &[u8], options: &GrepOptions, archive: bool) -> (Vec<String>, GrepSummary) {
+        let mut found = Vec::new();
+        let emit = |line: &crate::grep::GrepLine| {
+            let separator = if line.matched { ':' } else { '-' };
+            found.push(format!(
+                "{}{}{}{}{}",
+                line.member.unwrap_or_default(),
+                separator,
+                line.number,
+                separator,
+                String::from_utf8_lossy(line.line)
+            ));
+            Ok(())
+        };
+        let summary = match archive {
+            true => grep_archive(encoded, options, &DecodeOptions::default(), emit),
+            false => grep(encoded, options, &DecodeOptions::default(), emit),
+        }
+        .unwrap();
+        (found, summary)
+    }
+
+    #[test]
+    fn finds_lines() {
+        let input: String = (1..=100)
+            .map(|i| match i % 10 {
+                0 => format!("ERROR {}\n", i),
+                _ => format!("info {}\n", i),
+            })
+            .collect();
+        let encoded = encode(input.as_bytes(), &EncodeOptions::default());
+        let options = GrepOptions::new(Regex::new("ERROR [1-3]0$").unwrap());
+        let (lines, summary) = found(&encoded, &options, false);
+        assert_eq!(vec![":10:ERROR 10", ":20:ERROR 20", ":30:ERROR 30"], lines);
+        assert_eq!(3, summary.matches);
+        assert!(!summary.stopped);
+
+        // With context, stopping at the second
+        let options = options.context(1, 2).max_count(Some(2));
+        let (lines, summary) = found(&encoded, &options, false);
+        assert_eq!(
+            vec![
+                "-9-info 9",
+                ":10:ERROR 10",
+                "-11-info 11",
+                "-12-info 12",
+                "-19-info 19",
+                ":20:ERROR 20",
+                "-21-info 21",
+                "-22-info 22",
+            ],
+            lines
+        );
+        assert!(summary.stopped);
+
+        // A last line without a newline
+        let encoded = encode(b"a\nb\nERROR", &EncodeOptions::default());
+        let options = GrepOptions::new(Regex::new("ERROR").unwrap());
+        assert_eq!(vec![":3:ERROR"], found(&encoded, &options, false).0);
+    }
+
+    #[test]
+    fn finds_lines_in_archive() {
+        let dir = temp_path("grep");
+        std::fs::create_dir_all(dir.join("sub")).unwrap();
+        std::fs::write(dir.join("a.log"), "ok\nERROR one\nok\n").unwrap();
+        std::fs::write(dir.join("sub/b.log"), "ERROR two\nok").unwrap();
+        let mut encoded = Vec::new();
+        encode_dir(&dir, &mut encoded, &EncodeOptions::default()).unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        let options = GrepOptions::new(Regex::new("ERROR").unwrap());
+        let (mut lines, summary) = found(&encoded, &options, true);
+        lines.sort();
+        let name = dir.file_name().unwrap().to_string_lossy();
+        assert_eq!(
+            vec![
+                format!("{}/a.log:2:ERROR one", name),
+                format!("{}/sub/b.log:1:ERROR two", name)
+            ],
+            lines
+        );
+        assert_eq!(2, summary.matches);
+
+        let options = options.max_count(Some(1));
+        let (lines, summary) = found(&encoded, &options, true);
+        assert_eq!(1, lines.len());
+        assert!(summary.stopped);
+    }
+}
@@ -0,0 +1,275 @@
+//! gzip (RFC 1952) output, so files compressed by lizards can be read by standard tools. The
+//! LZSS matcher finds the matches as usual, and the DEFLATE (RFC 1951) stream is written as a
+//! single block using the fixed huffman codes. Our huffman trees are limited to
+//! [crate::huffman::MAX_HUFFMAN_CODE_LEN] bits, as DEFLATE's are, so dynamic codes could be
+//! built from them, but aren't written yet
+use log::info;
+use std::fs::File;
+use std::io::{Read, Write};
+
+use crate::encode::{tokenise, EncodeOptions, EncodeSummary};
+use crate::error::Result;
+use crate::file_io::FileInputOutput;
+use crate::input::input_reader;
+use crate::output::output_writer;
+use crate::throttle::Throttled;
+use crate::{helpers, Token};
+
+/// DEFLATE distances only go back this far
+const MAX_WINDOW_SIZE: usize = 32768;
+const MIN_MATCH_LEN: usize = 3;
+const MAX_MATCH_LEN: usize = 258;
+
+const END_OF_BLOCK: u16 = 256;
+
+/// Base lengths of length codes 257 to 285, and how many extra bits follow each
+const LENGTH_BASES: [u16; 29] = [
+    3, 4, 5, 6, 7, 8, 9, 10, 11, 13, 15, 17, 19, 23, 27, 31, 35, 43, 51, 59, 67, 83, 99, 115, 131,
+    163, 195, 227, 258,
+];
+const LENGTH_EXTRA_BITS: [u8; 29] = [
+    0, 0, 0, 0, 0, 0, 0, 0, 1, 1, 1, 1, 2, 2, 2, 2, 3, 3, 3, 3, 4, 4, 4, 4, 5, 5, 5, 5, 0,
+];
+
+/// Base distances of distance codes 0 to 29, and how many extra bits follow each
+const DISTANCE_BASES: [u16; 30] = [
+    1, 2, 3, 4, 5, 7, 9, 13, 17, 25, 33, 49, 65, 97, 129, 193, 257, 385, 513, 769, 1025, 1537,
+    2049, 3073, 4097, 6145, 8193, 12289, 16385, 24577,
+];
+const DISTANCE_EXTRA_BITS: [u8; 30] = [
+    0, 0, 0, 0, 1, 1, 2, 2, 3, 3, 4, 4, 5, 5, 6, 6, 7, 7, 8, 8, 9, 9, 10, 10, 11, 11, 12, 12, 13,
+    13,
+];
+
+/// Compress the input file to a gzip file at [file_io]'s encoded filename
+pub fn encode(file_io: &FileInputOutput, options: &EncodeOptions) -> Result<EncodeSummary> {
+    let throttle = options.throttle();
+    let input_file = File::open(file_io.unencoded_filename.as_path())?;
+    let input_file = Throttled::new(
+        input_reader(input_file, options.read_ahead)?,
+        throttle.clone(),
+    );
+    let output_file = File::create(file_io.encoded_filename.as_path())?;
+    let output_file = output_writer(Throttled::new(output_file, throttle), options.write_behind)?;
+    let summary = encode_from_reader(input_file, output_file, options)?;
+    info!(
+        "Encoded {:?} -> {:?} as gzip ({} -> {} bytes)",
+        file_io.unencoded_filename,
+        file_io.encoded_filename,
+        summary.input_bytes,
+        summary.output_bytes
+    );
+    Ok(summary)
+}
+
+/// Compress the input file as [encode] would, only counting the bytes of gzip output rather than
+/// writing them, to find how big the output would be
+pub fn dry_run(file_io: &FileInputOutput, options: &EncodeOptions) -> Result<EncodeSummary> {
+    let throttle = options.throttle();
+    let input_file = File::open(file_io.unencoded_filename.as_path())?;
+    let input_file = Throttled::new(
+        input_reader(input_file, options.read_ahead)?,
+        throttle.clone(),
+    );
+    let mut writer = helpers::CountingWriter::new(Throttled::new(std::io::sink(), throttle));
+    let summary = encode_from_reader(input_file, &mut writer, options)?;
+    Ok(EncodeSummary {
+        output_bytes: writer.count(),
+        ..summary
+    })
+}
+
+/// Compress everything from [reader] to gzip, writing it to [writer]. Unlike the lizards format
+/// this needs only one pass over the input. The window is capped at 32KiB, and matches are
+/// at least 3 bytes long unless [options] asks for longer
+pub fn encode_from_reader<R: Read, W: Write>(
+    reader: R,
+    writer: W,
+    options: &EncodeOptions,
+) -> Result<EncodeSummary> {
+    let mut reader = helpers::CountingReader::new(reader);
+    let mut writer = BitWriter::new(helpers::CountingWriter::new(writer));
+    let mut summary = EncodeSummary::default();
+
+    // No flags, modification time, extra flags, then "unknown" operating system
+    writer.write_bytes(&[0x1f, 0x8b, 8, 0, 0, 0, 0, 0, 0, 255])?;
+    // The only block, and it uses the fixed codes
+    writer.write_bits(1, 1)?;
+    writer.write_bits(0b01, 2)?;
+
+    let window_size = options.window_size.min(MAX_WINDOW_SIZE);
+    let min_match = options
+        .min_match
+        .unwrap_or(MIN_MATCH_LEN)
+        .max(MIN_MATCH_LEN);
+    tokenise(
+        &mut reader,
+        &[],
+        window_size,
+        options
+            .match_strategy
+            .finder(min_match, window_size)
+            .as_mut(),
+        options.max_match_len,
+        options.rle_min_run,
+        |value| {
+            match value {
+                Token::Literal(v) => {
+                    summary.literals += 1;
+                    writer.write_literal(*v as u16)
+                }
+                Token::Match(offset_len) => {
+                    summary.matches += 1;
+                    writer.write_long_match(offset_len.len.get(), offset_len.offset().get() as u16)
+                }
+                // The byte, then a match against itself for the rest
+                Token::Run(v, len) => {
+                    summary.runs += 1;
+                    writer.write_literal(*v as u16)?;
+                    writer.write_long_match(*len as usize - 1, 1)
+                }
+                Token::EndOfStream(_) | Token::Control(_) => {
+                    unreachable!("The tokeniser doesn't make control records")
+                }
+            }
+            .map_err(Into::into)
+        },
+    )?;
+    writer.write_literal(END_OF_BLOCK)?;
+    writer.flush_to_byte()?;
+
+    summary.input_bytes = reader.count();
+    summary.checksum = reader.checksum();
+    writer.write_bytes(&summary.checksum.to_le_bytes())?;
+    // Size modulo 2^32
+    writer.write_bytes(&(summary.input_bytes as u32).to_le_bytes())?;
+    writer.writer.flush()?;
+    summary.output_bytes = writer.writer.count();
+    Ok(summary)
+}
+
+/// Fixed huffman code (and its length) for a literal/length symbol
+fn fixed_literal_code(symbol: u16) -> (u16, u8) {
+    match symbol {
+        0..=143 => (0b00110000 + symbol, 8),
+        144..=255 => (0b110010000 + (symbol - 144), 9),
+        256..=279 => (symbol - 256, 7),
+        _ => (0b11000000 + (symbol - 280), 8),
+    }
+}
+
+/// DEFLATE packs bits from the least significant bit of each byte up
+struct BitWriter<W: Write> {
+    writer: W,
+    bits: u64,
+    bit_count: u8,
+}
+
+impl<W: Write> BitWriter<W> {
+    fn new(writer: W) -> Self {
+        Self {
+            writer,
+            bits: 0,
+            bit_count: 0,
+        }
+    }
+
+    /// Write the low [count] bits of [value], least significant first
+    fn write_bits(&mut self, value: u32, count: u8) -> std::io::Result<()> {
+        self.bits |= (value as u64) << self.bit_count;
+        self.bit_count += count;
+        while self.bit_count >= 8 {
+            self.writer.write_all(&[self.bits as u8])?;
+            self.bits >>= 8;
+            self.bit_count -= 8;
+        }
+        Ok(())
+    }
+
+    /// Huffman codes are packed most significant bit first, unlike everything else
+    fn write_code(&mut self, code: u16, len: u8) -> std::io::Result<()> {
+        let reversed = code.reverse_bits() >> (16 - len);
+        self.write_bits(reversed as u32, len)
+    }
+
+    fn write_literal(&mut self, symbol: u16) -> std::io::Result<()> {
+        let (code, len) = fixed_literal_code(symbol);
+        self.write_code(code, len)
+    }
+
+    fn write_match(&mut self, len: u16, distance: u16) -> std::io::Result<()> {
+        let i = LENGTH_BASES.iter().rposition(|base| *base <= len).unwrap();
+        self.write_literal(257 + i as u16)?;
+        self.write_bits((len - LENGTH_BASES[i]) as u32, LENGTH_EXTRA_BITS[i])?;
+        let i = DISTANCE_BASES
+            .iter()
+            .rposition(|base| *base <= distance)
+            .unwrap();
+        // Distance codes are all 5 bits in the fixed code
+        self.write_code(i as u16, 5)?;
+        self.write_bits(
+            (distance - DISTANCE_BASES[i]) as u32,
+            DISTANCE_EXTRA_BITS[i],
+        )
+    }
+
+    /// Split up matches longer than DEFLATE allows, as repeating the distance continues the
+    /// copy. [len] has to be at least the shortest match
+    fn write_long_match(&mut self, len: usize, distance: u16) -> std::io::Result<()> {
+        let mut remaining = len;
+        while remaining > MAX_MATCH_LEN {
+            // Don't leave less than the shortest match for the last piece
+            let len = MAX_MATCH_LEN.min(remaining - MIN_MATCH_LEN);
+            self.write_match(len as u16, distance)?;
+            remaining -= len;
+        }
+        self.write_match(remaining as u16, distance)
+    }
+
+    /// Pad with zeros up to the next byte boundary
+    fn flush_to_byte(&mut self) -> std::io::Result<()> {
+        if self.bit_count > 0 {
+            let padding = 8 - self.bit_count;
+            self.write_bits(0, padding)?;
+        }
+        Ok(())
+    }
+
+    fn write_bytes(&mut self, bytes: &[u8]) -> std::io::Result<()> {
+        debug_assert_eq!(0, self.bit_count);
+        self.writer.write_all(bytes)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::Read;
+
+    use crate::encode::EncodeOptions;
+    use crate::gzip::encode_from_reader;
+
+    fn gunzip(bytes: &[u8]) -> Vec<u8> {
+        let mut decoded = Vec::new();
+        flate2::read::GzDecoder::new(bytes)
+            .read_to_end(&mut decoded)
+            .unwrap();
+        decoded
+    }
+
+    #[test]
+    fn round_trip_with_flate2() {
+        let text = include_bytes!("../testfile.txt").repeat(20);
+        let long_run = vec![b'z'; 100000];
+        let binary: Vec<u8> = (0..5000u32).map(|i| (i * 7919 % 251) as u8).collect();
+        let with_runs = EncodeOptions::default().rle_min_run(Some(4));
+        for options in [EncodeOptions::default(), with_runs] {
+            for input in [&text[..], &long_run[..], &binary[..], b"a"] {
+                let mut encoded = Vec::new();
+                let summary = encode_from_reader(input, &mut encoded, &options).unwrap();
+                assert_eq!(input, &gunzip(&encoded)[..]);
+                assert_eq!(input.len() as u64, summary.input_bytes);
+                assert_eq!(encoded.len() as u64, summary.output_bytes);
+            }
+        }
+    }
+}
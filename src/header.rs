@@ -1,105 +1,596 @@
-use crate::huffman::{CodeMap, HuffmanTree};
-use std::collections::VecDeque;
-use std::convert::TryInto;
+use crate::control::{split_varint, write_varint};
+use crate::encode::{Algorithm, AutoSettings};
+use crate::entropy::{Entropy, RansTable};
+use crate::error::{Error, Result};
+use crate::file_io::FileMetadata;
+use crate::filter::Filter;
+use crate::format::{self, stored, Format, FORMAT_VERSION};
+use crate::huffman::HuffmanTree;
+use crate::DEFAULT_MAX_HEADER_SIZE;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::io::{Read, Seek, SeekFrom};
 
-#[derive(Debug, PartialEq)]
+/// Versioned headers start with these bytes. Version 1 headers predate this and start with
+/// their length instead
+const MAGIC: &[u8; 4] = b"LZRD";
+/// Long enough to tell whether bytes start a frame, see [starts_frame]
+pub(crate) const FRAME_PREFIX_LEN: usize = MAGIC.len();
+/// Magic and version byte, so long enough to tell which version a frame is, see [frame_version]
+pub(crate) const VERSIONED_PREFIX_LEN: usize = MAGIC.len() + 1;
+/// From this version the header's length is a varint of the serialised body's length. Before,
+/// it was a u16 of the whole header's length
+const VARINT_LEN_VERSION: u8 = 7;
+
+/// Encrypted files start with these bytes, followed by the version and a description of the
+/// encryption, see [crate::crypto]
+pub(crate) const ENCRYPTED_MAGIC: &[u8; 4] = b"LZRe";
+
+/// Whether [prefix], the first [FRAME_PREFIX_LEN] bytes of a file, start an encrypted one
+pub(crate) fn is_encrypted(prefix: &[u8]) -> bool {
+    prefix == ENCRYPTED_MAGIC
+}
+
+/// Signed files end with a trailer starting with these bytes, followed by a version byte, the
+/// signer's public key and the signature, see [crate::signing]
+pub(crate) const SIGNATURE_MAGIC: &[u8; 4] = b"LZRg";
+/// Of the whole signature trailer
+pub(crate) const SIGNATURE_TRAILER_LEN: usize = SIGNATURE_MAGIC.len() + 1 + 32 + 64;
+
+/// Whether [prefix], the first [FRAME_PREFIX_LEN] bytes following a frame, start a signature
+/// trailer, which ends the stream as much as the end of the file does
+pub(crate) fn starts_signature(prefix: &[u8]) -> bool {
+    prefix == SIGNATURE_MAGIC
+}
+
+/// Archives end with an index of their entries starting with these bytes, after the last
+/// frame, see [crate::archive]
+pub(crate) const INDEX_MAGIC: &[u8; 4] = b"LZRi";
+
+/// Whether [prefix], the first [FRAME_PREFIX_LEN] bytes following a frame, start an archive's
+/// index, which decoding skips over
+pub(crate) fn starts_index(prefix: &[u8]) -> bool {
+    prefix == INDEX_MAGIC
+}
+
+/// Whether the file [reader] reads ends with a signature trailer
+pub(crate) fn has_signature<R: Read + Seek>(mut reader: R) -> std::io::Result<bool> {
+    let len = reader.seek(SeekFrom::End(0))?;
+    if len < SIGNATURE_TRAILER_LEN as u64 {
+        return Ok(false);
+    }
+    reader.seek(SeekFrom::End(-(SIGNATURE_TRAILER_LEN as i64)))?;
+    let mut prefix = [0; FRAME_PREFIX_LEN];
+    reader.read_exact(&mut prefix)?;
+    Ok(starts_signature(&prefix))
+}
+
+/// The format version of the frame [prefix], its first [VERSIONED_PREFIX_LEN] bytes, starts. None
+/// for stored frames and encrypted files, which don't have one of their own, or if [prefix] is
+/// too short to tell. Anything else without the magic is taken to be version 1
+#[cfg(feature = "async")]
+pub(crate) fn frame_version(prefix: &[u8]) -> Option<u8> {
+    if prefix.starts_with(MAGIC) {
+        return prefix.get(MAGIC.len()).copied();
+    }
+    let magic = prefix.get(..FRAME_PREFIX_LEN)?;
+    match stored::is_stored(magic) || is_encrypted(magic) {
+        true => None,
+        false => Some(format::v1::FORMAT.version),
+    }
+}
+
+/// Whether [prefix], the first [FRAME_PREFIX_LEN] bytes following a frame, start another one
+/// rather than being junk on the end of the stream
+pub(crate) fn starts_frame(prefix: &[u8]) -> bool {
+    prefix == MAGIC || stored::is_stored(prefix)
+}
+
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
 pub struct Header {
-    pub huffman_tree: HuffmanTree,
+    #[serde(skip)]
+    version: u8,
     lookback_buffer_len: u64,
+    /// Literals are coded with this, and it's None when they're stored as they are
+    pub huffman_tree: Option<HuffmanTree>,
+    // Optional fields are serialised last and default when missing, so headers from before
+    // they were added still read. Older decoders fail on a longer header though, so appending
+    // one needs the version bumping
+    #[serde(default)]
+    file_metadata: Option<FileMetadata>,
+    /// Since version 4. Older streams are all LZ matches with huffman coded literals
+    #[serde(default)]
+    algorithm: Algorithm,
+    #[serde(default)]
+    filter: Filter,
+    /// Key-value pairs from whoever encoded the stream, e.g. where the input came from
+    #[serde(default)]
+    metadata: BTreeMap<String, String>,
+    /// Literals are rANS coded with this table rather than huffman coded
+    #[serde(default)]
+    rans_table: Option<RansTable>,
+    /// The window starts with this many bytes from the end of the previous frame's output, so
+    /// matches can reach back into it
+    #[serde(default)]
+    primed_len: u64,
+    /// What [crate::EncodeOptions::auto_level] picked, if it was used
+    #[serde(default)]
+    auto_settings: Option<AutoSettings>,
+    /// Matches can reach this far back, past the window, see [crate::EncodeOptions::long_range]
+    #[serde(default)]
+    long_range: Option<u64>,
 }
 
 impl Header {
-    pub fn new(huffman_tree: HuffmanTree, lookback_buffer_len: u64) -> Self {
+    pub fn new(huffman_tree: Option<HuffmanTree>, lookback_buffer_len: u64) -> Self {
         Self {
-            huffman_tree,
+            version: FORMAT_VERSION,
             lookback_buffer_len,
+            huffman_tree,
+            file_metadata: None,
+            algorithm: Algorithm::default(),
+            filter: Filter::default(),
+            metadata: BTreeMap::new(),
+            rans_table: None,
+            primed_len: 0,
+            auto_settings: None,
+            long_range: None,
         }
     }
-    pub fn to_bytes(&self) -> Vec<u8> {
-        let serialised_tree = rmp_serde::to_vec(&self.huffman_tree).unwrap();
-        // Total len is tree serialised length, [lookback_buffer_len] usize, and the size bytes
-        // this will go into
-        let total_len = serialised_tree.len() + 8 + 2;
-        if total_len > (u16::MAX as usize) {
-            panic!(
-                "length byte not enough, consider using >u16. Totaly len: {}, huffman tree: {}",
-                total_len,
-                self.huffman_tree.size()
-            );
+
+    /// Stands in for the header stored frames don't have
+    pub(crate) fn stored() -> Self {
+        Self::new(None, 0).with_algorithm(Algorithm::Stored)
+    }
+
+    pub fn with_filter(mut self, filter: Filter) -> Self {
+        self.filter = filter;
+        self
+    }
+
+    pub fn with_algorithm(mut self, algorithm: Algorithm) -> Self {
+        self.algorithm = algorithm;
+        self
+    }
+
+    pub fn with_file_metadata(mut self, file_metadata: Option<FileMetadata>) -> Self {
+        self.file_metadata = file_metadata;
+        self
+    }
+
+    pub fn with_metadata(mut self, metadata: BTreeMap<String, String>) -> Self {
+        self.metadata = metadata;
+        self
+    }
+
+    /// rANS code literals with [rans_table], in place of the huffman tree
+    pub fn with_rans_table(mut self, rans_table: Option<RansTable>) -> Self {
+        self.rans_table = rans_table;
+        self
+    }
+
+    /// Start the window with the last [primed_len] bytes the previous frame decoded to
+    pub fn with_primed_len(mut self, primed_len: u64) -> Self {
+        self.primed_len = primed_len;
+        self
+    }
+
+    pub fn with_auto_settings(mut self, auto_settings: Option<AutoSettings>) -> Self {
+        self.auto_settings = auto_settings;
+        self
+    }
+
+    /// Let matches reach [long_range] bytes back, rather than only across the window
+    pub fn with_long_range(mut self, long_range: Option<u64>) -> Self {
+        self.long_range = long_range;
+        self
+    }
+
+    pub fn version(&self) -> u8 {
+        self.version
+    }
+
+    pub fn lookback_buffer_len(&self) -> usize {
+        // Saturate rather than wrap where usize is 32 bits
+        usize::try_from(self.lookback_buffer_len).unwrap_or(usize::MAX)
+    }
+
+    /// How far back long range matches can reach, if they were made
+    pub fn long_range(&self) -> Option<u64> {
+        self.long_range
+    }
+
+    /// How much output the decoder keeps for matches to copy from: the window, or the long range
+    /// if that's further
+    pub fn history_len(&self) -> usize {
+        let long_range = usize::try_from(self.long_range.unwrap_or(0)).unwrap_or(usize::MAX);
+        self.lookback_buffer_len().max(long_range)
+    }
+
+    /// Attributes of the original file, to restore onto the decoded one
+    pub fn file_metadata(&self) -> Option<&FileMetadata> {
+        self.file_metadata.as_ref()
+    }
+
+    /// Key-value pairs given to [crate::EncodeOptions::metadata]
+    pub fn metadata(&self) -> &BTreeMap<String, String> {
+        &self.metadata
+    }
+
+    pub fn algorithm(&self) -> Algorithm {
+        self.algorithm
+    }
+
+    /// To undo on the decoded bytes
+    pub fn filter(&self) -> Filter {
+        self.filter
+    }
+
+    pub fn entropy(&self) -> Entropy {
+        match self.rans_table {
+            Some(_) => Entropy::Rans,
+            None => Entropy::Huffman,
         }
+    }
+
+    pub fn rans_table(&self) -> Option<&RansTable> {
+        self.rans_table.as_ref()
+    }
+
+    /// How many bytes of the previous frame's output the window starts with
+    pub fn primed_len(&self) -> usize {
+        usize::try_from(self.primed_len).unwrap_or(usize::MAX)
+    }
+
+    /// What [crate::EncodeOptions::auto_level] picked for the frame
+    pub fn auto_settings(&self) -> Option<AutoSettings> {
+        self.auto_settings
+    }
+
+    /// How the rest of the stream is laid out
+    pub(crate) fn format(&self) -> Format {
+        // The version was checked when the header was read
+        Format::for_version(self.version).unwrap()
+    }
 
-        let len_bytes: [u8; 2] = (total_len as u16).to_be_bytes();
-        let mut output = Vec::from(&len_bytes[..]);
-        output.extend_from_slice(&self.lookback_buffer_len.to_be_bytes());
-        output.extend(serialised_tree.iter());
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let serialised_body = rmp_serde::to_vec(&self).unwrap();
+        let mut output = Vec::from(&MAGIC[..]);
+        output.push(FORMAT_VERSION);
+        write_varint(&mut output, serialised_body.len() as u64).unwrap();
+        output.extend(serialised_body.iter());
         output
     }
 
     pub fn to_debug_bytes(&self) -> Vec<u8> {
-        let tree_size = self.huffman_tree.size();
-        let code_map_str = {
-            let code_map = crate::huffman::tree_to_code_map(&self.huffman_tree);
+        let tree_size = self.huffman_tree.as_ref().map(HuffmanTree::size);
+        let tree_dot = self.huffman_tree.as_ref().map(HuffmanTree::to_dot);
+        let code_map_str = self.huffman_tree.as_ref().map(|tree| {
+            let code_map = crate::huffman::tree_to_code_map(tree);
             code_map.to_debug_string()
-        };
+        });
         let debug_s = format!(
-            "<Header: Tree(size:{}), lookback buffer len: {}, Tree dotgraph: \n{}\nCode map: {}\n>",
+            "<Header: Version {}, Tree(size:{:?}), lookback buffer len: {}, primed len: {}, file metadata: {:?}, algorithm: {:?}, filter: {:?}, metadata: {:?}, entropy: {:?}, auto settings: {:?}, long range: {:?}, Tree dotgraph: \n{:?}\nCode map: {:?}\n>",
+            self.version,
             tree_size,
             self.lookback_buffer_len,
-            self.huffman_tree.to_dot(),
+            self.primed_len,
+            self.file_metadata,
+            self.algorithm,
+            self.filter,
+            self.metadata,
+            self.entropy(),
+            self.auto_settings,
+            self.long_range,
+            tree_dot,
             code_map_str,
         );
         debug_s.into_bytes()
     }
 
-    pub fn from_bytes(bytes: &Vec<u8>) -> Self {
-        //Assert bytes is correctly sized
-        let len = u16::from_be_bytes([bytes[0], bytes[1]]);
-        if (len as usize) != bytes.len() {
-            panic!("Not enough bytes! Expecting: {}, got: {}", len, bytes.len());
+    /// Given the first bytes of a stream, work out how long the whole header is. None if more
+    /// bytes are needed to tell
+    pub fn expected_len(prefix: &[u8]) -> Option<usize> {
+        let magic_bytes_seen = prefix.len().min(MAGIC.len());
+        if prefix[..magic_bytes_seen] == MAGIC[..magic_bytes_seen] {
+            let (body_start, body_len) = Self::body_position(prefix)?;
+            body_start.checked_add(body_len)
+        } else {
+            format::v1::header_len(prefix)
         }
+    }
 
-        let lbb_bytes_offset = 2;
-        let lbb_bytes_len = 8;
-        let be_bytes: [u8; 8] = (&bytes[lbb_bytes_offset..(lbb_bytes_offset + lbb_bytes_len)])
-            .try_into()
-            .unwrap();
-        let lookback_buffer_len = u64::from_be_bytes(be_bytes);
-        let huffman_tree =
-            rmp_serde::from_slice::<HuffmanTree>(&bytes[(lbb_bytes_offset + lbb_bytes_len)..])
-                .unwrap();
+    /// Read the header from the start of [reader], leaving it at the first token. Stored frames
+    /// have no header, so give [Header::stored] having read their magic
+    pub fn read_from<R: Read>(reader: R) -> Result<Self> {
+        Self::read_from_limited(reader, DEFAULT_MAX_HEADER_SIZE)
+    }
 
-        Self {
-            huffman_tree,
-            lookback_buffer_len,
+    /// [Header::read_from], failing on headers longer than [max_len] bytes
+    pub(crate) fn read_from_limited<R: Read>(reader: R, max_len: usize) -> Result<Self> {
+        let bytes = Self::read_bytes_from(reader, max_len)?;
+        match stored::is_stored(&bytes) {
+            true => Ok(Self::stored()),
+            false => Self::from_bytes(&bytes),
         }
     }
+
+    /// Read the header's bytes from the start of [reader] without parsing them, or just the
+    /// magic of a stored frame. Fails on headers longer than [max_len] bytes
+    pub(crate) fn read_bytes_from<R: Read>(mut reader: R, max_len: usize) -> Result<Vec<u8>> {
+        let mut bytes = Vec::new();
+        let mut read_more = |bytes: &mut Vec<u8>, len: usize| -> Result<()> {
+            match (&mut reader).take(len as u64).read_to_end(bytes)? == len {
+                true => Ok(()),
+                false => Err(Error::TruncatedStream("header")),
+            }
+        };
+        read_more(&mut bytes, stored::MAGIC.len())?;
+        if stored::is_stored(&bytes) {
+            return Ok(bytes);
+        }
+        if is_encrypted(&bytes) {
+            return Err(Error::Decryption(String::from(
+                "the header is encrypted along with everything else",
+            )));
+        }
+        let header_len = loop {
+            match Self::expected_len(&bytes) {
+                Some(header_len) => break header_len,
+                None => read_more(&mut bytes, 1)?,
+            }
+        };
+        if header_len > max_len {
+            return Err(Error::LimitExceeded {
+                limit: "header size",
+                max: max_len as u64,
+            });
+        }
+        let remaining = header_len.saturating_sub(bytes.len());
+        read_more(&mut bytes, remaining)?;
+        Ok(bytes)
+    }
+
+    /// Where the body of the versioned header starting [prefix] starts, and how long it is.
+    /// None if more bytes are needed to tell
+    fn body_position(prefix: &[u8]) -> Option<(usize, usize)> {
+        let version = *prefix.get(MAGIC.len())?;
+        let len_bytes = &prefix[VERSIONED_PREFIX_LEN..];
+        if version < VARINT_LEN_VERSION {
+            let total_len = u16::from_be_bytes([*len_bytes.first()?, *len_bytes.get(1)?]) as usize;
+            let body_start = VERSIONED_PREFIX_LEN + 2;
+            Some((body_start, total_len.saturating_sub(body_start)))
+        } else {
+            let (body_len, rest) = split_varint(len_bytes)?;
+            Some((prefix.len() - rest.len(), usize::try_from(body_len).ok()?))
+        }
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self> {
+        //Assert bytes is correctly sized
+        if Self::expected_len(bytes) != Some(bytes.len()) {
+            return Err(Error::CorruptHeader(format!(
+                "Header length doesn't match: Expecting: {:?}, got: {}",
+                Self::expected_len(bytes),
+                bytes.len()
+            )));
+        }
+
+        let header = if bytes.starts_with(MAGIC) {
+            let version = Format::for_version(bytes[MAGIC.len()])?.version;
+            // Checked to be there along with the length
+            let (body_start, _) = Self::body_position(bytes).unwrap();
+            // Every versioned header so far has the same body, thanks to optional fields
+            let mut header = rmp_serde::from_slice::<Header>(&bytes[body_start..])
+                .map_err(|e| Error::CorruptHeader(e.to_string()))?;
+            header.version = version;
+            header
+        } else {
+            let (lookback_buffer_len, huffman_tree) = format::v1::parse_header(bytes)?;
+            Self {
+                version: format::v1::FORMAT.version,
+                lookback_buffer_len,
+                huffman_tree: Some(huffman_tree),
+                file_metadata: None,
+                algorithm: Algorithm::default(),
+                filter: Filter::default(),
+                metadata: BTreeMap::new(),
+                rans_table: None,
+                primed_len: 0,
+                auto_settings: None,
+                long_range: None,
+            }
+        };
+        #[cfg(not(feature = "bwt"))]
+        if header.algorithm == Algorithm::Bwt {
+            return Err(Error::FeatureDisabled("bwt"));
+        }
+        match (&header.huffman_tree, header.algorithm) {
+            (_, Algorithm::Stored) => {
+                return Err(Error::CorruptHeader(String::from(
+                    "Stored frames don't have a header",
+                )))
+            }
+            // The decoder walks the tree without checking, so it needs to be sound before use
+            (Some(tree), _) => tree.validate_bytes()?,
+            (None, Algorithm::Lz) => (),
+            (None, Algorithm::LzHuffman) if header.rans_table.is_some() => (),
+            (None, _) => {
+                return Err(Error::CorruptHeader(String::from(
+                    "Huffman tree missing for an algorithm which needs one",
+                )))
+            }
+        }
+        // The window is primed with decoded bytes, which only match the input of an
+        // unfiltered LZ stream
+        let primable = matches!(header.algorithm, Algorithm::Lz | Algorithm::LzHuffman)
+            && header.filter == Filter::None;
+        if header.primed_len > 0 && (!primable || header.primed_len > header.lookback_buffer_len) {
+            return Err(Error::CorruptHeader(format!(
+                "Can't prime the window of a {:?} ({:?}) frame with {} bytes",
+                header.algorithm, header.filter, header.primed_len
+            )));
+        }
+        // Offsets into a long range only mean anything counted back from the end of the output
+        if header.long_range.is_some() && !header.format().relative_offsets {
+            return Err(Error::CorruptHeader(format!(
+                "Long range matches need relative offsets, which version {} doesn't have",
+                header.version
+            )));
+        }
+        Ok(header)
+    }
 }
 
+#[cfg(test)]
 mod test {
+    use std::collections::BTreeMap;
+
+    use crate::decode::decode_bytes;
+    use crate::file_io::FileMetadata;
+    use crate::format::FORMAT_VERSION;
     use crate::header::Header;
-    use crate::MAX_LOOKBACK_BUFFER_LEN;
+    use crate::huffman::HuffmanTree;
+    use crate::{encode, Algorithm, EncodeOptions, MAX_LOOKBACK_BUFFER_LEN};
 
-    #[test]
-    fn test() {
+    fn example_tree() -> HuffmanTree {
         // This example string courtesy of the wikipedia page on huffman coding
         // https://en.wikipedia.org/wiki/Huffman_coding
         let input = "A_DEAD_DAD_CEDED_A_BAD_BABE_A_BEADED_ABACA_BED";
-        // ENCODE
         let mut stats = crate::huffman::ByteStats::new();
         for byte in input.as_bytes().iter() {
-            let mut count = stats.entry(*byte).or_insert(0);
+            let count = stats.entry(u16::from(*byte)).or_insert(0);
             *count += 1;
         }
-        let huffman_tree = crate::huffman::build_tree(stats);
-        let header = Header {
-            huffman_tree,
-            lookback_buffer_len: MAX_LOOKBACK_BUFFER_LEN as u64,
-        };
+        crate::huffman::build_tree(stats)
+    }
+
+    #[test]
+    fn test() {
+        let header = Header::new(Some(example_tree()), MAX_LOOKBACK_BUFFER_LEN as u64);
 
         let header_as_bytes = header.to_bytes();
-        assert_eq!(64, header_as_bytes.len());
-        let output_header = Header::from_bytes(&header_as_bytes);
+        assert_eq!(
+            Some(header_as_bytes.len()),
+            Header::expected_len(&header_as_bytes)
+        );
+        let output_header = Header::from_bytes(&header_as_bytes).unwrap();
 
+        assert_eq!(FORMAT_VERSION, output_header.version());
         assert_eq!(header, output_header);
     }
+
+    #[test]
+    fn version_7_body() {
+        // Before the fields version 8 appended
+        let body = rmp_serde::to_vec(&(
+            MAX_LOOKBACK_BUFFER_LEN as u64,
+            Some(example_tree()),
+            None::<FileMetadata>,
+            Algorithm::LzHuffman,
+            crate::Filter::None,
+            BTreeMap::<String, String>::new(),
+        ))
+        .unwrap();
+        let mut bytes = b"LZRD\x07".to_vec();
+        crate::control::write_varint(&mut bytes, body.len() as u64).unwrap();
+        bytes.extend(body);
+        let header = Header::from_bytes(&bytes).unwrap();
+        assert_eq!(7, header.version());
+        assert_eq!(
+            Header::new(Some(example_tree()), MAX_LOOKBACK_BUFFER_LEN as u64),
+            Header {
+                version: FORMAT_VERSION,
+                ..header
+            }
+        );
+    }
+
+    #[test]
+    fn with_metadata() {
+        let file_metadata = FileMetadata {
+            modified: Some((1654732800, 123)),
+            mode: Some(0o644),
+            name: Some(String::from("notes.md")),
+            raw_name: None,
+        };
+        let metadata = BTreeMap::from([(String::from("build"), String::from("1234"))]);
+        let header = Header::new(Some(example_tree()), MAX_LOOKBACK_BUFFER_LEN as u64)
+            .with_file_metadata(Some(file_metadata.clone()))
+            .with_metadata(metadata.clone());
+        let output_header = Header::from_bytes(&header.to_bytes()).unwrap();
+        assert_eq!(Some(&file_metadata), output_header.file_metadata());
+        assert_eq!(&metadata, output_header.metadata());
+        assert_eq!(header, output_header);
+    }
+
+    #[test]
+    fn longer_than_u16() {
+        let metadata = BTreeMap::from([(String::from("big"), "x".repeat(100_000))]);
+        let header = Header::new(Some(example_tree()), MAX_LOOKBACK_BUFFER_LEN as u64)
+            .with_metadata(metadata);
+        let header_as_bytes = header.to_bytes();
+        assert!(header_as_bytes.len() > u16::MAX as usize);
+        assert_eq!(
+            Some(header_as_bytes.len()),
+            Header::expected_len(&header_as_bytes)
+        );
+        assert_eq!(header, Header::from_bytes(&header_as_bytes).unwrap());
+    }
+
+    #[test]
+    fn u16_length() {
+        // Versions 2 to 6 give the whole header's length as a u16
+        let header = Header::new(Some(example_tree()), MAX_LOOKBACK_BUFFER_LEN as u64);
+        let body = rmp_serde::to_vec(&header).unwrap();
+        let mut header_as_bytes = b"LZRD\x06".to_vec();
+        header_as_bytes.extend_from_slice(&(body.len() as u16 + 7).to_be_bytes());
+        header_as_bytes.extend_from_slice(&body);
+        assert_eq!(
+            Some(header_as_bytes.len()),
+            Header::expected_len(&header_as_bytes)
+        );
+        let output_header = Header::from_bytes(&header_as_bytes).unwrap();
+        assert_eq!(6, output_header.version());
+        assert_eq!(
+            header.lookback_buffer_len(),
+            output_header.lookback_buffer_len()
+        );
+    }
+
+    #[test]
+    fn version_1() {
+        // The header of a file encoded before headers were versioned
+        let v1_file = include_bytes!("../testfile.lizard");
+        let len = Header::expected_len(v1_file).unwrap();
+        let header = Header::from_bytes(&v1_file[..len]).unwrap();
+        assert_eq!(1, header.version());
+        assert_eq!(MAX_LOOKBACK_BUFFER_LEN, header.lookback_buffer_len());
+        assert_eq!(None, header.file_metadata());
+        assert!(header.metadata().is_empty());
+    }
+
+    #[test]
+    fn read_from() {
+        let input = "The cat sat on the mat. ".repeat(100);
+        let options = EncodeOptions::default().metadata("source", "cats.txt");
+        let mut encoded = Vec::new();
+        encode::encode_from_reader(input.as_bytes(), &mut encoded, &options).unwrap();
+        let header = Header::read_from(&encoded[..]).unwrap();
+        assert_eq!(
+            Some(&String::from("cats.txt")),
+            header.metadata().get("source")
+        );
+        assert_eq!(input.as_bytes(), decode_bytes(&encoded).unwrap());
+        // Cut short within the header
+        assert!(Header::read_from(&encoded[..10]).is_err());
+
+        let options = EncodeOptions::default().algorithm(Algorithm::Stored);
+        encoded.clear();
+        encode::encode_from_reader(input.as_bytes(), &mut encoded, &options).unwrap();
+        assert_eq!(
+            Algorithm::Stored,
+            Header::read_from(&encoded[..]).unwrap().algorithm()
+        );
+    }
 }
@@ -1,60 +1,71 @@
 use log::debug;
 use std::collections::VecDeque;
-use std::fs::File;
-use std::io::{BufReader, Read};
+use std::io::{ErrorKind, Read, Write};
 
-use crate::huffman::ByteStats;
-use crate::{MAX_LOOKBACK_BUFFER_LEN, MAX_READ_BUFFER_LEN};
+use crate::MAX_READ_BUFFER_LEN;
 
-pub fn read_buffer_to_string(vec: &VecDeque<u8>) -> String {
-    let mut v = Vec::new();
-    v.extend(vec.as_slices().0);
-    v.extend(vec.as_slices().1);
-    String::from_utf8(v).unwrap()
+/// The next byte of [reader], read into [input_buffer], or None at its end
+pub fn read_byte<R: Read>(reader: &mut R, input_buffer: &mut [u8]) -> std::io::Result<Option<u8>> {
+    loop {
+        return match reader.read(input_buffer) {
+            Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+            Err(e) => Err(e),
+            Ok(0) => {
+                debug!("Got zero bytes");
+                Ok(None)
+            }
+            Ok(1) => Ok(Some(input_buffer[0])),
+            Ok(n) => {
+                panic!("Sadness, got more than 1 byte on [read] : {}", n)
+            }
+        };
+    }
 }
 
-pub fn step_buffers(
+pub fn step_buffers<R: Read>(
     n: usize,
-    reader: &mut BufReader<File>,
+    reader: &mut R,
     input_buffer: &mut [u8],
     read_buffer: &mut VecDeque<u8>,
     lookback_buffer: &mut VecDeque<u8>,
+    lookback_buffer_len: usize,
     always_drain_read: bool,
-) {
+) -> std::io::Result<()> {
     for _i in 0..n {
-        let read = reader.read(input_buffer);
-        match read {
-            Err(e) => panic!("Error reading file: {}", e),
-            Ok(0) => {
-                debug!("Got zero bytes");
-                if always_drain_read {
-                    // TODO: Unwind duplicated code
-                    let transfer = read_buffer.pop_front();
-                    if let Some(v) = transfer {
-                        lookback_buffer.push_back(v);
-                        if lookback_buffer.len() > MAX_LOOKBACK_BUFFER_LEN {
-                            lookback_buffer.pop_front();
-                        }
-                    }
-                }
-            }
-            Ok(1) => {
-                read_buffer.push_back(input_buffer[0]);
-                if read_buffer.len() > MAX_READ_BUFFER_LEN || always_drain_read {
-                    let transfer = read_buffer.pop_front();
-                    if let Some(v) = transfer {
-                        lookback_buffer.push_back(v);
-                        if lookback_buffer.len() > MAX_LOOKBACK_BUFFER_LEN {
-                            lookback_buffer.pop_front();
-                        }
-                    }
+        if always_drain_read || read_buffer.len() >= MAX_READ_BUFFER_LEN {
+            let transfer = read_buffer.pop_front();
+            if let Some(v) = transfer {
+                lookback_buffer.push_back(v);
+                if lookback_buffer.len() > lookback_buffer_len {
+                    lookback_buffer.pop_front();
                 }
             }
-            Ok(n) => {
-                panic!("Sadness, got more than 1 byte on [read] : {}", n)
+        }
+        // The read buffer can be left longer than usual after a long match, only top it up
+        // once it's back under size
+        if read_buffer.len() < MAX_READ_BUFFER_LEN {
+            if let Some(v) = read_byte(reader, input_buffer)? {
+                read_buffer.push_back(v);
             }
         }
     }
+    Ok(())
+}
+
+/// Hex dump of at most the last [max_len] bytes of [buffer], for logging buffers that may not
+/// be text
+pub fn hex_dump_tail(buffer: &VecDeque<u8>, max_len: usize) -> String {
+    let skip = buffer.len().saturating_sub(max_len);
+    let hex = buffer
+        .iter()
+        .skip(skip)
+        .map(|x| format!("{:02x}", x))
+        .collect::<Vec<String>>()
+        .join(" ");
+    match skip {
+        0 => hex,
+        skip => format!("({} bytes skipped) {}", skip, hex),
+    }
 }
 
 pub fn u8_iter_str<'a, I: Iterator<Item = &'a u8>>(i: I) -> String {
@@ -62,3 +73,266 @@ pub fn u8_iter_str<'a, I: Iterator<Item = &'a u8>>(i: I) -> String {
         .collect::<Vec<String>>()
         .join(", ")
 }
+
+/// Passes reads through from [inner], keeping count of the bytes and their CRC32 on the way
+pub struct CountingReader<R: Read> {
+    inner: R,
+    count: u64,
+    hasher: crc32fast::Hasher,
+}
+
+impl<R: Read> CountingReader<R> {
+    pub fn new(inner: R) -> Self {
+        Self {
+            inner,
+            count: 0,
+            hasher: crc32fast::Hasher::new(),
+        }
+    }
+
+    pub fn count(&self) -> u64 {
+        self.count
+    }
+
+    pub fn checksum(&self) -> u32 {
+        self.hasher.clone().finalize()
+    }
+}
+
+impl<R: Read> Read for CountingReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let read = self.inner.read(buf)?;
+        self.count += read as u64;
+        self.hasher.update(&buf[..read]);
+        Ok(read)
+    }
+}
+
+/// Passes writes through to [inner], keeping count of the bytes and their CRC32 on the way
+pub struct CountingWriter<W: Write> {
+    inner: W,
+    count: u64,
+    hasher: crc32fast::Hasher,
+}
+
+impl<W: Write> CountingWriter<W> {
+    pub fn new(inner: W) -> Self {
+        Self {
+            inner,
+            count: 0,
+            hasher: crc32fast::Hasher::new(),
+        }
+    }
+
+    pub fn count(&self) -> u64 {
+        self.count
+    }
+
+    pub fn checksum(&self) -> u32 {
+        self.hasher.clone().finalize()
+    }
+
+    pub fn get_mut(&mut self) -> &mut W {
+        &mut self.inner
+    }
+
+    /// Start counting again from nothing
+    pub fn reset(&mut self) {
+        self.count = 0;
+        self.hasher = crc32fast::Hasher::new();
+    }
+}
+
+impl<W: Write> Write for CountingWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let written = self.inner.write(buf)?;
+        self.count += written as u64;
+        self.hasher.update(&buf[..written]);
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// Passes writes through to [inner], keeping the last [keep] bytes written since the tail was
+/// last taken
+pub struct TailWriter<W: Write> {
+    inner: W,
+    keep: usize,
+    tail: VecDeque<u8>,
+}
+
+impl<W: Write> TailWriter<W> {
+    pub fn new(inner: W, keep: usize) -> Self {
+        Self {
+            inner,
+            keep,
+            tail: VecDeque::new(),
+        }
+    }
+
+    pub fn get_ref(&self) -> &W {
+        &self.inner
+    }
+
+    /// The last bytes written, starting over with an empty tail
+    pub fn take_tail(&mut self) -> Vec<u8> {
+        std::mem::take(&mut self.tail).into()
+    }
+
+    /// Add [bytes] to the tail as if they'd been written, for bytes [inner] already holds
+    pub fn remember(&mut self, bytes: &[u8]) {
+        self.tail
+            .extend(&bytes[bytes.len().saturating_sub(self.keep)..]);
+        let excess = self.tail.len().saturating_sub(self.keep);
+        self.tail.drain(..excess);
+    }
+}
+
+impl<W: Write> Write for TailWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let written = self.inner.write(buf)?;
+        self.remember(&buf[..written]);
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// Passes writes and flushes through to [inner], trying them again when they're interrupted
+/// rather than giving up on the whole stream. [Write::write_all] already does so for writes, but
+/// a plain [Write::write] or [Write::flush] doesn't
+pub struct RetryWriter<W: Write> {
+    inner: W,
+}
+
+impl<W: Write> RetryWriter<W> {
+    pub fn new(inner: W) -> Self {
+        Self { inner }
+    }
+}
+
+impl<W: Write> Write for RetryWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        loop {
+            match self.inner.write(buf) {
+                Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+                result => return result,
+            }
+        }
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        loop {
+            match self.inner.flush() {
+                Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+                result => return result,
+            }
+        }
+    }
+}
+
+/// Reads [inner], failing every read from [fail_at] bytes in, like a disk error part way
+/// through a file. Every other read is interrupted, which readers are expected to retry
+#[cfg(test)]
+pub struct FailingReader<'a> {
+    pub inner: std::io::Cursor<&'a [u8]>,
+    pub fail_at: Option<u64>,
+    pub interrupted: bool,
+}
+
+#[cfg(test)]
+impl Read for FailingReader<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        self.interrupted = !self.interrupted;
+        if self.interrupted {
+            return Err(ErrorKind::Interrupted.into());
+        }
+        match self.fail_at {
+            Some(fail_at) if self.inner.position() >= fail_at => {
+                Err(std::io::Error::other("Input/output error"))
+            }
+            Some(fail_at) => {
+                let len = buf.len().min((fail_at - self.inner.position()) as usize);
+                self.inner.read(&mut buf[..len])
+            }
+            None => self.inner.read(buf),
+        }
+    }
+}
+
+#[cfg(test)]
+impl std::io::Seek for FailingReader<'_> {
+    fn seek(&mut self, pos: std::io::SeekFrom) -> std::io::Result<u64> {
+        self.inner.seek(pos)
+    }
+}
+
+/// Takes the first [accept] bytes written to it, as short writes where they don't all fit, then
+/// fails every write after that, like a full disk
+#[cfg(test)]
+pub struct FailingWriter {
+    pub accept: usize,
+}
+
+#[cfg(test)]
+impl Write for FailingWriter {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        if self.accept == 0 {
+            return Err(std::io::Error::other("No space left"));
+        }
+        let written = buf.len().min(self.accept);
+        self.accept -= written;
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Writes at most [max] bytes at a time to [inner], and is interrupted every other write or
+/// flush, as a pipe or socket can be
+#[cfg(test)]
+pub struct ShortWriter {
+    pub inner: Vec<u8>,
+    pub max: usize,
+    interrupt: bool,
+}
+
+#[cfg(test)]
+impl ShortWriter {
+    pub fn new(max: usize) -> Self {
+        Self {
+            inner: Vec::new(),
+            max,
+            interrupt: false,
+        }
+    }
+
+    fn interrupted(&mut self) -> bool {
+        self.interrupt = !self.interrupt;
+        self.interrupt
+    }
+}
+
+#[cfg(test)]
+impl Write for ShortWriter {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        if self.interrupted() {
+            return Err(ErrorKind::Interrupted.into());
+        }
+        self.inner.write(&buf[..buf.len().min(self.max)])
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        match self.interrupted() {
+            true => Err(ErrorKind::Interrupted.into()),
+            false => Ok(()),
+        }
+    }
+}
@@ -0,0 +1,375 @@
+//! A minimal HTTP/1.1 server and client for the http feature, which serve files compressed on
+//! the fly with `Content-Encoding: lizard` to clients that ask for it. It's a demo of the
+//! streaming encoder and decoder rather than a web server: only GET is handled, each
+//! connection serves one request, and files come from a single directory
+
+use log::{info, warn};
+use std::fs::File;
+use std::io::{BufRead, BufReader, BufWriter, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::path::{Path, PathBuf};
+
+use crate::decode::{self, DecodeOptions};
+use crate::encode::{EncodeOptions, StreamEncoder};
+use crate::error::{Error, Result};
+use crate::helpers::CountingReader;
+
+/// Name of the encoding in `Accept-Encoding` and `Content-Encoding` headers
+pub const CONTENT_ENCODING: &str = "lizard";
+/// Requests and responses with a longer status line and headers are refused
+const MAX_HEAD_LEN: u64 = 16 * 1024;
+
+/// What [fetch] got
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct FetchSummary {
+    /// Of the body as it was sent
+    pub input_bytes: u64,
+    /// Of the body once decoded
+    pub output_bytes: u64,
+    /// Whether the server sent the body lizards encoded
+    pub encoded: bool,
+}
+
+/// Serve the files under [root] to connections on [listener], each on a thread of its own,
+/// until accepting a connection fails
+pub fn serve(listener: TcpListener, root: &Path, options: &EncodeOptions) -> Result<()> {
+    for stream in listener.incoming() {
+        let stream = stream?;
+        let (root, options) = (root.to_path_buf(), options.clone());
+        std::thread::spawn(move || {
+            let peer = stream.peer_addr().ok();
+            if let Err(e) = handle_connection(stream, &root, &options) {
+                warn!("Serving {:?} failed: {}", peer, e);
+            }
+        });
+    }
+    Ok(())
+}
+
+fn handle_connection(stream: TcpStream, root: &Path, options: &EncodeOptions) -> Result<()> {
+    let mut reader = BufReader::new(stream.try_clone()?);
+    let mut writer = BufWriter::new(stream);
+    let head = read_head(&mut reader)?;
+    let mut request_line = head[0].split(' ');
+    let (method, target) = (request_line.next(), request_line.next().unwrap_or_default());
+    if method != Some("GET") {
+        return respond_with_status(writer, "405 Method Not Allowed");
+    }
+    let file = resolve(root, target).and_then(|path| File::open(path).ok());
+    let mut file = match file {
+        Some(file) if file.metadata()?.is_file() => file,
+        _ => return respond_with_status(writer, "404 Not Found"),
+    };
+    let lizard = header_value(&head, "accept-encoding").is_some_and(|encodings| {
+        encodings
+            .split(',')
+            .filter_map(|encoding| encoding.split(';').next())
+            .any(|encoding| encoding.trim().eq_ignore_ascii_case(CONTENT_ENCODING))
+    });
+    info!(
+        "GET {} ({})",
+        target,
+        if lizard { "lizard" } else { "identity" }
+    );
+    if lizard {
+        write!(
+            writer,
+            "HTTP/1.1 200 OK\r\nContent-Encoding: {}\r\nTransfer-Encoding: chunked\r\n\
+             Vary: Accept-Encoding\r\nConnection: close\r\n\r\n",
+            CONTENT_ENCODING
+        )?;
+        let mut encoder = StreamEncoder::new(ChunkedWriter(writer), options);
+        std::io::copy(&mut file, &mut encoder)?;
+        let (ChunkedWriter(mut writer), _) = encoder.finish()?;
+        writer.write_all(b"0\r\n\r\n")?;
+        writer.flush()?;
+    } else {
+        write!(
+            writer,
+            "HTTP/1.1 200 OK\r\nContent-Length: {}\r\nVary: Accept-Encoding\r\n\
+             Connection: close\r\n\r\n",
+            file.metadata()?.len()
+        )?;
+        std::io::copy(&mut file, &mut writer)?;
+        writer.flush()?;
+    }
+    Ok(())
+}
+
+fn respond_with_status<W: Write>(mut writer: W, status: &str) -> Result<()> {
+    info!("Responding {}", status);
+    write!(
+        writer,
+        "HTTP/1.1 {}\r\nContent-Length: 0\r\nConnection: close\r\n\r\n",
+        status
+    )?;
+    writer.flush()?;
+    Ok(())
+}
+
+/// The file under [root] that the request [target] names, if it doesn't reach outside [root]
+fn resolve(root: &Path, target: &str) -> Option<PathBuf> {
+    let path = target.split('?').next()?.strip_prefix('/')?;
+    let mut resolved = root.to_path_buf();
+    for segment in path.split('/').filter(|segment| !segment.is_empty()) {
+        let segment = percent_decode(segment)?;
+        if segment == "." || segment == ".." || segment.contains(['/', '\\']) {
+            return None;
+        }
+        resolved.push(segment);
+    }
+    Some(resolved)
+}
+
+fn percent_decode(segment: &str) -> Option<String> {
+    let mut bytes = Vec::with_capacity(segment.len());
+    let mut rest = segment.as_bytes();
+    while let Some((&byte, after)) = rest.split_first() {
+        rest = after;
+        if byte != b'%' {
+            bytes.push(byte);
+            continue;
+        }
+        let hex = std::str::from_utf8(rest.get(..2)?).ok()?;
+        bytes.push(u8::from_str_radix(hex, 16).ok()?);
+        rest = &rest[2..];
+    }
+    String::from_utf8(bytes).ok()
+}
+
+/// GET [url] (`http://host[:port]/path`), asking for it lizards encoded, and write the decoded
+/// body to [writer] as it arrives. Responses other than 200 are an error
+pub fn fetch<W: Write>(url: &str, mut writer: W, options: &DecodeOptions) -> Result<FetchSummary> {
+    let rest = url
+        .strip_prefix("http://")
+        .ok_or_else(|| protocol_error(format!("Only http:// URLs are supported, got {}", url)))?;
+    let (host, path) = match rest.find('/') {
+        Some(index) => rest.split_at(index),
+        None => (rest, "/"),
+    };
+    let address = match host.contains(':') {
+        true => host.to_string(),
+        false => format!("{}:80", host),
+    };
+    let stream = TcpStream::connect(address)?;
+    let mut request = BufWriter::new(stream.try_clone()?);
+    write!(
+        request,
+        "GET {} HTTP/1.1\r\nHost: {}\r\nAccept-Encoding: {}\r\nConnection: close\r\n\r\n",
+        path, host, CONTENT_ENCODING
+    )?;
+    request.flush()?;
+
+    let mut reader = BufReader::new(stream);
+    let head = read_head(&mut reader)?;
+    if head[0].split(' ').nth(1) != Some("200") {
+        return Err(protocol_error(format!("Server responded {}", head[0])));
+    }
+    let encoded = match header_value(&head, "content-encoding") {
+        None => false,
+        Some(encoding) if encoding.eq_ignore_ascii_case("identity") => false,
+        Some(encoding) if encoding.eq_ignore_ascii_case(CONTENT_ENCODING) => true,
+        Some(encoding) => {
+            return Err(protocol_error(format!(
+                "Unsupported content encoding {}",
+                encoding
+            )))
+        }
+    };
+    let chunked = header_value(&head, "transfer-encoding")
+        .is_some_and(|encoding| encoding.eq_ignore_ascii_case("chunked"));
+    let content_length = header_value(&head, "content-length").and_then(|len| len.parse().ok());
+    let body: Box<dyn Read> = match (chunked, content_length) {
+        (true, _) => Box::new(ChunkedReader::new(reader)),
+        (false, Some(len)) => Box::new(reader.take(len)),
+        (false, None) => Box::new(reader),
+    };
+    let mut body = CountingReader::new(body);
+    let output_bytes = match encoded {
+        true => {
+            let body = BufReader::new(&mut body);
+            decode::decode_to_writer_with_options(body, &mut writer, options)?
+        }
+        false => std::io::copy(&mut body, &mut writer)?,
+    };
+    writer.flush()?;
+    Ok(FetchSummary {
+        input_bytes: body.count(),
+        output_bytes,
+        encoded,
+    })
+}
+
+fn protocol_error(message: String) -> Error {
+    Error::Io(std::io::Error::new(
+        std::io::ErrorKind::InvalidData,
+        message,
+    ))
+}
+
+/// The status or request line followed by the headers, up to the blank line ending them
+fn read_head<R: BufRead>(reader: &mut R) -> Result<Vec<String>> {
+    let mut limited = reader.take(MAX_HEAD_LEN);
+    let mut head = Vec::new();
+    loop {
+        let mut line = String::new();
+        if limited.read_line(&mut line)? == 0 {
+            return Err(protocol_error(String::from(
+                "Connection closed before the end of the headers",
+            )));
+        }
+        let line = line.trim_end_matches(['\r', '\n']);
+        if line.is_empty() && !head.is_empty() {
+            return Ok(head);
+        }
+        head.push(line.to_string());
+    }
+}
+
+/// The value of header [name] in [head], matched ignoring case
+fn header_value<'a>(head: &'a [String], name: &str) -> Option<&'a str> {
+    head.iter().skip(1).find_map(|line| {
+        let (key, value) = line.split_once(':')?;
+        key.trim().eq_ignore_ascii_case(name).then(|| value.trim())
+    })
+}
+
+/// Sends each write as a chunk of a chunked body. The caller ends the body
+struct ChunkedWriter<W: Write>(W);
+
+impl<W: Write> Write for ChunkedWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        if !buf.is_empty() {
+            write!(self.0, "{:x}\r\n", buf.len())?;
+            self.0.write_all(buf)?;
+            self.0.write_all(b"\r\n")?;
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.0.flush()
+    }
+}
+
+/// Reads a chunked body, ending with its last chunk
+struct ChunkedReader<R: BufRead> {
+    inner: R,
+    /// Left of the current chunk
+    remaining: u64,
+    /// Whether a chunk has been read, which is followed by a CRLF
+    in_body: bool,
+    done: bool,
+}
+
+impl<R: BufRead> ChunkedReader<R> {
+    fn new(inner: R) -> Self {
+        Self {
+            inner,
+            remaining: 0,
+            in_body: false,
+            done: false,
+        }
+    }
+
+    fn read_line(&mut self) -> std::io::Result<String> {
+        let mut line = String::new();
+        if (&mut self.inner).take(MAX_HEAD_LEN).read_line(&mut line)? == 0 {
+            return Err(std::io::ErrorKind::UnexpectedEof.into());
+        }
+        Ok(line.trim_end_matches(['\r', '\n']).to_string())
+    }
+}
+
+impl<R: BufRead> Read for ChunkedReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        if self.done || buf.is_empty() {
+            return Ok(0);
+        }
+        if self.remaining == 0 {
+            if self.in_body {
+                self.read_line()?;
+            }
+            self.in_body = true;
+            let size_line = self.read_line()?;
+            let size = size_line.split(';').next().unwrap_or_default().trim();
+            self.remaining = u64::from_str_radix(size, 16).map_err(|_| {
+                std::io::Error::new(
+                    std::io::ErrorKind::InvalidData,
+                    format!("Invalid chunk size {:?}", size_line),
+                )
+            })?;
+            if self.remaining == 0 {
+                // Skip any trailers
+                while !self.read_line()?.is_empty() {}
+                self.done = true;
+                return Ok(0);
+            }
+        }
+        let len = (buf.len() as u64).min(self.remaining) as usize;
+        let read = self.inner.read(&mut buf[..len])?;
+        if read == 0 {
+            return Err(std::io::ErrorKind::UnexpectedEof.into());
+        }
+        self.remaining -= read as u64;
+        Ok(read)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::{Read, Write};
+    use std::net::{TcpListener, TcpStream};
+
+    use crate::http::{fetch, serve};
+    use crate::test_util::temp_path;
+    use crate::{DecodeOptions, EncodeOptions};
+
+    #[test]
+    fn serve_and_fetch() {
+        let root = temp_path("http");
+        std::fs::create_dir_all(&root).unwrap();
+        let text: Vec<u8> = (0..20_000)
+            .flat_map(|i| format!("request {} of many, ", i % 17).into_bytes())
+            .collect();
+        std::fs::write(root.join("some text.txt"), &text).unwrap();
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        let served = root.clone();
+        std::thread::spawn(move || serve(listener, &served, &EncodeOptions::default()));
+
+        let url = format!("http://{}/some%20text.txt", address);
+        let mut fetched = Vec::new();
+        let summary = fetch(&url, &mut fetched, &DecodeOptions::default()).unwrap();
+        assert!(summary.encoded);
+        assert_eq!(fetched, text);
+        assert_eq!(summary.output_bytes, text.len() as u64);
+        assert!(summary.input_bytes < text.len() as u64 / 4);
+
+        // Clients that don't ask for lizards encoding get the file as it is
+        let mut stream = TcpStream::connect(address).unwrap();
+        write!(
+            stream,
+            "GET /some%20text.txt HTTP/1.1\r\nHost: test\r\n\r\n"
+        )
+        .unwrap();
+        let mut response = Vec::new();
+        stream.read_to_end(&mut response).unwrap();
+        let head_len = response.windows(4).position(|w| w == b"\r\n\r\n").unwrap() + 4;
+        let head = String::from_utf8_lossy(&response[..head_len]);
+        assert!(head.starts_with("HTTP/1.1 200 OK"));
+        assert!(!head.contains("Content-Encoding"));
+        assert_eq!(&response[head_len..], &text[..]);
+
+        for missing in [
+            "/missing.txt",
+            "/../some%20text.txt",
+            "/%2e%2e/etc/passwd",
+            "/",
+        ] {
+            let url = format!("http://{}{}", address, missing);
+            assert!(fetch(&url, Vec::new(), &DecodeOptions::default()).is_err());
+        }
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+}
@@ -1,26 +1,113 @@
-use priority_queue::double_priority_queue::DoublePriorityQueue;
 use serde::{Deserialize, Serialize};
-use std::collections::hash_map::Entry::Vacant;
 use std::collections::HashMap;
 use std::fmt::{Debug, Formatter};
+use std::io::Read;
 use std::sync::atomic::{AtomicUsize, Ordering};
 
-pub type ByteStats = HashMap<u8, usize>;
+use crate::bit_io::{BitReader, BitWriter};
+use crate::error::{Error, Result};
 
-#[derive(Debug)]
+/// Codes are held in [Bits], so no leaf can be deeper than this. Counts fit in a u64, and a
+/// huffman tree over those can't get deeper than about 92, so built trees always fit
+const MAX_CODE_LEN: usize = Bits::MAX_LEN;
+
+/// A value to huffman code. Literals are bytes, but wider symbols let other codes (e.g. match
+/// lengths) share an alphabet with them, as in DEFLATE
+pub type Symbol = u16;
+
+/// How many times each symbol turns up
+pub type ByteStats = HashMap<Symbol, u64>;
+
+/// The code each symbol (and the END code) is written as
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub struct CodeMap {
-    codes: HashMap<u8, Bits>,
+    /// Indexed by symbol, None for symbols without a code. Always covers every byte value
+    codes: Vec<Option<Bits>>,
     end_code: Bits,
 }
 impl CodeMap {
-    pub fn new(codes: HashMap<u8, Bits>, end_code: Bits) -> Self {
-        Self { codes, end_code }
+    pub fn new(codes: HashMap<Symbol, Bits>, end_code: Bits) -> Self {
+        let len = codes
+            .keys()
+            .map(|value| *value as usize + 1)
+            .max()
+            .unwrap_or(0);
+        let mut code_table = vec![None; len.max(256)];
+        for (value, bits) in codes {
+            code_table[value as usize] = Some(bits);
+        }
+        Self {
+            codes: code_table,
+            end_code,
+        }
+    }
+
+    /// Canonical codes from each symbol's code length in bits, indexed by symbol and 0 for
+    /// symbols without a code. Codes are handed out shortest first, ties in symbol order, with
+    /// the END code last at the longest length. None unless that makes a complete prefix code,
+    /// i.e. every bit pattern leads somewhere, which the decoder relies on
+    pub fn from_lengths(lengths: &[u8]) -> Option<Self> {
+        if lengths.len() > Symbol::MAX as usize + 1 {
+            return None;
+        }
+        let end_len = *lengths.iter().max().unwrap_or(&0).max(&1) as u32;
+        if end_len as usize > MAX_CODE_LEN {
+            return None;
+        }
+        let mut symbols: Vec<(u32, Option<Symbol>)> = (0..lengths.len())
+            .filter(|value| lengths[*value] > 0)
+            .map(|value| (lengths[value] as u32, Some(value as Symbol)))
+            .collect();
+        symbols.sort();
+        symbols.push((end_len, None));
+
+        let mut codes = vec![None; lengths.len().max(256)];
+        let mut end_code = None;
+        // The next code, as a fraction of the code space with [end_len] bits of precision. None
+        // once a code has taken the last of a full 128 bit space
+        let mut next = Some(0u128);
+        for (len, value) in symbols {
+            // Past the end of the code space, so the lengths are too short
+            let code = next.filter(|next| next.checked_shr(end_len).unwrap_or(0) == 0)?;
+            let bits = Bits {
+                set_bits: code >> (end_len - len),
+                bit_size: len as usize,
+            };
+            match value {
+                Some(value) => codes[value as usize] = Some(bits),
+                None => end_code = Some(bits),
+            }
+            next = code.checked_add(1 << (end_len - len));
+        }
+        // END goes last and is one of the longest, so the code space is exactly filled when it
+        // takes the last code. Anything else leaves patterns leading nowhere
+        let end_code = end_code.unwrap();
+        if end_code.set_bits != u128::MAX >> (u128::BITS - end_len) {
+            return None;
+        }
+        Some(Self { codes, end_code })
+    }
+
+    /// Every symbol with a code, in symbol order
+    pub fn codes(&self) -> impl Iterator<Item = (Symbol, &Bits)> {
+        self.codes
+            .iter()
+            .enumerate()
+            .filter_map(|(value, bits)| bits.as_ref().map(|bits| (value as Symbol, bits)))
+    }
+
+    pub fn code_for(&self, value: impl Into<Symbol>) -> Option<&Bits> {
+        self.codes.get(value.into() as usize)?.as_ref()
+    }
+
+    /// Marks the end of a bitstream
+    pub fn end_code(&self) -> &Bits {
+        &self.end_code
     }
 
     pub fn to_debug_string(&self) -> String {
         let codes = self
-            .codes
-            .iter()
+            .codes()
             .map(|(val, bits)| format!("Val : {} -> {:?}", val, bits))
             .collect::<Vec<String>>()
             .join("\n");
@@ -29,14 +116,14 @@ impl CodeMap {
     }
 }
 
-#[derive(Debug, Serialize, Deserialize, PartialEq)]
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
 pub struct HuffmanTree {
     root_node: Option<Box<Node>>,
 }
 
-#[derive(Debug, Hash, PartialEq, Eq, Serialize, Deserialize)]
+#[derive(Debug, Clone, Hash, PartialEq, Eq, Serialize, Deserialize)]
 struct Node {
-    value: Option<u8>, //Only leaves have values
+    value: Option<Symbol>, //Only leaves have values
     left: Option<Box<Node>>,
     right: Option<Box<Node>>,
 
@@ -45,41 +132,88 @@ struct Node {
     is_end_node: bool,
 }
 
-#[derive(Clone, Hash, PartialEq, Eq)]
-/// Supports an encoded bit pattern from two bits (e.g. 10) up to 64 bits
+#[derive(Clone, Default, Hash, PartialEq, Eq)]
+/// A string of up to [Bits::MAX_LEN] bits, such as a huffman code. The first bit pushed is the
+/// first one written
 /// [bit_size] informs the user how many on the least signifigant bits of [set_bits] to care about
 pub struct Bits {
-    set_bits: u64,
+    set_bits: u128,
     bit_size: usize,
 }
 
+/// The longest code [build_tree] gives any value, as in DEFLATE. Keeps codes well within [Bits]
+/// and bounds the decoder's tables, at a small cost in ratio for very skewed inputs
+pub const MAX_HUFFMAN_CODE_LEN: usize = 15;
+
+/// A canonical huffman tree for [stats] with no code longer than [MAX_HUFFMAN_CODE_LEN]
 pub fn build_tree(stats: ByteStats) -> HuffmanTree {
-    let mut tree: HuffmanTree = HuffmanTree { root_node: None };
-    let mut priority_queue: DoublePriorityQueue<Node, usize> = DoublePriorityQueue::new();
+    let lengths = code_lengths(&stats, MAX_HUFFMAN_CODE_LEN).unwrap();
+    HuffmanTree::from_code_map(&CodeMap::from_lengths(&lengths).unwrap())
+}
 
-    for (val, count) in stats.iter() {
-        priority_queue.push(Node::new_leaf(*val), *count);
+/// The optimal code length for each symbol in [stats], along with an END code, given no code
+/// may be longer than [max_code_len] bits. Lengths are in the form [CodeMap::from_lengths]
+/// takes, covering at least every byte value, and END is always one of the longest. None if
+/// [max_code_len] is too short to give every symbol a code
+///
+/// Uses package-merge: each value is a coin worth 2^-len for each bit of its code, and picking
+/// the cheapest 2n - 2 coins from the merged packages of [max_code_len] denominations gives
+/// the lengths
+pub fn code_lengths(stats: &ByteStats, max_code_len: usize) -> Option<Vec<u8>> {
+    // END costs nothing and goes first, so it's never shorter than anything else. Ties are
+    // broken on value, as [stats] is a HashMap whose order varies between runs
+    let mut symbols: Vec<(u128, Option<Symbol>)> = stats
+        .iter()
+        .map(|(value, count)| (*count as u128, Some(*value)))
+        .collect();
+    // With nothing to code, END would be the only leaf and have no code at all, so it's given
+    // an unused neighbour to make a two leaf tree
+    if symbols.is_empty() {
+        symbols.push((0, Some(0)));
     }
-
-    // add end_node as lowest frequency pair
-    {
-        let (node, count) = priority_queue.pop_min().unwrap();
-        let combined_node = Node::new_vertex(Some(Box::new(node)), Some(Box::new(Node::new_end())));
-        priority_queue.push(combined_node, count);
+    symbols.push((0, None));
+    symbols.sort();
+    let n = symbols.len();
+    if max_code_len > Bits::MAX_LEN || (n - 1) >> max_code_len.min(usize::BITS as usize - 1) > 0 {
+        return None;
     }
 
-    // Pick off two lowest, and combine
-    while priority_queue.len() > 1 {
-        let (node0, count0) = priority_queue.pop_min().unwrap();
-        let (node1, count1) = priority_queue.pop_min().unwrap();
-        let combined_node = Node::new_vertex(Some(Box::new(node0)), Some(Box::new(node1)));
-        priority_queue.push(combined_node, count0 + count1);
+    // Each item is its weight and how many of each symbol's coins it holds
+    let leaves: Vec<(u128, Vec<u8>)> = symbols
+        .iter()
+        .enumerate()
+        .map(|(i, (count, _))| {
+            let mut coins = vec![0; n];
+            coins[i] = 1;
+            (*count, coins)
+        })
+        .collect();
+    let mut items = leaves.clone();
+    for _ in 1..max_code_len {
+        let packages = items.chunks_exact(2).map(|pair| {
+            let coins = pair[0].1.iter().zip(&pair[1].1).map(|(a, b)| a + b);
+            (pair[0].0 + pair[1].0, coins.collect())
+        });
+        let mut merged: Vec<(u128, Vec<u8>)> = leaves.iter().cloned().chain(packages).collect();
+        // Stable, so leaves stay ahead of packages of the same weight
+        merged.sort_by_key(|(weight, _)| *weight);
+        items = merged;
     }
 
-    //Now our only element in the queue is our root node
-    let (root_node, _count) = priority_queue.pop_min().unwrap();
-    tree.root_node = Some(Box::new(root_node));
-    tree
+    let len = stats
+        .keys()
+        .map(|value| *value as usize + 1)
+        .max()
+        .unwrap_or(0);
+    let mut lengths = vec![0u8; len.max(256)];
+    for (_, coins) in items.iter().take(2 * n - 2) {
+        for (i, count) in coins.iter().enumerate() {
+            if let Some(value) = symbols[i].1 {
+                lengths[value as usize] += count;
+            }
+        }
+    }
+    Some(lengths)
 }
 
 pub fn tree_to_code_map(tree: &HuffmanTree) -> CodeMap {
@@ -88,15 +222,15 @@ pub fn tree_to_code_map(tree: &HuffmanTree) -> CodeMap {
 
     fn rec(
         bits: Bits,
-        node: &Box<Node>,
-        code_map: &mut HashMap<u8, Bits>,
-        mut end_code: &mut Option<Bits>,
+        node: &Node,
+        code_map: &mut HashMap<Symbol, Bits>,
+        end_code: &mut Option<Bits>,
     ) {
         if let Some(value) = node.value {
             code_map.insert(value, bits.clone());
             return;
         } else if node.is_end_node {
-            end_code.insert(bits.clone());
+            *end_code = Some(bits.clone());
             return;
         }
         if let Some(left_node) = &node.left {
@@ -119,98 +253,71 @@ pub fn tree_to_code_map(tree: &HuffmanTree) -> CodeMap {
 
     rec(
         Bits::default(),
-        &tree.root_node.as_ref().unwrap(),
+        tree.root_node.as_ref().unwrap(),
         &mut codes,
         &mut end_code,
     );
-    CodeMap {
-        codes,
-        end_code: end_code.unwrap(),
-    }
+    CodeMap::new(codes, end_code.unwrap())
 }
 
-pub fn pack_to_u8<I: Iterator<Item = u8>>(code_map: &CodeMap, input_stream: I) -> Vec<u8> {
-    let mut output = Vec::new();
-    let mut working_bytes: u64 = 0;
-    let mut bits_left = 64;
+#[allow(dead_code)] // Only used for BWT blocks, chunks of literals are packed as they're added
+pub fn pack_to_u8<S: Into<Symbol>, I: Iterator<Item = S>>(
+    code_map: &CodeMap,
+    input_stream: I,
+) -> Vec<u8> {
+    pack(code_map, input_stream, false)
+}
+
+/// Like [pack_to_u8], but always writes the whole END code even if it runs into another byte.
+/// For a continuous bitstream, whose length isn't known up front, the decoder has nothing else
+/// to tell where it ends
+pub fn pack_stream<S: Into<Symbol>, I: Iterator<Item = S>>(
+    code_map: &CodeMap,
+    input_stream: I,
+) -> Vec<u8> {
+    pack(code_map, input_stream, true)
+}
+
+fn pack<S: Into<Symbol>, I: Iterator<Item = S>>(
+    code_map: &CodeMap,
+    input_stream: I,
+    whole_end: bool,
+) -> Vec<u8> {
+    // Writing to a Vec can't fail
+    let mut writer = BitWriter::new(Vec::new());
     for v in input_stream {
-        let value_bits = code_map.codes.get(&v).unwrap();
-        if value_bits.bit_size > bits_left {
-            //Split up. use the [bits_left] left bits from value_bits, then slap what's left
-            // in a new working_bytes
-
-            // if working_bytes = 0b11111100
-            // so bits_left = 2;
-            // and bits_inserting: 0b00011010, len 5
-
-            // working bytes += bits_inserting >> 5 - 2
-            // i.e. stamp 2msb of inserting: 0b000[11]010
-            // save working bytes
-
-            // Then take the remaining bits, slide them all the way left, losing the bits we
-            // previously wrote so: 0b00011[010] -> 0b[010]00000
-            // Rust is not a fan of obliterating bits by over-shifting, so we need to mask
-            // working_bytes = bits_inserting << (64 - bits_left)
-            // bits_left = 64 - (len - bits_left)
-
-            let num_bits_on_new = value_bits.bit_size - bits_left;
-
-            working_bytes |= (value_bits.set_bits >> num_bits_on_new);
-            output.extend_from_slice(&working_bytes.to_be_bytes());
-            working_bytes = value_bits.set_bits << (64 - num_bits_on_new);
-            bits_left = 64 - num_bits_on_new;
-        } else {
-            // let working_bytes = 0b11100000;
-            // let bits_left = 5;
-            // let bits_inserting = 0b101, len 3
-            // > shift left by (bits_left - len)
-            bits_left -= value_bits.bit_size;
-            working_bytes |= value_bits.set_bits << bits_left;
-
-            // working = 0;
-            // bit_size = 5
-            // bits_set = 0b000[01000]
-            // --
-            // bits_left -= bits_size --> 59
-            // to_set = 0b01000 << 59
-        }
-
-        if bits_left == 0 {
-            output.extend_from_slice(&working_bytes.to_be_bytes());
-            working_bytes = 0;
-            bits_left = 64;
-        }
-    }
-    // put as many bits of END_NODE's code on the end
-    if bits_left >= code_map.end_code.bit_size {
-        bits_left -= code_map.end_code.bit_size;
-        let bits_to_add = code_map.end_code.set_bits << bits_left;
-        working_bytes |= bits_to_add;
-    }
-
-    // Now stuff what remains in [working_bytes] into output
-    let bytes_populated = {
-        let floor = (64 - bits_left) / 8;
-        if (64 - bits_left) % 8 != 0 {
-            floor + 1
-        } else {
-            floor
+        writer.write_bits(code_map.code_for(v).unwrap()).unwrap();
+    }
+    match whole_end {
+        true => {
+            writer.write_bits(&code_map.end_code).unwrap();
+            writer.into_inner().unwrap()
         }
-    };
-    output.extend_from_slice(&working_bytes.to_be_bytes()[0..bytes_populated]);
-    output
+        // Fill the rest with the start of the END code. Leaving padding zeros instead could
+        // decode as another value, whereas running out part way through END stops the decoder
+        false => writer.finish_with(&code_map.end_code).unwrap(),
+    }
+}
+
+/// Decode bytes packed with a tree of byte symbols, see [HuffmanTree::validate_bytes]
+pub fn unpack_bytes(input_bytes: &[u8], tree: &HuffmanTree) -> Vec<u8> {
+    unpack_from(input_bytes, tree).expect("Reading a slice can't fail")
+}
+
+/// Like [unpack_bytes] but passing on any error reading [input]
+pub fn unpack_from<R: Read>(input: R, tree: &HuffmanTree) -> std::io::Result<Vec<u8>> {
+    let symbols = unpack_symbols(input, tree)?;
+    Ok(symbols.into_iter().map(|value| value as u8).collect())
 }
 
-pub fn unpack_bytes(mut input_bytes: &Vec<u8>, tree: &HuffmanTree) -> Vec<u8> {
-    //input_bytes.reverse();
-    let mut iter = input_bytes.iter().map(|v| *v);
-    let bit_stream = BitStream::new(move || iter.next());
+/// Decode the symbols packed in [input] until the END code or [input] runs out
+pub fn unpack_symbols<R: Read>(input: R, tree: &HuffmanTree) -> std::io::Result<Vec<Symbol>> {
     let mut output = Vec::new();
     let root_node = tree.root_node.as_ref().unwrap();
     let mut current_node = root_node;
 
-    for move_right in bit_stream {
-        current_node = if move_right {
+    for move_right in BitReader::new(input) {
+        current_node = if move_right? {
             current_node.right.as_ref().unwrap()
         } else {
             current_node.left.as_ref().unwrap()
@@ -220,16 +327,79 @@ pub fn unpack_bytes(mut input_bytes: &Vec<u8>, tree: &HuffmanTree) -> Vec<u8> {
             current_node = root_node;
         } else if current_node.is_end_node {
             break;
-        } else {
-            ()
-            // keep going
         }
+        // else keep going
+    }
+    Ok(output)
+}
+
+/// Decodes a continuous bitstream written by [pack_stream] a byte at a time, as the decoder
+/// gets them. The tree is flattened so the position in it can be held between bytes
+#[derive(Debug)]
+pub(crate) struct BitstreamDecoder {
+    nodes: Vec<FlatNode>,
+    current: usize,
+}
+
+#[derive(Debug)]
+enum FlatNode {
+    /// Indices of the left and right children
+    Branch(usize, usize),
+    Leaf(Symbol),
+    End,
+}
+
+impl BitstreamDecoder {
+    /// [tree] has to have been validated as having only byte symbols
+    pub fn new(tree: &HuffmanTree) -> Self {
+        fn flatten(node: &Node, nodes: &mut Vec<FlatNode>) -> usize {
+            let index = nodes.len();
+            nodes.push(FlatNode::End);
+            nodes[index] = match (node.value, &node.left, &node.right) {
+                (Some(value), _, _) => FlatNode::Leaf(value),
+                (None, Some(left), Some(right)) => {
+                    let left = flatten(left, nodes);
+                    let right = flatten(right, nodes);
+                    FlatNode::Branch(left, right)
+                }
+                _ => FlatNode::End,
+            };
+            index
+        }
+        let mut nodes = Vec::new();
+        if let Some(root_node) = &tree.root_node {
+            flatten(root_node, &mut nodes);
+        }
+        Self { nodes, current: 0 }
+    }
+
+    /// Decode the codes in [byte] onto [output]. Returns true once the END code is reached, in
+    /// which case the rest of the byte is padding
+    pub fn push_byte(&mut self, byte: u8, output: &mut impl Extend<u8>) -> bool {
+        for shift in (0..8).rev() {
+            let move_right = (byte >> shift) & 1 == 1;
+            self.current = match self.nodes.get(self.current) {
+                Some(FlatNode::Branch(left, right)) => match move_right {
+                    true => *right,
+                    false => *left,
+                },
+                _ => return true,
+            };
+            match self.nodes[self.current] {
+                FlatNode::Leaf(value) => {
+                    output.extend([value as u8]);
+                    self.current = 0;
+                }
+                FlatNode::End => return true,
+                FlatNode::Branch(..) => (),
+            }
+        }
+        false
     }
-    output
 }
 
 impl Node {
-    fn new_leaf(v: u8) -> Self {
+    fn new_leaf(v: Symbol) -> Self {
         Self {
             value: Some(v),
             left: None,
@@ -255,37 +425,72 @@ impl Node {
     }
 }
 
-impl Default for Bits {
-    fn default() -> Self {
-        Self {
-            set_bits: 0,
-            bit_size: 0,
-        }
-    }
-}
-
 impl Debug for Bits {
     fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
-        f.debug_struct("Bits")
-            .field("set_bits", &format!("{:064b}", self.set_bits))
-            .field("bit_size", &self.bit_size)
-            .finish()
+        let bits: String = self
+            .iter_bits()
+            .map(|bit| if bit { '1' } else { '0' })
+            .collect();
+        f.debug_tuple("Bits").field(&bits).finish()
     }
 }
 
 impl Bits {
+    pub const MAX_LEN: usize = u128::BITS as usize;
+
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn len(&self) -> usize {
+        self.bit_size
+    }
+
+    /// The bits as a number, the last one pushed being the least significant
+    pub fn value(&self) -> u128 {
+        self.set_bits
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.bit_size == 0
+    }
+
+    /// Add [bit] on the end. Panics if there are already [Bits::MAX_LEN] bits, rather than
+    /// losing the first one
+    pub fn push(&mut self, bit: bool) {
+        assert!(
+            self.bit_size < Self::MAX_LEN,
+            "Bits can hold at most {} bits",
+            Self::MAX_LEN
+        );
+        self.set_bits = (self.set_bits << 1) | bit as u128;
+        self.bit_size += 1;
+    }
+
+    /// Remove the last bit pushed
+    pub fn pop(&mut self) -> Option<bool> {
+        if self.bit_size == 0 {
+            return None;
+        }
+        let bit = self.set_bits & 1 == 1;
+        self.set_bits >>= 1;
+        self.bit_size -= 1;
+        Some(bit)
+    }
+
+    /// Each bit in the order it's written, first pushed first
+    pub fn iter_bits(&self) -> impl Iterator<Item = bool> + '_ {
+        (0..self.bit_size)
+            .rev()
+            .map(|i| (self.set_bits >> i) & 1 == 1)
+    }
+
     fn clone_with_increase(&self, is_left: bool) -> Self {
         // Some {set_bits:"11"; bit_size:2}, should become {set_bits:"110"; bit_size:3}
         // i.e. it needs to append to the right
-        let one_or_zero = // TODO: this is just bool to int ...
-            if is_left { 0 } else { 1 };
-
-        let mut new_set_bits = self.set_bits << 1;
-        new_set_bits |= one_or_zero;
-        Self {
-            set_bits: new_set_bits,
-            bit_size: self.bit_size + 1,
-        }
+        let mut bits = self.clone();
+        bits.push(!is_left);
+        bits
     }
 }
 
@@ -294,24 +499,46 @@ impl From<(u8, usize)> for Bits {
         //assert no bits set above bit_size
         //assert_eq!(bits >> bit_size, 0);
         Self {
-            set_bits: bits as u64,
+            set_bits: bits as u128,
             bit_size,
         }
     }
 }
 
-struct BitStream<F: FnMut() -> Option<u8>> {
-    current_byte: u8,
-    byte_pos: u8,
-    read_byte: F,
+impl FromIterator<bool> for Bits {
+    fn from_iter<I: IntoIterator<Item = bool>>(iter: I) -> Self {
+        let mut bits = Self::new();
+        for bit in iter {
+            bits.push(bit);
+        }
+        bits
+    }
+}
+
+/// Bits from bytes given by a closure, which ends them by returning None. Adapts a
+/// [BitReader] for callers without a [Read]
+pub struct BitStream<F: FnMut() -> Option<u8>> {
+    reader: BitReader<ByteSource<F>>,
+}
+
+struct ByteSource<F: FnMut() -> Option<u8>>(F);
+
+impl<F: FnMut() -> Option<u8>> Read for ByteSource<F> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        match (buf.first_mut(), (self.0)()) {
+            (Some(first), Some(byte)) => {
+                *first = byte;
+                Ok(1)
+            }
+            _ => Ok(0),
+        }
+    }
 }
 
 impl<F: FnMut() -> Option<u8>> BitStream<F> {
-    fn new(read_byte: F) -> Self {
+    pub fn new(read_byte: F) -> Self {
         Self {
-            current_byte: 0,
-            byte_pos: 8,
-            read_byte,
+            reader: BitReader::new(ByteSource(read_byte)),
         }
     }
 }
@@ -320,32 +547,39 @@ impl<F: FnMut() -> Option<u8>> Iterator for BitStream<F> {
     type Item = bool;
 
     fn next(&mut self) -> Option<Self::Item> {
-        // When bytepos = 0, we want to read the MSB, i.e. shift 0b00000001 left to
-        // 0b10000000 (i.e. shift left 7)
-
-        // Allowed byte_pos values are 1 to 8. when we extend past it, we read and reset
-        self.byte_pos += 1;
-
-        if self.byte_pos > 8 {
-            match (self.read_byte)() {
-                Some(new_byte) => {
-                    // Only resetting byte_pos here so if next() is called repeatedly when
-                    // F returns none it won't reset
-                    self.byte_pos = 1;
-                    self.current_byte = new_byte
-                }
-                None => return None,
-            }
-        }
-
-        let mask = 1 << (8 - self.byte_pos);
-        Some(mask & self.current_byte > 0)
+        // A closure has no errors to pass on
+        self.reader.next().map(|bit| bit.unwrap())
     }
 }
 
 impl HuffmanTree {
+    /// The tree the decoder needs to read what was packed with [code_map], e.g. one built with
+    /// [CodeMap::from_lengths] rather than from the input
+    pub fn from_code_map(code_map: &CodeMap) -> Self {
+        fn insert(root_node: &mut Node, bits: &Bits, leaf: Node) {
+            // Codes are read first bit first, with 0 going left
+            let mut node = root_node;
+            for go_right in bits.iter_bits() {
+                let child = match go_right {
+                    true => &mut node.right,
+                    false => &mut node.left,
+                };
+                node = child.get_or_insert_with(|| Box::new(Node::new_vertex(None, None)));
+            }
+            *node = leaf;
+        }
+        let mut root_node = Node::new_vertex(None, None);
+        for (value, bits) in code_map.codes() {
+            insert(&mut root_node, bits, Node::new_leaf(value));
+        }
+        insert(&mut root_node, code_map.end_code(), Node::new_end());
+        Self {
+            root_node: Some(Box::new(root_node)),
+        }
+    }
+
     pub fn size(&self) -> usize {
-        fn walk(node: &Box<Node>, mut count: usize) -> usize {
+        fn walk(node: &Node, mut count: usize) -> usize {
             if let Some(left_node) = &node.left {
                 count = walk(left_node, count + 1);
             }
@@ -356,10 +590,77 @@ impl HuffmanTree {
         }
         walk(self.root_node.as_ref().unwrap(), 1)
     }
+
+    /// Check a tree read from an untrusted header can be used for decoding: every internal node
+    /// has two children, no code is longer than [MAX_CODE_LEN] bits and there is exactly one end
+    /// node
+    pub fn validate(&self) -> Result<()> {
+        fn walk(
+            node: &Node,
+            depth: usize,
+            end_nodes: &mut usize,
+        ) -> std::result::Result<(), String> {
+            if depth > MAX_CODE_LEN {
+                return Err(format!("Code longer than {} bits", MAX_CODE_LEN));
+            }
+            match (node.value, &node.left, &node.right, node.is_end_node) {
+                (Some(_), None, None, false) => Ok(()),
+                (None, None, None, true) => {
+                    *end_nodes += 1;
+                    Ok(())
+                }
+                (None, Some(left), Some(right), false) => {
+                    walk(left, depth + 1, end_nodes)?;
+                    walk(right, depth + 1, end_nodes)
+                }
+                _ => Err(format!("Malformed node at depth {}", depth)),
+            }
+        }
+        let root_node = match &self.root_node {
+            Some(root_node) if root_node.left.is_some() => root_node,
+            // A leaf (or end node) at the root has no code to reach it
+            _ => return Err(Error::CorruptHeader(String::from("Tree has no branches"))),
+        };
+        let mut end_nodes = 0;
+        walk(root_node, 0, &mut end_nodes).map_err(Error::CorruptHeader)?;
+        if end_nodes != 1 {
+            return Err(Error::CorruptHeader(format!(
+                "Expected one end node in tree, found {}",
+                end_nodes
+            )));
+        }
+        Ok(())
+    }
+
+    /// [HuffmanTree::validate], and check every symbol is a byte, as needed to decode literals
+    pub fn validate_bytes(&self) -> Result<()> {
+        self.validate()?;
+        match self.max_symbol() {
+            Some(value) if value > u8::MAX as Symbol => Err(Error::CorruptHeader(format!(
+                "Symbol {} in a tree of bytes",
+                value
+            ))),
+            _ => Ok(()),
+        }
+    }
+
+    /// The biggest symbol with a code, if any
+    pub fn max_symbol(&self) -> Option<Symbol> {
+        fn walk(node: &Node) -> Option<Symbol> {
+            let children = [&node.left, &node.right];
+            let child_max = children
+                .into_iter()
+                .flatten()
+                .filter_map(|child| walk(child));
+            node.value.into_iter().chain(child_max).max()
+        }
+        walk(self.root_node.as_ref()?)
+    }
+
     pub(crate) fn to_dot(&self) -> String {
         let mut nodes = Vec::new();
         let mut relationships = Vec::new();
-        let mut node_id = AtomicUsize::new(1);
+        let node_id = AtomicUsize::new(1);
 
         fn walk(
             nodes: &mut Vec<String>,
@@ -370,17 +671,20 @@ impl HuffmanTree {
             is_left: bool,
         ) {
             match node {
-                None => return,
+                None => (),
                 Some(node) => {
                     let this_node_id = format!("node{}", node_id.fetch_add(1, Ordering::Relaxed));
                     let rel_label = if is_left {
-                        format!("[label=\"0\"]")
+                        "[label=\"0\"]"
                     } else {
-                        format!("[label=\"1\"]")
+                        "[label=\"1\"]"
                     };
                     relationships.push(format!("{} -> {} {};", parent_id, this_node_id, rel_label));
                     if let Some(value) = node.value {
-                        let as_string = String::from_utf8(vec![value]);
+                        let as_string = match u8::try_from(value) {
+                            Ok(byte) => String::from_utf8(vec![byte]),
+                            Err(_) => Ok(String::new()),
+                        };
                         let as_str = match &as_string.as_ref().map(String::as_str) {
                             Ok("\n") => "\\n",
                             Ok("\r") => "\\r",
@@ -446,9 +750,12 @@ impl HuffmanTree {
     }
 }
 
+#[cfg(test)]
 mod test {
     use crate::huffman::{
-        build_tree, pack_to_u8, tree_to_code_map, unpack_bytes, BitStream, Bits, ByteStats, CodeMap,
+        build_tree, code_lengths, pack_stream, pack_to_u8, tree_to_code_map, unpack_bytes,
+        unpack_symbols, BitStream, Bits, BitstreamDecoder, ByteStats, CodeMap, HuffmanTree, Node,
+        Symbol, MAX_HUFFMAN_CODE_LEN,
     };
     use std::collections::HashMap;
     use std::io::{BufReader, Read};
@@ -461,7 +768,7 @@ mod test {
         // ENCODE
         let mut stats = ByteStats::new();
         for byte in input.as_bytes().iter() {
-            let mut count = stats.entry(*byte).or_insert(0);
+            let count = stats.entry(Symbol::from(*byte)).or_insert(0);
             *count += 1;
         }
         let tree = build_tree(stats);
@@ -477,7 +784,6 @@ mod test {
 
         //Check
         assert_eq!(input, &output_string);
-        ()
     }
     #[test]
     fn round_trip() {
@@ -488,7 +794,7 @@ mod test {
         // ENCODE
         let mut stats = ByteStats::new();
         for byte in input.as_bytes().iter() {
-            let mut count = stats.entry(*byte).or_insert(0);
+            let count = stats.entry(Symbol::from(*byte)).or_insert(0);
             *count += 1;
         }
         let tree = build_tree(stats);
@@ -506,7 +812,150 @@ mod test {
 
         //Check
         assert_eq!(input, &output_string);
-        ()
+    }
+
+    #[test]
+    fn from_lengths() {
+        let input = "A_DEAD_DAD_CEDED_A_BAD_BABE_A_BEADED_ABACA_BED";
+        // With the END code at 5 bits, these fill the code space exactly
+        let mut lengths = [0u8; 256];
+        for (value, len) in [
+            (b'A', 2),
+            (b'D', 2),
+            (b'E', 2),
+            (b'_', 3),
+            (b'B', 4),
+            (b'C', 5),
+        ] {
+            lengths[value as usize] = len;
+        }
+        let code_map = CodeMap::from_lengths(&lengths).unwrap();
+        for (value, bits) in code_map.codes() {
+            assert_eq!(lengths[value as usize] as usize, bits.bit_size);
+        }
+        let tree = HuffmanTree::from_code_map(&code_map);
+        tree.validate().unwrap();
+        let encoded_bytes = pack_to_u8(&code_map, input.bytes());
+        assert_eq!(input.as_bytes(), &unpack_bytes(&encoded_bytes, &tree)[..]);
+
+        // One code short of filling the code space, and one code too many
+        let mut incomplete = [0u8; 256];
+        incomplete[..3].copy_from_slice(&[2, 2, 3]);
+        assert_eq!(None, CodeMap::from_lengths(&incomplete));
+        let mut overfull = [0u8; 256];
+        overfull[..4].copy_from_slice(&[1, 2, 2, 2]);
+        assert_eq!(None, CodeMap::from_lengths(&overfull));
+    }
+
+    #[test]
+    fn bits() {
+        let mut bits: Bits = [true, false, true].into_iter().collect();
+        assert_eq!(3, bits.len());
+        assert_eq!("Bits(\"101\")", format!("{:?}", bits));
+        assert_eq!(Some(true), bits.pop());
+        bits.push(false);
+        assert_eq!(
+            vec![true, false, false],
+            bits.iter_bits().collect::<Vec<_>>()
+        );
+
+        let long: Bits = (0..Bits::MAX_LEN).map(|i| i % 3 == 0).collect();
+        assert_eq!(
+            (0..Bits::MAX_LEN).map(|i| i % 3 == 0).collect::<Vec<_>>(),
+            long.iter_bits().collect::<Vec<_>>()
+        );
+    }
+
+    #[test]
+    fn codes_longer_than_64_bits() {
+        let stats = fibonacci_stats();
+        let lengths = code_lengths(&stats, Bits::MAX_LEN).unwrap();
+        let tree = HuffmanTree::from_code_map(&CodeMap::from_lengths(&lengths).unwrap());
+        tree.validate().unwrap();
+        let code_map = tree_to_code_map(&tree);
+        assert!(code_map.codes().any(|(_, bits)| bits.len() > 64));
+        let input: Vec<u8> = (0..80u8).chain((0..80u8).rev()).collect();
+        let encoded_bytes = pack_to_u8(&code_map, input.iter().cloned());
+        assert_eq!(input, unpack_bytes(&encoded_bytes, &tree));
+        assert_eq!(tree, HuffmanTree::from_code_map(&code_map));
+    }
+
+    /// Fibonacci counts give the most lopsided tree there is, one level per value
+    fn fibonacci_stats() -> ByteStats {
+        let mut stats = ByteStats::new();
+        let (mut a, mut b) = (1u64, 1u64);
+        for value in 0..80 {
+            stats.insert(value, a);
+            (a, b) = (b, a + b);
+        }
+        stats
+    }
+
+    #[test]
+    fn length_limited() {
+        let stats = fibonacci_stats();
+        let tree = build_tree(stats.clone());
+        let code_map = tree_to_code_map(&tree);
+        assert!(code_map
+            .codes()
+            .all(|(_, bits)| bits.len() <= MAX_HUFFMAN_CODE_LEN));
+        let input: Vec<u8> = (0..80u8).collect();
+        let encoded_bytes = pack_to_u8(&code_map, input.iter().cloned());
+        assert_eq!(input, unpack_bytes(&encoded_bytes, &tree));
+
+        // Unlimited, package-merge gives the same lengths as plain huffman coding
+        let lengths = code_lengths(&stats, Bits::MAX_LEN).unwrap();
+        assert_eq!(80, lengths[0]);
+        assert_eq!(1, lengths[79]);
+        // 81 codes (with END) don't fit in 6 bits
+        assert_eq!(None, code_lengths(&stats, 6));
+        assert!(code_lengths(&stats, 7).is_some());
+    }
+
+    #[test]
+    fn degenerate_stats() {
+        // Nothing at all, and a single value, both still make a two leaf tree
+        let empty = build_tree(ByteStats::new());
+        empty.validate_bytes().unwrap();
+        assert_eq!(1, tree_to_code_map(&empty).end_code().len());
+        assert_eq!(Vec::<u8>::new(), unpack_bytes(&[0xff], &empty));
+
+        let single = build_tree([(Symbol::from(b'a'), 1000)].into_iter().collect());
+        single.validate_bytes().unwrap();
+        let code_map = tree_to_code_map(&single);
+        assert_eq!(1, code_map.code_for(b'a').unwrap().len());
+        let input = vec![b'a'; 1000];
+        let packed = pack_stream(&code_map, input.iter().copied());
+        assert_eq!(126, packed.len());
+        assert_eq!(input, unpack_bytes(&packed, &single));
+    }
+
+    #[test]
+    fn wide_symbols() {
+        // Bytes sharing an alphabet with symbols past them, e.g. match lengths
+        let input: Vec<Symbol> = b"abracadabra"
+            .iter()
+            .map(|b| Symbol::from(*b))
+            .chain([256, 300, 256, 1000])
+            .collect();
+        let mut stats = ByteStats::new();
+        for value in &input {
+            *stats.entry(*value).or_insert(0) += 1;
+        }
+        let tree = build_tree(stats);
+        tree.validate().unwrap();
+        assert_eq!(Some(1000), tree.max_symbol());
+        assert!(tree.validate_bytes().is_err());
+        let code_map = tree_to_code_map(&tree);
+        assert!(code_map.code_for(1000u16).is_some());
+        assert!(code_map.code_for(999u16).is_none());
+        let packed = pack_stream(&code_map, input.iter().copied());
+        assert_eq!(input, unpack_symbols(&packed[..], &tree).unwrap());
+
+        // Wide trees serialise like byte ones, which old headers still decode as
+        let round_tripped: HuffmanTree =
+            rmp_serde::from_slice(&rmp_serde::to_vec(&tree).unwrap()).unwrap();
+        assert_eq!(tree, round_tripped);
     }
 
     #[test]
@@ -537,9 +986,9 @@ mod test {
         ];
         let expected_bytes: Vec<&str> = vec![
             "10111001", "11110111", "11011011", "10101011", "10101011", "10101011", "10101011",
-            // Here we're at the last byte of the u64 and moving onto the next
-            //
-            "10010011", "11101111", "11111000",
+            // Here we're at the last byte of the u64 and moving onto the next. The last byte is
+            // finished with as much of the END code as fits
+            "10010011", "11101111",
         ];
         let output = pack_to_u8(&code_map, input_bytes.iter().cloned());
         assert_eq!(
@@ -551,7 +1000,7 @@ mod test {
     #[test]
     fn bitstream_test() {
         let test_count = AtomicUsize::new(0);
-        let mut bitstream = BitStream::new(|| match test_count.fetch_add(1, Ordering::Relaxed) {
+        let bitstream = BitStream::new(|| match test_count.fetch_add(1, Ordering::Relaxed) {
             0 => Some(0b10100101),
             1 => Some(0b11110000),
             _ => None,
@@ -573,7 +1022,7 @@ mod test {
         let mut buffreader = BufReader::new(&data[..]);
         let mut buf: [u8; 1] = [0];
 
-        let mut bitstream = BitStream::new(|| {
+        let bitstream = BitStream::new(|| {
             let read = buffreader.read(&mut buf);
             match read {
                 Ok(1) => Some(buf[0]),
@@ -596,6 +1045,7 @@ mod test {
         assert_eq!(expected, as_bools);
     }
 
+    #[test]
     fn bitstream_more_than_u64() {
         let data: [u8; 9] = [
             u8::MAX,
@@ -611,7 +1061,7 @@ mod test {
         let mut buffreader = BufReader::new(&data[..]);
         let mut buf: [u8; 1] = [0];
 
-        let mut bitstream = BitStream::new(|| {
+        let bitstream = BitStream::new(|| {
             let read = buffreader.read(&mut buf);
             match read {
                 Ok(1) => Some(buf[0]),
@@ -629,4 +1079,80 @@ mod test {
         assert_eq!(expected, bool_chunks[7]);
         assert_eq!(expected, bool_chunks[8]);
     }
+
+    #[test]
+    fn build_tree_is_deterministic() {
+        // Lots of equal counts, so ties have to be broken. Each ByteStats iterates differently
+        let stats = || -> ByteStats { (0..256).map(|b| (b, 1 + b as u64 % 3)).collect() };
+        let tree = build_tree(stats());
+        for _ in 0..20 {
+            assert_eq!(tree, build_tree(stats()));
+        }
+    }
+
+    #[test]
+    fn validate() {
+        let mut stats = ByteStats::new();
+        for (i, b) in b"abcdefg".iter().enumerate() {
+            stats.insert(Symbol::from(*b), i as u64 + 1);
+        }
+        let tree = build_tree(stats);
+        tree.validate().unwrap();
+
+        let leaf = |v| Some(Box::new(Node::new_leaf(v)));
+        let end = || Some(Box::new(Node::new_end()));
+        let vertex = |left, right| Some(Box::new(Node::new_vertex(left, right)));
+        let invalid = [
+            HuffmanTree { root_node: None },
+            HuffmanTree {
+                root_node: leaf(b'a'.into()),
+            },
+            // Missing child
+            HuffmanTree {
+                root_node: vertex(leaf(b'a'.into()), vertex(end(), None)),
+            },
+            // No end node
+            HuffmanTree {
+                root_node: vertex(leaf(b'a'.into()), leaf(b'b'.into())),
+            },
+            // Two end nodes
+            HuffmanTree {
+                root_node: vertex(end(), end()),
+            },
+        ];
+        for tree in invalid {
+            assert!(tree.validate().is_err(), "{:?} should be invalid", tree);
+        }
+
+        // One branch longer than Bits can hold
+        let mut root_node = vertex(leaf(b'a'.into()), end());
+        for _ in 0..Bits::MAX_LEN {
+            root_node = vertex(leaf(b'b'.into()), root_node);
+        }
+        assert!(HuffmanTree { root_node }.validate().is_err());
+    }
+
+    #[test]
+    fn bitstream_decoder() {
+        let text = b"A_DEAD_DAD_CEDED_A_BAD_BABE_A_BEADED_ABACA_BED";
+        let stats: ByteStats = text.iter().fold(ByteStats::new(), |mut stats, b| {
+            *stats.entry(Symbol::from(*b)).or_insert(0) += 1;
+            stats
+        });
+        let tree = build_tree(stats);
+        let code_map = tree_to_code_map(&tree);
+        // Every length, so the END code lands at each position in a byte
+        for len in 0..text.len() {
+            let mut encoded = pack_stream(&code_map, text[..len].iter().copied());
+            // Whatever comes next isn't part of the bitstream
+            encoded.push(0xff);
+            let mut decoder = BitstreamDecoder::new(&tree);
+            let mut decoded = Vec::new();
+            let end = encoded
+                .iter()
+                .position(|byte| decoder.push_byte(*byte, &mut decoded));
+            assert_eq!(Some(encoded.len() - 2), end);
+            assert_eq!(&text[..len], &decoded[..]);
+        }
+    }
 }
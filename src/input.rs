@@ -0,0 +1,436 @@
+//! Reading the file being encoded. With [crate::EncodeOptions::read_ahead], a thread of its own
+//! reads the file a block at a time into a bounded channel, so the disk is kept busy while the
+//! encoder works on what it has. Otherwise, with the `uring` feature on Linux, reads are queued
+//! through io_uring several buffers ahead of where the encoder has got to, to the same end.
+//! Elsewhere, or where the kernel refuses io_uring (older kernels and some sandboxes), the file
+//! is read through a plain [BufReader]
+
+use std::fs::File;
+use std::io::{BufReader, ErrorKind, Read, Seek, SeekFrom};
+use std::sync::mpsc::{sync_channel, Receiver};
+use std::thread::JoinHandle;
+
+/// Bytes the read ahead thread reads at a time
+const READ_AHEAD_BLOCK_LEN: usize = 256 * 1024;
+
+/// What the encoder needs of its input
+pub(crate) trait ReadSeek: Read + Seek {}
+
+impl<T: Read + Seek> ReadSeek for T {}
+
+/// The best reader for [file] this platform and build have, or one reading up to [read_ahead]
+/// bytes ahead on a thread of its own if that's given
+pub(crate) fn input_reader(
+    file: File,
+    read_ahead: Option<usize>,
+) -> std::io::Result<Box<dyn ReadSeek>> {
+    if let Some(read_ahead) = read_ahead {
+        return Ok(Box::new(ReadAhead::new(file, read_ahead)?));
+    }
+    #[cfg(all(feature = "uring", target_os = "linux"))]
+    match io_uring::IoUring::new(uring::QUEUE_DEPTH as u32) {
+        Ok(ring) => return Ok(Box::new(uring::UringReader::new(ring, file)?)),
+        Err(e) => log::debug!("Not reading through io_uring: {}", e),
+    }
+    Ok(Box::new(BufReader::new(file)))
+}
+
+/// What the read ahead thread sends: a block of the file, or why it couldn't read one
+type Block = std::io::Result<Vec<u8>>;
+
+/// Reads [file] on another thread, holding up to [depth] blocks read ahead of where reading has
+/// got to. Seeking anywhere but where reading is stops the thread, throwing away what it read,
+/// and starts another from there
+pub(crate) struct ReadAhead {
+    /// While there's no thread reading it
+    file: Option<File>,
+    thread: Option<(Receiver<Block>, JoinHandle<File>)>,
+    depth: usize,
+    /// Of the file when it was opened, for seeking from the end
+    len: u64,
+    /// The block being read from, and how far into it reading is
+    current: Vec<u8>,
+    current_pos: usize,
+    pos: u64,
+}
+
+impl ReadAhead {
+    fn new(file: File, read_ahead: usize) -> std::io::Result<Self> {
+        Ok(Self {
+            len: file.metadata()?.len(),
+            file: Some(file),
+            thread: None,
+            depth: (read_ahead / READ_AHEAD_BLOCK_LEN).max(1),
+            current: Vec::new(),
+            current_pos: 0,
+            pos: 0,
+        })
+    }
+
+    /// Start reading ahead from [pos], unless it already is
+    fn start(&mut self) -> std::io::Result<()> {
+        let Some(mut file) = self.file.take() else {
+            return Ok(());
+        };
+        if let Err(e) = file.seek(SeekFrom::Start(self.pos)) {
+            self.file = Some(file);
+            return Err(e);
+        }
+        let (sender, receiver) = sync_channel::<Block>(self.depth);
+        let thread = std::thread::Builder::new()
+            .name(String::from("lizards-read-ahead"))
+            .spawn(move || {
+                loop {
+                    let mut block = Vec::with_capacity(READ_AHEAD_BLOCK_LEN);
+                    let read = (&mut file)
+                        .take(READ_AHEAD_BLOCK_LEN as u64)
+                        .read_to_end(&mut block);
+                    let end = !matches!(read, Ok(READ_AHEAD_BLOCK_LEN));
+                    // Fails once reading has stopped, as the receiver is dropped
+                    if sender.send(read.map(|_| block)).is_err() || end {
+                        return file;
+                    }
+                }
+            })?;
+        self.thread = Some((receiver, thread));
+        Ok(())
+    }
+
+    /// Stop reading ahead, throwing away whatever was read
+    fn stop(&mut self) {
+        if let Some((receiver, thread)) = self.thread.take() {
+            drop(receiver);
+            self.file = Some(thread.join().expect("The read ahead thread doesn't panic"));
+        }
+        self.current.clear();
+        self.current_pos = 0;
+    }
+}
+
+impl Read for ReadAhead {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        while self.current_pos == self.current.len() {
+            self.start()?;
+            let Some((receiver, _)) = &self.thread else {
+                unreachable!("Just started")
+            };
+            match receiver.recv() {
+                Ok(Ok(block)) if !block.is_empty() => {
+                    self.current = block;
+                    self.current_pos = 0;
+                }
+                // At the end of the file, or the thread finished there
+                Ok(Ok(_)) | Err(_) => {
+                    self.stop();
+                    return Ok(0);
+                }
+                Ok(Err(e)) => {
+                    self.stop();
+                    return Err(e);
+                }
+            }
+        }
+        let len = buf.len().min(self.current.len() - self.current_pos);
+        buf[..len].copy_from_slice(&self.current[self.current_pos..self.current_pos + len]);
+        self.current_pos += len;
+        self.pos += len as u64;
+        Ok(len)
+    }
+}
+
+impl Seek for ReadAhead {
+    fn seek(&mut self, pos: SeekFrom) -> std::io::Result<u64> {
+        let pos = match pos {
+            SeekFrom::Start(pos) => Some(pos),
+            SeekFrom::End(delta) => self.len.checked_add_signed(delta),
+            SeekFrom::Current(delta) => self.pos.checked_add_signed(delta),
+        }
+        .ok_or_else(|| {
+            std::io::Error::new(ErrorKind::InvalidInput, "Seek to a negative position")
+        })?;
+        if pos != self.pos {
+            self.stop();
+            self.pos = pos;
+        }
+        Ok(pos)
+    }
+}
+
+impl Drop for ReadAhead {
+    fn drop(&mut self) {
+        self.stop();
+    }
+}
+
+#[cfg(all(feature = "uring", target_os = "linux"))]
+mod uring {
+    use io_uring::{opcode, types, IoUring};
+    use std::collections::VecDeque;
+    use std::fs::File;
+    use std::io::{ErrorKind, Read, Result, Seek, SeekFrom};
+    use std::os::unix::fs::FileExt;
+    use std::os::unix::io::AsRawFd;
+
+    /// Reads in flight at once, including the buffer being read from
+    pub(super) const QUEUE_DEPTH: usize = 4;
+    const BUFFER_LEN: usize = 256 * 1024;
+
+    struct Slot {
+        buffer: Vec<u8>,
+        /// In the file, of the buffer's first byte
+        offset: u64,
+        /// Bytes asked for
+        len: usize,
+        /// The completed read's result, None while it's in flight
+        result: Option<i32>,
+    }
+
+    /// Reads [file] from [pos] on, with reads of the following buffers queued ahead of it
+    pub(crate) struct UringReader {
+        ring: IoUring,
+        file: File,
+        /// Of the file when it was opened. Reads stop there
+        len: u64,
+        slots: Vec<Slot>,
+        /// Slots with reads submitted, in file order
+        queued: VecDeque<usize>,
+        /// Slots free for the next read
+        idle: Vec<usize>,
+        /// The slot being read from, with how far into it reading is and where its bytes end
+        current: Option<(usize, usize, usize)>,
+        /// In the file, where the next queued read starts
+        next_offset: u64,
+        pos: u64,
+    }
+
+    impl UringReader {
+        pub(super) fn new(ring: IoUring, file: File) -> Result<Self> {
+            let len = file.metadata()?.len();
+            let slots = (0..QUEUE_DEPTH)
+                .map(|_| Slot {
+                    buffer: vec![0; BUFFER_LEN],
+                    offset: 0,
+                    len: 0,
+                    result: None,
+                })
+                .collect();
+            Ok(Self {
+                ring,
+                file,
+                len,
+                slots,
+                queued: VecDeque::new(),
+                idle: (0..QUEUE_DEPTH).rev().collect(),
+                current: None,
+                next_offset: 0,
+                pos: 0,
+            })
+        }
+
+        /// Queue reads into the idle slots, up to the end of the file
+        fn fill(&mut self) -> Result<()> {
+            let mut submitted = false;
+            while self.next_offset < self.len {
+                let Some(index) = self.idle.pop() else { break };
+                let slot = &mut self.slots[index];
+                slot.offset = self.next_offset;
+                slot.len = (self.len - self.next_offset).min(BUFFER_LEN as u64) as usize;
+                slot.result = None;
+                let entry = opcode::Read::new(
+                    types::Fd(self.file.as_raw_fd()),
+                    slot.buffer.as_mut_ptr(),
+                    slot.len as u32,
+                )
+                .offset(slot.offset)
+                .build()
+                .user_data(index as u64);
+                // Safety: the buffer isn't touched, moved or freed until the read has completed,
+                // as slots only go back to idle through [wait_for]
+                if unsafe { self.ring.submission().push(&entry) }.is_err() {
+                    self.idle.push(index);
+                    break;
+                }
+                self.next_offset += slot.len as u64;
+                self.queued.push_back(index);
+                submitted = true;
+            }
+            if submitted {
+                self.ring.submit()?;
+            }
+            Ok(())
+        }
+
+        /// Block until the read into slot [index] has completed
+        fn wait_for(&mut self, index: usize) -> Result<()> {
+            while self.slots[index].result.is_none() {
+                match self.ring.submit_and_wait(1) {
+                    Ok(_) => (),
+                    Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+                    Err(e) => return Err(e),
+                }
+                for completed in self.ring.completion() {
+                    self.slots[completed.user_data() as usize].result = Some(completed.result());
+                }
+            }
+            Ok(())
+        }
+
+        /// Wait for the read into slot [index] and give how many bytes it holds, finishing off a
+        /// short read with plain positioned reads
+        fn finish(&mut self, index: usize) -> Result<usize> {
+            self.wait_for(index)?;
+            let slot = &mut self.slots[index];
+            let mut filled = match slot.result {
+                Some(result) if result < 0 => {
+                    return Err(std::io::Error::from_raw_os_error(-result));
+                }
+                Some(result) => result as usize,
+                None => unreachable!(),
+            };
+            while filled < slot.len {
+                let read = self.file.read_at(
+                    &mut slot.buffer[filled..slot.len],
+                    slot.offset + filled as u64,
+                );
+                match read {
+                    // The file shrank since it was opened
+                    Ok(0) => break,
+                    Ok(read) => filled += read,
+                    Err(e) if e.kind() == ErrorKind::Interrupted => (),
+                    Err(e) => return Err(e),
+                }
+            }
+            Ok(filled)
+        }
+
+        /// Throw away everything read ahead, waiting for reads in flight to land
+        fn drain(&mut self) -> Result<()> {
+            if let Some((index, _, _)) = self.current.take() {
+                self.idle.push(index);
+            }
+            while let Some(&index) = self.queued.front() {
+                self.wait_for(index)?;
+                self.queued.pop_front();
+                self.idle.push(index);
+            }
+            Ok(())
+        }
+    }
+
+    impl Read for UringReader {
+        fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
+            loop {
+                if let Some((index, start, end)) = self.current {
+                    if start < end {
+                        let len = buf.len().min(end - start);
+                        buf[..len].copy_from_slice(&self.slots[index].buffer[start..start + len]);
+                        self.current = Some((index, start + len, end));
+                        self.pos += len as u64;
+                        return Ok(len);
+                    }
+                    self.current = None;
+                    self.idle.push(index);
+                }
+                self.fill()?;
+                let Some(index) = self.queued.pop_front() else {
+                    return Ok(0);
+                };
+                match self.finish(index) {
+                    Ok(filled) => self.current = Some((index, 0, filled)),
+                    Err(e) => {
+                        // Its read has landed unless waiting failed, when the ring is unusable
+                        // and [Drop] leaks the buffers
+                        if self.slots[index].result.is_some() {
+                            self.idle.push(index);
+                        } else {
+                            self.queued.push_front(index);
+                        }
+                        return Err(e);
+                    }
+                }
+            }
+        }
+    }
+
+    impl Seek for UringReader {
+        fn seek(&mut self, pos: SeekFrom) -> Result<u64> {
+            let pos = match pos {
+                SeekFrom::Start(pos) => Some(pos),
+                SeekFrom::End(delta) => self.len.checked_add_signed(delta),
+                SeekFrom::Current(delta) => self.pos.checked_add_signed(delta),
+            }
+            .ok_or_else(|| {
+                std::io::Error::new(ErrorKind::InvalidInput, "Seek to a negative position")
+            })?;
+            if pos != self.pos {
+                self.drain()?;
+                self.pos = pos;
+                self.next_offset = pos;
+            }
+            Ok(pos)
+        }
+    }
+
+    impl Drop for UringReader {
+        fn drop(&mut self) {
+            if self.drain().is_err() {
+                // The kernel may yet write into the buffers, so they can't be freed
+                std::mem::forget(std::mem::take(&mut self.slots));
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::{Read, Seek, SeekFrom};
+
+    use crate::input::input_reader;
+    use crate::test_util::temp_path;
+
+    #[test]
+    fn reads_and_seeks() {
+        let path = temp_path("input");
+        // Not a whole number of buffers
+        let data: Vec<u8> = (0..1_100_003u32).map(|i| (i * 7 % 251) as u8).collect();
+        std::fs::write(&path, &data).unwrap();
+        for read_ahead in [None, Some(0), Some(1 << 20)] {
+            check_reader(&path, &data, read_ahead);
+        }
+        std::fs::remove_file(path).unwrap();
+    }
+
+    fn check_reader(path: &std::path::Path, data: &[u8], read_ahead: Option<usize>) {
+        let mut reader = input_reader(std::fs::File::open(path).unwrap(), read_ahead).unwrap();
+
+        assert_eq!(data.len() as u64, reader.seek(SeekFrom::End(0)).unwrap());
+        reader.rewind().unwrap();
+        let mut read: Vec<u8> = Vec::new();
+        let mut buf = vec![0; 70_001];
+        loop {
+            let len = reader.read(&mut buf).unwrap();
+            if len == 0 {
+                break;
+            }
+            read.extend(&buf[..len]);
+        }
+        assert_eq!(data, read);
+
+        // Part way through, and back again once some has been read ahead
+        reader.seek(SeekFrom::Start(300_000)).unwrap();
+        reader.read_exact(&mut buf).unwrap();
+        assert_eq!(&data[300_000..370_001], buf.as_slice());
+        reader.seek(SeekFrom::Current(-100_000)).unwrap();
+        reader.read_exact(&mut buf[..10]).unwrap();
+        assert_eq!(&data[270_001..270_011], &buf[..10]);
+        assert!(reader.seek(SeekFrom::End(-2_000_000)).is_err());
+        // Reading to the end and going back to the start again, as the encoder does
+        reader.seek(SeekFrom::End(-5)).unwrap();
+        let mut end = Vec::new();
+        reader.read_to_end(&mut end).unwrap();
+        assert_eq!(&data[data.len() - 5..], end.as_slice());
+        reader.rewind().unwrap();
+        reader.read_exact(&mut buf[..10]).unwrap();
+        assert_eq!(&data[..10], &buf[..10]);
+    }
+}
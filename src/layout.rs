@@ -0,0 +1,498 @@
+//! An annotated map of an encoded stream, for seeing how it's laid out byte by byte without
+//! decoding it: the header, each chunk of literals, each match as it was read and each control
+//! record. Where literals are huffman coded, each chunk also says where the codes in it start,
+//! as codes run on from one chunk into the next
+
+use serde::Serialize;
+use std::fmt::{Display, Formatter};
+
+use crate::control::{split_varint, ControlRecord};
+use crate::encode::Algorithm;
+use crate::error::{Error, Result};
+use crate::format::stored;
+use crate::header::{self, Header};
+use crate::huffman::{self, BitstreamDecoder, CodeMap, HuffmanTree};
+use crate::offset_len::OffsetLen;
+use crate::token::ChunkMarker;
+use crate::DEFAULT_MAX_HEADER_SIZE;
+
+/// How many of a span's bytes are shown in hex before it's cut short
+const MAX_HEX_LEN: usize = 8;
+
+/// A run of the stream's bytes and what they are
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Span {
+    /// Where in the stream it starts
+    pub start: u64,
+    pub len: u64,
+    /// The first few bytes in hex, with `..` after them if there are more
+    pub hex: String,
+    pub part: Part,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub enum Part {
+    Header {
+        version: u8,
+        algorithm: Algorithm,
+    },
+    /// A whole stored frame: its magic, length and the bytes as they are
+    StoredFrame {
+        len: u64,
+    },
+    /// A chunk marker and the [ChunkMarker::len] bytes of literals after it. [Part::Chunk::bits]
+    /// is there for huffman coded literals
+    Chunk {
+        len: u8,
+        bits: Option<ChunkBits>,
+    },
+    /// A match's offset and length, as they were written
+    Match {
+        offset: u64,
+        len: u64,
+    },
+    Control(ControlRecord),
+    /// An optional control record this build doesn't know, which decoding skips
+    UnknownControl(u8),
+    /// A stream written with [Algorithm::Huffman], up to and including its END code
+    Bitstream {
+        literals: u64,
+    },
+    /// An archive's index of where its entries are, see [crate::archive]
+    Index {
+        len: u64,
+    },
+    /// A signature over the frames before it, see [crate::signing]
+    Signature,
+    /// Bytes after the end of the stream which decoding ignores
+    Trailing,
+}
+
+impl Part {
+    /// What sort of part it is, for tallying them up
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::Header { .. } => "header",
+            Self::StoredFrame { .. } => "stored frame",
+            Self::Chunk { .. } => "chunk",
+            Self::Match { .. } => "match",
+            Self::Control(_) | Self::UnknownControl(_) => "control record",
+            Self::Bitstream { .. } => "bitstream",
+            Self::Index { .. } => "index",
+            Self::Signature => "signature",
+            Self::Trailing => "trailing",
+        }
+    }
+}
+
+/// Where the huffman codes in a chunk fall. Chunks following each other are unpacked as one
+/// run of literals, so a code can start in one chunk and end in the next
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+pub struct ChunkBits {
+    /// How many codes start in the chunk
+    pub codes: u64,
+    /// Bits at the start of the chunk ending a code from the chunk before
+    pub carried_bits: u64,
+    /// After the run's last code, the bits of the END code and padding filling the last byte.
+    /// Only in the run's last chunk
+    pub padding_bits: Option<u64>,
+}
+
+/// Map out the stream in [bytes], frame by frame. Anything that can't be made sense of stops
+/// the map there, returning the spans up to it along with the error
+pub fn layout(bytes: &[u8]) -> (Vec<Span>, Result<()>) {
+    let mut walker = Walker {
+        bytes,
+        position: 0,
+        spans: Vec::new(),
+    };
+    let result = walker.frames();
+    (walker.spans, result)
+}
+
+struct Walker<'a> {
+    bytes: &'a [u8],
+    position: usize,
+    spans: Vec<Span>,
+}
+
+impl Walker<'_> {
+    fn push(&mut self, len: usize, part: Part) {
+        let bytes = &self.bytes[self.position..self.position + len];
+        let mut hex = bytes
+            .iter()
+            .take(MAX_HEX_LEN)
+            .map(|v| format!("{:02x}", v))
+            .collect::<Vec<_>>()
+            .join(" ");
+        if len > MAX_HEX_LEN {
+            hex.push_str(" ..");
+        }
+        self.spans.push(Span {
+            start: self.position as u64,
+            len: len as u64,
+            hex,
+            part,
+        });
+        self.position += len;
+    }
+
+    fn rest(&self) -> &[u8] {
+        &self.bytes[self.position..]
+    }
+
+    fn frames(&mut self) -> Result<()> {
+        self.frame()?;
+        while !self.rest().is_empty() {
+            let prefix = &self.rest()[..header::FRAME_PREFIX_LEN.min(self.rest().len())];
+            if header::starts_signature(prefix) {
+                self.push(self.rest().len(), Part::Signature);
+            } else if header::starts_index(prefix) {
+                self.index()?;
+            } else if header::starts_frame(prefix) {
+                self.frame()?;
+            } else {
+                self.push(self.rest().len(), Part::Trailing);
+            }
+        }
+        Ok(())
+    }
+
+    fn index(&mut self) -> Result<()> {
+        let mut rest = &self.rest()[header::INDEX_MAGIC.len()..];
+        let before = rest.len();
+        crate::archive::skip_index(&mut rest)?;
+        let index_len = header::INDEX_MAGIC.len() + before - rest.len();
+        // The body, without the magic and its length either side
+        let len = (index_len - header::INDEX_MAGIC.len() - 8) as u64;
+        self.push(index_len, Part::Index { len });
+        Ok(())
+    }
+
+    fn frame(&mut self) -> Result<()> {
+        let header_bytes = Header::read_bytes_from(self.rest(), DEFAULT_MAX_HEADER_SIZE)?;
+        if stored::is_stored(&header_bytes) {
+            let (len, payload) = split_varint(&self.rest()[stored::MAGIC.len()..])
+                .ok_or(Error::TruncatedStream("stored length"))?;
+            if (payload.len() as u64) < len {
+                return Err(Error::TruncatedStream("stored bytes"));
+            }
+            let frame_len = self.rest().len() - payload.len() + len as usize;
+            self.push(frame_len, Part::StoredFrame { len });
+            return Ok(());
+        }
+        let header = Header::from_bytes(&header_bytes)?;
+        self.push(
+            header_bytes.len(),
+            Part::Header {
+                version: header.version(),
+                algorithm: header.algorithm(),
+            },
+        );
+        self.tokens(header)
+    }
+
+    /// The tokens of a frame with [header], up to the end of stream record
+    fn tokens(&mut self, mut header: Header) -> Result<()> {
+        if header.algorithm() == Algorithm::Huffman {
+            self.bitstream(&header)?;
+        }
+        let format = header.format();
+        let mut code_map = huffman_literals(&header).map(huffman::tree_to_code_map);
+        // Where the chunks following each other start, waiting for the end of their run
+        let mut run = Vec::new();
+        loop {
+            let Some(&v) = self.rest().first() else {
+                self.chunks(&mut run, code_map.as_ref(), &header);
+                return match format.trailer {
+                    true => Err(Error::TruncatedStream("end of stream marker")),
+                    false => Ok(()),
+                };
+            };
+            if v >> 6 != 0b11 {
+                self.chunks(&mut run, code_map.as_ref(), &header);
+            }
+            match v >> 6 {
+                0b11 => {
+                    let len = ChunkMarker::from_encoded_u8(v).len;
+                    if self.rest().len() <= len as usize {
+                        return Err(Error::TruncatedStream("raw byte chunk"));
+                    }
+                    run.push(self.position);
+                    // Pushed once the run's over, when its bits can be worked out
+                    self.position += 1 + len as usize;
+                }
+                0b10 => {
+                    let rest = self.rest();
+                    let (len, (offset, match_len)) = match format.binned_matches {
+                        true => {
+                            let len = (1..=rest.len())
+                                .find(|len| OffsetLen::binned_len(&rest[..*len]) == Some(*len))
+                                .ok_or(Error::TruncatedStream("offset len"))?;
+                            (len, OffsetLen::read_binned(&rest[..len]))
+                        }
+                        false => {
+                            let len = OffsetLen::whole_bytes_len(v);
+                            if rest.len() < len {
+                                return Err(Error::TruncatedStream("offset len"));
+                            }
+                            (len, OffsetLen::read_whole_bytes(&rest[..len]))
+                        }
+                    };
+                    self.push(
+                        len,
+                        Part::Match {
+                            offset,
+                            len: match_len,
+                        },
+                    );
+                }
+                0b00 if format.control_records => {
+                    let (value_len, after_len) = split_varint(&self.rest()[1..])
+                        .ok_or(Error::TruncatedStream("control record"))?;
+                    let value = usize::try_from(value_len)
+                        .ok()
+                        .and_then(|value_len| after_len.get(..value_len))
+                        .ok_or(Error::TruncatedStream("control record"))?;
+                    let len = self.rest().len() - after_len.len() + value.len();
+                    let part = match ControlRecord::from_parts(v, value)? {
+                        None => Part::UnknownControl(v),
+                        Some(record) => {
+                            if let ControlRecord::NewHuffmanTree(tree) = &record {
+                                header.huffman_tree = Some(tree.clone());
+                                code_map = huffman_literals(&header).map(huffman::tree_to_code_map);
+                            }
+                            Part::Control(record)
+                        }
+                    };
+                    let end = matches!(part, Part::Control(ControlRecord::EndOfStream(_)));
+                    self.push(len, part);
+                    if end {
+                        return Ok(());
+                    }
+                }
+                _ => return Err(Error::InvalidToken(v)),
+            }
+        }
+    }
+
+    /// Push the chunks of [run], whose literals are huffman coded with [code_map] if given
+    fn chunks(&mut self, run: &mut Vec<usize>, code_map: Option<&CodeMap>, header: &Header) {
+        if run.is_empty() {
+            return;
+        }
+        let end = self.position;
+        let chunk_len = |start: usize| ChunkMarker::from_encoded_u8(self.bytes[start]).len;
+        let bits = code_map.map(|code_map| {
+            let packed: Vec<u8> = run
+                .iter()
+                .flat_map(|&start| &self.bytes[start + 1..start + 1 + chunk_len(start) as usize])
+                .copied()
+                .collect();
+            // Checked to be there when the code map was made
+            let tree = header.huffman_tree.as_ref().unwrap();
+            code_bits(
+                &packed,
+                run.iter().map(|&start| chunk_len(start)),
+                tree,
+                code_map,
+            )
+        });
+        for (i, start) in std::mem::take(run).into_iter().enumerate() {
+            let len = chunk_len(start);
+            self.position = start;
+            let bits = bits.as_ref().map(|bits| bits[i].clone());
+            self.push(1 + len as usize, Part::Chunk { len, bits });
+        }
+        self.position = end;
+    }
+
+    fn bitstream(&mut self, header: &Header) -> Result<()> {
+        // Checked to be there when the header was read
+        let mut decoder = BitstreamDecoder::new(header.huffman_tree.as_ref().unwrap());
+        let mut literals = Vec::new();
+        for (i, &v) in self.rest().iter().enumerate() {
+            if decoder.push_byte(v, &mut literals) {
+                let literals = literals.len() as u64;
+                self.push(i + 1, Part::Bitstream { literals });
+                return Ok(());
+            }
+        }
+        Err(Error::TruncatedStream("huffman bitstream"))
+    }
+}
+
+/// The tree literals are packed with in a stream with [header], if they're huffman coded
+fn huffman_literals(header: &Header) -> Option<&HuffmanTree> {
+    match (header.algorithm(), header.rans_table()) {
+        (Algorithm::LzHuffman, None) => header.huffman_tree.as_ref(),
+        _ => None,
+    }
+}
+
+/// Where the codes fall in [packed], a run of chunks [chunk_lens] bytes long coded with [tree]
+fn code_bits(
+    packed: &[u8],
+    chunk_lens: impl Iterator<Item = u8>,
+    tree: &HuffmanTree,
+    code_map: &CodeMap,
+) -> Vec<ChunkBits> {
+    let mut code_starts = Vec::new();
+    let mut codes_end = 0;
+    for literal in huffman::unpack_bytes(packed, tree) {
+        code_starts.push(codes_end);
+        codes_end += code_map
+            .code_for(literal)
+            .map_or(0, |bits| bits.len() as u64);
+    }
+    let mut chunks: Vec<ChunkBits> = Vec::new();
+    let mut chunk_start = 0;
+    let mut codes = code_starts.iter().peekable();
+    for len in chunk_lens {
+        let chunk_end = chunk_start + 8 * len as u64;
+        let first = codes.peek().map_or(codes_end, |start| **start);
+        let mut chunk = ChunkBits {
+            carried_bits: first.min(chunk_end).saturating_sub(chunk_start),
+            ..Default::default()
+        };
+        while codes.next_if(|start| **start < chunk_end).is_some() {
+            chunk.codes += 1;
+        }
+        chunks.push(chunk);
+        chunk_start = chunk_end;
+    }
+    if let Some(last) = chunks.last_mut() {
+        last.padding_bits = Some(chunk_start - codes_end);
+    }
+    chunks
+}
+
+/// One line per span: where it is, its bytes and what they are
+impl Display for Span {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{:08x} {:>5}  {:<26} ", self.start, self.len, self.hex)?;
+        match &self.part {
+            Part::Header { version, algorithm } => {
+                write!(f, "Header: version {}, {:?}", version, algorithm)
+            }
+            Part::StoredFrame { len } => write!(f, "Stored frame: {} bytes", len),
+            Part::Chunk { len, bits } => {
+                write!(f, "Chunk: {} bytes", len)?;
+                if let Some(bits) = bits {
+                    write!(f, ", {} codes start in it", bits.codes)?;
+                    if bits.carried_bits > 0 {
+                        write!(f, " after {} bits of the last", bits.carried_bits)?;
+                    }
+                    if let Some(padding_bits) = bits.padding_bits {
+                        write!(f, ", then {} bits of END and padding", padding_bits)?;
+                    }
+                }
+                Ok(())
+            }
+            Part::Match { offset, len } => write!(f, "Match: offset {}, len {}", offset, len),
+            Part::Control(record) => write!(f, "Control: {}", record),
+            Part::UnknownControl(v) => write!(f, "Unknown control record {:#010b}", v),
+            Part::Bitstream { literals } => write!(f, "Bitstream: {} literals", literals),
+            Part::Index { len } => write!(f, "Archive index: {} bytes", len),
+            Part::Signature => write!(f, "Signature"),
+            Part::Trailing => write!(f, "Trailing bytes"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::control::ControlRecord;
+    use crate::encode::encode_from_reader;
+    use crate::error::Error;
+    use crate::header;
+    use crate::layout::{layout, ChunkBits, Part};
+    use crate::test_util::encode;
+    use crate::{Algorithm, EncodeOptions};
+
+    #[test]
+    fn covers_the_stream() {
+        let input = "The cat sat on the mat. The cat sat on the hat. ".repeat(20);
+        for algorithm in [Algorithm::LzHuffman, Algorithm::Lz, Algorithm::Huffman] {
+            let encoded = encode(
+                input.as_bytes(),
+                &EncodeOptions::default().algorithm(algorithm),
+            );
+            let (spans, result) = layout(&encoded);
+            result.unwrap();
+            // Spans follow on from each other to the end
+            let mut position = 0;
+            for span in &spans {
+                assert_eq!(position, span.start);
+                position += span.len;
+            }
+            assert_eq!(encoded.len() as u64, position);
+            assert!(matches!(spans[0].part, Part::Header { .. }));
+            assert!(matches!(
+                spans.last().unwrap().part,
+                Part::Control(ControlRecord::EndOfStream(_))
+            ));
+            let matches = spans
+                .iter()
+                .filter(|span| matches!(span.part, Part::Match { .. }))
+                .count();
+            assert_eq!(algorithm != Algorithm::Huffman, matches > 0);
+        }
+    }
+
+    #[test]
+    fn huffman_chunk_bits() {
+        // Long enough a run of literals to take several chunks
+        let input: Vec<u8> = (0..=255u8).cycle().take(600).map(|v| v / 3).collect();
+        let options = EncodeOptions::default().algorithm(Algorithm::LzHuffman);
+        let (spans, result) = layout(&encode(&input, &options));
+        result.unwrap();
+        let bits: Vec<&ChunkBits> = spans
+            .iter()
+            .filter_map(|span| match &span.part {
+                Part::Chunk { bits, .. } => bits.as_ref(),
+                _ => None,
+            })
+            .collect();
+        assert!(bits.len() > 1);
+        // Each code starts in one chunk, so a run's are all counted
+        let codes: u64 = bits.iter().map(|bits| bits.codes).sum();
+        let literals = input.len() as u64;
+        assert!(codes <= literals && codes > 0);
+        assert!(bits.iter().any(|bits| bits.carried_bits > 0));
+        assert!(bits.iter().any(|bits| bits.padding_bits.is_some()));
+
+        let options = EncodeOptions::default().algorithm(Algorithm::Lz);
+        let (spans, _) = layout(&encode(&input, &options));
+        assert!(spans
+            .iter()
+            .all(|span| !matches!(span.part, Part::Chunk { bits: Some(_), .. })));
+    }
+
+    #[test]
+    fn stored_and_truncated() {
+        let mut encoded = Vec::new();
+        let options = EncodeOptions::default().algorithm(Algorithm::Stored);
+        encode_from_reader(&b"abc"[..], &mut encoded, &options).unwrap();
+        let (spans, result) = layout(&encoded);
+        result.unwrap();
+        assert_eq!(1, spans.len());
+        assert_eq!(Part::StoredFrame { len: 3 }, spans[0].part);
+        assert_eq!(encoded.len() as u64, spans[0].len);
+
+        // An archive's index, here of no entries, after the frames
+        let frames_len = encoded.len();
+        encoded.extend(header::INDEX_MAGIC);
+        encoded.extend([0, 0, 0, 1, 0x90, 0, 0, 0, 1]);
+        let (spans, result) = layout(&encoded);
+        result.unwrap();
+        assert_eq!(Part::Index { len: 1 }, spans[1].part);
+        assert_eq!(frames_len as u64, spans[1].start);
+        assert_eq!(spans.len(), 2);
+
+        let encoded = encode(b"hello hello hello", &EncodeOptions::default());
+        let (spans, result) = layout(&encoded[..encoded.len() - 3]);
+        assert!(matches!(result, Err(Error::TruncatedStream(_))));
+        assert!(matches!(spans[0].part, Part::Header { .. }));
+    }
+}
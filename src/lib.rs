@@ -0,0 +1,115 @@
+pub mod archive;
+#[cfg(feature = "async")]
+pub mod async_io;
+mod bit_io;
+mod blocks;
+#[cfg(feature = "bwt")]
+mod bwt;
+#[cfg(feature = "cache")]
+pub mod cache;
+mod chunking;
+pub mod config;
+mod control;
+pub mod cost;
+#[cfg(feature = "crypto")]
+pub mod crypto;
+pub mod decode;
+pub mod diff;
+pub mod encode;
+mod entropy;
+pub mod error;
+pub mod file_io;
+mod filter;
+mod format;
+pub mod grep;
+pub mod gzip;
+mod header;
+mod helpers;
+#[cfg(feature = "http")]
+pub mod http;
+mod huffman;
+mod input;
+pub mod layout;
+mod long_range;
+mod match_finder;
+mod offset_len;
+mod optimal_parse;
+mod output;
+mod output_stream;
+pub mod recover;
+pub mod resume;
+pub mod selftest;
+#[cfg(feature = "crypto")]
+pub mod signing;
+mod sniff;
+#[cfg(test)]
+mod test_util;
+#[cfg(any(test, feature = "testing"))]
+pub mod testing;
+pub mod throttle;
+mod token;
+pub mod trace;
+pub mod volumes;
+
+#[cfg(feature = "async")]
+pub use async_io::{AsyncLizardDecoder, AsyncLizardEncoder};
+pub use bit_io::{BitReader, BitWriter};
+pub use control::{ControlRecord, Trailer};
+pub use decode::{DecodeOptions, DecodeSummary};
+pub use encode::{Algorithm, AutoSettings, EncodeOptions, EncodeSummary, StreamEncoder};
+pub use entropy::{Entropy, EntropyCoder, HuffmanCoder, RansTable};
+pub use error::{Error, Result};
+pub use filter::Filter;
+pub use header::Header;
+pub use huffman::{BitStream, Bits, CodeMap, HuffmanTree, Symbol};
+pub use match_finder::MatchStrategy;
+pub use offset_len::{MatchLen, OffsetLen, WindowPos};
+pub use output_stream::StreamStats;
+pub use token::Token;
+
+/// Default for [encode::StreamEncoder::segment_len], how much input goes in each frame
+pub const STREAM_SEGMENT_LEN: usize = 1 << 20;
+
+/// The most of the previous frame's output a frame's window can start with, which is as much
+/// as the decoder keeps between frames
+pub const MAX_PRIMED_LEN: usize = 1 << 16;
+
+/// Default size of the lookback buffer (aka window) matches are made against
+pub const MAX_LOOKBACK_BUFFER_LEN: usize = 1000;
+const MAX_READ_BUFFER_LEN: usize = 400;
+// Matches can be extended beyond the read buffer, this caps how far by default
+pub const MAX_MATCH_LEN: usize = u16::MAX as usize;
+
+/// Encoded inputs smaller than this are always decoded on the calling thread, as starting worker
+/// threads would take longer than decoding them
+pub const MIN_THREADED_DECODE_LEN: u64 = 1 << 20;
+
+/// Runs of literals are broken up with a block boundary after at most this many (see
+/// [EncodeOptions::max_literal_run]), as the decoder holds a whole run before unpacking it.
+/// Costs 2 bytes per run broken, and keeps decoding within a fixed amount of memory
+pub const MAX_LITERAL_RUN_LEN: usize = 1 << 16;
+
+/// Default for [DecodeOptions::max_output_size], far more than any real file but still finite
+pub const DEFAULT_MAX_OUTPUT_SIZE: u64 = 1 << 40;
+
+/// Default for [DecodeOptions::max_header_size]. Real headers are a few KB, most of which is
+/// the huffman tree
+pub const DEFAULT_MAX_HEADER_SIZE: usize = 1 << 24;
+
+/// Default number of input bytes put through the BWT together. Bigger blocks compress better,
+/// but sorting them is slower and the decoder has to hold a whole block
+pub const BWT_BLOCK_SIZE: usize = 100_000;
+
+/// Default for [EncodeOptions::level]
+pub const DEFAULT_LEVEL: u8 = 6;
+/// Highest [EncodeOptions::level], which parses optimally rather than greedily
+pub const MAX_LEVEL: u8 = 9;
+
+// It's not worth doing matches under a size where offset_len would take up more space
+const MIN_MATCH_SIZE: usize = 4;
+// Upper bound when picking the min match size automatically
+const MAX_AUTO_MIN_MATCH_SIZE: usize = 32;
+// How much of the start of the input is looked at when picking the min match size
+const MIN_MATCH_SAMPLE_LEN: usize = 4096;
+// How much of the start of each frame's input is looked at by EncodeOptions::auto_level
+const AUTO_LEVEL_SAMPLE_LEN: usize = 64 * 1024;
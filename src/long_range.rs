@@ -0,0 +1,314 @@
+//! Matches further back than the window ([crate::EncodeOptions::window_size]) can see, like
+//! zstd's long distance matching. Every [FINGERPRINT_LEN] bytes of the input so far, a
+//! fingerprint of the [FINGERPRINT_LEN] bytes starting there is put in a table, and as the
+//! encoder goes along it looks up the fingerprint of the bytes in front of it. A hit that's out of
+//! the window but within [crate::EncodeOptions::long_range] is checked and extended into a match,
+//! and the bytes between such matches are tokenised as usual. The decoder keeps the long range
+//! window of output as its history, so it's the second, bigger tier of the window rather than
+//! anything the matchers search
+//!
+//! Only repeats of at least [FINGERPRINT_LEN] bytes are found, and then only those lined up with
+//! a fingerprint somewhere in their first [FINGERPRINT_LEN] bytes, which is what whole repeated
+//! files or sections look like
+
+use std::collections::HashMap;
+use std::io::Read;
+
+use crate::chunking::GEAR;
+use crate::encode::{tokenise_window, EncodeOptions};
+use crate::error::Result;
+use crate::offset_len::{MatchLen, OffsetLen, WindowPos};
+use crate::optimal_parse::Prices;
+use crate::token::Token;
+
+/// Bytes covered by a fingerprint, and so the shortest long range match. The gear hash
+/// shifts each byte out after 64 more
+pub(crate) const FINGERPRINT_LEN: usize = 64;
+/// Input read at a time. Bytes without long range matches are tokenised at most this many at a
+/// time, as otherwise they'd all be held
+const SEGMENT_LEN: usize = 1 << 20;
+
+/// The gear hash of [bytes], which depends only on the last 64 of them
+fn fingerprint(bytes: &[u8]) -> u64 {
+    bytes
+        .iter()
+        .fold(0u64, |hash, b| (hash << 1).wrapping_add(GEAR[*b as usize]))
+}
+
+/// Input read so far, from [base] bytes in, along with where its fingerprints were
+struct History {
+    data: Vec<u8>,
+    /// Of [data]'s first byte, counting the primer
+    base: usize,
+    /// Fingerprint to where the bytes it was taken of start, counting the primer
+    table: HashMap<u64, usize>,
+    /// Fingerprints of bytes starting before here have been taken
+    indexed: usize,
+}
+
+impl History {
+    /// Take the fingerprints of whatever's a whole [FINGERPRINT_LEN] bytes before [end]
+    fn index_up_to(&mut self, end: usize) {
+        while self.indexed + FINGERPRINT_LEN <= end {
+            let start = self.indexed - self.base;
+            let hash = fingerprint(&self.data[start..start + FINGERPRINT_LEN]);
+            self.table.insert(hash, self.indexed);
+            self.indexed += FINGERPRINT_LEN;
+        }
+    }
+
+    /// Forget everything before [keep], along with fingerprints of it
+    fn drop_before(&mut self, keep: usize) {
+        // Fingerprints are still to be taken from [indexed] on
+        let keep = keep.min(self.indexed);
+        if keep <= self.base {
+            return;
+        }
+        self.data.drain(..keep - self.base);
+        self.base = keep;
+        let table_len = self.table.len();
+        // Only now and again, as it's a pass over the whole table
+        if table_len > 2 * self.data.len() / FINGERPRINT_LEN {
+            self.table.retain(|_, start| *start >= keep);
+        }
+    }
+
+    /// The bytes from [start] to [end], after the [window] bytes before them (or as many as
+    /// there are)
+    fn gap(&self, start: usize, end: usize, window: usize) -> (&[u8], &[u8]) {
+        let primed = start.saturating_sub(window).max(self.base);
+        let (primer, gap) = self.data[primed - self.base..end - self.base].split_at(start - primed);
+        (primer, gap)
+    }
+
+    /// The match for the bytes from [start], which [candidate] has the same fingerprint as, if
+    /// they really are the same. Extended back as far as [gap_start] and forward as far as
+    /// there's input, up to [max_len] long. Gives where it starts, how far back it is and how
+    /// long
+    fn check(
+        &self,
+        start: usize,
+        candidate: usize,
+        gap_start: usize,
+        max_len: usize,
+    ) -> Option<(usize, usize, usize)> {
+        if candidate < self.base {
+            return None;
+        }
+        let data = |pos: usize| self.data[pos - self.base];
+        let end = self.base + self.data.len();
+        let mut len = (0..FINGERPRINT_LEN)
+            .take_while(|i| data(start + i) == data(candidate + i))
+            .count();
+        if len < FINGERPRINT_LEN {
+            return None;
+        }
+        while start + len < end && len < max_len && data(start + len) == data(candidate + len) {
+            len += 1;
+        }
+        let mut back = 0;
+        while start - back > gap_start
+            && candidate - back > self.base
+            && len < max_len
+            && data(start - back - 1) == data(candidate - back - 1)
+        {
+            back += 1;
+            len += 1;
+        }
+        Some((start - back, start - candidate, len))
+    }
+}
+
+/// Tokenise [input_reader] as [crate::encode::tokenise_window] would, but also making matches
+/// from further back than the window, as far as [long_range]. [primer] is taken as coming
+/// before the input, and long range matches can reach back into it too
+pub(crate) fn tokenise<R: Read>(
+    mut input_reader: R,
+    primer: &[u8],
+    long_range: usize,
+    min_match: usize,
+    prices: Option<&Prices>,
+    options: &EncodeOptions,
+    mut emit: impl FnMut(&Token) -> Result<()>,
+) -> Result<()> {
+    let mut history = History {
+        data: primer.to_vec(),
+        base: 0,
+        table: HashMap::new(),
+        indexed: 0,
+    };
+    let mut gap_start = primer.len();
+    let mut done = false;
+    // The bytes between long range matches, with the window leading up to them as their primer
+    let tokenise_gap = |history: &History,
+                        start: usize,
+                        end: usize,
+                        emit: &mut dyn FnMut(&Token) -> Result<()>| {
+        let (primer, gap) = history.gap(start, end, options.window_size);
+        tokenise_window(gap, primer, min_match, prices, options, emit)
+    };
+    let mut pos = gap_start;
+    // Of the bytes from the first, rolled along a byte at a time
+    let mut hash: Option<(usize, u64)> = None;
+    while !done {
+        // Whatever's further back than the long range can't be matched, but the window before
+        // the gap is its primer
+        let keep = gap_start
+            .saturating_sub(options.window_size)
+            .min(pos.saturating_sub(long_range));
+        history.drop_before(keep);
+        let read = (&mut input_reader)
+            .take(SEGMENT_LEN as u64)
+            .read_to_end(&mut history.data)?;
+        done = read < SEGMENT_LEN;
+        let end = history.base + history.data.len();
+        while pos + FINGERPRINT_LEN <= end {
+            history.index_up_to(pos);
+            let ahead = &history.data[pos - history.base..][..FINGERPRINT_LEN];
+            let rolled = match hash {
+                Some((at, hash)) if at + 1 == pos => {
+                    (hash << 1).wrapping_add(GEAR[ahead[FINGERPRINT_LEN - 1] as usize])
+                }
+                _ => fingerprint(ahead),
+            };
+            hash = Some((pos, rolled));
+            let found = history
+                .table
+                .get(&rolled)
+                .filter(|candidate| {
+                    let distance = pos - **candidate;
+                    distance > options.window_size && distance <= long_range
+                })
+                .and_then(|candidate| {
+                    history.check(pos, *candidate, gap_start, options.max_match_len)
+                });
+            let Some((start, distance, len)) = found else {
+                pos += 1;
+                continue;
+            };
+            if start > gap_start {
+                tokenise_gap(&history, gap_start, start, &mut emit)?;
+            }
+            let matched = history.data[start - history.base..][..len].to_vec();
+            // Checked to be within the long range, and no longer than the longest match
+            let offset = WindowPos::new(distance, long_range).unwrap();
+            let len = MatchLen::new(len).unwrap();
+            emit(&Token::Match(OffsetLen::new_with_match(
+                offset,
+                len,
+                Some(matched),
+            )))?;
+            gap_start = start + len.get();
+            pos = gap_start;
+        }
+        // Don't hold on to too much without a match
+        if !done && pos - gap_start >= SEGMENT_LEN {
+            tokenise_gap(&history, gap_start, pos, &mut emit)?;
+            gap_start = pos;
+        }
+    }
+    let end = history.base + history.data.len();
+    if end > gap_start {
+        tokenise_gap(&history, gap_start, end, &mut emit)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use crate::long_range::{tokenise, FINGERPRINT_LEN};
+    use crate::{EncodeOptions, MatchStrategy, Token, MAX_LOOKBACK_BUFFER_LEN};
+
+    fn random(len: usize, seed: u32) -> Vec<u8> {
+        (0..len)
+            .scan(seed, |x, _| {
+                *x = x.wrapping_mul(1_103_515_245).wrapping_add(12345);
+                Some((*x >> 16) as u8)
+            })
+            .collect()
+    }
+
+    /// The tokens [input] is coded as with a long range of [long_range]
+    fn tokens(input: &[u8], primer: &[u8], long_range: usize) -> Vec<Token> {
+        // Hash chains, as scanning the window is slow for this much input in debug builds
+        let options = EncodeOptions::default().match_strategy(MatchStrategy::HashChain);
+        let mut tokens = Vec::new();
+        tokenise(input, primer, long_range, 4, None, &options, |token| {
+            tokens.push(token.clone());
+            Ok(())
+        })
+        .unwrap();
+        tokens
+    }
+
+    /// [tokens] decoded after [primer]
+    fn decode(tokens: &[Token], primer: &[u8]) -> Vec<u8> {
+        let mut decoded = primer.to_vec();
+        for token in tokens {
+            match token {
+                Token::Literal(b) => decoded.push(*b),
+                Token::Match(offset_len) => {
+                    let start = decoded.len() - offset_len.offset().get();
+                    for i in start..start + offset_len.len.get() {
+                        decoded.push(decoded[i]);
+                    }
+                }
+                token => panic!("Unexpected {:?}", token),
+            }
+        }
+        decoded.split_off(primer.len())
+    }
+
+    /// Matches from further back than the window
+    fn long_matches(tokens: &[Token]) -> Vec<(usize, usize)> {
+        tokens
+            .iter()
+            .filter_map(|token| match token {
+                Token::Match(offset_len) if offset_len.offset().get() > MAX_LOOKBACK_BUFFER_LEN => {
+                    Some((offset_len.offset().get(), offset_len.len.get()))
+                }
+                _ => None,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn finds_long_matches() {
+        let repeated = random(5000, 1);
+        let mut input = repeated.clone();
+        input.extend(random(20_000, 2));
+        // Not lined up with the fingerprints
+        input.extend(&repeated[10..]);
+        input.extend(random(100, 3));
+        let tokens = tokens(&input, &[], 1 << 20);
+        assert_eq!(input, decode(&tokens, &[]));
+        // All of it, extended back to where it started
+        assert_eq!(vec![(24_990, 4990)], long_matches(&tokens));
+
+        // Not when it's out of range
+        let tokens = super::test::tokens(&input, &[], 20_000);
+        assert_eq!(input, decode(&tokens, &[]));
+        assert!(long_matches(&tokens).is_empty());
+
+        // Or too short to have a fingerprint
+        let mut input = repeated[..FINGERPRINT_LEN - 1].to_vec();
+        input.extend(random(2000, 4));
+        input.extend(&repeated[..FINGERPRINT_LEN - 1]);
+        assert!(long_matches(&super::test::tokens(&input, &[], 1 << 20)).is_empty());
+    }
+
+    #[test]
+    fn across_segments_and_primer() {
+        // Further apart than a segment
+        let primer = random(3000, 5);
+        let mut input = random(1_500_000, 6);
+        input.extend(&primer);
+        input.extend(&input[1000..11_000].to_vec());
+        let tokens = tokens(&input, &primer, 4 << 20);
+        assert_eq!(input, decode(&tokens, &primer));
+        let matches = long_matches(&tokens);
+        assert_eq!(2, matches.len(), "{:?}", matches);
+        assert_eq!(3000, matches[0].1);
+        assert_eq!(10_000, matches[1].1);
+    }
+}
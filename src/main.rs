@@ -1,47 +1,118 @@
-use std::collections::VecDeque;
+use clap::{ArgEnum, Args, Parser};
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::ffi::OsStr;
 use std::fs::File;
-use std::io::{BufRead, BufReader, BufWriter, Read, Write};
+use std::io::{BufReader, IsTerminal, Write};
+use std::num::NonZeroUsize;
 use std::ops::Range;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
+use std::process::ExitCode;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{mpsc, Mutex};
+use std::time::Instant;
 
-use clap::{Args, Parser};
-use log::info;
+use lizards::config::{self, Config};
+#[cfg(feature = "crypto")]
+use lizards::crypto::Passphrase;
+use lizards::file_io::{
+    expand_globs, FileInputOutput, FileInputOutputBuilder, InputFile, OutputTarget,
+    OverwritePolicy, DEFAULT_SUFFIX,
+};
+use lizards::grep::{GrepLine, GrepOptions};
+use lizards::selftest;
+#[cfg(feature = "crypto")]
+use lizards::signing;
+use lizards::throttle::Throttled;
+use lizards::volumes::{self, VolumeReader};
+use lizards::{
+    archive, decode, diff, encode, gzip, layout, recover, resume, trace, Algorithm, AutoSettings,
+    DecodeOptions, EncodeOptions, Entropy, Error, Filter, Header, MatchStrategy, StreamStats,
+    Token, BWT_BLOCK_SIZE, DEFAULT_LEVEL, DEFAULT_MAX_HEADER_SIZE, DEFAULT_MAX_OUTPUT_SIZE,
+    MAX_LEVEL, MAX_LOOKBACK_BUFFER_LEN,
+};
+#[cfg(feature = "crypto")]
+use zeroize::Zeroizing;
 
-use file_io::FileInputOutput;
-use offset_len::OffsetLen;
-use output_stream::OutputStream;
-use std::str::FromStr;
+#[derive(Args, Debug)]
+struct CommandLineArgs {
+    /// Input filename
+    #[clap(
+        short,
+        long,
+        required_unless_present = "glob",
+        parse(from_os_str),
+        allow_invalid_utf8 = true
+    )]
+    filename: Option<PathBuf>,
 
-mod decode;
-mod encode;
-mod file_io;
-mod header;
-mod helpers;
-mod huffman;
-mod offset_len;
-mod output_stream;
+    /// Glob pattern for input files (e.g. 'logs/**/*.log'), expanded by lizards rather than the
+    /// shell. Can be given multiple times, each matched file is processed separately
+    #[clap(long, conflicts_with = "filename")]
+    glob: Vec<String>,
 
-const MAX_LOOKBACK_BUFFER_LEN: usize = 1000;
-const MAX_READ_BUFFER_LEN: usize = 400;
+    /// Glob pattern for files to leave out of those matched by [glob]. Can be given multiple times
+    #[clap(long, requires = "glob")]
+    exclude: Vec<String>,
 
-// It's not worth doing matches under a size where offset_len would take up more space
-const MIN_MATCH_SIZE: usize = 4;
+    /// Output filename. If not provided, will be derived based on input filename. If it's an
+    /// existing directory, the derived filename is used within it (and it must be one with [glob])
+    #[clap(short = 'O', long, parse(from_os_str), allow_invalid_utf8 = true)]
+    output_filename: Option<PathBuf>,
 
-const DEBUG: bool = false;
+    /// What to do when the output exists: fail (the default), overwrite it, rename the output
+    /// (appending .1, .2 and so on) or prompt (asking when attached to a terminal, otherwise
+    /// failing)
+    #[clap(long, arg_enum)]
+    on_conflict: Option<OnConflict>,
 
-#[derive(Args, Debug)]
-struct CommandLineArgs {
-    /// Input filename
-    #[clap(short, long)]
-    filename: String,
+    /// The same as --on-conflict overwrite
+    #[clap(long, short, conflicts_with = "on-conflict")]
+    overwrite: bool,
 
-    /// Output filename. If not provided, will be derived based on input filename
-    #[clap(short = 'O', long)]
-    output_filename: Option<String>,
+    /// Delete the input file once the output has been successfully written and synced to disk
+    #[clap(long, overrides_with = "keep")]
+    rm_source: bool,
 
-    /// By default if [output_filename] exists command will not run unless providing [overwrite]
-    #[clap(long, short)]
-    overwrite: bool,
+    /// Sync the output to disk, and on unix the directory it's in, before reporting success, so
+    /// once a file is reported done it survives a crash or power cut
+    #[clap(long)]
+    fsync: bool,
+
+    /// Keep the input file (default)
+    #[clap(long, overrides_with = "rm-source")]
+    keep: bool,
+
+    /// Store what symlinks in a directory packed with --tar point to, rather than the links
+    #[clap(long, overrides_with = "no-follow-symlinks")]
+    follow_symlinks: bool,
+
+    /// Refuse an input file that's a symlink, rather than reading what it points to. Links in a
+    /// directory packed with --tar are stored as links either way unless [follow_symlinks]
+    #[clap(long, overrides_with = "follow-symlinks")]
+    no_follow_symlinks: bool,
+
+    /// Print a JSON object describing the result (one per line per input file) rather than
+    /// human readable text
+    #[clap(long)]
+    json: bool,
+
+    /// Log more detail to stderr, e.g. a dump of the data around a corrupt match. Give twice for
+    /// debug logging
+    #[clap(short, long, parse(from_occurrences))]
+    verbose: u8,
+
+    /// Threads to use, defaulting to one per core. Files matched by [glob] are processed this
+    /// many at a time, and a single file with --block-size compresses and decompresses this
+    /// many blocks at a time
+    #[clap(long, short = 'j')]
+    threads: Option<usize>,
+
+    /// Ignore the config file (lizards.toml in $XDG_CONFIG_HOME or ~/.config), which otherwise
+    /// gives defaults for options not given here. LIZARDS_LEVEL, LIZARDS_THREADS, LIZARDS_WINDOW
+    /// and LIZARDS_ON_CONFLICT are still used
+    #[clap(long)]
+    no_config: bool,
 }
 
 #[derive(Args, Debug)]
@@ -49,91 +120,2824 @@ struct CompressSpecificArgs {
     #[clap(flatten)]
     common: CommandLineArgs,
 
-    /// Max bytes used as a buffer when compressing. Higher size will use more RAM to run but
-    /// should result in better compression
-    #[clap(long, default_value_t = MAX_LOOKBACK_BUFFER_LEN)]
-    buffer_size: usize,
-}
-#[derive(Args, Debug)]
-struct DecompressSpecificArgs {
+    /// Max bytes used as a buffer when compressing, by default the most the format allows.
+    /// Higher size will use more RAM to run but should result in better compression
+    #[clap(long)]
+    buffer_size: Option<usize>,
+
+    /// Shortest match worth encoding. If not provided, will be picked based on how compressible
+    /// the start of the file looks
+    #[clap(long)]
+    min_match: Option<usize>,
+
+    /// Decode the output after writing it, checking it matches the input
+    #[clap(long)]
+    verify: bool,
+
+    /// Make the output depend only on the input's contents and these options, by not recording
+    /// the input's modification time and permissions
+    #[clap(long)]
+    reproducible: bool,
+
+    /// Format to write. gzip output can be read by standard tools, and is written to the input
+    /// filename with `.gz` appended unless [output_filename] is given
+    #[clap(long, arg_enum, default_value_t = OutputFormat::Lizard)]
+    format: OutputFormat,
+
+    /// How to compress: huffman codes every byte without looking for matches, which suits
+    /// already deduplicated data. lz stores literals between LZSS matches as they are, which is
+    /// faster and suits nearly random data. lz+huffman huffman codes those literals. bwt
+    /// (experimental, needs the bwt feature) uses the Burrows-Wheeler transform instead of
+    /// matching, which is slow but compresses text much better. stored keeps the input as it is.
+    /// Small inputs are stored anyway when that comes out smaller
+    #[clap(long, arg_enum, default_value_t = AlgorithmArg::LzHuffman)]
+    algorithm: AlgorithmArg,
+
+    /// Write runs of a single byte at least this long (4 or more) as a single run record, before
+    /// looking for matches. Helps zero padded binaries and sparse files
+    #[clap(long, value_name = "MIN_RUN")]
+    rle: Option<usize>,
+
+    /// Transform the input before compressing it, undone on decompress. delta8 and delta16
+    /// store the difference from the previous 8 or 16 bit sample, which helps time series and
+    /// image-like binary data a lot
+    #[clap(long, arg_enum, default_value_t = FilterArg::None)]
+    filter: FilterArg,
+
+    /// Bytes transformed together with --algorithm bwt. Bigger blocks compress better but take
+    /// longer, and need more memory to decompress
+    #[clap(long, default_value_t = BWT_BLOCK_SIZE)]
+    bwt_block_size: usize,
+
+    /// Compress blocks of this many bytes independently, each followed by its length and
+    /// checksum, so `lizards recover` can get back everything outside damaged blocks. Only with
+    /// --algorithm lz or lz+huffman
+    #[clap(long, value_name = "BYTES")]
+    block_size: Option<usize>,
+
+    /// End blocks where the content picks rather than every --block-size bytes, which is then
+    /// their average size. Bytes inserted near the start of a file only change the blocks around
+    /// them rather than every block after, for backup tools deduplicating blocks
+    #[clap(long, requires = "block-size")]
+    content_defined_blocks: bool,
+
+    /// Keep the tokens each block is coded as in this directory, keyed by a BLAKE3 hash of the
+    /// block, and reuse them for blocks seen before rather than looking for their matches again.
+    /// Compressing a file that has mostly stayed the same, with --content-defined-blocks, then
+    /// only works hard on what changed. Safe to share between runs, and to delete
+    #[cfg(feature = "cache")]
+    #[clap(
+        long,
+        value_name = "DIR",
+        requires = "block-size",
+        parse(from_os_str),
+        allow_invalid_utf8 = true
+    )]
+    cache_dir: Option<PathBuf>,
+
+    /// Also match repeats of 64 bytes or more from up to this far back (K, M and G suffixes are
+    /// powers of 1024), beyond --buffer-size, e.g. sections of a big file repeated far apart.
+    /// Decompressing holds this much output. Only with --algorithm lz or lz+huffman
+    #[clap(long, value_name = "SIZE", parse(try_from_str = parse_size))]
+    long_range: Option<u64>,
+
+    /// How literals are entropy coded with --algorithm lz+huffman. rans gets closer to their
+    /// entropy than huffman coding, at the cost of some speed
+    #[clap(long, arg_enum, default_value_t = EntropyArg::Huffman)]
+    entropy: EntropyArg,
+
+    /// How matches are looked for: scan checks every position in the window, hash-chain only
+    /// those starting with the same 3 bytes, which is much faster with a big --buffer-size but
+    /// can miss the longest match. Either decompresses the same
+    #[clap(long, arg_enum, default_value_t = MatchFinderArg::Scan)]
+    match_finder: MatchFinderArg,
+
+    /// How hard to try, from 1 to 9. At 9 tokens are picked by what they cost over the whole
+    /// input rather than taking the longest match each time, which is smaller but much slower.
+    /// Lower levels are all the same for now. auto picks the level, --min-match (unless given)
+    /// and whether to huffman code literals from the first 64KiB of the input, recording what it
+    /// picked in the header. Ignored with --format gzip. Defaults to 6
+    #[clap(long, parse(try_from_str = parse_level))]
+    level: Option<LevelArg>,
+
+    /// Record KEY as VALUE in the header, shown by `lizards list`. Can be given multiple times
+    #[clap(long, value_name = "KEY=VALUE", parse(try_from_str = parse_key_value))]
+    meta: Vec<(String, String)>,
+
+    /// Also write a trace of the tokens the input was coded as, one JSON object per line, to
+    /// the input filename with its extension changed to `.dblzd`. `lizards debug-decode` reads
+    /// it back. Not written with --tar or --format gzip
+    #[clap(long)]
+    trace: bool,
+
+    /// Also print what the output stream was made up of: bytes written as literals and
+    /// covered by matches, how many chunks the literals took, and bits of padding. Not counted
+    /// for --format gzip or stored output
+    #[clap(long)]
+    stats: bool,
+
+    /// Split the output into volumes of this many bytes (K, M, G and T suffixes are powers of
+    /// 1024), written to the output filename with `.001`, `.002`, ... appended. Decompressing
+    /// the first volume, or the name without a number, reads them all back
+    #[clap(long, value_name = "SIZE", parse(try_from_str = parse_size))]
+    split_size: Option<u64>,
+
+    /// Hold reading the input and writing the output to this many bytes per second between
+    /// them, e.g. 10MB/s or 500K (suffixes are powers of 1024), so a background job doesn't
+    /// saturate a shared disk. With --tar only the compressed file is held to it
+    #[clap(long, value_name = "RATE", parse(try_from_str = parse_rate))]
+    rate_limit: Option<u64>,
+
+    /// Read the input on a thread of its own, up to this many bytes (K, M, G and T suffixes are
+    /// powers of 1024) ahead of the compressor, so reading the disk and compressing overlap.
+    /// Read 256K at a time. Not with --tar
+    #[clap(long, value_name = "SIZE", parse(try_from_str = parse_size))]
+    read_ahead: Option<u64>,
+
+    /// Write the output (and the --trace) on a thread of its own, holding up to this
+    /// many bytes (K, M, G and T suffixes are powers of 1024) not yet written, so packing the
+    /// output and writing the disk overlap. Written 256K at a time. Not with --tar
+    #[clap(long, value_name = "SIZE", parse(try_from_str = parse_size))]
+    write_behind: Option<u64>,
+
+    /// Pack the directory given as [filename] into a tar stream and compress that, written to
+    /// the directory's name with `.tar.lizard` appended unless [output_filename] is given
+    #[clap(long, conflicts_with = "glob")]
+    tar: bool,
+
+    /// Extension appended to the input's name to name the output, `lizard` by default (or `gz`
+    /// with --format gzip), after `tar.` with --tar. Give decompress the same one
+    #[clap(long, short = 'S', parse(try_from_str = parse_suffix))]
+    suffix: Option<String>,
+
+    /// With --tar, compress files which look already compressed (JPEGs, zips, videos and so on,
+    /// by their extension or first bytes) like anything else, rather than storing them as they
+    /// are
+    #[clap(long, requires = "tar")]
+    force_compress: bool,
+
+    /// Compress as usual but throw the output away, reporting the size it would be and the
+    /// ratio without writing anything. An existing output isn't a conflict, as it's left alone
+    #[clap(long, conflicts_with_all = &["verify", "split-size", "trace", "rm-source", "fsync"])]
+    dry_run: bool,
+
+    /// Encrypt the output with AES-256-GCM, using a key derived from a passphrase asked for
+    /// twice on the terminal, unless given by [passphrase_file] or LIZARDS_PASSPHRASE.
+    /// Decompressing needs it again
+    #[cfg(feature = "crypto")]
+    #[clap(long, conflicts_with = "tar")]
+    encrypt: bool,
+
+    #[cfg(feature = "crypto")]
     #[clap(flatten)]
-    common: CommandLineArgs,
+    secret: PassphraseArgs,
+
+    /// Sign the output with the ed25519 secret key in KEYFILE (see `lizards keygen`), appending
+    /// the signature to it. `lizards verify` checks it
+    #[cfg(feature = "crypto")]
+    #[clap(
+        long,
+        value_name = "KEYFILE",
+        parse(from_os_str),
+        allow_invalid_utf8 = true
+    )]
+    sign: Option<PathBuf>,
+
+    /// With [sign], write the signature to the output filename with `.sig` appended instead of
+    /// appending it to the output
+    #[cfg(feature = "crypto")]
+    #[clap(long, requires = "sign")]
+    detached_signature: bool,
 }
 
-#[derive(Parser, Debug)]
-#[clap(author, version, about, long_about = None)]
-enum CommandLineSubCommand {
-    /// Compress a file to the lizards format
-    Compress(CompressSpecificArgs),
+/// A number of bytes, optionally with a K, M, G or T suffix for powers of 1024
+fn parse_size(s: &str) -> Result<u64, String> {
+    let (digits, shift) = match s.char_indices().last() {
+        Some((index, suffix)) if suffix.is_ascii_alphabetic() => {
+            match "KMGT".find(suffix.to_ascii_uppercase()) {
+                Some(power) => (&s[..index], 10 * (power as u32 + 1)),
+                None => return Err(format!("Unknown size suffix in {:?}", s)),
+            }
+        }
+        _ => (s, 0),
+    };
+    digits
+        .parse::<u64>()
+        .ok()
+        .filter(|size| *size > 0)
+        .and_then(|size| size.checked_mul(1 << shift))
+        .ok_or_else(|| format!("Expected a size such as 100M, got {:?}", s))
+}
 
-    /// Decompress a lizards compressed file
-    Decompress(DecompressSpecificArgs),
+/// An extension for [FileInputOutputBuilder::suffix], which can be given with its dot as gzip
+/// takes it, e.g. `.lz`
+fn parse_suffix(s: &str) -> Result<String, String> {
+    let suffix = s.strip_prefix('.').unwrap_or(s);
+    match suffix.is_empty() || suffix.contains(std::path::is_separator) {
+        true => Err(format!("Expected an extension such as lz, got {:?}", s)),
+        false => Ok(suffix.to_string()),
+    }
 }
 
-fn main() {
-    match CommandLineSubCommand::parse() {
-        CommandLineSubCommand::Compress(args) => {
-            let file_input_output = FileInputOutput::new_from_unencoded(
-                &args.common.filename,
-                args.common.output_filename.as_deref(),
-                true,
-            );
-            file_input_output.input_is_valid(true).unwrap();
-            file_input_output
-                .output_is_valid(true, args.common.overwrite)
-                .unwrap();
+/// A number of bytes per second as [parse_size] takes them, optionally followed by B or iB
+/// and /s, e.g. 10MB/s
+fn parse_rate(s: &str) -> Result<u64, String> {
+    let size = s.strip_suffix("/s").unwrap_or(s);
+    let size = size.strip_suffix('B').unwrap_or(size);
+    let size = size.strip_suffix('i').unwrap_or(size);
+    parse_size(size).map_err(|_| format!("Expected a rate such as 10MB/s, got {:?}", s))
+}
+
+fn parse_level(s: &str) -> Result<LevelArg, String> {
+    if s == "auto" {
+        return Ok(LevelArg::Auto);
+    }
+    s.parse::<u8>()
+        .ok()
+        .filter(|level| (1..=MAX_LEVEL).contains(level))
+        .map(LevelArg::Fixed)
+        .ok_or_else(|| {
+            format!(
+                "Expected a level from 1 to {} or auto, got {:?}",
+                MAX_LEVEL, s
+            )
+        })
+}
+
+fn parse_key_value(s: &str) -> Result<(String, String), String> {
+    match s.split_once('=') {
+        Some((key, value)) if !key.is_empty() => Ok((key.to_string(), value.to_string())),
+        _ => Err(format!("Expected KEY=VALUE, got {:?}", s)),
+    }
+}
+
+#[derive(ArgEnum, Clone, Copy, Debug, PartialEq, Eq)]
+enum OnConflict {
+    Fail,
+    Overwrite,
+    Rename,
+    Prompt,
+}
 
-            encode::encode(&file_input_output);
+impl From<config::OnConflict> for OnConflict {
+    fn from(on_conflict: config::OnConflict) -> Self {
+        match on_conflict {
+            config::OnConflict::Fail => OnConflict::Fail,
+            config::OnConflict::Overwrite => OnConflict::Overwrite,
+            config::OnConflict::Rename => OnConflict::Rename,
+            config::OnConflict::Prompt => OnConflict::Prompt,
         }
-        CommandLineSubCommand::Decompress(args) => {
-            let file_input_output = FileInputOutput::new_from_encoded(
-                &args.common.filename,
-                args.common.output_filename.as_deref(),
-            );
+    }
+}
+
+#[derive(ArgEnum, Clone, Copy, Debug, PartialEq, Eq)]
+enum OutputFormat {
+    Lizard,
+    Gzip,
+}
+
+#[derive(ArgEnum, Clone, Copy, Debug, PartialEq, Eq)]
+enum AlgorithmArg {
+    Huffman,
+    Lz,
+    #[clap(name = "lz+huffman")]
+    LzHuffman,
+    Bwt,
+    Stored,
+}
+
+#[derive(ArgEnum, Clone, Copy, Debug, PartialEq, Eq)]
+enum EntropyArg {
+    Huffman,
+    Rans,
+}
+
+impl From<EntropyArg> for Entropy {
+    fn from(arg: EntropyArg) -> Self {
+        match arg {
+            EntropyArg::Huffman => Entropy::Huffman,
+            EntropyArg::Rans => Entropy::Rans,
+        }
+    }
+}
+
+#[derive(ArgEnum, Clone, Copy, Debug, PartialEq, Eq)]
+enum MatchFinderArg {
+    Scan,
+    HashChain,
+}
 
-            file_input_output.input_is_valid(false).unwrap();
-            file_input_output
-                .output_is_valid(false, args.common.overwrite)
-                .unwrap();
-            decode::decode(&file_input_output);
+impl From<MatchFinderArg> for MatchStrategy {
+    fn from(arg: MatchFinderArg) -> Self {
+        match arg {
+            MatchFinderArg::Scan => MatchStrategy::Scan,
+            MatchFinderArg::HashChain => MatchStrategy::HashChain,
         }
     }
 }
 
-pub enum EncodedValue {
-    OffsetLen(OffsetLen),
-    RawU8(u8),
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum LevelArg {
+    Auto,
+    Fixed(u8),
 }
 
-impl EncodedValue {
-    fn to_bytes(&self) -> Vec<u8> {
+impl std::fmt::Display for LevelArg {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
-            Self::RawU8(v) => vec![*v],
-            Self::OffsetLen(offset_len) => Vec::from(offset_len.to_bytes_new()),
+            LevelArg::Auto => write!(f, "auto"),
+            LevelArg::Fixed(level) => write!(f, "{}", level),
         }
     }
-    // TODO: Implement Write to write to a buffer instead of having to make a vec each time?
 }
 
-struct ChunkMarker {
-    len: u8,
+impl Default for LevelArg {
+    fn default() -> Self {
+        LevelArg::Fixed(DEFAULT_LEVEL)
+    }
 }
 
-impl ChunkMarker {
-    pub const MAX_VALUE: usize = 0b00111111;
-    fn to_u8(&self) -> u8 {
-        let mask = 0b11000000;
-        self.len | mask
+impl From<config::Level> for LevelArg {
+    fn from(level: config::Level) -> Self {
+        match level {
+            config::Level::Auto => LevelArg::Auto,
+            config::Level::Fixed(level) => LevelArg::Fixed(level),
+        }
     }
+}
 
-    fn from_encoded_u8(v: u8) -> Self {
-        Self {
-            len: v & 0b00111111,
+impl LevelArg {
+    fn apply(self, options: EncodeOptions) -> EncodeOptions {
+        match self {
+            LevelArg::Auto => options.auto_level(),
+            LevelArg::Fixed(level) => options.level(level),
         }
     }
+}
+
+#[derive(ArgEnum, Clone, Copy, Debug, PartialEq, Eq)]
+enum FilterArg {
+    None,
+    Delta8,
+    Delta16,
+}
+
+impl From<FilterArg> for Filter {
+    fn from(arg: FilterArg) -> Self {
+        match arg {
+            FilterArg::None => Filter::None,
+            FilterArg::Delta8 => Filter::Delta8,
+            FilterArg::Delta16 => Filter::Delta16,
+        }
+    }
+}
+
+impl From<AlgorithmArg> for Algorithm {
+    fn from(arg: AlgorithmArg) -> Self {
+        match arg {
+            AlgorithmArg::Huffman => Algorithm::Huffman,
+            AlgorithmArg::Lz => Algorithm::Lz,
+            AlgorithmArg::LzHuffman => Algorithm::LzHuffman,
+            AlgorithmArg::Bwt => Algorithm::Bwt,
+            AlgorithmArg::Stored => Algorithm::Stored,
+        }
+    }
+}
+#[derive(Args, Debug)]
+struct DecompressSpecificArgs {
+    #[clap(flatten)]
+    common: CommandLineArgs,
+
+    /// Restore the original file's modification time and permissions onto the output (default)
+    #[clap(long, overrides_with = "no-preserve")]
+    preserve: bool,
+
+    /// Don't restore the original file's modification time and permissions
+    #[clap(long, overrides_with = "preserve")]
+    no_preserve: bool,
+
+    /// Fail rather than decode more than this many bytes, to guard against inputs crafted to
+    /// expand enormously
+    #[clap(long, value_name = "BYTES", default_value_t = DEFAULT_MAX_OUTPUT_SIZE)]
+    max_output_size: u64,
+
+    /// Fail on headers longer than this many bytes
+    #[clap(long, value_name = "BYTES", default_value_t = DEFAULT_MAX_HEADER_SIZE)]
+    max_header_size: usize,
+
+    /// Extract the decompressed tar stream (e.g. from `compress --tar`) into the directory
+    /// given by [output_filename], or the one the input is in. Existing files are overwritten
+    #[clap(long)]
+    untar: bool,
+
+    /// Extension the input's name is expected to end with, `lizard` by default, which is
+    /// stripped from it to name the output, e.g. `file.txt.lizard` decompresses to `file.txt`
+    #[clap(long, short = 'S', parse(try_from_str = parse_suffix))]
+    suffix: Option<String>,
+
+    /// Decompress an input whose name doesn't end with the suffix, naming the output after it
+    /// with `.out` appended unless the header has the original name. Without this such an input
+    /// is refused, unless the output file is given
+    #[clap(long)]
+    force: bool,
+
+    /// With --untar, only extract entries whose path in the archive matches this glob, e.g.
+    /// 'dir/src/**/*.rs'. Can be given multiple times. Only the frames holding matching entries
+    /// are decompressed, using the archive's index
+    #[clap(long, value_name = "GLOB", requires = "untar", parse(try_from_str = glob::Pattern::new))]
+    only: Vec<glob::Pattern>,
+
+    /// Carry on a decompress that was cut short rather than refusing or overwriting its output:
+    /// the blocks the output already holds are checked against their checksums and kept, and
+    /// the rest decoded. Only files compressed with --block-size can be carried on part way,
+    /// others are decompressed again. The output is left behind if this fails, to try again
+    #[clap(long = "continue", conflicts_with = "untar")]
+    resume: bool,
+
+    /// Hold reading the input and writing the output to this many bytes per second between
+    /// them, e.g. 10MB/s or 500K (suffixes are powers of 1024), so a background job doesn't
+    /// saturate a shared disk. With --untar only the compressed file is held to it
+    #[clap(long, value_name = "RATE", parse(try_from_str = parse_rate))]
+    rate_limit: Option<u64>,
+
+    /// Write the output on a thread of its own, holding up to this many bytes (K, M, G and T
+    /// suffixes are powers of 1024) not yet written, so decompressing and writing the disk
+    /// overlap. Written 256K at a time. Not with --untar or --continue
+    #[clap(long, value_name = "SIZE", parse(try_from_str = parse_size))]
+    write_behind: Option<u64>,
+
+    #[cfg(feature = "crypto")]
+    #[clap(flatten)]
+    secret: PassphraseArgs,
+}
+
+/// Where the passphrase for encrypting, or decrypting encrypted inputs, comes from
+#[cfg(feature = "crypto")]
+#[derive(Args, Debug)]
+struct PassphraseArgs {
+    /// Read the passphrase from the first line of this file rather than asking for it. Without
+    /// this, the LIZARDS_PASSPHRASE environment variable is used if it's set
+    #[clap(
+        long,
+        value_name = "FILE",
+        parse(from_os_str),
+        allow_invalid_utf8 = true
+    )]
+    passphrase_file: Option<PathBuf>,
+
+    /// From [passphrase_file], LIZARDS_PASSPHRASE or the terminal, when it's needed
+    #[clap(skip)]
+    passphrase: Option<Passphrase>,
+}
+
+#[derive(Args, Debug)]
+struct RecoverSpecificArgs {
+    #[clap(flatten)]
+    common: CommandLineArgs,
+}
+
+#[derive(Args, Debug)]
+struct ListSpecificArgs {
+    #[clap(flatten)]
+    common: CommandLineArgs,
+}
+
+#[derive(Args, Debug)]
+struct AnalyzeSpecificArgs {
+    #[clap(flatten)]
+    common: CommandLineArgs,
+
+    /// Break the compressed size down into what the literals, matches and runs cost in bits,
+    /// and what's left for the header, chunk markers and padding. Only counted with --algorithm
+    /// lz or lz+huffman, and estimated from the huffman codes with --entropy rans
+    #[clap(long)]
+    costs: bool,
+
+    /// As for compress
+    #[clap(long)]
+    buffer_size: Option<usize>,
+
+    /// As for compress
+    #[clap(long, arg_enum, default_value_t = AlgorithmArg::LzHuffman)]
+    algorithm: AlgorithmArg,
+
+    /// As for compress
+    #[clap(long, arg_enum, default_value_t = EntropyArg::Huffman)]
+    entropy: EntropyArg,
+
+    /// As for compress
+    #[clap(long, arg_enum, default_value_t = MatchFinderArg::Scan)]
+    match_finder: MatchFinderArg,
+
+    /// As for compress
+    #[clap(long, parse(try_from_str = parse_level))]
+    level: Option<LevelArg>,
+}
+
+#[derive(Args, Debug)]
+struct DebugDecodeSpecificArgs {
+    #[clap(flatten)]
+    common: CommandLineArgs,
+}
+
+#[derive(Args, Debug)]
+struct DiffSpecificArgs {
+    #[clap(flatten)]
+    common: CommandLineArgs,
+
+    /// Lizards compressed file to compare the input with
+    #[clap(parse(from_os_str), allow_invalid_utf8 = true)]
+    other: PathBuf,
+}
+
+#[derive(Args, Debug)]
+struct InspectSpecificArgs {
+    #[clap(flatten)]
+    common: CommandLineArgs,
+
+    /// Print every part of the file on its own line: where it is, its first bytes in hex and
+    /// what it is, e.g. a match's offset and length or where the huffman codes in a chunk start
+    #[clap(long)]
+    layout: bool,
+}
+
+#[derive(Args, Debug)]
+struct TestSpecificArgs {
+    #[clap(flatten)]
+    common: CommandLineArgs,
+
+    #[cfg(feature = "crypto")]
+    #[clap(flatten)]
+    secret: PassphraseArgs,
+}
+
+#[derive(Args, Debug)]
+struct CatSpecificArgs {
+    /// Files to decompress, one after the other. A file split with --split-size can be given
+    /// by its first volume or the name without a number
+    #[clap(required = true, parse(from_os_str), allow_invalid_utf8 = true)]
+    files: Vec<PathBuf>,
+
+    /// Fail rather than decode more than this many bytes of any one file
+    #[clap(long, value_name = "BYTES", default_value_t = DEFAULT_MAX_OUTPUT_SIZE)]
+    max_output_size: u64,
+
+    /// Log more detail to stderr. Give twice for debug logging
+    #[clap(short, long, parse(from_occurrences))]
+    verbose: u8,
+
+    /// Threads to decode files compressed with --block-size on, defaulting to one per core
+    #[clap(long, short = 'j')]
+    threads: Option<usize>,
+
+    /// As for decompress
+    #[clap(long)]
+    no_config: bool,
+
+    #[cfg(feature = "crypto")]
+    #[clap(flatten)]
+    secret: PassphraseArgs,
+}
+
+#[derive(Args, Debug)]
+struct GrepSpecificArgs {
+    /// Regular expression to look for, matched anywhere in each line
+    pattern: String,
+
+    /// Files to search, one after the other. Archives written by `compress --tar` are searched
+    /// entry by entry
+    #[clap(required = true, parse(from_os_str), allow_invalid_utf8 = true)]
+    files: Vec<PathBuf>,
+
+    /// Take [pattern] as a literal string rather than a regular expression
+    #[clap(short = 'F', long)]
+    fixed_strings: bool,
+
+    /// Match upper and lower case letters alike
+    #[clap(short, long)]
+    ignore_case: bool,
+
+    /// Print each line's number, counting from 1 in each file or archive entry
+    #[clap(short = 'n', long)]
+    line_number: bool,
+
+    /// Print this many lines after each matching line
+    #[clap(short = 'A', long, value_name = "NUM")]
+    after_context: Option<usize>,
+
+    /// Print this many lines before each matching line
+    #[clap(short = 'B', long, value_name = "NUM")]
+    before_context: Option<usize>,
+
+    /// Print this many lines before and after each matching line
+    #[clap(short = 'C', long, value_name = "NUM")]
+    context: Option<usize>,
+
+    /// Stop decompressing each file once this many lines have matched
+    #[clap(short, long, value_name = "NUM")]
+    max_count: Option<u64>,
+
+    /// Fail rather than decode more than this many bytes of any one file
+    #[clap(long, value_name = "BYTES", default_value_t = DEFAULT_MAX_OUTPUT_SIZE)]
+    max_output_size: u64,
+
+    /// Log more detail to stderr. Give twice for debug logging
+    #[clap(short, long, parse(from_occurrences))]
+    verbose: u8,
+
+    /// Threads to decode files compressed with --block-size on, defaulting to one per core
+    #[clap(long, short = 'j')]
+    threads: Option<usize>,
+
+    /// As for decompress
+    #[clap(long)]
+    no_config: bool,
+
+    #[cfg(feature = "crypto")]
+    #[clap(flatten)]
+    secret: PassphraseArgs,
+}
+
+#[cfg(feature = "crypto")]
+#[derive(Args, Debug)]
+struct VerifySpecificArgs {
+    #[clap(flatten)]
+    common: CommandLineArgs,
+
+    /// File holding the public key to check signatures against (see `lizards keygen`)
+    #[clap(
+        long,
+        value_name = "KEYFILE",
+        parse(from_os_str),
+        allow_invalid_utf8 = true
+    )]
+    pubkey: PathBuf,
+}
+
+#[cfg(feature = "crypto")]
+#[derive(Args, Debug)]
+struct KeygenSpecificArgs {
+    /// Where to write the secret key. The public key is written alongside it with `.pub`
+    /// appended
+    #[clap(parse(from_os_str), allow_invalid_utf8 = true)]
+    output_filename: PathBuf,
+
+    /// By default if either key file exists command will not run unless providing [overwrite]
+    #[clap(long, short)]
+    overwrite: bool,
+}
+
+#[derive(Args, Debug)]
+struct SelftestSpecificArgs {
+    /// Length of the generated inputs, other than the empty and one byte ones
+    #[clap(long, value_name = "BYTES", default_value_t = selftest::DEFAULT_INPUT_LEN)]
+    size: usize,
+
+    /// Instead, encode and decode a single frame of more than 4GiB generated on the fly,
+    /// checking the sizes and checksums add up. Takes a few minutes
+    #[clap(long, conflicts_with = "size")]
+    stress_large: bool,
+
+    /// Print a JSON object describing the results rather than a table
+    #[clap(long)]
+    json: bool,
+
+    /// Log each result to stderr as it comes. Give twice for debug logging
+    #[clap(short, long, parse(from_occurrences))]
+    verbose: u8,
+}
+
+#[derive(Args, Debug)]
+struct AppendSpecificArgs {
+    /// Archive to add to, written by `compress --tar`
+    #[clap(parse(from_os_str), allow_invalid_utf8 = true)]
+    archive: PathBuf,
+
+    /// Files and directories to add, named in the archive as given
+    #[clap(required = true, parse(from_os_str), allow_invalid_utf8 = true)]
+    paths: Vec<PathBuf>,
+
+    /// Store what symlinks point to, rather than the links
+    #[clap(long)]
+    follow_symlinks: bool,
+
+    /// As for compress --tar
+    #[clap(long)]
+    force_compress: bool,
+
+    /// Print a JSON object describing the result rather than human readable text
+    #[clap(long)]
+    json: bool,
+
+    /// Log more detail to stderr. Give twice for debug logging
+    #[clap(short, long, parse(from_occurrences))]
+    verbose: u8,
+}
+
+#[derive(Args, Debug)]
+struct UpdateSpecificArgs {
+    /// Archive to update, written by `compress --tar`
+    #[clap(parse(from_os_str), allow_invalid_utf8 = true)]
+    archive: PathBuf,
+
+    /// Directory the archive was packed from
+    #[clap(parse(from_os_str), allow_invalid_utf8 = true)]
+    dir: PathBuf,
+
+    /// As for compress, which with --content-defined-blocks and --cache-dir means only the
+    /// blocks of entries that changed are compressed again
+    #[clap(long, value_name = "BYTES")]
+    block_size: Option<usize>,
+
+    /// As for compress
+    #[clap(long, requires = "block-size")]
+    content_defined_blocks: bool,
+
+    /// As for compress. Give the same directory each time the archive is updated
+    #[cfg(feature = "cache")]
+    #[clap(
+        long,
+        value_name = "DIR",
+        requires = "block-size",
+        parse(from_os_str),
+        allow_invalid_utf8 = true
+    )]
+    cache_dir: Option<PathBuf>,
+
+    /// Store what symlinks point to, rather than the links
+    #[clap(long)]
+    follow_symlinks: bool,
+
+    /// As for compress --tar
+    #[clap(long)]
+    force_compress: bool,
+
+    /// Print a JSON object describing the result rather than human readable text
+    #[clap(long)]
+    json: bool,
+
+    /// Log more detail to stderr. Give twice for debug logging
+    #[clap(short, long, parse(from_occurrences))]
+    verbose: u8,
+}
+
+#[cfg(feature = "http")]
+#[derive(Args, Debug)]
+struct ServeSpecificArgs {
+    /// Directory to serve files from. Files are sent lizards encoded to clients that accept
+    /// `Content-Encoding: lizard`, and as they are to others
+    #[clap(long, default_value = ".")]
+    dir: String,
+
+    /// Address to listen on
+    #[clap(long, default_value = "127.0.0.1:8080")]
+    addr: String,
+
+    /// Log each request to stderr. Give twice for debug logging
+    #[clap(short, long, parse(from_occurrences))]
+    verbose: u8,
+}
+
+#[cfg(feature = "http")]
+#[derive(Args, Debug)]
+struct FetchSpecificArgs {
+    /// URL to fetch, e.g. http://127.0.0.1:8080/some/file
+    url: String,
+
+    /// Output filename. If not provided, the last part of the URL's path
+    #[clap(short = 'O', long)]
+    output_filename: Option<String>,
+
+    /// By default if [output_filename] exists command will not run unless providing [overwrite]
+    #[clap(long, short)]
+    overwrite: bool,
+
+    /// Print a JSON object describing the result rather than human readable text
+    #[clap(long)]
+    json: bool,
+
+    /// Log more detail to stderr. Give twice for debug logging
+    #[clap(short, long, parse(from_occurrences))]
+    verbose: u8,
+
+    /// Fail rather than decode more than this many bytes
+    #[clap(long, value_name = "BYTES", default_value_t = DEFAULT_MAX_OUTPUT_SIZE)]
+    max_output_size: u64,
+}
+
+#[derive(Parser, Debug)]
+#[clap(author, version, about, long_about = None)]
+enum CommandLineSubCommand {
+    /// Compress a file to the lizards format
+    Compress(CompressSpecificArgs),
+
+    /// Decompress a lizards compressed file
+    Decompress(DecompressSpecificArgs),
+
+    /// Decompress files to stdout, e.g. `lizards cat app.log.lizard | grep ERROR`. Nothing but
+    /// the decompressed bytes is written to stdout, errors go to stderr
+    Cat(CatSpecificArgs),
+
+    /// Print the lines of compressed files matching a regular expression, decompressing them as
+    /// they're searched rather than to a file. Exits 1 if no lines matched, as grep does
+    Grep(GrepSpecificArgs),
+
+    /// Decompress what can be from a damaged file compressed with --block-size, skipping
+    /// damaged blocks
+    Recover(RecoverSpecificArgs),
+
+    /// Show how a lizards compressed file was compressed, and the metadata recorded with it,
+    /// without decompressing it
+    List(ListSpecificArgs),
+
+    /// Check a lizards compressed file decompresses cleanly, without writing the output. Each
+    /// entry of an archive written by `compress --tar` is read through and reported
+    Test(TestSpecificArgs),
+
+    /// Show how a lizards compressed file is laid out, without decoding it: how many bytes its
+    /// header, chunks of literals, matches and control records take. With --layout, lists each
+    /// of them
+    Inspect(InspectSpecificArgs),
+
+    /// Compress a file in memory, without writing the output, and report what it was coded as.
+    /// With --costs, shows how many bits the literals, matches and runs took, for seeing why a
+    /// file doesn't compress
+    Analyze(AnalyzeSpecificArgs),
+
+    /// Print the tokens in a trace written by `compress --trace`, one per line, so two encodes
+    /// can be compared with diff
+    DebugDecode(DebugDecodeSpecificArgs),
+
+    /// Compare how two lizards compressed files were coded, token by token: where they first
+    /// differ, and how many literals, matches and runs each has
+    Diff(DiffSpecificArgs),
+
+    /// Add files to the end of an archive written by `compress --tar`, without recompressing
+    /// what's already in it
+    Append(AppendSpecificArgs),
+
+    /// Bring an archive written by `compress --tar` up to date with the directory it was packed
+    /// from, leaving it alone if nothing has changed
+    Update(UpdateSpecificArgs),
+
+    /// Round trip generated inputs through compression and decompression with a spread of
+    /// options, printing which pass. For checking a build works on a new platform
+    Selftest(SelftestSpecificArgs),
+
+    /// Check the signature of a file compressed with `--sign`, appended to it or in a `.sig`
+    /// file alongside it
+    #[cfg(feature = "crypto")]
+    Verify(VerifySpecificArgs),
+
+    /// Generate an ed25519 key pair for `compress --sign` and `lizards verify`
+    #[cfg(feature = "crypto")]
+    Keygen(KeygenSpecificArgs),
+
+    /// Serve a directory over HTTP, compressing files on the fly for clients that accept it
+    #[cfg(feature = "http")]
+    Serve(ServeSpecificArgs),
+
+    /// Download a file over HTTP, decompressing it if it was sent lizards encoded
+    #[cfg(feature = "http")]
+    Fetch(FetchSpecificArgs),
+}
+
+// Exit codes, so scripts can tell failures apart. 2 is left to clap for bad arguments
+const EXIT_FAILURE: u8 = 1;
+const EXIT_INPUT_MISSING: u8 = 3;
+const EXIT_OUTPUT_EXISTS: u8 = 4;
+const EXIT_CORRUPT_INPUT: u8 = 5;
+const EXIT_VERIFICATION_FAILED: u8 = 6;
+const EXIT_DECRYPTION_FAILED: u8 = 7;
+
+struct Failure {
+    exit_code: u8,
+    message: String,
+}
+
+impl Failure {
+    fn new(exit_code: u8, message: impl ToString) -> Self {
+        Self {
+            exit_code,
+            message: message.to_string(),
+        }
+    }
+}
+
+impl From<Error> for Failure {
+    fn from(e: Error) -> Self {
+        let exit_code = match e {
+            Error::Io(_) => EXIT_FAILURE,
+            Error::TruncatedStream(_)
+            | Error::CorruptHeader(_)
+            | Error::CorruptStream { .. }
+            | Error::InvalidToken(_)
+            | Error::InvalidControlRecord(_)
+            | Error::UnsupportedVersion(_)
+            | Error::FeatureDisabled(_)
+            | Error::LimitExceeded { .. } => EXIT_CORRUPT_INPUT,
+            Error::VerificationFailed { .. } => EXIT_VERIFICATION_FAILED,
+            Error::Decryption(_) => EXIT_DECRYPTION_FAILED,
+            Error::Signature(_) => EXIT_VERIFICATION_FAILED,
+            Error::InputMissing(_) => EXIT_INPUT_MISSING,
+            Error::InputIsSymlink(_) => EXIT_FAILURE,
+            Error::OutputExists(_) | Error::OutputIsInput(_) => EXIT_OUTPUT_EXISTS,
+            Error::UnknownSuffix { .. } | Error::Config { .. } => EXIT_FAILURE,
+        };
+        Self::new(exit_code, e)
+    }
+}
+
+/// The outcome of processing one file, printed as JSON with [--json]
+#[derive(Serialize, Default)]
+struct Report {
+    input: String,
+    output: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    input_bytes: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    output_bytes: Option<u64>,
+    /// Compressed size over uncompressed size
+    #[serde(skip_serializing_if = "Option::is_none")]
+    ratio: Option<f64>,
+    duration_secs: f64,
+    /// CRC32 of the uncompressed data as hex
+    #[serde(skip_serializing_if = "Option::is_none")]
+    checksum: Option<String>,
+    /// Ranges of the input skipped by recover
+    #[serde(skip_serializing_if = "Option::is_none")]
+    damaged: Option<Vec<Range<u64>>>,
+    /// What list found in the header
+    #[serde(skip_serializing_if = "Option::is_none")]
+    header: Option<HeaderReport>,
+    /// What test found in an archive
+    #[serde(skip_serializing_if = "Option::is_none")]
+    entries: Option<Vec<archive::CheckedEntry>>,
+    /// What analyze found the input was coded as
+    #[serde(skip_serializing_if = "Option::is_none")]
+    tokens: Option<TokensReport>,
+    /// What debug-decode read from a trace
+    #[serde(skip_serializing_if = "Option::is_none")]
+    trace: Option<Vec<Token>>,
+    /// How diff found the input's tokens compare with the other file's
+    #[serde(skip_serializing_if = "Option::is_none")]
+    diff: Option<diff::TokenDiff>,
+    /// What inspect found the input is made of
+    #[serde(skip_serializing_if = "Option::is_none")]
+    layout: Option<LayoutReport>,
+    /// What compress --stats found the output stream was made of
+    #[serde(skip_serializing_if = "Option::is_none")]
+    stream: Option<StreamStats>,
+    /// Of the output, what decompress --continue found was already there
+    #[serde(skip_serializing_if = "Option::is_none")]
+    kept_bytes: Option<u64>,
+    /// What update found had changed
+    #[serde(skip_serializing_if = "Option::is_none")]
+    update: Option<archive::UpdateSummary>,
+    /// Blocks compress --cache-dir found in the cache
+    #[serde(skip_serializing_if = "Option::is_none")]
+    cached_blocks: Option<u64>,
+    /// Whether this reports compress --dry-run, so nothing was written
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    dry_run: bool,
+    /// Whether this reports a test, printed as its outcome
+    #[serde(skip)]
+    tested: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+    exit_code: u8,
+}
+
+/// How a file was compressed, from its header
+#[derive(Serialize)]
+struct HeaderReport {
+    version: u8,
+    algorithm: Algorithm,
+    entropy: Entropy,
+    window_size: usize,
+    filter: Filter,
+    metadata: BTreeMap<String, String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    auto_settings: Option<AutoSettings>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    long_range: Option<u64>,
+}
+
+/// How many of each part inspect found, and with --layout each of them
+#[derive(Serialize)]
+struct LayoutReport {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    spans: Option<Vec<layout::Span>>,
+    parts: BTreeMap<&'static str, PartCount>,
+}
+
+#[derive(Default, Serialize)]
+struct PartCount {
+    count: u64,
+    bytes: u64,
+}
+
+impl LayoutReport {
+    fn print(&self, input: &str) {
+        for span in self.spans.iter().flatten() {
+            println!("{}", span);
+        }
+        println!("{}:", input);
+        for (name, part) in &self.parts {
+            println!("  {}: {} ({} bytes)", name, part.count, part.bytes);
+        }
+    }
+}
+
+/// How many of each token analyze coded a file as, and with --costs how many bits they took
+#[derive(Serialize)]
+struct TokensReport {
+    literals: TokenCount,
+    matches: TokenCount,
+    runs: TokenCount,
+    /// The rest of the output: the header, chunk markers, padding and trailer
+    #[serde(skip_serializing_if = "Option::is_none")]
+    other_bits: Option<u64>,
+}
+
+#[derive(Serialize)]
+struct TokenCount {
+    count: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    bits: Option<u64>,
+}
+
+impl TokensReport {
+    fn print(&self, output_bytes: u64) {
+        let percent = |bits: u64| 100.0 * bits as f64 / (output_bytes * 8).max(1) as f64;
+        for (name, tokens) in [
+            ("literals", &self.literals),
+            ("matches", &self.matches),
+            ("runs", &self.runs),
+        ] {
+            match tokens.bits {
+                None => println!("  {}: {}", name, tokens.count),
+                Some(bits) => println!(
+                    "  {}: {}, {} bits ({:.1}%)",
+                    name,
+                    tokens.count,
+                    bits,
+                    percent(bits)
+                ),
+            }
+        }
+        if let Some(bits) = self.other_bits {
+            println!(
+                "  header, chunk markers and padding: {} bits ({:.1}%)",
+                bits,
+                percent(bits)
+            );
+        }
+    }
+}
+
+impl Report {
+    fn new(input: &Path, output: &Path) -> Self {
+        Self {
+            input: input.display().to_string(),
+            output: output.display().to_string(),
+            ..Default::default()
+        }
+    }
+
+    fn print(&self, json: bool) {
+        if json {
+            println!("{}", serde_json::to_string(self).unwrap());
+        } else if self.tested {
+            for entry in self.entries.iter().flatten() {
+                match &entry.error {
+                    None => println!("  {}: OK ({} bytes)", entry.path, entry.size),
+                    Some(error) => println!("  {}: FAILED ({})", entry.path, error),
+                }
+            }
+            match &self.error {
+                None => println!("{}: OK", self.input),
+                Some(error) => eprintln!("{}: FAILED: {}", self.input, error),
+            }
+        } else if let Some(layout) = &self.layout {
+            // Up to anything that couldn't be made sense of, which is the error
+            layout.print(&self.input);
+            if let Some(error) = &self.error {
+                eprintln!("Error: {}", error);
+            }
+        } else if let Some(error) = &self.error {
+            eprintln!("Error: {}", error);
+        } else if let Some(trace) = &self.trace {
+            for token in trace {
+                println!("{}", token);
+            }
+        } else if let Some(diff) = &self.diff {
+            self.print_diff(diff);
+        } else if let Some(tokens) = &self.tokens {
+            let output_bytes = self.output_bytes.unwrap_or(0);
+            println!(
+                "{}: {} -> {} bytes",
+                self.input,
+                self.input_bytes.unwrap_or(0),
+                output_bytes
+            );
+            tokens.print(output_bytes);
+        } else if let Some(header) = &self.header {
+            println!(
+                "{}: version {}, {:?} ({:?}), window {}, filter {:?}",
+                self.input,
+                header.version,
+                header.algorithm,
+                header.entropy,
+                header.window_size,
+                header.filter
+            );
+            if let Some(long_range) = header.long_range {
+                println!("  long range matches up to {} bytes back", long_range);
+            }
+            if let Some(settings) = &header.auto_settings {
+                println!(
+                    "  level auto picked {}, min match {}, {:?}",
+                    settings.level, settings.min_match, settings.algorithm
+                );
+            }
+            for (key, value) in &header.metadata {
+                println!("  {}={}", key, value);
+            }
+        } else if self.dry_run {
+            println!(
+                "Dry run: {} -> {} would be {} -> {} bytes ({:.1}%)",
+                self.input,
+                self.output,
+                self.input_bytes.unwrap_or(0),
+                self.output_bytes.unwrap_or(0),
+                self.ratio.unwrap_or(0.0) * 100.0
+            );
+        } else if matches!(&self.update, Some(update) if update.encoded.is_none()) {
+            println!("{} is up to date with {}", self.output, self.input);
+        } else {
+            println!(
+                "Done: {} -> {} ({} -> {} bytes)",
+                self.input,
+                self.output,
+                self.input_bytes.unwrap_or(0),
+                self.output_bytes.unwrap_or(0)
+            );
+            if let Some(stream) = &self.stream {
+                println!(
+                    "  {} literal bytes in {} chunks, {} bytes matched, {} bits of padding",
+                    stream.literal_bytes, stream.chunks, stream.matched_bytes, stream.padding_bits
+                );
+            }
+            if let Some(update) = &self.update {
+                println!(
+                    "  {} changed, {} added, {} removed, {} bytes copied unchanged",
+                    update.changed.len(),
+                    update.added.len(),
+                    update.removed.len(),
+                    update.copied_bytes
+                );
+            }
+            if let Some(cached_blocks) = self.cached_blocks {
+                println!("  reused {} blocks from the cache", cached_blocks);
+            }
+            if let Some(kept_bytes) = self.kept_bytes {
+                println!("  kept {} bytes already decoded", kept_bytes);
+            }
+        }
+    }
+}
+
+impl Report {
+    fn print_diff(&self, diff: &diff::TokenDiff) {
+        match &diff.first_difference {
+            None => println!("{} and {}: same tokens", self.input, self.output),
+            Some(divergence) => {
+                println!(
+                    "{} and {}: differ at token {}",
+                    self.input, self.output, divergence.index
+                );
+                for (name, token) in [
+                    (&self.input, &divergence.left),
+                    (&self.output, &divergence.right),
+                ] {
+                    match token {
+                        Some(token) => println!("  {}: {}", name, token),
+                        None => println!("  {}: <no more tokens>", name),
+                    }
+                }
+            }
+        }
+        for (name, stats) in [(&self.input, &diff.left), (&self.output, &diff.right)] {
+            println!(
+                "  {}: {} literals, {} matches ({} bytes), {} runs ({} bytes), {} control records",
+                name,
+                stats.literals,
+                stats.matches,
+                stats.matched_bytes,
+                stats.runs,
+                stats.run_bytes,
+                stats.control_records
+            );
+        }
+    }
+}
+
+impl CommandLineArgs {
+    fn threads(&self) -> usize {
+        threads(self.threads)
+    }
+
+    fn output_target(&self) -> OutputTarget {
+        OutputTarget::from_arg(self.output_filename.as_deref())
+    }
+
+    /// With --on-conflict prompt, asks whether to overwrite [output] if it exists
+    fn overwrite_policy(&self, output: &Path) -> OverwritePolicy {
+        match (self.overwrite, self.on_conflict.unwrap_or(OnConflict::Fail)) {
+            (true, _) | (_, OnConflict::Overwrite) => OverwritePolicy::Always,
+            (_, OnConflict::Fail) => OverwritePolicy::Never,
+            (_, OnConflict::Rename) => OverwritePolicy::Rename,
+            (_, OnConflict::Prompt) => match output.is_file() && confirm_overwrite(output) {
+                true => OverwritePolicy::Always,
+                false => OverwritePolicy::Never,
+            },
+        }
+    }
+
+    fn input_filenames(&self) -> Result<Vec<PathBuf>, Failure> {
+        match &self.filename {
+            Some(filename) => Ok(vec![filename.clone()]),
+            None => {
+                let paths = expand_globs(&self.glob, &self.exclude)
+                    .map_err(|e| Failure::new(EXIT_FAILURE, e))?;
+                if paths.is_empty() {
+                    return Err(Failure::new(
+                        EXIT_INPUT_MISSING,
+                        format!("No files matched {:?}", self.glob),
+                    ));
+                }
+                Ok(paths)
+            }
+        }
+    }
+}
+
+/// Ask on the terminal whether to overwrite [output], taking no for an answer if stdin isn't
+/// one. One file is asked about at a time, however many threads are running
+fn confirm_overwrite(output: &Path) -> bool {
+    static ASKING: Mutex<()> = Mutex::new(());
+    if !std::io::stdin().is_terminal() {
+        return false;
+    }
+    let _asking = ASKING
+        .lock()
+        .unwrap_or_else(|poisoned| poisoned.into_inner());
+    eprint!("{:?} exists. Overwrite it? [y/N] ", output);
+    let mut answer = String::new();
+    match std::io::stdin().read_line(&mut answer) {
+        Ok(_) => matches!(answer.trim(), "y" | "Y" | "yes"),
+        Err(_) => false,
+    }
+}
+
+fn compress(filename: &Path, args: &CompressSpecificArgs, threads: usize) -> Report {
+    // Without any trailing separator, which would otherwise end up in the output's name
+    let input: PathBuf = match args.tar {
+        true => filename.components().collect(),
+        false => filename.to_path_buf(),
+    };
+    let builder = FileInputOutput::builder(InputFile::Unencoded(input.clone()))
+        .output(args.common.output_target())
+        .follow_symlinks(!args.common.no_follow_symlinks);
+    let suffix = args.suffix.as_deref();
+    let builder = match args.format {
+        OutputFormat::Lizard if args.tar => match suffix {
+            Some(suffix) => builder.suffix(format!("tar.{}", suffix)),
+            None => builder.suffix(archive::TAR_EXTENSION),
+        },
+        OutputFormat::Lizard => builder
+            .debug(args.trace)
+            .suffix(suffix.unwrap_or(DEFAULT_SUFFIX)),
+        OutputFormat::Gzip => builder.suffix(suffix.unwrap_or("gz")),
+    };
+    let builder = builder.split(args.split_size.is_some());
+    let conflict = match args.split_size {
+        Some(_) => volumes::volume_path(&builder.output_path(), 1),
+        None => builder.output_path(),
+    };
+    let policy = match args.dry_run {
+        true => OverwritePolicy::Always,
+        false => args.common.overwrite_policy(&conflict),
+    };
+    let builder = builder.overwrite(policy);
+    let mut report = Report::new(&input, &builder.output_path());
+    report.dry_run = args.dry_run;
+    let start = Instant::now();
+    let result = try_compress(&builder, args, threads, &mut report);
+    report.duration_secs = start.elapsed().as_secs_f64();
+    if let Err(failure) = result {
+        report.error = Some(failure.message);
+        report.exit_code = failure.exit_code;
+    }
+    report
+}
+
+fn try_compress(
+    builder: &FileInputOutputBuilder,
+    args: &CompressSpecificArgs,
+    threads: usize,
+    report: &mut Report,
+) -> Result<(), Failure> {
+    let file_input_output = &builder.build()?;
+    report.output = file_input_output.output_path().display().to_string();
+
+    let mut options = EncodeOptions::default()
+        .window_size(args.buffer_size.unwrap_or(MAX_LOOKBACK_BUFFER_LEN))
+        .store_metadata(!args.reproducible)
+        .threads(threads)
+        .follow_symlinks(args.common.follow_symlinks)
+        .force_compress(args.force_compress)
+        .algorithm(args.algorithm.into())
+        .rle_min_run(args.rle)
+        .filter(args.filter.into())
+        .bwt_block_size(args.bwt_block_size)
+        .block_size(args.block_size)
+        .content_defined_blocks(args.content_defined_blocks)
+        .long_range(
+            args.long_range
+                .map(|len| usize::try_from(len).unwrap_or(usize::MAX)),
+        )
+        .entropy(args.entropy.into())
+        .match_strategy(args.match_finder.into())
+        .rate_limit(args.rate_limit)
+        .read_ahead(
+            args.read_ahead
+                .map(|len| usize::try_from(len).unwrap_or(usize::MAX)),
+        )
+        .write_behind(
+            args.write_behind
+                .map(|len| usize::try_from(len).unwrap_or(usize::MAX)),
+        );
+    options = args.level.unwrap_or_default().apply(options);
+    if let Some(min_match) = args.min_match {
+        options = options.min_match(min_match);
+    }
+    for (key, value) in &args.meta {
+        options = options.metadata(key, value);
+    }
+    #[cfg(feature = "cache")]
+    {
+        options = options.cache_dir(args.cache_dir.as_ref());
+    }
+    #[cfg(feature = "crypto")]
+    if let Some(passphrase) = &args.secret.passphrase {
+        options = options.encrypt(passphrase.clone());
+    }
+    if !args.meta.is_empty() && args.algorithm == AlgorithmArg::Stored {
+        return Err(Failure::new(
+            EXIT_FAILURE,
+            "--meta needs a header, which --algorithm stored doesn't write",
+        ));
+    }
+    if args.split_size.is_some() && args.format == OutputFormat::Gzip {
+        return Err(Failure::new(
+            EXIT_FAILURE,
+            "--split-size isn't supported for gzip output",
+        ));
+    }
+    if args.tar {
+        if !file_input_output.unencoded_filename.is_dir() {
+            return Err(Failure::new(
+                EXIT_INPUT_MISSING,
+                "--tar needs a directory to pack",
+            ));
+        }
+        if args.format == OutputFormat::Gzip {
+            return Err(Failure::new(
+                EXIT_FAILURE,
+                "--tar isn't supported for gzip output",
+            ));
+        }
+        if args.common.rm_source {
+            return Err(Failure::new(
+                EXIT_FAILURE,
+                "--rm-source isn't supported with --tar",
+            ));
+        }
+    }
+    if args.format == OutputFormat::Gzip {
+        if args.verify {
+            return Err(Failure::new(
+                EXIT_FAILURE,
+                "--verify isn't supported for gzip output",
+            ));
+        }
+        if args.algorithm != AlgorithmArg::LzHuffman {
+            return Err(Failure::new(
+                EXIT_FAILURE,
+                "gzip output is always lz+huffman",
+            ));
+        }
+        if args.filter != FilterArg::None {
+            return Err(Failure::new(
+                EXIT_FAILURE,
+                "Filters aren't supported for gzip output",
+            ));
+        }
+        if args.block_size.is_some() {
+            return Err(Failure::new(
+                EXIT_FAILURE,
+                "--block-size isn't supported for gzip output",
+            ));
+        }
+        if !args.meta.is_empty() {
+            return Err(Failure::new(
+                EXIT_FAILURE,
+                "--meta isn't supported for gzip output",
+            ));
+        }
+        if args.entropy != EntropyArg::Huffman {
+            return Err(Failure::new(
+                EXIT_FAILURE,
+                "gzip output is always huffman coded",
+            ));
+        }
+    }
+    #[cfg(feature = "crypto")]
+    let signing_key = match &args.sign {
+        Some(keyfile) => {
+            if !args.detached_signature {
+                let unsupported = if args.format == OutputFormat::Gzip {
+                    Some("gzip output")
+                } else if args.split_size.is_some() {
+                    Some("--split-size")
+                } else if args.encrypt {
+                    Some("--encrypt")
+                } else {
+                    None
+                };
+                if let Some(unsupported) = unsupported {
+                    return Err(Failure::new(
+                        EXIT_FAILURE,
+                        format!(
+                            "An appended signature isn't supported with {}, use --detached-signature",
+                            unsupported
+                        ),
+                    ));
+                }
+            }
+            Some(read_key_file(keyfile, signing::SecretKey::from_hex)?)
+        }
+        None => None,
+    };
+    if args.entropy != EntropyArg::Huffman && args.algorithm != AlgorithmArg::LzHuffman {
+        return Err(Failure::new(
+            EXIT_FAILURE,
+            "--entropy needs --algorithm lz+huffman",
+        ));
+    }
+    if args.block_size.is_some()
+        && !matches!(args.algorithm, AlgorithmArg::Lz | AlgorithmArg::LzHuffman)
+    {
+        return Err(Failure::new(
+            EXIT_FAILURE,
+            "--block-size needs --algorithm lz or lz+huffman",
+        ));
+    }
+    #[cfg(feature = "crypto")]
+    if args.dry_run && signing_key.is_some() {
+        return Err(Failure::new(
+            EXIT_FAILURE,
+            "--sign isn't supported with --dry-run",
+        ));
+    }
+    let summary = match args.format {
+        OutputFormat::Lizard if args.tar && args.dry_run => {
+            archive::dry_run_dir(&file_input_output.unencoded_filename, &options)?
+        }
+        OutputFormat::Lizard if args.tar => {
+            encode_tar(file_input_output, &options, args.split_size)?
+        }
+        OutputFormat::Lizard if args.dry_run => encode::dry_run(file_input_output, &options)?,
+        OutputFormat::Lizard => match args.split_size {
+            Some(split_size) => encode::encode_to_volumes(file_input_output, &options, split_size)?,
+            None => encode::encode(file_input_output, &options)?,
+        },
+        OutputFormat::Gzip if args.dry_run => gzip::dry_run(file_input_output, &options)?,
+        OutputFormat::Gzip => gzip::encode(file_input_output, &options)?,
+    };
+    report.input_bytes = Some(summary.input_bytes);
+    report.output_bytes = Some(summary.output_bytes);
+    report.ratio = Some(summary.ratio());
+    report.checksum = Some(format!("{:08x}", summary.checksum));
+    if args.stats {
+        report.stream = Some(summary.stream.clone());
+    }
+    #[cfg(feature = "cache")]
+    if args.cache_dir.is_some() {
+        report.cached_blocks = Some(summary.cached_blocks);
+    }
+    if args.dry_run {
+        return Ok(());
+    }
+
+    if args.verify {
+        let encoded =
+            VolumeReader::open(&file_input_output.encoded_filename).map_err(Error::from)?;
+        let verify_options = DecodeOptions::default();
+        #[cfg(feature = "crypto")]
+        let verify_options = match &args.secret.passphrase {
+            Some(passphrase) => verify_options.passphrase(passphrase.clone()),
+            None => verify_options,
+        };
+        let decoded = decode::check(BufReader::new(encoded), &verify_options)?;
+        if decoded.checksum != summary.checksum {
+            return Err(Error::VerificationFailed {
+                expected: summary.checksum,
+                actual: decoded.checksum,
+            }
+            .into());
+        }
+    }
+    #[cfg(feature = "crypto")]
+    if let Some(key) = &signing_key {
+        let output = &file_input_output.encoded_filename;
+        let encoded = VolumeReader::open(output).map_err(Error::from)?;
+        let signature = key.sign(BufReader::new(encoded))?;
+        match args.detached_signature {
+            true => std::fs::write(signature_path(output), signature).map_err(Error::from)?,
+            false => {
+                let mut file = std::fs::OpenOptions::new()
+                    .append(true)
+                    .open(output)
+                    .map_err(Error::from)?;
+                std::io::Write::write_all(&mut file, &signature).map_err(Error::from)?;
+                report.output_bytes = Some(summary.output_bytes + signature.len() as u64);
+            }
+        }
+    }
+    if args.common.fsync {
+        file_input_output
+            .sync_output()
+            .map_err(|e| Failure::new(EXIT_FAILURE, e))?;
+    }
+    if args.common.rm_source {
+        file_input_output
+            .remove_input()
+            .map_err(|e| Failure::new(EXIT_FAILURE, e))?;
+    }
+    Ok(())
+}
+
+/// Pack the directory [file_input_output] names into its encoded file, or volumes of
+/// [split_size]
+fn encode_tar(
+    file_input_output: &FileInputOutput,
+    options: &EncodeOptions,
+    split_size: Option<u64>,
+) -> Result<lizards::EncodeSummary, Error> {
+    let dir = file_input_output.unencoded_filename.as_path();
+    let output_path = file_input_output.encoded_filename.as_path();
+    let throttle = options.throttle();
+    match split_size {
+        Some(split_size) => {
+            let mut writer = volumes::VolumeWriter::create(output_path, split_size);
+            let throttled = Throttled::new(&mut writer, throttle);
+            match archive::encode_dir(dir, throttled, options) {
+                Ok(summary) => writer.finish().map(|_| summary).map_err(Error::from),
+                Err(e) => {
+                    let _ = writer.remove_all();
+                    Err(e)
+                }
+            }
+        }
+        None => {
+            let output = Throttled::new(File::create(output_path)?, throttle);
+            archive::encode_dir(dir, std::io::BufWriter::new(output), options).inspect_err(|_| {
+                let _ = std::fs::remove_file(output_path);
+            })
+        }
+    }
+}
+
+fn decompress(filename: &Path, args: &DecompressSpecificArgs, threads: usize) -> Report {
+    let builder = FileInputOutput::builder(InputFile::Encoded(filename.to_path_buf()))
+        .follow_symlinks(!args.common.no_follow_symlinks)
+        .suffix(args.suffix.as_deref().unwrap_or(DEFAULT_SUFFIX))
+        .force(args.force);
+    let builder = match (args.common.output_target(), args.untar) {
+        // Archives are extracted into the output directory, or next to themselves by default
+        (OutputTarget::Path(dir) | OutputTarget::Dir(dir), true) => {
+            builder.output(OutputTarget::Path(dir))
+        }
+        (OutputTarget::Auto, true) => match filename.parent() {
+            Some(parent) if !parent.as_os_str().is_empty() => {
+                builder.output(OutputTarget::Path(parent.to_path_buf()))
+            }
+            _ => builder.output(OutputTarget::Path(PathBuf::from("."))),
+        },
+        (output @ OutputTarget::Path(_), false) => builder.output(output),
+        // Otherwise decode to the original file's name, if the header has it. Any problem
+        // reading the header is left for the decode to report
+        (output, false) => {
+            let options = decode_options(args, threads);
+            let name = VolumeReader::open(filename)
+                .ok()
+                .and_then(|input| decode::read_header(BufReader::new(input), &options).ok())
+                .and_then(|header| header.file_metadata()?.safe_name());
+            match name {
+                Some(name) => builder.output(output).original_name(name),
+                None => builder.output(output),
+            }
+        }
+    };
+    // Carrying on needs the output that's there
+    let policy = match args.resume {
+        true => OverwritePolicy::Always,
+        false => args.common.overwrite_policy(&builder.output_path()),
+    };
+    let builder = builder.overwrite(policy);
+    let mut report = Report::new(filename, &builder.output_path());
+    let start = Instant::now();
+    let result = try_decompress(&builder, args, threads, &mut report);
+    report.duration_secs = start.elapsed().as_secs_f64();
+    if let Err(failure) = result {
+        report.error = Some(failure.message);
+        report.exit_code = failure.exit_code;
+    }
+    report
+}
+
+fn try_decompress(
+    builder: &FileInputOutputBuilder,
+    args: &DecompressSpecificArgs,
+    threads: usize,
+    report: &mut Report,
+) -> Result<(), Failure> {
+    let file_input_output = &builder.build()?;
+    report.output = file_input_output.output_path().display().to_string();
+
+    let options = decode_options(args, threads);
+    let summary = match args.untar {
+        true => {
+            let input =
+                VolumeReader::open(&file_input_output.encoded_filename).map_err(Error::from)?;
+            let input_bytes = input.len();
+            let input = Throttled::new(input, options.throttle());
+            let dest = file_input_output.unencoded_filename.as_path();
+            lizards::DecodeSummary {
+                input_bytes,
+                ..archive::decode_matching_to_dir(
+                    BufReader::new(input),
+                    dest,
+                    &args.only,
+                    &options,
+                )?
+            }
+        }
+        false if args.resume => {
+            let resumed = resume::resume(file_input_output, &options)?;
+            report.kept_bytes = Some(resumed.kept_bytes);
+            lizards::DecodeSummary {
+                input_bytes: resumed.input_bytes,
+                output_bytes: resumed.output_bytes,
+                checksum: resumed.checksum,
+            }
+        }
+        false => decode::decode(file_input_output, &options)?,
+    };
+    report.input_bytes = Some(summary.input_bytes);
+    report.output_bytes = Some(summary.output_bytes);
+    report.ratio = Some(summary.input_bytes as f64 / summary.output_bytes as f64);
+    report.checksum = Some(format!("{:08x}", summary.checksum));
+
+    if args.common.fsync {
+        file_input_output
+            .sync_output()
+            .map_err(|e| Failure::new(EXIT_FAILURE, e))?;
+    }
+    if args.common.rm_source {
+        file_input_output
+            .remove_input()
+            .map_err(|e| Failure::new(EXIT_FAILURE, e))?;
+    }
+    Ok(())
+}
+
+fn decode_options(args: &DecompressSpecificArgs, threads: usize) -> DecodeOptions {
+    let options = DecodeOptions::default()
+        .preserve(!args.no_preserve)
+        .threads(threads)
+        .max_output_size(args.max_output_size)
+        .max_header_size(args.max_header_size)
+        .rate_limit(args.rate_limit)
+        .write_behind(
+            args.write_behind
+                .map(|len| usize::try_from(len).unwrap_or(usize::MAX)),
+        );
+    #[cfg(feature = "crypto")]
+    let options = match &args.secret.passphrase {
+        Some(passphrase) => options.passphrase(passphrase.clone()),
+        None => options,
+    };
+    options
+}
+
+fn recover(filename: &Path, args: &RecoverSpecificArgs, _threads: usize) -> Report {
+    // Whatever a damaged file has come to be called, there's something to get back from it
+    let builder = FileInputOutput::builder(InputFile::Encoded(filename.to_path_buf()))
+        .output(args.common.output_target())
+        .follow_symlinks(!args.common.no_follow_symlinks)
+        .force(true);
+    let policy = args.common.overwrite_policy(&builder.output_path());
+    let builder = builder.overwrite(policy);
+    let mut report = Report::new(filename, &builder.output_path());
+    let start = Instant::now();
+    let result = try_recover(&builder, args, &mut report);
+    report.duration_secs = start.elapsed().as_secs_f64();
+    if let Err(failure) = result {
+        report.error = Some(failure.message);
+        report.exit_code = failure.exit_code;
+    }
+    report
+}
+
+fn try_recover(
+    builder: &FileInputOutputBuilder,
+    args: &RecoverSpecificArgs,
+    report: &mut Report,
+) -> Result<(), Failure> {
+    let file_input_output = &builder.build()?;
+    report.output = file_input_output.output_path().display().to_string();
+    let summary = recover::recover(file_input_output)?;
+    report.input_bytes = Some(summary.input_bytes);
+    report.output_bytes = Some(summary.output_bytes);
+    report.damaged = Some(summary.damaged);
+    if args.common.fsync {
+        file_input_output
+            .sync_output()
+            .map_err(|e| Failure::new(EXIT_FAILURE, e))?;
+    }
+    Ok(())
+}
+
+fn list(path: &Path, _args: &ListSpecificArgs, _threads: usize) -> Report {
+    let mut report = Report::new(path, path);
+    let start = Instant::now();
+    let result = try_list(path, &mut report);
+    report.duration_secs = start.elapsed().as_secs_f64();
+    if let Err(failure) = result {
+        report.error = Some(failure.message);
+        report.exit_code = failure.exit_code;
+    }
+    report
+}
+
+fn try_list(path: &Path, report: &mut Report) -> Result<(), Failure> {
+    let file = File::open(path).map_err(|e| Failure::new(EXIT_INPUT_MISSING, e))?;
+    report.input_bytes = Some(file.metadata().map_err(Error::from)?.len());
+    let header = Header::read_from(BufReader::new(file))?;
+    report.header = Some(HeaderReport {
+        version: header.version(),
+        algorithm: header.algorithm(),
+        entropy: header.entropy(),
+        window_size: header.lookback_buffer_len(),
+        filter: header.filter(),
+        metadata: header.metadata().clone(),
+        auto_settings: header.auto_settings(),
+        long_range: header.long_range(),
+    });
+    Ok(())
+}
+
+fn inspect(path: &Path, args: &InspectSpecificArgs, _threads: usize) -> Report {
+    let mut report = Report::new(path, path);
+    let start = Instant::now();
+    let result = try_inspect(path, args, &mut report);
+    report.duration_secs = start.elapsed().as_secs_f64();
+    if let Err(failure) = result {
+        report.error = Some(failure.message);
+        report.exit_code = failure.exit_code;
+    }
+    report
+}
+
+fn try_inspect(
+    path: &Path,
+    args: &InspectSpecificArgs,
+    report: &mut Report,
+) -> Result<(), Failure> {
+    let bytes = std::fs::read(path).map_err(|e| Failure::new(EXIT_INPUT_MISSING, e))?;
+    report.input_bytes = Some(bytes.len() as u64);
+    let (spans, result) = layout::layout(&bytes);
+    let mut parts = BTreeMap::new();
+    for span in &spans {
+        let part: &mut PartCount = parts.entry(span.part.name()).or_default();
+        part.count += 1;
+        part.bytes += span.len;
+    }
+    report.layout = Some(LayoutReport {
+        spans: args.layout.then_some(spans),
+        parts,
+    });
+    Ok(result?)
+}
+
+fn analyze(path: &Path, args: &AnalyzeSpecificArgs, _threads: usize) -> Report {
+    let mut report = Report::new(path, path);
+    let start = Instant::now();
+    let result = try_analyze(path, args, &mut report);
+    report.duration_secs = start.elapsed().as_secs_f64();
+    if let Err(failure) = result {
+        report.error = Some(failure.message);
+        report.exit_code = failure.exit_code;
+    }
+    report
+}
+
+fn try_analyze(
+    path: &Path,
+    args: &AnalyzeSpecificArgs,
+    report: &mut Report,
+) -> Result<(), Failure> {
+    let file = File::open(path).map_err(|e| Failure::new(EXIT_INPUT_MISSING, e))?;
+    let options = EncodeOptions::default()
+        .window_size(args.buffer_size.unwrap_or(MAX_LOOKBACK_BUFFER_LEN))
+        .algorithm(args.algorithm.into())
+        .entropy(args.entropy.into())
+        .match_strategy(args.match_finder.into());
+    let options = args.level.unwrap_or_default().apply(options);
+    let summary = encode::encode_from_reader(BufReader::new(file), std::io::sink(), &options)?;
+    report.input_bytes = Some(summary.input_bytes);
+    report.output_bytes = Some(summary.output_bytes);
+    report.ratio = Some(summary.ratio());
+    let bits = |bits| args.costs.then_some(bits);
+    let token_bits = summary.literal_bits + summary.match_bits + summary.run_bits;
+    report.tokens = Some(TokensReport {
+        literals: TokenCount {
+            count: summary.literals,
+            bits: bits(summary.literal_bits),
+        },
+        matches: TokenCount {
+            count: summary.matches,
+            bits: bits(summary.match_bits),
+        },
+        runs: TokenCount {
+            count: summary.runs,
+            bits: bits(summary.run_bits),
+        },
+        other_bits: bits((summary.output_bytes * 8).saturating_sub(token_bits)),
+    });
+    Ok(())
+}
+
+fn debug_decode(path: &Path, _args: &DebugDecodeSpecificArgs, _threads: usize) -> Report {
+    let mut report = Report::new(path, path);
+    let start = Instant::now();
+    let result = try_debug_decode(path, &mut report);
+    report.duration_secs = start.elapsed().as_secs_f64();
+    if let Err(failure) = result {
+        report.error = Some(failure.message);
+        report.exit_code = failure.exit_code;
+    }
+    report
+}
+
+fn try_debug_decode(path: &Path, report: &mut Report) -> Result<(), Failure> {
+    let file = File::open(path).map_err(|e| Failure::new(EXIT_INPUT_MISSING, e))?;
+    let tokens = trace::read_trace(BufReader::new(file))
+        .collect::<lizards::Result<Vec<Token>>>()
+        .map_err(|e| Failure::new(EXIT_CORRUPT_INPUT, e))?;
+    report.trace = Some(tokens);
+    Ok(())
+}
+
+fn diff(path: &Path, args: &DiffSpecificArgs, _threads: usize) -> Report {
+    let mut report = Report::new(path, &args.other);
+    let start = Instant::now();
+    let result = try_diff(path, args, &mut report);
+    report.duration_secs = start.elapsed().as_secs_f64();
+    if let Err(failure) = result {
+        report.error = Some(failure.message);
+        report.exit_code = failure.exit_code;
+    }
+    report
+}
+
+fn try_diff(path: &Path, args: &DiffSpecificArgs, report: &mut Report) -> Result<(), Failure> {
+    let open = |path: &Path| File::open(path).map_err(|e| Failure::new(EXIT_INPUT_MISSING, e));
+    let left = BufReader::new(open(path)?);
+    let right = BufReader::new(open(&args.other)?);
+    report.diff = Some(diff::diff_tokens(left, right, &DecodeOptions::default())?);
+    Ok(())
+}
+
+fn test(path: &Path, args: &TestSpecificArgs, threads: usize) -> Report {
+    let mut report = Report::new(path, path);
+    report.tested = true;
+    let start = Instant::now();
+    let result = try_test(path, args, threads, &mut report);
+    report.duration_secs = start.elapsed().as_secs_f64();
+    if let Err(failure) = result {
+        report.error = Some(failure.message);
+        report.exit_code = failure.exit_code;
+    }
+    report
+}
+
+fn try_test(
+    path: &Path,
+    #[allow(unused_variables)] args: &TestSpecificArgs,
+    threads: usize,
+    report: &mut Report,
+) -> Result<(), Failure> {
+    let input = VolumeReader::open(path).map_err(|e| Failure::new(EXIT_INPUT_MISSING, e))?;
+    let input_bytes = input.len();
+    let options = DecodeOptions::default().threads(threads);
+    #[cfg(feature = "crypto")]
+    let options = match &args.secret.passphrase {
+        Some(passphrase) => options.passphrase(passphrase.clone()),
+        None => options,
+    };
+    let base = volumes::strip_first_volume(path).unwrap_or_else(|| path.to_path_buf());
+    let summary = match base.to_string_lossy().ends_with(archive::TAR_EXTENSION) {
+        true => {
+            let (entries, result) = archive::check_entries(BufReader::new(input), &options);
+            report.entries = Some(entries);
+            result?
+        }
+        false => decode::check(BufReader::new(input), &options)?,
+    };
+    report.input_bytes = Some(input_bytes);
+    report.output_bytes = Some(summary.output_bytes);
+    report.checksum = Some(format!("{:08x}", summary.checksum));
+    Ok(())
+}
+
+/// Decompress each of [args]' files to stdout in turn, stopping at the first that fails. Unlike
+/// the other commands nothing is reported on stdout, so only errors are printed, to stderr
+fn cat(args: &CatSpecificArgs) -> ExitCode {
+    let options = DecodeOptions::default()
+        .threads(threads(args.threads))
+        .max_output_size(args.max_output_size);
+    #[cfg(feature = "crypto")]
+    let options = match &args.secret.passphrase {
+        Some(passphrase) => options.passphrase(passphrase.clone()),
+        None => options,
+    };
+    let mut stdout = std::io::stdout().lock();
+    for file in &args.files {
+        match try_cat(file, &options, &mut stdout) {
+            Ok(true) => {}
+            Ok(false) => break,
+            Err(failure) => {
+                eprintln!("Error: {}: {}", file.display(), failure.message);
+                return ExitCode::from(failure.exit_code);
+            }
+        }
+    }
+    ExitCode::SUCCESS
+}
+
+/// Gives whether stdout is still being read, as it isn't once e.g. `lizards cat | head` has seen
+/// enough
+fn try_cat(file: &Path, options: &DecodeOptions, stdout: &mut impl Write) -> Result<bool, Failure> {
+    let input = VolumeReader::open(file).map_err(|e| Failure::new(EXIT_INPUT_MISSING, e))?;
+    let mut writer = std::io::BufWriter::new(stdout);
+    let written =
+        decode::decode_to_writer_with_options(BufReader::new(input), &mut writer, options)
+            .and_then(|_| writer.flush().map_err(Error::from));
+    match written {
+        Ok(()) => Ok(true),
+        Err(Error::Io(e)) if e.kind() == std::io::ErrorKind::BrokenPipe => Ok(false),
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// Print the lines of each of [args]' files that match, as grep would, stopping at the first
+/// file that can't be read
+fn grep(args: &GrepSpecificArgs) -> ExitCode {
+    let pattern = match args.fixed_strings {
+        true => regex::escape(&args.pattern),
+        false => args.pattern.clone(),
+    };
+    let pattern = match regex::bytes::RegexBuilder::new(&pattern)
+        .case_insensitive(args.ignore_case)
+        .build()
+    {
+        Ok(pattern) => pattern,
+        Err(e) => {
+            eprintln!("Error: {}", e);
+            return ExitCode::from(EXIT_FAILURE);
+        }
+    };
+    let options = GrepOptions::new(pattern)
+        .context(
+            args.before_context.or(args.context).unwrap_or(0),
+            args.after_context.or(args.context).unwrap_or(0),
+        )
+        .max_count(args.max_count);
+    let decode_options = DecodeOptions::default()
+        .threads(threads(args.threads))
+        .max_output_size(args.max_output_size);
+    #[cfg(feature = "crypto")]
+    let decode_options = match &args.secret.passphrase {
+        Some(passphrase) => decode_options.passphrase(passphrase.clone()),
+        None => decode_options,
+    };
+    let mut stdout = std::io::BufWriter::new(std::io::stdout().lock());
+    let mut matched = false;
+    for file in &args.files {
+        let found = try_grep(file, args, &options, &decode_options, &mut stdout);
+        match found.and_then(|found| {
+            stdout.flush().map_err(|e| Failure::new(EXIT_FAILURE, e))?;
+            Ok(found)
+        }) {
+            Ok(Some(matches)) => matched |= matches > 0,
+            // Whatever's reading stdout has seen enough
+            Ok(None) => return ExitCode::SUCCESS,
+            Err(failure) => {
+                eprintln!("Error: {}: {}", file.display(), failure.message);
+                return ExitCode::from(failure.exit_code);
+            }
+        }
+    }
+    match matched {
+        true => ExitCode::SUCCESS,
+        false => ExitCode::from(EXIT_FAILURE),
+    }
+}
+
+/// Gives how many lines of [file] matched, or None once stdout isn't being read
+fn try_grep(
+    file: &Path,
+    args: &GrepSpecificArgs,
+    options: &GrepOptions,
+    decode_options: &DecodeOptions,
+    stdout: &mut impl Write,
+) -> Result<Option<u64>, Failure> {
+    let input = VolumeReader::open(file).map_err(|e| Failure::new(EXIT_INPUT_MISSING, e))?;
+    let name = (args.files.len() > 1).then(|| file.display().to_string());
+    let context = [args.before_context, args.after_context, args.context]
+        .iter()
+        .any(|lines| lines.is_some_and(|lines| lines > 0));
+    // To print a separator between lines that don't follow on from each other, as grep does
+    let mut last: Option<(Option<String>, u64)> = None;
+    let emit = |line: &GrepLine| {
+        let follows = last.as_ref().is_some_and(|(member, number)| {
+            member.as_deref() == line.member && number + 1 == line.number
+        });
+        if context && last.is_some() && !follows {
+            writeln!(stdout, "--")?;
+        }
+        last = Some((line.member.map(String::from), line.number));
+        let separator = if line.matched { ':' } else { '-' };
+        if let Some(name) = &name {
+            write!(stdout, "{}{}", name, separator)?;
+        }
+        if let Some(member) = line.member {
+            write!(stdout, "{}{}", member, separator)?;
+        }
+        if args.line_number {
+            write!(stdout, "{}{}", line.number, separator)?;
+        }
+        stdout.write_all(line.line)?;
+        stdout.write_all(b"\n")
+    };
+    let base = volumes::strip_first_volume(file).unwrap_or_else(|| file.to_path_buf());
+    let reader = BufReader::new(input);
+    let found = match base.to_string_lossy().ends_with(archive::TAR_EXTENSION) {
+        true => lizards::grep::grep_archive(reader, options, decode_options, emit),
+        false => lizards::grep::grep(reader, options, decode_options, emit),
+    };
+    match found {
+        Ok(summary) => Ok(Some(summary.matches)),
+        Err(Error::Io(e)) if e.kind() == std::io::ErrorKind::BrokenPipe => Ok(None),
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// Where a detached signature of [path] goes
+#[cfg(feature = "crypto")]
+fn signature_path(path: &Path) -> PathBuf {
+    let mut signature = path.as_os_str().to_owned();
+    signature.push(".sig");
+    PathBuf::from(signature)
+}
+
+#[cfg(feature = "crypto")]
+fn read_key_file<K>(keyfile: &Path, parse: fn(&str) -> lizards::Result<K>) -> Result<K, Failure> {
+    let hex = std::fs::read_to_string(keyfile)
+        .map(Zeroizing::new)
+        .map_err(|e| {
+            Failure::new(
+                EXIT_INPUT_MISSING,
+                format!("Couldn't read key file {}: {}", keyfile.display(), e),
+            )
+        })?;
+    parse(&hex).map_err(|e| Failure::new(EXIT_FAILURE, format!("{}: {}", keyfile.display(), e)))
+}
+
+#[cfg(feature = "crypto")]
+fn verify(path: &Path, args: &VerifySpecificArgs, _threads: usize) -> Report {
+    let mut report = Report::new(path, path);
+    report.tested = true;
+    let start = Instant::now();
+    let result = try_verify(path, args);
+    report.duration_secs = start.elapsed().as_secs_f64();
+    if let Err(failure) = result {
+        report.error = Some(failure.message);
+        report.exit_code = failure.exit_code;
+    }
+    report
+}
+
+#[cfg(feature = "crypto")]
+fn try_verify(path: &Path, args: &VerifySpecificArgs) -> Result<(), Failure> {
+    let key = read_key_file(&args.pubkey, signing::PublicKey::from_hex)?;
+    let input = VolumeReader::open(path).map_err(|e| Failure::new(EXIT_INPUT_MISSING, e))?;
+    let detached = signature_path(&volumes::strip_first_volume(path).unwrap_or(path.into()));
+    match detached.exists() {
+        true => {
+            let signature = std::fs::read(&detached).map_err(Error::from)?;
+            key.verify(BufReader::new(input), &signature)?
+        }
+        false => {
+            let len = input.len();
+            key.verify_embedded(BufReader::new(input), len)?
+        }
+    }
+    Ok(())
+}
+
+#[cfg(feature = "crypto")]
+fn keygen(args: &KeygenSpecificArgs) -> ExitCode {
+    let secret_path = args.output_filename.clone();
+    let mut public_path = secret_path.clone().into_os_string();
+    public_path.push(".pub");
+    let public_path = PathBuf::from(public_path);
+    let result = (|| {
+        for path in [&secret_path, &public_path] {
+            if path.exists() && !args.overwrite {
+                return Err(Failure::new(
+                    EXIT_OUTPUT_EXISTS,
+                    format!(
+                        "{:?} exists. Consider passing overwrite to ignore this",
+                        path
+                    ),
+                ));
+            }
+        }
+        let key = signing::SecretKey::generate();
+        let mut options = std::fs::OpenOptions::new();
+        options.write(true).create(true).truncate(true);
+        #[cfg(unix)]
+        std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+        let mut secret = options.open(&secret_path).map_err(Error::from)?;
+        let hex = key.to_hex();
+        std::io::Write::write_all(&mut secret, hex.as_bytes())
+            .and_then(|_| std::io::Write::write_all(&mut secret, b"\n"))
+            .map_err(Error::from)?;
+        std::fs::write(&public_path, format!("{}\n", key.public_key().to_hex()))
+            .map_err(Error::from)?;
+        Ok(())
+    })();
+    match result {
+        Ok(()) => {
+            println!(
+                "Wrote secret key to {} and public key to {}",
+                secret_path.display(),
+                public_path.display()
+            );
+            ExitCode::SUCCESS
+        }
+        Err(failure) => {
+            eprintln!("Error: {}", failure.message);
+            ExitCode::from(failure.exit_code)
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct SelftestReport {
+    platform: String,
+    results: Vec<selftest::SelfTestResult>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+    exit_code: u8,
+}
+
+fn selftest(args: &SelftestSpecificArgs) -> ExitCode {
+    let platform = format!(
+        "{}, {}-endian, {}-bit",
+        std::env::consts::ARCH,
+        if cfg!(target_endian = "big") {
+            "big"
+        } else {
+            "little"
+        },
+        usize::BITS
+    );
+    let dir = std::env::temp_dir().join(format!("lizards_selftest_{}", std::process::id()));
+    let results = if args.stress_large {
+        selftest::stress_large(selftest::STRESS_LARGE_LEN).map(|result| vec![result])
+    } else {
+        selftest::run(&dir, args.size, |result| match &result.error {
+            None => log::info!("{} with {}: ok", result.input, result.options),
+            Some(error) => log::warn!("{} with {}: {}", result.input, result.options, error),
+        })
+    };
+    let _ = std::fs::remove_dir_all(&dir);
+    let mut report = SelftestReport {
+        platform,
+        results: Vec::new(),
+        error: None,
+        exit_code: 0,
+    };
+    match results {
+        Ok(results) => {
+            if !results.iter().all(selftest::SelfTestResult::passed) {
+                report.exit_code = EXIT_VERIFICATION_FAILED;
+            }
+            report.results = results;
+        }
+        Err(e) => {
+            let failure = Failure::from(e);
+            report.error = Some(failure.message);
+            report.exit_code = failure.exit_code;
+        }
+    }
+    if args.json {
+        println!("{}", serde_json::to_string(&report).unwrap());
+    } else if let Some(error) = &report.error {
+        eprintln!("Error: {}", error);
+    } else {
+        print_selftest_matrix(&report);
+    }
+    ExitCode::from(report.exit_code)
+}
+
+/// A row per input and a column per option set, then why any failed
+fn print_selftest_matrix(report: &SelftestReport) {
+    println!("Platform: {}", report.platform);
+    let mut inputs: Vec<&str> = Vec::new();
+    let mut options: Vec<&str> = Vec::new();
+    for result in &report.results {
+        if !inputs.contains(&result.input) {
+            inputs.push(result.input);
+        }
+        if !options.contains(&result.options) {
+            options.push(result.options);
+        }
+    }
+    let first_width = inputs.iter().map(|input| input.len()).max().unwrap_or(0);
+    print!("{:first_width$}", "");
+    for name in &options {
+        print!("  {}", name);
+    }
+    println!();
+    for input in &inputs {
+        let mut row = format!("{:first_width$}", input);
+        for name in &options {
+            let result = report
+                .results
+                .iter()
+                .find(|result| result.input == *input && result.options == *name);
+            let cell = match result.map(selftest::SelfTestResult::passed) {
+                Some(true) => "ok",
+                Some(false) => "FAIL",
+                None => "-",
+            };
+            row.push_str(&format!("  {:width$}", cell, width = name.len()));
+        }
+        println!("{}", row.trim_end());
+    }
+    let passed = report
+        .results
+        .iter()
+        .filter(|result| result.passed())
+        .count();
+    println!("{}/{} passed", passed, report.results.len());
+    for result in &report.results {
+        if let Some(error) = &result.error {
+            eprintln!("{} with {}: {}", result.input, result.options, error);
+        }
+    }
+}
+
+fn append(args: &AppendSpecificArgs) -> ExitCode {
+    let archive = &args.archive;
+    let paths: Vec<&OsStr> = args.paths.iter().map(|path| path.as_os_str()).collect();
+    let mut report = Report::new(Path::new(&paths.join(OsStr::new(" "))), archive);
+    let start = Instant::now();
+    let result = try_append(args, archive, &mut report);
+    report.duration_secs = start.elapsed().as_secs_f64();
+    if let Err(failure) = result {
+        report.error = Some(failure.message);
+        report.exit_code = failure.exit_code;
+    }
+    report.print(args.json);
+    ExitCode::from(report.exit_code)
+}
+
+fn try_append(
+    args: &AppendSpecificArgs,
+    archive: &Path,
+    report: &mut Report,
+) -> Result<(), Failure> {
+    if !archive.is_file() {
+        return Err(Failure::new(
+            EXIT_INPUT_MISSING,
+            format!("Archive does not exist: {:?}", archive),
+        ));
+    }
+    if volumes::volumes_base(archive).is_some() {
+        return Err(Failure::new(
+            EXIT_FAILURE,
+            "Can't append to an archive split into volumes",
+        ));
+    }
+    // Appending a tar stream to anything else would corrupt it
+    if !args
+        .archive
+        .as_os_str()
+        .as_encoded_bytes()
+        .ends_with(archive::TAR_EXTENSION.as_bytes())
+    {
+        return Err(Failure::new(
+            EXIT_FAILURE,
+            format!("Can only append to .{} archives", archive::TAR_EXTENSION),
+        ));
+    }
+    if let Some(missing) = args.paths.iter().find(|path| !path.exists()) {
+        return Err(Failure::new(
+            EXIT_INPUT_MISSING,
+            format!("Input file does not exist: {:?}", missing),
+        ));
+    }
+    let options = EncodeOptions::default()
+        .follow_symlinks(args.follow_symlinks)
+        .force_compress(args.force_compress);
+    let summary = archive::append_to(archive, &args.paths, &options)?;
+    report.input_bytes = Some(summary.input_bytes);
+    report.output_bytes = Some(summary.output_bytes);
+    report.ratio = Some(summary.ratio());
+    Ok(())
+}
+
+fn update(args: &UpdateSpecificArgs) -> ExitCode {
+    let mut report = Report::new(&args.dir, &args.archive);
+    let start = Instant::now();
+    let result = try_update(args, &mut report);
+    report.duration_secs = start.elapsed().as_secs_f64();
+    if let Err(failure) = result {
+        report.error = Some(failure.message);
+        report.exit_code = failure.exit_code;
+    }
+    report.print(args.json);
+    ExitCode::from(report.exit_code)
+}
+
+fn try_update(args: &UpdateSpecificArgs, report: &mut Report) -> Result<(), Failure> {
+    let archive = &args.archive;
+    if !archive.is_file() {
+        return Err(Failure::new(
+            EXIT_INPUT_MISSING,
+            format!("Archive does not exist: {:?}", archive),
+        ));
+    }
+    if !args.dir.is_dir() {
+        return Err(Failure::new(
+            EXIT_INPUT_MISSING,
+            format!("Directory does not exist: {:?}", args.dir),
+        ));
+    }
+    if volumes::volumes_base(archive).is_some() {
+        return Err(Failure::new(
+            EXIT_FAILURE,
+            "Can't update an archive split into volumes",
+        ));
+    }
+    let options = EncodeOptions::default()
+        .follow_symlinks(args.follow_symlinks)
+        .force_compress(args.force_compress)
+        .block_size(args.block_size)
+        .content_defined_blocks(args.content_defined_blocks);
+    #[cfg(feature = "cache")]
+    let options = options.cache_dir(args.cache_dir.as_ref());
+    let summary = archive::update(archive, &args.dir, &options)?;
+    if let Some(encoded) = &summary.encoded {
+        report.input_bytes = Some(encoded.input_bytes);
+        report.output_bytes = Some(encoded.output_bytes);
+        report.ratio = Some(encoded.ratio());
+        #[cfg(feature = "cache")]
+        if args.cache_dir.is_some() {
+            report.cached_blocks = Some(encoded.cached_blocks);
+        }
+    }
+    report.update = Some(summary);
+    Ok(())
+}
+
+#[cfg(feature = "http")]
+fn serve(args: &ServeSpecificArgs) -> ExitCode {
+    let listener = match std::net::TcpListener::bind(&args.addr) {
+        Ok(listener) => listener,
+        Err(e) => {
+            eprintln!("Error: Couldn't listen on {}: {}", args.addr, e);
+            return ExitCode::from(EXIT_FAILURE);
+        }
+    };
+    println!("Serving {} on http://{}", args.dir, args.addr);
+    match lizards::http::serve(listener, Path::new(&args.dir), &EncodeOptions::default()) {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e) => {
+            let failure = Failure::from(e);
+            eprintln!("Error: {}", failure.message);
+            ExitCode::from(failure.exit_code)
+        }
+    }
+}
+
+#[cfg(feature = "http")]
+fn fetch(args: &FetchSpecificArgs) -> ExitCode {
+    let output_filename = args.output_filename.clone().unwrap_or_else(|| {
+        let url = args.url.strip_prefix("http://").unwrap_or(&args.url);
+        let path = url.split(['?', '#']).next().unwrap_or_default();
+        match path
+            .split_once('/')
+            .and_then(|(_, path)| path.rsplit('/').next())
+        {
+            Some(name) if !name.is_empty() => name.to_string(),
+            _ => String::from("index"),
+        }
+    });
+    let output = Path::new(&output_filename);
+    let mut report = Report::new(Path::new(&args.url), output);
+    let start = Instant::now();
+    let result = try_fetch(args, output, &mut report);
+    report.duration_secs = start.elapsed().as_secs_f64();
+    if let Err(failure) = result {
+        report.error = Some(failure.message);
+        report.exit_code = failure.exit_code;
+    }
+    report.print(args.json);
+    ExitCode::from(report.exit_code)
+}
+
+#[cfg(feature = "http")]
+fn try_fetch(args: &FetchSpecificArgs, output: &Path, report: &mut Report) -> Result<(), Failure> {
+    if output.exists() && !args.overwrite {
+        return Err(Failure::new(
+            EXIT_OUTPUT_EXISTS,
+            "Output file exists. Consider passing overwrite to ignore this",
+        ));
+    }
+    let options = DecodeOptions::default().max_output_size(args.max_output_size);
+    let file = File::create(output).map_err(Error::from)?;
+    let summary = match lizards::http::fetch(&args.url, std::io::BufWriter::new(file), &options) {
+        Ok(summary) => summary,
+        Err(e) => {
+            let _ = std::fs::remove_file(output);
+            return Err(e.into());
+        }
+    };
+    report.input_bytes = Some(summary.input_bytes);
+    report.output_bytes = Some(summary.output_bytes);
+    report.ratio = Some(summary.input_bytes as f64 / summary.output_bytes as f64);
+    Ok(())
+}
+
+/// Run [process] over each input, stopping at the first failure. With more than one input they
+/// are processed [CommandLineArgs::threads] at a time, each given one thread, and files already
+/// started when one fails are still finished. Reports are printed in input order either way
+fn run_all<A: Sync>(
+    common: &CommandLineArgs,
+    args: &A,
+    process: fn(&Path, &A, usize) -> Report,
+) -> ExitCode {
+    if let Some(output_filename) = &common.output_filename {
+        if !common.glob.is_empty() && !output_filename.is_dir() {
+            eprintln!("Error: --output-filename must be an existing directory with --glob");
+            return ExitCode::from(EXIT_FAILURE);
+        }
+    }
+    let filenames = match common.input_filenames() {
+        Ok(filenames) => filenames,
+        Err(failure) => {
+            eprintln!("Error: {}", failure.message);
+            return ExitCode::from(failure.exit_code);
+        }
+    };
+    let threads = common.threads();
+    if filenames.len() == 1 || threads == 1 {
+        for filename in filenames {
+            let report = process(&filename, args, threads);
+            report.print(common.json);
+            if report.exit_code != 0 {
+                return ExitCode::from(report.exit_code);
+            }
+        }
+        return ExitCode::SUCCESS;
+    }
+
+    let next = AtomicUsize::new(0);
+    let failed = AtomicBool::new(false);
+    let (sender, receiver) = mpsc::channel();
+    std::thread::scope(|scope| {
+        for _ in 0..threads.min(filenames.len()) {
+            let sender = sender.clone();
+            let (next, failed, filenames) = (&next, &failed, &filenames);
+            scope.spawn(move || {
+                while !failed.load(Ordering::Relaxed) {
+                    let index = next.fetch_add(1, Ordering::Relaxed);
+                    let Some(filename) = filenames.get(index) else {
+                        break;
+                    };
+                    let report = process(filename, args, 1);
+                    if report.exit_code != 0 {
+                        failed.store(true, Ordering::Relaxed);
+                    }
+                    if sender.send((index, report)).is_err() {
+                        break;
+                    }
+                }
+            });
+        }
+        drop(sender);
+
+        let mut waiting = BTreeMap::new();
+        let mut next_to_print = 0;
+        let mut exit_code = ExitCode::SUCCESS;
+        for (index, report) in receiver {
+            waiting.insert(index, report);
+            while let Some(report) = waiting.remove(&next_to_print) {
+                report.print(common.json);
+                if report.exit_code != 0 && exit_code == ExitCode::SUCCESS {
+                    exit_code = ExitCode::from(report.exit_code);
+                }
+                next_to_print += 1;
+            }
+        }
+        exit_code
+    })
+}
+
+/// [threads] as given, or one per core
+fn threads(threads: Option<usize>) -> usize {
+    match threads {
+        Some(threads) => threads.max(1),
+        None => std::thread::available_parallelism().map_or(1, NonZeroUsize::get),
+    }
+}
+
+impl CommandLineSubCommand {
+    fn common_mut(&mut self) -> Option<&mut CommandLineArgs> {
+        match self {
+            CommandLineSubCommand::Compress(args) => Some(&mut args.common),
+            CommandLineSubCommand::Decompress(args) => Some(&mut args.common),
+            CommandLineSubCommand::Recover(args) => Some(&mut args.common),
+            CommandLineSubCommand::List(args) => Some(&mut args.common),
+            CommandLineSubCommand::Test(args) => Some(&mut args.common),
+            CommandLineSubCommand::Inspect(args) => Some(&mut args.common),
+            CommandLineSubCommand::Analyze(args) => Some(&mut args.common),
+            CommandLineSubCommand::DebugDecode(args) => Some(&mut args.common),
+            CommandLineSubCommand::Diff(args) => Some(&mut args.common),
+            #[cfg(feature = "crypto")]
+            CommandLineSubCommand::Verify(args) => Some(&mut args.common),
+            _ => None,
+        }
+    }
+}
+
+/// Fill in options not given on the command line from the environment, and then the config file
+/// unless it was asked to be ignored
+fn apply_config(command: &mut CommandLineSubCommand) -> Result<(), Failure> {
+    let no_config = match command {
+        CommandLineSubCommand::Cat(args) => args.no_config,
+        CommandLineSubCommand::Grep(args) => args.no_config,
+        _ => match command.common_mut() {
+            Some(common) => common.no_config,
+            // Nothing else takes anything from it
+            None => return Ok(()),
+        },
+    };
+    let config = match no_config {
+        true => Config::default(),
+        false => Config::load_default()?,
+    };
+    let config = config.with_env()?;
+    if let Some(common) = command.common_mut() {
+        common.threads = common.threads.or(config.threads);
+        common.on_conflict = common.on_conflict.or(config.on_conflict.map(Into::into));
+        common.exclude.extend(config.exclude.iter().cloned());
+    }
+    match command {
+        CommandLineSubCommand::Compress(args) => {
+            args.level = args.level.or(config.level.map(Into::into));
+            args.buffer_size = args.buffer_size.or(config.window_size);
+        }
+        CommandLineSubCommand::Analyze(args) => {
+            args.level = args.level.or(config.level.map(Into::into));
+            args.buffer_size = args.buffer_size.or(config.window_size);
+        }
+        CommandLineSubCommand::Cat(args) => args.threads = args.threads.or(config.threads),
+        CommandLineSubCommand::Grep(args) => args.threads = args.threads.or(config.threads),
+        _ => {}
+    }
+    Ok(())
+}
+
+fn init_logging(verbose: u8) {
+    let level = match verbose {
+        0 => log::LevelFilter::Warn,
+        1 => log::LevelFilter::Info,
+        _ => log::LevelFilter::Debug,
+    };
+    env_logger::Builder::new().filter_level(level).init();
+}
+
+#[cfg(feature = "crypto")]
+fn any_encrypted(filenames: &[PathBuf]) -> bool {
+    filenames.iter().any(|filename| {
+        VolumeReader::open(filename)
+            .and_then(lizards::crypto::is_encrypted)
+            .unwrap_or(false)
+    })
+}
+
+/// Environment variable the passphrase is read from, for scripts. [PassphraseArgs::passphrase_file]
+/// takes precedence
+#[cfg(feature = "crypto")]
+const PASSPHRASE_VAR: &str = "LIZARDS_PASSPHRASE";
+
+#[cfg(feature = "crypto")]
+impl PassphraseArgs {
+    /// Fill in [passphrase] from wherever it's been given, asking for it on the terminal as a
+    /// last resort. When it's new, i.e. for encrypting, it's asked for twice to catch typos
+    fn read(&mut self, new: bool) -> Result<(), Failure> {
+        let passphrase = if let Some(path) = &self.passphrase_file {
+            let contents = std::fs::read_to_string(path)
+                .map(Zeroizing::new)
+                .map_err(|e| {
+                    Failure::new(
+                        EXIT_INPUT_MISSING,
+                        format!("Couldn't read passphrase file {}: {}", path.display(), e),
+                    )
+                })?;
+            Passphrase::new(contents.lines().next().unwrap_or_default())
+        } else if let Some(passphrase) = std::env::var_os(PASSPHRASE_VAR) {
+            let passphrase = passphrase.into_string().map_err(|_| {
+                Failure::new(
+                    EXIT_FAILURE,
+                    format!("{} isn't valid UTF-8", PASSPHRASE_VAR),
+                )
+            })?;
+            Passphrase::new(passphrase)
+        } else {
+            let ask = |prompt| {
+                rpassword::prompt_password(prompt)
+                    .map(Passphrase::new)
+                    .map_err(|e| {
+                        Failure::new(EXIT_FAILURE, format!("Couldn't read a passphrase: {}", e))
+                    })
+            };
+            let passphrase = ask("Passphrase: ")?;
+            if new && ask("Confirm passphrase: ")? != passphrase {
+                return Err(Failure::new(EXIT_FAILURE, "The passphrases don't match"));
+            }
+            passphrase
+        };
+        if new && passphrase.is_empty() {
+            return Err(Failure::new(EXIT_FAILURE, "The passphrase can't be empty"));
+        }
+        self.passphrase = Some(passphrase);
+        Ok(())
+    }
+}
+
+/// Get the passphrase once up front, for compressing with --encrypt or decompressing or
+/// testing any encrypted input
+#[cfg(feature = "crypto")]
+fn read_passphrases(command: &mut CommandLineSubCommand) -> Result<(), Failure> {
+    match command {
+        CommandLineSubCommand::Compress(args) if args.encrypt => {
+            if args.format == OutputFormat::Gzip {
+                return Err(Failure::new(
+                    EXIT_FAILURE,
+                    "--encrypt isn't supported for gzip output",
+                ));
+            }
+            args.secret.read(true)
+        }
+        CommandLineSubCommand::Decompress(args) => {
+            match any_encrypted(&args.common.input_filenames()?) {
+                true => args.secret.read(false),
+                false => Ok(()),
+            }
+        }
+        CommandLineSubCommand::Test(args) => match any_encrypted(&args.common.input_filenames()?) {
+            true => args.secret.read(false),
+            false => Ok(()),
+        },
+        CommandLineSubCommand::Cat(args) if any_encrypted(&args.files) => args.secret.read(false),
+        CommandLineSubCommand::Grep(args) if any_encrypted(&args.files) => args.secret.read(false),
+        _ => Ok(()),
+    }
+}
+
+fn main() -> ExitCode {
+    #[allow(unused_mut)]
+    let mut command = CommandLineSubCommand::parse();
+    match &command {
+        CommandLineSubCommand::Compress(args) => init_logging(args.common.verbose),
+        CommandLineSubCommand::Decompress(args) => init_logging(args.common.verbose),
+        CommandLineSubCommand::Cat(args) => init_logging(args.verbose),
+        CommandLineSubCommand::Grep(args) => init_logging(args.verbose),
+        CommandLineSubCommand::Recover(args) => init_logging(args.common.verbose),
+        CommandLineSubCommand::List(args) => init_logging(args.common.verbose),
+        CommandLineSubCommand::Test(args) => init_logging(args.common.verbose),
+        CommandLineSubCommand::Analyze(args) => init_logging(args.common.verbose),
+        CommandLineSubCommand::DebugDecode(args) => init_logging(args.common.verbose),
+        CommandLineSubCommand::Diff(args) => init_logging(args.common.verbose),
+        CommandLineSubCommand::Inspect(args) => init_logging(args.common.verbose),
+        CommandLineSubCommand::Append(args) => init_logging(args.verbose),
+        CommandLineSubCommand::Update(args) => init_logging(args.verbose),
+        CommandLineSubCommand::Selftest(args) => init_logging(args.verbose),
+        #[cfg(feature = "crypto")]
+        CommandLineSubCommand::Verify(args) => init_logging(args.common.verbose),
+        #[cfg(feature = "crypto")]
+        CommandLineSubCommand::Keygen(_) => init_logging(0),
+        #[cfg(feature = "http")]
+        CommandLineSubCommand::Serve(args) => init_logging(args.verbose.max(1)),
+        #[cfg(feature = "http")]
+        CommandLineSubCommand::Fetch(args) => init_logging(args.verbose),
+    }
+    if let Err(failure) = apply_config(&mut command) {
+        eprintln!("Error: {}", failure.message);
+        return ExitCode::from(failure.exit_code);
+    }
+    #[cfg(feature = "crypto")]
+    if let Err(failure) = read_passphrases(&mut command) {
+        eprintln!("Error: {}", failure.message);
+        return ExitCode::from(failure.exit_code);
+    }
+    match command {
+        CommandLineSubCommand::Compress(args) => run_all(&args.common, &args, compress),
+        CommandLineSubCommand::Decompress(args) => run_all(&args.common, &args, decompress),
+        CommandLineSubCommand::Cat(args) => cat(&args),
+        CommandLineSubCommand::Grep(args) => grep(&args),
+        CommandLineSubCommand::Recover(args) => run_all(&args.common, &args, recover),
+        CommandLineSubCommand::List(args) => run_all(&args.common, &args, list),
+        CommandLineSubCommand::Test(args) => run_all(&args.common, &args, test),
+        CommandLineSubCommand::Analyze(args) => run_all(&args.common, &args, analyze),
+        CommandLineSubCommand::DebugDecode(args) => run_all(&args.common, &args, debug_decode),
+        CommandLineSubCommand::Diff(args) => run_all(&args.common, &args, diff),
+        CommandLineSubCommand::Inspect(args) => run_all(&args.common, &args, inspect),
+        CommandLineSubCommand::Append(args) => append(&args),
+        CommandLineSubCommand::Update(args) => update(&args),
+        CommandLineSubCommand::Selftest(args) => selftest(&args),
+        #[cfg(feature = "crypto")]
+        CommandLineSubCommand::Verify(args) => run_all(&args.common, &args, verify),
+        #[cfg(feature = "crypto")]
+        CommandLineSubCommand::Keygen(args) => keygen(&args),
+        #[cfg(feature = "http")]
+        CommandLineSubCommand::Serve(args) => serve(&args),
+        #[cfg(feature = "http")]
+        CommandLineSubCommand::Fetch(args) => fetch(&args),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use clap::Parser;
+
+    use crate::{CommandLineSubCommand, EXIT_FAILURE, EXIT_INPUT_MISSING};
+
+    fn input_filenames_failure(args: &[&str]) -> u8 {
+        let command = CommandLineSubCommand::try_parse_from(args).unwrap();
+        let CommandLineSubCommand::Compress(args) = command else {
+            panic!("Expected compress to be parsed");
+        };
+        match args.common.input_filenames() {
+            Ok(paths) => panic!("Expected no input files, got {:?}", paths),
+            Err(failure) => failure.exit_code,
+        }
+    }
+
+    #[test]
+    fn glob_matching_nothing_is_input_missing() {
+        let args = ["lizards", "compress", "--glob", "lizards_no_such_file_*"];
+        assert_eq!(EXIT_INPUT_MISSING, input_filenames_failure(&args));
+    }
 
-    fn to_debug_bytes(&self) -> Vec<u8> {
-        let s = format!("<{}>", self.len);
-        s.into_bytes()
+    #[test]
+    fn invalid_patterns_are_failures() {
+        let args = ["lizards", "compress", "--glob", "["];
+        assert_eq!(EXIT_FAILURE, input_filenames_failure(&args));
+        // Also how an invalid exclude pattern from the config file ends up
+        let args = ["lizards", "compress", "--glob", "*", "--exclude", "["];
+        assert_eq!(EXIT_FAILURE, input_filenames_failure(&args));
     }
 }
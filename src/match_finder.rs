@@ -0,0 +1,311 @@
+//! Finding the token to encode next: the longest match against the window, or a literal. The
+//! strategies trade how hard they look against how fast they are, so [MatchStrategy::Scan]
+//! stays the default while others are tried out alongside it
+
+use serde::{Deserialize, Serialize};
+use std::collections::VecDeque;
+
+use crate::offset_len::{MatchLen, OffsetLen, WindowPos};
+use crate::Token;
+
+/// How the encoder looks for matches. Not recorded anywhere, as the decoder doesn't need to know
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum MatchStrategy {
+    /// Check every position in the window, so always finds the longest match. Slow with big
+    /// windows
+    #[default]
+    Scan,
+    /// Only check positions that start with the same few bytes, most recent first, up to a
+    /// limit. Much faster with big windows, but can miss the longest match
+    HashChain,
+}
+
+impl MatchStrategy {
+    pub(crate) fn finder(self, min_match: usize, window_size: usize) -> Box<dyn MatchFinder> {
+        match self {
+            Self::Scan => Box::new(Scan::new(min_match)),
+            Self::HashChain => Box::new(HashChain::new(min_match, window_size)),
+        }
+    }
+}
+
+/// Picks the token covering the front of a [Window]'s read buffer. Matches must be at least as
+/// long as the finder's min match, and shouldn't go past the end of the read buffer, which
+/// the encoder extends them beyond itself. A finder is used for one run of the encoder, seeing
+/// the window move forward each time
+pub(crate) trait MatchFinder {
+    fn next_token(&mut self, window: &Window) -> Token;
+}
+
+/// What a [MatchFinder] sees: the bytes already coded, which matches start in, then the bytes
+/// still to code, starting with the one the next token covers
+pub(crate) struct Window<'a> {
+    lookback: &'a VecDeque<u8>,
+    read: &'a VecDeque<u8>,
+    /// How far into the input (including any primer) the front of [read] is
+    position: u64,
+}
+
+impl<'a> Window<'a> {
+    pub fn new(lookback: &'a VecDeque<u8>, read: &'a VecDeque<u8>, position: u64) -> Self {
+        Self {
+            lookback,
+            read,
+            position,
+        }
+    }
+
+    /// Byte [i] of the lookback buffer followed by the read buffer
+    fn byte(&self, i: usize) -> u8 {
+        match i.checked_sub(self.lookback.len()) {
+            None => self.lookback[i],
+            Some(i) => self.read[i],
+        }
+    }
+
+    /// How much of the read buffer matches the bytes from [start] in the lookback buffer on.
+    /// Matches can run on into the read buffer, so repetitions (e.g. a long run of one byte)
+    /// match against themselves, as the decoder will have produced those bytes by the time it
+    /// needs them
+    fn match_len(&self, start: usize) -> usize {
+        self.read
+            .iter()
+            .enumerate()
+            .take_while(|(i, expecting)| self.byte(start + i) == **expecting)
+            .count()
+    }
+
+    /// The match starting at [start] in the lookback buffer [len] long, or the literal at the
+    /// front of the read buffer if there's no match at least [min_match] long
+    fn token(&self, best: Option<(usize, usize)>, min_match: usize) -> Token {
+        let offset_len = best
+            .filter(|(_, len)| *len >= min_match)
+            .and_then(|(start, len)| {
+                let window_len = self.lookback.len();
+                let offset = WindowPos::new(window_len - start, window_len)?;
+                let matched_values = self.read.iter().take(len).copied().collect();
+                Some(OffsetLen::new_with_match(
+                    offset,
+                    MatchLen::new(len)?,
+                    Some(matched_values),
+                ))
+            });
+        match offset_len {
+            Some(offset_len) => Token::Match(offset_len),
+            None => Token::Literal(*self.read.front().unwrap()),
+        }
+    }
+}
+
+/// [MatchStrategy::Scan]. Of equally long matches, takes the one furthest back
+pub(crate) struct Scan {
+    min_match: usize,
+}
+
+impl Scan {
+    pub fn new(min_match: usize) -> Self {
+        Self { min_match }
+    }
+}
+
+impl MatchFinder for Scan {
+    fn next_token(&mut self, window: &Window) -> Token {
+        // Best match so far: start, matched length
+        let mut best: Option<(usize, usize)> = None;
+        for start in 0..window.lookback.len() {
+            let len = window.match_len(start);
+            if best.map_or(len > 0, |(_, best_len)| len > best_len) {
+                best = Some((start, len));
+                if len == window.read.len() {
+                    // Can't do any better than matching everything we have
+                    break;
+                }
+            }
+        }
+        window.token(best, self.min_match)
+    }
+}
+
+/// Bytes hashed to find where to look for a match, so shorter matches aren't found
+pub(crate) const HASH_LEN: usize = 3;
+pub(crate) const HASH_BITS: u32 = 15;
+/// Most positions [HashChain] checks for each token
+pub(crate) const MAX_CHAIN_LEN: usize = 128;
+
+/// Hash of the first [HASH_LEN] of [bytes], under `1 << HASH_BITS`
+pub(crate) fn hash(bytes: [u8; HASH_LEN]) -> usize {
+    let bytes = bytes.iter().fold(0u32, |hash, b| hash << 8 | *b as u32);
+    (bytes.wrapping_mul(0x9e3779b1) >> (32 - HASH_BITS)) as usize
+}
+
+/// [MatchStrategy::HashChain]. Of equally long matches, takes the nearest
+pub(crate) struct HashChain {
+    min_match: usize,
+    /// The most recent position each hash was seen at
+    head: Vec<Option<u64>>,
+    /// By position, modulo the window size: the previous position with the same hash
+    prev: Vec<Option<u64>>,
+    /// Positions before this have been added to the chains
+    added: u64,
+}
+
+impl HashChain {
+    pub fn new(min_match: usize, window_size: usize) -> Self {
+        Self {
+            min_match,
+            head: vec![None; 1 << HASH_BITS],
+            prev: vec![None; window_size.max(1)],
+            added: 0,
+        }
+    }
+
+    /// Hash of the [HASH_LEN] bytes from [i] in the window, if there are that many
+    fn hash(window: &Window, i: usize) -> Option<usize> {
+        if i + HASH_LEN > window.lookback.len() + window.read.len() {
+            return None;
+        }
+        Some(hash(std::array::from_fn(|j| window.byte(i + j))))
+    }
+}
+
+impl MatchFinder for HashChain {
+    fn next_token(&mut self, window: &Window) -> Token {
+        let window_start = window.position - window.lookback.len() as u64;
+        // Add the positions that have moved into the lookback buffer since last time. Any that
+        // have already dropped out of it can't be matched against anyway
+        self.added = self.added.max(window_start);
+        while self.added < window.position {
+            if let Some(hash) = Self::hash(window, (self.added - window_start) as usize) {
+                let slot = (self.added % self.prev.len() as u64) as usize;
+                self.prev[slot] = self.head[hash].replace(self.added);
+            }
+            self.added += 1;
+        }
+
+        let mut best: Option<(usize, usize)> = None;
+        let mut candidate =
+            Self::hash(window, window.lookback.len()).and_then(|hash| self.head[hash]);
+        for _ in 0..MAX_CHAIN_LEN {
+            // The chain runs back in position, until it leaves the window
+            let Some(position) = candidate.filter(|position| *position >= window_start) else {
+                break;
+            };
+            let start = (position - window_start) as usize;
+            let len = window.match_len(start);
+            if best.map_or(len > 0, |(_, best_len)| len > best_len) {
+                best = Some((start, len));
+                if len == window.read.len() {
+                    break;
+                }
+            }
+            let slot = (position % self.prev.len() as u64) as usize;
+            candidate = self.prev[slot].filter(|previous| *previous < position);
+        }
+        window.token(best, self.min_match)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::VecDeque;
+
+    use crate::match_finder::{MatchFinder, MatchStrategy, Window};
+    use crate::Token;
+
+    /// Checks every offset in turn, as simply as possible, to compare the finders against
+    struct BruteForce;
+
+    impl MatchFinder for BruteForce {
+        fn next_token(&mut self, window: &Window) -> Token {
+            let coded: Vec<u8> = window.lookback.iter().copied().collect();
+            let read: Vec<u8> = window.read.iter().copied().collect();
+            let all = [coded.as_slice(), read.as_slice()].concat();
+            let best = (0..coded.len())
+                .map(|start| {
+                    let len = (0..read.len())
+                        .take_while(|i| all[start + i] == read[*i])
+                        .count();
+                    (start, len)
+                })
+                .max_by_key(|(start, len)| (*len, usize::MAX - start));
+            window.token(best, 1)
+        }
+    }
+
+    fn len(token: &Token) -> usize {
+        match token {
+            Token::Match(offset_len) => offset_len.len.get(),
+            _ => 1,
+        }
+    }
+
+    /// Steps through [input] with [finder] as the encoder would, checking each token is right
+    /// and giving the lengths of the longest possible matches along with those found
+    fn run(input: &[u8], window_size: usize, finder: &mut dyn MatchFinder) -> Vec<(usize, usize)> {
+        let mut lookback = VecDeque::new();
+        let mut lens = Vec::new();
+        let mut position = 0;
+        while position < input.len() {
+            let read: VecDeque<u8> = input[position..].iter().take(32).copied().collect();
+            let window = Window::new(&lookback, &read, position as u64);
+            let token = finder.next_token(&window);
+            let longest = len(&BruteForce.next_token(&window));
+            if let Token::Match(offset_len) = &token {
+                let start = offset_len.offset().start(position).unwrap();
+                assert!(start + window_size >= position, "outside the window");
+                let matched = &input[position..position + offset_len.len.get()];
+                let source: Vec<u8> = (0..matched.len()).map(|i| input[start + i]).collect();
+                assert_eq!(matched, source.as_slice());
+            }
+            lens.push((longest, len(&token)));
+            for b in &input[position..position + len(&token)] {
+                lookback.push_back(*b);
+                if lookback.len() > window_size {
+                    lookback.pop_front();
+                }
+            }
+            position += len(&token);
+        }
+        lens
+    }
+
+    fn input() -> Vec<u8> {
+        let words = [
+            "gecko", "skink", "iguana", "anole", "monitor", " ", " ", "\n",
+        ];
+        (0..2000u32)
+            .map(|i| words[(i.wrapping_mul(2654435761) >> 7) as usize % words.len()])
+            .collect::<String>()
+            .into_bytes()
+    }
+
+    #[test]
+    fn scan_finds_longest() {
+        let mut scan = MatchStrategy::Scan.finder(1, 300);
+        for (longest, found) in run(&input(), 300, scan.as_mut()) {
+            assert_eq!(longest, found);
+        }
+    }
+
+    #[test]
+    fn hash_chain_within_bounds() {
+        let mut hash_chain = MatchStrategy::HashChain.finder(1, 300);
+        let lens = run(&input(), 300, hash_chain.as_mut());
+        for (longest, found) in &lens {
+            assert!(found <= longest);
+            // Any match long enough to hash is found, if not necessarily the longest
+            if *longest >= super::HASH_LEN {
+                assert!(*found >= super::HASH_LEN);
+            }
+        }
+    }
+
+    #[test]
+    fn hash_chain_starts_with_primer() {
+        let lookback: VecDeque<u8> = b"__abcdef".iter().copied().collect();
+        let read: VecDeque<u8> = b"abcdefgh".iter().copied().collect();
+        let mut hash_chain = MatchStrategy::HashChain.finder(4, 100);
+        let token = hash_chain.next_token(&Window::new(&lookback, &read, 8));
+        assert!(matches!(token, Token::Match(offset_len) if offset_len.len.get() == 6));
+    }
+}
@@ -1,20 +1,126 @@
-use std::ops::Range;
+use serde::{Deserialize, Serialize};
+use std::io::Write;
 
-#[derive(Debug, PartialEq)]
+/// Bits used for each value's bin in the binned layout
+const BIN_BITS: u32 = 5;
+/// Bin for values too big for the others, whose extra bits are the whole u64
+const ESCAPE_BIN: u64 = (1 << BIN_BITS) - 1;
+/// The `10` token prefix and both bins
+const BINNED_PREFIX_BITS: u32 = 2 + 2 * BIN_BITS;
+/// Bytes taken by a match with both values escaped
+const MAX_BINNED_LEN: usize = (BINNED_PREFIX_BITS as usize + 2 * 64).div_ceil(8);
+
+/// Where a match starts, counted back from where it's made, so 1 is the byte just before. Only
+/// made by [WindowPos::new], so is always within the window the match was made in. Deserializing
+/// one only checks it isn't 0, as there's no window to check against
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+#[serde(try_from = "u32", into = "u32")]
+pub struct WindowPos(u32);
+
+impl WindowPos {
+    /// Biggest window a [WindowPos] can point back across
+    pub const MAX_WINDOW_LEN: usize = u32::MAX as usize;
+
+    /// The byte just before
+    pub const NEAREST: Self = Self(1);
+
+    /// [distance] bytes back in a window of [window_len] bytes, or None if that's not one of
+    /// them
+    pub fn new(distance: impl TryInto<u32>, window_len: usize) -> Option<Self> {
+        let distance = distance.try_into().ok().filter(|d| *d > 0)?;
+        (distance as usize <= window_len).then_some(Self(distance))
+    }
+
+    pub fn get(self) -> usize {
+        self.0 as usize
+    }
+
+    /// Where the match starts, when made with [cursor] bytes before it. None if that's not one
+    /// of those bytes, which it can be if [cursor] isn't the window this was checked against
+    pub fn start(self, cursor: usize) -> Option<usize> {
+        cursor.checked_sub(self.get())
+    }
+}
+
+impl TryFrom<u32> for WindowPos {
+    type Error = &'static str;
+
+    fn try_from(distance: u32) -> Result<Self, Self::Error> {
+        Self::new(distance, Self::MAX_WINDOW_LEN).ok_or("a match can't start where it's made")
+    }
+}
+
+impl From<WindowPos> for u32 {
+    fn from(pos: WindowPos) -> Self {
+        pos.0
+    }
+}
+
+/// How many bytes a match covers
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+pub struct MatchLen(u32);
+
+impl MatchLen {
+    /// Longest match that can be made
+    pub const MAX: usize = u32::MAX as usize;
+
+    /// None if [len] is longer than [MatchLen::MAX]
+    pub fn new(len: impl TryInto<u32>) -> Option<Self> {
+        len.try_into().ok().map(Self)
+    }
+
+    pub fn get(self) -> usize {
+        self.0 as usize
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct OffsetLen {
-    offset: u64,
-    pub len: u64,
+    /// Before version 6 the stream instead held an index into the lookback buffer, which the
+    /// decoder turns into one of these
+    offset: WindowPos,
+    pub len: MatchLen,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
     matched_bytes: Option<Vec<u8>>,
 }
 
 impl OffsetLen {
-    pub fn new_with_match(offset: u64, len: u64, matched_bytes: Option<Vec<u8>>) -> Self {
+    pub fn new_with_match(
+        offset: WindowPos,
+        len: MatchLen,
+        matched_bytes: Option<Vec<u8>>,
+    ) -> Self {
         Self {
             offset,
             len,
             matched_bytes,
         }
     }
+
+    pub fn new(offset: WindowPos, len: MatchLen) -> Self {
+        Self::new_with_match(offset, len, None)
+    }
+
+    /// The match [offset] bytes back in a window of [window_len] bytes and [len] long, or None
+    /// if it doesn't fit in the window, as values read from a corrupt stream may not
+    pub fn checked(
+        offset: impl TryInto<u32>,
+        len: impl TryInto<u32>,
+        window_len: usize,
+    ) -> Option<Self> {
+        Some(Self::new(
+            WindowPos::new(offset, window_len)?,
+            MatchLen::new(len)?,
+        ))
+    }
+
+    /// A match in a window as big as they get, for tests
+    #[cfg(test)]
+    pub(crate) fn of(offset: u32, len: u32) -> Self {
+        Self::checked(offset, len, WindowPos::MAX_WINDOW_LEN).unwrap()
+    }
+
+    #[cfg(test)]
     const SIZES: [u64; 8] = [
         2u64.pow(8) - 1,
         2u64.pow(16) - 1,
@@ -26,6 +132,7 @@ impl OffsetLen {
         u64::MAX,
     ];
 
+    #[cfg(test)]
     fn find_num_bytes(v: u64) -> usize {
         for (i, size) in Self::SIZES.iter().enumerate() {
             if v <= *size {
@@ -34,6 +141,7 @@ impl OffsetLen {
         }
         panic!("BUG: Though a u64 wouldn't fit into any sizes including a u64")
     }
+    #[cfg(test)]
     fn take_bytes(value: u64, num_bytes: usize) -> Vec<u8> {
         let mut output = Vec::<u8>::new();
         for i in 0..num_bytes {
@@ -45,19 +153,29 @@ impl OffsetLen {
     fn value_of_bytes(bytes: &[u8]) -> u64 {
         let mut result: u64 = 0;
         for (i, byte) in bytes.iter().enumerate() {
-            result = result | (*byte as u64) << (i * 8);
+            result |= (*byte as u64) << (i * 8);
         }
         result
     }
 
-    pub fn new(offset: u64, len: u64) -> Self {
-        Self::new_with_match(offset, len, None)
+    /// Number of bytes [write_to] will produce
+    pub fn encoded_len(&self) -> usize {
+        let (offset, len) = self.values();
+        let bits = BINNED_PREFIX_BITS + extra_bits(bin(offset).0) + extra_bits(bin(len).0);
+        bits.div_ceil(8) as usize
+    }
+
+    /// The offset and length as they're written
+    fn values(&self) -> (u64, u64) {
+        (u64::from(self.offset.0), u64::from(self.len.0))
     }
 
-    pub fn to_bytes_new(&self) -> Vec<u8> {
+    /// The layout before version 5, which the encoder no longer writes
+    #[cfg(test)]
+    pub fn whole_bytes(offset: u64, len: u64) -> Vec<u8> {
         // 8 16 24 32 40 48 56 64
-        let num_bytes_for_offset = Self::find_num_bytes(self.offset);
-        let num_bytes_for_len = Self::find_num_bytes(self.len);
+        let num_bytes_for_offset = Self::find_num_bytes(offset);
+        let num_bytes_for_len = Self::find_num_bytes(len);
 
         // We convert each number of bytes into 3 bits
         // (we get 0-7, by subtracting 1 from this number
@@ -75,11 +193,17 @@ impl OffsetLen {
         // Then bytes: [num_bytes; offset_0; ...; offset_i; len_0; ... len_i]
         // Where 0th is the right hand u8
         // To reconstruct one would do e.g. [offset_2; offset_1; offset_1]
-        result.extend(Self::take_bytes(self.offset, num_bytes_for_offset));
-        result.extend(Self::take_bytes(self.len, num_bytes_for_len));
+        result.extend(Self::take_bytes(offset, num_bytes_for_offset));
+        result.extend(Self::take_bytes(len, num_bytes_for_len));
         result
     }
 
+    /// Total length of a match in the whole bytes layout, given its first byte
+    pub fn whole_bytes_len(header_byte: u8) -> usize {
+        let (num_bytes_for_offset, num_bytes_for_len) = Self::read_header_byte(header_byte);
+        1 + num_bytes_for_offset + num_bytes_for_len
+    }
+
     pub fn read_header_byte(header_byte: u8) -> (usize, usize) {
         // Increasing number by 1 as it was decreased when encoded to fit in 3 bits
         let num_bytes_for_offset = (header_byte >> 3 & 0b00000111) as usize + 1;
@@ -87,8 +211,10 @@ impl OffsetLen {
         (num_bytes_for_offset, num_bytes_for_len)
     }
 
-    pub fn of_bytes_new(bytes: &Vec<u8>) -> Self {
-        let len_byte = *bytes.get(0).unwrap();
+    /// The offset and length of a whole match in the layout before version 5, unchecked as
+    /// they're yet to be fitted to a window
+    pub fn read_whole_bytes(bytes: &[u8]) -> (u64, u64) {
+        let len_byte = *bytes.first().unwrap();
         let (num_bytes_for_offset, num_bytes_for_len) = Self::read_header_byte(len_byte);
         let expected_num_bytes = 1 + num_bytes_for_offset + num_bytes_for_len;
         if bytes.len() != expected_num_bytes {
@@ -101,76 +227,235 @@ impl OffsetLen {
         }
         let offset_bytes = &bytes[1..(1 + num_bytes_for_offset)];
         let len_bytes = &bytes[(1 + num_bytes_for_offset)..];
-        let offset = Self::value_of_bytes(offset_bytes);
-        let len = Self::value_of_bytes(len_bytes);
-        Self {
-            offset,
-            len,
-            matched_bytes: None,
+        (
+            Self::value_of_bytes(offset_bytes),
+            Self::value_of_bytes(len_bytes),
+        )
+    }
+
+    /// Since version 5 each value is written as the bin of its bit length plus the bits below
+    /// its leading 1, so small values take few bits. Laid out most significant bit first as
+    /// `[10][offset bin: 5][len bin: 5][offset extra bits][len extra bits]`, padded to a byte
+    /// Returns how many bytes were written
+    pub fn write_to<W: Write>(&self, writer: &mut W) -> std::io::Result<usize> {
+        let (offset, len) = self.values();
+        write_binned(offset, len, writer)
+    }
+
+    /// The length of the binned match starting [prefix], or None if more bytes are needed to
+    /// tell
+    pub fn binned_len(prefix: &[u8]) -> Option<usize> {
+        if prefix.len() < 2 {
+            return None;
         }
+        let bins = u16::from_be_bytes([prefix[0], prefix[1]]) >> (16 - BINNED_PREFIX_BITS);
+        let offset_bin = (bins >> BIN_BITS) as u64 & ESCAPE_BIN;
+        let len_bin = bins as u64 & ESCAPE_BIN;
+        let bits = BINNED_PREFIX_BITS + extra_bits(offset_bin) + extra_bits(len_bin);
+        Some(bits.div_ceil(8) as usize)
     }
 
-    pub fn to_bytes_debug(&self) -> Vec<u8> {
-        let matched_string = if let Some(bytes) = &self.matched_bytes {
-            match String::from_utf8(bytes.clone()) {
-                Ok(s) => format!("{:?}", s),
-                Err(_) => format!("{:?}", bytes),
-            }
-        } else {
-            String::from("No matched bytes recorded")
+    /// The offset and length of a whole match written by [write_to], unchecked as they're yet
+    /// to be fitted to a window
+    pub fn read_binned(bytes: &[u8]) -> (u64, u64) {
+        let mut reader = BitUnpacker { bytes, position: 2 };
+        let offset_bin = reader.take(BIN_BITS);
+        let len_bin = reader.take(BIN_BITS);
+        let offset = unbin(offset_bin, reader.take(extra_bits(offset_bin)));
+        let len = unbin(len_bin, reader.take(extra_bits(len_bin)));
+        (offset, len)
+    }
+
+    /// The bytes matched, if they were recorded when the match was made
+    pub fn matched_bytes(&self) -> Option<&[u8]> {
+        self.matched_bytes.as_deref()
+    }
+
+    /// Grow the match by one byte, which must be [byte]. Stays at [MatchLen::MAX] rather than
+    /// growing past it
+    pub fn extend(&mut self, byte: u8) {
+        let Some(len) = self.len.0.checked_add(1) else {
+            return;
         };
-        let s = format!("({},{}: {})", self.offset, self.len, matched_string);
-        s.into_bytes()
+        self.len = MatchLen(len);
+        if let Some(matched_bytes) = &mut self.matched_bytes {
+            matched_bytes.push(byte);
+        }
     }
 
-    pub fn to_range(&self) -> Range<usize> {
-        Range {
-            start: self.offset as usize,
-            end: self.range_end(),
+    pub fn offset(&self) -> WindowPos {
+        self.offset
+    }
+}
+
+/// [OffsetLen::write_to] for any values, including those too big for an [OffsetLen]
+fn write_binned<W: Write>(offset: u64, len: u64, writer: &mut W) -> std::io::Result<usize> {
+    let (offset_bin, offset_extra) = bin(offset);
+    let (len_bin, len_extra) = bin(len);
+    let mut packer = BitPacker::default();
+    packer.push(0b10, 2);
+    packer.push(offset_bin, BIN_BITS);
+    packer.push(len_bin, BIN_BITS);
+    packer.push(offset_extra, extra_bits(offset_bin));
+    packer.push(len_extra, extra_bits(len_bin));
+    writer.write_all(packer.as_bytes())?;
+    Ok(packer.as_bytes().len())
+}
+
+/// The bin [v] falls in and the extra bits distinguishing it within the bin. Bins 0 and 1 hold
+/// just that value, and bin n otherwise holds the values with bit length n
+fn bin(v: u64) -> (u64, u64) {
+    let bit_len = (64 - v.leading_zeros()) as u64;
+    if bit_len >= ESCAPE_BIN {
+        (ESCAPE_BIN, v)
+    } else if bit_len <= 1 {
+        (bit_len, 0)
+    } else {
+        (bit_len, v & ((1 << (bit_len - 1)) - 1))
+    }
+}
+
+fn unbin(bin: u64, extra: u64) -> u64 {
+    match bin {
+        ESCAPE_BIN => extra,
+        0 | 1 => bin,
+        _ => (1 << (bin - 1)) | extra,
+    }
+}
+
+fn extra_bits(bin: u64) -> u32 {
+    match bin {
+        ESCAPE_BIN => 64,
+        0 | 1 => 0,
+        _ => bin as u32 - 1,
+    }
+}
+
+/// Packs values most significant bit first, into a buffer big enough for any match so writing
+/// one doesn't allocate
+#[derive(Default)]
+struct BitPacker {
+    bytes: [u8; MAX_BINNED_LEN],
+    bit_len: usize,
+}
+
+impl BitPacker {
+    fn push(&mut self, value: u64, count: u32) {
+        for shift in (0..count).rev() {
+            let bit = ((value >> shift) & 1) as u8;
+            self.bytes[self.bit_len / 8] |= bit << (7 - self.bit_len % 8);
+            self.bit_len += 1;
         }
     }
 
-    pub fn range_end(&self) -> usize {
-        (self.offset + self.len) as usize
+    fn as_bytes(&self) -> &[u8] {
+        &self.bytes[..self.bit_len.div_ceil(8)]
+    }
+}
+
+struct BitUnpacker<'a> {
+    bytes: &'a [u8],
+    /// In bits
+    position: usize,
+}
+
+impl<'a> BitUnpacker<'a> {
+    fn take(&mut self, count: u32) -> u64 {
+        let mut value = 0;
+        for _ in 0..count {
+            let bit = (self.bytes[self.position / 8] >> (7 - self.position % 8)) & 1;
+            value = (value << 1) | bit as u64;
+            self.position += 1;
+        }
+        value
     }
 }
 
+#[cfg(test)]
 mod test {
-    use super::OffsetLen;
+    use super::{write_binned, MatchLen, OffsetLen, WindowPos};
+
+    #[test]
+    fn binned_round_trip() {
+        let values = [
+            0,
+            1,
+            2,
+            3,
+            4,
+            255,
+            256,
+            999,
+            1 << 30,
+            (1 << 31) - 1,
+            u64::MAX,
+        ];
+        for offset in values {
+            for len in values {
+                let mut bytes = Vec::new();
+                let written = write_binned(offset, len, &mut bytes).unwrap();
+                assert_eq!(written, bytes.len());
+                assert_eq!(Some(bytes.len()), OffsetLen::binned_len(&bytes[..2]));
+                assert_eq!((offset, len), OffsetLen::read_binned(&bytes));
+                if let Some(a) = OffsetLen::checked(offset, len, WindowPos::MAX_WINDOW_LEN) {
+                    let mut written = Vec::new();
+                    a.write_to(&mut written).unwrap();
+                    assert_eq!(bytes, written);
+                    assert_eq!(a.encoded_len(), bytes.len());
+                }
+            }
+        }
+        // A typical match in a 1000 byte window takes 3 bytes rather than 4
+        assert_eq!(3, OffsetLen::of(700, 12).encoded_len());
+    }
+
+    #[test]
+    fn checked_against_window() {
+        assert_eq!(None, WindowPos::new(0, 10));
+        assert_eq!(Some(10), WindowPos::new(10, 10).map(WindowPos::get));
+        assert_eq!(None, WindowPos::new(11, 10));
+        assert_eq!(None, WindowPos::new(u64::MAX, usize::MAX));
+        assert_eq!(Some(0), WindowPos::new(10, 10).unwrap().start(10));
+        assert_eq!(None, WindowPos::new(10, 10).unwrap().start(9));
+        assert_eq!(None, MatchLen::new(1u64 << 32));
+        assert!(OffsetLen::checked(3, 5, 3).is_some());
+        assert!(OffsetLen::checked(4, 5, 3).is_none());
+    }
+
     #[test]
     fn offset_len_round_trip() {
-        let a = OffsetLen::new(5, 10);
-        let bytes = a.to_bytes_new();
+        let a = (5, 10);
+        let bytes = OffsetLen::whole_bytes(a.0, a.1);
         println!("{:?}: {:?}", a, bytes);
-        let b = OffsetLen::of_bytes_new(&bytes);
+        let b = OffsetLen::read_whole_bytes(&bytes);
         assert_eq!(a, b)
     }
 
     #[test]
     fn two_byte_offset_len_round_trip() {
-        let a = OffsetLen::new(2047, 2047);
-        let bytes = a.to_bytes_new();
+        let a = (2047, 2047);
+        let bytes = OffsetLen::whole_bytes(a.0, a.1);
         println!("{:?}: {:?}", a, bytes);
-        let b = OffsetLen::of_bytes_new(&bytes);
+        let b = OffsetLen::read_whole_bytes(&bytes);
         assert_eq!(a, b)
     }
 
     #[test]
     fn three_byte_offset_len_round_trip() {
-        let a = OffsetLen::new(OffsetLen::SIZES[2], OffsetLen::SIZES[2]);
-        let bytes = a.to_bytes_new();
+        let a = (OffsetLen::SIZES[2], OffsetLen::SIZES[2]);
+        let bytes = OffsetLen::whole_bytes(a.0, a.1);
         println!("{:?}: {:?}", a, bytes);
-        let b = OffsetLen::of_bytes_new(&bytes);
+        let b = OffsetLen::read_whole_bytes(&bytes);
         assert_eq!(a, b)
     }
 
     #[test]
     fn all_bytes_offset_len_round_trip() {
         for size in OffsetLen::SIZES {
-            let a = OffsetLen::new(size, size);
-            let bytes = a.to_bytes_new();
+            let a = (size, size);
+            let bytes = OffsetLen::whole_bytes(a.0, a.1);
             println!("{:?}: {:?}", a, bytes);
-            let b = OffsetLen::of_bytes_new(&bytes);
+            let b = OffsetLen::read_whole_bytes(&bytes);
             assert_eq!(a, b)
         }
     }
@@ -0,0 +1,375 @@
+//! Optimal parsing, used at [crate::MAX_LEVEL]: rather than taking the longest match at each
+//! step, pick the tokens that cost the fewest bits over a whole segment of input. Matches are
+//! found with hash chains (as [crate::MatchStrategy::HashChain] does) for every position first,
+//! then the cheapest way to code the segment is worked out backwards from its end, pricing
+//! literals by their huffman codes
+
+use std::io::Read;
+
+use crate::cost::{cost_in_bits, token_bits};
+use crate::error::Result;
+use crate::huffman::CodeMap;
+use crate::match_finder::{hash, HASH_BITS, HASH_LEN, MAX_CHAIN_LEN};
+use crate::offset_len::OffsetLen;
+use crate::Token;
+
+/// Least input parsed at once. Segments are at least as long as the window, so matching
+/// against the previous segment doesn't cost more than parsing this one
+const SEGMENT_LEN: usize = 1 << 16;
+/// Matches at least this long are always taken, and stop the search for longer ones. Working
+/// out the best way to split them up isn't worth the time
+const NICE_LEN: usize = 256;
+
+/// What each token costs, in bits
+#[derive(Debug, Clone)]
+pub(crate) struct Prices {
+    literal: [u32; 256],
+    /// On top of the literal, for one following anything but another literal: it starts a new
+    /// chunk, so needs a chunk marker and the end of the chunk before it to be padded out
+    chunk_start: u32,
+}
+
+impl Prices {
+    /// Literals written as they are, for [crate::Algorithm::Lz]
+    pub fn stored() -> Self {
+        Self {
+            literal: [8; 256],
+            chunk_start: 8,
+        }
+    }
+
+    /// Literals huffman coded with [code_map], as [crate::Algorithm::LzHuffman] does. Bytes
+    /// without a code can't appear in the input, so are never picked
+    pub fn from_code_map(code_map: &CodeMap) -> Self {
+        let literal = std::array::from_fn(|b| cost_in_bits(&Token::Literal(b as u8), code_map));
+        Self {
+            literal,
+            // The END code, then on average half a byte of padding
+            chunk_start: 8 + code_map.end_code().len() as u32 + 4,
+        }
+    }
+
+    fn offset_len(offset_len: OffsetLen) -> u64 {
+        token_bits(&Token::Match(offset_len), None) as u64
+    }
+
+    fn run(value: u8, len: usize) -> u64 {
+        token_bits(&Token::Run(value, len as u64), None) as u64
+    }
+}
+
+/// How a position is best coded
+#[derive(Debug, Clone, Copy)]
+enum Step {
+    Literal,
+    Match { offset: usize, len: usize },
+    Run { len: usize },
+}
+
+impl Step {
+    fn len(self) -> usize {
+        match self {
+            Self::Literal => 1,
+            Self::Match { len, .. } | Self::Run { len } => len,
+        }
+    }
+}
+
+/// Stands in for [crate::encode::tokenise] at [crate::MAX_LEVEL], taking the same options
+pub(crate) struct OptimalParser {
+    window_size: usize,
+    min_match: usize,
+    max_match_len: usize,
+    rle_min_run: Option<usize>,
+    prices: Prices,
+}
+
+impl OptimalParser {
+    pub fn new(
+        window_size: usize,
+        min_match: usize,
+        max_match_len: usize,
+        rle_min_run: Option<usize>,
+        prices: Prices,
+    ) -> Self {
+        Self {
+            window_size,
+            min_match,
+            max_match_len,
+            rle_min_run,
+            prices,
+        }
+    }
+
+    /// Read everything from [input_reader], passing the tokens it's coded as to [emit]. Matches
+    /// can start in [primer], as with [crate::encode::tokenise]
+    pub fn tokenise<R: Read>(
+        &self,
+        mut input_reader: R,
+        primer: &[u8],
+        mut emit: impl FnMut(&Token) -> Result<()>,
+    ) -> Result<()> {
+        let segment_len = SEGMENT_LEN.max(self.window_size);
+        let mut data = primer[primer.len().saturating_sub(self.window_size)..].to_vec();
+        // Whether the last token was a literal, carried between segments
+        let mut after_literal = false;
+        loop {
+            let history_len = data.len();
+            (&mut input_reader)
+                .take(segment_len as u64)
+                .read_to_end(&mut data)?;
+            if data.len() == history_len {
+                return Ok(());
+            }
+            let steps = self.parse(&data, history_len, after_literal);
+            let mut position = history_len;
+            for step in steps {
+                let value = match step {
+                    Step::Literal => Token::Literal(data[position]),
+                    Step::Match { offset, len } => {
+                        let matched_bytes = data[position..position + len].to_vec();
+                        let offset_len = self.offset_len(offset, len);
+                        Token::Match(OffsetLen::new_with_match(
+                            offset_len.offset(),
+                            offset_len.len,
+                            Some(matched_bytes),
+                        ))
+                    }
+                    Step::Run { len } => Token::Run(data[position], len as u64),
+                };
+                emit(&value)?;
+                after_literal = matches!(step, Step::Literal);
+                position += step.len();
+            }
+            data.drain(..data.len().saturating_sub(self.window_size));
+        }
+    }
+
+    /// The match [offset] back and [len] long. Matches are only found within the window, and
+    /// no longer than the longest allowed, so always fit
+    fn offset_len(&self, offset: usize, len: usize) -> OffsetLen {
+        OffsetLen::checked(offset, len, self.window_size)
+            .expect("BUG: Found a match that doesn't fit in the window")
+    }
+
+    /// The cheapest steps coding [data] from [start] on, the bytes before it being those
+    /// already coded
+    fn parse(&self, data: &[u8], start: usize, after_literal: bool) -> Vec<Step> {
+        let matches = self.find_matches(data, start);
+        let runs = self.find_runs(&data[start..]);
+        let len = data.len() - start;
+        // By position in the segment, and whether the token before was a literal: the fewest
+        // bits the rest of the segment can be coded in, and the step that starts doing so
+        let mut cost = vec![[0u64; 2]; len + 1];
+        let mut best = vec![[Step::Literal; 2]; len];
+        for i in (0..len).rev() {
+            for after_literal in [false, true] {
+                let literal_price = self.prices.literal[data[start + i] as usize] as u64
+                    + match after_literal {
+                        true => 0,
+                        false => self.prices.chunk_start as u64,
+                    };
+                let mut choice = (literal_price + cost[i + 1][1], Step::Literal);
+                let (offsets, forced) = &matches[i];
+                if let Some(run_len) = runs[i] {
+                    let price = Prices::run(data[start + i], run_len) + cost[i + run_len][0];
+                    choice = (price, Step::Run { len: run_len });
+                } else if *forced {
+                    let (offset, len) = *offsets.last().unwrap();
+                    let price = Prices::offset_len(self.offset_len(offset, len)) + cost[i + len][0];
+                    choice = (price, Step::Match { offset, len });
+                } else {
+                    // Each offset covers the lengths past the one before it, as it's the
+                    // nearest reaching that far
+                    let mut shortest = self.min_match;
+                    for (offset, longest) in offsets {
+                        for len in shortest..=*longest {
+                            let price = Prices::offset_len(self.offset_len(*offset, len))
+                                + cost[i + len][0];
+                            if price < choice.0 {
+                                choice = (
+                                    price,
+                                    Step::Match {
+                                        offset: *offset,
+                                        len,
+                                    },
+                                );
+                            }
+                        }
+                        shortest = shortest.max(longest + 1);
+                    }
+                }
+                cost[i][after_literal as usize] = choice.0;
+                best[i][after_literal as usize] = choice.1;
+            }
+        }
+
+        let mut steps = Vec::new();
+        let mut i = 0;
+        let mut after_literal = after_literal;
+        while i < len {
+            let step = best[i][after_literal as usize];
+            steps.push(step);
+            after_literal = matches!(step, Step::Literal);
+            i += step.len();
+        }
+        steps
+    }
+
+    /// For each position in [data] from [start] on: the nearest offset reaching each longer
+    /// match, shortest first, and whether the longest is long enough to be taken outright
+    fn find_matches(&self, data: &[u8], start: usize) -> Vec<(Vec<(usize, usize)>, bool)> {
+        let hash_at = |i: usize| {
+            let bytes = data.get(i..i + HASH_LEN)?;
+            Some(hash(bytes.try_into().unwrap()))
+        };
+        // The most recent position each hash was seen at, and by position the one before that
+        let mut head: Vec<Option<usize>> = vec![None; 1 << HASH_BITS];
+        let mut prev: Vec<Option<usize>> = vec![None; data.len()];
+        let insert = |i: usize, head: &mut Vec<Option<usize>>, prev: &mut Vec<Option<usize>>| {
+            if let Some(hash) = hash_at(i) {
+                prev[i] = head[hash].replace(i);
+            }
+        };
+        for i in start.saturating_sub(self.window_size)..start {
+            insert(i, &mut head, &mut prev);
+        }
+
+        let mut matches = Vec::with_capacity(data.len() - start);
+        // The long match the last position was covered by, which carries on from here
+        let mut carried: Option<(usize, usize)> = None;
+        for position in start..data.len() {
+            let max_len = self.max_match_len.min(data.len() - position);
+            let found = match carried {
+                Some((offset, len)) if len > NICE_LEN => (vec![(offset, len - 1)], true),
+                _ => {
+                    let mut offsets: Vec<(usize, usize)> = Vec::new();
+                    let mut candidate = hash_at(position).and_then(|hash| head[hash]);
+                    for _ in 0..MAX_CHAIN_LEN {
+                        let Some(earlier) = candidate else {
+                            break;
+                        };
+                        if position - earlier > self.window_size {
+                            break;
+                        }
+                        let len = (0..max_len)
+                            .take_while(|i| data[earlier + i] == data[position + i])
+                            .count();
+                        if len >= self.min_match && offsets.last().is_none_or(|(_, l)| len > *l) {
+                            offsets.push((position - earlier, len));
+                            if len >= NICE_LEN {
+                                break;
+                            }
+                        }
+                        candidate = prev[earlier];
+                    }
+                    let forced = offsets.last().is_some_and(|(_, len)| *len >= NICE_LEN);
+                    (offsets, forced)
+                }
+            };
+            carried = found.1.then(|| *found.0.last().unwrap());
+            matches.push(found);
+            insert(position, &mut head, &mut prev);
+        }
+        matches
+    }
+
+    /// For each position in [segment], the run starting there if it's long enough to code
+    /// as one
+    fn find_runs(&self, segment: &[u8]) -> Vec<Option<usize>> {
+        let Some(min_run) = self.rle_min_run else {
+            return vec![None; segment.len()];
+        };
+        let mut lens = vec![0; segment.len()];
+        for i in (0..segment.len()).rev() {
+            lens[i] = match segment.get(i + 1) {
+                Some(next) if *next == segment[i] && lens[i + 1] < self.max_match_len => {
+                    lens[i + 1] + 1
+                }
+                _ => 1,
+            };
+        }
+        lens.into_iter()
+            .map(|len| (len >= min_run).then_some(len))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::huffman::{build_tree, tree_to_code_map, ByteStats, Symbol};
+    use crate::optimal_parse::{OptimalParser, Prices};
+    use crate::Token;
+
+    fn input() -> Vec<u8> {
+        let words = ["gecko", "skink", "iguana", "anole", "monitor", " ", "\n"];
+        (0..5000u32)
+            .map(|i| words[(i.wrapping_mul(2654435761) >> 9) as usize % words.len()])
+            .collect::<String>()
+            .into_bytes()
+    }
+
+    /// What [tokens] decode to, starting from [primer], checking they stay in a window of 300
+    fn decode(primer: &[u8], tokens: &[Token]) -> Vec<u8> {
+        let mut output = primer.to_vec();
+        for token in tokens {
+            match token {
+                Token::Literal(b) => output.push(*b),
+                Token::Run(b, len) => output.extend(std::iter::repeat_n(*b, *len as usize)),
+                Token::Match(offset_len) => {
+                    let start = offset_len.offset().start(output.len()).unwrap();
+                    assert!(output.len() - start <= 300, "outside the window");
+                    for i in 0..offset_len.len.get() {
+                        output.push(output[start + i]);
+                    }
+                }
+                token => panic!("Not a token the parser makes: {:?}", token),
+            }
+        }
+        output.split_off(primer.len())
+    }
+
+    fn tokenise(parser: &OptimalParser, input: &[u8], primer: &[u8]) -> Vec<Token> {
+        let mut tokens = Vec::new();
+        parser
+            .tokenise(input, primer, |token| {
+                tokens.push(token.clone());
+                Ok(())
+            })
+            .unwrap();
+        tokens
+    }
+
+    #[test]
+    fn round_trip() {
+        let input = input();
+        let mut stats = ByteStats::new();
+        for b in &input {
+            *stats.entry(Symbol::from(*b)).or_insert(0) += 1;
+        }
+        let code_map = tree_to_code_map(&build_tree(stats));
+        let mut runs = input.clone();
+        runs.extend([b'x'; 3000]);
+        for (prices, rle_min_run) in [
+            (Prices::stored(), None),
+            (Prices::from_code_map(&code_map), None),
+            (Prices::stored(), Some(8)),
+        ] {
+            let parser = OptimalParser::new(300, 4, 100, rle_min_run, prices);
+            for input in [&input[..], &runs[..], b"", b"a"] {
+                let tokens = tokenise(&parser, input, b"");
+                assert_eq!(decode(b"", &tokens), input);
+                for token in &tokens {
+                    if let Token::Match(offset_len) = token {
+                        assert!((4..=100).contains(&offset_len.len.get()));
+                    }
+                }
+            }
+        }
+        // Matches can start in the primer
+        let parser = OptimalParser::new(300, 4, 100, None, Prices::stored());
+        let tokens = tokenise(&parser, b"skink gecko", b"gecko skink ");
+        assert_eq!(decode(b"gecko skink ", &tokens), b"skink gecko");
+        assert!(tokens.len() < 4);
+    }
+}
@@ -0,0 +1,230 @@
+//! Writing output files. With [crate::EncodeOptions::write_behind] or
+//! [crate::DecodeOptions::write_behind], the writes are handed to a thread of their own through a
+//! bounded channel a block at a time, so the disk is written while the encoder packs the next
+//! block or the decoder decodes it. Otherwise the output goes through a plain [BufWriter]
+
+use std::io::{BufWriter, ErrorKind, Write};
+use std::sync::mpsc::{sync_channel, SyncSender};
+use std::thread::JoinHandle;
+
+use crate::encode::Restartable;
+
+/// Bytes handed to the write behind thread at a time
+const WRITE_BEHIND_BLOCK_LEN: usize = 256 * 1024;
+
+/// [writer], buffered, or written on a thread of its own holding up to [write_behind] bytes
+/// that haven't been written yet if that's given
+pub(crate) fn output_writer<W: Write + Send + 'static>(
+    writer: W,
+    write_behind: Option<usize>,
+) -> std::io::Result<Box<dyn Write + Send>> {
+    Ok(match write_behind {
+        Some(write_behind) => Box::new(WriteBehind::new(writer, write_behind)?),
+        None => Box::new(BufWriter::new(writer)),
+    })
+}
+
+/// Something to do with the writer once everything sent before it is written, and where to send
+/// how it went
+type Call<W> = (
+    Box<dyn FnOnce(&mut W) -> std::io::Result<()> + Send>,
+    SyncSender<std::io::Result<()>>,
+);
+
+/// What the write behind thread is sent
+enum Command<W> {
+    Write(Vec<u8>),
+    Call(Call<W>),
+}
+
+/// The write behind thread, and how to send it commands
+type Thread<W> = (SyncSender<Command<W>>, JoinHandle<std::io::Result<W>>);
+
+/// Writes to [writer] on another thread, holding up to [depth] blocks that haven't been written
+/// yet. Writing only fails once the thread has, so an error can be reported by a later write or
+/// flush than the one whose bytes couldn't be written. As with [BufWriter], errors writing the
+/// last of the output on drop are lost, so flush or [WriteBehind::finish] first
+pub(crate) struct WriteBehind<W: Write + Send + 'static> {
+    /// None once finished, or the thread has stopped
+    thread: Option<Thread<W>>,
+    /// Written but not yet sent
+    buffer: Vec<u8>,
+}
+
+impl<W: Write + Send + 'static> WriteBehind<W> {
+    pub fn new(mut writer: W, write_behind: usize) -> std::io::Result<Self> {
+        let depth = (write_behind / WRITE_BEHIND_BLOCK_LEN).max(1);
+        let (sender, receiver) = sync_channel::<Command<W>>(depth);
+        let thread = std::thread::Builder::new()
+            .name(String::from("lizards-write-behind"))
+            .spawn(move || {
+                for command in receiver {
+                    match command {
+                        // Dropping the receiver fails whatever's sent next
+                        Command::Write(block) => writer.write_all(&block)?,
+                        Command::Call((call, reply)) => {
+                            // The other end only goes away if it panicked
+                            let _ = reply.send(call(&mut writer));
+                        }
+                    }
+                }
+                Ok(writer)
+            })?;
+        Ok(Self {
+            thread: Some((sender, thread)),
+            buffer: Vec::with_capacity(WRITE_BEHIND_BLOCK_LEN),
+        })
+    }
+
+    /// Why the thread stopped, once something sent to it has failed
+    fn stopped(&mut self) -> std::io::Error {
+        match self.thread.take() {
+            Some((sender, thread)) => {
+                drop(sender);
+                match thread
+                    .join()
+                    .expect("The write behind thread doesn't panic")
+                {
+                    Err(e) => e,
+                    Ok(_) => unreachable!("The thread only stops early on an error"),
+                }
+            }
+            None => std::io::Error::new(
+                ErrorKind::BrokenPipe,
+                "Writing stopped after an earlier error",
+            ),
+        }
+    }
+
+    fn send(&mut self, command: Command<W>) -> std::io::Result<()> {
+        let Some((sender, _)) = &self.thread else {
+            return Err(self.stopped());
+        };
+        sender.send(command).map_err(|_| self.stopped())
+    }
+
+    /// Send what's buffered on to be written
+    fn send_buffer(&mut self) -> std::io::Result<()> {
+        if self.buffer.is_empty() {
+            return Ok(());
+        }
+        let block = std::mem::replace(&mut self.buffer, Vec::with_capacity(WRITE_BEHIND_BLOCK_LEN));
+        self.send(Command::Write(block))
+    }
+
+    /// Run [call] on the writer once everything sent so far is written
+    fn call(
+        &mut self,
+        call: impl FnOnce(&mut W) -> std::io::Result<()> + Send + 'static,
+    ) -> std::io::Result<()> {
+        let (reply, result) = sync_channel(1);
+        self.send(Command::Call((Box::new(call), reply)))?;
+        // No reply when the thread failed writing something sent before
+        result.recv().map_err(|_| self.stopped())?
+    }
+
+    /// Write and flush everything, giving back the writer
+    pub fn finish(mut self) -> std::io::Result<W> {
+        self.flush()?;
+        let (sender, thread) = self.thread.take().ok_or_else(|| self.stopped())?;
+        drop(sender);
+        thread
+            .join()
+            .expect("The write behind thread doesn't panic")
+    }
+}
+
+impl<W: Write + Send + 'static> Write for WriteBehind<W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let len = buf.len().min(WRITE_BEHIND_BLOCK_LEN - self.buffer.len());
+        self.buffer.extend_from_slice(&buf[..len]);
+        if self.buffer.len() == WRITE_BEHIND_BLOCK_LEN {
+            self.send_buffer()?;
+        }
+        Ok(len)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.send_buffer()?;
+        self.call(|writer| writer.flush())
+    }
+}
+
+impl<W: Restartable + Send + 'static> Restartable for WriteBehind<W> {
+    fn restart(&mut self) -> std::io::Result<()> {
+        self.buffer.clear();
+        self.call(|writer| writer.restart())
+    }
+}
+
+impl<W: Write + Send + 'static> Drop for WriteBehind<W> {
+    fn drop(&mut self) {
+        if self.thread.is_some() {
+            let _ = self.send_buffer();
+        }
+        if let Some((sender, thread)) = self.thread.take() {
+            drop(sender);
+            let _ = thread.join();
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::Write;
+
+    use crate::encode::Restartable;
+    use crate::output::{output_writer, WriteBehind};
+
+    /// Fails writes once it's been given [limit] bytes
+    struct Full {
+        written: Vec<u8>,
+        limit: usize,
+    }
+
+    impl Write for Full {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            if self.written.len() + buf.len() > self.limit {
+                return Err(std::io::Error::other("full"));
+            }
+            self.written.extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn writes_behind() {
+        let data: Vec<u8> = (0..1_100_003u32).map(|i| (i * 7 % 251) as u8).collect();
+        for write_behind in [0, 1 << 20] {
+            let mut writer = WriteBehind::new(Vec::new(), write_behind).unwrap();
+            for chunk in data.chunks(70_001) {
+                writer.write_all(chunk).unwrap();
+            }
+            assert_eq!(data, writer.finish().unwrap());
+
+            // Starting again throws away what was written and what's still to be
+            let mut writer = WriteBehind::new(Vec::new(), write_behind).unwrap();
+            writer.write_all(&data).unwrap();
+            writer.restart().unwrap();
+            writer.write_all(b"again").unwrap();
+            assert_eq!(b"again".to_vec(), writer.finish().unwrap());
+        }
+
+        // A failed write is reported by a later write or flush
+        let full = Full {
+            written: Vec::new(),
+            limit: 1000,
+        };
+        let mut writer = output_writer(full, Some(0)).unwrap();
+        let failed = data
+            .chunks(70_001)
+            .try_for_each(|chunk| writer.write_all(chunk))
+            .and_then(|_| writer.flush());
+        assert_eq!("full", failed.unwrap_err().to_string());
+        assert!(writer.flush().is_err());
+    }
+}
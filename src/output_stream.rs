@@ -1,126 +1,575 @@
-use std::fs::File;
-use std::io::{BufWriter, Write};
+use serde::Serialize;
+use std::io::{BufWriter, Result, Write};
 
+use crate::bit_io::BitWriter;
+use crate::control::{ControlRecord, Trailer};
+use crate::entropy::EntropyCoder;
 use crate::header::Header;
-use crate::huffman::CodeMap;
-use crate::{ChunkMarker, EncodedValue};
+use crate::huffman::{Bits, CodeMap};
+use crate::token::{ChunkMarker, Token};
+use crate::MAX_LITERAL_RUN_LEN;
 
-pub struct OutputStream<W: Write> {
-    buf: Vec<u8>,
+/// What an [OutputStream] wrote, as counted while writing it
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+pub struct StreamStats {
+    /// Bytes written as literals, before they were packed
+    pub literal_bytes: u64,
+    /// Bytes covered by matches
+    pub matched_bytes: u64,
+    /// Chunks of literals, each costing a byte for its marker
+    pub chunks: u64,
+    /// Bits finishing the last byte of each run of huffman coded literals, and of a bitstream
+    /// after its END code, which don't code anything. Not counted for rANS, whose runs end in
+    /// its final state rather than part of a byte
+    pub padding_bits: u64,
+}
+
+impl std::ops::AddAssign for StreamStats {
+    fn add_assign(&mut self, other: Self) {
+        self.literal_bytes += other.literal_bytes;
+        self.matched_bytes += other.matched_bytes;
+        self.chunks += other.chunks;
+        self.padding_bits += other.padding_bits;
+    }
+}
+
+pub struct OutputStream<'a, W: Write> {
+    /// Packs the literals since the last token which wasn't one. Whole chunks are written out
+    /// as they fill, so it never holds more than one chunk's worth
+    literals: Option<BitWriter<Vec<u8>>>,
     output: BufWriter<W>,
-    debug_output: Option<BufWriter<File>>,
-    code_map: CodeMap,
+    /// Gets a [crate::trace] of the tokens written to [output]
+    trace: Option<Box<dyn Write + 'a>>,
+    /// Literals are written as they are without one
+    code_map: Option<CodeMap>,
+    /// Codes each run of literals as a whole, in place of [code_map]. For coders like rANS,
+    /// which can't start until they have the whole run
+    run_coder: Option<Box<dyn EntropyCoder + 'a>>,
+    /// The literals since the last token which wasn't one, for [run_coder]
+    run: Vec<u8>,
+    /// How many literals there have been since the last token which wasn't one
+    run_len: usize,
+    /// Runs of literals are ended with a block boundary once they're this long, see
+    /// [OutputStream::with_max_literal_run]
+    max_literal_run: usize,
+    /// Bytes written to [output] so far
+    written: u64,
+    /// Where in [output] the current block started, see [end_block]
+    block_start: u64,
+    stats: StreamStats,
 }
 
-impl<W: Write> OutputStream<W> {
+impl<'a, W: Write> OutputStream<'a, W> {
+    /// Literals are packed with [code_map], which needn't come from the input: a static table
+    /// from [CodeMap::from_lengths] works as well, so long as the header carries the matching
+    /// [crate::HuffmanTree::from_code_map]
     pub fn new(
-        code_map: CodeMap,
+        code_map: Option<CodeMap>,
         output: BufWriter<W>,
-        debug_output: Option<BufWriter<File>>,
+        trace: Option<Box<dyn Write + 'a>>,
     ) -> Self {
         Self {
-            buf: Vec::new(),
+            literals: None,
             output,
-            debug_output,
+            trace,
             code_map,
+            run_coder: None,
+            run: Vec::new(),
+            run_len: 0,
+            max_literal_run: MAX_LITERAL_RUN_LEN,
+            written: 0,
+            block_start: 0,
+            stats: StreamStats::default(),
         }
     }
 
-    fn end_chunk(&mut self) {
-        let bytes = crate::huffman::pack_to_u8(&self.code_map, self.buf.iter().map(|x| *x));
-        //split into chunks of max size the size we can fit into one chunk marker
-        for chunk in bytes.chunks(ChunkMarker::MAX_VALUE) {
-            let chunk_marker = ChunkMarker {
-                len: chunk.len() as u8,
-            };
-            self.output.write(&[chunk_marker.to_u8()]);
-            self.output.write_all(chunk);
-            if let Some(writer) = &mut self.debug_output {
-                writer.write_all(&chunk_marker.to_debug_bytes());
-                //TODO: Writing buf here is a lie if there are >1 chunks as buf is everything
-                // This is hard to do because we don't know how many actual bytes we've fitted into
-                // the chunks. Solution would be to make [huffman:pack_to_u8] give us chunks with
-                // some char size data
-                let bytes: String = chunk
-                    .iter()
-                    .map(|x| format!("{:08b}", x))
-                    .collect::<Vec<String>>()
-                    .join("");
-                writer.write_all(&bytes.into_bytes());
-                writer.write_all(&self.buf);
-            }
+    pub fn with_run_coder(mut self, run_coder: Box<dyn EntropyCoder + 'a>) -> Self {
+        self.run_coder = Some(run_coder);
+        self
+    }
+
+    /// End runs of literals after [max_literal_run] of them rather than [MAX_LITERAL_RUN_LEN],
+    /// so they go out sooner and fewer are held waiting, e.g. with a run coder, which holds the
+    /// whole run. Each run ended costs a 2 byte block boundary
+    pub fn with_max_literal_run(mut self, max_literal_run: usize) -> Self {
+        self.max_literal_run = max_literal_run.clamp(1, MAX_LITERAL_RUN_LEN);
+        self
+    }
+
+    fn write_output(&mut self, bytes: &[u8]) -> Result<()> {
+        self.output.write_all(bytes)?;
+        self.written += bytes.len() as u64;
+        Ok(())
+    }
+
+    fn write_chunk(&mut self, chunk: &[u8]) -> Result<()> {
+        let chunk_marker = ChunkMarker {
+            len: chunk.len() as u8,
+        };
+        self.output.write_all(&[chunk_marker.to_u8()])?;
+        self.output.write_all(chunk)?;
+        self.written += 1 + chunk.len() as u64;
+        self.stats.chunks += 1;
+        Ok(())
+    }
+
+    fn trace(&mut self, token: &Token) -> Result<()> {
+        match &mut self.trace {
+            Some(writer) => crate::trace::write_token(writer, token),
+            None => Ok(()),
+        }
+    }
+
+    fn add_literal(&mut self, value: u8) -> Result<()> {
+        if self.run_len == self.max_literal_run {
+            self.write_control(&ControlRecord::BlockBoundary)?;
+        }
+        self.run_len += 1;
+        self.stats.literal_bytes += 1;
+        self.trace(&Token::Literal(value))?;
+        if self.run_coder.is_some() {
+            self.run.push(value);
+            return Ok(());
+        }
+        let literals = self
+            .literals
+            .get_or_insert_with(|| BitWriter::new(Vec::with_capacity(ChunkMarker::MAX_VALUE)));
+        let bits = match &self.code_map {
+            Some(code_map) => code_map.code_for(value).unwrap().clone(),
+            None => Bits::from((value, 8)),
+        };
+        // Writing to a Vec can't fail
+        literals.write_bits(&bits).unwrap();
+        // Chunks run on into each other, so a full one can go out before the rest are packed
+        if literals.get_ref().len() >= ChunkMarker::MAX_VALUE {
+            let chunk: Vec<u8> = literals.get_mut().drain(..ChunkMarker::MAX_VALUE).collect();
+            self.write_chunk(&chunk)?;
         }
-        self.buf.clear();
+        Ok(())
     }
 
-    pub fn write_header(&mut self, header: &Header) {
-        self.output.write_all(&header.to_bytes());
-        if let Some(writer) = &mut self.debug_output {
-            writer.write_all(&header.to_debug_bytes());
+    /// Write out any literals still waiting, finishing their last byte with the start of the END
+    /// code so the decoder stops there
+    fn end_chunk(&mut self) -> Result<()> {
+        self.run_len = 0;
+        if let Some(run_coder) = &self.run_coder {
+            if !self.run.is_empty() {
+                let encoded = run_coder.encode(&self.run);
+                self.run.clear();
+                for chunk in encoded.chunks(ChunkMarker::MAX_VALUE) {
+                    self.write_chunk(chunk)?;
+                }
+            }
+            return Ok(());
         }
+        let Some(literals) = self.literals.take() else {
+            return Ok(());
+        };
+        let chunk = match &self.code_map {
+            Some(code_map) => {
+                self.stats.padding_bits += ((8 - literals.pending_bits()) % 8) as u64;
+                literals.finish_with(code_map.end_code())
+            }
+            None => literals.into_inner(),
+        }?;
+        if !chunk.is_empty() {
+            self.write_chunk(&chunk)?;
+        }
+        Ok(())
     }
 
-    pub fn add(&mut self, value: &EncodedValue) {
+    pub fn write_header(&mut self, header: &Header) -> Result<()> {
+        self.write_output(&header.to_bytes())?;
+        self.block_start = self.written;
+        Ok(())
+    }
+
+    /// Add [value] to the stream. [Token::EndOfStream] finishes it, as [finalise] does
+    pub fn add(&mut self, value: &Token) -> Result<()> {
         match value {
-            EncodedValue::RawU8(v) => {
-                self.buf.push(*v);
+            Token::Literal(v) => self.add_literal(*v),
+            Token::Run(value, len) => self.write_control(&ControlRecord::Run {
+                value: *value,
+                len: *len,
+            }),
+            Token::Match(offset_len) => {
+                self.end_chunk()?;
+                self.stats.matched_bytes += offset_len.len.get() as u64;
+                self.written += value.write_to(&mut self.output)? as u64;
+                self.trace(value)
             }
-            EncodedValue::OffsetLen(offset_len) => {
-                if !self.buf.is_empty() {
-                    self.end_chunk()
-                }
-                self.output.write_all(&offset_len.to_bytes_new()).unwrap();
-                if let Some(writer) = &mut self.debug_output {
-                    writer.write_all(&offset_len.to_bytes_debug());
-                }
+            Token::EndOfStream(trailer) => self.finalise(*trailer).map(|_| ()),
+            Token::Control(record) => self.write_control(record),
+        }
+    }
+
+    /// Huffman code all of [input] as a single bitstream, with no chunk markers. It ends with
+    /// the END code, so nothing else can be added before a control record. Traced as literals
+    pub fn add_bitstream<I: Iterator<Item = u8>>(&mut self, input: I) -> Result<()> {
+        let code_map = self
+            .code_map
+            .as_ref()
+            .expect("A bitstream needs a code map");
+        let trace = &mut self.trace;
+        let stats = &mut self.stats;
+        let mut code_bits = code_map.end_code().len() as u64;
+        // Hold on to the first trace error, as the bitstream is packed from an iterator
+        let mut trace_error = None;
+        let input = input.inspect(|v| {
+            stats.literal_bytes += 1;
+            code_bits += code_map.code_for(*v).unwrap().len() as u64;
+            if let (Some(writer), None) = (&mut *trace, &trace_error) {
+                trace_error = crate::trace::write_token(writer, &Token::Literal(*v)).err();
             }
+        });
+        let bytes = crate::huffman::pack_stream(code_map, input);
+        if let Some(e) = trace_error {
+            return Err(e);
+        }
+        self.stats.padding_bits += bytes.len() as u64 * 8 - code_bits;
+        self.write_output(&bytes)
+    }
+
+    /// Huffman code [block] with the current tree, and write it as a control record
+    #[cfg(feature = "bwt")]
+    pub fn add_bwt_block(&mut self, block: &crate::bwt::Block) -> Result<()> {
+        let code_map = self.code_map.as_ref().expect("BWT blocks need a code map");
+        let packed = crate::huffman::pack_to_u8(code_map, block.data.iter().copied());
+        self.write_control(&ControlRecord::BwtBlock {
+            primary_index: block.primary_index,
+            packed,
+        })
+    }
+
+    /// Write [record], first ending any run of literals so it doesn't get split up. Literals
+    /// after a new huffman tree are coded with it
+    pub fn write_control(&mut self, record: &ControlRecord) -> Result<()> {
+        self.end_chunk()?;
+        if let ControlRecord::NewHuffmanTree(tree) = record {
+            self.code_map = Some(crate::huffman::tree_to_code_map(tree));
         }
+        self.written += record.write_to(&mut self.output)? as u64;
+        // Only cloned when it's needed, as records can hold a whole tree or BWT block
+        if self.trace.is_some() {
+            self.trace(&Token::from(record.clone()))?;
+        }
+        Ok(())
+    }
+
+    /// End the current block, which decoded to [decoded_len] bytes with CRC32 [checksum], with a
+    /// record describing it. The encoder has to make sure no match reaches back before the
+    /// block, so that it decodes on its own
+    pub fn end_block(&mut self, decoded_len: u64, checksum: u32) -> Result<()> {
+        self.end_chunk()?;
+        self.write_control(&ControlRecord::BlockCheck {
+            checksum,
+            decoded_len,
+            encoded_len: self.written - self.block_start,
+        })?;
+        self.block_start = self.written;
+        Ok(())
     }
-    pub fn finalise(&mut self) {
-        if !self.buf.is_empty() {
-            self.end_chunk()
+
+    /// End the current chunk of literals with a block boundary and flush everything written so
+    /// far, so a decoder on the other end of e.g. a socket can decode all of it without waiting
+    /// for more (like zlib's Z_SYNC_FLUSH). Decoders hold on to a chunk until the next token in
+    /// case it continues, hence the boundary. Can't be used part way through a bitstream
+    #[allow(dead_code)]
+    pub fn flush_block(&mut self) -> Result<()> {
+        self.write_control(&ControlRecord::BlockBoundary)?;
+        self.output.flush()?;
+        if let Some(writer) = &mut self.trace {
+            writer.flush()?;
         }
-        self.output.flush();
-        if let Some(writer) = &mut self.debug_output {
-            writer.flush();
+        Ok(())
+    }
+
+    /// End the stream with [trailer], returning what was written
+    pub fn finalise(&mut self, trailer: Trailer) -> Result<StreamStats> {
+        self.end_chunk()?;
+        self.write_control(&ControlRecord::EndOfStream(trailer))?;
+        self.output.flush()?;
+        if let Some(writer) = &mut self.trace {
+            writer.flush()?;
         }
+        Ok(self.stats.clone())
     }
 }
 
+#[cfg(test)]
 mod test {
+    use std::cell::RefCell;
     use std::collections::HashMap;
     use std::io::{BufWriter, Write};
+    use std::rc::Rc;
 
-    use crate::huffman::{Bits, CodeMap};
-    use crate::output_stream::OutputStream;
-    use crate::{helpers, EncodedValue};
+    use crate::control::{ControlRecord, Trailer};
+    use crate::decode::decode_bytes;
+    use crate::header::Header;
+    use crate::huffman::{Bits, CodeMap, HuffmanTree};
+    use crate::offset_len::OffsetLen;
+    use crate::output_stream::{OutputStream, StreamStats};
+    use crate::{helpers, Token, MAX_LITERAL_RUN_LEN};
+
+    fn code_map() -> CodeMap {
+        let mut codes = HashMap::new();
+        codes.insert(0b00000001, Bits::from((0b00001011, 4)));
+        codes.insert(0b00000010, Bits::from((0b00001001, 4)));
+        let end_code = Bits::from((0b00001111, 4));
+        CodeMap::new(codes, end_code)
+    }
 
     #[test]
     fn expected_output() {
         let mut output_buf = Vec::new();
         {
-            let mut output_writer = BufWriter::new(&mut output_buf);
-            let code_map = {
-                let mut codes = HashMap::new();
-                codes.insert(0b00000001, Bits::from((0b00001011, 4)));
-                codes.insert(0b00000010, Bits::from((0b00001001, 4)));
-                let end_code = Bits::from((0b00001111, 4));
-                CodeMap::new(codes, end_code)
-            };
-            let mut output_stream = OutputStream::new(code_map, output_writer, None);
+            let output_writer = BufWriter::new(&mut output_buf);
+            let mut output_stream = OutputStream::new(Some(code_map()), output_writer, None);
 
             let values: [u8; 4] = [1, 2, 1, 1];
             for value in values.iter() {
-                output_stream.add(&EncodedValue::RawU8(*value));
+                output_stream.add(&Token::Literal(*value)).unwrap();
             }
-            output_stream.finalise();
+            let stats = output_stream
+                .finalise(Trailer {
+                    len: 4,
+                    checksum: 0x01020304,
+                })
+                .unwrap();
+            let expected = StreamStats {
+                literal_bytes: 4,
+                chunks: 1,
+                ..Default::default()
+            };
+            assert_eq!(expected, stats);
         }
         let expected = {
-            //The chunk marker for 3 bytes
-            let chunk_marker: u8 = 0b11000011;
-            let encoded_bit: Vec<u8> = vec![chunk_marker, 0b10111001, 0b10111011, 0b11110000];
+            //The chunk marker for 2 bytes. The codes fill them exactly, so there's no END code
+            let chunk_marker: u8 = 0b11000010;
+            let mut encoded_bit: Vec<u8> = vec![chunk_marker, 0b10111001, 0b10111011];
+            // End of stream, its length, then the trailer's length and checksum
+            encoded_bit.extend([0, 12, 0, 0, 0, 0, 0, 0, 0, 4, 1, 2, 3, 4]);
             helpers::u8_iter_str(encoded_bit.iter())
         };
         assert_eq!(expected, helpers::u8_iter_str(output_buf.iter()));
     }
+
+    #[test]
+    fn trace() {
+        let mut output_buf = Vec::new();
+        let mut trace_buf = Vec::new();
+        {
+            let mut output_stream = OutputStream::new(
+                Some(code_map()),
+                BufWriter::new(&mut output_buf),
+                Some(Box::new(&mut trace_buf)),
+            );
+            output_stream.add(&Token::Literal(1)).unwrap();
+            output_stream.add(&Token::Literal(2)).unwrap();
+            output_stream
+                .add(&Token::Match(OffsetLen::of(2, 3)))
+                .unwrap();
+            let stats = output_stream
+                .finalise(Trailer {
+                    len: 5,
+                    checksum: 0x01020304,
+                })
+                .unwrap();
+            let expected = StreamStats {
+                literal_bytes: 2,
+                matched_bytes: 3,
+                chunks: 1,
+                padding_bits: 0,
+            };
+            assert_eq!(expected, stats);
+        }
+        // The literals, the match and the end record, with no chunk markers
+        let expected = concat!(
+            "{\"Literal\":1}\n",
+            "{\"Literal\":2}\n",
+            "{\"Match\":{\"offset\":2,\"len\":3}}\n",
+            "{\"EndOfStream\":{\"len\":5,\"checksum\":16909060}}\n"
+        );
+        assert_eq!(expected, String::from_utf8(trace_buf).unwrap());
+    }
+
+    /// Lets the test look at what's been written while the stream still owns the writer
+    #[derive(Clone, Default)]
+    struct SharedBuf(Rc<RefCell<Vec<u8>>>);
+
+    impl Write for SharedBuf {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.borrow_mut().write(buf)
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn flush_block() {
+        let written = SharedBuf::default();
+        let mut output_stream =
+            OutputStream::new(Some(code_map()), BufWriter::new(written.clone()), None);
+        for value in [1, 2, 1] {
+            output_stream.add(&Token::Literal(value)).unwrap();
+        }
+        assert!(written.0.borrow().is_empty());
+        output_stream.flush_block().unwrap();
+        // The chunk marker for 2 bytes, the packed literals, then a block boundary
+        let expected = vec![0b11000010, 0b10111001, 0b10111111, 2, 0];
+        assert_eq!(expected, *written.0.borrow());
+    }
+
+    #[test]
+    fn stats() {
+        let mut output_stream =
+            OutputStream::new(Some(code_map()), BufWriter::new(std::io::sink()), None);
+        for value in [1, 2, 1] {
+            output_stream.add(&Token::Literal(value)).unwrap();
+        }
+        output_stream
+            .add(&Token::Match(OffsetLen::of(3, 4)))
+            .unwrap();
+        output_stream.add(&Token::Literal(2)).unwrap();
+        output_stream.add(&Token::Run(0, 10)).unwrap();
+        let stats = output_stream
+            .finalise(Trailer {
+                len: 18,
+                checksum: 0x01020304,
+            })
+            .unwrap();
+        // Three 4 bit codes leave half a byte for the start of END, and one leaves 4 bits more
+        let expected = StreamStats {
+            literal_bytes: 4,
+            matched_bytes: 4,
+            chunks: 2,
+            padding_bits: 8,
+        };
+        assert_eq!(expected, stats);
+    }
+
+    #[test]
+    fn failing_writer() {
+        let trailer = Trailer {
+            len: 100,
+            checksum: 0x01020304,
+        };
+        // Buffered, so the output only fails once it's flushed
+        let mut output_stream = OutputStream::new(
+            Some(code_map()),
+            BufWriter::new(helpers::FailingWriter { accept: 10 }),
+            None,
+        );
+        for _ in 0..100 {
+            output_stream.add(&Token::Literal(1)).unwrap();
+        }
+        assert!(output_stream.finalise(trailer).is_err());
+
+        let mut output_stream = OutputStream::new(
+            Some(code_map()),
+            BufWriter::new(std::io::sink()),
+            Some(Box::new(helpers::FailingWriter { accept: 0 })),
+        );
+        assert!(output_stream.add(&Token::Literal(1)).is_err());
+        let bitstream = output_stream.add_bitstream([1, 2, 1].into_iter());
+        assert!(bitstream.is_err());
+    }
+
+    #[test]
+    fn static_table() {
+        // Every byte value gets an 8 bit code but one, which takes 9 bits alongside END
+        let mut lengths = [8u8; 256];
+        lengths[255] = 9;
+        let code_map = CodeMap::from_lengths(&lengths).unwrap();
+        let input = b"any bytes at all, not just ones counted up front";
+        let header = Header::new(Some(HuffmanTree::from_code_map(&code_map)), 0);
+        let mut output_buf = header.to_bytes();
+        {
+            let mut output_stream =
+                OutputStream::new(Some(code_map), BufWriter::new(&mut output_buf), None);
+            for value in input {
+                output_stream.add(&Token::Literal(*value)).unwrap();
+            }
+            output_stream
+                .finalise(Trailer {
+                    len: input.len() as u64,
+                    checksum: crc32fast::hash(input),
+                })
+                .unwrap();
+        }
+        assert_eq!(&input[..], decode_bytes(&output_buf).unwrap());
+    }
+
+    #[test]
+    fn long_literal_runs() {
+        let mut lengths = [8u8; 256];
+        lengths[255] = 9;
+        let code_map = CodeMap::from_lengths(&lengths).unwrap();
+        let input: Vec<u8> = (0..MAX_LITERAL_RUN_LEN * 2 + 5)
+            .map(|i| (i * 7 % 251) as u8)
+            .collect();
+        let header = Header::new(Some(HuffmanTree::from_code_map(&code_map)), 0);
+        let mut output_buf = header.to_bytes();
+        let mut trace_buf = Vec::new();
+        {
+            let mut output_stream = OutputStream::new(
+                Some(code_map),
+                BufWriter::new(&mut output_buf),
+                Some(Box::new(&mut trace_buf)),
+            );
+            for value in &input {
+                output_stream.add(&Token::Literal(*value)).unwrap();
+            }
+            output_stream
+                .finalise(Trailer {
+                    len: input.len() as u64,
+                    checksum: crc32fast::hash(&input),
+                })
+                .unwrap();
+        }
+        // Broken up so the decoder never has to hold more than one capped run
+        let boundary = Token::Control(ControlRecord::BlockBoundary);
+        let tokens = crate::trace::read_trace(&trace_buf[..]);
+        let boundaries = tokens.filter(|token| *token.as_ref().unwrap() == boundary);
+        assert_eq!(2, boundaries.count());
+        assert_eq!(input, decode_bytes(&output_buf).unwrap());
+    }
+
+    #[test]
+    fn max_literal_run() {
+        let input = b"literals with no match between them, none at all";
+        let mut lengths = [8u8; 256];
+        lengths[255] = 9;
+        let code_map = CodeMap::from_lengths(&lengths).unwrap();
+        let header = Header::new(Some(HuffmanTree::from_code_map(&code_map)), 0);
+        let mut output_buf = header.to_bytes();
+        let written = SharedBuf::default();
+        {
+            let mut output_stream =
+                OutputStream::new(Some(code_map), BufWriter::new(written.clone()), None)
+                    .with_max_literal_run(10);
+            for value in input {
+                output_stream.add(&Token::Literal(*value)).unwrap();
+            }
+            // Everything up to the last boundary has gone out
+            output_stream.output.flush().unwrap();
+            let boundaries = written
+                .0
+                .borrow()
+                .windows(2)
+                .filter(|w| w == &[2, 0])
+                .count();
+            assert_eq!(input.len() / 10, boundaries);
+            output_stream
+                .finalise(Trailer {
+                    len: input.len() as u64,
+                    checksum: crc32fast::hash(input),
+                })
+                .unwrap();
+        }
+        output_buf.extend(written.0.borrow().iter());
+        assert_eq!(&input[..], decode_bytes(&output_buf).unwrap());
+    }
 }
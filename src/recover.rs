@@ -0,0 +1,182 @@
+//! Getting back what can be decoded from a damaged stream, written with
+//! [crate::EncodeOptions::block_size]. Each block is followed by a record holding its length
+//! and checksum, so blocks which still decode to the right bytes are kept and the rest skipped
+
+use log::{info, warn};
+use std::fs::File;
+use std::io::{BufWriter, Read, Write};
+use std::ops::Range;
+
+use crate::blocks::decode_block;
+use crate::control::{ControlRecord, Trailer};
+use crate::decode::{decode_stream, DecodeOptions};
+use crate::error::{Error, Result};
+use crate::file_io::FileInputOutput;
+use crate::filter::Filter;
+use crate::header::Header;
+
+/// What happened during a recovery
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct RecoverSummary {
+    pub input_bytes: u64,
+    pub output_bytes: u64,
+    /// Blocks which decoded correctly
+    pub blocks: u64,
+    /// Ranges of the input which couldn't be decoded, so were skipped
+    pub damaged: Vec<Range<u64>>,
+}
+
+/// Recover what can be from the encoded file into the unencoded one
+pub fn recover(file_io: &FileInputOutput) -> Result<RecoverSummary> {
+    let mut input = Vec::new();
+    File::open(file_io.encoded_filename.as_path())?.read_to_end(&mut input)?;
+    let (output, summary) = recover_bytes(&input)?;
+    let mut writer = BufWriter::new(File::create(file_io.unencoded_filename.as_path())?);
+    writer.write_all(&output)?;
+    writer.flush()?;
+    info!(
+        "Recovered {:?} -> {:?} ({} blocks, {} damaged ranges)",
+        file_io.encoded_filename,
+        file_io.unencoded_filename,
+        summary.blocks,
+        summary.damaged.len()
+    );
+    Ok(summary)
+}
+
+/// Recover what can be from the stream [bytes]. Only the header has to be intact. A stream
+/// without blocks has to decode as a whole, as it would normally
+pub fn recover_bytes(bytes: &[u8]) -> Result<(Vec<u8>, RecoverSummary)> {
+    let header_len = match Header::expected_len(bytes) {
+        Some(header_len) if header_len <= bytes.len() => header_len,
+        _ => return Err(Error::TruncatedStream("header")),
+    };
+    let header = Header::from_bytes(&bytes[..header_len])?;
+    // Blocks are unfiltered together at the end, as the filter runs across them
+    let filter = header.filter();
+    let block_header = header.with_filter(Filter::None).to_bytes();
+
+    let mut output = Vec::new();
+    let mut summary = RecoverSummary {
+        input_bytes: bytes.len() as u64,
+        ..Default::default()
+    };
+    let mut block_start = header_len;
+    let mut position = header_len;
+    // Look everywhere for block checks, as one after some damage points back to where its
+    // block started
+    while position < bytes.len() {
+        match ControlRecord::from_bytes(&bytes[position..]) {
+            Some((
+                ControlRecord::BlockCheck {
+                    checksum,
+                    decoded_len,
+                    encoded_len,
+                },
+                record_len,
+            )) => {
+                let start = usize::try_from(encoded_len)
+                    .ok()
+                    .and_then(|encoded_len| position.checked_sub(encoded_len))
+                    .filter(|start| *start >= block_start);
+                let trailer = Trailer {
+                    len: decoded_len,
+                    checksum,
+                };
+                if let Some(start) = start {
+                    if let Ok(decoded) = decode_block(
+                        &block_header,
+                        &bytes[start..position],
+                        trailer,
+                        &DecodeOptions::default(),
+                    ) {
+                        if start > block_start {
+                            summary.damaged.push(block_start as u64..start as u64);
+                        }
+                        output.extend(decoded);
+                        summary.blocks += 1;
+                        position += record_len;
+                        block_start = position;
+                        continue;
+                    }
+                }
+            }
+            Some((ControlRecord::EndOfStream(_), _)) if position == block_start => {
+                block_start = bytes.len();
+                break;
+            }
+            _ => (),
+        }
+        position += 1;
+    }
+    if summary.blocks == 0 {
+        let (output, _) = decode_stream(&mut &bytes[..], &DecodeOptions::default())?;
+        summary.output_bytes = output.len() as u64;
+        return Ok((output, summary));
+    }
+    if block_start < bytes.len() {
+        summary.damaged.push(block_start as u64..bytes.len() as u64);
+    }
+    for range in &summary.damaged {
+        warn!("Skipped damaged bytes {:?}", range);
+    }
+    filter.unfilter(&mut output);
+    summary.output_bytes = output.len() as u64;
+    Ok((output, summary))
+}
+
+#[cfg(test)]
+mod test {
+    use crate::recover::recover_bytes;
+    use crate::{encode, EncodeOptions};
+
+    fn encode_in_blocks(input: &[u8]) -> Vec<u8> {
+        let options = EncodeOptions::default().block_size(Some(1000));
+        let mut encoded = Vec::new();
+        encode::encode_from_reader(input, &mut encoded, &options).unwrap();
+        encoded
+    }
+
+    #[test]
+    fn intact() {
+        let input = "The cat sat on the mat. ".repeat(200).into_bytes();
+        let encoded = encode_in_blocks(&input);
+        assert_eq!(input, crate::decode::decode_bytes(&encoded).unwrap());
+        let (recovered, summary) = recover_bytes(&encoded).unwrap();
+        assert_eq!(input, recovered);
+        assert_eq!(5, summary.blocks);
+        assert!(summary.damaged.is_empty());
+    }
+
+    #[test]
+    fn skips_damaged_blocks() {
+        // Nearly random, so the blocks are much bigger than the header
+        let mut state = 1u32;
+        let input: Vec<u8> = (0..5000)
+            .map(|_| {
+                state = state.wrapping_mul(1103515245).wrapping_add(12345);
+                b'a' + (state >> 16) as u8 % 16
+            })
+            .collect();
+        let mut encoded = encode_in_blocks(&input);
+        // Somewhere in the middle, well after the header
+        let middle = encoded.len() / 2;
+        for b in &mut encoded[middle..(middle + 4)] {
+            *b ^= 0xff;
+        }
+        assert!(crate::decode::decode_bytes(&encoded).is_err());
+        let (recovered, summary) = recover_bytes(&encoded).unwrap();
+        assert_eq!(1, summary.damaged.len());
+        assert!(summary.damaged[0].contains(&(middle as u64)));
+        // Everything else came back, in whole blocks
+        assert_eq!(4, summary.blocks);
+        assert_eq!(4000, recovered.len());
+        let damaged_block = input
+            .chunks(1000)
+            .position(|block| !recovered.windows(1000).any(|w| w == block))
+            .unwrap();
+        let mut expected = input.clone();
+        expected.drain((damaged_block * 1000)..((damaged_block + 1) * 1000));
+        assert_eq!(expected, recovered);
+    }
+}
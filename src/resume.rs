@@ -0,0 +1,282 @@
+//! Carrying on a decode which was cut short, e.g. by a crash or the storage going away part way
+//! through a big file, rather than starting it again. Streams written with
+//! [crate::EncodeOptions::block_size] record each block's length and checksum, so the blocks the
+//! partly written output already holds are checked against those and kept, and decoding starts
+//! from the first one it doesn't hold whole. Only the first frame's blocks are kept, and streams
+//! which can't be read as blocks are decoded again from the start
+
+use log::{debug, info};
+use std::fs::{File, OpenOptions};
+use std::io::{BufReader, BufWriter, Read, Seek, SeekFrom, Write};
+
+use crate::blocks::{splits_into_blocks, BlockReader};
+use crate::decode::{decode_following_frames, decode_with_header, DecodeOptions};
+use crate::error::{Error, Result};
+use crate::file_io::FileInputOutput;
+use crate::filter::{FilterReader, Unfilter};
+use crate::format::stored;
+use crate::header::{self, Header};
+use crate::helpers::{CountingWriter, TailWriter};
+use crate::throttle::{Throttle, Throttled};
+use crate::volumes::VolumeReader;
+use crate::MAX_PRIMED_LEN;
+
+/// What happened when resuming a decode
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ResumeSummary {
+    pub input_bytes: u64,
+    /// Of the whole output, including what was kept
+    pub output_bytes: u64,
+    /// CRC32 of the whole output
+    pub checksum: u32,
+    /// Blocks the existing output already held, so weren't decoded again
+    pub kept_blocks: u64,
+    pub kept_bytes: u64,
+}
+
+/// Carry on decoding the encoded file into the unencoded one, which may be missing, partly
+/// written or hold something else entirely. Unlike [crate::decode::decode] the output is left
+/// as it is if this fails, so it can be resumed again
+pub fn resume(file_io: &FileInputOutput, options: &DecodeOptions) -> Result<ResumeSummary> {
+    let input = VolumeReader::open(file_io.encoded_filename.as_path())?;
+    let input_bytes = input.len();
+    let mut output = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create(true)
+        .truncate(false)
+        .open(file_io.unencoded_filename.as_path())?;
+    let (mut summary, header) = resume_stream(input, &mut output, options)?;
+    summary.input_bytes = input_bytes;
+    if options.preserve {
+        if let Some(file_metadata) = header.file_metadata() {
+            file_metadata.apply(file_io.unencoded_filename.as_path())?;
+        }
+    }
+    info!(
+        "Resumed {:?} -> {:?} (kept {} blocks, {} -> {} bytes)",
+        file_io.encoded_filename,
+        file_io.unencoded_filename,
+        summary.kept_blocks,
+        summary.input_bytes,
+        summary.output_bytes
+    );
+    Ok(summary)
+}
+
+/// Carry on decoding the stream from [reader] into [output], keeping the blocks [output]
+/// already holds and replacing anything after them. Blocks are decoded one at a time, whatever
+/// [DecodeOptions::threads] says. Returns the first frame's header
+pub fn resume_stream<R: Read>(
+    reader: R,
+    output: &mut File,
+    options: &DecodeOptions,
+) -> Result<(ResumeSummary, Header)> {
+    // Checking what's already there counts against the rate too
+    let throttle = options.throttle();
+    let mut reader = BufReader::new(Throttled::new(reader, throttle.clone()));
+    let mut prefix = Vec::with_capacity(header::FRAME_PREFIX_LEN);
+    (&mut reader)
+        .take(header::FRAME_PREFIX_LEN as u64)
+        .read_to_end(&mut prefix)?;
+    if header::is_encrypted(&prefix) {
+        return start_over(prefix.chain(reader), output, throttle, options);
+    }
+    let header_bytes = Header::read_bytes_from(
+        prefix.as_slice().chain(&mut reader),
+        options.max_header_size,
+    )?;
+    if stored::is_stored(&header_bytes) || !splits_into_blocks(&Header::from_bytes(&header_bytes)?)
+    {
+        return start_over(header_bytes.chain(reader), output, throttle, options);
+    }
+    let header = Header::from_bytes(&header_bytes)?;
+    let mut blocks = BlockReader::new(&mut reader, &header_bytes)?;
+    let mut summary = ResumeSummary::default();
+    // Of the filtered bytes, which is what the trailers check
+    let mut hasher = crc32fast::Hasher::new();
+    let mut unfilter = Unfilter::new(header.filter());
+    // Of the unfiltered bytes kept, which is what's in the output
+    let mut kept_hasher = crc32fast::Hasher::new();
+    let mut kept_tail = TailWriter::new(std::io::sink(), MAX_PRIMED_LEN);
+
+    let existing_len = output.seek(SeekFrom::End(0))?;
+    output.rewind()?;
+    let mut existing = FilterReader::new(
+        BufReader::new(Throttled::new(&mut *output, throttle.clone())),
+        header.filter(),
+    );
+    let mut next = None;
+    while let Some(block) = blocks.next_block()? {
+        let len = block.trailer.len;
+        if summary.kept_bytes + len > existing_len {
+            next = Some(block);
+            break;
+        }
+        let mut bytes = Vec::new();
+        (&mut existing).take(len).read_to_end(&mut bytes)?;
+        if crc32fast::hash(&bytes) != block.trailer.checksum {
+            next = Some(block);
+            break;
+        }
+        hasher.update(&bytes);
+        unfilter.apply(&mut bytes);
+        kept_hasher.update(&bytes);
+        kept_tail.write_all(&bytes)?;
+        summary.kept_blocks += 1;
+        summary.kept_bytes += len;
+    }
+    drop(existing);
+    debug!(
+        "Keeping {} blocks ({} bytes) of the {} bytes already decoded",
+        summary.kept_blocks, summary.kept_bytes, existing_len
+    );
+
+    output.set_len(summary.kept_bytes)?;
+    output.seek(SeekFrom::Start(summary.kept_bytes))?;
+    let mut writer = TailWriter::new(
+        CountingWriter::new(BufWriter::new(Throttled::new(&mut *output, throttle))),
+        MAX_PRIMED_LEN,
+    );
+    writer.remember(&kept_tail.take_tail());
+    let mut len = summary.kept_bytes;
+    while let Some(block) = match next.take() {
+        Some(block) => Some(block),
+        None => blocks.next_block()?,
+    } {
+        let mut decoded = block.decode(options)?;
+        len += decoded.len() as u64;
+        options.check_output_len(len)?;
+        hasher.update(&decoded);
+        unfilter.apply(&mut decoded);
+        writer.write_all(&decoded)?;
+    }
+    let trailer = blocks.trailer.unwrap();
+    let checksum = hasher.finalize();
+    if len != trailer.len || checksum != trailer.checksum {
+        debug!("Decoded {} bytes, trailer says {}", len, trailer.len);
+        return Err(Error::VerificationFailed {
+            expected: trailer.checksum,
+            actual: checksum,
+        });
+    }
+    decode_following_frames(&mut reader, &mut writer, options, len, None)?;
+    writer.flush()?;
+
+    let written = writer.get_ref();
+    summary.output_bytes = summary.kept_bytes + written.count();
+    kept_hasher.combine(&crc32fast::Hasher::new_with_initial_len(
+        written.checksum(),
+        written.count(),
+    ));
+    summary.checksum = kept_hasher.finalize();
+    Ok((summary, header))
+}
+
+/// Decode [stream] into [output] from the start, for streams without blocks to keep
+fn start_over<R: Read>(
+    stream: R,
+    output: &mut File,
+    throttle: Option<Throttle>,
+    options: &DecodeOptions,
+) -> Result<(ResumeSummary, Header)> {
+    output.set_len(0)?;
+    output.rewind()?;
+    let writer = BufWriter::new(Throttled::new(&mut *output, throttle));
+    let (decoded, header) = decode_with_header(stream, writer, options)?;
+    let summary = ResumeSummary {
+        output_bytes: decoded.output_bytes,
+        checksum: decoded.checksum,
+        ..Default::default()
+    };
+    Ok((summary, header))
+}
+
+#[cfg(test)]
+mod test {
+    use std::fs::File;
+    use std::io::{Read, Seek, Write};
+
+    use crate::resume::resume_stream;
+    use crate::test_util::{encode, temp_path};
+    use crate::{DecodeOptions, EncodeOptions, Filter, StreamEncoder};
+
+    fn input() -> Vec<u8> {
+        (0..3000)
+            .flat_map(|i| format!("{} bottles, ", i % 99).into_bytes())
+            .collect()
+    }
+
+    /// Resume decoding [encoded] into a file first holding [existing], giving back what the
+    /// file ends up holding and how many blocks were kept
+    fn resume(name: &str, encoded: &[u8], existing: &[u8]) -> (Vec<u8>, u64) {
+        let path = temp_path(&format!("resume_{}", name));
+        std::fs::write(&path, existing).unwrap();
+        let mut output = File::options().read(true).write(true).open(&path).unwrap();
+        let (summary, _) = resume_stream(encoded, &mut output, &DecodeOptions::default()).unwrap();
+        let mut decoded = Vec::new();
+        output.rewind().unwrap();
+        output.read_to_end(&mut decoded).unwrap();
+        std::fs::remove_file(path).unwrap();
+        assert_eq!(decoded.len() as u64, summary.output_bytes);
+        assert_eq!(crc32fast::hash(&decoded), summary.checksum);
+        (decoded, summary.kept_blocks)
+    }
+
+    #[test]
+    fn keeps_whole_blocks() {
+        let input = input();
+        for filter in [Filter::None, Filter::Delta8] {
+            let options = EncodeOptions::default()
+                .block_size(Some(1000))
+                .filter(filter);
+            let encoded = encode(&input, &options);
+            assert_eq!((input.clone(), 0), resume("empty", &encoded, b""));
+            assert_eq!((input.clone(), 2), resume("part", &encoded, &input[..2500]));
+            let blocks = input.len() as u64 / 1000 + 1;
+            assert_eq!((input.clone(), blocks), resume("whole", &encoded, &input));
+
+            // Anything after the stream's output is dropped
+            let mut longer = input.clone();
+            longer.extend(b"left over");
+            assert_eq!((input.clone(), blocks), resume("longer", &encoded, &longer));
+
+            // Only blocks which match are kept
+            let mut damaged = input[..2500].to_vec();
+            damaged[1500] ^= 1;
+            assert_eq!((input.clone(), 1), resume("damaged", &encoded, &damaged));
+        }
+    }
+
+    #[test]
+    fn starts_over() {
+        let input = input();
+        for options in [
+            // A single block can only be kept whole
+            EncodeOptions::default(),
+            EncodeOptions::default().algorithm(crate::Algorithm::Stored),
+            EncodeOptions::default().algorithm(crate::Algorithm::Huffman),
+        ] {
+            let encoded = encode(&input, &options);
+            assert_eq!((input.clone(), 0), resume("over", &encoded, &input[..2500]));
+        }
+        let encoded = encode(&input, &EncodeOptions::default());
+        assert_eq!((input.clone(), 1), resume("over_whole", &encoded, &input));
+    }
+
+    #[test]
+    fn following_frames() {
+        let input: Vec<u8> = input().repeat(3);
+        let options = EncodeOptions::default().block_size(Some(2000));
+        let mut encoder = StreamEncoder::new(Vec::new(), &options).segment_len(10_000);
+        encoder.write_all(&input).unwrap();
+        let (encoded, _) = encoder.finish().unwrap();
+        // Part way through the first frame, at its end, where the next frame's window starts
+        // with what was kept, and part way through the second
+        for (len, kept) in [(5000, 2), (10_000, 5), (15_000, 5)] {
+            let (decoded, kept_blocks) = resume("frames", &encoded, &input[..len]);
+            assert_eq!(input, decoded);
+            assert_eq!(kept, kept_blocks);
+        }
+    }
+}
@@ -0,0 +1,311 @@
+//! A battery of generated inputs round tripped through the whole pipeline (files on disk, encode,
+//! decode, compare) with a spread of options, for checking a build works on a new platform, e.g.
+//! big-endian or 32-bit. The inputs are the same every run, so a failure can be reproduced
+
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::Path;
+
+use crate::decode::{self, DecodeOptions};
+use crate::encode::{self, StreamEncoder};
+use crate::error::Result;
+use crate::file_io::{FileInputOutput, InputFile, OutputTarget, OverwritePolicy};
+use crate::{Algorithm, EncodeOptions, Entropy, Filter, MatchStrategy};
+
+/// Default length of the inputs which have one, i.e. all but the empty and tiny ones
+pub const DEFAULT_INPUT_LEN: usize = 1 << 18;
+
+/// Default length of [stress_large]'s input, just past 4GiB so anything counting bytes in 32
+/// bits wraps
+pub const STRESS_LARGE_LEN: u64 = (1 << 32) + (1 << 20);
+
+/// How one input fared with one set of options
+#[derive(Debug, serde::Serialize)]
+pub struct SelfTestResult {
+    pub input: &'static str,
+    pub options: &'static str,
+    pub input_bytes: u64,
+    /// None if the round trip failed
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub output_bytes: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+impl SelfTestResult {
+    pub fn passed(&self) -> bool {
+        self.error.is_none()
+    }
+}
+
+/// The inputs, by name, [len] bytes long where they have a length
+pub fn inputs(len: usize) -> Vec<(&'static str, Vec<u8>)> {
+    let mut random = Lcg(0x5eed);
+    let runs = (0..)
+        .flat_map(|i: usize| std::iter::repeat_n((i % 7) as u8 * 31, 1 + i * 37 % 300))
+        .take(len)
+        .collect();
+    let periodic = (0..len).map(|i| (i % 37) as u8 ^ 0x55).collect();
+    let random_bytes = (0..len).map(|_| random.next() as u8).collect();
+    let words = [
+        "lizard", "gecko", "the", "basks", "on", "a", "warm", "rock", "and", "sheds",
+    ];
+    let mut text = Vec::with_capacity(len);
+    while text.len() < len {
+        let word = words[random.next() as usize % words.len()];
+        text.extend_from_slice(word.as_bytes());
+        text.push(match random.next() % 12 {
+            0 => b'\n',
+            _ => b' ',
+        });
+    }
+    text.truncate(len);
+    vec![
+        ("empty", Vec::new()),
+        ("tiny", b"a".to_vec()),
+        ("runs", runs),
+        ("periodic", periodic),
+        ("random", random_bytes),
+        ("text", text),
+    ]
+}
+
+/// The option combinations each input is round tripped with, by name. "stream" encodes with
+/// [StreamEncoder] rather than [encode::encode]
+pub fn option_sets() -> Vec<(&'static str, EncodeOptions)> {
+    let default = EncodeOptions::default;
+    #[allow(unused_mut)]
+    let mut sets = vec![
+        ("default", default()),
+        ("lz", default().algorithm(Algorithm::Lz)),
+        ("huffman", default().algorithm(Algorithm::Huffman)),
+        ("stored", default().algorithm(Algorithm::Stored)),
+        ("rans", default().entropy(Entropy::Rans)),
+        ("delta8", default().filter(Filter::Delta8)),
+        ("rle", default().rle_min_run(Some(16))),
+        ("blocks", default().block_size(Some(1 << 16))),
+        (
+            "content-defined-blocks",
+            default()
+                .block_size(Some(1 << 14))
+                .content_defined_blocks(true),
+        ),
+        ("window", default().window_size(1 << 12)),
+        ("long-range", default().long_range(Some(1 << 24))),
+        (
+            "hash-chain",
+            default().match_strategy(MatchStrategy::HashChain),
+        ),
+        ("stream", default()),
+    ];
+    #[cfg(feature = "bwt")]
+    sets.push(("bwt", default().algorithm(Algorithm::Bwt)));
+    #[cfg(feature = "crypto")]
+    sets.push((
+        "encrypted",
+        default().with_key(crate::crypto::EncryptionKey::new(
+            [7; crate::crypto::KEY_LEN],
+        )),
+    ));
+    sets
+}
+
+/// Round trip every input with every option set, through files in [dir], calling [progress]
+/// with each result as it comes
+pub fn run(
+    dir: &Path,
+    len: usize,
+    mut progress: impl FnMut(&SelfTestResult),
+) -> Result<Vec<SelfTestResult>> {
+    std::fs::create_dir_all(dir)?;
+    let mut results = Vec::new();
+    for (input, bytes) in inputs(len) {
+        let original = dir.join(input);
+        std::fs::write(&original, &bytes)?;
+        for (name, options) in option_sets() {
+            let encoded = dir.join(format!("{}.{}.lizard", input, name));
+            let decoded = dir.join(format!("{}.{}.out", input, name));
+            let outcome = round_trip(&original, &encoded, &decoded, name, &options);
+            let result = SelfTestResult {
+                input,
+                options: name,
+                input_bytes: bytes.len() as u64,
+                output_bytes: outcome.as_ref().ok().copied(),
+                error: outcome.err(),
+            };
+            progress(&result);
+            results.push(result);
+            let _ = std::fs::remove_file(&encoded);
+            let _ = std::fs::remove_file(&decoded);
+        }
+        std::fs::remove_file(&original)?;
+    }
+    Ok(results)
+}
+
+/// Encode [original] to [encoded] and decode it to [decoded], giving the encoded size if
+/// [decoded] matches
+fn round_trip(
+    original: &Path,
+    encoded: &Path,
+    decoded: &Path,
+    name: &str,
+    options: &EncodeOptions,
+) -> std::result::Result<u64, String> {
+    let encode_io = FileInputOutput::builder(InputFile::Unencoded(original.to_path_buf()))
+        .output(OutputTarget::Path(encoded.to_path_buf()))
+        .overwrite(OverwritePolicy::Always)
+        .build()
+        .map_err(|e| e.to_string())?;
+    let encoded_bytes = match name {
+        "stream" => {
+            let output = std::fs::File::create(encoded).map_err(|e| e.to_string())?;
+            let mut encoder = StreamEncoder::new(output, options)
+                .segment_len(1 << 16)
+                .shared_window(true);
+            let input = std::fs::read(original).map_err(|e| e.to_string())?;
+            encoder.write_all(&input).map_err(|e| e.to_string())?;
+            let (_, summary) = encoder.finish().map_err(|e| e.to_string())?;
+            summary.output_bytes
+        }
+        _ => {
+            encode::encode(&encode_io, options)
+                .map_err(|e| format!("encoding: {}", e))?
+                .output_bytes
+        }
+    };
+    let decode_io = FileInputOutput::builder(InputFile::Encoded(encoded.to_path_buf()))
+        .output(OutputTarget::Path(decoded.to_path_buf()))
+        .overwrite(OverwritePolicy::Always)
+        .build()
+        .map_err(|e| e.to_string())?;
+    let decode_options = DecodeOptions {
+        #[cfg(feature = "crypto")]
+        secret: options.secret.clone(),
+        ..DecodeOptions::default()
+    };
+    decode::decode(&decode_io, &decode_options).map_err(|e| format!("decoding: {}", e))?;
+    let matches = std::fs::read(original).map_err(|e| e.to_string())?
+        == std::fs::read(decoded).map_err(|e| e.to_string())?;
+    match matches {
+        true => Ok(encoded_bytes),
+        false => Err(String::from("decoded to different bytes")),
+    }
+}
+
+/// Encode [len] bytes of a short repeating pattern as a single frame and decode it back,
+/// checking the lengths and checksums on both sides come out right. For lengths past 4GiB, to
+/// catch anything counting in 32 bits. The input is generated as it's read, and the pattern
+/// fits in the window, so the encoded stream is small enough to hold in memory. Takes minutes
+pub fn stress_large(len: u64) -> Result<SelfTestResult> {
+    let options = EncodeOptions::default().algorithm(Algorithm::Lz);
+    let mut encoded = Vec::new();
+    let input = Repeating::new(len);
+    let encoded_summary = encode::encode_stream(input, &mut encoded, None, None, &[], &options)?;
+    let decode_options = DecodeOptions::default().max_output_size(u64::MAX);
+    let (decoded_summary, _) =
+        decode::decode_with_header(&encoded[..], std::io::sink(), &decode_options)?;
+    let error = if encoded_summary.input_bytes != len {
+        Some(format!(
+            "encoder read {} bytes",
+            encoded_summary.input_bytes
+        ))
+    } else if decoded_summary.output_bytes != len {
+        Some(format!("decoded to {} bytes", decoded_summary.output_bytes))
+    } else if decoded_summary.checksum != encoded_summary.checksum {
+        Some(String::from("decoded to different bytes"))
+    } else {
+        None
+    };
+    Ok(SelfTestResult {
+        input: "large",
+        options: "lz",
+        input_bytes: len,
+        output_bytes: Some(encoded.len() as u64),
+        error,
+    })
+}
+
+/// [len] bytes of a pseudo-random pattern shorter than the window, over and over
+struct Repeating {
+    pattern: Vec<u8>,
+    len: u64,
+    position: u64,
+}
+
+impl Repeating {
+    fn new(len: u64) -> Self {
+        let mut random = Lcg(0x1a26e);
+        Self {
+            pattern: (0..251).map(|_| random.next() as u8).collect(),
+            len,
+            position: 0,
+        }
+    }
+}
+
+impl Read for Repeating {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let n = (buf.len() as u64).min(self.len.saturating_sub(self.position)) as usize;
+        let start = (self.position % self.pattern.len() as u64) as usize;
+        let pattern = self.pattern.iter().cycle().skip(start);
+        for (b, p) in buf[..n].iter_mut().zip(pattern) {
+            *b = *p;
+        }
+        self.position += n as u64;
+        Ok(n)
+    }
+}
+
+impl Seek for Repeating {
+    fn seek(&mut self, pos: SeekFrom) -> std::io::Result<u64> {
+        let position = match pos {
+            SeekFrom::Start(offset) => Some(offset),
+            SeekFrom::End(offset) => self.len.checked_add_signed(offset),
+            SeekFrom::Current(offset) => self.position.checked_add_signed(offset),
+        };
+        self.position = position.ok_or(std::io::ErrorKind::InvalidInput)?;
+        Ok(self.position)
+    }
+}
+
+/// A fixed sequence of pseudo-random numbers, so the inputs are the same everywhere
+struct Lcg(u64);
+
+impl Lcg {
+    fn next(&mut self) -> u32 {
+        self.0 = self
+            .0
+            .wrapping_mul(6364136223846793005)
+            .wrapping_add(1442695040888963407);
+        (self.0 >> 33) as u32
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::selftest::{inputs, option_sets, run, stress_large};
+    use crate::test_util::temp_path;
+
+    #[test]
+    fn all_pass() {
+        let dir = temp_path("selftest");
+        let mut seen = 0;
+        let results = run(&dir, 3000, |_| seen += 1).unwrap();
+        assert_eq!(results.len(), inputs(0).len() * option_sets().len());
+        assert_eq!(seen, results.len());
+        for result in &results {
+            assert!(result.passed(), "{:?}", result);
+        }
+        // The inputs are the same every time
+        assert_eq!(inputs(100), inputs(100));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn stress() {
+        // The real thing is past 4GiB, which takes too long here
+        let result = stress_large(5_000_000).unwrap();
+        assert!(result.passed(), "{:?}", result);
+        assert!(result.output_bytes.unwrap() < 5_000_000 / 100);
+    }
+}
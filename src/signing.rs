@@ -0,0 +1,222 @@
+//! ed25519 signatures over a whole encoded file, so anyone with the public key can check it came
+//! from whoever holds the signing key and hasn't changed since. What's signed is a SHA-512 hash
+//! of the file (Ed25519ph), so files are read through rather than held. The signature is either
+//! appended to the file as a trailer, which decoding stops at, or written to a file of its own.
+//! Either way it's [header::SIGNATURE_MAGIC], a version byte, the signer's public key and the
+//! signature
+
+use ed25519_dalek::{Digest, Sha512, Signature, SigningKey, VerifyingKey};
+use std::fmt::{Debug, Formatter};
+use std::io::{Read, Write};
+use zeroize::Zeroizing;
+
+use crate::error::{Error, Result};
+use crate::header::{self, SIGNATURE_MAGIC, SIGNATURE_TRAILER_LEN};
+
+const SIGNATURE_VERSION: u8 = 1;
+/// Ed25519ph context, so a signature made here can't be passed off as one over anything else
+const CONTEXT: &[u8] = b"lizards signature";
+const KEY_LEN: usize = 32;
+
+/// A signature trailer, or the contents of a detached signature file
+pub type SignatureBytes = [u8; SIGNATURE_TRAILER_LEN];
+
+/// A key to sign files with, stored as hex. Wiped from memory when dropped, and left out of
+/// Debug output
+pub struct SecretKey(SigningKey);
+
+impl SecretKey {
+    pub fn generate() -> Self {
+        Self(SigningKey::generate(&mut aes_gcm::aead::OsRng))
+    }
+
+    pub fn from_hex(hex: &str) -> Result<Self> {
+        Ok(Self(SigningKey::from_bytes(&*key_from_hex(hex)?)))
+    }
+
+    /// Wiped from memory when dropped, like the key itself
+    pub fn to_hex(&self) -> Zeroizing<String> {
+        Zeroizing::new(hex::encode(Zeroizing::new(self.0.to_bytes())))
+    }
+
+    pub fn public_key(&self) -> PublicKey {
+        PublicKey(self.0.verifying_key())
+    }
+
+    /// Sign everything [reader] reads, giving back the signature to append or write out
+    pub fn sign<R: Read>(&self, reader: R) -> Result<SignatureBytes> {
+        let signature = self
+            .0
+            .sign_prehashed(hash(reader)?, Some(CONTEXT))
+            .map_err(|e| Error::Signature(e.to_string()))?;
+        let mut bytes = [0; SIGNATURE_TRAILER_LEN];
+        let mut writer = &mut bytes[..];
+        writer.write_all(SIGNATURE_MAGIC)?;
+        writer.write_all(&[SIGNATURE_VERSION])?;
+        writer.write_all(self.0.verifying_key().as_bytes())?;
+        writer.write_all(&signature.to_bytes())?;
+        Ok(bytes)
+    }
+}
+
+impl Debug for SecretKey {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "SecretKey(..)")
+    }
+}
+
+/// A key to check signatures with, stored as hex
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PublicKey(VerifyingKey);
+
+impl PublicKey {
+    pub fn from_hex(hex: &str) -> Result<Self> {
+        VerifyingKey::from_bytes(&*key_from_hex(hex)?)
+            .map(Self)
+            .map_err(|_| Error::Signature(String::from("not a valid public key")))
+    }
+
+    pub fn to_hex(&self) -> String {
+        hex::encode(self.0.as_bytes())
+    }
+
+    /// Check [signature] was made by this key over everything [reader] reads
+    pub fn verify<R: Read>(&self, reader: R, signature: &[u8]) -> Result<()> {
+        self.check(hash(reader)?, signature)
+    }
+
+    /// Check the signature trailer on the end of the [len] bytes [reader] reads
+    pub fn verify_embedded<R: Read>(&self, mut reader: R, len: u64) -> Result<()> {
+        let signed_len = len
+            .checked_sub(SIGNATURE_TRAILER_LEN as u64)
+            .ok_or_else(|| Error::Signature(String::from("the file isn't signed")))?;
+        let mut hashed = (&mut reader).take(signed_len);
+        let hash = hash(&mut hashed)?;
+        if hashed.limit() > 0 {
+            return Err(Error::TruncatedStream("signed file"));
+        }
+        let mut signature = [0; SIGNATURE_TRAILER_LEN];
+        reader
+            .read_exact(&mut signature)
+            .map_err(|_| Error::TruncatedStream("signature"))?;
+        if !header::starts_signature(&signature[..SIGNATURE_MAGIC.len()]) {
+            return Err(Error::Signature(String::from("the file isn't signed")));
+        }
+        self.check(hash, &signature)
+    }
+
+    fn check(&self, hash: Sha512, signature: &[u8]) -> Result<()> {
+        let (signer, signature) = parse(signature)?;
+        if signer != self.0 {
+            return Err(Error::Signature(format!(
+                "signed by another key ({})",
+                hex::encode(signer.as_bytes())
+            )));
+        }
+        self.0
+            .verify_prehashed(hash, Some(CONTEXT), &signature)
+            .map_err(|_| Error::Signature(String::from("the file has changed since it was signed")))
+    }
+}
+
+fn key_from_hex(hex: &str) -> Result<Zeroizing<[u8; KEY_LEN]>> {
+    let mut key = Zeroizing::new([0; KEY_LEN]);
+    hex::decode_to_slice(hex.trim(), &mut key[..])
+        .map_err(|_| Error::Signature(format!("a key is {} hex characters", KEY_LEN * 2)))?;
+    Ok(key)
+}
+
+fn hash<R: Read>(mut reader: R) -> Result<Sha512> {
+    let mut hasher = Sha512::new();
+    let mut buffer = vec![0; 1 << 16];
+    loop {
+        match reader.read(&mut buffer)? {
+            0 => return Ok(hasher),
+            read => hasher.update(&buffer[..read]),
+        }
+    }
+}
+
+/// The signer's key and signature from a trailer or detached signature
+fn parse(bytes: &[u8]) -> Result<(VerifyingKey, Signature)> {
+    let malformed = || Error::Signature(String::from("the signature is malformed"));
+    if bytes.len() != SIGNATURE_TRAILER_LEN {
+        return Err(malformed());
+    }
+    let (magic, rest) = bytes.split_at(SIGNATURE_MAGIC.len());
+    let (version, rest) = rest.split_first().unwrap();
+    let (signer, signature) = rest.split_at(KEY_LEN);
+    if !header::starts_signature(magic) {
+        return Err(malformed());
+    }
+    if *version != SIGNATURE_VERSION {
+        return Err(Error::Signature(format!(
+            "unsupported signature version {}",
+            version
+        )));
+    }
+    let signer = VerifyingKey::from_bytes(signer.try_into().unwrap()).map_err(|_| malformed())?;
+    Ok((signer, Signature::from_bytes(signature.try_into().unwrap())))
+}
+
+#[cfg(test)]
+mod test {
+    use crate::decode::decode_to_writer;
+    use crate::encode::{encode_from_reader, EncodeOptions};
+    use crate::signing::{PublicKey, SecretKey};
+    use crate::Error;
+
+    #[test]
+    fn sign_and_verify() {
+        let key = SecretKey::generate();
+        let public_key = key.public_key();
+        assert_eq!(
+            SecretKey::from_hex(&key.to_hex()).unwrap().public_key(),
+            public_key
+        );
+        assert_eq!(
+            PublicKey::from_hex(&public_key.to_hex()).unwrap(),
+            public_key
+        );
+        assert!(format!("{:?}", key).ends_with("(..)"));
+        assert!(matches!(
+            PublicKey::from_hex("abc"),
+            Err(Error::Signature(_))
+        ));
+
+        let input: Vec<u8> = (0..20_000).map(|i| (i % 13) as u8).collect();
+        let mut encoded = Vec::new();
+        encode_from_reader(&input[..], &mut encoded, &EncodeOptions::default()).unwrap();
+        let signature = key.sign(&encoded[..]).unwrap();
+
+        // Detached
+        public_key.verify(&encoded[..], &signature).unwrap();
+        let other = SecretKey::generate().public_key();
+        let mut changed = encoded.clone();
+        changed[10] ^= 1;
+        for result in [
+            other.verify(&encoded[..], &signature),
+            public_key.verify(&changed[..], &signature),
+            public_key.verify(&encoded[..], &signature[1..]),
+        ] {
+            assert!(matches!(result, Err(Error::Signature(_))));
+        }
+
+        // Embedded, which decoding stops at
+        let signed = [&encoded[..], &signature].concat();
+        public_key
+            .verify_embedded(&signed[..], signed.len() as u64)
+            .unwrap();
+        let mut decoded = Vec::new();
+        decode_to_writer(&signed[..], &mut decoded).unwrap();
+        assert_eq!(decoded, input);
+        let mut changed = signed.clone();
+        changed[10] ^= 1;
+        for bad in [&encoded, &changed] {
+            assert!(matches!(
+                public_key.verify_embedded(&bad[..], bad.len() as u64),
+                Err(Error::Signature(_))
+            ));
+        }
+    }
+}
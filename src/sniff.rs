@@ -0,0 +1,112 @@
+//! Telling files which are already compressed, e.g. JPEGs, zips and videos, so packing a
+//! directory can store them rather than compress them again for next to nothing. Files are
+//! known by their extension, or failing that by the magic bytes they start with
+
+use std::io::Read;
+use std::path::Path;
+
+use crate::header;
+
+/// Extensions of formats which are compressed already, in lower case: images, audio, video,
+/// archives, then documents and fonts which are zips or compressed inside
+const COMPRESSED_EXTENSIONS: &[&str] = &[
+    "jpg", "jpeg", "png", "gif", "webp", "heic", "heif", "avif", "jxl", "mp3", "aac", "m4a", "ogg",
+    "oga", "opus", "flac", "wma", "mp4", "m4v", "mov", "mkv", "webm", "avi", "wmv", "flv", "zip",
+    "jar", "apk", "gz", "tgz", "bz2", "tbz2", "xz", "txz", "zst", "lz4", "7z", "rar", "lizard",
+    "docx", "xlsx", "pptx", "odt", "ods", "odp", "epub", "woff", "woff2",
+];
+
+/// Bytes compressed formats start with, at the offset they're found
+const COMPRESSED_MAGIC: &[(usize, &[u8])] = &[
+    // JPEG
+    (0, &[0xff, 0xd8, 0xff]),
+    (0, b"\x89PNG\r\n\x1a\n"),
+    (0, b"GIF8"),
+    // WebP, inside a RIFF container
+    (8, b"WEBP"),
+    // MP4, MOV, HEIC and AVIF
+    (4, b"ftyp"),
+    // Matroska and WebM
+    (0, &[0x1a, 0x45, 0xdf, 0xa3]),
+    (0, b"OggS"),
+    (0, b"fLaC"),
+    // MP3 with ID3 tags
+    (0, b"ID3"),
+    (0, b"PK\x03\x04"),
+    // gzip
+    (0, &[0x1f, 0x8b]),
+    (0, b"BZh"),
+    (0, &[0xfd, b'7', b'z', b'X', b'Z', 0]),
+    // zstd
+    (0, &[0x28, 0xb5, 0x2f, 0xfd]),
+    (0, &[b'7', b'z', 0xbc, 0xaf, 0x27, 0x1c]),
+    (0, b"Rar!\x1a\x07"),
+];
+
+/// Bytes read from the start of a file to look for [COMPRESSED_MAGIC] in
+const MAGIC_LEN: usize = 16;
+
+/// Whether the file at [path] looks already compressed, by its extension or first bytes. A file
+/// that can't be read doesn't, so packing it fails as it would otherwise
+pub(crate) fn already_compressed(path: &Path) -> bool {
+    let extension = path
+        .extension()
+        .and_then(|extension| extension.to_str())
+        .map(|extension| extension.to_ascii_lowercase());
+    if let Some(extension) = extension {
+        if COMPRESSED_EXTENSIONS.contains(&extension.as_str()) {
+            return true;
+        }
+    }
+    let mut start = Vec::with_capacity(MAGIC_LEN);
+    match std::fs::File::open(path) {
+        Ok(file) => match file.take(MAGIC_LEN as u64).read_to_end(&mut start) {
+            Ok(_) => compressed_magic(&start),
+            Err(_) => false,
+        },
+        Err(_) => false,
+    }
+}
+
+/// Whether [start], the first bytes of a file, are those of a compressed format
+fn compressed_magic(start: &[u8]) -> bool {
+    let lizards = start
+        .get(..header::FRAME_PREFIX_LEN)
+        .is_some_and(header::starts_frame);
+    lizards
+        || COMPRESSED_MAGIC
+            .iter()
+            .any(|(offset, magic)| start.get(*offset..).is_some_and(|at| at.starts_with(magic)))
+}
+
+#[cfg(test)]
+mod test {
+    use crate::encode::encode_from_reader;
+    use crate::sniff::{already_compressed, compressed_magic};
+    use crate::test_util::temp_path;
+    use crate::EncodeOptions;
+
+    #[test]
+    fn sniffs() {
+        assert!(compressed_magic(&[0xff, 0xd8, 0xff, 0xe0, 0, 0x10]));
+        assert!(compressed_magic(b"\0\0\0\x20ftypisom"));
+        assert!(compressed_magic(b"RIFF\0\0\0\0WEBPVP8 "));
+        assert!(!compressed_magic(b"RIFF\0\0\0\0WAVEfmt "));
+        assert!(!compressed_magic(b"Hello, world"));
+        assert!(!compressed_magic(b""));
+        let mut encoded = Vec::new();
+        encode_from_reader(&b"hello"[..], &mut encoded, &EncodeOptions::default()).unwrap();
+        assert!(compressed_magic(&encoded));
+
+        let dir = temp_path("sniff");
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("photo.JPG"), b"not really").unwrap();
+        std::fs::write(dir.join("archive"), b"PK\x03\x04rest").unwrap();
+        std::fs::write(dir.join("notes.txt"), b"plain text").unwrap();
+        assert!(already_compressed(&dir.join("photo.JPG")));
+        assert!(already_compressed(&dir.join("archive")));
+        assert!(!already_compressed(&dir.join("notes.txt")));
+        assert!(!already_compressed(&dir.join("missing")));
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
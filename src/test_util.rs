@@ -0,0 +1,19 @@
+//! Helpers shared by the tests of several modules
+use std::path::PathBuf;
+
+use crate::encode::encode_from_reader;
+use crate::EncodeOptions;
+
+/// [input] encoded with [options]. With metadata, so small inputs aren't given up on and stored
+pub(crate) fn encode(input: &[u8], options: &EncodeOptions) -> Vec<u8> {
+    let mut encoded = Vec::new();
+    let options = options.clone().metadata("test", "lizards");
+    encode_from_reader(input, &mut encoded, &options).unwrap();
+    encoded
+}
+
+/// A path in the temp directory for the test [name], not shared with test runs of other
+/// processes. Nothing is created there
+pub(crate) fn temp_path(name: &str) -> PathBuf {
+    std::env::temp_dir().join(format!("lizards_{}_{}", name, std::process::id()))
+}
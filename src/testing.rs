@@ -0,0 +1,122 @@
+//! Helpers for checking lizards round trips, e.g. from the tests of code embedding the library.
+//! Only available with the `testing` feature
+use proptest::prelude::*;
+
+use crate::{decode, encode, Algorithm, EncodeOptions, Entropy, Filter, MAX_MATCH_LEN};
+
+/// Longest input the generators produce, kept small as the encoder isn't quick
+const MAX_INPUT_LEN: usize = 4096;
+
+/// Encode [bytes] with [options] and check decoding gives them back, and that the encoding
+/// isn't bigger than [encode::max_encoded_len], panicking if not
+pub fn assert_roundtrip(bytes: &[u8], options: &EncodeOptions) {
+    let mut encoded = Vec::new();
+    encode::encode_from_reader(bytes, &mut encoded, options)
+        .unwrap_or_else(|e| panic!("Encoding failed with {:?}: {}", options, e));
+    assert!(
+        encoded.len() as u64 <= encode::max_encoded_len(bytes.len() as u64),
+        "Encoding with {:?} expanded {} bytes to {}",
+        options,
+        bytes.len(),
+        encoded.len()
+    );
+    let decoded = decode::decode_bytes(&encoded)
+        .unwrap_or_else(|e| panic!("Decoding failed with {:?}: {}", options, e));
+    assert!(
+        decoded == bytes,
+        "Round trip with {:?} gave different bytes: {} in, {} out",
+        options,
+        bytes.len(),
+        decoded.len()
+    );
+}
+
+/// A few runs of repeated bytes
+pub fn long_runs() -> impl Strategy<Value = Vec<u8>> {
+    prop::collection::vec((any::<u8>(), 1..MAX_INPUT_LEN / 4), 1..4).prop_map(|runs| {
+        runs.into_iter()
+            .flat_map(|(byte, len)| std::iter::repeat_n(byte, len))
+            .collect()
+    })
+}
+
+/// A short pattern repeated over and over
+pub fn periodic() -> impl Strategy<Value = Vec<u8>> {
+    (prop::collection::vec(any::<u8>(), 1..16), 1..MAX_INPUT_LEN)
+        .prop_map(|(pattern, len)| pattern.into_iter().cycle().take(len).collect())
+}
+
+/// Uniformly random bytes, which won't compress
+pub fn random_binary() -> impl Strategy<Value = Vec<u8>> {
+    prop::collection::vec(any::<u8>(), 1..MAX_INPUT_LEN)
+}
+
+/// Any of the above
+pub fn pathological_input() -> impl Strategy<Value = Vec<u8>> {
+    prop_oneof![long_runs(), periodic(), random_binary()]
+}
+
+/// Options across the range the encoder accepts, including tiny windows and match lengths
+pub fn encode_options() -> impl Strategy<Value = EncodeOptions> {
+    let max_match_len = prop_oneof![Just(MAX_MATCH_LEN), 1..64usize];
+    let algorithm = prop_oneof![
+        Just(Algorithm::LzHuffman),
+        Just(Algorithm::Huffman),
+        Just(Algorithm::Lz),
+        Just(Algorithm::Stored)
+    ];
+    (
+        1..2048usize,
+        prop::option::of(1..40usize),
+        max_match_len,
+        algorithm,
+        prop::option::of(1..100usize),
+        prop_oneof![
+            Just(Filter::None),
+            Just(Filter::Delta8),
+            Just(Filter::Delta16)
+        ],
+        prop::option::of(1..3000usize),
+        prop_oneof![Just(Entropy::Huffman), Just(Entropy::Rans)],
+    )
+        .prop_map(
+            |(
+                window_size,
+                min_match,
+                max_match_len,
+                algorithm,
+                rle_min_run,
+                filter,
+                block_size,
+                entropy,
+            )| {
+                let options = EncodeOptions::default()
+                    .window_size(window_size)
+                    .max_match_len(max_match_len)
+                    .algorithm(algorithm)
+                    .rle_min_run(rle_min_run)
+                    .filter(filter)
+                    .block_size(block_size)
+                    .entropy(entropy);
+                match min_match {
+                    Some(min_match) => options.min_match(min_match),
+                    None => options.auto_min_match(),
+                }
+            },
+        )
+}
+
+#[cfg(test)]
+mod test {
+    use proptest::prelude::*;
+
+    use crate::testing::{assert_roundtrip, encode_options, pathological_input};
+
+    proptest! {
+        #![proptest_config(ProptestConfig::with_cases(64))]
+        #[test]
+        fn roundtrip(input in pathological_input(), options in encode_options()) {
+            assert_roundtrip(&input, &options);
+        }
+    }
+}
@@ -0,0 +1,138 @@
+//! Holding reads and writes to a rate, for running big jobs in the background on a shared host
+//! without taking all of a disk's bandwidth. Reads and writes through clones of the same
+//! [Throttle] share its rate, so a compress's reading and writing together keep to it
+
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// How far under the rate IO can fall before the time stops counting, so an idle spell isn't
+/// made up for by a burst afterwards
+const MAX_CREDIT: Duration = Duration::from_secs(1);
+
+#[derive(Debug)]
+struct Budget {
+    bytes_per_sec: u64,
+    start: Instant,
+    /// Since [start]
+    bytes: u64,
+}
+
+/// A rate shared by everything read and written through its clones
+#[derive(Debug, Clone)]
+pub struct Throttle(Arc<Mutex<Budget>>);
+
+impl Throttle {
+    pub fn new(bytes_per_sec: u64) -> Self {
+        Self(Arc::new(Mutex::new(Budget {
+            bytes_per_sec: bytes_per_sec.max(1),
+            start: Instant::now(),
+            bytes: 0,
+        })))
+    }
+
+    /// Count [len] bytes against the rate, sleeping until they're due if they came early
+    fn take(&self, len: usize) {
+        let mut budget = self.0.lock().unwrap();
+        budget.bytes += len as u64;
+        let due = Duration::from_secs_f64(budget.bytes as f64 / budget.bytes_per_sec as f64);
+        let elapsed = budget.start.elapsed();
+        match due.checked_sub(elapsed) {
+            // Sleeping with the lock held, as anything else would be over the rate too
+            Some(wait) => std::thread::sleep(wait),
+            None if elapsed - due > MAX_CREDIT => {
+                budget.start = Instant::now();
+                budget.bytes = 0;
+            }
+            None => (),
+        }
+    }
+}
+
+/// Passes reads and writes through to [inner], held to [throttle]'s rate if there is one
+pub struct Throttled<T> {
+    inner: T,
+    throttle: Option<Throttle>,
+}
+
+impl<T> Throttled<T> {
+    pub fn new(inner: T, throttle: Option<Throttle>) -> Self {
+        Self { inner, throttle }
+    }
+
+    pub fn get_mut(&mut self) -> &mut T {
+        &mut self.inner
+    }
+}
+
+impl<R: Read> Read for Throttled<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let read = self.inner.read(buf)?;
+        if let Some(throttle) = &self.throttle {
+            throttle.take(read);
+        }
+        Ok(read)
+    }
+}
+
+impl<W: Write> Write for Throttled<W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let written = self.inner.write(buf)?;
+        if let Some(throttle) = &self.throttle {
+            throttle.take(written);
+        }
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+impl<S: Seek> Seek for Throttled<S> {
+    fn seek(&mut self, pos: SeekFrom) -> std::io::Result<u64> {
+        self.inner.seek(pos)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::{Read, Write};
+    use std::time::{Duration, Instant};
+
+    use crate::throttle::{Throttle, Throttled};
+
+    #[test]
+    fn holds_to_rate() {
+        let throttle = Throttle::new(100_000);
+        let mut reader = Throttled::new(&[0u8; 10_000][..], Some(throttle.clone()));
+        let mut writer = Throttled::new(Vec::new(), Some(throttle));
+        let start = Instant::now();
+        let mut buf = [0; 1000];
+        for _ in 0..10 {
+            reader.read_exact(&mut buf).unwrap();
+            writer.write_all(&buf).unwrap();
+        }
+        // 20KB shared between them at 100KB/s
+        assert!(start.elapsed() >= Duration::from_millis(200));
+        assert_eq!(10_000, writer.get_mut().len());
+
+        // Without a throttle it's just passed through
+        let start = Instant::now();
+        let mut writer = Throttled::new(Vec::new(), None);
+        writer.write_all(&[0; 1_000_000]).unwrap();
+        assert!(start.elapsed() < Duration::from_millis(200));
+    }
+
+    #[test]
+    fn idle_time_not_saved_up() {
+        let throttle = Throttle::new(10_000);
+        let mut writer = Throttled::new(std::io::sink(), Some(throttle));
+        std::thread::sleep(Duration::from_millis(1200));
+        let start = Instant::now();
+        writer.write_all(&[0; 1]).unwrap();
+        // The count starts over after being idle, so the idle second isn't spent on this
+        writer.write_all(&[0; 2_000]).unwrap();
+        assert!(start.elapsed() >= Duration::from_millis(150));
+    }
+}
@@ -0,0 +1,176 @@
+//! The tokens a stream is coded as, after the header: literals packed into chunks, matches
+//! against the window, and control records for everything else. The encoder's tokenisers only
+//! make [Token::Literal], [Token::Match] and [Token::Run], the rest are written around them
+
+use serde::{Deserialize, Serialize};
+use std::fmt::{Display, Formatter};
+
+use crate::control::{ControlRecord, Trailer};
+use crate::offset_len::OffsetLen;
+
+/// One token of a stream
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum Token {
+    /// A byte as it is, packed into a chunk with the literals either side of it
+    Literal(u8),
+    /// Bytes copied from earlier in the window
+    Match(OffsetLen),
+    /// The u8 repeated this many times, written as a [ControlRecord::Run]
+    Run(u8, u64),
+    /// The last token of the stream
+    EndOfStream(Trailer),
+    /// Any other control record. Runs and the end of stream have their own variants, which
+    /// [Token::from] gives for those records
+    Control(ControlRecord),
+}
+
+impl Token {
+    /// Write the token as it appears in the stream, returning how many bytes that took.
+    /// Literals are written as they are, as packing them depends on the literals around them
+    pub(crate) fn write_to<W: std::io::Write>(&self, writer: &mut W) -> std::io::Result<usize> {
+        match self {
+            Self::Literal(v) => writer.write_all(&[*v]).map(|()| 1),
+            Self::Match(offset_len) => offset_len.write_to(writer),
+            Self::Run(value, len) => ControlRecord::Run {
+                value: *value,
+                len: *len,
+            }
+            .write_to(writer),
+            Self::EndOfStream(trailer) => ControlRecord::EndOfStream(*trailer).write_to(writer),
+            Self::Control(record) => record.write_to(writer),
+        }
+    }
+}
+
+/// One line per token, for comparing with diff: literals with the character they are if it's
+/// printable, matches with the bytes they matched if those were recorded, and control records
+/// as they're logged
+impl Display for Token {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Literal(v) if v.is_ascii_graphic() || *v == b' ' => {
+                write!(f, "Literal {:#04x} {:?}", v, *v as char)
+            }
+            Self::Literal(v) => write!(f, "Literal {:#04x}", v),
+            Self::Match(offset_len) => {
+                write!(
+                    f,
+                    "Match {} back, {} long",
+                    offset_len.offset().get(),
+                    offset_len.len.get()
+                )?;
+                match offset_len.matched_bytes() {
+                    Some(bytes) => match std::str::from_utf8(bytes) {
+                        Ok(s) => write!(f, ": {:?}", s),
+                        Err(_) => write!(f, ": {:?}", bytes),
+                    },
+                    None => Ok(()),
+                }
+            }
+            Self::Run(value, len) => ControlRecord::Run {
+                value: *value,
+                len: *len,
+            }
+            .fmt(f),
+            Self::EndOfStream(trailer) => ControlRecord::EndOfStream(*trailer).fmt(f),
+            Self::Control(record) => record.fmt(f),
+        }
+    }
+}
+
+impl From<ControlRecord> for Token {
+    fn from(record: ControlRecord) -> Self {
+        match record {
+            ControlRecord::Run { value, len } => Self::Run(value, len),
+            ControlRecord::EndOfStream(trailer) => Self::EndOfStream(trailer),
+            record => Self::Control(record),
+        }
+    }
+}
+
+/// Starts a chunk of packed literals, holding how many bytes of them follow
+pub(crate) struct ChunkMarker {
+    pub len: u8,
+}
+
+impl ChunkMarker {
+    pub const MAX_VALUE: usize = 0b00111111;
+    pub fn to_u8(&self) -> u8 {
+        let mask = 0b11000000;
+        self.len | mask
+    }
+
+    pub fn from_encoded_u8(v: u8) -> Self {
+        Self {
+            len: v & 0b00111111,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::control::{ControlRecord, Trailer};
+    use crate::offset_len::OffsetLen;
+    use crate::token::{ChunkMarker, Token};
+
+    fn tokens() -> Vec<Token> {
+        vec![
+            Token::Literal(b'a'),
+            Token::Match(OffsetLen::of(700, 12)),
+            Token::Run(0, 100),
+            Token::EndOfStream(Trailer {
+                len: 5,
+                checksum: 0x01020304,
+            }),
+            Token::Control(ControlRecord::BlockBoundary),
+        ]
+    }
+
+    #[test]
+    fn written_as_records() {
+        let lens: Vec<usize> = tokens()
+            .iter()
+            .map(|token| token.write_to(&mut std::io::sink()).unwrap())
+            .collect();
+        assert_eq!(vec![1, 3, 4, 14, 2], lens);
+        let mut bytes = Vec::new();
+        Token::Run(7, 3).write_to(&mut bytes).unwrap();
+        let run = ControlRecord::Run { value: 7, len: 3 };
+        assert_eq!(run.to_bytes(), bytes);
+    }
+
+    #[test]
+    fn from_control_record() {
+        let run = ControlRecord::Run { value: 7, len: 3 };
+        assert_eq!(Token::Run(7, 3), Token::from(run));
+        let trailer = Trailer {
+            len: 1,
+            checksum: 2,
+        };
+        let end = ControlRecord::EndOfStream(trailer);
+        assert_eq!(Token::EndOfStream(trailer), Token::from(end));
+        assert_eq!(
+            Token::Control(ControlRecord::Padding(2)),
+            Token::from(ControlRecord::Padding(2))
+        );
+    }
+
+    #[test]
+    fn serde_round_trip() {
+        let tokens = tokens();
+        let json = serde_json::to_string(&tokens).unwrap();
+        assert_eq!(tokens, serde_json::from_str::<Vec<Token>>(&json).unwrap());
+        // Matches can't point at the byte being made
+        let zero_offset = r#"{"Match":{"offset":0,"len":4}}"#;
+        assert!(serde_json::from_str::<Token>(zero_offset).is_err());
+    }
+
+    #[test]
+    fn chunk_marker_round_trip() {
+        for len in 0..=ChunkMarker::MAX_VALUE as u8 {
+            let byte = ChunkMarker { len }.to_u8();
+            assert_eq!(0b11, byte >> 6);
+            assert_eq!(len, ChunkMarker::from_encoded_u8(byte).len);
+        }
+    }
+}
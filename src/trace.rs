@@ -0,0 +1,128 @@
+//! The trace an encode can write alongside its output (`file.dblzd`, see
+//! [crate::file_io::FileInputOutputBuilder::debug]), for seeing and comparing what the input
+//! was coded as: each [Token] after the header as a line of JSON, in the order they were
+//! written. Unlike the packed stream, literals are traced one by one, so chunks don't show up
+
+use std::io::{BufRead, Write};
+
+use crate::error::Result;
+use crate::token::Token;
+
+pub(crate) fn write_token<W: Write + ?Sized>(writer: &mut W, token: &Token) -> std::io::Result<()> {
+    serde_json::to_writer(&mut *writer, token)?;
+    writer.write_all(b"\n")
+}
+
+/// The tokens in a trace written by an encode, in order. A line that isn't a token is an
+/// [crate::Error::Io] of kind [std::io::ErrorKind::InvalidData]
+pub fn read_trace<R: BufRead>(reader: R) -> impl Iterator<Item = Result<Token>> {
+    reader.lines().map(|line| {
+        let token = serde_json::from_str(&line?).map_err(std::io::Error::from)?;
+        Ok(token)
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use std::cell::RefCell;
+    use std::io::{BufWriter, Cursor, Write};
+    use std::rc::Rc;
+
+    use crate::control::Trailer;
+    use crate::encode::encode_stream;
+    use crate::output_stream::OutputStream;
+    use crate::trace::{read_trace, write_token};
+    use crate::{Algorithm, EncodeOptions, Token};
+
+    /// Lets the test read the trace once the encoder's done with the writer
+    #[derive(Clone, Default)]
+    struct SharedBuf(Rc<RefCell<Vec<u8>>>);
+
+    impl Write for SharedBuf {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.borrow_mut().write(buf)
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    /// Apply [tokens] as the decoder would
+    fn replay(tokens: &[Token]) -> Vec<u8> {
+        let mut output = Vec::new();
+        for token in tokens {
+            match token {
+                Token::Literal(v) => output.push(*v),
+                Token::Match(offset_len) => {
+                    let start = offset_len.offset().start(output.len()).unwrap();
+                    for i in 0..offset_len.len.get() {
+                        output.push(output[start + i]);
+                    }
+                }
+                Token::Run(v, len) => output.extend(std::iter::repeat_n(*v, *len as usize)),
+                Token::EndOfStream(_) | Token::Control(_) => (),
+            }
+        }
+        output
+    }
+
+    #[test]
+    fn round_trip() {
+        let input = "The cat sat on the mat. The cat sat on the hat. ".repeat(20);
+        // Enough literals to span several chunks, and a bitstream
+        for algorithm in [Algorithm::LzHuffman, Algorithm::Lz, Algorithm::Huffman] {
+            let trace = SharedBuf::default();
+            let options = EncodeOptions::default()
+                .algorithm(algorithm)
+                .rle_min_run(Some(4));
+            let summary = encode_stream(
+                Cursor::new(input.as_bytes()),
+                &mut Vec::new(),
+                Some(Box::new(trace.clone())),
+                None,
+                &[],
+                &options,
+            )
+            .unwrap();
+            let trace = trace.0.borrow();
+            let tokens: Vec<Token> = read_trace(&trace[..]).collect::<Result<_, _>>().unwrap();
+            assert_eq!(input.as_bytes(), replay(&tokens));
+            let end = Token::EndOfStream(Trailer {
+                len: input.len() as u64,
+                checksum: summary.checksum,
+            });
+            assert_eq!(Some(&end), tokens.last());
+        }
+    }
+
+    #[test]
+    fn traced_as_written() {
+        let tokens = [
+            Token::Literal(b'a'),
+            Token::Literal(b'b'),
+            Token::Match(crate::OffsetLen::of(2, 3)),
+            Token::Run(b'c', 5),
+        ];
+        let mut trace = Vec::new();
+        {
+            let mut output_stream = OutputStream::new(
+                None,
+                BufWriter::new(std::io::sink()),
+                Some(Box::new(&mut trace)),
+            );
+            for token in &tokens {
+                output_stream.add(token).unwrap();
+            }
+        }
+        let read: Vec<Token> = read_trace(&trace[..]).collect::<Result<_, _>>().unwrap();
+        assert_eq!(&tokens[..], &read[..]);
+
+        let mut bad = Vec::new();
+        write_token(&mut bad, &Token::Literal(1)).unwrap();
+        bad.extend(b"not a token\n");
+        let mut read = read_trace(&bad[..]);
+        assert!(read.next().unwrap().is_ok());
+        assert!(matches!(read.next(), Some(Err(crate::Error::Io(_)))));
+    }
+}
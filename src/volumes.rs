@@ -0,0 +1,291 @@
+//! Output split across numbered files of a fixed size (`out.lizard.001`, `.002`, ...), for
+//! moving big files through channels with a size limit. The volumes are just the stream cut up,
+//! so concatenating them gives the same file as encoding without splitting
+
+use log::info;
+use std::fs::File;
+use std::io::{BufWriter, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+
+/// Path of volume [index] (counting from 1) of [base], e.g. `out.lizard.001`
+pub fn volume_path(base: &Path, index: u32) -> PathBuf {
+    let mut path = base.as_os_str().to_owned();
+    path.push(format!(".{:03}", index));
+    PathBuf::from(path)
+}
+
+/// [path] without its volume number if it's the first volume, e.g. `out.lizard` for
+/// `out.lizard.001`. Otherwise None
+pub fn strip_first_volume(path: &Path) -> Option<PathBuf> {
+    match path.extension()?.to_str()? {
+        "001" => Some(path.with_extension("")),
+        _ => None,
+    }
+}
+
+/// The base [path]'s volumes are named from, if it names volumes rather than a single file:
+/// either the first volume itself, or a base which doesn't exist but whose first volume does
+pub fn volumes_base(path: &Path) -> Option<PathBuf> {
+    if let Some(base) = strip_first_volume(path) {
+        return Some(base);
+    }
+    match !path.exists() && volume_path(path, 1).exists() {
+        true => Some(path.to_path_buf()),
+        false => None,
+    }
+}
+
+/// The volumes of [base] that exist, in order
+pub fn existing_volumes(base: &Path) -> Vec<PathBuf> {
+    (1..)
+        .map(|index| volume_path(base, index))
+        .take_while(|path| path.exists())
+        .collect()
+}
+
+/// The files [path] names: the volumes it's the base or first volume of, or just itself
+pub fn input_paths(path: &Path) -> Vec<PathBuf> {
+    match volumes_base(path) {
+        Some(base) => existing_volumes(&base),
+        None => vec![path.to_path_buf()],
+    }
+}
+
+/// Writes to the volumes of [base], starting the next once one holds [volume_size] bytes
+pub struct VolumeWriter {
+    base: PathBuf,
+    volume_size: u64,
+    /// Of the volume being written, 0 before the first is created
+    index: u32,
+    current: Option<BufWriter<File>>,
+    /// Written to the current volume
+    current_len: u64,
+}
+
+impl VolumeWriter {
+    pub fn create(base: &Path, volume_size: u64) -> Self {
+        Self {
+            base: base.to_path_buf(),
+            volume_size: volume_size.max(1),
+            index: 0,
+            current: None,
+            current_len: 0,
+        }
+    }
+
+    /// Flush the last volume and remove any left over from an earlier, longer output, so
+    /// they're not read as following this one. Returns how many volumes were written
+    pub fn finish(mut self) -> std::io::Result<u32> {
+        if let Some(mut current) = self.current.take() {
+            current.flush()?;
+        }
+        for stale in existing_volumes(&self.base)
+            .iter()
+            .skip(self.index as usize)
+        {
+            info!("Removing stale volume {:?}", stale);
+            std::fs::remove_file(stale)?;
+        }
+        Ok(self.index)
+    }
+
+    /// Remove all the volumes written so far, to start again
+    pub fn remove_all(&mut self) -> std::io::Result<()> {
+        self.current = None;
+        for index in 1..=self.index {
+            std::fs::remove_file(volume_path(&self.base, index))?;
+        }
+        self.index = 0;
+        self.current_len = 0;
+        Ok(())
+    }
+}
+
+impl Write for VolumeWriter {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        if self.current.is_none() || self.current_len == self.volume_size {
+            if let Some(mut full) = self.current.take() {
+                full.flush()?;
+            }
+            self.index += 1;
+            let file = File::create(volume_path(&self.base, self.index))?;
+            self.current = Some(BufWriter::new(file));
+            self.current_len = 0;
+        }
+        // Compared as u64, volumes can be bigger than a 32-bit usize
+        let len = (buf.len() as u64).min(self.volume_size - self.current_len) as usize;
+        let written = self.current.as_mut().unwrap().write(&buf[..len])?;
+        self.current_len += written as u64;
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        match &mut self.current {
+            Some(current) => current.flush(),
+            None => Ok(()),
+        }
+    }
+}
+
+/// Reads [input_paths] of a path one after the other, as a single file
+pub struct VolumeReader {
+    /// Each path with the offset its volume starts at
+    volumes: Vec<(PathBuf, u64)>,
+    /// Which volume is open, read up to [position]
+    current: Option<(usize, File)>,
+    position: u64,
+    len: u64,
+}
+
+impl VolumeReader {
+    pub fn open(path: &Path) -> std::io::Result<Self> {
+        let mut volumes = Vec::new();
+        let mut len = 0;
+        for path in input_paths(path) {
+            let volume_len = std::fs::metadata(&path)?.len();
+            volumes.push((path, len));
+            len += volume_len;
+        }
+        Ok(Self {
+            volumes,
+            current: None,
+            position: 0,
+            len,
+        })
+    }
+
+    /// Of all the files together
+    pub fn len(&self) -> u64 {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+}
+
+impl Read for VolumeReader {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        if buf.is_empty() || self.position >= self.len {
+            return Ok(0);
+        }
+        // The last volume starting at or before [position], skipping any empty ones
+        let volume = self
+            .volumes
+            .partition_point(|(_, start)| *start <= self.position)
+            - 1;
+        let file = match &mut self.current {
+            Some((current, file)) if *current == volume => file,
+            _ => {
+                let (path, start) = &self.volumes[volume];
+                let mut file = File::open(path)?;
+                file.seek(SeekFrom::Start(self.position - start))?;
+                &mut self.current.insert((volume, file)).1
+            }
+        };
+        let read = file.read(buf)?;
+        if read == 0 {
+            return Err(std::io::ErrorKind::UnexpectedEof.into());
+        }
+        self.position += read as u64;
+        Ok(read)
+    }
+}
+
+impl Seek for VolumeReader {
+    fn seek(&mut self, pos: SeekFrom) -> std::io::Result<u64> {
+        let position = match pos {
+            SeekFrom::Start(offset) => Some(offset),
+            SeekFrom::End(offset) => self.len.checked_add_signed(offset),
+            SeekFrom::Current(offset) => self.position.checked_add_signed(offset),
+        }
+        .ok_or(std::io::ErrorKind::InvalidInput)?;
+        if position != self.position {
+            self.current = None;
+            self.position = position;
+        }
+        Ok(position)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::{Read, Seek, SeekFrom, Write};
+
+    use crate::decode::{decode, DecodeOptions};
+    use crate::encode::{encode_to_volumes, EncodeOptions};
+    use crate::file_io::{FileInputOutput, InputFile, OutputTarget};
+    use crate::test_util::temp_path;
+    use crate::volumes::{existing_volumes, volume_path, volumes_base, VolumeReader, VolumeWriter};
+
+    #[test]
+    fn volumes() {
+        let dir = temp_path("volumes");
+        std::fs::create_dir_all(&dir).unwrap();
+        let base = dir.join("out.lizard");
+        let bytes: Vec<u8> = (0..3500).map(|i| (i % 251) as u8).collect();
+
+        let mut writer = VolumeWriter::create(&base, 1000);
+        for piece in bytes.chunks(300) {
+            writer.write_all(piece).unwrap();
+        }
+        assert_eq!(writer.finish().unwrap(), 4);
+        let lens: Vec<u64> = existing_volumes(&base)
+            .iter()
+            .map(|path| std::fs::metadata(path).unwrap().len())
+            .collect();
+        assert_eq!(lens, [1000, 1000, 1000, 500]);
+        assert_eq!(volumes_base(&base), Some(base.clone()));
+        assert_eq!(volumes_base(&volume_path(&base, 1)), Some(base.clone()));
+        for path in [base.clone(), volume_path(&base, 1)] {
+            let mut read = Vec::new();
+            let mut reader = VolumeReader::open(&path).unwrap();
+            assert_eq!(reader.len(), bytes.len() as u64);
+            reader.read_to_end(&mut read).unwrap();
+            assert_eq!(read, bytes);
+            // Across the end of a volume, from the start of one, and from the end
+            for (from, start) in [
+                (SeekFrom::Start(900), 900),
+                (SeekFrom::Start(2000), 2000),
+                (SeekFrom::End(-600), 2900),
+            ] {
+                assert_eq!(reader.seek(from).unwrap(), start);
+                let mut read = vec![0; 200];
+                reader.read_exact(&mut read).unwrap();
+                assert_eq!(read, bytes[start as usize..][..200]);
+            }
+        }
+
+        // Writing fewer volumes over the top doesn't leave the old ones to be read after them
+        let mut writer = VolumeWriter::create(&base, 1000);
+        writer.write_all(&bytes[..1500]).unwrap();
+        assert_eq!(writer.finish().unwrap(), 2);
+        assert_eq!(existing_volumes(&base).len(), 2);
+
+        // Encoding the same way, and decoding reading them back
+        let input = dir.join("input.txt");
+        let text: Vec<u8> = (0..3000)
+            .flat_map(|i| format!("volume {}, ", i % 13).into_bytes())
+            .collect();
+        std::fs::write(&input, &text).unwrap();
+        let file_io = FileInputOutput::builder(InputFile::Unencoded(input.clone()))
+            .output(OutputTarget::Path(base.clone()))
+            .build()
+            .unwrap();
+        let summary = encode_to_volumes(&file_io, &EncodeOptions::default(), 1000).unwrap();
+        let volumes = existing_volumes(&base);
+        assert_eq!(volumes.len() as u64, summary.output_bytes.div_ceil(1000));
+        let output = dir.join("output.txt");
+        let file_io = FileInputOutput::builder(InputFile::Encoded(volumes[0].clone()))
+            .output(OutputTarget::Path(output.clone()))
+            .build()
+            .unwrap();
+        let decoded = decode(&file_io, &DecodeOptions::default()).unwrap();
+        assert_eq!(decoded.input_bytes, summary.output_bytes);
+        assert_eq!(std::fs::read(&output).unwrap(), text);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}